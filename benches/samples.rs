@@ -0,0 +1,74 @@
+#![feature(test)]
+
+extern crate test;
+extern crate bf;
+
+use bf::ast;
+use bf::samples;
+use bf::traits::{BytecodeCompilable, Interpretable, PeepholeCompilable};
+
+use test::Bencher;
+
+fn bench_peephole(b: &mut Bencher, name: &str) {
+    let sample = samples::sample(name).unwrap();
+    let program = ast::parse_program(sample.source.as_bytes()).unwrap().peephole_compile();
+
+    b.iter(|| {
+        program.interpret_memory(None, sample.input).unwrap()
+    });
+}
+
+fn bench_nested(b: &mut Bencher, name: &str) {
+    let guest = samples::sample(name).unwrap();
+    let input = samples::self_interpreter_input(guest);
+    let program = ast::parse_program(samples::by_name("dbfi").unwrap().as_bytes()).unwrap()
+        .bytecode_compile();
+
+    b.iter(|| {
+        program.interpret_memory(None, &input).unwrap()
+    });
+}
+
+fn bench_bytecode(b: &mut Bencher, name: &str) {
+    let sample = samples::sample(name).unwrap();
+    let program = ast::parse_program(sample.source.as_bytes()).unwrap().bytecode_compile();
+
+    b.iter(|| {
+        program.interpret_memory(None, sample.input).unwrap()
+    });
+}
+
+#[bench]
+fn peephole_hello(b: &mut Bencher) {
+    bench_peephole(b, "hello");
+}
+
+#[bench]
+fn peephole_rot13(b: &mut Bencher) {
+    bench_peephole(b, "rot13");
+}
+
+#[bench]
+fn peephole_mandelbrot(b: &mut Bencher) {
+    bench_peephole(b, "mandelbrot");
+}
+
+#[bench]
+fn bytecode_rot13(b: &mut Bencher) {
+    bench_bytecode(b, "rot13");
+}
+
+#[bench]
+fn bytecode_mandelbrot(b: &mut Bencher) {
+    bench_bytecode(b, "mandelbrot");
+}
+
+#[bench]
+fn bytecode_dbfi_hello(b: &mut Bencher) {
+    bench_nested(b, "hello");
+}
+
+#[bench]
+fn bytecode_dbfi_rot13(b: &mut Bencher) {
+    bench_nested(b, "rot13");
+}
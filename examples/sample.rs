@@ -0,0 +1,34 @@
+//! Runs a sample program on standard input and output.
+//!
+//! ```shell
+//! $ echo 'Uryyb' | cargo run --features samples --example sample -- rot13
+//! Hello
+//! ```
+
+extern crate bf;
+
+use std::env;
+use std::process::exit;
+
+use bf::ast;
+use bf::samples;
+use bf::traits::{Interpretable, PeepholeCompilable};
+
+fn main() {
+    let name = env::args().nth(1).unwrap_or_default();
+
+    let source = match samples::by_name(&name) {
+        Some(source) => source,
+        None => {
+            let names: Vec<_> = samples::names().collect();
+            eprintln!("usage: sample NAME, where NAME is one of: {}", names.join(", "));
+            exit(1)
+        }
+    };
+
+    let program = ast::parse_program(source.as_bytes()).unwrap().peephole_compile();
+    if let Err(e) = program.interpret_stdin(None) {
+        eprintln!("sample: runtime error: {}.", e);
+        exit(3)
+    }
+}
@@ -0,0 +1,107 @@
+//! Measures nested execution: runs the `dbfi` self-interpreter on a guest sample with each
+//! backend, and compares that with running the guest directly.
+//!
+//! ```shell
+//! $ cargo run --release --features samples --example self_interpreter -- rot13 10
+//! guest rot13, 10 runs each
+//! backend       direct      nested    slowdown
+//! ast          0.077ms   257.709ms     3344.0x
+//! peep         0.057ms    67.439ms     1189.9x
+//! ...
+//! ```
+
+extern crate bf;
+
+use std::env;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use bf::ast;
+use bf::samples;
+use bf::traits::*;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let name = args.next().unwrap_or_else(|| "hello".to_owned());
+    let runs = args.next().map(|n| n.parse().unwrap_or_else(|_| usage())).unwrap_or(10);
+
+    let guest = match samples::sample(&name) {
+        Some(guest) if guest.name != "dbfi" => guest,
+        _ => usage(),
+    };
+    let dbfi = samples::sample("dbfi").unwrap();
+
+    println!("guest {}, {} runs each", guest.name, runs);
+    println!("{:8} {:>11} {:>11} {:>11}", "backend", "direct", "nested", "slowdown");
+    for backend in backends() {
+        let direct = time(backend, guest.source, guest.input, guest.output, runs);
+        let nested = time(backend, dbfi.source, &samples::self_interpreter_input(guest),
+                          guest.output, runs);
+        println!("{:8} {:>9.3}ms {:>9.3}ms {:>10.1}x", backend.name, millis(direct),
+                 millis(nested), nested.as_secs_f64() / direct.as_secs_f64());
+    }
+}
+
+/// A backend, which compiles a parsed program and runs it the given number of times on the
+/// given input, returning the last output.
+#[derive(Clone, Copy)]
+struct Backend {
+    name: &'static str,
+    run:  fn(&ast::Program, &[u8], usize) -> Vec<u8>,
+}
+
+fn backends() -> Vec<Backend> {
+    fn run<P: Interpretable + ?Sized>(program: &P, input: &[u8], runs: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        for _ in 0 .. runs {
+            output = program.interpret_memory(None, input).unwrap();
+        }
+        output
+    }
+
+    #[allow(unused_mut)]
+    let mut backends = vec![
+        Backend { name: "ast", run: |p, i, n| run(p, i, n) },
+        Backend { name: "rle", run: |p, i, n| run(&*p.rle_compile(), i, n) },
+        Backend { name: "peep", run: |p, i, n| run(&*p.peephole_compile(), i, n) },
+        Backend { name: "byte", run: |p, i, n| run(&*p.bytecode_compile(), i, n) },
+        Backend { name: "threaded", run: |p, i, n| run(&p.threaded_compile(), i, n) },
+    ];
+    #[cfg(feature = "jit")]
+    backends.push(Backend { name: "jit", run: |p, i, n| run(&p.jit_compile(true), i, n) });
+    backends
+}
+
+/// The mean time for one run of `source` on `input`, not counting compilation, which is
+/// timed separately and subtracted. Exits if the output is not `expected`.
+fn time(backend: Backend, source: &str, input: &[u8], expected: &[u8], runs: usize)
+        -> Duration
+{
+    let program = ast::parse_program(source.as_bytes()).unwrap();
+
+    let start = Instant::now();
+    (backend.run)(&program, input, 0);
+    let compile = start.elapsed();
+
+    let start = Instant::now();
+    let output = (backend.run)(&program, input, runs);
+    let elapsed = start.elapsed().saturating_sub(compile);
+
+    if runs > 0 && output != expected {
+        eprintln!("self_interpreter: {} gave the wrong output", backend.name);
+        exit(1)
+    }
+
+    elapsed / runs.max(1) as u32
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000.0
+}
+
+fn usage() -> ! {
+    let names: Vec<_> = samples::names().filter(|&name| name != "dbfi").collect();
+    eprintln!("usage: self_interpreter [GUEST [RUNS]], where GUEST is one of: {}",
+              names.join(", "));
+    exit(1)
+}
@@ -0,0 +1,18 @@
+// Loads the native addon built by `cargo build`, preferring a release build.
+"use strict";
+
+const fs = require("fs");
+const path = require("path");
+
+const name = { darwin: "libbf_node.dylib", win32: "bf_node.dll" }[process.platform]
+    || "libbf_node.so";
+const candidates = ["release", "debug"]
+    .map(profile => path.join(__dirname, "target", profile, name));
+const found = candidates.find(file => fs.existsSync(file));
+if (!found) {
+    throw new Error("bf-node: build the addon with `cargo build --release` first");
+}
+
+const addon = { exports: {} };
+process.dlopen(addon, found);
+module.exports = addon.exports;
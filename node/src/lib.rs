@@ -0,0 +1,479 @@
+//! Node.js bindings for `bf`, through Node-API.
+//!
+//! `cargo build --release` in this directory builds `libbf_node.so` (or `.dylib`), which
+//! `index.js` loads as a native addon. Programs and executions are opaque handles:
+//!
+//! ```js
+//! const bf = require("./index.js");
+//!
+//! const program = bf.compile(",[.,]");
+//! bf.run(program, Buffer.from("hi"), { fuel: 1000 });
+//! // => { output: <Buffer 68 69>, text: "hi", stop: "halted", steps: 8, instructions: 5 }
+//!
+//! const execution = bf.start(program);
+//! bf.step(execution, 100);          // => { event: "input" }
+//! bf.provideInput(execution, 0x41);
+//! bf.step(execution, 100);          // => { event: "output", byte: 65 }
+//! bf.inspect(execution);            // => { pc: 3, pointer: 0, memory: <Buffer 41 00 ...> }
+//! ```
+//!
+//! Sources may be strings or `Buffer`s. `compile` and `run` take the
+//! [limits](../bf/limits/struct.SourceLimits.html) `maxSourceSize`, `maxDepth` and
+//! `maxInstructions`, defaulting to a [sandbox](../bf/sandbox/struct.Sandbox.html)’s, and `run`
+//! also takes `fuel`, `memory` and `maxOutput`. `start` takes `memory`. Syntax errors throw an
+//! `Error` with code `BF_SYNTAX`, and exceeded limits one with `BF_LIMIT`; `run` reports
+//! run-time errors in `stop`, and `step` throws them with `BF_RUNTIME`. A run’s `text` is its
+//! `output` decoded as UTF-8 for display, with invalid bytes as U+FFFD. `provideInput` throws
+//! a `RangeError` with code `ERR_OUT_OF_RANGE` for a byte above 255.
+//!
+//! With the `jit` feature, `compile(source, { jit: true })` also compiles the
+//! program to native code, which `run` then uses. Native runs have no fuel or output bounds.
+
+extern crate bf;
+
+mod sys;
+
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::ptr;
+use std::sync::Arc;
+
+use bf::bytecode::{self, Execution, StepResult};
+use bf::common::Error;
+use bf::limits::SourceLimits;
+use bf::sandbox::Sandbox;
+use bf::state::State;
+use sys::*;
+
+/// JavaScript’s `Number.MAX_SAFE_INTEGER`, the largest count an option can give exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// An error to throw, with its code.
+struct Throw(&'static str, String);
+
+type JsResult<T> = Result<T, Throw>;
+
+/// What the bindings’ external values hold.
+enum Handle {
+    Program(Compiled),
+    Execution(Stepper),
+}
+
+/// A compiled program.
+struct Compiled {
+    code: Arc<bytecode::Program>,
+    #[cfg(feature = "jit")]
+    native: Option<bf::jit::Program>,
+}
+
+/// A program being run a step at a time, with the program it runs.
+struct Stepper {
+    // Declared first so that it is dropped before the code it borrows.
+    execution: Execution<'static>,
+    _code: Arc<bytecode::Program>,
+}
+
+impl Stepper {
+    fn new(code: &Arc<bytecode::Program>, state: State) -> Self {
+        let code = Arc::clone(code);
+        // The code is behind an `Arc` that lives as long as the execution, so the reference
+        // stays valid however the `Stepper` moves.
+        let program: &'static bytecode::Program = unsafe { &*(&*code as *const _) };
+        Stepper { execution: Execution::new(program, state), _code: code }
+    }
+}
+
+/// A Node-API environment, for the duration of a call.
+struct Env(napi_env);
+
+impl Env {
+    fn check(&self, status: napi_status) -> JsResult<()> {
+        if status == NAPI_OK {
+            Ok(())
+        } else {
+            Err(Throw("BF_NAPI", format!("Node-API call failed with status {}", status)))
+        }
+    }
+
+    fn value<F: FnOnce(*mut napi_value) -> napi_status>(&self, f: F) -> JsResult<napi_value> {
+        let mut result = ptr::null_mut();
+        self.check(f(&mut result))?;
+        Ok(result)
+    }
+
+    fn type_of(&self, value: napi_value) -> JsResult<i32> {
+        let mut result = 0;
+        self.check(unsafe { napi_typeof(self.0, value, &mut result) })?;
+        Ok(result)
+    }
+
+    fn undefined(&self) -> JsResult<napi_value> {
+        self.value(|result| unsafe { napi_get_undefined(self.0, result) })
+    }
+
+    #[cfg(feature = "jit")]
+    fn null(&self) -> JsResult<napi_value> {
+        self.value(|result| unsafe { napi_get_null(self.0, result) })
+    }
+
+    fn number(&self, n: usize) -> JsResult<napi_value> {
+        self.value(|result| unsafe { napi_create_double(self.0, n as f64, result) })
+    }
+
+    fn string(&self, s: &str) -> JsResult<napi_value> {
+        self.value(|result| unsafe {
+            napi_create_string_utf8(self.0, s.as_ptr() as *const _, s.len(), result)
+        })
+    }
+
+    fn buffer(&self, bytes: &[u8]) -> JsResult<napi_value> {
+        self.value(|result| unsafe {
+            napi_create_buffer_copy(self.0, bytes.len(), bytes.as_ptr() as *const c_void,
+                                    ptr::null_mut(), result)
+        })
+    }
+
+    fn object(&self, members: Vec<(&str, napi_value)>) -> JsResult<napi_value> {
+        let object = self.value(|result| unsafe { napi_create_object(self.0, result) })?;
+        for (name, value) in members {
+            let name = CString::new(name).unwrap();
+            self.check(unsafe { napi_set_named_property(self.0, object, name.as_ptr(), value) })?;
+        }
+        Ok(object)
+    }
+
+    fn external(&self, handle: Handle) -> JsResult<napi_value> {
+        unsafe extern "C" fn finalize(_env: napi_env, data: *mut c_void, _hint: *mut c_void) {
+            drop(Box::from_raw(data as *mut Handle));
+        }
+
+        let data = Box::into_raw(Box::new(handle)) as *mut c_void;
+        let result = self.value(|result| unsafe {
+            napi_create_external(self.0, data, Some(finalize), ptr::null_mut(), result)
+        });
+        if result.is_err() {
+            drop(unsafe { Box::from_raw(data as *mut Handle) });
+        }
+        result
+    }
+
+    /// The handle in an external value from `external`. JavaScript keeps the value, and so the
+    /// handle, alive for the rest of the call.
+    fn handle<'a>(&self, value: napi_value) -> JsResult<&'a mut Handle> {
+        if self.type_of(value)? != NAPI_EXTERNAL {
+            return Err(invalid("expected a program or execution handle"));
+        }
+        let mut data = ptr::null_mut();
+        self.check(unsafe { napi_get_value_external(self.0, value, &mut data) })?;
+        Ok(unsafe { &mut *(data as *mut Handle) })
+    }
+
+    /// The bytes of a string or `Buffer`.
+    fn bytes(&self, value: napi_value) -> JsResult<Vec<u8>> {
+        if self.type_of(value)? == NAPI_STRING {
+            let mut length = 0;
+            self.check(unsafe {
+                napi_get_value_string_utf8(self.0, value, ptr::null_mut(), 0, &mut length)
+            })?;
+            let mut bytes = vec![0u8; length + 1];
+            self.check(unsafe {
+                napi_get_value_string_utf8(self.0, value, bytes.as_mut_ptr() as *mut _,
+                                           bytes.len(), &mut length)
+            })?;
+            bytes.truncate(length);
+            return Ok(bytes);
+        }
+
+        let mut is_buffer = false;
+        self.check(unsafe { napi_is_buffer(self.0, value, &mut is_buffer) })?;
+        if !is_buffer {
+            return Err(invalid("expected a string or Buffer"));
+        }
+        let mut data = ptr::null_mut();
+        let mut length = 0;
+        self.check(unsafe { napi_get_buffer_info(self.0, value, &mut data, &mut length) })?;
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(unsafe { ::std::slice::from_raw_parts(data as *const u8, length) }.to_vec())
+    }
+
+    /// A non-negative integer, rounded down and capped at `MAX_SAFE_INTEGER`.
+    fn count(&self, value: napi_value) -> JsResult<usize> {
+        if self.type_of(value)? != NAPI_NUMBER {
+            return Err(invalid("expected a number"));
+        }
+        let mut n = 0.0;
+        self.check(unsafe { napi_get_value_double(self.0, value, &mut n) })?;
+        if n.is_nan() || n < 0.0 {
+            return Err(invalid("expected a non-negative number"));
+        }
+        Ok(n.min(MAX_SAFE_INTEGER) as usize)
+    }
+
+    /// A member of an options object, or `None` if there is no object or no such member.
+    fn option(&self, options: napi_value, name: &str) -> JsResult<Option<napi_value>> {
+        match self.type_of(options)? {
+            NAPI_UNDEFINED | NAPI_NULL => return Ok(None),
+            NAPI_OBJECT => (),
+            _ => return Err(invalid("expected an options object")),
+        }
+        let name = CString::new(name).unwrap();
+        let value = self.value(|result| unsafe {
+            napi_get_named_property(self.0, options, name.as_ptr(), result)
+        })?;
+        match self.type_of(value)? {
+            NAPI_UNDEFINED | NAPI_NULL => Ok(None),
+            _ => Ok(Some(value)),
+        }
+    }
+
+    fn count_option(&self, options: napi_value, name: &str, default: usize) -> JsResult<usize> {
+        match self.option(options, name)? {
+            Some(value) => self.count(value),
+            None => Ok(default),
+        }
+    }
+
+    fn limits(&self, options: napi_value) -> JsResult<SourceLimits> {
+        let default = Sandbox::default().limits;
+        Ok(SourceLimits {
+            max_source_len: self.count_option(options, "maxSourceSize", default.max_source_len)?,
+            max_depth: self.count_option(options, "maxDepth", default.max_depth)?,
+            max_instructions:
+                self.count_option(options, "maxInstructions", default.max_instructions)?,
+        })
+    }
+}
+
+fn invalid(message: &str) -> Throw {
+    Throw("ERR_INVALID_ARG_TYPE", message.to_owned())
+}
+
+/// The code of the errors thrown as `RangeError`s.
+const OUT_OF_RANGE: &str = "ERR_OUT_OF_RANGE";
+
+/// An argument outside the values it may take.
+fn out_of_range(message: &str) -> Throw {
+    Throw(OUT_OF_RANGE, message.to_owned())
+}
+
+/// The error to throw for a BF error, given the code for errors that are not about limits.
+fn bf_error(error: Error, code: &'static str) -> Throw {
+    let code = match error {
+        Error::SourceTooLarge(_) | Error::NestingTooDeep(_) | Error::ProgramTooLarge(_) =>
+            "BF_LIMIT",
+        _ => code,
+    };
+    Throw(code, error.to_string())
+}
+
+/// `compile(source, options?)`
+fn compile(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let source = env.bytes(args[0])?;
+    let program = env.limits(args[1])?.compile(&source)
+        .map_err(|e| bf_error(e, "BF_SYNTAX"))?;
+
+    #[cfg(feature = "jit")]
+    let native = match env.option(args[1], "jit")? {
+        Some(_) => Some(bf::jit::compile(&program, true)),
+        None => None,
+    };
+
+    env.external(Handle::Program(Compiled {
+        code: bytecode::compile(&program).into(),
+        #[cfg(feature = "jit")]
+        native,
+    }))
+}
+
+/// `run(programOrSource, input?, options?)`
+fn run(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let input = match env.type_of(args[1])? {
+        NAPI_UNDEFINED | NAPI_NULL => Vec::new(),
+        _ => env.bytes(args[1])?,
+    };
+    let options = args[2];
+    let default = Sandbox::default();
+    let sandbox = Sandbox {
+        limits: env.limits(options)?,
+        memory_size: env.count_option(options, "memory", default.memory_size)?.max(1),
+        fuel: env.count_option(options, "fuel", default.fuel)?,
+        max_output: env.count_option(options, "maxOutput", default.max_output)?,
+        ..default
+    };
+
+    let report = if env.type_of(args[0])? == NAPI_EXTERNAL {
+        let compiled = match *env.handle(args[0])? {
+            Handle::Program(ref compiled) => compiled,
+            Handle::Execution(_) => return Err(invalid("expected a program, not an execution")),
+        };
+
+        #[cfg(feature = "jit")]
+        {
+            use bf::traits::Interpretable;
+            if let Some(ref native) = compiled.native {
+                let (stop, output) = match native.interpret_memory(Some(sandbox.memory_size),
+                                                                   &input) {
+                    Ok(output) => ("halted".to_owned(), output),
+                    Err(e) => (e.to_string(), Vec::new()),
+                };
+                return env.object(vec![
+                    ("output", env.buffer(&output)?),
+                    ("text", env.string(&String::from_utf8_lossy(&output))?),
+                    ("stop", env.string(&stop)?),
+                    ("steps", env.null()?),
+                    ("instructions", env.number(compiled.code.len())?),
+                ]);
+            }
+        }
+
+        sandbox.run_bytecode(&compiled.code, &input)
+    } else {
+        sandbox.run(&env.bytes(args[0])?, &input).map_err(|e| bf_error(e, "BF_SYNTAX"))?
+    };
+
+    env.object(vec![
+        ("output", env.buffer(&report.output)?),
+        ("text", env.string(&String::from_utf8_lossy(&report.output))?),
+        ("stop", env.string(&report.stop.to_string())?),
+        ("steps", env.number(report.steps)?),
+        ("instructions", env.number(report.instructions)?),
+    ])
+}
+
+/// `start(program, options?)`
+fn start(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let memory_size = env.count_option(args[1], "memory", bf::state::DEFAULT_CAPACITY)?.max(1);
+    let stepper = match *env.handle(args[0])? {
+        Handle::Program(ref compiled) =>
+            Stepper::new(&compiled.code, State::with_capacity(memory_size)),
+        Handle::Execution(_) => return Err(invalid("expected a program, not an execution")),
+    };
+    env.external(Handle::Execution(stepper))
+}
+
+fn stepper<'a>(env: &Env, value: napi_value) -> JsResult<&'a mut Stepper> {
+    match *env.handle(value)? {
+        Handle::Execution(ref mut stepper) => Ok(stepper),
+        Handle::Program(_) => Err(invalid("expected an execution, not a program")),
+    }
+}
+
+/// `step(execution, maxSteps?)`
+fn step(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let max_steps = match env.type_of(args[1])? {
+        NAPI_UNDEFINED => 1,
+        _ => env.count(args[1])?,
+    };
+    let execution = &mut stepper(env, args[0])?.execution;
+
+    let mut result = StepResult::Continue;
+    for _ in 0 .. max_steps {
+        result = execution.step().map_err(|e| bf_error(e, "BF_RUNTIME"))?;
+        if result != StepResult::Continue {
+            break;
+        }
+    }
+
+    match result {
+        StepResult::Continue => env.object(vec![("event", env.string("continue")?)]),
+        StepResult::NeedsInput => env.object(vec![("event", env.string("input")?)]),
+        StepResult::Output(byte) => env.object(vec![
+            ("event", env.string("output")?),
+            ("byte", env.number(byte as usize)?),
+        ]),
+        StepResult::Halted => env.object(vec![("event", env.string("halted")?)]),
+    }
+}
+
+/// `provideInput(execution, byte)`, where a `null` byte is end of input.
+fn provide_input(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let byte = match env.type_of(args[1])? {
+        NAPI_UNDEFINED | NAPI_NULL => None,
+        _ => match env.count(args[1])? {
+            byte if byte <= 255 => Some(byte as u8),
+            _ => return Err(out_of_range("expected a byte from 0 to 255")),
+        },
+    };
+    stepper(env, args[0])?.execution.provide_input(byte);
+    env.undefined()
+}
+
+/// `inspect(execution)`
+fn inspect(env: &Env, args: &[napi_value]) -> JsResult<napi_value> {
+    let execution = &stepper(env, args[0])?.execution;
+    let memory: Vec<u8> = execution.state().memory().iter().map(|cell| cell.0).collect();
+    env.object(vec![
+        ("pc", env.number(execution.pc())?),
+        ("pointer", env.number(execution.state().pointer())?),
+        ("memory", env.buffer(&memory)?),
+    ])
+}
+
+/// Calls a binding with its arguments, throwing any error it returns.
+fn call(env: napi_env, info: napi_callback_info,
+        f: fn(&Env, &[napi_value]) -> JsResult<napi_value>) -> napi_value {
+    let env = Env(env);
+    let mut args = [ptr::null_mut(); 3];
+    let mut argc = args.len();
+    let result = env.check(unsafe {
+        napi_get_cb_info(env.0, info, &mut argc, args.as_mut_ptr(), ptr::null_mut(),
+                         ptr::null_mut())
+    }).and_then(|()| f(&env, &args));
+
+    match result {
+        Ok(value) => value,
+        Err(Throw(code, message)) => {
+            let mut pending = false;
+            unsafe { napi_is_exception_pending(env.0, &mut pending) };
+            if !pending {
+                let throw = if code == OUT_OF_RANGE {
+                    napi_throw_range_error
+                } else {
+                    napi_throw_error
+                };
+                let code = CString::new(code).unwrap();
+                let message = CString::new(message).unwrap_or_default();
+                unsafe { throw(env.0, code.as_ptr(), message.as_ptr()) };
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+macro_rules! bindings {
+    ($($name:expr => $function:ident,)*) => {
+        /// Registers the bindings on the module’s `exports`.
+        ///
+        /// # Safety
+        ///
+        /// Node calls this once, with a valid environment, when it loads the addon.
+        #[no_mangle]
+        pub unsafe extern "C" fn napi_register_module_v1(env: napi_env, exports: napi_value)
+                                                         -> napi_value {
+            $(
+                unsafe extern "C" fn $function(env: napi_env, info: napi_callback_info)
+                                               -> napi_value {
+                    call(env, info, ::$function)
+                }
+
+                let mut function = ptr::null_mut();
+                napi_create_function(env, $name.as_ptr() as *const _, $name.len(), $function,
+                                     ptr::null_mut(), &mut function);
+                let name = CString::new($name).unwrap();
+                napi_set_named_property(env, exports, name.as_ptr(), function);
+            )*
+            exports
+        }
+    }
+}
+
+bindings! {
+    "compile" => compile,
+    "run" => run,
+    "start" => start,
+    "step" => step,
+    "provideInput" => provide_input,
+    "inspect" => inspect,
+}
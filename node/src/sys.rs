@@ -0,0 +1,73 @@
+//! The parts of Node-API (`node_api.h`) the bindings use.
+//!
+//! Node resolves these when it loads the addon, so the library links without them.
+
+#![allow(non_camel_case_types)]
+
+use std::os::raw::{c_char, c_void};
+
+pub enum napi_env__ {}
+pub enum napi_value__ {}
+pub enum napi_callback_info__ {}
+
+pub type napi_env = *mut napi_env__;
+pub type napi_value = *mut napi_value__;
+pub type napi_callback_info = *mut napi_callback_info__;
+pub type napi_status = i32;
+
+pub type napi_callback = unsafe extern "C" fn(napi_env, napi_callback_info) -> napi_value;
+pub type napi_finalize = unsafe extern "C" fn(napi_env, *mut c_void, *mut c_void);
+
+pub const NAPI_OK: napi_status = 0;
+
+pub const NAPI_UNDEFINED: i32 = 0;
+pub const NAPI_NULL: i32 = 1;
+pub const NAPI_NUMBER: i32 = 3;
+pub const NAPI_STRING: i32 = 4;
+pub const NAPI_OBJECT: i32 = 6;
+pub const NAPI_EXTERNAL: i32 = 8;
+
+extern "C" {
+    pub fn napi_create_function(env: napi_env, name: *const c_char, length: usize,
+                                cb: napi_callback, data: *mut c_void, result: *mut napi_value)
+                                -> napi_status;
+    pub fn napi_get_cb_info(env: napi_env, info: napi_callback_info, argc: *mut usize,
+                            argv: *mut napi_value, this_arg: *mut napi_value,
+                            data: *mut *mut c_void) -> napi_status;
+    pub fn napi_typeof(env: napi_env, value: napi_value, result: *mut i32) -> napi_status;
+    pub fn napi_throw_error(env: napi_env, code: *const c_char, msg: *const c_char)
+                            -> napi_status;
+    pub fn napi_throw_range_error(env: napi_env, code: *const c_char, msg: *const c_char)
+                                  -> napi_status;
+    pub fn napi_is_exception_pending(env: napi_env, result: *mut bool) -> napi_status;
+
+    pub fn napi_get_undefined(env: napi_env, result: *mut napi_value) -> napi_status;
+    #[cfg(feature = "jit")]
+    pub fn napi_get_null(env: napi_env, result: *mut napi_value) -> napi_status;
+    pub fn napi_create_object(env: napi_env, result: *mut napi_value) -> napi_status;
+    pub fn napi_create_double(env: napi_env, value: f64, result: *mut napi_value)
+                              -> napi_status;
+    pub fn napi_create_string_utf8(env: napi_env, string: *const c_char, length: usize,
+                                   result: *mut napi_value) -> napi_status;
+    pub fn napi_create_buffer_copy(env: napi_env, length: usize, data: *const c_void,
+                                   result_data: *mut *mut c_void, result: *mut napi_value)
+                                   -> napi_status;
+    pub fn napi_create_external(env: napi_env, data: *mut c_void,
+                                finalize_cb: Option<napi_finalize>, finalize_hint: *mut c_void,
+                                result: *mut napi_value) -> napi_status;
+
+    pub fn napi_get_value_double(env: napi_env, value: napi_value, result: *mut f64)
+                                 -> napi_status;
+    pub fn napi_get_value_string_utf8(env: napi_env, value: napi_value, buf: *mut c_char,
+                                      bufsize: usize, result: *mut usize) -> napi_status;
+    pub fn napi_is_buffer(env: napi_env, value: napi_value, result: *mut bool) -> napi_status;
+    pub fn napi_get_buffer_info(env: napi_env, value: napi_value, data: *mut *mut c_void,
+                                length: *mut usize) -> napi_status;
+    pub fn napi_get_value_external(env: napi_env, value: napi_value, result: *mut *mut c_void)
+                                   -> napi_status;
+
+    pub fn napi_set_named_property(env: napi_env, object: napi_value, name: *const c_char,
+                                   value: napi_value) -> napi_status;
+    pub fn napi_get_named_property(env: napi_env, object: napi_value, name: *const c_char,
+                                   result: *mut napi_value) -> napi_status;
+}
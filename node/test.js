@@ -0,0 +1,36 @@
+// Tests the bindings: `cargo build && node test.js`.
+"use strict";
+
+const assert = require("assert");
+const bf = require("./index.js");
+
+const program = bf.compile(",[.,]");
+assert.deepStrictEqual(bf.run(program, Buffer.from("hi"), { fuel: 1000 }),
+                       { output: Buffer.from("hi"), text: "hi", stop: "halted", steps: 8,
+                         instructions: 5 });
+assert.strictEqual(bf.run(",[.,]", Buffer.from([0xe2, 0x82, 0xac, 0xff])).text, "€\ufffd");
+assert.strictEqual(bf.run("+[]", null, { fuel: 10 }).stop, "out of fuel");
+assert.strictEqual(bf.run(">>", "", { memory: 2 }).stop, "pointer overflow");
+assert.deepStrictEqual(bf.run("+[.]", "", { maxOutput: 2 }).output, Buffer.from([1, 1]));
+
+assert.throws(() => bf.compile("[["), { code: "BF_SYNTAX", message: "unmatched ‘[’" });
+assert.throws(() => bf.compile("[[]]", { maxDepth: 1 }), { code: "BF_LIMIT" });
+assert.throws(() => bf.run(42), { code: "ERR_INVALID_ARG_TYPE" });
+
+const execution = bf.start(program, { memory: 4 });
+assert.deepStrictEqual(bf.step(execution), { event: "input" });
+assert.throws(() => bf.provideInput(execution, 300),
+              { name: "RangeError", code: "ERR_OUT_OF_RANGE" });
+bf.provideInput(execution, 0x41);
+assert.deepStrictEqual(bf.step(execution, 100), { event: "output", byte: 0x41 });
+assert.deepStrictEqual(bf.inspect(execution),
+                       { pc: 3, pointer: 0, memory: Buffer.from([0x41, 0, 0, 0]) });
+assert.deepStrictEqual(bf.step(execution, 100), { event: "input" });
+bf.provideInput(execution, null);
+assert.deepStrictEqual(bf.step(execution, 100), { event: "halted" });
+assert.throws(() => bf.step(program), { code: "ERR_INVALID_ARG_TYPE" });
+
+const failing = bf.start(bf.compile("<"));
+assert.throws(() => bf.step(failing), { code: "BF_RUNTIME", message: "pointer underflow" });
+
+console.log("bf-node: all tests passed");
@@ -0,0 +1,454 @@
+//! Adapters for programs’ input and output.
+//!
+//! Programs read and write bytes, and nothing makes those bytes text. For display,
+//! [`Utf8Output`](struct.Utf8Output.html) collects a program’s output and decodes it as UTF-8,
+//! keeping the raw bytes too, and [`Utf8Writer`](struct.Utf8Writer.html) decodes it on the way
+//! to another writer, such as a terminal. Either way, bytes that are not UTF-8 become U+FFFD,
+//! as with `String::from_utf8_lossy`, and a character split across writes is decoded whole.
+//!
+//! Programs also assume one newline convention or the other. A
+//! [`NewlineWriter`](struct.NewlineWriter.html) translates output to the other, and
+//! [`StripCr`](struct.StripCr.html) drops carriage returns from input, so that a program
+//! expecting lone `\n` can read lines typed on Windows.
+//!
+//! A [`BufferedWriter`](struct.BufferedWriter.html) holds output back by a
+//! [`Buffering`](../options/enum.Buffering.html) policy, since programs print a byte at a time,
+//! and [`Counting`](struct.Counting.html) counts the bytes passing through either way.
+
+use std::io::{self, Read, Write};
+use std::str;
+
+use options::Buffering;
+
+/// Decodes UTF-8 a piece at a time, holding back an incomplete character at the end of a piece
+/// until the next piece completes it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Utf8Decoder {
+    /// The start of a character, at most three bytes.
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// A decoder with nothing pending.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes the next piece of the stream onto `text`.
+    pub fn decode(&mut self, bytes: &[u8], text: &mut String) {
+        self.pending.extend_from_slice(bytes);
+        let mut rest = &self.pending[..];
+
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(error) => {
+                    let (valid, after) = rest.split_at(error.valid_up_to());
+                    text.push_str(str::from_utf8(valid).unwrap_or_default());
+                    match error.error_len() {
+                        Some(len) => {
+                            text.push('\u{FFFD}');
+                            rest = &after[len ..];
+                        }
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let pending = rest.len();
+        let start = self.pending.len() - pending;
+        self.pending.drain(.. start);
+    }
+
+    /// Ends the stream, decoding any incomplete character left as U+FFFD.
+    pub fn finish(&mut self, text: &mut String) {
+        if !self.pending.is_empty() {
+            self.pending.clear();
+            text.push('\u{FFFD}');
+        }
+    }
+}
+
+/// Output collected in memory, both as bytes and decoded as UTF-8.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Utf8Output {
+    raw: Vec<u8>,
+    text: String,
+    decoder: Utf8Decoder,
+}
+
+impl Utf8Output {
+    /// Empty output.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The bytes written, exactly.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// The text decoded so far, leaving out an incomplete character at the end.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Takes the text decoded since the last call, for showing output as it arrives.
+    pub fn take_text(&mut self) -> String {
+        ::std::mem::take(&mut self.text)
+    }
+
+    /// Ends the output, returning the rest of the text, with any incomplete character at the
+    /// end as U+FFFD, and all the bytes.
+    pub fn finish(mut self) -> (String, Vec<u8>) {
+        self.decoder.finish(&mut self.text);
+        (self.text, self.raw)
+    }
+}
+
+impl Write for Utf8Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.raw.extend_from_slice(buf);
+        self.decoder.decode(buf, &mut self.text);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// A writer that decodes output as UTF-8 before passing it on, so that the other writer only
+/// ever sees valid UTF-8.
+///
+/// Dropping it ends the output, writing any incomplete character left as U+FFFD.
+#[derive(Debug)]
+pub struct Utf8Writer<W: Write> {
+    inner: W,
+    decoder: Utf8Decoder,
+    text: String,
+}
+
+impl<W: Write> Utf8Writer<W> {
+    /// Decodes output on its way to `inner`.
+    pub fn new(inner: W) -> Self {
+        Utf8Writer { inner, decoder: Utf8Decoder::new(), text: String::new() }
+    }
+}
+
+impl<W: Write> Write for Utf8Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.text.clear();
+        self.decoder.decode(buf, &mut self.text);
+        self.inner.write_all(self.text.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Utf8Writer<W> {
+    fn drop(&mut self) {
+        self.text.clear();
+        self.decoder.finish(&mut self.text);
+        let _ = self.inner.write_all(self.text.as_bytes());
+        let _ = self.inner.flush();
+    }
+}
+
+/// A writer that holds output back by a buffering policy before passing it on.
+///
+/// Dropping it writes out what is left.
+#[derive(Debug)]
+pub struct BufferedWriter<W: Write> {
+    inner: W,
+    buffering: Buffering,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BufferedWriter<W> {
+    /// Buffers output on its way to `inner`.
+    pub fn new(inner: W, buffering: Buffering) -> Self {
+        BufferedWriter { inner, buffering, buffer: Vec::new() }
+    }
+
+    /// The buffering policy.
+    pub fn buffering(&self) -> Buffering {
+        self.buffering
+    }
+
+    /// Changes the buffering policy, writing out what is waiting first.
+    pub fn set_buffering(&mut self, buffering: Buffering) -> io::Result<()> {
+        self.write_out(self.buffer.len())?;
+        self.buffering = buffering;
+        Ok(())
+    }
+
+    /// The number of bytes waiting.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Writes out the first `len` bytes waiting.
+    fn write_out(&mut self, len: usize) -> io::Result<()> {
+        if len > 0 {
+            self.inner.write_all(&self.buffer[.. len])?;
+            self.buffer.drain(.. len);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BufferedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.buffering {
+            Buffering::Unbuffered => self.inner.write_all(buf)?,
+            Buffering::Line => {
+                self.buffer.extend_from_slice(buf);
+                if let Some(newline) = buf.iter().rposition(|&byte| byte == b'\n') {
+                    let len = self.buffer.len() - (buf.len() - newline - 1);
+                    self.write_out(len)?;
+                }
+            }
+            Buffering::Block(size) => {
+                self.buffer.extend_from_slice(buf);
+                if self.buffer.len() >= size {
+                    self.write_out(self.buffer.len())?;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_out(self.buffer.len())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BufferedWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// A newline convention.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Newlines {
+    /// `\n` alone, as on Unix.
+    Lf,
+    /// `\r\n`, as on Windows.
+    Crlf,
+}
+
+/// A writer that translates newlines in output to one convention before passing it on.
+///
+/// To `Crlf`, each `\n` not already after a `\r` becomes `\r\n`; to `Lf`, each `\r\n` becomes
+/// `\n`, and other `\r`s are kept. Dropping it writes out a `\r` held back in case a `\n`
+/// followed.
+#[derive(Debug)]
+pub struct NewlineWriter<W: Write> {
+    inner: W,
+    newlines: Newlines,
+    /// Whether the last byte written was `\r`.
+    after_cr: bool,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> NewlineWriter<W> {
+    /// Translates output to `newlines` on its way to `inner`.
+    pub fn new(inner: W, newlines: Newlines) -> Self {
+        NewlineWriter { inner, newlines, after_cr: false, buffer: Vec::new() }
+    }
+}
+
+impl<W: Write> Write for NewlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.clear();
+        for &byte in buf {
+            match self.newlines {
+                Newlines::Crlf => {
+                    if byte == b'\n' && !self.after_cr {
+                        self.buffer.push(b'\r');
+                    }
+                    self.buffer.push(byte);
+                }
+                Newlines::Lf => {
+                    if self.after_cr && byte != b'\n' {
+                        self.buffer.push(b'\r');
+                    }
+                    if byte != b'\r' {
+                        self.buffer.push(byte);
+                    }
+                }
+            }
+            self.after_cr = byte == b'\r';
+        }
+
+        self.inner.write_all(&self.buffer)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for NewlineWriter<W> {
+    fn drop(&mut self) {
+        if self.newlines == Newlines::Lf && self.after_cr {
+            let _ = self.inner.write_all(b"\r");
+        }
+        let _ = self.inner.flush();
+    }
+}
+
+/// A reader that drops every `\r` from its input.
+#[derive(Debug)]
+pub struct StripCr<R: Read>(pub R);
+
+impl<R: Read> Read for StripCr<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.0.read(buf)?;
+            let mut kept = 0;
+            for i in 0 .. len {
+                if buf[i] != b'\r' {
+                    buf[kept] = buf[i];
+                    kept += 1;
+                }
+            }
+            // Reading nothing but `\r`s is not the end of input.
+            if kept > 0 || len == 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+/// Counts the bytes read from or written to the reader or writer it wraps.
+#[derive(Clone, Debug, Default)]
+pub struct Counting<T> {
+    inner: T,
+    count: u64,
+}
+
+impl<T> Counting<T> {
+    /// Wraps a reader or writer, with nothing counted yet.
+    pub fn new(inner: T) -> Self {
+        Counting { inner, count: 0 }
+    }
+
+    /// How many bytes have been read or written.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The wrapped reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_split_across_writes_decode_whole() {
+        let mut output = Utf8Output::new();
+        for &byte in "né €!".as_bytes() {
+            output.write_all(&[byte]).unwrap();
+        }
+        assert_eq!(output.text(), "né €!");
+        assert_eq!(output.raw(), "né €!".as_bytes());
+
+        output.write_all(b"\xe2\x82").unwrap();
+        assert_eq!(output.take_text(), "né €!");
+        let (text, raw) = output.finish();
+        assert_eq!(text, "\u{FFFD}");
+        assert!(raw.ends_with(b"!\xe2\x82"));
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        let bytes = b"a\xffb\xc3(\xe2\x82\xacc";
+        let mut text = Vec::new();
+        Utf8Writer::new(&mut text).write_all(bytes).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap(), String::from_utf8_lossy(bytes));
+    }
+
+    fn translate(newlines: Newlines, pieces: &[&[u8]]) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut writer = NewlineWriter::new(&mut output, newlines);
+            for piece in pieces {
+                writer.write_all(piece).unwrap();
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn newlines_translate_both_ways() {
+        assert_eq!(translate(Newlines::Crlf, &[b"a\nb\r\n", b"\r", b"\nc\n"]),
+                   b"a\r\nb\r\n\r\nc\r\n");
+        assert_eq!(translate(Newlines::Lf, &[b"a\r\nb\r", b"\n\rc\r"]), b"a\nb\n\rc\r");
+
+        let mut input = String::new();
+        StripCr(&b"\r\rone\r\ntwo\r"[..]).read_to_string(&mut input).unwrap();
+        assert_eq!(input, "one\ntwo");
+    }
+
+    #[test]
+    fn output_waits_by_policy() {
+        let written = |buffering, pieces: &[&[u8]]| {
+            let mut output = Vec::new();
+            let mut waiting = Vec::new();
+            {
+                let mut writer = BufferedWriter::new(&mut output, buffering);
+                for piece in pieces {
+                    writer.write_all(piece).unwrap();
+                }
+                waiting.extend_from_slice(&writer.buffer);
+            }
+            (output, waiting)
+        };
+
+        let pieces: &[&[u8]] = &[b"a", b"b\nc", b"d"];
+        let (output, waiting) = written(Buffering::Unbuffered, pieces);
+        assert_eq!((&output[..], &waiting[..]), (&b"ab\ncd"[..], &b""[..]));
+        let (output, waiting) = written(Buffering::Line, pieces);
+        assert_eq!((&output[..], &waiting[..]), (&b"ab\ncd"[..], &b"cd"[..]));
+        let (output, waiting) = written(Buffering::Block(3), pieces);
+        assert_eq!((&output[..], &waiting[..]), (&b"ab\ncd"[..], &b"d"[..]));
+    }
+}
@@ -0,0 +1,218 @@
+//! Tests of the bounds analysis written as annotated Brainfuck.
+//!
+//! A comment `/* marks: left=3,right=0 */` between two commands says what the marks must be
+//! there, as the compiler would see them just before the next instruction, and one at the end
+//! of a loop body says what they are when the iteration ends. Either mark may be left out. A
+//! comment `/* assume: left=2,right=0 */` raises the marks there instead, as a run-time check
+//! does, which is how a test starts the program somewhere other than the ends of memory.
+//!
+//! [`check`](fn.check.html) runs the analysis over the program as the compiler does and fails
+//! with every annotation that does not hold, so that the analysis can change with its tests
+//! saying what changed.
+
+use super::{AbstractInterpreter, BoundsAnalysis};
+use ast;
+use common::Instruction;
+use peephole::{Program, Statement};
+use source_map::{line_column, SourceMap};
+use traits::PeepholeCompilable;
+
+/// What an annotation says.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Marks,
+    Assume,
+}
+
+/// An annotation, with where it was found.
+#[derive(Clone, Debug)]
+struct Annotation {
+    kind: Kind,
+    left: Option<usize>,
+    right: Option<usize>,
+    /// The offset in the program with the annotations taken out.
+    offset: usize,
+    /// The line and column in the annotated source.
+    location: (usize, usize),
+}
+
+/// Checks the annotations in `annotated` against the analysis, panicking with those that fail.
+pub fn check(annotated: &str) {
+    let (source, annotations) = parse(annotated);
+    let program = ast::parse_program(&source).unwrap().peephole_compile();
+
+    let mut checker = Checker {
+        map: SourceMap::new(&source, &program),
+        source: &source,
+        interpreter: AbstractInterpreter::new(&program),
+        annotations,
+        next: 0,
+        pc: 0,
+        failures: Vec::new(),
+    };
+    checker.walk(&program);
+    checker.reach(usize::MAX);
+
+    if !checker.failures.is_empty() {
+        panic!("annotations that do not hold:\n{}", checker.failures.join("\n"));
+    }
+}
+
+/// Takes the annotations out of the source.
+fn parse(annotated: &str) -> (Vec<u8>, Vec<Annotation>) {
+    let (mut source, mut annotations) = (Vec::new(), Vec::new());
+    let mut rest = annotated;
+
+    while let Some(start) = rest.find("/*") {
+        source.extend_from_slice(&rest.as_bytes()[.. start]);
+        let consumed = annotated.len() - rest.len() + start;
+        let end = rest[start ..].find("*/").expect("unterminated annotation") + start;
+        annotations.push(annotation(rest[start + 2 .. end].trim(), source.len(),
+                                    line_column(annotated.as_bytes(), consumed)));
+        rest = &rest[end + 2 ..];
+    }
+    source.extend_from_slice(rest.as_bytes());
+
+    (source, annotations)
+}
+
+/// Parses one annotation, such as `marks: left=3,right=0`.
+fn annotation(text: &str, offset: usize, location: (usize, usize)) -> Annotation {
+    let (kind, fields) = if let Some(fields) = text.strip_prefix("marks:") {
+        (Kind::Marks, fields)
+    } else if let Some(fields) = text.strip_prefix("assume:") {
+        (Kind::Assume, fields)
+    } else {
+        bad(text, location)
+    };
+
+    let mut result = Annotation { kind, left: None, right: None, offset, location };
+    for field in fields.split(',') {
+        let mut parts = field.trim().splitn(2, '=');
+        let (name, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let value = value.trim().parse::<usize>().unwrap_or_else(|_| bad(text, location));
+        match name.trim() {
+            "left" => result.left = Some(value),
+            "right" => result.right = Some(value),
+            _ => bad(text, location),
+        }
+    }
+
+    result
+}
+
+fn bad<T>(text: &str, (line, column): (usize, usize)) -> T {
+    panic!("bad annotation at {}:{}: {}", line, column, text)
+}
+
+struct Checker<'a> {
+    map: SourceMap,
+    source: &'a [u8],
+    interpreter: AbstractInterpreter,
+    annotations: Vec<Annotation>,
+    /// The index of the first annotation not yet reached.
+    next: usize,
+    pc: usize,
+    failures: Vec<String>,
+}
+
+impl<'a> Checker<'a> {
+    /// Walks the program as the compiler does, in preorder.
+    fn walk(&mut self, program: &Program) {
+        for statement in program {
+            let span = self.map.span(self.pc).expect("instruction without a span");
+            self.reach(span.start);
+            if let Some(annotation) = self.annotations.get(self.next) {
+                let within = self.source.get(annotation.offset .. span.end).unwrap_or(&[]);
+                if within.iter().any(|c| b"<>+-.,[]".contains(c)) &&
+                    !matches!(*statement, Statement::Loop(_)) {
+                    let (line, column) = annotation.location;
+                    panic!("annotation at {}:{} is inside an instruction", line, column);
+                }
+            }
+
+            match *statement {
+                Statement::Instr(Instruction::Right(count)) => {
+                    self.interpreter.move_right(count);
+                }
+                Statement::Instr(Instruction::Left(count)) => {
+                    self.interpreter.move_left(count);
+                }
+                Statement::Instr(Instruction::FindZeroRight(_)) |
+                Statement::Instr(Instruction::IndexRight(_)) => self.interpreter.reset_right(),
+                Statement::Instr(Instruction::FindZeroLeft(_)) |
+                Statement::Instr(Instruction::IndexLeft(_)) => self.interpreter.reset_left(),
+                Statement::Instr(_) => (),
+
+                Statement::Loop(ref body) => {
+                    self.interpreter.enter_loop(body);
+                    self.pc += 1;
+                    self.walk(body);
+                    let end = self.map.span(self.pc).expect("loop end without a span");
+                    self.reach(end.start);
+                    self.interpreter.leave_loop();
+                }
+            }
+
+            self.pc += 1;
+        }
+    }
+
+    /// Applies the annotations before source offset `offset`.
+    fn reach(&mut self, offset: usize) {
+        while let Some(annotation) = self.annotations.get(self.next).cloned() {
+            if annotation.offset > offset {
+                break;
+            }
+            self.next += 1;
+
+            let interpreter = &mut self.interpreter;
+            match annotation.kind {
+                Kind::Assume => interpreter.assume(annotation.left.unwrap_or(0),
+                                                   annotation.right.unwrap_or(0)),
+                Kind::Marks => {
+                    let found = (interpreter.left_mark, interpreter.right_mark);
+                    let expected = (annotation.left.unwrap_or(found.0),
+                                    annotation.right.unwrap_or(found.1));
+                    if found != expected {
+                        let (line, column) = annotation.location;
+                        self.failures.push(format!(
+                            "{}:{}: expected left={},right={}, found left={},right={}",
+                            line, column, expected.0, expected.1, found.0, found.1));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_are_annotated_where_they_end() {
+        check(">> /* marks: left=2,right=0 */ < + /* marks: left=1,right=1 */ \
+               << /* marks: left=0,right=3 */");
+        check("/* assume: left=4 */ <<< /* marks: left=1 */ [-] /* marks: left=1,right=3 */");
+    }
+
+    #[test]
+    fn loops_are_annotated_at_their_heads_and_ends() {
+        check("/* assume: left=2 */ [ /* marks: left=2 */ <<[<]>>> /* marks: left=3 */ ] \
+               /* marks: left=2,right=0 */");
+        check("/* assume: right=5 */ [>.] /* marks: right=0 */");
+    }
+
+    #[test]
+    #[should_panic(expected = "annotation at 1:2 is inside an instruction")]
+    fn annotations_inside_runs_fail() {
+        check("</* marks: left=0 */<");
+    }
+
+    #[test]
+    #[should_panic(expected = "1:5: expected left=1,right=0, found left=2,right=0")]
+    fn annotations_that_do_not_hold_fail() {
+        check(">>  /* marks: left=1 */");
+    }
+}
@@ -0,0 +1,218 @@
+//! Loop movement balance analysis.
+//!
+//! This analysis determines, for the body of a loop, the least and greatest net movement of one
+//! iteration. This is used by the bound checking analysis when it encounters loops: a loop whose
+//! iterations never move net left can keep the distance from the left end, and likewise for the
+//! right. Tracking the amounts, rather than only the directions, keeps a loop such as
+//! `[>>[<]<<<]` from being lost: it steps right before its scan left, but back by more after.
+
+use std::fmt;
+use std::sync::Arc;
+
+use peephole::{Statement, Program};
+
+/// The body of a loop is a shared slice of `Statement`s.
+pub type LoopBody = Arc<[Statement]>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// The net movement of one iteration of a loop, as an interval of displacements.
+///
+/// A bound of `None` is unbounded, as after a scan. The four ways a loop can move, which are
+/// what the bounds analysis needs, follow as [`is_balanced`](#method.is_balanced),
+/// [`is_right_only`](#method.is_right_only) and [`is_left_only`](#method.is_left_only); the
+/// interval also says how far, as for a loop that scans right and then steps back one cell,
+/// which moves right by an unknown amount or left by at most one.
+pub struct LoopBalance {
+    /// The least net displacement, if bounded.
+    pub min: Option<isize>,
+    /// The greatest net displacement, if bounded.
+    pub max: Option<isize>,
+}
+
+/// The computed net movement for each loop.
+///
+/// Loops are numbered in preorder—the order in which the compiler encounters them—rather than
+/// by address, so that the analysis does not depend on where the program was allocated.
+#[derive(Debug)]
+pub struct LoopBalanceMap(Vec<LoopBalance>);
+
+impl LoopBalance {
+    /// Movement that may be any amount either way.
+    pub const UNKNOWN: LoopBalance = LoopBalance { min: None, max: None };
+
+    /// Movement of exactly `disp`.
+    pub fn exact(disp: isize) -> Self {
+        LoopBalance { min: Some(disp), max: Some(disp) }
+    }
+
+    /// The displacement, if it is exact.
+    pub fn exact_disp(self) -> Option<isize> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min == max => Some(min),
+            _ => None,
+        }
+    }
+
+    /// Is the loop body exactly balanced between right and left?
+    pub fn is_balanced(self) -> bool {
+        self.exact_disp() == Some(0)
+    }
+
+    /// Does the loop move net right (if at all)?
+    pub fn is_right_only(self) -> bool {
+        self.min.is_some_and(|min| min >= 0)
+    }
+
+    /// Does the loop move net left (if at all)?
+    pub fn is_left_only(self) -> bool {
+        self.max.is_some_and(|max| max <= 0)
+    }
+
+    /// May the loop move net either way?
+    pub fn is_unknown(self) -> bool {
+        !self.is_right_only() && !self.is_left_only()
+    }
+
+    /// The movement of `self` followed by `other`.
+    pub fn then(self, other: LoopBalance) -> Self {
+        let add = |a: Option<isize>, b: Option<isize>| a.and_then(|a| a.checked_add(b?));
+        LoopBalance { min: add(self.min, other.min), max: add(self.max, other.max) }
+    }
+
+    /// The movement of any number of iterations of a loop moving `self` each, including none.
+    pub fn repeated(self) -> Self {
+        LoopBalance {
+            min: self.min.filter(|&min| min >= 0).map(|_| 0),
+            max: self.max.filter(|&max| max <= 0).map(|_| 0),
+        }
+    }
+}
+
+impl fmt::Display for LoopBalance {
+    /// A phrase for the movement, such as `balanced` or `moves right by an unknown amount`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(0), Some(0)) => write!(f, "balanced"),
+            (Some(min), Some(max)) if min == max => write!(f, "moves {:+} per iteration", min),
+            (Some(min), Some(max)) => write!(f, "moves between {:+} and {:+}", min, max),
+            (Some(0), None) => write!(f, "moves right by an unknown amount"),
+            (None, Some(0)) => write!(f, "moves left by an unknown amount"),
+            (Some(min), None) if min > 0 => write!(f, "moves right by at least {}", min),
+            (None, Some(max)) if max < 0 => write!(f, "moves left by at least {}", -max),
+            (Some(min), None) =>
+                write!(f, "moves right by an unknown amount or left by at most {}", -min),
+            (None, Some(max)) =>
+                write!(f, "moves left by an unknown amount or right by at most {}", max),
+            (None, None) => write!(f, "moves an unknown amount either way"),
+        }
+    }
+}
+
+impl LoopBalanceMap {
+    /// Initializes the map for the given program.
+    pub fn new(program: &Program) -> Self {
+        let mut result = LoopBalanceMap(Vec::new());
+
+        for statement in program {
+            match *statement {
+                Statement::Instr(_) => (),
+                Statement::Loop(ref body) => {
+                    result.analyze_loop(body);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The number of loops.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the program has no loops.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The balance of each loop, in preorder.
+    pub fn iter(&self) -> impl Iterator<Item = LoopBalance> + '_ {
+        self.0.iter().cloned()
+    }
+
+    /// Gets the balance of the loop with the given preorder index.
+    pub fn get(&self, index: usize) -> LoopBalance {
+        *self.0.get(index).unwrap_or(&LoopBalance::UNKNOWN)
+    }
+
+    /// Performs the analysis for the given loop body and any sub-loops.
+    ///
+    /// Stores the result of the analysis in `self`.
+    fn analyze_loop(&mut self, body: &LoopBody) -> LoopBalance {
+        use peephole::Statement::*;
+        use common::Instruction::*;
+
+        let index = self.0.len();
+        self.0.push(LoopBalance::UNKNOWN);
+
+        let right = LoopBalance { min: Some(0), max: None };
+        let left = LoopBalance { min: None, max: Some(0) };
+        let mut net = LoopBalance::exact(0);
+
+        for statement in &**body {
+            match *statement {
+                Instr(Right(count)) => net = net.then(LoopBalance::exact(count as isize)),
+
+                Instr(Left(count)) => net = net.then(LoopBalance::exact(-(count as isize))),
+
+                Instr(Add(_)) | Instr(In) | Instr(Out) |
+                Instr(SetZero) | Instr(OffsetAddRight(_)) | Instr(OffsetAddLeft(_)) |
+                Instr(DivMod) => (),
+
+                Instr(JumpZero(_)) | Instr(JumpNotZero(_)) =>
+                    panic!("unexpected jump instruction"),
+
+                Instr(FindZeroRight(_)) | Instr(IndexRight(_)) => net = net.then(right),
+
+                Instr(FindZeroLeft(_)) | Instr(IndexLeft(_)) => net = net.then(left),
+
+                Loop(ref body) => net = net.then(self.analyze_loop(body).repeated()),
+            }
+        }
+
+        self.0[index] = net;
+
+        net
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::PeepholeCompilable;
+
+    fn balances(source: &[u8]) -> Vec<LoopBalance> {
+        LoopBalanceMap::new(&::ast::parse_program(source).unwrap().peephole_compile()).iter()
+            .collect()
+    }
+
+    #[test]
+    fn moves_and_scans_bound_the_interval() {
+        let bounded = |min, max| LoopBalance { min, max };
+        assert_eq!(balances(b"[->>+<]"), [LoopBalance::exact(1)]);
+        assert_eq!(balances(b"[[>]<]"), [bounded(Some(-1), None)]);
+        assert_eq!(balances(b"[>>[<]<<<]"), [bounded(None, Some(-1))]);
+        assert_eq!(balances(b"[>[-<]>]"), [bounded(None, Some(2)), LoopBalance::exact(-1)]);
+        assert_eq!(balances(b"[[>]<[<]]"), [LoopBalance::UNKNOWN]);
+    }
+
+    #[test]
+    fn directions_follow_from_the_bounds() {
+        let balance = balances(b"[>>[<]<<<]")[0];
+        assert!(balance.is_left_only() && !balance.is_right_only());
+        assert_eq!(balance.to_string(), "moves left by at least 1");
+        assert_eq!(balances(b"[[>]<]")[0].to_string(),
+                   "moves right by an unknown amount or left by at most 1");
+        assert!(balances(b"[>[-<]>]")[0].is_unknown());
+    }
+}
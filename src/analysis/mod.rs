@@ -0,0 +1,508 @@
+//! Bounds checking analysis for the native code generators.
+//!
+//! Tracks how far the pointer is known to be from each end of memory, so that the
+//! [JIT](../jit/index.html) and the [assembly emitter](../asm/index.html) can leave out bounds
+//! checks they can prove unnecessary.
+
+pub mod loop_balance;
+#[cfg(test)]
+mod annotations;
+
+use std::collections::HashMap;
+
+use self::loop_balance::LoopBalanceMap;
+use common::{Count, Instruction};
+use traits::IntoUsize;
+use peephole::{Statement, Program};
+
+/// Interface for bounds checking analysis.
+///
+/// We use two implementations of this trait to specialize the compiler for checked versus
+/// unchecked mode. The [impl for checked mode](struct.AbstractInterpreter.html) actually does the
+/// analysis, whereas the [impl for unchecked mode](struct.NoAnalysis.html) is all no-ops.
+pub trait BoundsAnalysis {
+    /// Creates a new analyzer for the given program.
+    fn new(program: &Program) -> Self;
+
+    /// Moves the pointer the given distance to the left.
+    ///
+    /// Returns whether we can prove that this move will not underflow.
+    fn move_left(&mut self, count: Count) -> bool;
+
+    /// Moves the pointer the given distance to the right.
+    ///
+    /// Returns whether we can prove that this move will not overflow.
+    fn move_right(&mut self, count: Count) -> bool;
+
+    /// Checks whether the pointer can safely move the given distance to the left.
+    fn check_left(&self, count: Count) -> bool;
+
+    /// Checks whether the pointer can safely move the given distance to the right.
+    fn check_right(&self, count: Count) -> bool;
+
+    /// Resets the left mark.
+    ///
+    /// This is used when we may move an arbitrary distance to the left.
+    fn reset_left(&mut self);
+
+    /// Resets the right mark.
+    ///
+    /// This is used when we may move an arbitrary distance to the right.
+    fn reset_right(&mut self);
+
+    /// Updates the marks upon entering a loop.
+    fn enter_loop(&mut self, body: &[Statement]);
+
+    /// Updates the marks upon leaving a loop.
+    fn leave_loop(&mut self);
+
+    /// Records that the pointer is at least `left` cells from the bottom of memory and
+    /// `right` from the top, as established by a run-time check.
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    fn assume(&mut self, left: usize, right: usize);
+}
+
+/// Abstract interpreter that tracks an abstraction of the pointer position.
+///
+/// In particular, it tracks the minimum distances from each end of memory. This can be used to
+/// prove some bounds checks unnecessary.
+///
+/// On entering a loop, the marks become ones that hold at the start of every iteration. They
+/// are found by running the body over the marks until they settle, once for each loop and
+/// distinct marks it is entered with, and are kept where the loop’s balance already proves
+/// them.
+#[derive(Debug)]
+pub struct AbstractInterpreter {
+    /// The minimum distance from the bottom of memory.
+    left_mark: usize,
+    /// The minimum distance from the top of memory.
+    right_mark: usize,
+    /// The marks to restore when leaving a loop.
+    loop_stack: Vec<(usize, usize)>,
+    /// The computed net movement for each loop.
+    loop_balances: LoopBalanceMap,
+    /// The preorder index of the next loop to be entered.
+    next_loop: usize,
+    /// The marks at the start of each iteration, by loop index and marks on entry.
+    loop_heads: HashMap<(usize, (usize, usize)), (usize, usize)>,
+}
+
+/// How many loop entry contexts to analyze before falling back on loop balances alone.
+const MAX_LOOP_HEADS: usize = 1 << 16;
+
+impl BoundsAnalysis for AbstractInterpreter {
+    /// Initialize the interpreter with the body of the program.
+    ///
+    /// The interpreter initially analyzes the program for loop balances, but only if we're doing
+    /// bounds checking in the first place. (There's no point in doing the analysis if we're not
+    /// going to use it.)
+    fn new(program: &Program) -> Self {
+        AbstractInterpreter {
+            left_mark: 0,
+            right_mark: 0,
+            loop_stack: Vec::new(),
+            loop_balances: LoopBalanceMap::new(program),
+            next_loop: 0,
+            loop_heads: HashMap::new(),
+        }
+    }
+
+    /// Moves the pointer the given distance to the left.
+    ///
+    /// Returns whether we can prove that this move will not underflow.
+    fn move_left(&mut self, count: Count) -> bool {
+        let count = count.into_usize();
+
+        self.right_mark += count;
+        if count <= self.left_mark {
+            self.left_mark -= count;
+            true
+        } else {
+            self.left_mark = 0;
+            false
+        }
+    }
+
+    /// Moves the pointer the given distance to the right.
+    ///
+    /// Returns whether we can prove that this move will not overflow.
+    fn move_right(&mut self, count: Count) -> bool {
+        let count = count.into_usize();
+
+        self.left_mark += count;
+        if count <= self.right_mark {
+            self.right_mark -= count;
+            true
+        } else {
+            self.right_mark = 0;
+            false
+        }
+    }
+
+    fn check_left(&self, count: Count) -> bool {
+        count.into_usize() <= self.left_mark
+    }
+
+    fn check_right(&self, count: Count) -> bool {
+        count.into_usize() <= self.right_mark
+    }
+
+    /// Resets the left mark.
+    ///
+    /// This is used when we may move an arbitrary distance to the left.
+    fn reset_left(&mut self) {
+        self.left_mark = 0;
+    }
+
+    /// Resets the right mark.
+    ///
+    /// This is used when we may move an arbitrary distance to the right.
+    fn reset_right(&mut self) {
+        self.right_mark = 0;
+    }
+
+    /// Updates the marks upon entering a loop, to ones that hold at the start of every
+    /// iteration.
+    ///
+    /// Loops must be entered in preorder, as the compiler does.
+    fn enter_loop(&mut self, body: &[Statement]) {
+        let index = self.next_loop;
+        self.next_loop += 1;
+
+        let entry = (self.left_mark, self.right_mark);
+        let (left_mark, right_mark) = self.loop_head(index, body, entry);
+        self.left_mark = left_mark;
+        self.right_mark = right_mark;
+
+        self.loop_stack.push((left_mark, right_mark));
+    }
+
+    /// Updates the marks upon leaving a loop.
+    fn leave_loop(&mut self) {
+        let (left_mark, right_mark) = self.loop_stack.pop()
+            .expect("got exit_loop without matching enter_loop");
+        self.left_mark = left_mark;
+        self.right_mark = right_mark;
+    }
+
+    fn assume(&mut self, left: usize, right: usize) {
+        self.left_mark = self.left_mark.max(left);
+        self.right_mark = self.right_mark.max(right);
+    }
+}
+
+impl AbstractInterpreter {
+    /// The minimum distance of the pointer from the bottom of memory, so far as it is known.
+    pub fn left_mark(&self) -> usize {
+        self.left_mark
+    }
+
+    /// The marks that hold at the start of every iteration of loop `index`, entered with the
+    /// marks `entry`.
+    ///
+    /// Each round runs the body from the marks so far and lowers them to the marks it ends
+    /// with. A mark that goes down a second time would likely keep going, so it drops to zero,
+    /// which settles within a few rounds.
+    fn loop_head(&mut self, index: usize, body: &[Statement], entry: (usize, usize))
+                 -> (usize, usize) {
+        let balance = self.loop_balances.get(index);
+        let by_balance = (if balance.is_right_only() { entry.0 } else { 0 },
+                          if balance.is_left_only() { entry.1 } else { 0 });
+
+        if let Some(&head) = self.loop_heads.get(&(index, entry)) {
+            return head;
+        }
+        if self.loop_heads.len() >= MAX_LOOP_HEADS {
+            return by_balance;
+        }
+
+        let lower = |mark: usize, end: usize, lowered: &mut bool| {
+            if end >= mark {
+                mark
+            } else if !*lowered {
+                *lowered = true;
+                end
+            } else {
+                0
+            }
+        };
+
+        let (mut head, mut lowered) = (entry, (false, false));
+        loop {
+            let end = self.run_body(body, index + 1, head);
+            let next = (lower(head.0, end.0, &mut lowered.0), lower(head.1, end.1, &mut lowered.1));
+            if next == head {
+                break;
+            }
+            head = next;
+        }
+
+        let head = (head.0.max(by_balance.0), head.1.max(by_balance.1));
+        self.loop_heads.insert((index, entry), head);
+        head
+    }
+
+    /// The marks after running `body` from `marks`, where `index` is the preorder index of the
+    /// first loop in `body`.
+    fn run_body(&mut self, body: &[Statement], mut index: usize, marks: (usize, usize))
+                -> (usize, usize) {
+        use common::Instruction::*;
+
+        let (mut left, mut right) = marks;
+        for statement in body {
+            match *statement {
+                Statement::Instr(Right(count)) => {
+                    left += count.into_usize();
+                    right = right.saturating_sub(count.into_usize());
+                }
+                Statement::Instr(Left(count)) => {
+                    right += count.into_usize();
+                    left = left.saturating_sub(count.into_usize());
+                }
+                Statement::Instr(FindZeroRight(_)) | Statement::Instr(IndexRight(_)) => right = 0,
+                Statement::Instr(FindZeroLeft(_)) | Statement::Instr(IndexLeft(_)) => left = 0,
+                Statement::Instr(_) => (),
+
+                Statement::Loop(ref inner) => {
+                    let head = self.loop_head(index, inner, (left, right));
+                    left = head.0;
+                    right = head.1;
+                    index += 1 + loop_count(inner);
+                }
+            }
+        }
+
+        (left, right)
+    }
+}
+
+/// The number of loops in `program`, counting nested ones.
+fn loop_count(program: &[Statement]) -> usize {
+    program.iter()
+        .map(|statement| match *statement {
+            Statement::Loop(ref body) => 1 + loop_count(body),
+            Statement::Instr(_) => 0,
+        })
+        .sum()
+}
+
+/// Facts about a whole program, for sizing its tape.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProgramAnalysis {
+    max_cells: Option<usize>,
+}
+
+impl ProgramAnalysis {
+    /// Analyzes the program.
+    pub fn new(program: &Program) -> Self {
+        let mut reach = 0;
+        let max_cells = walk_right(program, 0, &mut reach).map(|_| reach as usize + 1);
+        ProgramAnalysis { max_cells }
+    }
+
+    /// The number of cells the program can reach, counting from the start of the tape, if its
+    /// travel to the right is bounded.
+    ///
+    /// It is bounded when the program has no scans or index loops to the right, and every
+    /// loop’s body ends no further right than it starts, so that each iteration starts no
+    /// further right than the first. A tape of this many cells runs the program just as a
+    /// longer one does.
+    pub fn max_cells(&self) -> Option<usize> {
+        self.max_cells
+    }
+}
+
+/// Walks statements starting at most `offset` cells from the start of the tape, extending
+/// `reach` with the furthest offset they can access. Returns how far right they can end, or
+/// `None` if there is no bound.
+fn walk_right(program: &[Statement], mut offset: isize, reach: &mut isize) -> Option<isize> {
+    use common::Instruction::*;
+
+    for statement in program {
+        let access = match *statement {
+            Statement::Instr(Right(count)) => { offset += count.into_usize() as isize; offset }
+            Statement::Instr(Left(count)) => { offset -= count.into_usize() as isize; offset }
+            Statement::Instr(OffsetAddRight(count)) => offset + count.into_usize() as isize,
+            Statement::Instr(FindZeroRight(_)) | Statement::Instr(IndexRight(_)) => return None,
+            Statement::Instr(_) => offset,
+
+            Statement::Loop(ref body) => {
+                if walk_right(body, offset, reach)? > offset {
+                    return None;
+                }
+                offset
+            }
+        };
+        *reach = (*reach).max(access);
+    }
+
+    Some(offset)
+}
+
+/// No-op implementation of `BoundsAnalysis`.
+///
+/// Tracks no information, and returns `false` (not proved) for moves.
+pub struct NoAnalysis;
+
+impl BoundsAnalysis for NoAnalysis {
+    fn new(_program: &Program) -> Self { NoAnalysis }
+    fn move_left(&mut self, _count: Count) -> bool { false }
+    fn move_right(&mut self, _count: Count) -> bool { false }
+    fn check_left(&self, _count: Count) -> bool { false }
+    fn check_right(&self, _count: Count) -> bool { false }
+    fn reset_left(&mut self) { }
+    fn reset_right(&mut self) { }
+    fn enter_loop(&mut self, _body: &[Statement]) { }
+    fn leave_loop(&mut self) { }
+    fn assume(&mut self, _left: usize, _right: usize) { }
+}
+
+/// How far left and right of where it starts one iteration of a loop body can take the
+/// pointer, or anything it accesses.
+///
+/// Only the part of the body before the first scan or unbalanced inner loop counts, since
+/// the analysis loses track of the pointer there anyway.
+#[cfg_attr(not(feature = "jit"), allow(dead_code))]
+pub fn excursion(body: &Program) -> (usize, usize) {
+    let (mut offset, mut min, mut max) = (0, 0, 0);
+    walk_excursion(body, &mut offset, &mut min, &mut max);
+    (min.unsigned_abs(), max as usize)
+}
+
+/// Extends `min` and `max` with the offsets that `body` reaches starting from `offset`,
+/// returning whether it kept track of the pointer to the end.
+fn walk_excursion(body: &Program, offset: &mut isize, min: &mut isize, max: &mut isize) -> bool {
+    use common::Instruction::*;
+
+    for statement in body {
+        match *statement {
+            Statement::Instr(instruction) => {
+                let reach = match instruction {
+                    Right(count) => { *offset += count.into_usize() as isize; *offset }
+                    Left(count) => { *offset -= count.into_usize() as isize; *offset }
+                    OffsetAddRight(count) => *offset + count.into_usize() as isize,
+                    OffsetAddLeft(count) => *offset - count.into_usize() as isize,
+                    FindZeroRight(_) | FindZeroLeft(_) | IndexRight(_) | IndexLeft(_) =>
+                        return false,
+                    Instruction::Add(_) | In | Out | SetZero | DivMod | JumpZero(_) |
+                    JumpNotZero(_) => *offset,
+                };
+                *min = (*min).min(reach);
+                *max = (*max).max(reach);
+            }
+
+            Statement::Loop(ref inner) => {
+                let start = *offset;
+                if !walk_excursion(inner, offset, min, max) || *offset != start {
+                    return false;
+                }
+            }
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::PeepholeCompilable;
+
+    fn body_excursion(source: &[u8]) -> (usize, usize) {
+        let program = ::ast::parse_program(source).unwrap().peephole_compile();
+        match program[0] {
+            Statement::Loop(ref body) => excursion(body),
+            _ => panic!("not a loop"),
+        }
+    }
+
+    #[test]
+    fn excursions_cover_moves_and_offset_adds() {
+        assert_eq!(body_excursion(b"[->>+<]"), (0, 2));
+        assert_eq!(body_excursion(b"[<<<->>>>-]"), (3, 1));
+        assert_eq!(body_excursion(b"[[->+<]<]"), (1, 1));
+        assert_eq!(body_excursion(b"[>[>>-<<-]<-]"), (0, 3));
+    }
+
+    #[test]
+    fn excursions_stop_where_the_pointer_is_lost() {
+        assert_eq!(body_excursion(b"[>[>]>>>]"), (0, 1));
+        assert_eq!(body_excursion(b"[<[<->>]<<<<]"), (2, 0));
+    }
+
+    #[test]
+    fn max_cells_bound_travel_to_the_right() {
+        let max_cells = |source: &[u8]| {
+            ProgramAnalysis::new(&::ast::parse_program(source).unwrap().peephole_compile())
+                .max_cells()
+        };
+        assert_eq!(max_cells(b""), Some(1));
+        assert_eq!(max_cells(b">>+[-<+>]<."), Some(3));
+        assert_eq!(max_cells(b">>>>+[-[->>+<<]<]"), Some(7));
+        assert_eq!(max_cells(b">+[>[<<]<]>>"), Some(4));
+        assert_eq!(max_cells(b"+[>+]"), None);
+        assert_eq!(max_cells(b"+[>]"), None);
+    }
+
+    #[test]
+    fn assumptions_prove_moves() {
+        let program = ::ast::parse_program(b"+").unwrap().peephole_compile();
+        let mut interpreter = AbstractInterpreter::new(&program);
+        assert!(!interpreter.check_left(2));
+        interpreter.assume(2, 0);
+        assert!(interpreter.move_left(2));
+        assert!(!interpreter.move_left(1));
+    }
+
+    #[test]
+    fn loops_that_end_up_left_keep_the_right_mark() {
+        // The scan could go anywhere left, but every iteration ends left of where it began.
+        let program = ::ast::parse_program(b"[>>[<]<<<]").unwrap().peephole_compile();
+        let mut interpreter = AbstractInterpreter::new(&program);
+        interpreter.assume(0, 2);
+        interpreter.enter_loop(&[]);
+        assert!(!interpreter.check_left(1));
+        assert!(interpreter.move_right(2));
+    }
+
+    #[test]
+    fn loops_keep_the_marks_every_iteration_starts_with() {
+        // Each iteration scans left but then moves right past where it started, so it can
+        // always step two left at the start.
+        let program = ::ast::parse_program(b"[<<[<]>>>]").unwrap().peephole_compile();
+        let mut interpreter = AbstractInterpreter::new(&program);
+        interpreter.assume(2, 0);
+        interpreter.enter_loop(&program_body(&program));
+        assert!(interpreter.move_left(2));
+        assert!(!interpreter.check_left(1));
+
+        // Moving right every iteration wears the right mark down to nothing.
+        let program = ::ast::parse_program(b"[>[-]]").unwrap().peephole_compile();
+        let mut interpreter = AbstractInterpreter::new(&program);
+        interpreter.assume(0, 5);
+        interpreter.enter_loop(&program_body(&program));
+        assert!(!interpreter.check_right(1));
+    }
+
+    #[test]
+    fn nested_loops_are_analyzed_in_the_context_they_are_entered_in() {
+        let program = ::ast::parse_program(b"[<<[<]>>>[<<[<]>>>]]").unwrap().peephole_compile();
+        let mut interpreter = AbstractInterpreter::new(&program);
+        interpreter.assume(2, 0);
+        let body = program_body(&program);
+        interpreter.enter_loop(&body);
+        assert!(interpreter.move_left(2));
+        interpreter.reset_left();
+        interpreter.move_right(3);
+        interpreter.enter_loop(&program_body(&body[3..]));
+        assert!(interpreter.move_left(2));
+        assert!(interpreter.loop_heads.contains_key(&(1, (3, 0))));
+    }
+
+    fn program_body(program: &Program) -> Vec<Statement> {
+        match program[0] {
+            Statement::Loop(ref body) => body.to_vec(),
+            _ => panic!("not a loop"),
+        }
+    }
+}
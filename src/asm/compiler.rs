@@ -0,0 +1,439 @@
+use super::Syntax;
+use analysis::{AbstractInterpreter, BoundsAnalysis, NoAnalysis};
+use common::Count;
+use options::CompileOptions;
+use peephole;
+use rts;
+use state::DEFAULT_CAPACITY;
+
+/// Program forms that can be compiled to assembly source.
+pub trait AsmCompilable {
+    /// Compile the given program into the peephole AST to prepare for code generation.
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R;
+
+    /// Compile the given program to x86-64 assembly source in the given syntax.
+    fn asm_compile(&self, memory_size: Option<usize>, syntax: Syntax, options: &CompileOptions)
+                   -> String {
+        self.with_peephole(|program| compile(program, memory_size, syntax, options))
+    }
+}
+
+/// Compiles peephole-optimized AST to x86-64 assembly source, including the run-time system.
+///
+/// The tape has `memory_size` cells. Of the options, only `checked` applies.
+pub fn compile(program: &peephole::Program, memory_size: Option<usize>, syntax: Syntax,
+               options: &CompileOptions) -> String {
+    let memory_size = memory_size.unwrap_or(DEFAULT_CAPACITY);
+
+    if options.checked {
+        let mut compiler = Compiler::<AbstractInterpreter>::new(program, syntax, true);
+        compiler.compile(program);
+        compiler.into_source(memory_size)
+    } else {
+        let mut compiler = Compiler::<NoAnalysis>::new(program, syntax, false);
+        compiler.compile(program);
+        compiler.into_source(memory_size)
+    }
+}
+
+/// An instruction operand.
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    /// A 64- or 8-bit register.
+    Reg(&'static str),
+    /// An immediate value.
+    Imm(i64),
+    /// The byte at the address in the first register, plus the second if present.
+    Byte(&'static str, Option<&'static str>),
+    /// The address of a symbol, relative to the instruction pointer.
+    Rip(&'static str),
+}
+
+use self::Operand::*;
+
+const POINTER: Operand   = Reg("r12");
+const MEM_START: Operand = Reg("r13");
+const MEM_LIMIT: Operand = Reg("r14");
+
+const AT_POINTER: Operand = Byte("r12", None);
+const AT_OFFSET: Operand  = Byte("r12", Some("rax"));
+
+/// The compiler state.
+struct Compiler<B: BoundsAnalysis> {
+    /// The assembly source so far.
+    out: String,
+    /// The syntax to emit.
+    syntax: Syntax,
+    /// Whether we are emitting bounds checks.
+    checked: bool,
+    /// The number of local labels allocated so far.
+    labels: usize,
+    /// Abstract interpreter for bounds checking analysis.
+    interpreter: B,
+}
+
+impl<B: BoundsAnalysis> Compiler<B> {
+    fn new(program: &peephole::Program, syntax: Syntax, checked: bool) -> Self {
+        let mut result = Compiler {
+            out: String::new(),
+            syntax,
+            checked,
+            labels: 0,
+            interpreter: B::new(program),
+        };
+
+        result.emit_prologue();
+
+        result
+    }
+
+    fn into_source(mut self, memory_size: usize) -> String {
+        self.emit_epilogue();
+        self.emit_runtime(memory_size);
+        self.out
+    }
+
+    fn emit_prologue(&mut self) {
+        self.out.push_str("# Generated by bf-rs.\n");
+        if self.syntax == Syntax::Intel {
+            self.directive(".intel_syntax noprefix");
+        }
+        self.directive(".text");
+        self.directive(".globl bf_main");
+        self.label("bf_main");
+
+        for &reg in &["rbx", "r12", "r13", "r14", "r15"] {
+            self.op("push", &[Reg(reg)]);
+        }
+
+        self.op("mov", &[POINTER, Reg("rcx")]);     // first argument
+        self.op("mov", &[MEM_START, Reg("rcx")]);
+        self.op("mov", &[MEM_LIMIT, Reg("rcx")]);
+        self.op("add", &[MEM_LIMIT, Reg("rdx")]);   // second argument
+    }
+
+    fn emit_epilogue(&mut self) {
+        self.op("mov", &[Reg("rax"), Imm(rts::OKAY as i64)]);
+        self.jump("jmp", ".Lfinish");
+
+        self.label(".Lunderflow");
+        self.op("mov", &[Reg("rax"), Imm(rts::UNDERFLOW as i64)]);
+        self.jump("jmp", ".Lfinish");
+
+        self.label(".Loverflow");
+        self.op("mov", &[Reg("rax"), Imm(rts::OVERFLOW as i64)]);
+
+        self.label(".Lfinish");
+        for &reg in &["r15", "r14", "r13", "r12", "rbx"] {
+            self.op("pop", &[Reg(reg)]);
+        }
+        self.op("ret", &[]);
+    }
+
+    /// Emits the entry point, the I/O routines, and the tape.
+    fn emit_runtime(&mut self, memory_size: usize) {
+        self.out.push_str("\n# Run-time system\n");
+        self.directive(".globl _start");
+        self.label("_start");
+        self.op("lea", &[Reg("rcx"), Rip("bf_tape")]);
+        self.op("mov", &[Reg("rdx"), Imm(memory_size as i64)]);
+        self.jump("call", "bf_main");
+        self.op("mov", &[Reg("rdi"), Reg("rax")]);
+        self.op("mov", &[Reg("eax"), Imm(60)]);       // exit
+        self.op("syscall", &[]);
+
+        // Returns the next input byte in `al`, or 0 at end of input.
+        self.label("bf_read");
+        self.op("push", &[Imm(0)]);
+        self.op("xor", &[Reg("eax"), Reg("eax")]);   // read
+        self.op("xor", &[Reg("edi"), Reg("edi")]);   // standard input
+        self.op("mov", &[Reg("rsi"), Reg("rsp")]);
+        self.op("mov", &[Reg("edx"), Imm(1)]);
+        self.op("syscall", &[]);
+        self.op("pop", &[Reg("rax")]);
+        self.op("ret", &[]);
+
+        // Writes the byte in `dl`.
+        self.label("bf_write");
+        self.op("push", &[Reg("rdx")]);
+        self.op("mov", &[Reg("eax"), Imm(1)]);       // write
+        self.op("mov", &[Reg("edi"), Imm(1)]);       // standard output
+        self.op("mov", &[Reg("rsi"), Reg("rsp")]);
+        self.op("mov", &[Reg("edx"), Imm(1)]);
+        self.op("syscall", &[]);
+        self.op("pop", &[Reg("rdx")]);
+        self.op("ret", &[]);
+
+        self.directive(".bss");
+        self.label("bf_tape");
+        self.directive(&format!(".zero {}", memory_size));
+        self.directive(".section .note.GNU-stack,\"\",@progbits");
+    }
+
+    fn compile(&mut self, program: &[peephole::Statement]) {
+        for stm in program {
+            self.compile_statement(stm);
+        }
+    }
+
+    fn compile_statement(&mut self, stm: &peephole::Statement) {
+        use peephole::Statement::*;
+        use common::Instruction::*;
+
+        match *stm {
+            Instr(Right(count)) => {
+                let proved = self.interpreter.move_right(count);
+                self.load_pos_offset(count, proved);
+                self.op("add", &[POINTER, Reg("rax")]);
+            }
+
+            Instr(Left(count)) => {
+                let proved = self.interpreter.move_left(count);
+                self.load_neg_offset(count, proved);
+                self.op("sub", &[POINTER, Reg("rax")]);
+            }
+
+            Instr(Add(count)) => {
+                self.op("add", &[AT_POINTER, Imm(count as i8 as i64)]);
+            }
+
+            Instr(In) => {
+                self.jump("call", "bf_read");
+                self.op("mov", &[AT_POINTER, Reg("al")]);
+            }
+
+            Instr(Out) => {
+                self.op("xor", &[Reg("rdx"), Reg("rdx")]);
+                self.op("mov", &[Reg("dl"), AT_POINTER]);
+                self.jump("call", "bf_write");
+            }
+
+            Instr(SetZero) => {
+                self.op("mov", &[AT_POINTER, Imm(0)]);
+            }
+
+            Instr(FindZeroRight(skip)) => {
+                self.interpreter.reset_right();
+
+                let begin_loop = self.new_label();
+                let end_loop = self.new_label();
+                self.jump("jmp", &end_loop);
+                self.label(&begin_loop);
+                self.load_pos_offset(skip, false);
+                self.op("add", &[POINTER, Reg("rax")]);
+                self.label(&end_loop);
+                self.op("cmp", &[AT_POINTER, Imm(0)]);
+                self.jump("jnz", &begin_loop);
+            }
+
+            Instr(FindZeroLeft(skip)) => {
+                self.interpreter.reset_left();
+
+                let begin_loop = self.new_label();
+                let end_loop = self.new_label();
+                self.jump("jmp", &end_loop);
+                self.label(&begin_loop);
+                self.load_neg_offset(skip, false);
+                self.op("sub", &[POINTER, Reg("rax")]);
+                self.label(&end_loop);
+                self.op("cmp", &[AT_POINTER, Imm(0)]);
+                self.jump("jnz", &begin_loop);
+            }
+
+            // The loop that follows does the work.
+            Instr(DivMod) | Instr(IndexRight(_)) | Instr(IndexLeft(_)) => (),
+
+            Instr(OffsetAddRight(offset)) => {
+                let proved = self.interpreter.check_right(offset);
+
+                let skip = self.new_label();
+                self.op("cmp", &[AT_POINTER, Imm(0)]);
+                self.jump("jz", &skip);
+                self.load_pos_offset(offset, proved);
+                self.offset_add();
+                self.label(&skip);
+            }
+
+            Instr(OffsetAddLeft(offset)) => {
+                let proved = self.interpreter.check_left(offset);
+
+                let skip = self.new_label();
+                self.op("cmp", &[AT_POINTER, Imm(0)]);
+                self.jump("jz", &skip);
+                self.load_neg_offset(offset, proved);
+                self.op("neg", &[Reg("rax")]);
+                self.offset_add();
+                self.label(&skip);
+            }
+
+            Instr(JumpZero(_)) | Instr(JumpNotZero(_)) =>
+                panic!("unexpected jump instruction"),
+
+            Loop(ref body) => {
+                let begin_label = self.new_label();
+                let end_label = self.new_label();
+
+                self.interpreter.enter_loop(body);
+
+                self.jump("jmp", &end_label);
+                self.label(&begin_label);
+                self.compile(body);
+                self.label(&end_label);
+                self.op("cmp", &[AT_POINTER, Imm(0)]);
+                self.jump("jnz", &begin_label);
+
+                self.interpreter.leave_loop();
+            }
+        }
+    }
+
+    /// Adds the byte at the pointer to the byte at the (signed) offset in `rax`, zeroing the
+    /// former.
+    fn offset_add(&mut self) {
+        self.op("mov", &[Reg("cl"), AT_POINTER]);
+        self.op("mov", &[AT_POINTER, Imm(0)]);
+        self.op("add", &[AT_OFFSET, Reg("cl")]);
+    }
+
+    fn load_pos_offset(&mut self, offset: Count, proved: bool) {
+        self.op("mov", &[Reg("rax"), Imm(offset as i64)]);
+
+        if self.checked && !proved {
+            self.op("mov", &[Reg("rcx"), MEM_LIMIT]);
+            self.op("sub", &[Reg("rcx"), POINTER]);
+            self.op("cmp", &[Reg("rcx"), Reg("rax")]);
+            self.jump("jle", ".Loverflow");
+        }
+    }
+
+    fn load_neg_offset(&mut self, offset: Count, proved: bool) {
+        self.op("mov", &[Reg("rax"), Imm(offset as i64)]);
+
+        if self.checked && !proved {
+            self.op("mov", &[Reg("rcx"), POINTER]);
+            self.op("sub", &[Reg("rcx"), MEM_START]);
+            self.op("cmp", &[Reg("rcx"), Reg("rax")]);
+            self.jump("jl", ".Lunderflow");
+        }
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    fn label(&mut self, name: &str) {
+        self.out.push_str(name);
+        self.out.push_str(":\n");
+    }
+
+    fn directive(&mut self, text: &str) {
+        self.out.push_str("    ");
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn jump(&mut self, mnemonic: &str, target: &str) {
+        self.out.push_str(&format!("    {} {}\n", mnemonic, target));
+    }
+
+    /// Emits an instruction, with its operands given in Intel order (destination first).
+    fn op(&mut self, mnemonic: &str, operands: &[Operand]) {
+        let line = match self.syntax {
+            Syntax::Intel => {
+                let operands: Vec<_> = operands.iter().map(|&operand| intel(operand)).collect();
+                format!("{} {}", mnemonic, operands.join(", "))
+            }
+
+            Syntax::Att => {
+                let bytes = operands.iter().any(|operand| matches!(*operand, Byte(..)));
+                let suffix = if bytes { "b" } else { "" };
+                let operands: Vec<_> = operands.iter().rev().map(|&operand| att(operand))
+                    .collect();
+                format!("{}{} {}", mnemonic, suffix, operands.join(", "))
+            }
+        };
+
+        self.out.push_str("    ");
+        self.out.push_str(line.trim_end());
+        self.out.push('\n');
+    }
+}
+
+fn intel(operand: Operand) -> String {
+    match operand {
+        Reg(reg) => reg.to_owned(),
+        Imm(value) => value.to_string(),
+        Byte(base, None) => format!("byte ptr [{}]", base),
+        Byte(base, Some(index)) => format!("byte ptr [{} + {}]", base, index),
+        Rip(symbol) => format!("[rip + {}]", symbol),
+    }
+}
+
+fn att(operand: Operand) -> String {
+    match operand {
+        Reg(reg) => format!("%{}", reg),
+        Imm(value) => format!("${}", value),
+        Byte(base, None) => format!("(%{})", base),
+        Byte(base, Some(index)) => format!("(%{},%{})", base, index),
+        Rip(symbol) => format!("{}(%rip)", symbol),
+    }
+}
+
+impl AsmCompilable for peephole::Program {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(self)
+    }
+}
+
+impl<T: peephole::PeepholeCompilable + ?Sized> AsmCompilable for T {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(&self.peephole_compile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn asm(source: &[u8], syntax: Syntax, options: &CompileOptions) -> String {
+        ::ast::parse_program(source).unwrap().asm_compile(Some(100), syntax, options)
+    }
+
+    #[test]
+    fn intel_syntax() {
+        let output = asm(b"+>[-<+>]", Syntax::Intel, &CompileOptions::default());
+        assert!(output.contains(".intel_syntax noprefix\n"));
+        assert!(output.contains("\n    add byte ptr [r12], 1\n"));
+        assert!(output.contains("\n    add byte ptr [r12 + rax], cl\n"));
+        assert!(output.contains("\n    lea rcx, [rip + bf_tape]\n"));
+        assert!(output.contains("\n    .zero 100\n"));
+    }
+
+    #[test]
+    fn att_syntax() {
+        let output = asm(b"+>[-<+>]", Syntax::Att, &CompileOptions::default());
+        assert!(!output.contains(".intel_syntax"));
+        assert!(output.contains("\n    addb $1, (%r12)\n"));
+        assert!(output.contains("\n    addb %cl, (%r12,%rax)\n"));
+        assert!(output.contains("\n    lea bf_tape(%rip), %rcx\n"));
+        assert!(output.contains("\n    ret\n"));
+    }
+
+    #[test]
+    fn proved_checks_are_omitted() {
+        let checked = asm(b">>><<<", Syntax::Intel, &CompileOptions::default());
+        assert_eq!(checked.matches("jle .Loverflow").count(), 1);
+        assert_eq!(checked.matches("jl .Lunderflow").count(), 0);
+
+        let options = CompileOptions { checked: false, ..CompileOptions::default() };
+        let unchecked = asm(FACTOR_SRC, Syntax::Att, &options);
+        assert!(!unchecked.contains("jle .Loverflow"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Emits x86-64 assembly source for Brainfuck programs.
+//!
+//! The generated code has the same layout as the [JIT](../jit/index.html)’s—the same register
+//! assignments, bounds checks, and loop shapes, with checks the bounds analysis proves
+//! unnecessary left out—but is written as GNU assembler source in either Intel or AT&T
+//! [`Syntax`](enum.Syntax.html), so it can be inspected, edited, and assembled with standard
+//! tools. A tiny Linux run-time system is appended: `_start` runs the program on a tape in
+//! `.bss`, I/O goes through the `read` and `write` system calls, and the exit status is the
+//! program’s result code (0 for success, 1 for pointer underflow, 2 for overflow). For example:
+//!
+//! ```shell
+//! $ as factor.s -o factor.o && ld factor.o -o factor
+//! ```
+//!
+//! Unlike the JIT, this does not need the `jit` feature.
+
+mod compiler;
+
+pub use self::compiler::{compile, AsmCompilable};
+
+/// Assembly language syntax.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// Intel syntax, as in `add byte ptr [r12], 1`.
+    Intel,
+    /// AT&T syntax, as in `addb $1, (%r12)`.
+    Att,
+}
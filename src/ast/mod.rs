@@ -10,8 +10,9 @@
 
 mod parser;
 mod interpreter;
+pub mod visit;
 
-pub use self::parser::parse_program;
+pub use self::parser::{parse_program, parse_reader};
 
 use common::Command;
 
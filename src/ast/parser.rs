@@ -1,6 +1,11 @@
+use std::io::{self, Read};
+
 use super::*;
 use common::{BfResult, Error};
 
+/// The size of the chunks read by [`parse_reader`](fn.parse_reader.html).
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Parses Brainfuck concrete syntax into an abstract syntax tree.
 ///
 /// # Errors
@@ -16,13 +21,72 @@ pub fn parse_program(input: &[u8]) -> BfResult<Box<Program>> {
     }
 }
 
+/// Parses Brainfuck concrete syntax from a reader into an abstract syntax tree.
+///
+/// Unlike [`parse_program`](fn.parse_program.html), this does not require the whole source to
+/// be in memory at once. The input is consumed in fixed-size chunks and only the commands
+/// themselves are retained, so comments cost nothing and the parser does not recurse on loop
+/// nesting.
+///
+/// # Errors
+///
+/// Unmatched square brackets will result in an `Err` return, as will any error from reading
+/// `input`. See [`common::Error`](../common/enum.Error.html).
+pub fn parse_reader<R: Read>(mut input: R) -> BfResult<Box<Program>> {
+    use common::Command::*;
+
+    let mut buffer = vec![0; READ_CHUNK_SIZE];
+    let mut current = Vec::new();
+    let mut stack: Vec<Vec<Statement>> = Vec::new();
+
+    loop {
+        let len = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e.kind())),
+        };
+
+        for &c in &buffer[.. len] {
+            let command = match c {
+                b'<' => Left,
+                b'>' => Right,
+                b'+' => Up,
+                b'-' => Down,
+                b',' => In,
+                b'.' => Out,
+                b'[' => {
+                    stack.push(current);
+                    current = Vec::new();
+                    continue;
+                }
+                b']' => {
+                    let body = current.into_boxed_slice();
+                    current = stack.pop().ok_or(Error::UnmatchedEnd)?;
+                    current.push(Statement::Loop(body));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            current.push(Statement::Cmd(command));
+        }
+    }
+
+    if stack.is_empty() {
+        Ok(current.into_boxed_slice())
+    } else {
+        Err(Error::UnmatchedBegin)
+    }
+}
+
 /// The type returned by a parser.
 ///
 /// A successful parse returns `Ok` of a pair of the result value and a slice of the
 /// remaining input. A failed parse returns `Err`.
 type Parser<'a, R> = BfResult<(R, &'a [u8])>;
 
-fn parse_instruction<'a>(mut input: &'a [u8]) -> Parser<Option<Statement>> {
+fn parse_instruction<'a>(mut input: &'a [u8]) -> Parser<'a, Option<Statement>> {
     use common::Command::*;
 
     let ok = |cmd, inp: &'a [u8]| Ok((Some(Statement::Cmd(cmd)), inp));
@@ -65,7 +129,7 @@ fn parse_instruction<'a>(mut input: &'a [u8]) -> Parser<Option<Statement>> {
     }
 }
 
-fn parse_instructions(mut input: &[u8]) -> Parser<Box<Program>> {
+fn parse_instructions(mut input: &[u8]) -> Parser<'_, Box<Program>> {
     let mut instructions = Vec::new();
 
     loop {
@@ -165,12 +229,51 @@ mod tests {
         assert_parse_error(".[.].]", Error::UnmatchedEnd);
     }
 
+    #[test]
+    fn reader_agrees_with_slice_parser() {
+        use test_helpers::FACTOR_SRC;
+        assert_eq!(parse_reader(FACTOR_SRC), parse_program(FACTOR_SRC));
+        assert_eq!(parse_reader(OneByteReader(FACTOR_SRC)), parse_program(FACTOR_SRC));
+    }
+
+    #[test]
+    fn reader_reports_read_errors() {
+        struct FailingReader;
+
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "oops"))
+            }
+        }
+
+        assert_eq!(parse_reader(FailingReader), Err(Error::Io(io::ErrorKind::BrokenPipe)));
+    }
+
     fn assert_parse(input: &str, program: &[Statement]) {
-        assert_eq!(parse_program(input.as_bytes()), Ok(program.to_vec().into_boxed_slice()));
+        let expected = Ok(program.to_vec().into_boxed_slice());
+        assert_eq!(parse_program(input.as_bytes()), expected);
+        assert_eq!(parse_reader(OneByteReader(input.as_bytes())), expected);
     }
 
     fn assert_parse_error(input: &str, message: Error) {
         assert_eq!(parse_program(input.as_bytes()), Err(message));
+        assert_eq!(parse_reader(OneByteReader(input.as_bytes())), Err(message));
+    }
+
+    /// Yields its input one byte per `read`, to exercise chunk boundaries.
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&c, rest)) if !buf.is_empty() => {
+                    buf[0] = c;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
     }
 
     fn mk_loop(instructions: Vec<Statement>) -> Statement {
@@ -0,0 +1,133 @@
+//! Visitor and folder traits for the unoptimized AST.
+//!
+//! Implement [`Visitor`](trait.Visitor.html) to inspect a program, or
+//! [`Folder`](trait.Folder.html) to rebuild one, overriding only the methods for the cases of
+//! interest. The default methods perform the traversal by calling the `walk_*` and `fold_*`
+//! functions of this module, which an overriding method can also call to recurse.
+
+use common::Command;
+use super::{Program, Statement};
+
+/// A read-only traversal of an AST.
+pub trait Visitor {
+    /// Visits a sequence of statements.
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program);
+    }
+
+    /// Visits a single statement.
+    fn visit_statement(&mut self, statement: &Statement) {
+        walk_statement(self, statement);
+    }
+
+    /// Visits a non-loop command.
+    fn visit_cmd(&mut self, _command: Command) { }
+
+    /// Visits a loop, given its body.
+    fn visit_loop(&mut self, body: &Program) {
+        self.visit_program(body);
+    }
+}
+
+/// Visits each statement of `program` in order.
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    for statement in program {
+        visitor.visit_statement(statement);
+    }
+}
+
+/// Dispatches on the kind of `statement`.
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, statement: &Statement) {
+    match *statement {
+        Statement::Cmd(command) => visitor.visit_cmd(command),
+        Statement::Loop(ref body) => visitor.visit_loop(body),
+    }
+}
+
+/// A rewriting traversal of an AST, which consumes the program and builds a new one.
+pub trait Folder {
+    /// Folds a sequence of statements.
+    fn fold_program(&mut self, program: Box<Program>) -> Box<Program> {
+        fold_program(self, program)
+    }
+
+    /// Folds a single statement.
+    fn fold_statement(&mut self, statement: Statement) -> Statement {
+        fold_statement(self, statement)
+    }
+
+    /// Folds a non-loop command.
+    fn fold_cmd(&mut self, command: Command) -> Statement {
+        Statement::Cmd(command)
+    }
+
+    /// Folds a loop, given its body.
+    fn fold_loop(&mut self, body: Box<Program>) -> Statement {
+        Statement::Loop(self.fold_program(body))
+    }
+}
+
+/// Folds each statement of `program` in order.
+pub fn fold_program<F: Folder + ?Sized>(folder: &mut F, program: Box<Program>) -> Box<Program> {
+    program.into_vec().into_iter()
+        .map(|statement| folder.fold_statement(statement))
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
+}
+
+/// Dispatches on the kind of `statement`.
+pub fn fold_statement<F: Folder + ?Sized>(folder: &mut F, statement: Statement) -> Statement {
+    match statement {
+        Statement::Cmd(command) => folder.fold_cmd(command),
+        Statement::Loop(body) => folder.fold_loop(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::parse_program;
+    use common::Command::*;
+
+    #[test]
+    fn visitor_sees_every_command_and_loop() {
+        #[derive(Default)]
+        struct Counter { commands: usize, loops: usize, depth: usize, max_depth: usize }
+
+        impl Visitor for Counter {
+            fn visit_cmd(&mut self, _command: Command) {
+                self.commands += 1;
+            }
+
+            fn visit_loop(&mut self, body: &Program) {
+                self.loops += 1;
+                self.depth += 1;
+                self.max_depth = self.max_depth.max(self.depth);
+                walk_program(self, body);
+                self.depth -= 1;
+            }
+        }
+
+        let mut counter = Counter::default();
+        counter.visit_program(&parse_program(b"+[>[-]<[.]],").unwrap());
+        assert_eq!((counter.commands, counter.loops, counter.max_depth), (6, 3, 2));
+    }
+
+    #[test]
+    fn folder_rewrites_commands() {
+        struct Mirror;
+
+        impl Folder for Mirror {
+            fn fold_cmd(&mut self, command: Command) -> Statement {
+                Statement::Cmd(match command {
+                    Left => Right,
+                    Right => Left,
+                    other => other,
+                })
+            }
+        }
+
+        let program = parse_program(b"<[>+<]").unwrap();
+        assert_eq!(Mirror.fold_program(program), parse_program(b">[<+>]").unwrap());
+    }
+}
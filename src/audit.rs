@@ -0,0 +1,168 @@
+//! Audits of the bounds checks in native code.
+//!
+//! In checked mode, the [JIT](../jit/index.html) and the [assembly emitter](../asm/index.html)
+//! check a move or offset access at run time only where the bounds analysis cannot prove that
+//! it stays on the tape. An audit lists every such access with whether it was proved safe or
+//! kept its check, so that turning checks off with `--unchecked` can be justified access by
+//! access: it is the checked ones that would go unguarded. `bfi --audit` writes the report.
+
+use std::fmt::Write;
+
+use analysis::{AbstractInterpreter, BoundsAnalysis};
+use common::{Count, Instruction};
+use diagnostics::Span;
+use peephole::{self, Statement};
+use source_map::{self, SourceMap};
+
+/// What an audited access does.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// A move by the given distance.
+    Move(Direction, Count),
+    /// An offset add reaching the given distance.
+    OffsetAdd(Direction, Count),
+    /// One step of a scan for zero, which is always checked.
+    Scan(Direction, Count),
+}
+
+/// Which way an access reaches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Towards the start of the tape.
+    Left,
+    /// Towards the end of the tape.
+    Right,
+}
+
+/// One audited access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Check {
+    /// The access’s bytecode address.
+    pub pc: usize,
+    /// What the access does.
+    pub access: Access,
+    /// Whether the analysis proved the access safe, so that it has no run-time check.
+    pub proved: bool,
+}
+
+/// Audits the bounds checks of a program, in order of bytecode address.
+pub fn audit(program: &peephole::Program) -> Vec<Check> {
+    let mut auditor = Auditor {
+        interpreter: AbstractInterpreter::new(program),
+        pc: 0,
+        checks: Vec::new(),
+    };
+    auditor.audit(program);
+    auditor.checks
+}
+
+/// Writes a report of a program’s audit, one access a line with its place in the source, and a
+/// summary line at the end.
+pub fn report(source: &[u8], program: &peephole::Program) -> String {
+    let map = SourceMap::new(source, program);
+    let checks = audit(program);
+    let mut result = String::new();
+
+    for check in &checks {
+        let span = map.span(check.pc).unwrap_or(Span::at(source.len()));
+        let (line, column) = source_map::line_column(source, span.start);
+        let (what, direction, distance) = match check.access {
+            Access::Move(direction, distance) => ("move", direction, distance),
+            Access::OffsetAdd(direction, distance) => ("offset add", direction, distance),
+            Access::Scan(direction, distance) => ("scan", direction, distance),
+        };
+        let direction = match direction {
+            Direction::Left => "left",
+            Direction::Right => "right",
+        };
+        let status = if check.proved { "proved" } else { "checked" };
+        let _ = writeln!(result, "{}:{}\tpc {}\t{} {} {}\t{}",
+                         line, column, check.pc, what, direction, distance, status);
+    }
+
+    let proved = checks.iter().filter(|check| check.proved).count();
+    let _ = writeln!(result, "{} accesses: {} checked, {} proved",
+                     checks.len(), checks.len() - proved, proved);
+    result
+}
+
+struct Auditor {
+    interpreter: AbstractInterpreter,
+    pc: usize,
+    checks: Vec<Check>,
+}
+
+impl Auditor {
+    /// Walks the program as the asm emitter does, statement by statement.
+    fn audit(&mut self, program: &peephole::Program) {
+        use self::Direction::*;
+
+        for statement in program {
+            let access = match *statement {
+                Statement::Instr(Instruction::Right(count)) =>
+                    Some((Access::Move(Right, count), self.interpreter.move_right(count))),
+                Statement::Instr(Instruction::Left(count)) =>
+                    Some((Access::Move(Left, count), self.interpreter.move_left(count))),
+                Statement::Instr(Instruction::OffsetAddRight(count)) =>
+                    Some((Access::OffsetAdd(Right, count), self.interpreter.check_right(count))),
+                Statement::Instr(Instruction::OffsetAddLeft(count)) =>
+                    Some((Access::OffsetAdd(Left, count), self.interpreter.check_left(count))),
+                Statement::Instr(Instruction::FindZeroRight(skip)) => {
+                    self.interpreter.reset_right();
+                    Some((Access::Scan(Right, skip), false))
+                }
+                Statement::Instr(Instruction::FindZeroLeft(skip)) => {
+                    self.interpreter.reset_left();
+                    Some((Access::Scan(Left, skip), false))
+                }
+                Statement::Instr(_) => None,
+
+                Statement::Loop(ref body) => {
+                    self.interpreter.enter_loop(body);
+                    self.pc += 1;
+                    self.audit(body);
+                    self.interpreter.leave_loop();
+                    None
+                }
+            };
+
+            if let Some((access, proved)) = access {
+                self.checks.push(Check { pc: self.pc, access, proved });
+            }
+            self.pc += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Direction::*;
+    use traits::PeepholeCompilable;
+
+    fn audit_source(source: &[u8]) -> Vec<Check> {
+        audit(&::ast::parse_program(source).unwrap().peephole_compile())
+    }
+
+    #[test]
+    fn moves_back_are_proved() {
+        assert_eq!(audit_source(b">>+<[>]"), vec![
+            Check { pc: 0, access: Access::Move(Right, 2), proved: false },
+            Check { pc: 2, access: Access::Move(Left, 1), proved: true },
+            Check { pc: 3, access: Access::Scan(Right, 1), proved: false },
+        ]);
+        assert_eq!(audit_source(b">[-<+>]"), vec![
+            Check { pc: 0, access: Access::Move(Right, 1), proved: false },
+            Check { pc: 1, access: Access::OffsetAdd(Left, 1), proved: true },
+        ]);
+    }
+
+    #[test]
+    fn reports_give_source_positions() {
+        let source = b">>\n<<<";
+        let report = report(source, &::ast::parse_program(source).unwrap().peephole_compile());
+        assert_eq!(report, "1:1\tpc 0\tmove right 2\tchecked\n\
+                            2:1\tpc 1\tmove left 3\tchecked\n\
+                            2 accesses: 2 checked, 0 proved\n");
+    }
+}
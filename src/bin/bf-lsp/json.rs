@@ -0,0 +1,277 @@
+//! Just enough JSON for the language server protocol.
+
+use std::fmt;
+
+/// A JSON value. Object members keep their order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Builds an object from its members.
+    pub fn object(members: Vec<(&str, Json)>) -> Self {
+        Json::Object(members.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+
+    /// Looks up a member of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) =>
+                members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Follows a path of object members.
+    pub fn path(&self, keys: &[&str]) -> Option<&Json> {
+        keys.iter().try_fold(self, |json, key| json.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref elements) => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref elements) => {
+                f.write_str("[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 { f.write_str(",")?; }
+                    write!(f, "{}", element)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(ref members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 { f.write_str(",")?; }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Parses a JSON document.
+pub fn parse(input: &str) -> Result<Json, &'static str> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos == parser.chars.len() { Ok(value) } else { Err("trailing characters") }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, &'static str> {
+        let c = self.peek().ok_or("unexpected end of input")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), &'static str> {
+        if self.next()? == expected { Ok(()) } else { Err("unexpected character") }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() { self.pos += 1; } else { break; }
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, &'static str> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, &'static str> {
+        self.whitespace();
+        match self.peek().ok_or("unexpected end of input")? {
+            'n' => self.keyword("null", Json::Null),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.pos += 1;
+                let mut elements = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Ok(Json::Array(elements)),
+                        _ => return Err("expected ‘,’ or ‘]’"),
+                    }
+                }
+            }
+            '{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(':')?;
+                    members.push((key, self.value()?));
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Ok(Json::Object(members)),
+                        _ => return Err("expected ‘,’ or ‘}’"),
+                    }
+                }
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, &'static str> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || "+-.eE".contains(c) { self.pos += 1; } else { break; }
+        }
+        let text: String = self.chars[start .. self.pos].iter().collect();
+        text.parse().map(Json::Number).map_err(|_| "bad number")
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(result),
+                '\\' => match self.next()? {
+                    'n' => result.push('\n'),
+                    'r' => result.push('\r'),
+                    't' => result.push('\t'),
+                    'b' => result.push('\u{8}'),
+                    'f' => result.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        if (0xd800 .. 0xdc00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4()?;
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                        }
+                        result.push(::std::char::from_u32(code).ok_or("bad escape")?);
+                    }
+                    c => result.push(c),
+                },
+                c => result.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, &'static str> {
+        let mut code = 0;
+        for _ in 0 .. 4 {
+            code = code * 16 + self.next()?.to_digit(16).ok_or("bad escape")?;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = r#"{"a":[1,true,null,"x\"\n\u00e9\ud83d\ude00"],"b":{}}"#;
+        let json = parse(text).unwrap();
+        assert_eq!(json.path(&["a"]).and_then(Json::as_array).map(|a| a.len()), Some(4));
+        assert_eq!(parse(&json.to_string()).unwrap(), json);
+        assert_eq!(json.to_string(), "{\"a\":[1,true,null,\"x\\\"\\né😀\"],\"b\":{}}");
+    }
+}
@@ -0,0 +1,290 @@
+//! A language server for Brainfuck (`--features lsp`).
+//!
+//! Speaks the language server protocol over standard input and output. It publishes the
+//! [diagnostics](../bf/diagnostics/index.html) for each open document as it changes, highlights
+//! the bracket matching the one at the cursor, and formats documents with
+//! [`bf::fmt`](../bf/fmt/index.html). Documents are synchronized in full on every change.
+
+extern crate bf;
+
+mod json;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use bf::diagnostics::{self, Severity, Span};
+use json::Json;
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut server = Server {
+        output: stdout.lock(),
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => exit(1),
+            Err(e) => {
+                eprintln!("bf-lsp: {}", e);
+                exit(1)
+            }
+        };
+
+        match json::parse(&message) {
+            Ok(message) => server.handle(&message),
+            Err(e) => eprintln!("bf-lsp: malformed message: {}", e),
+        }
+    }
+}
+
+/// Reads one message’s content, or `None` at end of input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length.ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    String::from_utf8(content).map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+struct Server<W: Write> {
+    output: W,
+    /// The text of each open document, by URI.
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn handle(&mut self, message: &Json) {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let uri = params.path(&["textDocument", "uri"]).and_then(Json::as_str)
+            .unwrap_or("").to_owned();
+
+        let result = match method {
+            "initialize" => Some(Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    ("textDocumentSync", Json::Number(1.0)),
+                    ("documentFormattingProvider", true.into()),
+                    ("documentHighlightProvider", true.into()),
+                ])),
+                ("serverInfo", Json::object(vec![
+                    ("name", "bf-lsp".into()),
+                    ("version", env!("CARGO_PKG_VERSION").into()),
+                ])),
+            ])),
+
+            "textDocument/didOpen" => {
+                if let Some(text) = params.path(&["textDocument", "text"]).and_then(Json::as_str) {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                    self.publish_diagnostics(&uri);
+                }
+                None
+            }
+
+            "textDocument/didChange" => {
+                let text = params.get("contentChanges").and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text")).and_then(Json::as_str);
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_owned());
+                    self.publish_diagnostics(&uri);
+                }
+                None
+            }
+
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri);
+                None
+            }
+
+            "textDocument/formatting" => Some(self.format(&uri)),
+
+            "textDocument/documentHighlight" => Some(self.highlight(&uri, params)),
+
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Json::Null)
+            }
+
+            "exit" => exit(if self.shutdown { 0 } else { 1 }),
+
+            _ => None,
+        };
+
+        // Only requests have ids; notifications get no response.
+        if let Some(id) = message.get("id") {
+            let response = match result {
+                Some(result) => Json::object(vec![
+                    ("jsonrpc", "2.0".into()),
+                    ("id", id.clone()),
+                    ("result", result),
+                ]),
+                None => Json::object(vec![
+                    ("jsonrpc", "2.0".into()),
+                    ("id", id.clone()),
+                    ("error", Json::object(vec![
+                        ("code", Json::Number(-32601.0)),
+                        ("message", format!("unsupported method: {}", method).into()),
+                    ])),
+                ]),
+            };
+            self.send(&response);
+        }
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => {
+                let index = LineIndex::new(text);
+                diagnostics::check(text.as_bytes()).iter().map(|diagnostic| {
+                    let severity = match diagnostic.severity {
+                        Severity::Error => 1.0,
+                        Severity::Warning => 2.0,
+                    };
+                    Json::object(vec![
+                        ("range", index.range(diagnostic.span)),
+                        ("severity", Json::Number(severity)),
+                        ("source", "bf".into()),
+                        ("message", diagnostic.message.into()),
+                    ])
+                }).collect()
+            }
+            None => Vec::new(),
+        };
+
+        self.send(&Json::object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            ("params", Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ])),
+        ]));
+    }
+
+    fn format(&self, uri: &str) -> Json {
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+
+        let formatted = bf::fmt::format(text.as_bytes());
+        if formatted == *text {
+            return Json::Array(Vec::new());
+        }
+
+        let index = LineIndex::new(text);
+        Json::Array(vec![Json::object(vec![
+            ("range", index.range(Span { start: 0, end: text.len() })),
+            ("newText", formatted.into()),
+        ])])
+    }
+
+    /// Highlights a bracket next to the cursor and its match.
+    fn highlight(&self, uri: &str, params: &Json) -> Json {
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+
+        let index = LineIndex::new(text);
+        let line = params.path(&["position", "line"]).and_then(Json::as_u64);
+        let character = params.path(&["position", "character"]).and_then(Json::as_u64);
+        let offset = match (line, character) {
+            (Some(line), Some(character)) => index.offset(line as usize, character as usize),
+            _ => return Json::Null,
+        };
+
+        let source = text.as_bytes();
+        let candidates = Some(offset).into_iter().chain(offset.checked_sub(1));
+        for bracket in candidates {
+            if let Some(other) = diagnostics::matching_bracket(source, bracket) {
+                return Json::Array(vec![bracket, other].into_iter().map(|offset| {
+                    Json::object(vec![("range", index.range(Span::at(offset)))])
+                }).collect());
+            }
+        }
+
+        Json::Array(Vec::new())
+    }
+
+    fn send(&mut self, message: &Json) {
+        let content = message.to_string();
+        let _ = write!(self.output, "Content-Length: {}\r\n\r\n{}", content.len(), content);
+        let _ = self.output.flush();
+    }
+}
+
+/// Converts between byte offsets and protocol positions, which count UTF-16 code units.
+struct LineIndex<'a> {
+    text: &'a str,
+    /// The byte offset of the start of each line.
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let starts = Some(0).into_iter()
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { text, starts }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let start = self.starts[line];
+        let character: usize = self.text[start .. offset].chars().map(char::len_utf16).sum();
+        Json::object(vec![("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object(vec![("start", self.position(span.start)),
+                          ("end", self.position(span.end))])
+    }
+
+    fn offset(&self, line: usize, character: usize) -> usize {
+        let start = match self.starts.get(line) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+
+        let mut units = 0;
+        for (i, c) in self.text[start ..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+
+        self.text.len()
+    }
+}
@@ -0,0 +1,422 @@
+//! A playground server for Brainfuck (`--features server`).
+//!
+//! `bf-server [ADDRESS]` listens on `ADDRESS`, by default `127.0.0.1:8080`, and runs the
+//! programs posted to `/run` in a [sandbox](../bf/sandbox/index.html). A request is a JSON
+//! object, where only `program` is required:
+//!
+//! ```json
+//! {"program": ",[.,]", "input": "hi", "options": {"fuel": 1000, "memory": 100, "output": 10}}
+//! ```
+//!
+//! The options can lower the server’s own bounds, set with `--fuel`, `--memory` and
+//! `--max-output`, but not raise them. Every run also stops after `--timeout` milliseconds,
+//! by default 10,000. The response gives what the program printed, why it
+//! stopped, how long it ran, and the [diagnostics](../bf/diagnostics/index.html) for its
+//! source. A program that does not compile gets an `error` instead of a `stop`:
+//!
+//! ```json
+//! {"output": "hi", "stop": "halted", "error": null,
+//!  "stats": {"instructions": 5, "steps": 8, "input_bytes": 2, "output_bytes": 2,
+//!            "peak_pointer": 0, "compile_ms": 0.05, "run_ms": 0.01},
+//!  "diagnostics": []}
+//! ```
+//!
+//! A `GET` of `/capabilities` gives the backends, dialects and features the server was built
+//! with, as from [`bf::capabilities`](../bf/capabilities/index.html).
+//!
+//! Output that is not UTF-8 is decoded lossily. Each connection gets its own thread and one
+//! response, up to `--max-connections` at once; past that, and for a program posted while
+//! `--max-queued` others wait, the answer is a 503. Programs run on an
+//! [`Executor`](../bf/executor/struct.Executor.html) of `--threads` threads, by default one for
+//! each CPU, whose tapes are [reset](../bf/state/struct.State.html#method.reset) between runs,
+//! clearing only the pages the last program wrote.
+
+extern crate bf;
+
+#[macro_use]
+extern crate clap;
+
+// The language server’s JSON, of which this uses less.
+#[path = "../bf-lsp/json.rs"]
+#[allow(dead_code)]
+mod json;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg};
+
+use bf::diagnostics::{self, Severity};
+use bf::executor::{Executor, Job, QueueLimits};
+use bf::sandbox::Sandbox;
+use json::Json;
+
+/// The most bytes a request body may have.
+const MAX_BODY: usize = 4 << 20;
+
+/// The most bytes a request line and headers may have.
+const MAX_HEAD: usize = 16 << 10;
+
+/// How long a connection may take to send its request, or to take the response.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let matches = App::new("bf-server")
+        .version(crate_version!())
+        .about("A Brainfuck playground server")
+        .arg(Arg::with_name("ADDRESS")
+            .help("The address to listen on (default 127.0.0.1:8080)")
+            .index(1))
+        .arg(Arg::with_name("fuel")
+            .long("fuel")
+            .value_name("STEPS")
+            .help("Steps a program may run (default 100,000,000)")
+            .takes_value(true))
+        .arg(Arg::with_name("memory")
+            .long("memory")
+            .value_name("SIZE")
+            .help("Memory size in bytes (default 30,000)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-output")
+            .long("max-output")
+            .value_name("BYTES")
+            .help("Bytes a program may print (default 1,048,576)")
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("MILLIS")
+            .help("Milliseconds a program may run (default 10,000)")
+            .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .value_name("N")
+            .help("Threads running programs (default one per CPU)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-queued")
+            .long("max-queued")
+            .value_name("N")
+            .help("Programs that may wait for a thread (default 64)")
+            .takes_value(true))
+        .arg(Arg::with_name("max-connections")
+            .long("max-connections")
+            .value_name("N")
+            .help("Connections served at once (default 256)")
+            .takes_value(true))
+        .get_matches();
+
+    let mut sandbox = Sandbox::default();
+    let number = |name, default| matches.value_of(name).map_or(default, |n| {
+        n.parse().unwrap_or_else(|e| {
+            eprintln!("bf-server: could not parse --{}: {}.", name, e);
+            exit(1)
+        })
+    });
+    sandbox.fuel = number("fuel", sandbox.fuel);
+    sandbox.memory_size = number("memory", sandbox.memory_size).max(1);
+    sandbox.max_output = number("max-output", sandbox.max_output);
+    sandbox.timeout = Some(Duration::from_millis(number("timeout", 10_000) as u64));
+
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    let limits = QueueLimits { max_queued: Some(number("max-queued", 64)),
+                               ..QueueLimits::default() };
+    let executor = Arc::new(Executor::with_limits(sandbox, number("threads", cpus), limits));
+    let max_connections = number("max-connections", 256);
+
+    let address = matches.value_of("ADDRESS").unwrap_or("127.0.0.1:8080");
+    let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+        eprintln!("bf-server: {}: ‘{}’.", e, address);
+        exit(1)
+    });
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) if connections.fetch_add(1, Ordering::SeqCst) >= max_connections => {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                let refused = stream.set_write_timeout(Some(READ_TIMEOUT))
+                    .and_then(|_| answer(stream, 503, &error("too many connections")));
+                if let Err(e) = refused {
+                    eprintln!("bf-server: {}", e);
+                }
+            }
+            Ok(stream) => {
+                let executor = executor.clone();
+                let connections = connections.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &executor) {
+                        eprintln!("bf-server: {}", e);
+                    }
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => eprintln!("bf-server: {}", e),
+        }
+    }
+}
+
+/// An HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Answers one request on the connection.
+fn serve(stream: TcpStream, executor: &Executor) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let output = stream.try_clone()?;
+
+    let (status, body) = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => respond(&request, executor),
+        Err((status, message)) => (status, error(message)),
+    };
+
+    answer(output, status, &body)
+}
+
+/// Writes the response and closes the connection.
+fn answer(mut output: TcpStream, status: u16, body: &Json) -> io::Result<()> {
+    let body = body.to_string();
+    write!(output, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, reason(status), body.len(), body)?;
+    output.flush()
+}
+
+/// Reads a request, or fails with the status to answer it with.
+fn read_request<R: BufRead>(input: &mut R) -> Result<Request, (u16, &'static str)> {
+    let bad = |_| (400, "bad request");
+    let mut head = input.by_ref().take(MAX_HEAD as u64);
+
+    let mut line = String::new();
+    head.read_line(&mut line).map_err(bad)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err((400, "bad request")),
+    };
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if head.read_line(&mut header).map_err(bad)? == 0 {
+            return Err(if head.limit() == 0 { (431, "headers too large") }
+                       else { (400, "bad request") });
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            if header[.. colon].eq_ignore_ascii_case("content-length") {
+                length = header[colon + 1 ..].trim().parse()
+                    .map_err(|_| (400, "bad Content-Length"))?;
+            }
+        }
+    }
+
+    if length > MAX_BODY {
+        return Err((413, "request too large"));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(bad)?;
+
+    Ok(Request { method, path, body })
+}
+
+/// The status and body answering a request.
+fn respond(request: &Request, executor: &Executor) -> (u16, Json) {
+    if request.path == "/capabilities" {
+        return match &request.method[..] {
+            "GET" => (200, capabilities()),
+            _ => (405, error("only GET is allowed")),
+        };
+    }
+    if request.path != "/run" {
+        return (404, error("not found"));
+    }
+    if request.method != "POST" {
+        return (405, error("only POST is allowed"));
+    }
+
+    let body = match ::std::str::from_utf8(&request.body).ok().map(json::parse) {
+        Some(Ok(body)) => body,
+        _ => return (400, error("the body is not JSON")),
+    };
+    let program = match body.get("program").and_then(Json::as_str) {
+        Some(program) => program,
+        None => return (400, error("expected a string ‘program’")),
+    };
+    let input = body.get("input").and_then(Json::as_str).unwrap_or("");
+
+    let mut job = Job::new(program, input);
+    let option = |name| body.path(&["options", name]).and_then(Json::as_u64);
+    job.options.fuel = option("fuel").map(|n| n as usize);
+    job.options.memory_size = option("memory").map(|n| n as usize);
+    job.options.max_output = option("output").map(|n| n as usize);
+
+    let diagnostics = diagnostics::check(program.as_bytes()).iter().map(|diagnostic| {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        Json::object(vec![
+            ("start", diagnostic.span.start.into()),
+            ("end", diagnostic.span.end.into()),
+            ("severity", severity.into()),
+            ("message", diagnostic.message.into()),
+        ])
+    }).collect();
+
+    let report = match executor.submit(job) {
+        Ok(pending) => pending.wait(),
+        Err(rejected) => return (503, error(&rejected.to_string())),
+    };
+
+    let result = match report {
+        Ok(report) => Json::object(vec![
+            ("output", String::from_utf8_lossy(&report.output).into_owned().into()),
+            ("stop", report.stop.to_string().into()),
+            ("error", Json::Null),
+            ("stats", Json::object(vec![
+                ("instructions", report.instructions.into()),
+                ("steps", report.steps.into()),
+                ("input_bytes", (report.stats.input_bytes as usize).into()),
+                ("output_bytes", (report.stats.output_bytes as usize).into()),
+                ("peak_pointer", report.stats.peak_pointer.unwrap_or(0).into()),
+                ("compile_ms", millis(report.stats.compile_time.unwrap_or_default())),
+                ("run_ms", millis(report.stats.run_time)),
+            ])),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]),
+        Err(e) => Json::object(vec![
+            ("output", "".into()),
+            ("stop", Json::Null),
+            ("error", e.to_string().into()),
+            ("stats", Json::Null),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]),
+    };
+
+    (200, result)
+}
+
+/// What the server’s build of the crate can do.
+fn capabilities() -> Json {
+    let strings = |names: &[&str]| Json::Array(names.iter().map(|&name| name.into()).collect());
+    let capabilities = bf::capabilities();
+    Json::object(vec![
+        ("backends", strings(&capabilities.backends)),
+        ("targets", strings(&capabilities.targets)),
+        ("dialects", strings(&capabilities.dialects)),
+        ("jit_arch", capabilities.jit_arch.map_or(Json::Null, Json::from)),
+        ("llvm_version", capabilities.llvm_version.map_or(Json::Null, |v| (v as usize).into())),
+        ("count_bits", (capabilities.count_bits as usize).into()),
+        ("features", strings(&capabilities.features)),
+    ])
+}
+
+/// A duration in milliseconds.
+fn millis(duration: Duration) -> Json {
+    Json::Number(duration.as_secs_f64() * 1000.0)
+}
+
+fn error(message: &str) -> Json {
+    Json::object(vec![("error", message.into())])
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executor() -> Executor {
+        Executor::new(Sandbox::default(), 1)
+    }
+
+    fn post_to(executor: &Executor, body: &str) -> (u16, String) {
+        let request = format!("POST /run HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+                              body.len(), body);
+        match read_request(&mut request.as_bytes()) {
+            Ok(request) => {
+                let (status, body) = respond(&request, executor);
+                (status, body.to_string())
+            }
+            Err((status, message)) => (status, message.to_owned()),
+        }
+    }
+
+    fn post(body: &str) -> (u16, String) {
+        post_to(&executor(), body)
+    }
+
+    #[test]
+    fn programs_are_run() {
+        let (status, body) = post(r#"{"program": ",[.,]", "input": "hi"}"#);
+        assert_eq!(status, 200);
+        let start = r#"{"output":"hi","stop":"halted","error":null,"#.to_owned() +
+            r#""stats":{"instructions":5,"steps":8,"input_bytes":2,"output_bytes":2,"# +
+            r#""peak_pointer":0,"compile_ms":"#;
+        assert!(body.starts_with(&start));
+        assert!(body.ends_with(r#"},"diagnostics":[]}"#));
+
+        let (status, body) = post(r#"{"program": "+[]", "options": {"fuel": 10}}"#);
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""stop":"out of fuel""#));
+        assert!(body.contains(r#""message":"empty loop never terminates once entered""#));
+
+        let (_, body) = post(r#"{"program": "+["}"#);
+        assert!(body.contains(r#""error":"unmatched ‘[’""#));
+    }
+
+    #[test]
+    fn bad_requests_are_refused() {
+        assert_eq!(post("{").0, 400);
+        assert_eq!(post(r#"{"input": ""}"#).0, 400);
+        let request = Request { method: "GET".to_owned(), path: "/run".to_owned(),
+                                body: Vec::new() };
+        assert_eq!(respond(&request, &executor()).0, 405);
+
+        let request = format!("POST /run HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert_eq!(read_request(&mut request.as_bytes()).err(), Some((413, "request too large")));
+        let request = format!("POST /run HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(MAX_HEAD));
+        assert_eq!(read_request(&mut request.as_bytes()).err(), Some((431, "headers too large")));
+    }
+
+    #[test]
+    fn runs_are_turned_away_when_the_queue_is_full() {
+        let limits = QueueLimits { max_queued: Some(0), ..QueueLimits::default() };
+        let executor = Executor::with_limits(Sandbox::default(), 1, limits);
+        assert_eq!(post_to(&executor, r#"{"program": "+"}"#),
+                   (503, r#"{"error":"too many jobs are queued"}"#.to_owned()));
+    }
+
+    #[test]
+    fn capabilities_are_served() {
+        let request = Request { method: "GET".to_owned(), path: "/capabilities".to_owned(),
+                                body: Vec::new() };
+        let (status, body) = respond(&request, &executor());
+        assert_eq!(status, 200);
+        let body = json::parse(&body.to_string()).unwrap();
+        let features = body.get("features").and_then(Json::as_array).unwrap();
+        assert!(features.iter().any(|feature| feature.as_str() == Some("server")));
+    }
+}
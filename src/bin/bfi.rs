@@ -2,27 +2,195 @@
 //!
 //! ```
 //! USAGE:
-//!     bfi [FLAGS] [OPTIONS] [--] [FILE]...
+//!     bfi [FLAGS] [OPTIONS] [FILE]... [SUBCOMMAND]
 //!
 //! FLAGS:
-//!         --ast          Interpret the unoptimized AST
-//!         --byte         Compile AST to bytecode
-//!     -h, --help         Prints help information
-//!         --jit          JIT to native x64 (default)
-//!         --llvm         JIT using LLVM
-//!         --peep         Interpret the peephole-optimized AST
-//!         --rle          Interpret the run-length encoded the AST
-//!     -u, --unchecked    Omit memory bounds checks in JIT
-//!     -V, --version      Prints version information
+//!         --ast              Interpret the unoptimized AST
+//!         --auto-memory      Size memory to just the cells the program can reach
+//!         --brainfork        Interpret the Brainfork dialect, where ‘Y’ forks
+//!         --byte             Compile AST to bytecode
+//!         --debug-symbols    Make LLVM output debuggable with GDB
+//!         --deterministic    Avoid address-dependent code generation
+//!     -h, --help             Prints help information
+//!         --huge-pages       Back large tapes with 2 MB huge pages where the OS allows
+//!         --jit              JIT to native x64 (default)
+//!         --llvm             JIT using LLVM
+//!     -m, --macros           Expand @define macros and @include files
+//!         --multitape        Interpret the multi-tape dialect, where braces switch tapes
+//!         --no-config        Ignore bf.toml files
+//!         --outline-loops    Compile each top-level loop separately in LLVM
+//!         --peep             Interpret the peephole-optimized AST
+//!         --precompute       Run programs that read no input at compile time
+//!     -q, --quiet            Print no error messages, only exit with the error’s code
+//!         --rle              Interpret the run-length encoded the AST
+//!         --sanitize         Check every memory access in native code
+//!         --speculate        Check whole loop iterations in JIT, deoptimizing near the edges
+//!         --stats            Report steps, I/O and timings on stderr after the run
+//!         --threaded         Compile AST to closure-threaded code
+//!         --trace            Interpret bytecode, tracing hot loops (experimental)
+//!     -u, --unchecked        Omit memory bounds checks in JIT
+//!         --utf8             Decode output as UTF-8, replacing invalid bytes
+//!     -V, --version          Prints version information
 //!
 //! OPTIONS:
-//!     -e, --expr <CODE>...    BF code to execute
-//!     -s, --size <SIZE>       Memory size in bytes (default 30,000)
+//!         --audit <FILE>               Write a report of native code’s bounds checks to FILE
+//!         --buffer <POLICY>            Buffer output: none (default), line, or a size in bytes
+//!         --codegen-threads <N>        Threads for compiling outlined loops (default 1)
+//!         --crash-dump <FILE>          Write a crash dump to FILE on a run-time error
+//!     -e, --expr <CODE>...             BF code to execute
+//!     -I, --include <DIR>...           Look for source files in DIR too
+//!         --input-batch <BYTES>        Read up to BYTES of input at a time in native code
+//!         --max-depth <N>              Refuse programs with loops nested more than N deep
+//!         --max-instructions <N>       Refuse programs that optimize to more than N instructions
+//!         --max-loop-iterations <N>    Stop a loop that runs N times in a row, as likely infinite
+//!         --max-source-size <BYTES>    Refuse programs longer than BYTES
+//!         --newlines <STYLE>           Write newlines as STYLE, crlf or lf; drop ‘\r’ from input
+//!         --precompute-steps <N>       Step budget for --precompute (default 10,000,000)
+//!     -s, --size <SIZE>                Memory size in bytes (default 30,000)
+//!         --source-map <FILE>          Write the bytecode’s source map to FILE (with --byte)
 //!
 //! ARGS:
 //!     <FILE>...    The source file(s) to interpret
+//!
+//! SUBCOMMANDS:
+//!     analyze         Prints static facts about a program
+//!     cache           Lists the compilation cache’s entries
+//!     capabilities    Lists the backends, dialects and features this build has
+//!     compile         Compiles to a native executable via C
+//!     cost            Runs a program and reports its estimated cost in cycles
+//!     dispatch        Runs a program and reports the opcode pairs its bytecode runs most
+//!     explain         Shows the source side by side with the instructions it optimizes to
+//!     help            Prints this message or the help of the given subcommand(s)
+//!     lang            Compiles the tiny imperative language to Brainfuck
+//!     minify          Strips a program down to its commands and checks it still behaves the same
+//!     run             Runs a program, with --visualize showing each step
 //! ```
 //!
+//! `bfi compile -o prog prog.bf` builds a standalone executable using the system C compiler
+//! (`cc`, or `$CC` if set). Executables are cached under `~/.cache/bf-rs`, so rebuilding an
+//! unchanged program is instant; `bfi cache` lists the cache and `bfi cache --clear` empties it.
+//! With `--seccomp`, the executable puts itself in seccomp’s strict mode as it starts, so that
+//! it can only read its input, write its output and exit, for handing out programs compiled
+//! from untrusted source; this needs Linux. See [`bf::c`](../bf/c/index.html).
+//!
+//! `bfi capabilities` lists the backends, code generators, dialects and Cargo features this
+//! build of `bfi` has; see [`bf::capabilities`](../bf/capabilities/index.html).
+//!
+//! `bfi analyze prog.bf` prints the program’s command and instruction counts, its loop nesting,
+//! the loops whose net movement is unknown, and how much memory it needs as far as the bounds
+//! analysis can tell; see [`bf::stats`](../bf/stats/index.html). `--loops` lists every loop,
+//! indented by its nesting, with its net movement and, for a loop that does not come back to
+//! where it started, the scan or inner loop that keeps it from doing so; those loops keep their
+//! bounds checks in native code. With `--symex`, it also runs the program on symbolic input,
+//! within bounds on steps, paths and input length, and prints an input for each kind of
+//! run-time error it can reach, such as the pointer moving off the left of the tape; see
+//! [`bf::symex`](../bf/symex/index.html). `--corpus DIR` writes inputs found the same way that
+//! between them take each way through each loop, as `1.in`, `2.in` and so on, with the
+//! program’s output for each as `1.out`, `2.out`.
+//!
+//! `bfi cost prog.bf` runs the program’s bytecode and then reports on stderr its estimated
+//! cycles and the loops that cost the most. `--weight NAME=CYCLES` changes what a kind of
+//! instruction costs; see [`bf::cost`](../bf/cost/index.html) for the names and defaults.
+//!
+//! `bfi dispatch prog.bf` runs the program’s bytecode and then reports on stderr how many
+//! instructions it dispatched and the pairs of opcodes that most often ran one after the
+//! other, as candidates for superinstructions; `--pairs N` sets how many to list. See
+//! [`bf::dispatch`](../bf/dispatch/index.html).
+//!
+//! `bfi explain prog.bf` prints the program’s source on the left and, on each line, the
+//! instruction that part of it became on the right, so that rewrites such as `[-]` to
+//! `SetZero` can be seen in place; see [`bf::explain`](../bf/explain/index.html). With
+//! `--pseudo`, it then writes the program as pseudo-code, with clears, copies, multiplication
+//! loops and scans spelled out and cells named for their place on the tape; see
+//! [`bf::decompile`](../bf/decompile/index.html).
+//!
+//! `bfi lang prog.txt` compiles a program in a tiny imperative language, with variables,
+//! `while`, `if`, byte arithmetic and I/O, and prints the Brainfuck it becomes; `--run` runs it
+//! instead. See [`bf::lang`](../bf/lang/index.html) for the language.
+//!
+//! `bfi minify prog.bf` writes out just the program’s commands, and `--shrink` writes out the
+//! optimized program instead, with dead code gone. Either way, the result is run side by side
+//! with the original on random inputs to check that it behaves the same, unless `--no-verify`
+//! is given; see [`bf::minify`](../bf/minify/index.html).
+//!
+//! `bfi run --visualize prog.bf` steps through the program’s bytecode on a worker thread,
+//! redrawing the terminal after each step with the source, the instruction about to run
+//! highlighted, the tape around the pointer, and the output so far; `--delay MS` sets the pause
+//! between steps. If the program fails, the last instructions it ran are listed with their
+//! locations before the error. Without `--visualize`, `bfi run` runs the program as usual. See
+//! [`bf::visualize`](../bf/visualize/index.html) and [`bf::events`](../bf/events/index.html).
+//!
+//! `bfi run --lockstep prog.bf` reads all of standard input, then runs the program in the JIT
+//! (closure-threaded code without the `jit` feature) and in the peephole interpreter side by
+//! side. If they part ways, it reports the first loop iteration or stretch of code where they
+//! did, with its IR and assembly, rather than the output; see
+//! [`bf::lockstep`](../bf/lockstep/index.html).
+//!
+//! With `--auto-memory`, the tape is only as long as the cells the program can reach, where the
+//! analysis can bound them and that is less than the memory size; see
+//! [`RunOptions`](../bf/options/struct.RunOptions.html).
+//!
+//! With `--huge-pages`, a tape of several megabytes is backed by 2 MB huge pages where the OS
+//! supports them, saving TLB misses for programs that range over it; elsewhere the flag does
+//! nothing. See [`State::with_huge_pages`](../bf/state/struct.State.html#method.with_huge_pages).
+//!
+//! With `--precompute`, a program that reads no input and halts within the step budget is run
+//! before the selected pass, which then just prints the output.
+//!
+//! `--max-loop-iterations` runs the program in the peephole interpreter, stopping it with the
+//! loop’s location when any loop goes around that many times without exiting. This singles
+//! out an infinite loop, as when grading submissions, without a budget for the whole run.
+//!
+//! `--crash-dump FILE` runs the program in the bytecode interpreter and, if it fails at run
+//! time, writes FILE with the command line, the step count, the pointer, the cells around it
+//! and the last instructions run, to attach to a bug report; see
+//! [`bf::crash_dump`](../bf/crash_dump/index.html).
+//!
+//! `--buffer line` or `--buffer SIZE` holds output back until a line or that many bytes are
+//! waiting, rather than writing each byte as it is printed, which is much faster for programs
+//! that print a lot. The JIT and LLVM backends also write it out before each read, so prompts
+//! still show; see [`Buffering`](../bf/options/enum.Buffering.html).
+//!
+//! `--input-batch BYTES` has the JIT and LLVM backends read up to that many bytes of input at
+//! once and serve `,` from them, rather than asking for each byte; any output is written out
+//! before each such read.
+//!
+//! `--stats` reports on stderr, once the program has run, how many bytes it read and wrote,
+//! how long compiling and running it took, and, for interpreters that step through
+//! instructions such as `--byte`, how many steps it ran and how far right the pointer went;
+//! see [`bf::run_stats`](../bf/run_stats/index.html).
+//!
+//! `--utf8` decodes the program’s output as UTF-8 on its way to the terminal, so that stray
+//! bytes show as U+FFFD rather than garbling what follows; see
+//! [`bf::adapters`](../bf/adapters/index.html). `--newlines crlf` writes each `\n` the program
+//! prints as `\r\n`, and `--newlines lf` each `\r\n` as `\n`; either way, carriage returns are
+//! dropped from input, so that a program expecting `\n` alone reads lines typed on Windows the
+//! same. Neither applies to `--llvm`.
+//!
+//! `--audit` lists each move and offset access with whether the bounds analysis proved it safe
+//! or native code checks it at run time; the checked ones are what `--unchecked` gives up.
+//!
+//! Several files run as one program, concatenated in the order given. A file that is not found
+//! as named is looked for in each `--include` directory in turn. With `--macros`, the program
+//! is run through the [macro preprocessor](../bf/macros/index.html) first, which finds
+//! `@include` files the same way.
+//!
+//! With `--byte`, run-time errors are reported with their file, line and column, and the macro
+//! use they were expanded from if any. `--source-map` saves the mapping from bytecode
+//! addresses to source spans for other tools.
+//!
+//! Defaults for the backend, memory size and other options can be set in a `bf.toml` file for
+//! the project or the user; see [`bf::config`](../bf/config/index.html). Flags override them.
+//!
+//! `--max-source-size`, `--max-depth` and `--max-instructions` refuse programs that are too
+//! large before they are compiled, for running source that is not trusted; see
+//! [`bf::limits`](../bf/limits/index.html).
+//!
+//! The exit status tells failures apart: 1 for a bad command line, 2 for a syntax error, 3 for
+//! a run-time error such as the pointer leaving the tape, 4 when the C compiler fails, 5 when
+//! a limit refuses the program or stops the run, and 6 for an I/O error. `--quiet` leaves out
+//! the message.
+//!
 //! See [the library crate documentation](../bf/index.html) for more.
 
 extern crate bf;
@@ -30,21 +198,134 @@ extern crate bf;
 #[macro_use]
 extern crate clap;
 
-use std::io::Read;
-use std::fs::File;
-use std::process::exit;
+use std::ascii;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit, Command};
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
+use bf::adapters::{BufferedWriter, Newlines, NewlineWriter, StripCr, Utf8Writer};
 use bf::ast;
+use bf::audit;
+use bf::brainfork::{self, Limits};
+use bf::lang;
+use bf::limits::SourceLimits;
+use bf::lockstep;
+use bf::macros::{self, Expansion};
+use bf::minify;
+use bf::multitape;
+use bf::trace;
+use bf::precompute;
+use bf::bytecode::{self, Fault};
+use bf::config::{Config, LoadError};
+use bf::common::Error;
+use bf::codegen;
+use bf::cost::{self, CostModel};
+use bf::crash_dump;
+use bf::decompile;
+use bf::dispatch;
+use bf::events::{self, End, ExecEvent, Worker};
+use bf::explain;
+use bf::history;
+use bf::cache::{Cache, Key};
+use bf::c::CCompilable;
+use bf::options::{Buffering, CompileOptions, RunOptions};
+use bf::peephole::{self, Stopped};
+use bf::run_stats::{self, RunStats};
+use bf::source_map::SourceMap;
+use bf::sources::{Location, Sources};
+use bf::state::{self, State};
+use bf::sandbox::Sandbox;
+use bf::stats;
+use bf::symex;
+use bf::text::Text;
+use bf::visualize::{Frame, Layout};
 use bf::traits::*;
 
+/// The exit codes, so that scripts can tell failures apart without reading the messages.
+mod code {
+    /// The command line is bad, or names no program.
+    pub const USAGE: i32 = 1;
+    /// The program does not parse.
+    pub const SYNTAX: i32 = 2;
+    /// The program moved the pointer off the tape, or failed some other way as it ran.
+    pub const RUNTIME: i32 = 3;
+    /// The C compiler could not be run or failed.
+    pub const COMPILER: i32 = 4;
+    /// The program is larger than the limits allow, or a limit stopped the run.
+    pub const LIMIT: i32 = 5;
+    /// Reading or writing a file or stream failed.
+    pub const IO: i32 = 6;
+}
+
+/// Whether `--quiet` was given.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug, Clone)]
 struct Options {
-    program_text:  Vec<u8>,
+    sources:       Sources,
+    include:       Vec<PathBuf>,
+    expansion:     Option<Expansion>,
+    limits:        SourceLimits,
     memory_size:   Option<usize>,
+    auto_memory:   bool,
+    huge_pages:    bool,
     compiler_pass: Pass,
     unchecked:     bool,
+    deterministic: bool,
+    outline_loops: bool,
+    codegen_threads: usize,
+    debug_symbols: bool,
+    sanitize:      bool,
+    speculate:     bool,
+    seccomp:       bool,
+    precompute:    Option<u64>,
+    max_loop_iterations: Option<u64>,
+    crash_dump:    Option<String>,
+    utf8:          bool,
+    newlines:      Option<Newlines>,
+    buffering:     Buffering,
+    input_batch:   usize,
+    native_output: Option<String>,
+    source_map:    Option<String>,
+    audit:         Option<String>,
+    /// With `--stats`, when `bfi` started on the program.
+    stats:         Option<Instant>,
+}
+
+impl Options {
+    /// The program’s text, after any preprocessing.
+    fn text(&self) -> &[u8] {
+        self.expansion.as_ref().map_or(self.sources.text(), Expansion::text)
+    }
+
+    /// Where in the source files an offset into the text is, as `file:line:column`, with the
+    /// macro use it was expanded from if any.
+    fn locate(&self, offset: usize) -> Option<String> {
+        let show = |location: Location| {
+            format!("{}:{}:{}", location.file, location.line, location.column)
+        };
+        match self.expansion {
+            None => self.sources.locate(offset).map(show),
+            Some(ref expansion) => {
+                let origin = expansion.origin(offset)?;
+                let written = show(expansion.sources().locate(origin.offset)?);
+                match origin.expanded_at.and_then(|at| expansion.sources().locate(at)) {
+                    Some(at) => Some(format!("{} (expanded from {})", written, show(at))),
+                    None => Some(written),
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -53,6 +334,10 @@ enum Pass {
     Rle,
     Bytecode,
     Peephole,
+    Threaded,
+    Brainfork,
+    Multitape,
+    Trace,
     #[cfg(feature = "jit")]
     Jit,
     #[cfg(feature = "llvm")]
@@ -60,9 +345,44 @@ enum Pass {
 }
 
 fn main() {
-    let options = get_options();
+    let mut options = get_options();
+
+    let mut program = parse(&options);
 
-    let program = parse(&options);
+    if let Some(budget) = options.precompute {
+        if let Some(constant) = precompute::precompute(&program, options.memory_size, budget) {
+            program = constant;
+        }
+    }
+
+    if options.auto_memory {
+        let run = RunOptions {
+            memory_size: options.memory_size,
+            auto_memory: true,
+            ..RunOptions::default()
+        };
+        options.memory_size = run.memory_size_for(&program.peephole_compile());
+    }
+
+    if let Some(ref path) = options.audit {
+        fs::write(path, audit::report(options.text(), &program.peephole_compile()))
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, path)));
+    }
+
+    if let Some(ref output) = options.native_output {
+        compile_native(&program, output, &options);
+        return;
+    }
+
+    if let Some(cap) = options.max_loop_iterations {
+        run_capped(&program.peephole_compile(), cap, &options);
+        return;
+    }
+
+    if let Some(ref path) = options.crash_dump {
+        run_dumping(&program.peephole_compile(), path, &options);
+        return;
+    }
 
     match options.compiler_pass {
         Pass::Ast => {
@@ -80,32 +400,510 @@ fn main() {
         }
 
         Pass::Bytecode => {
-            let program = program.bytecode_compile();
+            let program = program.peephole_compile();
+            let map = SourceMap::new(options.text(), &program);
+            if let Some(ref path) = options.source_map {
+                fs::write(path, map.to_string())
+                    .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, path)));
+            }
+
+            let program = bytecode::compile(&program);
+            let mut state = new_state(&options);
+            let result = if options.stats.is_some() {
+                let (result, stats) = bytecode::interpret_measuring(&program, &mut state,
+                                                                    stdin(&options),
+                                                                    buffered_stdout(&options));
+                print_stats(&options, stats);
+                result
+            } else {
+                bytecode::interpret_locating(&program, &mut state, stdin(&options),
+                                             buffered_stdout(&options))
+            };
+            result.unwrap_or_else(|fault| fault_exit(&fault, &map, &options));
+        }
+
+        Pass::Threaded => {
+            let program = program.threaded_compile();
+            interpret(&program, &options);
+        }
+
+        Pass::Brainfork => {
+            let program = brainfork::parse_program(options.text())
+                .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+            let state = new_state(&options);
+            let (result, stats) = run_stats::measure(stdin(&options), buffered_stdout(&options),
+                                                     |input, output| {
+                brainfork::run(&program, state, input, output, &Limits::default())
+            });
+            print_stats(&options, stats);
+            result
+                .unwrap_or_else(|e| error_exit(runtime_code(&e), &format!("runtime error: {}.", e)));
+        }
+
+        Pass::Multitape => {
+            let program = multitape::parse_program(options.text())
+                .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
             interpret(&*program, &options);
         }
 
+        Pass::Trace => {
+            let program = trace::Program::new(program.bytecode_compile(),
+                                              trace::Options::default());
+            interpret(&program, &options);
+        }
+
         #[cfg(feature = "jit")]
         Pass::Jit => {
-            let program = program.jit_compile(!options.unchecked);
-            interpret(&program, &options);
+            let program = program.jit_compile_with_options(&CompileOptions {
+                checked:       !options.unchecked,
+                deterministic: options.deterministic,
+                sanitize:      options.sanitize,
+                speculate:     options.speculate,
+                ..CompileOptions::default()
+            });
+            let state = new_state(&options);
+            let run = RunOptions {
+                buffering:   options.buffering,
+                input_batch: options.input_batch,
+                ..RunOptions::default()
+            };
+            let (result, stats) = run_stats::measure(stdin(&options), stdout(&options),
+                                                     |input, output| {
+                program.interpret_with_options(state, input, output, &run)
+            });
+            print_stats(&options, stats);
+            result
+                .unwrap_or_else(|e| error_exit(runtime_code(&e), &format!("runtime error: {}.", e)));
         }
 
         #[cfg(feature = "llvm")]
         Pass::Llvm => {
-            program.llvm_run(options.memory_size)
-                .unwrap_or_else(|e| error_exit(3, &format!("runtime error: {}.", e)));
+            program.llvm_run(&CompileOptions {
+                outline_loops: options.outline_loops,
+                codegen_threads: options.codegen_threads,
+                debug_symbols: options.debug_symbols,
+                sanitize: options.sanitize,
+                ..CompileOptions::default()
+            }, &RunOptions {
+                memory_size: options.memory_size,
+                buffering:   options.buffering,
+                input_batch: options.input_batch,
+                ..RunOptions::default()
+            })
+                .unwrap_or_else(|e| error_exit(runtime_code(&e), &format!("runtime error: {}.", e)));
         }
     }
 }
 
 fn parse(options: &Options) -> Box<ast::Program> {
-    ast::parse_program(&options.program_text)
-        .unwrap_or_else(|e| error_exit(2, &format!("syntax error: {}.", e)))
+    let program = ast::parse_program(options.text())
+        .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+    if options.limits.max_instructions < usize::MAX {
+        options.limits.check_program(&program.peephole_compile())
+            .unwrap_or_else(|e| error_exit(code::LIMIT, &format!("error: {}.", e)));
+    }
+    program
+}
+
+/// Standard input, without carriage returns with `--newlines`.
+fn stdin(options: &Options) -> Box<dyn Read> {
+    match options.newlines {
+        Some(_) => Box::new(StripCr(io::stdin())),
+        None => Box::new(io::stdin()),
+    }
+}
+
+/// Standard output for the interpreters, buffered with `--buffer`.
+fn buffered_stdout(options: &Options) -> BufferedWriter<Box<dyn Write>> {
+    BufferedWriter::new(stdout(options), options.buffering)
+}
+
+/// Standard output, with newlines translated with `--newlines` and decoded as UTF-8 with
+/// `--utf8`.
+fn stdout(options: &Options) -> Box<dyn Write> {
+    let mut output: Box<dyn Write> = Box::new(io::stdout());
+    if let Some(newlines) = options.newlines {
+        output = Box::new(NewlineWriter::new(output, newlines));
+    }
+    if options.utf8 {
+        output = Box::new(Utf8Writer::new(output));
+    }
+    output
 }
 
 fn interpret<P: Interpretable + ?Sized>(program: &P, options: &Options) {
-    program.interpret_stdin(options.memory_size)
-        .unwrap_or_else(|e| error_exit(3, &format!("runtime error: {}.", e)))
+    let result = if options.stats.is_some() {
+        let state = new_state(options);
+        let (result, stats) = program.interpret_stats(state, stdin(options),
+                                                      buffered_stdout(options));
+        print_stats(options, stats);
+        result
+    } else {
+        program.interpret_state(new_state(options), stdin(options), buffered_stdout(options))
+    };
+    result.unwrap_or_else(|e| error_exit(runtime_code(&e), &format!("runtime error: {}.", e)))
+}
+
+/// A fresh tape of the memory size, on huge pages with `--huge-pages`.
+fn new_state(options: &Options) -> State {
+    let size = options.memory_size.unwrap_or(state::DEFAULT_CAPACITY);
+    if options.huge_pages {
+        State::with_huge_pages(size)
+    } else {
+        State::with_capacity(size)
+    }
+}
+
+/// Prints a run’s stats on stderr with `--stats`, counting the time before the run as compile
+/// time.
+fn print_stats(options: &Options, mut stats: RunStats) {
+    if let Some(started) = options.stats {
+        stats.compile_time = Some(started.elapsed().saturating_sub(stats.run_time));
+        eprint!("{}", stats);
+    }
+}
+
+/// Exits for a run-time error in bytecode, giving where in the source it happened.
+fn fault_exit(fault: &Fault, map: &SourceMap, options: &Options) -> ! {
+    let message = match map.span(fault.pc).and_then(|span| options.locate(span.start)) {
+        Some(location) => format!("runtime error: {} at {}.", fault.error, location),
+        None => format!("runtime error: {}.", fault.error),
+    };
+    error_exit(runtime_code(&fault.error), &message)
+}
+
+/// Runs the program in the peephole interpreter, stopping any loop that runs `cap` times in a
+/// row.
+fn run_capped(program: &peephole::Program, cap: u64, options: &Options) {
+    let mut state = new_state(options);
+    let mut output = buffered_stdout(options);
+    match peephole::interpret_capped(program, &mut state, stdin(options), &mut output, cap) {
+        Ok(()) => (),
+        Err(Stopped::Error(e)) => error_exit(runtime_code(&e), &format!("runtime error: {}.", e)),
+        Err(Stopped::RunawayLoop(runaway)) => {
+            drop(output);
+            let location = runaway.span(options.text(), program)
+                .and_then(|span| options.locate(span.start))
+                .map_or(String::new(), |location| format!(" at {}", location));
+            error_exit(code::LIMIT, &format!("error: the loop{} ran {} times in a row, and \
+                                              probably never terminates.", location, cap))
+        }
+    }
+}
+
+/// Runs the program’s bytecode, writing a crash dump to `path` if it fails.
+fn run_dumping(program: &peephole::Program, path: &str, options: &Options) {
+    let map = SourceMap::new(options.text(), program);
+    let program = bytecode::compile(program);
+    let command_line = env::args().skip(1).collect::<Vec<_>>().join(" ");
+
+    let mut state = new_state(options);
+    let mut output = buffered_stdout(options);
+    let result = crash_dump::interpret(&program, &mut state, stdin(options), &mut output,
+                                       &command_line);
+    drop(output);
+    if let Err(mut dump) = result {
+        dump.locate(&map);
+        dump.write_to(path)
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, path)));
+        fault_exit(&dump.fault(), &map, options);
+    }
+}
+
+/// Runs the program’s bytecode, then reports its estimated cost on stderr.
+fn report_cost(options: &Options, model: &CostModel, hottest: usize) {
+    let program = parse(options).peephole_compile();
+    let map = SourceMap::new(options.text(), &program);
+    let program = bytecode::compile(&program);
+
+    let mut state = new_state(options);
+    let mut output = buffered_stdout(options);
+    let profile = cost::profile(&program, &mut state, stdin(options), &mut output)
+        .unwrap_or_else(|fault| fault_exit(&fault, &map, options));
+    drop(output);
+
+    let report = model.report(&program, &profile);
+    eprint!("{}", report.report(hottest, |pc| {
+        map.span(pc).and_then(|span| options.locate(span.start)).unwrap_or_default()
+    }));
+}
+
+/// Runs the program’s bytecode, then reports the opcode pairs it dispatched most on stderr.
+fn report_dispatch(options: &Options, pairs: usize) {
+    let program = parse(options).peephole_compile();
+    let map = SourceMap::new(options.text(), &program);
+    let program = bytecode::compile(&program);
+
+    let mut state = new_state(options);
+    let mut output = buffered_stdout(options);
+    let stats = dispatch::profile(&program, &mut state, stdin(options), &mut output)
+        .unwrap_or_else(|fault| fault_exit(&fault, &map, options));
+    drop(output);
+
+    eprint!("{}", stats.report(pairs));
+}
+
+/// Steps through the program’s bytecode on a worker thread, redrawing the terminal after each
+/// step.
+fn visualize(options: &Options, delay: Duration) {
+    let program = parse(options).peephole_compile();
+    let map = SourceMap::new(options.text(), &program);
+    let program = bytecode::compile(&program);
+
+    let state = new_state(options);
+    let worker = Worker::spawn(Arc::from(program), state, io::stdin(), &events::Options {
+        milestone: 0,
+        start_paused: true,
+        history: history::DEFAULT_LEN,
+    });
+    let layout = Layout::default();
+    let draw = |frame: &Frame| {
+        print!("\x1b[H\x1b[2J{}", layout.render(frame));
+        let _ = io::stdout().flush();
+    };
+
+    let (mut output, mut executed) = (Vec::new(), Vec::new());
+    worker.send(events::Command::Step);
+    worker.send(events::Command::Snapshot);
+    let (pc, steps, end) = loop {
+        match worker.events().recv() {
+            Ok(ExecEvent::Output(byte)) => output.push(byte),
+            Ok(ExecEvent::Snapshot { pc, steps, state }) => {
+                draw(&Frame { source: options.text(), span: map.span(pc), steps, state: &state,
+                              output: &output });
+                thread::sleep(delay);
+                worker.send(events::Command::Step);
+                worker.send(events::Command::Snapshot);
+            }
+            Ok(ExecEvent::History(history)) => executed = history,
+            Ok(ExecEvent::Ended { pc, steps, end }) => break (pc, steps, end),
+            Ok(_) => (),
+            Err(_) => unreachable!("runs end with an event"),
+        }
+    };
+
+    let state = worker.join();
+    draw(&Frame { source: options.text(), span: None, steps, state: &state, output: &output });
+    if let End::Failed(error) = end {
+        if !QUIET.load(Ordering::Relaxed) {
+            history::locate(&mut executed, &map);
+            eprintln!("the last instructions run:");
+            for entry in &executed {
+                let location = entry.span.and_then(|span| options.locate(span.start));
+                eprintln!("  {}: {} at {}", entry.pc, entry.instruction,
+                          location.unwrap_or_default());
+            }
+        }
+        fault_exit(&Fault { error, pc }, &map, options);
+    }
+}
+
+/// Runs the program natively and in the interpreter on all of standard input, reporting where
+/// they first part ways.
+fn run_lockstep(options: &Options) {
+    let program = parse(options).peephole_compile();
+    let mut input = Vec::new();
+    stdin(options).read_to_end(&mut input)
+        .unwrap_or_else(|e| error_exit(code::IO, &format!("error: could not read input: {}.", e)));
+    let state = new_state(options);
+
+    #[cfg(feature = "jit")]
+    let tier = lockstep::Jit(CompileOptions {
+        checked:       !options.unchecked,
+        deterministic: options.deterministic,
+        sanitize:      options.sanitize,
+        speculate:     options.speculate,
+        ..CompileOptions::default()
+    });
+    #[cfg(not(feature = "jit"))]
+    let tier = lockstep::Threaded;
+
+    match lockstep::lockstep(&program, &tier, state, &input) {
+        Ok(checkpoint) => {
+            let mut output = stdout(options);
+            let _ = output.write_all(&checkpoint.output).and_then(|()| output.flush());
+            if let Err(e) = checkpoint.result {
+                error_exit(runtime_code(&e), &format!("runtime error: {}.", e));
+            }
+        }
+        Err(divergence) => {
+            let map = SourceMap::new(options.text(), &program);
+            let pc = match divergence.segment {
+                lockstep::Segment::Program => 0,
+                lockstep::Segment::Code { pc } | lockstep::Segment::Iteration { pc, .. } => pc,
+            };
+            let location = map.span(pc).and_then(|span| options.locate(span.start))
+                .map_or(String::new(), |location| format!(" (at {})", location));
+            let report = divergence.to_string();
+            let (first, rest) = report.split_at(report.find('\n').unwrap_or(report.len()));
+            error_exit(code::RUNTIME, &format!("error: {}{}{}", first, location, rest));
+        }
+    }
+}
+
+/// Explores the program’s bytecode on symbolic input, printing an input for each kind of
+/// run-time error it can reach.
+fn report_symex(options: &Options) {
+    let program = parse(options).peephole_compile();
+    let map = SourceMap::new(options.text(), &program);
+    let program = bytecode::compile(&program);
+
+    let bounds = symex::Options {
+        memory_size: options.memory_size.unwrap_or(state::DEFAULT_CAPACITY),
+        ..symex::Options::default()
+    };
+    let outcome = symex::explore(&program, &bounds);
+    println!("symbolic execution: {} path{}, {}", outcome.paths,
+             if outcome.paths == 1 { "" } else { "s" },
+             if outcome.complete { "all followed to the end" } else { "stopped at the bounds" });
+
+    for finding in &outcome.findings {
+        let location = map.span(finding.pc).and_then(|span| options.locate(span.start))
+            .map_or(String::new(), |location| format!(" at {}", location));
+        let input: String = finding.input.iter()
+            .flat_map(|&byte| ascii::escape_default(byte))
+            .map(char::from)
+            .collect();
+        println!("{}{} on input \"{}\"", finding.error, location, input);
+    }
+    if outcome.findings.is_empty() {
+        println!("{}", if outcome.complete { "no run-time errors on any input" }
+                       else { "no run-time errors found within the bounds" });
+    }
+}
+
+/// Writes a test corpus covering the program’s loops to `dir`, as numbered `.in` files and the
+/// `.out` files the program writes for them.
+fn write_corpus(options: &Options, dir: &str) {
+    let program = bytecode::compile(&parse(options).peephole_compile());
+    let bounds = symex::Options {
+        memory_size: options.memory_size.unwrap_or(state::DEFAULT_CAPACITY),
+        ..symex::Options::default()
+    };
+    let coverage = symex::cover(&program, &bounds);
+    let sandbox = Sandbox { memory_size: bounds.memory_size, ..Sandbox::default() };
+
+    let io_exit = |e: io::Error, path: &Path| -> ! {
+        error_exit(code::IO, &format!("{}: ‘{}’.", e, path.display()))
+    };
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir).unwrap_or_else(|e| io_exit(e, dir));
+    for (n, test) in coverage.tests.iter().enumerate() {
+        let output = sandbox.run_bytecode(&program, &test.input).output;
+        for &(extension, bytes) in &[("in", &test.input), ("out", &output)] {
+            let path = dir.join(format!("{}.{}", n + 1, extension));
+            fs::write(&path, bytes).unwrap_or_else(|e| io_exit(e, &path));
+        }
+    }
+
+    let edges = coverage.tests.iter().map(|test| test.covers.len()).sum::<usize>();
+    println!("corpus: {} tests covering {} of {} loop edges, written to {}", coverage.tests.len(),
+             edges, edges + coverage.uncovered.len(), dir.display());
+}
+
+/// Compiles the program to a native executable by way of C and the system C compiler, which
+/// can be overridden with the `CC` environment variable. Executables are cached.
+fn compile_native(program: &ast::Program, output: &str, options: &Options) {
+    let cc = env::var_os("CC").unwrap_or_else(|| OsString::from("cc"));
+    let cache = Cache::open_default().ok();
+    let key = Key::new("c-exe", program.fingerprint(), &(options.memory_size, options.unchecked,
+                                                         options.seccomp, &cc));
+
+    if let Some(executable) = cache.as_ref().and_then(|cache| cache.get(&key)) {
+        write_executable(output, &executable);
+        return;
+    }
+
+    let source = program.c_compile(options.memory_size, &CompileOptions {
+        checked: !options.unchecked,
+        seccomp: options.seccomp,
+        ..CompileOptions::default()
+    });
+
+    let c_file = env::temp_dir().join(format!("bfi-{}.c", process::id()));
+    fs::write(&c_file, source)
+        .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, c_file.display())));
+
+    let status = Command::new(&cc)
+        .arg("-O2")
+        .arg("-o").arg(output)
+        .arg(&c_file)
+        .status();
+    let _ = fs::remove_file(&c_file);
+
+    match status {
+        Ok(ref status) if status.success() => (),
+        Ok(status) => error_exit(code::COMPILER, &format!("error: C compiler failed ({}).", status)),
+        Err(e) => error_exit(code::COMPILER, &format!("error: could not run ‘{}’: {}.",
+                                         cc.to_string_lossy(), e)),
+    }
+
+    if let (Some(cache), Ok(executable)) = (cache, fs::read(output)) {
+        let _ = cache.put(&key, &executable);
+    }
+}
+
+fn write_executable(output: &str, executable: &[u8]) {
+    fs::write(output, executable)
+        .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, output)));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(output, fs::Permissions::from_mode(0o755));
+    }
+}
+
+/// The number of inputs `bfi minify` compares the programs on.
+const MINIFY_TRIALS: usize = 32;
+
+/// Minifies the program, checks that the result behaves the same unless told not to, and
+/// writes it out.
+fn minify_program(options: &Options, shrink: bool, verify: bool, output: Option<&str>) {
+    let program = parse(options);
+    let mut text = if shrink {
+        minify::shrink(&program.peephole_compile())
+    } else {
+        minify::strip(options.text())
+    };
+
+    if verify {
+        match minify::verify(&program, &text, 1, MINIFY_TRIALS) {
+            Ok(0) if !QUIET.load(Ordering::Relaxed) =>
+                eprintln!("bfi: warning: could not verify, as the program did not halt in time."),
+            Ok(_) => (),
+            Err(mismatch) =>
+                error_exit(code::RUNTIME,
+                           &format!("error: the minified program differs on input {:?}: {}.",
+                                    String::from_utf8_lossy(&mismatch.input), mismatch.reason)),
+        }
+    }
+
+    text.push(b'\n');
+    match output {
+        Some(path) => fs::write(path, &text)
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, path))),
+        None => io::stdout().write_all(&text)
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("{}.", e))),
+    }
+}
+
+/// Lists or clears the compilation cache.
+fn manage_cache(clear: bool) {
+    let cache = Cache::open_default()
+        .unwrap_or_else(|e| error_exit(code::IO, &format!("error: could not open cache: {}.", e)));
+
+    if clear {
+        let count = cache.clear()
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("error: could not clear cache: {}.", e)));
+        println!("Removed {} entries from {}.", count, cache.dir().display());
+    } else {
+        let entries = cache.entries()
+            .unwrap_or_else(|e| error_exit(code::IO, &format!("error: could not read cache: {}.", e)));
+        for entry in entries {
+            println!("{:>10}  {}", entry.size, entry.name);
+        }
+    }
 }
 
 #[cfg(feature = "jit")]
@@ -116,30 +914,196 @@ const DEFAULT_PASS: Pass = Pass::Peephole;
 
 fn get_options() -> Options {
     let mut result = Options {
-        program_text:  Vec::new(),
+        sources:       Sources::new(),
+        include:       Vec::new(),
+        expansion:     None,
+        limits:        SourceLimits::default(),
         memory_size:   None,
+        auto_memory:   false,
+        huge_pages:    false,
         compiler_pass: DEFAULT_PASS,
         unchecked:     false,
+        deterministic: false,
+        outline_loops: false,
+        codegen_threads: 1,
+        debug_symbols: false,
+        sanitize:      false,
+        speculate:     false,
+        seccomp:       false,
+        precompute:    None,
+        max_loop_iterations: None,
+        crash_dump:    None,
+        utf8:          false,
+        newlines:      None,
+        buffering:     Buffering::Unbuffered,
+        input_batch:   1,
+        native_output: None,
+        source_map:    None,
+        audit:         None,
+        stats:         None,
     };
 
     let matches = build_clap_app().get_matches();
+    QUIET.store(matches.is_present("quiet"), Ordering::Relaxed);
 
-    if let Some(size) = matches.value_of("size") {
-        let size = size.parse()
-            .unwrap_or_else(|e|
-                error_exit(1, &format!("error: could not parse memory size: {}.", e)));
-        if size == 0 {
-            error_exit(1, "error: memory size must be at least 1.");
+    if !matches.is_present("no-config") {
+        apply_config(&mut result);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cache") {
+        manage_cache(matches.is_present("clear"));
+        exit(0);
+    }
+
+    if matches.subcommand_matches("capabilities").is_some() {
+        print!("{}", bf::capabilities());
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("analyze") {
+        get_program(matches, &mut result);
+        let summary = stats::summarize(result.text())
+            .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+        print!("{}", summary.report(|offset| result.locate(offset).unwrap_or_default()));
+        if matches.is_present("loops") {
+            print!("{}", summary.report_loops(|offset| result.locate(offset).unwrap_or_default()));
         }
-        result.memory_size = Some(size);
+        if matches.is_present("symex") {
+            report_symex(&result);
+        }
+        if let Some(dir) = matches.value_of("corpus") {
+            write_corpus(&result, dir);
+        }
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cost") {
+        get_program(matches, &mut result);
+
+        let mut model = CostModel::default();
+        for weight in matches.values_of("weight").into_iter().flatten() {
+            let parsed = weight.find('=').and_then(|equals| {
+                let cycles = weight[equals + 1 ..].parse().ok()?;
+                Some(model.set(&weight[.. equals], cycles))
+            });
+            if parsed != Some(true) {
+                let names = cost::WEIGHTS.join(", ");
+                error_exit(code::USAGE, &format!("error: bad weight ‘{}’; expected \
+                                                  NAME=CYCLES, where NAME is one of {}.",
+                                                 weight, names));
+            }
+        }
+        let hottest = matches.value_of("loops").map_or(5, |n| n.parse().unwrap_or_else(|e| {
+            error_exit(code::USAGE, &format!("error: could not parse --loops: {}.", e))
+        }));
+
+        report_cost(&result, &model, hottest);
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("dispatch") {
+        get_program(matches, &mut result);
+        let pairs = matches.value_of("pairs").map_or(10, |n| n.parse().unwrap_or_else(|e| {
+            error_exit(code::USAGE, &format!("error: could not parse --pairs: {}.", e))
+        }));
+        report_dispatch(&result, pairs);
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("explain") {
+        get_program(matches, &mut result);
+        let width = matches.value_of("width").map_or(24, |n| n.parse().unwrap_or_else(|e| {
+            error_exit(code::USAGE, &format!("error: could not parse --width: {}.", e))
+        }));
+        let explanation = explain::explain(result.text(), width)
+            .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+        print!("{}", explanation);
+        if matches.is_present("pseudo") {
+            println!();
+            print!("{}", decompile::decompile(&parse(&result).peephole_compile()));
+        }
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("lang") {
+        get_program(matches, &mut result);
+        let source = str::from_utf8(result.text())
+            .unwrap_or_else(|_| error_exit(code::SYNTAX, "syntax error: source is not UTF-8."));
+        let program = lang::compile(source)
+            .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+        if matches.is_present("run") {
+            interpret(&*program.bytecode_compile(), &result);
+        } else {
+            println!("{}", Text(&*codegen::from_peephole(&program)));
+        }
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("minify") {
+        get_program(matches, &mut result);
+        minify_program(&result, matches.is_present("shrink"), !matches.is_present("no-verify"),
+                       matches.value_of("output"));
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("run") {
+        get_program(matches, &mut result);
+        result.limits.check_source(result.text())
+            .unwrap_or_else(|e| error_exit(code::LIMIT, &format!("error: {}.", e)));
+
+        if matches.is_present("visualize") {
+            let delay = matches.value_of("delay").map_or(100, |n| n.parse().unwrap_or_else(|e| {
+                error_exit(code::USAGE, &format!("error: could not parse --delay: {}.", e))
+            }));
+            visualize(&result, Duration::from_millis(delay));
+            exit(0);
+        }
+
+        if matches.is_present("lockstep") {
+            run_lockstep(&result);
+            exit(0);
+        }
+
+        return result;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("compile") {
+        get_program(matches, &mut result);
+
+        if matches.is_present("unchecked") {
+            result.unchecked = true;
+        }
+        if matches.is_present("seccomp") {
+            result.seccomp = true;
+        }
+
+        let output = matches.value_of("output").map(str::to_owned).unwrap_or_else(|| {
+            matches.value_of("FILE")
+                .and_then(|file| Path::new(file).file_stem())
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "a.out".to_owned())
+        });
+        result.native_output = Some(output);
+
+        return result;
     }
 
+    get_program(&matches, &mut result);
+
     if matches.is_present("jit") {
         #[cfg(feature = "jit")]
-        let _ = result.compiler_pass = Pass::Jit;
+        { result.compiler_pass = Pass::Jit; }
     } else if matches.is_present("llvm") {
         #[cfg(feature = "llvm")]
-        let _ = result.compiler_pass = Pass::Llvm;
+        { result.compiler_pass = Pass::Llvm; }
+    } else if matches.is_present("brainfork") {
+        result.compiler_pass = Pass::Brainfork;
+    } else if matches.is_present("multitape") {
+        result.compiler_pass = Pass::Multitape;
+    } else if matches.is_present("trace") {
+        result.compiler_pass = Pass::Trace;
+    } else if matches.is_present("threaded") {
+        result.compiler_pass = Pass::Threaded;
     } else if matches.is_present("byte") {
         result.compiler_pass = Pass::Bytecode;
     } else if matches.is_present("peep") {
@@ -154,22 +1118,209 @@ fn get_options() -> Options {
         result.unchecked = true;
     }
 
+    if matches.is_present("deterministic") {
+        result.deterministic = true;
+    }
+
+    if matches.is_present("utf8") {
+        result.utf8 = true;
+    }
+
+    if matches.is_present("stats") {
+        result.stats = Some(Instant::now());
+    }
+
+    match matches.value_of("buffer") {
+        Some("none") => result.buffering = Buffering::Unbuffered,
+        Some("line") => result.buffering = Buffering::Line,
+        Some(size) => match size.parse() {
+            Ok(size) if size > 0 => result.buffering = Buffering::Block(size),
+            _ => error_exit(code::USAGE, &format!("error: bad buffering ‘{}’; expected none, \
+                                                   line, or a size in bytes.", size)),
+        },
+        None => (),
+    }
+
+    if let Some(size) = matches.value_of("input-batch") {
+        match size.parse() {
+            Ok(size) if size > 0 => result.input_batch = size,
+            _ => error_exit(code::USAGE, &format!("error: bad input batch ‘{}’; expected a \
+                                                   size in bytes.", size)),
+        }
+    }
+
+    match matches.value_of("newlines") {
+        Some("crlf") => result.newlines = Some(Newlines::Crlf),
+        Some("lf") => result.newlines = Some(Newlines::Lf),
+        Some(style) => error_exit(code::USAGE, &format!("error: unknown newline style ‘{}’; \
+                                                         expected crlf or lf.", style)),
+        None => (),
+    }
+
+    if matches.is_present("outline-loops") {
+        result.outline_loops = true;
+    }
+
+    if matches.is_present("sanitize") {
+        result.sanitize = true;
+    }
+
+    if matches.is_present("speculate") {
+        result.speculate = true;
+    }
+
+    if matches.is_present("debug-symbols") {
+        result.debug_symbols = true;
+    }
+
+    if matches.is_present("auto-memory") {
+        result.auto_memory = true;
+    }
+
+    if matches.is_present("huge-pages") {
+        result.huge_pages = true;
+    }
+
+    if matches.is_present("precompute") {
+        let budget = matches.value_of("precompute-steps").map_or(precompute::DEFAULT_BUDGET, |n| {
+            n.parse().unwrap_or_else(|e|
+                error_exit(code::USAGE, &format!("error: could not parse step budget: {}.", e)))
+        });
+        result.precompute = Some(budget);
+    }
+
+    if let Some(n) = matches.value_of("max-loop-iterations") {
+        result.max_loop_iterations = Some(n.parse().unwrap_or_else(|e| {
+            error_exit(code::USAGE,
+                       &format!("error: could not parse --max-loop-iterations: {}.", e))
+        }));
+    }
+
+    if let Some(path) = matches.value_of("crash-dump") {
+        result.crash_dump = Some(path.to_owned());
+    }
+
+    if let Some(path) = matches.value_of("source-map") {
+        result.source_map = Some(path.to_owned());
+    }
+
+    if let Some(path) = matches.value_of("audit") {
+        result.audit = Some(path.to_owned());
+    }
+
+    if let Some(threads) = matches.value_of("codegen-threads") {
+        let threads = threads.parse()
+            .unwrap_or_else(|e|
+                error_exit(code::USAGE, &format!("error: could not parse thread count: {}.", e)));
+        if threads == 0 {
+            error_exit(code::USAGE, "error: thread count must be at least 1.");
+        }
+        result.codegen_threads = threads;
+    }
+
+    result
+}
+
+/// Gets the memory size and the program text.
+/// Sets the defaults that the `bf.toml` files give, for the flags to override.
+fn apply_config(result: &mut Options) {
+    let dir = env::current_dir().unwrap_or_default();
+    let config = Config::load(&dir).unwrap_or_else(|(path, e)| {
+        let code = if let LoadError::Io(_) = e { code::IO } else { code::USAGE };
+        error_exit(code, &format!("{}: {}.", path.display(), e))
+    });
+
+    if let Some(level) = config.opt_level {
+        result.compiler_pass = match level {
+            0 => Pass::Ast,
+            1 => Pass::Rle,
+            2 => Pass::Peephole,
+            _ => DEFAULT_PASS,
+        };
+    }
+
+    if let Some(ref backend) = config.backend {
+        result.compiler_pass = match &**backend {
+            "ast" => Pass::Ast,
+            "rle" => Pass::Rle,
+            "peep" => Pass::Peephole,
+            "byte" => Pass::Bytecode,
+            "threaded" => Pass::Threaded,
+            "trace" => Pass::Trace,
+            #[cfg(feature = "jit")]
+            "jit" => Pass::Jit,
+            #[cfg(feature = "llvm")]
+            "llvm" => Pass::Llvm,
+            _ => error_exit(code::USAGE, &format!("error: this bfi was built without the ‘{}’ \
+                                                   backend.", backend)),
+        };
+    }
+
+    match config.dialect.as_deref() {
+        Some("brainfork") => result.compiler_pass = Pass::Brainfork,
+        Some("multitape") => result.compiler_pass = Pass::Multitape,
+        _ => (),
+    }
+
+    if config.memory_size.is_some() {
+        result.memory_size = config.memory_size;
+    }
+
+    if let Some(unchecked) = config.unchecked {
+        result.unchecked = unchecked;
+    }
+
+    if config.precompute == Some(true) {
+        result.precompute = Some(precompute::DEFAULT_BUDGET);
+    }
+}
+
+fn get_program(matches: &ArgMatches, result: &mut Options) {
+    if let Some(size) = matches.value_of("size") {
+        let size = size.parse()
+            .unwrap_or_else(|e|
+                error_exit(code::USAGE, &format!("error: could not parse memory size: {}.", e)));
+        if size == 0 {
+            error_exit(code::USAGE, "error: memory size must be at least 1.");
+        }
+        result.memory_size = Some(size);
+    }
+
+    let limit = |name, default| matches.value_of(name).map_or(default, |n| {
+        n.parse().unwrap_or_else(|e|
+            error_exit(code::USAGE, &format!("error: could not parse --{}: {}.", name, e)))
+    });
+    result.limits = SourceLimits {
+        max_source_len: limit("max-source-size", result.limits.max_source_len),
+        max_depth: limit("max-depth", result.limits.max_depth),
+        max_instructions: limit("max-instructions", result.limits.max_instructions),
+    };
+
+    if let Some(dirs) = matches.values_of("include") {
+        result.include.extend(dirs.map(PathBuf::from));
+    }
+
     if let Some(exprs) = matches.values_of("expr") {
         for e in exprs {
-            result.program_text.extend(e.as_bytes());
+            result.sources.push("<expr>", e.as_bytes());
         }
     } else if let Some(files) = matches.values_of("FILE") {
         for f in files {
-            let mut file = File::open(f)
-                .unwrap_or_else(|e| error_exit(1, &format!("{}: ‘{}’.", e, f)));
-            file.read_to_end(&mut result.program_text)
-                .unwrap_or_else(|e| error_exit(1, &format!("{}: ‘{}’.", e, f)));
+            result.sources.read(f, &result.include)
+                .unwrap_or_else(|e| error_exit(code::IO, &format!("{}: ‘{}’.", e, f)));
         }
     } else {
-        error_exit(1, "error: no program given.");
+        error_exit(code::USAGE, "error: no program given.");
     }
 
-    result
+    if matches.is_present("macros") {
+        let expansion = macros::preprocess(&result.sources, &result.include)
+            .unwrap_or_else(|e| error_exit(code::SYNTAX, &format!("syntax error: {}.", e)));
+        result.expansion = Some(expansion);
+    }
+
+    result.limits.check_source(result.text())
+        .unwrap_or_else(|e| error_exit(code::LIMIT, &format!("error: {}.", e)));
 }
 
 fn build_clap_app() -> App<'static, 'static> {
@@ -177,33 +1328,126 @@ fn build_clap_app() -> App<'static, 'static> {
         .version(crate_version!())
         .author("Jesse A. Tov <jesse.tov@gmail.com>")
         .about("A Brainfuck interpreter")
-        .arg(Arg::with_name("expr")
-            .short("e")
-            .long("expr")
-            .value_name("CODE")
-            .help("BF code to execute")
-            .multiple(true)
-            .takes_value(true)
-            .conflicts_with("FILE"))
-        .arg(Arg::with_name("FILE")
-            .help("The source file(s) to interpret")
-            .multiple(true)
-            .conflicts_with("expr")
-            .index(1))
-        .arg(Arg::with_name("size")
-            .short("s")
-            .long("size")
-            .value_name("SIZE")
-            .help("Memory size in bytes (default 30,000)")
-            .takes_value(true))
+        .args(&program_args("The source file(s) to interpret"))
+        .subcommand(SubCommand::with_name("compile")
+            .about("Compiles to a native executable via C")
+            .args(&program_args("The source file(s) to compile"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PROG")
+                .help("Output executable (default: FILE without extension, or a.out)")
+                .takes_value(true))
+            .arg(Arg::with_name("unchecked")
+                .short("u")
+                .long("unchecked")
+                .help("Omit memory bounds checks"))
+            .arg(Arg::with_name("seccomp")
+                .long("seccomp")
+                .help("Allow the executable only to read, write and exit (Linux)")))
+        .subcommand(SubCommand::with_name("cache")
+            .about("Lists the compilation cache’s entries")
+            .arg(Arg::with_name("clear")
+                .long("clear")
+                .help("Removes all entries instead")))
+        .subcommand(SubCommand::with_name("capabilities")
+            .about("Lists the backends, dialects and features this build has"))
+        .subcommand(SubCommand::with_name("analyze")
+            .about("Prints static facts about a program")
+            .args(&program_args("The source file(s) to analyze"))
+            .arg(Arg::with_name("loops")
+                .long("loops")
+                .help("Also list each loop with its balance, and what makes it unbalanced"))
+            .arg(Arg::with_name("symex")
+                .long("symex")
+                .help("Also search for inputs that make the program fail, symbolically"))
+            .arg(Arg::with_name("corpus")
+                .long("corpus")
+                .value_name("DIR")
+                .help("Write inputs that cover each loop’s edges, with their outputs, to DIR")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("cost")
+            .about("Runs a program and reports its estimated cost in cycles")
+            .args(&program_args("The source file(s) to run"))
+            .arg(Arg::with_name("weight")
+                .short("w")
+                .long("weight")
+                .value_name("NAME=CYCLES")
+                .help("Set the cost of a kind of instruction")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true))
+            .arg(Arg::with_name("loops")
+                .long("loops")
+                .value_name("N")
+                .help("How many of the costliest loops to list (default 5)")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("dispatch")
+            .about("Runs a program and reports the opcode pairs its bytecode runs most")
+            .args(&program_args("The source file(s) to run"))
+            .arg(Arg::with_name("pairs")
+                .long("pairs")
+                .value_name("N")
+                .help("How many of the most frequent pairs to list (default 10)")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("explain")
+            .about("Shows the source side by side with the instructions it optimizes to")
+            .args(&program_args("The source file(s) to explain"))
+            .arg(Arg::with_name("width")
+                .long("width")
+                .value_name("N")
+                .help("Width of the source column (default 24)")
+                .takes_value(true))
+            .arg(Arg::with_name("pseudo")
+                .long("pseudo")
+                .help("Also write the program as pseudo-code, with its idioms spelled out")))
+        .subcommand(SubCommand::with_name("lang")
+            .about("Compiles the tiny imperative language to Brainfuck")
+            .args(&program_args("The source file(s) to compile"))
+            .arg(Arg::with_name("run")
+                .long("run")
+                .help("Run the program instead of printing its Brainfuck")))
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs a program, with --visualize showing each step")
+            .args(&program_args("The source file(s) to run"))
+            .arg(Arg::with_name("visualize")
+                .long("visualize")
+                .help("Show the source, tape and output after each step"))
+            .arg(Arg::with_name("lockstep")
+                .long("lockstep")
+                .help("Check native code against the interpreter, loop iteration by iteration")
+                .conflicts_with("visualize"))
+            .arg(Arg::with_name("delay")
+                .long("delay")
+                .value_name("MS")
+                .help("Milliseconds between steps with --visualize (default 100)")
+                .takes_value(true)
+                .requires("visualize")))
+        .subcommand(SubCommand::with_name("minify")
+            .about("Strips a program down to its commands and checks it still behaves the same")
+            .args(&program_args("The source file(s) to minify"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Output file (default: standard output)")
+                .takes_value(true))
+            .arg(Arg::with_name("shrink")
+                .long("shrink")
+                .help("Write out the optimized program instead, dropping dead code"))
+            .arg(Arg::with_name("no-verify")
+                .long("no-verify")
+                .help("Skip running both programs to compare them")))
         .arg(Arg::with_name("ast")
             .long("ast")
             .help("Interpret the unoptimized AST")
-            .conflicts_with_all(&["rle", "peep", "byte", "jit", "llvm"]))
+            .conflicts_with_all(&["rle", "peep", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
         .arg(Arg::with_name("rle")
             .long("rle")
             .help("Interpret the run-length encoded the AST")
-            .conflicts_with_all(&["ast", "peep", "byte", "jit", "llvm"]))
+            .conflicts_with_all(&["ast", "peep", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
         .arg(Arg::with_name("peep")
             .long("peep")
             .help(
@@ -212,39 +1456,231 @@ fn build_clap_app() -> App<'static, 'static> {
                 } else {
                     "Interpret the peephole-optimized AST (default)"
                 })
-            .conflicts_with_all(&["ast", "rle", "byte", "jit", "llvm"]))
+            .conflicts_with_all(&["ast", "rle", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
         .arg(Arg::with_name("byte")
             .long("byte")
             .help("Compile AST to bytecode")
-            .conflicts_with_all(&["ast", "rle", "peep", "jit", "llvm"]));
+            .conflicts_with_all(&["ast", "rle", "peep", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
+        .arg(Arg::with_name("threaded")
+            .long("threaded")
+            .help("Compile AST to closure-threaded code")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
+        .arg(Arg::with_name("brainfork")
+            .long("brainfork")
+            .help("Interpret the Brainfork dialect, where ‘Y’ forks")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "jit", "llvm",
+                                  "multitape", "trace"]))
+        .arg(Arg::with_name("multitape")
+            .long("multitape")
+            .help("Interpret the multi-tape dialect, where braces switch tapes")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "trace"]))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Interpret bytecode, tracing hot loops (experimental)")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape"]))
+        .arg(Arg::with_name("source-map")
+            .long("source-map")
+            .value_name("FILE")
+            .help("Write the bytecode’s source map to FILE (with --byte)")
+            .takes_value(true)
+            .requires("byte"))
+        .arg(Arg::with_name("audit")
+            .long("audit")
+            .value_name("FILE")
+            .help("Write a report of native code’s bounds checks to FILE")
+            .takes_value(true)
+            .conflicts_with_all(&["brainfork", "multitape"]))
+        .arg(Arg::with_name("precompute")
+            .long("precompute")
+            .help("Run programs that read no input at compile time")
+            .conflicts_with_all(&["brainfork", "multitape", "source-map", "audit"]))
+        .arg(Arg::with_name("max-loop-iterations")
+            .long("max-loop-iterations")
+            .value_name("N")
+            .help("Stop a loop that runs N times in a row, as likely infinite")
+            .takes_value(true)
+            .conflicts_with_all(&["ast", "rle", "byte", "threaded", "jit", "llvm",
+                                  "brainfork", "multitape", "trace"]))
+        .arg(Arg::with_name("crash-dump")
+            .long("crash-dump")
+            .value_name("FILE")
+            .help("Write a crash dump to FILE on a run-time error")
+            .takes_value(true)
+            .conflicts_with_all(&["ast", "rle", "threaded", "jit", "llvm", "brainfork",
+                                  "multitape", "trace", "max-loop-iterations"]))
+        .arg(Arg::with_name("precompute-steps")
+            .long("precompute-steps")
+            .value_name("N")
+            .help("Step budget for --precompute (default 10,000,000)")
+            .takes_value(true)
+            .requires("precompute"))
+        .arg(Arg::with_name("auto-memory")
+            .long("auto-memory")
+            .help("Size memory to just the cells the program can reach")
+            .conflicts_with_all(&["brainfork", "multitape"]))
+        .arg(Arg::with_name("huge-pages")
+            .long("huge-pages")
+            .help("Back large tapes with 2 MB huge pages where the OS allows"))
+        .arg(Arg::with_name("no-config")
+            .long("no-config")
+            .help("Ignore bf.toml files"))
+        .arg(Arg::with_name("buffer")
+            .long("buffer")
+            .value_name("POLICY")
+            .help("Buffer output: none (default), line, or a size in bytes")
+            .takes_value(true))
+        .arg(Arg::with_name("input-batch")
+            .long("input-batch")
+            .value_name("BYTES")
+            .help("Read up to BYTES of input at a time in native code")
+            .takes_value(true))
+        .arg(Arg::with_name("newlines")
+            .long("newlines")
+            .value_name("STYLE")
+            .help("Write newlines as STYLE, crlf or lf; drop ‘\\r’ from input")
+            .takes_value(true)
+            .conflicts_with("llvm"))
+        .arg(Arg::with_name("utf8")
+            .long("utf8")
+            .help("Decode output as UTF-8, replacing invalid bytes")
+            .conflicts_with("llvm"))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .help("Report steps, I/O and timings on stderr after the run")
+            .conflicts_with("llvm"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Print no error messages, only exit with the error’s code"))
+        .arg(Arg::with_name("deterministic")
+            .long("deterministic")
+            .help("Avoid address-dependent code generation"));
 
     #[cfg(feature = "llvm")]
     let app = app
         .arg(Arg::with_name("llvm")
             .long("llvm")
             .help("JIT using LLVM")
-            .conflicts_with_all(&["ast", "rle", "peep", "byte", "jit"]));
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "jit",
+                                  "brainfork", "multitape", "trace"]))
+        .arg(Arg::with_name("outline-loops")
+            .long("outline-loops")
+            .help("Compile each top-level loop separately in LLVM")
+            .requires("llvm"))
+        .arg(Arg::with_name("codegen-threads")
+            .long("codegen-threads")
+            .takes_value(true)
+            .value_name("N")
+            .help("Threads for compiling outlined loops (default 1)")
+            .requires("outline-loops"))
+        .arg(Arg::with_name("debug-symbols")
+            .long("debug-symbols")
+            .help("Make LLVM output debuggable with GDB")
+            .requires("llvm"));
 
     #[cfg(feature = "jit")]
     let app = app
         .arg(Arg::with_name("jit")
             .long("jit")
             .help("JIT to native x64 (default)")
-            .conflicts_with_all(&["ast", "rle", "peep", "byte", "llvm"]));
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "llvm",
+                                  "brainfork", "multitape", "trace"]));
 
-    #[cfg(any(feature = "jit"))]
+    #[cfg(feature = "jit")]
     let app = app
         .arg(Arg::with_name("unchecked")
             .short("u")
             .long("unchecked")
             .help("Omit memory bounds checks in JIT")
-            .conflicts_with_all(&["ast", "rle", "peep", "byte", "llvm"]));
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "llvm",
+                                  "brainfork", "multitape", "trace"]))
+        .arg(Arg::with_name("speculate")
+            .long("speculate")
+            .help("Check whole loop iterations in JIT, deoptimizing near the edges")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded", "llvm",
+                                  "brainfork", "multitape", "trace", "unchecked"]));
+
+    #[cfg(any(feature = "jit", feature = "llvm"))]
+    let app = app
+        .arg(Arg::with_name("sanitize")
+            .long("sanitize")
+            .help("Check every memory access in native code")
+            .conflicts_with_all(&["ast", "rle", "peep", "byte", "threaded",
+                                  "brainfork", "multitape", "trace"]));
 
     app
 }
 
+/// Arguments giving the program and its memory size.
+fn program_args(file_help: &'static str) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("expr")
+            .short("e")
+            .long("expr")
+            .value_name("CODE")
+            .help("BF code to execute")
+            .multiple(true)
+            .takes_value(true)
+            .conflicts_with("FILE"),
+        Arg::with_name("FILE")
+            .help(file_help)
+            .multiple(true)
+            .conflicts_with("expr")
+            .index(1),
+        Arg::with_name("include")
+            .short("I")
+            .long("include")
+            .value_name("DIR")
+            .help("Look for source files in DIR too")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("macros")
+            .short("m")
+            .long("macros")
+            .help("Expand @define macros and @include files"),
+        Arg::with_name("max-source-size")
+            .long("max-source-size")
+            .value_name("BYTES")
+            .help("Refuse programs longer than BYTES")
+            .takes_value(true),
+        Arg::with_name("max-depth")
+            .long("max-depth")
+            .value_name("N")
+            .help("Refuse programs with loops nested more than N deep")
+            .takes_value(true),
+        Arg::with_name("max-instructions")
+            .long("max-instructions")
+            .value_name("N")
+            .help("Refuse programs that optimize to more than N instructions")
+            .takes_value(true),
+        Arg::with_name("size")
+            .short("s")
+            .long("size")
+            .value_name("SIZE")
+            .help("Memory size in bytes (default 30,000)")
+            .takes_value(true),
+    ]
+}
+
+/// Exits with the given code, printing the message unless `--quiet` was given.
 fn error_exit(code: i32, msg: &str) -> ! {
-    eprintln!("bfi: {}", msg);
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("bfi: {}", msg);
+    }
     exit(code)
 }
 
+/// The exit code for a run-time error.
+fn runtime_code(error: &Error) -> i32 {
+    match *error {
+        Error::Io(_) => code::IO,
+        _ => code::RUNTIME,
+    }
+}
+
@@ -0,0 +1,233 @@
+//! The Brainfork dialect, which adds a fork command `Y`.
+//!
+//! Forking duplicates the running thread. In the parent, the current cell is set to 0. The
+//! child gets a copy of the tape after the pointer, so its first cell is the one to the
+//! parent’s right, and that cell is set to 1. Both continue after the `Y`.
+//!
+//! Threads are scheduled cooperatively, round-robin, a fixed number of instructions each turn,
+//! as by the [multi-machine executor](../machine/index.html). They share the input and output:
+//! each read takes the next byte of input, whichever thread asks, and writes are interleaved
+//! in the order they happen.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use bytecode::{self, StepResult};
+use common::{BfResult, Error};
+use state::State;
+
+/// A compiled Brainfork program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Program {
+    /// The program, with each fork compiled to a no-op `Add(0)`.
+    code: Box<bytecode::Program>,
+    /// Whether the instruction at each address is a fork.
+    forks: Box<[bool]>,
+}
+
+/// Limits on a Brainfork run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// How many instructions each thread runs per turn.
+    pub quantum: usize,
+    /// The most threads that may exist at once. Forking when there are this many sets the
+    /// parent’s cell to 0 but creates no child.
+    pub max_threads: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { quantum: 1_000, max_threads: 1_024 }
+    }
+}
+
+/// Parses Brainfork source. Runs of moves and of adds are combined, but loops are not
+/// optimized.
+///
+/// # Errors
+///
+/// Unmatched square brackets will result in an `Err` return. See
+/// [`common::Error`](../common/enum.Error.html).
+pub fn parse_program(source: &[u8]) -> BfResult<Program> {
+    use common::Instruction::*;
+
+    let mut code = Vec::new();
+    let mut forks = Vec::new();
+    let mut open = Vec::new();
+
+    for &c in source {
+        // Combine with the previous instruction, unless that is a fork.
+        let previous = if forks.last() == Some(&false) { code.last_mut() } else { None };
+        match (c, previous) {
+            (b'+', Some(&mut Add(ref mut n))) => { *n = n.wrapping_add(1); continue; }
+            (b'-', Some(&mut Add(ref mut n))) => { *n = n.wrapping_sub(1); continue; }
+            (b'>', Some(&mut Right(ref mut n))) => { *n += 1; continue; }
+            (b'<', Some(&mut Left(ref mut n))) => { *n += 1; continue; }
+            _ => (),
+        }
+
+        let instruction = match c {
+            b'+' => Add(1),
+            b'-' => Add(255),
+            b'>' => Right(1),
+            b'<' => Left(1),
+            b',' => In,
+            b'.' => Out,
+            b'[' => {
+                open.push(code.len());
+                JumpZero(0)
+            }
+            b']' => {
+                let begin = open.pop().ok_or(Error::UnmatchedEnd)?;
+                code[begin] = JumpZero(bytecode::usize_to_count(code.len()));
+                JumpNotZero(bytecode::usize_to_count(begin))
+            }
+            b'Y' => Add(0),
+            _ => continue,
+        };
+
+        code.push(instruction);
+        forks.push(c == b'Y');
+    }
+
+    if !open.is_empty() {
+        return Err(Error::UnmatchedBegin);
+    }
+
+    Ok(Program { code: code.into_boxed_slice(), forks: forks.into_boxed_slice() })
+}
+
+/// A thread of a running program.
+struct Thread {
+    state: State,
+    pc:    usize,
+}
+
+/// Runs a program, starting with one thread on the given state.
+///
+/// # Errors
+///
+/// The first run-time error in any thread stops the whole program.
+pub fn run<R: Read, W: Write>(program: &Program, state: State, mut input: R, mut output: W,
+                              limits: &Limits) -> BfResult<()>
+{
+    let mut threads = VecDeque::new();
+    threads.push_back(Thread { state, pc: 0 });
+
+    while let Some(mut thread) = threads.pop_front() {
+        let mut halted = false;
+
+        for _ in 0 .. limits.quantum.max(1) {
+            if program.forks.get(thread.pc) == Some(&true) {
+                if threads.len() + 1 < limits.max_threads {
+                    let mut child = thread.state.split_suffix()?;
+                    child.store(1);
+                    threads.push_back(Thread { state: child, pc: thread.pc + 1 });
+                }
+                thread.state.store(0);
+                thread.pc += 1;
+                continue;
+            }
+
+            let mut byte = None;
+            let result = loop {
+                let Thread { ref mut state, ref mut pc } = thread;
+                match bytecode::step(&program.code, state, pc, &mut byte)? {
+                    StepResult::NeedsInput => byte = Some(read(&mut input)),
+                    result => break result,
+                }
+            };
+
+            match result {
+                StepResult::Output(byte) => { let _ = output.write_all(&[byte]); }
+                StepResult::Halted => {
+                    halted = true;
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        if !halted {
+            threads.push_back(thread);
+        }
+    }
+
+    let _ = output.flush();
+    Ok(())
+}
+
+/// Runs a program from memory, returning its output.
+pub fn run_memory(program: &Program, memory_size: Option<usize>, input: &[u8],
+                  limits: &Limits) -> BfResult<Vec<u8>>
+{
+    let state = memory_size.map(State::with_capacity).unwrap_or_default();
+    let mut output = Vec::new();
+    run(program, state, input, &mut output, limits)?;
+    Ok(output)
+}
+
+/// Reads a byte, with end of input reading as 0 like in the interpreters.
+fn read<R: Read>(input: &mut R) -> u8 {
+    let mut byte = [0];
+    let _ = input.read_exact(&mut byte);
+    byte[0]
+}
+
+impl Program {
+    /// The compiled instructions, with each fork compiled to a no-op `Add(0)`.
+    pub fn code(&self) -> &bytecode::Program {
+        &self.code
+    }
+
+    /// Whether the instruction at the given address is a fork.
+    pub fn is_fork(&self, pc: usize) -> bool {
+        self.forks.get(pc) == Some(&true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn run_source(source: &[u8], input: &[u8]) -> BfResult<Vec<u8>> {
+        run_memory(&parse_program(source).unwrap(), Some(64), input, &Limits::default())
+    }
+
+    #[test]
+    fn plain_brainfuck_runs() {
+        let factor = parse_program(FACTOR_SRC).unwrap();
+        assert_eq!(run_memory(&factor, None, b"100\n", &Limits::default()),
+                   Ok(b"100: 2 2 5 5\n".to_vec()));
+        assert_eq!(parse_program(b"[").map(|_| ()), Err(Error::UnmatchedBegin));
+        assert_eq!(parse_program(b"]").map(|_| ()), Err(Error::UnmatchedEnd));
+    }
+
+    #[test]
+    fn fork_splits_parent_and_child() {
+        // The parent sees 0 and prints ‘P’; the child sees 1 and prints ‘C’.
+        let source = b"Y>+<[>-<>>++++++++[<<++++++++>>-]<<++.[-]]\
+                       >[->++++++++++[<++++++++>-]<.[-]]";
+        let mut output = run_source(source, b"").unwrap();
+        output.sort();
+        assert_eq!(output, b"CP");
+    }
+
+    #[test]
+    fn threads_share_input_and_limits_hold() {
+        // Each of two threads reads one byte and echoes it.
+        let output = run_source(b"Y,.", b"ab").unwrap();
+        let mut sorted = output.clone();
+        sorted.sort();
+        assert_eq!(sorted, b"ab");
+
+        // Three forks make eight threads, unless limited.
+        let program = parse_program(b"YYY+.").unwrap();
+        let limits = Limits { quantum: 1, max_threads: 2 };
+        assert_eq!(run_memory(&program, Some(8), b"", &Limits::default()).unwrap().len(), 8);
+        assert_eq!(run_memory(&program, Some(8), b"", &limits).unwrap().len(), 2);
+        assert_eq!(run_memory(&program, Some(2), b"", &Limits::default()),
+                   Err(Error::PointerOverflow));
+    }
+}
@@ -0,0 +1,363 @@
+//! Breakpoints for the [event-channel worker](../events/index.html), with conditions.
+//!
+//! A [`Breakpoint`](struct.Breakpoint.html) stops a run before the instruction at an address,
+//! found from a source position with [`SourceMap::pc_at`](../source_map/struct.SourceMap.html),
+//! either every time or only when its [`Condition`](struct.Condition.html) holds. Conditions are
+//! small expressions over the machine and the breakpoint’s hit count:
+//!
+//!  - `cell[3] == 0`, where the index can be any expression, such as `cell[ptr - 1]`;
+//!  - `ptr > 100`, the pointer’s position;
+//!  - `hits == 5` or `hits % 10 == 0`, how many times the run has reached the breakpoint,
+//!    counting this time;
+//!
+//! combined with `+ - * / %`, the comparisons `== != < <= > >=`, `!`, `&&`, `||` and
+//! parentheses, as in C. Values are integers, with comparisons giving 1 or 0, cells past either
+//! end of the tape reading as 0, and division by 0 giving 0.
+//!
+//! A breakpoint can also carry a [script](../script/index.html) to run each time it stops the
+//! run.
+
+use std::fmt;
+use std::str::FromStr;
+
+use script::Script;
+use state::State;
+
+/// A place for a run to stop.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Breakpoint {
+    /// The address of the instruction to stop before.
+    pub pc: usize,
+    /// When to stop there, or `None` for every time.
+    pub condition: Option<Condition>,
+    /// What to do when it stops the run, or `None` to just pause.
+    pub script: Option<Script>,
+    hits: u64,
+}
+
+impl Breakpoint {
+    /// A breakpoint that stops before the instruction at `pc` every time.
+    pub fn at(pc: usize) -> Self {
+        Breakpoint { pc, condition: None, script: None, hits: 0 }
+    }
+
+    /// Stops only when the condition holds.
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    /// Runs the script when it stops the run.
+    pub fn then(mut self, script: Script) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    /// How many times the run has reached the breakpoint.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Counts a hit in the given state, returning whether to stop.
+    pub fn hit(&mut self, state: &State) -> bool {
+        self.hits += 1;
+        self.condition.as_ref().is_none_or(|condition| condition.holds(state, self.hits))
+    }
+}
+
+/// An expression deciding whether a breakpoint stops the run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Condition {
+    expr: Expr,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Expr {
+    Number(i64),
+    Cell(Box<Expr>),
+    Pointer,
+    Hits,
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Op {
+    Add, Sub, Mul, Div, Rem,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    And, Or,
+}
+
+impl Condition {
+    /// Whether the condition holds in the given state, at the given hit count.
+    pub fn holds(&self, state: &State, hits: u64) -> bool {
+        self.expr.eval(state, hits) != 0
+    }
+}
+
+impl Expr {
+    pub(crate) fn eval(&self, state: &State, hits: u64) -> i64 {
+        match *self {
+            Expr::Number(n) => n,
+            Expr::Cell(ref index) => {
+                let index = index.eval(state, hits);
+                if index < 0 { return 0; }
+                state.memory().get(index as usize).map_or(0, |cell| i64::from(cell.0))
+            }
+            Expr::Pointer => state.pointer() as i64,
+            Expr::Hits => hits as i64,
+            Expr::Not(ref operand) => (operand.eval(state, hits) == 0) as i64,
+            Expr::Binary(Op::And, ref left, ref right) => {
+                (left.eval(state, hits) != 0 && right.eval(state, hits) != 0) as i64
+            }
+            Expr::Binary(Op::Or, ref left, ref right) => {
+                (left.eval(state, hits) != 0 || right.eval(state, hits) != 0) as i64
+            }
+            Expr::Binary(op, ref left, ref right) => {
+                let (a, b) = (left.eval(state, hits), right.eval(state, hits));
+                match op {
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Mul => a.wrapping_mul(b),
+                    Op::Div => a.checked_div(b).unwrap_or(0),
+                    Op::Rem => a.checked_rem(b).unwrap_or(0),
+                    Op::Eq => (a == b) as i64,
+                    Op::Ne => (a != b) as i64,
+                    Op::Lt => (a < b) as i64,
+                    Op::Le => (a <= b) as i64,
+                    Op::Gt => (a > b) as i64,
+                    Op::Ge => (a >= b) as i64,
+                    Op::And | Op::Or => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// An error parsing a condition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConditionError {
+    /// The byte offset where the error was detected.
+    pub offset: usize,
+    /// What went wrong.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.offset + 1, self.message)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(s: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser::new(s);
+        let expr = parser.expression()?;
+        parser.end()?;
+        Ok(Condition { expr })
+    }
+}
+
+/// The binary operators, with their spellings, loosest first; each entry binds tighter than the
+/// ones before it.
+const LEVELS: &[&[(&str, Op)]] = &[
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Eq), ("!=", Op::Ne)],
+    &[("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+    &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+];
+
+/// A parser for expressions, which scripts share.
+pub(crate) struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(text: &'a str) -> Self {
+        Parser { text, position: 0 }
+    }
+
+    pub(crate) fn expression(&mut self) -> Result<Expr, ConditionError> {
+        self.expr(0)
+    }
+
+    /// Fails unless only space is left.
+    pub(crate) fn end(&mut self) -> Result<(), ConditionError> {
+        self.skip_space();
+        if self.position < self.text.len() {
+            return Err(self.error("expected an operator"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The text from `start` up to here.
+    pub(crate) fn since(&self, start: usize) -> &'a str {
+        &self.text[start .. self.position]
+    }
+
+    /// Takes the next word, which is empty if there is none.
+    pub(crate) fn word(&mut self) -> &'a str {
+        self.skip_space();
+        let rest = self.rest();
+        let word = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        self.position += word;
+        &rest[.. word]
+    }
+
+    /// Parses operators at `level` and tighter, associating to the left.
+    fn expr(&mut self, level: usize) -> Result<Expr, ConditionError> {
+        if level == LEVELS.len() {
+            return self.atom();
+        }
+
+        let mut left = self.expr(level + 1)?;
+        while let Some(op) = self.operator(LEVELS[level]) {
+            let right = self.expr(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn operator(&mut self, ops: &[(&str, Op)]) -> Option<Op> {
+        self.skip_space();
+        let &(spelling, op) = ops.iter().find(|&&(spelling, _)| self.rest().starts_with(spelling))?;
+        self.position += spelling.len();
+        Some(op)
+    }
+
+    fn atom(&mut self) -> Result<Expr, ConditionError> {
+        self.skip_space();
+        let rest = self.rest();
+
+        if rest.starts_with("(") {
+            self.position += 1;
+            let expr = self.expr(0)?;
+            self.expect(")")?;
+            Ok(expr)
+        } else if rest.starts_with("!") {
+            self.position += 1;
+            Ok(Expr::Not(Box::new(self.atom()?)))
+        } else if rest.starts_with("-") {
+            self.position += 1;
+            let operand = self.atom()?;
+            Ok(Expr::Binary(Op::Sub, Box::new(Expr::Number(0)), Box::new(operand)))
+        } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let number = rest[.. digits].parse().map_err(|_| self.error("number too large"))?;
+            self.position += digits;
+            Ok(Expr::Number(number))
+        } else {
+            let start = self.position;
+            match self.word() {
+                "ptr" => Ok(Expr::Pointer),
+                "hits" => Ok(Expr::Hits),
+                "cell" => {
+                    self.expect("[")?;
+                    let index = self.expr(0)?;
+                    self.expect("]")?;
+                    Ok(Expr::Cell(Box::new(index)))
+                }
+                word => {
+                    self.position = start;
+                    Err(self.error(if word.is_empty() {
+                        "expected a number, ‘cell[…]’, ‘ptr’ or ‘hits’"
+                    } else {
+                        "unknown name; expected ‘cell’, ‘ptr’ or ‘hits’"
+                    }))
+                }
+            }
+        }
+    }
+
+    pub(crate) fn expect(&mut self, token: &'static str) -> Result<(), ConditionError> {
+        if self.eat(token) {
+            return Ok(());
+        }
+        Err(self.error(match token {
+            ")" => "expected ‘)’",
+            "]" => "expected ‘]’",
+            "[" => "expected ‘[’",
+            "=" => "expected ‘=’",
+            _ => "expected ‘,’",
+        }))
+    }
+
+    /// Takes the given token if it comes next.
+    pub(crate) fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.rest().starts_with(token);
+        if found {
+            self.position += token.len();
+        }
+        found
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position ..]
+    }
+
+    fn skip_space(&mut self) {
+        while self.text.as_bytes().get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    pub(crate) fn error(&self, message: &'static str) -> ConditionError {
+        ConditionError { offset: self.position, message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holds(condition: &str, state: &State, hits: u64) -> bool {
+        condition.parse::<Condition>().unwrap().holds(state, hits)
+    }
+
+    #[test]
+    fn conditions_look_at_cells_the_pointer_and_hits() {
+        let mut state = State::with_capacity(8);
+        state.up(5);
+        state.right(3usize).unwrap();
+        state.up(2);
+
+        assert!(holds("cell[0] == 5 && cell[3] == 2", &state, 1));
+        assert!(holds("cell[ptr - 3] * 2 > cell[ptr] + 7", &state, 1));
+        assert!(holds("cell[100] == 0 && cell[-1] == 0 && !cell[1]", &state, 1));
+        assert!(holds("ptr == 3 || 1 / 0", &state, 1));
+        assert!(!holds("(ptr == 3 || cell[0]) && hits % 10 == 0", &state, 9));
+        assert!(holds("(ptr == 3 || cell[0]) && hits % 10 == 0", &state, 10));
+        assert!(holds("-ptr + 4 == 1", &state, 1));
+    }
+
+    #[test]
+    fn bad_conditions_say_where() {
+        let error = |text: &str| text.parse::<Condition>().unwrap_err().to_string();
+        assert_eq!(error("cell[0] = 1"), "column 9: expected an operator");
+        assert_eq!(error("cells == 1"), "column 1: unknown name; expected ‘cell’, ‘ptr’ or ‘hits’");
+        assert_eq!(error("(ptr > 3"), "column 9: expected ‘)’");
+        assert_eq!(error("cell 3"), "column 6: expected ‘[’");
+        assert_eq!(error("ptr >"), "column 6: expected a number, ‘cell[…]’, ‘ptr’ or ‘hits’");
+    }
+
+    #[test]
+    fn breakpoints_count_every_hit() {
+        let state = State::new();
+        let mut breakpoint = Breakpoint::at(4).when("hits >= 2".parse().unwrap());
+        assert!(!breakpoint.hit(&state));
+        assert!(breakpoint.hit(&state));
+        assert_eq!(breakpoint.hits(), 2);
+        assert!(Breakpoint::at(0).hit(&state));
+    }
+}
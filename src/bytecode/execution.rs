@@ -0,0 +1,268 @@
+use state::State;
+use common::{BfResult, Error, Instruction};
+use traits::IntoUsize;
+use super::*;
+
+/// What happened in one step of an [`Execution`](struct.Execution.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StepResult {
+    /// An instruction ran without I/O.
+    Continue,
+    /// The next instruction reads input, which must be supplied with
+    /// [`Execution::provide_input`](struct.Execution.html#method.provide_input) before
+    /// it can run.
+    NeedsInput,
+    /// An instruction wrote the given byte.
+    Output(u8),
+    /// The program has finished.
+    Halted,
+}
+
+/// A bytecode program being run one step at a time.
+///
+/// Rather than reading and writing through `Read` and `Write`, an execution hands its I/O to
+/// the host, so a GUI, game, or grader can drive it one instruction, or one I/O event, at a
+/// time without threads. As an `Iterator`, it yields each I/O event and then the `Halted`
+/// step, or the first error. It yields `NeedsInput` again each time until input is provided,
+/// so a program that reads must be iterated `by_ref` and fed between events.
+#[derive(Clone, Debug)]
+pub struct Execution<'a> {
+    program: &'a Program,
+    state: State,
+    pc: usize,
+    input: Option<u8>,
+    done: bool,
+}
+
+impl<'a> Execution<'a> {
+    /// Starts running a program against the given state.
+    pub fn new(program: &'a Program, state: State) -> Self {
+        Execution { program, state, pc: 0, input: None, done: false }
+    }
+
+    /// Goes on with a run that stopped before address `pc` with the given state, such as one
+    /// that native code left at a [`Safepoint`](../rts/struct.Safepoint.html).
+    pub fn resume(program: &'a Program, state: State, pc: usize) -> Self {
+        Execution { program, state, pc, input: None, done: false }
+    }
+
+    /// Supplies the byte read by the next input instruction, or `None` for end of input,
+    /// which reads as 0 like in the other interpreters.
+    pub fn provide_input(&mut self, byte: Option<u8>) {
+        self.input = Some(byte.unwrap_or(0));
+    }
+
+    /// The address of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The machine state.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The machine state, for modification between steps.
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Stops the execution, returning the machine state.
+    pub fn into_state(self) -> State {
+        self.state
+    }
+
+    /// Runs one instruction.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the instruction fails, leaving the address at the failing instruction.
+    pub fn step(&mut self) -> BfResult<StepResult> {
+        step(self.program, &mut self.state, &mut self.pc, &mut self.input)
+    }
+
+    /// Runs until the next I/O event or the end of the program.
+    pub fn run(&mut self) -> BfResult<StepResult> {
+        loop {
+            match self.step()? {
+                StepResult::Continue => (),
+                result => return Ok(result),
+            }
+        }
+    }
+}
+
+/// Runs the instruction at `pc`, taking the byte for an input instruction from `input`.
+pub(crate) fn step(program: &Program, state: &mut State, pc: &mut usize, input: &mut Option<u8>)
+                   -> BfResult<StepResult>
+{
+    match program.get(*pc) {
+        Some(&instruction) => execute(instruction, state, pc, input),
+        None => Ok(StepResult::Halted),
+    }
+}
+
+/// Runs `instruction`, found at `pc`, and advances `pc`, unless it needs input not yet in
+/// `input`.
+#[inline]
+pub(crate) fn execute(instruction: Instruction, state: &mut State, pc: &mut usize,
+                      input: &mut Option<u8>) -> BfResult<StepResult>
+{
+    use common::Instruction::*;
+
+    let mut result = StepResult::Continue;
+
+    match instruction {
+        Left(count) => state.left(count)?,
+        Right(count) => state.right(count)?,
+        Add(count) => state.up(count),
+
+        In => match input.take() {
+            Some(byte) => state.store(byte),
+            None => return Ok(StepResult::NeedsInput),
+        },
+
+        Out => result = StepResult::Output(state.load()),
+
+        JumpZero(address) => {
+            if state.load() == 0 {
+                *pc = address.into_usize();
+            }
+        }
+
+        JumpNotZero(address) => {
+            if state.load() != 0 {
+                *pc = address.into_usize();
+            }
+        }
+
+        SetZero => state.store(0),
+
+        OffsetAddRight(offset) => {
+            if state.load() != 0 {
+                let value = state.load();
+                state.up_pos_offset(offset, value)?;
+                state.store(0);
+            }
+        }
+
+        OffsetAddLeft(offset) => {
+            if state.load() != 0 {
+                let value = state.load();
+                state.up_neg_offset(offset, value)?;
+                state.store(0);
+            }
+        }
+
+        FindZeroRight(offset) => {
+            while state.load() != 0 {
+                state.right(offset)?;
+            }
+        }
+
+        FindZeroLeft(offset) => {
+            while state.load() != 0 {
+                state.left(offset)?;
+            }
+        }
+
+        DivMod => { state.div_mod(); }
+        IndexRight(skip) => { state.index_right(skip); }
+        IndexLeft(skip) => { state.index_left(skip); }
+    }
+
+    *pc += 1;
+    Ok(result)
+}
+
+impl<'a> Iterator for Execution<'a> {
+    type Item = Result<StepResult, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+
+        let result = self.run();
+        if matches!(result, Ok(StepResult::Halted) | Err(_)) {
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn compile(source: &[u8]) -> Box<Program> {
+        ::ast::parse_program(source).unwrap().bytecode_compile()
+    }
+
+    /// Runs an execution to completion, feeding it `input`, and returns its output.
+    fn drive(execution: &mut Execution, input: &[u8]) -> BfResult<Vec<u8>> {
+        let mut input = input.iter().cloned();
+        let mut output = Vec::new();
+
+        loop {
+            match execution.run()? {
+                StepResult::NeedsInput => execution.provide_input(input.next()),
+                StepResult::Output(byte) => output.push(byte),
+                StepResult::Continue => unreachable!(),
+                StepResult::Halted => return Ok(output),
+            }
+        }
+    }
+
+    #[test]
+    fn factoring() {
+        let program = compile(FACTOR_SRC);
+        let mut execution = Execution::new(&program, State::new());
+        assert_eq!(drive(&mut execution, b"100\n"), Ok(b"100: 2 2 5 5\n".to_vec()));
+    }
+
+    #[test]
+    fn steps_wait_for_input() {
+        let program = compile(b",+.");
+        let mut execution = Execution::new(&program, State::new());
+        assert_eq!(execution.step(), Ok(StepResult::NeedsInput));
+        assert_eq!(execution.step(), Ok(StepResult::NeedsInput));
+        assert_eq!(execution.pc(), 0);
+
+        execution.provide_input(Some(b'A'));
+        assert_eq!(execution.step(), Ok(StepResult::Continue));
+        assert_eq!(execution.step(), Ok(StepResult::Continue));
+        assert_eq!(execution.step(), Ok(StepResult::Output(b'B')));
+        assert_eq!(execution.step(), Ok(StepResult::Halted));
+    }
+
+    #[test]
+    fn resumed_runs_go_on_from_the_address() {
+        let program = compile(b"++++++[>++++++++<-]>[.+<+>]");
+        let mut execution = Execution::new(&program, State::new());
+        for _ in 0 .. 20 {
+            assert_eq!(execution.step(), Ok(StepResult::Continue));
+        }
+        let pc = execution.pc();
+
+        let mut execution = Execution::resume(&program, execution.into_state(), pc);
+        let output = drive(&mut execution, b"").unwrap();
+        assert_eq!((output.len(), output[0], output[207]), (208, b'0', 255));
+    }
+
+    #[test]
+    fn iterates_over_events() {
+        let program = compile(HELLO_WORLD_SRC);
+        let output: Vec<u8> = Execution::new(&program, State::new())
+            .filter_map(|result| match result {
+                Ok(StepResult::Output(byte)) => Some(byte),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(output, b"Hello, World!");
+
+        let program = compile(b">.<<");
+        let events: Vec<_> = Execution::new(&program, State::new()).collect();
+        assert_eq!(events, vec![Ok(StepResult::Output(0)), Err(Error::PointerUnderflow)]);
+    }
+}
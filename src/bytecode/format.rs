@@ -0,0 +1,281 @@
+//! The `.bfc` binary format for bytecode programs.
+//!
+//! A `.bfc` file starts with the magic bytes `\0bfc` and a little-endian `u16` giving the
+//! version of the opcode table it was written with, followed by the number of instructions
+//! and the instructions themselves. Each instruction is an opcode byte and, for those that take
+//! one, an operand: a byte for `Add`, and otherwise an unsigned LEB128 number.
+//!
+//! Opcodes are only ever added at the end of the table, and each version says how many there
+//! are, so [`read`](fn.read.html) loads every earlier version, treating a file’s opcodes by the
+//! table it was written with. A file from a newer version, or one using an opcode its version
+//! did not have, is refused rather than run as something else:
+//!
+//!  - Version 1 has the twelve original instructions, `Left` through `FindZeroLeft`.
+//!  - Version 2 adds `DivMod`.
+//!  - Version 3 adds `IndexRight` and `IndexLeft`.
+
+use std::fmt;
+
+use common::Count;
+use super::Program;
+use traits::IntoUsize;
+
+/// The magic bytes that start every `.bfc` file.
+pub const MAGIC: [u8; 4] = *b"\0bfc";
+
+/// The version of the opcode table that [`write`](fn.write.html) uses.
+pub const VERSION: u16 = 3;
+
+/// The number of opcodes in each version’s table, indexed by version.
+const OPCODES: [u8; VERSION as usize + 1] = [0, 12, 13, 15];
+
+/// An error loading a `.bfc` file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FormatError {
+    /// The file does not start with the magic bytes.
+    NotBytecode,
+    /// The file was written with a version of the format this crate does not know.
+    UnsupportedVersion(u16),
+    /// The file ends partway through.
+    Truncated,
+    /// The instruction at the given address has an opcode its file’s version does not have.
+    UnknownOpcode(usize, u8),
+    /// The instruction at the given address has an operand too large for a `Count`, or a jump
+    /// that does not match.
+    BadOperand(usize),
+    /// There is more data after the instructions.
+    TrailingData,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FormatError::*;
+
+        match *self {
+            NotBytecode => write!(f, "not a bytecode file"),
+            UnsupportedVersion(version) =>
+                write!(f, "unsupported bytecode version {} (the latest is {})", version, VERSION),
+            Truncated => write!(f, "bytecode file is truncated"),
+            UnknownOpcode(pc, opcode) =>
+                write!(f, "unknown opcode {} at instruction {}", opcode, pc),
+            BadOperand(pc) => write!(f, "bad operand at instruction {}", pc),
+            TrailingData => write!(f, "trailing data after the bytecode"),
+        }
+    }
+}
+
+/// The result of loading a `.bfc` file.
+pub type FormatResult<T> = Result<T, FormatError>;
+
+/// Serializes a program in the current version of the format.
+pub fn write(program: &Program) -> Vec<u8> {
+    use common::Instruction::*;
+
+    let mut result = MAGIC.to_vec();
+    result.extend_from_slice(&[VERSION as u8, (VERSION >> 8) as u8]);
+    write_number(&mut result, program.len() as u64);
+
+    for &instruction in program {
+        let (opcode, operand) = match instruction {
+            Left(count)           => (0, Some(count)),
+            Right(count)          => (1, Some(count)),
+            Add(amount)           => {
+                result.extend_from_slice(&[2, amount]);
+                continue;
+            }
+            In                    => (3, None),
+            Out                   => (4, None),
+            JumpZero(address)     => (5, Some(address)),
+            JumpNotZero(address)  => (6, Some(address)),
+            SetZero               => (7, None),
+            OffsetAddRight(count) => (8, Some(count)),
+            OffsetAddLeft(count)  => (9, Some(count)),
+            FindZeroRight(count)  => (10, Some(count)),
+            FindZeroLeft(count)   => (11, Some(count)),
+            DivMod                => (12, None),
+            IndexRight(count)     => (13, Some(count)),
+            IndexLeft(count)      => (14, Some(count)),
+        };
+
+        result.push(opcode);
+        if let Some(operand) = operand {
+            write_number(&mut result, operand.into_usize() as u64);
+        }
+    }
+
+    result
+}
+
+/// Loads a program written with this or any earlier version of the format.
+pub fn read(bytes: &[u8]) -> FormatResult<Box<Program>> {
+    use common::Instruction::*;
+
+    if !bytes.starts_with(&MAGIC) {
+        return Err(FormatError::NotBytecode);
+    }
+    let mut reader = Reader { bytes, position: MAGIC.len() };
+
+    let version = u16::from(reader.byte()?) | u16::from(reader.byte()?) << 8;
+    if version == 0 || version > VERSION {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+    let opcodes = OPCODES[version as usize];
+
+    let len = reader.number()?;
+    // Each instruction takes at least a byte, which bounds the allocation.
+    if len > (bytes.len() - reader.position) as u64 {
+        return Err(FormatError::Truncated);
+    }
+
+    let mut instructions = Vec::with_capacity(len as usize);
+    let mut open = Vec::new();
+
+    for pc in 0 .. len as usize {
+        let opcode = reader.byte()?;
+        if opcode >= opcodes {
+            return Err(FormatError::UnknownOpcode(pc, opcode));
+        }
+
+        let instruction = match opcode {
+            2  => Add(reader.byte()?),
+            3  => In,
+            4  => Out,
+            7  => SetZero,
+            12 => DivMod,
+            _  => {
+                let operand = reader.count(pc)?;
+                match opcode {
+                    0  => Left(operand),
+                    1  => Right(operand),
+                    5  => JumpZero(operand),
+                    6  => JumpNotZero(operand),
+                    8  => OffsetAddRight(operand),
+                    9  => OffsetAddLeft(operand),
+                    10 => FindZeroRight(operand),
+                    11 => FindZeroLeft(operand),
+                    13 => IndexRight(operand),
+                    _  => IndexLeft(operand),
+                }
+            }
+        };
+
+        match instruction {
+            JumpZero(_) => open.push(pc),
+            JumpNotZero(address) if open.pop() != Some(address.into_usize()) ||
+                    instructions[address.into_usize()] != JumpZero(pc as Count) =>
+                return Err(FormatError::BadOperand(pc)),
+            _ => (),
+        }
+
+        instructions.push(instruction);
+    }
+
+    if let Some(pc) = open.pop() {
+        return Err(FormatError::BadOperand(pc));
+    }
+    if reader.position != bytes.len() {
+        return Err(FormatError::TrailingData);
+    }
+
+    Ok(instructions.into_boxed_slice())
+}
+
+fn write_number(result: &mut Vec<u8>, mut number: u64) {
+    while number >= 0x80 {
+        result.push(number as u8 | 0x80);
+        number >>= 7;
+    }
+    result.push(number as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> FormatResult<u8> {
+        let byte = *self.bytes.get(self.position).ok_or(FormatError::Truncated)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// Reads a LEB128 number, or `u64::MAX` if it does not fit.
+    fn number(&mut self) -> FormatResult<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        let mut overflow = false;
+
+        loop {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift < 64 {
+                overflow |= shift > 0 && bits >> (64 - shift) != 0;
+                result |= bits << shift;
+            } else {
+                overflow |= bits != 0;
+            }
+            if byte < 0x80 {
+                return Ok(if overflow { u64::MAX } else { result });
+            }
+            shift += 7;
+        }
+    }
+
+    fn count(&mut self, pc: usize) -> FormatResult<Count> {
+        let number = self.number()?;
+        let count = number as Count;
+        if count as u64 == number {
+            Ok(count)
+        } else {
+            Err(FormatError::BadOperand(pc))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction::*;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn with_version(version: u16, program: &Program) -> Vec<u8> {
+        let mut bytes = write(program);
+        bytes[4] = version as u8;
+        bytes
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().bytecode_compile();
+        let bytes = write(&program);
+        assert!(bytes.starts_with(b"\0bfc\x03\0"));
+        assert_eq!(read(&bytes), Ok(program));
+
+        let program = [Right(300), Add(255), DivMod, JumpZero(5), IndexLeft(2), JumpNotZero(3)];
+        assert_eq!(read(&write(&program)).as_ref().map(|p| &**p), Ok(&program[..]));
+    }
+
+    #[test]
+    fn older_versions_load_with_their_own_tables() {
+        let original = [In, JumpZero(4), FindZeroLeft(3), Out, JumpNotZero(1)];
+        assert_eq!(read(&with_version(1, &original)).map(|p| p.len()), Ok(5));
+        assert_eq!(read(&with_version(2, &[DivMod])).map(|p| p[0]), Ok(DivMod));
+
+        assert_eq!(read(&with_version(1, &[Out, DivMod])), Err(FormatError::UnknownOpcode(1, 12)));
+        assert_eq!(read(&with_version(2, &[IndexRight(1)])),
+                   Err(FormatError::UnknownOpcode(0, 13)));
+        assert_eq!(read(&with_version(4, &[Out])), Err(FormatError::UnsupportedVersion(4)));
+    }
+
+    #[test]
+    fn damaged_files_are_refused() {
+        let bytes = write(&[In, JumpZero(3), Out, JumpNotZero(1)]);
+        assert_eq!(read(b"Left(1)"), Err(FormatError::NotBytecode));
+        assert_eq!(read(&bytes[.. bytes.len() - 1]), Err(FormatError::Truncated));
+        assert_eq!(read(&[&bytes[..], b"\0"].concat()), Err(FormatError::TrailingData));
+        assert_eq!(read(&write(&[JumpZero(1), JumpNotZero(1)])), Err(FormatError::BadOperand(1)));
+        assert_eq!(read(&write(&[JumpZero(0)])), Err(FormatError::BadOperand(0)));
+    }
+}
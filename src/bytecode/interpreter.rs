@@ -1,44 +1,200 @@
 use std::io::{Read, Write};
+use std::time::Instant;
 
+use adapters::Counting;
+use run_stats::RunStats;
 use state::State;
-use common::BfResult;
-use traits::Interpretable;
+use common::{BfResult, Error};
+use traits::{Interpretable, IntoUsize};
 use super::*;
 
+/// A run-time error, with the address of the instruction that caused it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fault {
+    /// The error.
+    pub error: Error,
+    /// The address of the failing instruction, which a
+    /// [`SourceMap`](../source_map/struct.SourceMap.html) can map to source.
+    pub pc: usize,
+}
+
 impl Interpretable for Program {
     fn interpret_state<R: Read, W: Write>(
-        &self, mut state: State, mut input: R, mut output: W) -> BfResult<()>
+        &self, mut state: State, input: R, output: W) -> BfResult<()>
     {
-        interpret(self, &mut state, &mut input, &mut output)
+        interpret_locating(self, &mut state, input, output).map_err(|fault| fault.error)
     }
+
+    fn interpret_stats<R: Read, W: Write>(&self, mut state: State, input: R, output: W)
+        -> (BfResult<()>, RunStats)
+    {
+        let (result, stats) = interpret_measuring(self, &mut state, input, output);
+        (result.map_err(|fault| fault.error), stats)
+    }
+}
+
+/// Interprets a program against the given state, reporting which instruction failed on error.
+pub fn interpret_locating<R, W>(instructions: &Program, state: &mut State,
+                                mut input: R, mut output: W)
+                                    -> Result<(), Fault>
+    where R: Read, W: Write
+{
+    interpret(instructions, state, &mut input, &mut output, &mut NoProgress)
+        .map_err(|(error, pc)| Fault { error, pc })
 }
 
-fn interpret<R, W>(instructions: &Program, state: &mut State,
-                   input: &mut R, output: &mut W)
-                       -> BfResult<()>
+/// Interprets a program like [`interpret_locating`](fn.interpret_locating.html), counting its
+/// steps, input and output and the furthest right the pointer goes, as well as timing it.
+pub fn interpret_measuring<R, W>(instructions: &Program, state: &mut State, input: R, output: W)
+                                 -> (Result<(), Fault>, RunStats)
     where R: Read, W: Write
+{
+    let mut input = Counting::new(input);
+    let mut output = Counting::new(output);
+    let mut hook = Measure { steps: 0, peak_pointer: state.pointer() };
+    let start = Instant::now();
+    let result = interpret(instructions, state, &mut input, &mut output, &mut hook)
+        .map_err(|(error, pc)| Fault { error, pc });
+
+    (result, RunStats {
+        steps: Some(hook.steps),
+        input_bytes: input.count(),
+        output_bytes: output.count(),
+        peak_pointer: Some(hook.peak_pointer),
+        run_time: start.elapsed(),
+        compile_time: None,
+    })
+}
+
+/// How far a run has got, as passed to a progress callback.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// The number of instructions run so far.
+    pub steps: u64,
+    /// The address of the next instruction.
+    pub pc: usize,
+    /// The machine state.
+    pub state: &'a State,
+}
+
+/// Whether a progress callback lets the run go on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Control {
+    /// Keep running.
+    Continue,
+    /// Stop the run, which then fails with
+    /// [`Error::Interrupted`](../common/enum.Error.html#variant.Interrupted).
+    Stop,
+}
+
+/// Interprets a program like [`interpret_locating`](fn.interpret_locating.html), calling
+/// `on_progress` after every `every_n_steps` instructions.
+///
+/// This is for long runs: the callback can update a progress bar, check whether the user has
+/// cancelled, or save the state, and then returns whether to go on. Runs without a callback
+/// pay nothing for it.
+///
+/// # Errors
+///
+/// Fails with `Error::Interrupted`, at the next instruction, when `on_progress` stops the run.
+///
+/// # Panics
+///
+/// Panics if `every_n_steps` is 0.
+pub fn interpret_on_progress<R, W, F>(instructions: &Program, state: &mut State,
+                                      mut input: R, mut output: W,
+                                      every_n_steps: u64, on_progress: F)
+                                          -> Result<(), Fault>
+    where R: Read, W: Write, F: FnMut(&Progress) -> Control
+{
+    assert!(every_n_steps > 0, "interpret_on_progress: every_n_steps must be positive");
+
+    let mut hook = Every { period: every_n_steps, countdown: every_n_steps, steps: 0,
+                           callback: on_progress };
+    interpret(instructions, state, &mut input, &mut output, &mut hook)
+        .map_err(|(error, pc)| Fault { error, pc })
+}
+
+/// Runs after each instruction, returning whether to go on.
+trait Hook {
+    fn tick(&mut self, pc: usize, state: &State) -> bool;
+}
+
+struct NoProgress;
+
+impl Hook for NoProgress {
+    #[inline(always)]
+    fn tick(&mut self, _pc: usize, _state: &State) -> bool {
+        true
+    }
+}
+
+struct Measure {
+    steps: u64,
+    peak_pointer: usize,
+}
+
+impl Hook for Measure {
+    #[inline]
+    fn tick(&mut self, _pc: usize, state: &State) -> bool {
+        self.steps += 1;
+        self.peak_pointer = self.peak_pointer.max(state.pointer());
+        true
+    }
+}
+
+struct Every<F> {
+    period: u64,
+    countdown: u64,
+    steps: u64,
+    callback: F,
+}
+
+impl<F: FnMut(&Progress) -> Control> Hook for Every<F> {
+    #[inline]
+    fn tick(&mut self, pc: usize, state: &State) -> bool {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return true;
+        }
+
+        self.countdown = self.period;
+        self.steps += self.period;
+        (self.callback)(&Progress { steps: self.steps, pc, state }) == Control::Continue
+    }
+}
+
+fn interpret<R, W, H>(instructions: &Program, state: &mut State,
+                      input: &mut R, output: &mut W, hook: &mut H)
+                          -> Result<(), (Error, usize)>
+    where R: Read, W: Write, H: Hook
 {
     use common::Instruction::*;
 
     let mut pc = 0;
 
+    // Attaches the current address to an error.
+    macro_rules! at_pc {
+        ($e:expr) => ($e.map_err(|error| (error, pc))?)
+    }
+
     while pc < instructions.len() {
         match instructions[pc] {
-            Left(count) => state.left(count)?,
-            Right(count) => state.right(count)?,
+            Left(count) => at_pc!(state.left(count)),
+            Right(count) => at_pc!(state.right(count)),
             Add(count) => state.up(count),
             In => state.read(input),
             Out => state.write(output),
 
             JumpZero(address) => {
                 if state.load() == 0 {
-                    pc = address as usize;
+                    pc = address.into_usize();
                 }
             }
 
             JumpNotZero(address) => {
                 if state.load() != 0 {
-                    pc = address as usize;
+                    pc = address.into_usize();
                 }
             }
 
@@ -48,7 +204,7 @@ fn interpret<R, W>(instructions: &Program, state: &mut State,
                 if state.load() != 0 {
                     let value = state.load();
                     state.store(0);
-                    state.up_pos_offset(offset, value)?;
+                    at_pc!(state.up_pos_offset(offset, value));
                 }
             }
 
@@ -56,24 +212,32 @@ fn interpret<R, W>(instructions: &Program, state: &mut State,
                 if state.load() != 0 {
                     let value = state.load();
                     state.store(0);
-                    state.up_neg_offset(offset, value)?;
+                    at_pc!(state.up_neg_offset(offset, value));
                 }
             }
 
             FindZeroRight(offset) => {
                 while state.load() != 0 {
-                    state.right(offset)?;
+                    at_pc!(state.right(offset));
                 }
             }
 
             FindZeroLeft(offset) => {
                 while state.load() != 0 {
-                    state.left(offset)?;
+                    at_pc!(state.left(offset));
                 }
             }
+
+            DivMod => { state.div_mod(); }
+            IndexRight(skip) => { state.index_right(skip); }
+            IndexLeft(skip) => { state.index_left(skip); }
         }
 
         pc += 1;
+
+        if !hook.tick(pc, state) {
+            return Err((Error::Interrupted, pc));
+        }
     }
 
     Ok(())
@@ -88,6 +252,45 @@ mod tests {
         assert_parse_interpret(HELLO_WORLD_SRC, "", "Hello, World!");
     }
 
+    #[test]
+    fn faults_locate_the_instruction() {
+        use common::Error;
+        use state::State;
+
+        let program = ::bytecode::compile(&::peephole::compile(&::rle::compile(
+            &::ast::parse_program(b"+[>+]").unwrap())));
+        let mut state = State::with_capacity(4);
+        assert_eq!(super::interpret_locating(&program, &mut state, &b""[..], Vec::new()),
+                   Err(super::Fault { error: Error::PointerOverflow, pc: 2 }));
+    }
+
+    #[test]
+    fn progress_is_reported_and_can_stop_the_run() {
+        use common::Error;
+        use state::State;
+        use super::{interpret_on_progress, Control};
+
+        let compile = |source: &[u8]| ::bytecode::compile(&::peephole::compile(&::rle::compile(
+            &::ast::parse_program(source).unwrap())));
+
+        let mut steps = Vec::new();
+        let mut output = Vec::new();
+        interpret_on_progress(&compile(FACTOR_SRC), &mut State::new(), &b"12\n"[..], &mut output,
+                              100, |progress| { steps.push(progress.steps); Control::Continue })
+            .unwrap();
+        assert_eq!(output, b"12: 2 2 3\n");
+        assert_eq!(&steps[.. 3], &[100, 200, 300]);
+
+        let mut last = 0;
+        let run = interpret_on_progress(&compile(b"+[]"), &mut State::new(), &b""[..], Vec::new(),
+                                        1_000, |progress| {
+            last = progress.steps;
+            if last < 5_000 { Control::Continue } else { Control::Stop }
+        });
+        assert_eq!(run.map_err(|fault| fault.error), Err(Error::Interrupted));
+        assert_eq!(last, 5_000);
+    }
+
     #[test]
     fn factoring() {
         assert_parse_interpret(FACTOR_SRC, "2\n", "2: 2\n");
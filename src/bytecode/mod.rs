@@ -14,13 +14,24 @@
 //! Flattening is not necessary for interpretation, but it might
 //! perform better because of the cache. So far, it appears
 //! to perform worse than the peephole-optimized AST.
+//!
+//! Bytecode can also be run one step at a time as an [`Execution`](struct.Execution.html),
+//! which hands its I/O to the host instead of using `Read` and `Write`, and saved in the
+//! versioned [`.bfc` format](format/index.html).
 
 use common;
 
 mod compiler;
 mod interpreter;
+mod execution;
+pub mod format;
 
 pub use self::compiler::{compile, BytecodeCompilable};
+pub use self::interpreter::{interpret_locating, interpret_measuring, interpret_on_progress, Control,
+                            Fault, Progress};
+pub use self::execution::{Execution, StepResult};
+pub(crate) use self::execution::{execute, step};
+pub(crate) use self::compiler::usize_to_count;
 
 /// A program is a bytecode sequence of instructions.
 pub type Program = [common::Instruction];
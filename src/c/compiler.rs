@@ -0,0 +1,331 @@
+use std::fmt;
+
+use common::Instruction;
+use options::CompileOptions;
+use peephole::{self, Statement};
+use state::DEFAULT_CAPACITY;
+
+/// Program forms that can be transpiled to C.
+pub trait CCompilable {
+    /// Compile the given program into the peephole AST to prepare for transpiling.
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R;
+
+    /// Transpile the given program to a C program.
+    fn c_compile(&self, memory_size: Option<usize>, options: &CompileOptions) -> String {
+        self.with_peephole(|program| compile(program, memory_size, options))
+    }
+}
+
+const PRELUDE: &str = "\
+/* Generated by bf-rs. */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+static void fail(const char *message)
+{
+    fflush(stdout);
+    fprintf(stderr, \"bf: runtime error: %s.\\n\", message);
+    exit(3);
+}
+";
+
+/// The runtime for `seccomp`, which can only read, write and exit.
+const SECCOMP_PRELUDE: &str = "\
+/* Generated by bf-rs. */
+
+#ifndef __linux__
+#error \"seccomp is only available on Linux\"
+#endif
+
+#define _GNU_SOURCE
+#include <stddef.h>
+#include <unistd.h>
+#include <sys/prctl.h>
+#include <sys/syscall.h>
+#include <linux/seccomp.h>
+
+#define EOF (-1)
+
+static unsigned char in_buf[4096], out_buf[4096];
+static size_t in_pos, in_len, out_len;
+
+static void bf_exit(int status);
+
+static void bf_write(int fd, const unsigned char *bytes, size_t len)
+{
+    while (len > 0) {
+        ssize_t n = write(fd, bytes, len);
+        if (n <= 0) syscall(SYS_exit, 1);
+        bytes += n;
+        len -= (size_t) n;
+    }
+}
+
+static void bf_flush(void)
+{
+    bf_write(1, out_buf, out_len);
+    out_len = 0;
+}
+
+static void bf_putchar(unsigned char c)
+{
+    out_buf[out_len++] = c;
+    if (out_len == sizeof out_buf) bf_flush();
+}
+
+static int bf_getchar(void)
+{
+    if (in_pos == in_len) {
+        ssize_t n;
+        bf_flush();
+        n = read(0, in_buf, sizeof in_buf);
+        if (n <= 0) return EOF;
+        in_pos = 0;
+        in_len = (size_t) n;
+    }
+    return in_buf[in_pos++];
+}
+
+static void bf_exit(int status)
+{
+    bf_flush();
+    for (;;) syscall(SYS_exit, status);
+}
+
+static void fail(const char *message)
+{
+    const char *end = message;
+    while (*end) ++end;
+    bf_flush();
+    bf_write(2, (const unsigned char *) \"bf: runtime error: \", 19);
+    bf_write(2, (const unsigned char *) message, (size_t) (end - message));
+    bf_write(2, (const unsigned char *) \".\\n\", 2);
+    bf_exit(3);
+}
+";
+
+/// Transpiles the given program to a self-contained C program with a tape of `memory_size`
+/// cells.
+///
+/// Of the options, only `checked` and `seccomp` apply.
+pub fn compile(program: &peephole::Program, memory_size: Option<usize>,
+               options: &CompileOptions) -> String {
+    let mut compiler = Compiler {
+        out:     String::from(if options.seccomp { SECCOMP_PRELUDE } else { PRELUDE }),
+        checked: options.checked,
+        seccomp: options.seccomp,
+        depth:   1,
+    };
+
+    let memory_size = memory_size.unwrap_or(DEFAULT_CAPACITY);
+    compiler.line(0, "");
+    compiler.line(0, &format!("#define MEMORY_SIZE {}", memory_size));
+    compiler.line(0, "");
+    compiler.line(0, "static unsigned char mem[MEMORY_SIZE];");
+    compiler.line(0, "");
+    compiler.line(0, "int main(void)");
+    compiler.line(0, "{");
+    compiler.line(1, "size_t p = 0;");
+    compiler.line(1, "int c;");
+    compiler.line(0, "");
+    if options.seccomp {
+        compiler.line(1, "if (prctl(PR_SET_SECCOMP, SECCOMP_MODE_STRICT) != 0)");
+        compiler.line(2, "fail(\"could not enter seccomp strict mode\");");
+        compiler.line(0, "");
+    }
+    compiler.compile_block(program);
+    compiler.line(0, "");
+    compiler.line(1, "(void) c;");
+    if options.seccomp {
+        compiler.line(1, "bf_exit(0);");
+    }
+    compiler.line(1, "return 0;");
+    compiler.line(0, "}");
+
+    compiler.out
+}
+
+struct Compiler {
+    out:     String,
+    checked: bool,
+    seccomp: bool,
+    depth:   usize,
+}
+
+impl Compiler {
+    fn line(&mut self, depth: usize, text: &str) {
+        if !text.is_empty() {
+            for _ in 0 .. depth {
+                self.out.push_str("    ");
+            }
+            self.out.push_str(text);
+        }
+        self.out.push('\n');
+    }
+
+    fn emit(&mut self, text: &str) {
+        let depth = self.depth;
+        self.line(depth, text);
+    }
+
+    fn compile_block(&mut self, program: &[Statement]) {
+        for statement in program {
+            self.compile_statement(statement);
+        }
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) {
+        match *statement {
+            Statement::Instr(instruction) => self.compile_instruction(instruction),
+
+            Statement::Loop(ref body) => {
+                self.emit("while (mem[p]) {");
+                self.depth += 1;
+                self.compile_block(body);
+                self.depth -= 1;
+                self.emit("}");
+            }
+        }
+    }
+
+    fn compile_instruction(&mut self, instruction: Instruction) {
+        use common::Instruction::*;
+
+        match instruction {
+            Left(count) => {
+                self.check_left(count);
+                self.emit(&format!("p -= {};", count));
+            }
+
+            Right(count) => {
+                self.check_right(count);
+                self.emit(&format!("p += {};", count));
+            }
+
+            Add(amount) => self.emit(&format!("mem[p] += {};", amount)),
+
+            In if self.seccomp => self.emit("c = bf_getchar(); mem[p] = c == EOF ? 0 : c;"),
+            In => self.emit("c = getchar(); mem[p] = c == EOF ? 0 : c;"),
+
+            Out if self.seccomp => self.emit("bf_putchar(mem[p]);"),
+            Out => self.emit("putchar(mem[p]);"),
+
+            SetZero => self.emit("mem[p] = 0;"),
+
+            OffsetAddRight(offset) => {
+                self.offset_add(|compiler| compiler.check_right(offset),
+                                &format!("mem[p + {}] += mem[p];", offset));
+            }
+
+            OffsetAddLeft(offset) => {
+                self.offset_add(|compiler| compiler.check_left(offset),
+                                &format!("mem[p - {}] += mem[p];", offset));
+            }
+
+            FindZeroRight(skip) => {
+                self.find_zero(|compiler| compiler.check_right(skip),
+                               &format!("p += {};", skip));
+            }
+
+            FindZeroLeft(skip) => {
+                self.find_zero(|compiler| compiler.check_left(skip),
+                               &format!("p -= {};", skip));
+            }
+
+            // The loop that follows does the work.
+            DivMod | IndexRight(_) | IndexLeft(_) => (),
+
+            JumpZero(_) | JumpNotZero(_) =>
+                panic!("unexpected jump instruction"),
+        }
+    }
+
+    fn offset_add<F: FnOnce(&mut Self)>(&mut self, check: F, add: &str) {
+        self.emit("if (mem[p]) {");
+        self.depth += 1;
+        check(self);
+        self.emit(add);
+        self.emit("mem[p] = 0;");
+        self.depth -= 1;
+        self.emit("}");
+    }
+
+    fn find_zero<F: FnOnce(&mut Self)>(&mut self, check: F, step: &str) {
+        self.emit("while (mem[p]) {");
+        self.depth += 1;
+        check(self);
+        self.emit(step);
+        self.depth -= 1;
+        self.emit("}");
+    }
+
+    fn check_left<C: fmt::Display>(&mut self, count: C) {
+        if self.checked {
+            self.emit(&format!("if (p < {}) fail(\"pointer underflow\");", count));
+        }
+    }
+
+    fn check_right<C: fmt::Display>(&mut self, count: C) {
+        if self.checked {
+            self.emit(&format!("if (MEMORY_SIZE - p <= {}) fail(\"pointer overflow\");",
+                               count));
+        }
+    }
+}
+
+impl CCompilable for peephole::Program {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(self)
+    }
+}
+
+impl<T: peephole::PeepholeCompilable + ?Sized> CCompilable for T {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(&self.peephole_compile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn c(source: &[u8], options: &CompileOptions) -> String {
+        ::ast::parse_program(source).unwrap().c_compile(Some(100), options)
+    }
+
+    #[test]
+    fn program_shape() {
+        let output = c(b",[.,]", &CompileOptions::default());
+        assert!(output.contains("#define MEMORY_SIZE 100\n"));
+        assert!(output.contains("\nint main(void)\n{\n"));
+        assert!(output.contains("\n    c = getchar(); mem[p] = c == EOF ? 0 : c;\n"));
+        assert!(output.contains("\n    while (mem[p]) {\n        putchar(mem[p]);\n"));
+    }
+
+    #[test]
+    fn checks_can_be_omitted() {
+        let checked = c(FACTOR_SRC, &CompileOptions::default());
+        assert!(checked.contains("fail(\"pointer overflow\");"));
+
+        let options = CompileOptions { checked: false, ..CompileOptions::default() };
+        let unchecked = c(FACTOR_SRC, &options);
+        assert!(!unchecked.contains("fail(\"pointer"));
+    }
+
+    #[test]
+    fn seccomp_programs_do_their_own_io() {
+        let options = CompileOptions { seccomp: true, ..CompileOptions::default() };
+        let output = c(b",[.,]", &options);
+        assert!(!output.contains("<stdio.h>"));
+        assert!(output.contains("\n    if (prctl(PR_SET_SECCOMP, SECCOMP_MODE_STRICT) != 0)\n"));
+        assert!(output.contains("\n    while (mem[p]) {\n        bf_putchar(mem[p]);\n"));
+        assert!(output.ends_with("\n    bf_exit(0);\n    return 0;\n}\n"));
+    }
+}
@@ -0,0 +1,18 @@
+//! Transpiler from the peephole IR to C, for ahead-of-time compilation.
+//!
+//! The output is a complete C program, runtime included: the tape is a static array, `,` and
+//! `.` use `getchar` and `putchar` (end of input stores 0), and moving the pointer off the tape
+//! prints `bf: runtime error: pointer underflow.` (or `overflow`) to standard error and exits
+//! with status 3, as `bfi` does. Any C89 compiler will build it; `bfi compile` does so with the
+//! system’s `cc`.
+//!
+//! With the [`seccomp`](../options/struct.CompileOptions.html#structfield.seccomp) option, the
+//! runtime instead does its I/O with `read` and `write` on buffers of its own and puts the
+//! process in seccomp’s strict mode as it starts, so that a compiled program, whatever it
+//! does, can make no other system calls; any other kills it. It ends with the `exit` system
+//! call, as `exit_group`, which the C library’s `exit` makes, is not allowed either. That
+//! runtime needs Linux.
+
+mod compiler;
+
+pub use self::compiler::{compile, CCompilable};
@@ -0,0 +1,249 @@
+//! An on-disk cache of compiled programs.
+//!
+//! Entries are keyed by the program’s [fingerprint](../fingerprint/index.html), a hash of the
+//! options it was compiled with, the backend that produced it, and this crate’s version, so a
+//! stale entry is never returned after an upgrade. Values are opaque bytes: native executables
+//! from `bfi compile`, or anything else a backend can serialize. Bytecode is the exception:
+//! it is stored in the [`.bfc` format](../bytecode/format/index.html), whose own version says
+//! how to load it, so its entries are keyed without the crate’s version and survive upgrades.
+//! (JIT code is not cached, since it embeds addresses in the host process.) The default
+//! location is `$XDG_CACHE_HOME/bf-rs`, falling back to `~/.cache/bf-rs`.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use ast;
+use bytecode::{self, format};
+use fingerprint::{hash_text, Fingerprintable};
+use traits::BytecodeCompilable;
+
+/// The version component of every key.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Identifies one compiled artifact.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Key {
+    backend:     &'static str,
+    version:     &'static str,
+    fingerprint: u64,
+    options:     u64,
+}
+
+impl Key {
+    /// Makes the key for the output of the named backend, given the fingerprint of its input
+    /// and the options it was run with. The options are hashed via their `Debug` form.
+    pub fn new<O: fmt::Debug + ?Sized>(backend: &'static str, fingerprint: u64, options: &O)
+                                       -> Self {
+        Key {
+            backend,
+            version: VERSION,
+            fingerprint,
+            options: hash_text("options", format_args!("{:?}", options)),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}-{}-{:016x}-{:016x}", self.backend, self.version, self.fingerprint, self.options)
+    }
+}
+
+/// A cache entry, as listed by [`Cache::entries`](struct.Cache.html#method.entries).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// The entry’s file name, which encodes its key.
+    pub name: String,
+    /// The size of the stored value in bytes.
+    pub size: u64,
+}
+
+/// A cache directory.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The default cache directory, if the environment gives a home for it.
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("bf-rs"))
+    }
+
+    /// Opens the cache in the given directory, creating it if necessary.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Cache { dir })
+    }
+
+    /// Opens the cache in the [default directory](#method.default_dir).
+    pub fn open_default() -> io::Result<Self> {
+        let dir = Self::default_dir().ok_or_else(||
+            io::Error::new(io::ErrorKind::NotFound, "no cache directory"))?;
+        Self::open(dir)
+    }
+
+    /// The directory holding the cache.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Looks up the value stored under the given key.
+    pub fn get(&self, key: &Key) -> Option<Vec<u8>> {
+        fs::read(self.path(key)).ok()
+    }
+
+    /// Whether a value is stored under the given key.
+    pub fn contains(&self, key: &Key) -> bool {
+        self.path(key).is_file()
+    }
+
+    /// Stores a value under the given key, replacing any previous value.
+    ///
+    /// The value is written to a temporary file and then renamed into place, so concurrent
+    /// readers see either the old value or the new one.
+    pub fn put(&self, key: &Key, value: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        let temp = self.dir.join(format!(".{}.{}", key.file_name(), process::id()));
+        fs::write(&temp, value)?;
+        fs::rename(&temp, &path)
+    }
+
+    /// Returns the value stored under the given key, computing and storing it if absent.
+    ///
+    /// Failure to store the value is not an error, since the cache is only an optimization.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(&self, key: &Key, compute: F) -> Vec<u8> {
+        self.get(key).unwrap_or_else(|| {
+            let value = compute();
+            let _ = self.put(key, &value);
+            value
+        })
+    }
+
+    /// Removes the value stored under the given key, returning whether there was one.
+    pub fn remove(&self, key: &Key) -> io::Result<bool> {
+        match fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the cache’s entries, in order by name.
+    pub fn entries(&self) -> io::Result<Vec<Entry>> {
+        let mut result = Vec::new();
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') { continue; }
+
+            let metadata = dir_entry.metadata()?;
+            if metadata.is_file() {
+                result.push(Entry { name, size: metadata.len() });
+            }
+        }
+
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(result)
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for entry in &entries {
+            fs::remove_file(self.dir.join(&entry.name))?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Compiles the given program to bytecode, reusing the cached result if there is one.
+    ///
+    /// An entry that does not load, such as one written by a newer version of the crate, is
+    /// replaced.
+    pub fn bytecode(&self, program: &ast::Program) -> Box<bytecode::Program> {
+        let key = Key { version: "bfc", ..Key::new("bytecode", program.fingerprint(), &()) };
+
+        if let Some(Ok(program)) = self.get(&key).map(|bytes| format::read(&bytes)) {
+            return program;
+        }
+
+        let result = program.bytecode_compile();
+        let _ = self.put(&key, &format::write(&result));
+        result
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(key.file_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn temp_cache(name: &str) -> Cache {
+        let dir = env::temp_dir().join(format!("bf-rs-cache-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Cache::open(dir).unwrap()
+    }
+
+    #[test]
+    fn put_get_clear() {
+        let cache = temp_cache("put-get-clear");
+        let key = Key::new("bytecode", 1, &());
+        assert_eq!(cache.get(&key), None);
+
+        cache.put(&key, b"abc").unwrap();
+        assert!(cache.contains(&key));
+        assert_eq!(cache.get(&key), Some(b"abc".to_vec()));
+        assert_eq!(cache.get_or_insert_with(&key, || unreachable!()), b"abc".to_vec());
+
+        let other = Key::new("bytecode", 2, &());
+        assert_eq!(cache.get_or_insert_with(&other, || b"de".to_vec()), b"de".to_vec());
+        assert_eq!(cache.entries().unwrap().iter().map(|entry| entry.size).sum::<u64>(), 5);
+
+        assert!(cache.remove(&other).unwrap());
+        assert!(!cache.remove(&other).unwrap());
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert_eq!(cache.entries().unwrap(), vec![]);
+
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn bytecode_round_trips() {
+        let cache = temp_cache("bytecode");
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap();
+        let compiled = cache.bytecode(&program);
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].name.starts_with("bytecode-bfc-"));
+        assert_eq!(cache.bytecode(&program), compiled);
+        assert_eq!(compiled, program.bytecode_compile());
+
+        // An entry from a future format version is recompiled.
+        let path = cache.dir().join(&entries[0].name);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4] = 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(cache.bytecode(&program), compiled);
+        assert_eq!(fs::read(&path).unwrap(), format::write(&compiled));
+
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn keys_distinguish_options_and_backends() {
+        assert_eq!(Key::new("c", 7, &true), Key::new("c", 7, &true));
+        assert_ne!(Key::new("c", 7, &true), Key::new("c", 7, &false));
+        assert_ne!(Key::new("c", 7, &true), Key::new("js", 7, &true));
+        assert_ne!(Key::new("c", 7, &true), Key::new("c", 8, &true));
+    }
+}
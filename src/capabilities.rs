@@ -0,0 +1,116 @@
+//! What this build of the crate can do, for frontends to offer only that.
+//!
+//! Some backends depend on Cargo features, and the JIT on the target architecture too, so a
+//! frontend that lists every backend fails at run time on builds without them.
+//! [`capabilities`](fn.capabilities.html) says which backends, code generators, dialects and
+//! features were compiled in; `bfi capabilities` prints it, and `bf-server` serves it as JSON
+//! at `/capabilities`.
+
+use std::fmt;
+use std::mem;
+
+/// What was compiled in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// The backends that run programs, by their `bfi` flags, such as `peep` or `jit`.
+    pub backends: Vec<&'static str>,
+    /// The languages programs can be compiled to ahead of time, such as `c`.
+    pub targets: Vec<&'static str>,
+    /// The source languages understood, such as `brainfork`.
+    pub dialects: Vec<&'static str>,
+    /// The architecture the JIT emits code for, if it was compiled in.
+    pub jit_arch: Option<&'static str>,
+    /// The oldest LLVM major version the LLVM backend was built for, if it was compiled in.
+    pub llvm_version: Option<u32>,
+    /// The width of instruction counts in bits, which bounds the offsets one instruction can
+    /// take.
+    pub count_bits: u32,
+    /// The Cargo features enabled.
+    pub features: Vec<&'static str>,
+}
+
+/// What this build can do.
+pub fn capabilities() -> Capabilities {
+    let mut backends = vec!["ast", "rle", "peep", "byte", "threaded", "trace"];
+    if cfg!(feature = "jit") {
+        backends.push("jit");
+    }
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+
+    let llvm_version = if !cfg!(feature = "llvm") {
+        None
+    } else if cfg!(feature = "llvm-18") {
+        Some(18)
+    } else if cfg!(feature = "llvm-17") {
+        Some(17)
+    } else if cfg!(feature = "llvm-16") {
+        Some(16)
+    } else if cfg!(feature = "llvm-15") {
+        Some(15)
+    } else {
+        Some(7)
+    };
+
+    let features = [
+        ("jit", cfg!(feature = "jit")),
+        ("llvm", cfg!(feature = "llvm")),
+        ("llvm-15", cfg!(feature = "llvm-15")),
+        ("llvm-16", cfg!(feature = "llvm-16")),
+        ("llvm-17", cfg!(feature = "llvm-17")),
+        ("llvm-18", cfg!(feature = "llvm-18")),
+        ("lsp", cfg!(feature = "lsp")),
+        ("server", cfg!(feature = "server")),
+        ("samples", cfg!(feature = "samples")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("u32count", cfg!(feature = "u32count")),
+        ("u16count", cfg!(feature = "u16count")),
+        ("nightly", cfg!(feature = "nightly")),
+    ];
+
+    Capabilities {
+        backends,
+        targets: vec!["c", "js", "asm", "ebpf"],
+        dialects: vec!["brainfuck", "brainfork", "multitape"],
+        jit_arch: if cfg!(feature = "jit") { Some("x86_64") } else { None },
+        llvm_version,
+        count_bits: 8 * mem::size_of::<::common::Count>() as u32,
+        features: features.iter().filter(|&&(_, on)| on).map(|&(name, _)| name).collect(),
+    }
+}
+
+impl fmt::Display for Capabilities {
+    /// One line for each kind of capability, such as `backends: ast, rle, peep`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backends: {}", self.backends.join(", "))?;
+        writeln!(f, "targets: {}", self.targets.join(", "))?;
+        writeln!(f, "dialects: {}", self.dialects.join(", "))?;
+        writeln!(f, "jit: {}", self.jit_arch.unwrap_or("no"))?;
+        match self.llvm_version {
+            Some(version) => writeln!(f, "llvm: {} or later", version)?,
+            None => writeln!(f, "llvm: no")?,
+        }
+        writeln!(f, "count bits: {}", self.count_bits)?;
+        if self.features.is_empty() {
+            writeln!(f, "features: none")
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_follow_the_features() {
+        let capabilities = capabilities();
+        assert!(capabilities.backends.starts_with(&["ast", "rle", "peep", "byte"]));
+        assert_eq!(capabilities.backends.contains(&"jit"), cfg!(feature = "jit"));
+        assert_eq!(capabilities.llvm_version.is_some(), cfg!(feature = "llvm"));
+        assert_eq!(capabilities.features.contains(&"u16count"), capabilities.count_bits == 16);
+        assert!(capabilities.to_string().contains("\ndialects: brainfuck, brainfork, multitape\n"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Generates Brainfuck programs.
+//!
+//! [`add_constant`](fn.add_constant.html) and [`set_constant`](fn.set_constant.html) synthesize
+//! short snippets that change a cell to a given value, for compilers generating Brainfuck from
+//! higher-level languages. [`print_string`](fn.print_string.html) builds on them to produce a
+//! program that prints given bytes, for tests, tutorials, and larger generated programs.
+//! [`from_peephole`](fn.from_peephole.html) turns optimized IR, such as that of an
+//! [`ir::Builder`](../ir/struct.Builder.html), back into Brainfuck.
+
+use ast::{Program, Statement};
+use common::Command::{self, *};
+use common::Instruction as I;
+use peephole;
+use traits::IntoUsize;
+
+/// The largest (absolute) starting value tried for a loop counter.
+const MAX_COUNTER: isize = 16;
+
+/// The largest (absolute) amount a loop counter is stepped by each iteration.
+const MAX_COUNTER_STEP: isize = 7;
+
+/// The largest (absolute) amount a loop adds to the target each iteration.
+const MAX_STEP: isize = 24;
+
+/// Generates a short snippet adding `delta` to the current cell, modulo 256.
+///
+/// The snippet may use the cell at offset `scratch` from the current one as a loop counter;
+/// that cell must be zero beforehand and is zero afterward. Loops run the counter from its
+/// starting value to zero by a constant step, wrapping around if need be, and the snippet is
+/// the shortest such loop, plus a remainder, found within small bounds, or plain `+` or `-`
+/// commands when those are shorter. With a `scratch` of 0, only plain commands are used.
+pub fn add_constant(delta: u8, scratch: isize) -> Box<Program> {
+    let mut program = Vec::new();
+    push_add(&mut program, delta, scratch);
+    program.into_boxed_slice()
+}
+
+/// Generates a short snippet setting the current cell to `value`: `[-]` followed by
+/// [`add_constant`](fn.add_constant.html).
+pub fn set_constant(value: u8, scratch: isize) -> Box<Program> {
+    let mut program = vec![Statement::Loop(vec![Statement::Cmd(Down)].into_boxed_slice())];
+    push_add(&mut program, value, scratch);
+    program.into_boxed_slice()
+}
+
+/// Generates a compact program printing the given bytes.
+///
+/// Each byte is reached from the previous one in the current cell with
+/// [`add_constant`](fn.add_constant.html), using the cell to its right as scratch. The program
+/// needs two cells and leaves the pointer on the first.
+pub fn print_string(text: &[u8]) -> Box<Program> {
+    let mut program = Vec::new();
+    let mut current = 0u8;
+
+    for &byte in text {
+        push_add(&mut program, byte.wrapping_sub(current), 1);
+        program.push(Statement::Cmd(Out));
+        current = byte;
+    }
+
+    program.into_boxed_slice()
+}
+
+/// Generates Brainfuck that does what a peephole program does.
+///
+/// Each instruction becomes the loop or commands it stands for. `DivMod` and the index
+/// instructions become nothing, as the loops they come before do the same work.
+pub fn from_peephole(program: &peephole::Program) -> Box<Program> {
+    let mut result = Vec::new();
+    push_peephole(&mut result, program);
+    result.into_boxed_slice()
+}
+
+fn push_peephole(program: &mut Vec<Statement>, source: &peephole::Program) {
+    let body = |commands: &[(Command, usize)]| {
+        let mut body = Vec::new();
+        for &(command, count) in commands {
+            push_commands(&mut body, command, count);
+        }
+        Statement::Loop(body.into_boxed_slice())
+    };
+
+    for statement in source {
+        let instruction = match *statement {
+            peephole::Statement::Instr(instruction) => instruction,
+            peephole::Statement::Loop(ref inner) => {
+                let mut body = Vec::new();
+                push_peephole(&mut body, inner);
+                program.push(Statement::Loop(body.into_boxed_slice()));
+                continue;
+            }
+        };
+
+        match instruction {
+            I::Left(count) => push_commands(program, Left, count.into_usize()),
+            I::Right(count) => push_commands(program, Right, count.into_usize()),
+            I::Add(amount) => push_repeated(program, nearest(amount as isize)),
+            I::In => program.push(Statement::Cmd(In)),
+            I::Out => program.push(Statement::Cmd(Out)),
+            I::SetZero => program.push(body(&[(Down, 1)])),
+            I::OffsetAddRight(count) => {
+                let count = count.into_usize();
+                program.push(body(&[(Down, 1), (Right, count), (Up, 1), (Left, count)]));
+            }
+            I::OffsetAddLeft(count) => {
+                let count = count.into_usize();
+                program.push(body(&[(Down, 1), (Left, count), (Up, 1), (Right, count)]));
+            }
+            I::FindZeroRight(count) => program.push(body(&[(Right, count.into_usize())])),
+            I::FindZeroLeft(count) => program.push(body(&[(Left, count.into_usize())])),
+            I::DivMod | I::IndexRight(_) | I::IndexLeft(_) => (),
+            I::JumpZero(_) | I::JumpNotZero(_) => panic!("jump in peephole program"),
+        }
+    }
+}
+
+/// A multiplication loop: with the counter set to `counter`, each iteration adds `step` to the
+/// target and subtracts `counter_step` from the counter, and then `remainder` is added.
+#[derive(Clone, Copy, Debug)]
+struct Recipe {
+    counter:      isize,
+    counter_step: isize,
+    step:         isize,
+    remainder:    isize,
+}
+
+fn push_add(program: &mut Vec<Statement>, delta: u8, scratch: isize) {
+    let direct = nearest(delta as isize);
+    let mut best = (direct.unsigned_abs(), None);
+
+    if scratch != 0 {
+        // The moves to and from the counter, twice, and the brackets.
+        let overhead = 4 * scratch.unsigned_abs() + 2;
+
+        for counter in (-MAX_COUNTER ..= MAX_COUNTER).filter(|&n| n != 0) {
+            for counter_step in (-MAX_COUNTER_STEP ..= MAX_COUNTER_STEP).filter(|&s| s % 2 != 0) {
+                let iterations = iterations(counter, counter_step);
+
+                for step in (-MAX_STEP ..= MAX_STEP).filter(|&s| s != 0) {
+                    let remainder = nearest(delta as isize - iterations * step);
+                    let cost = overhead + counter.unsigned_abs() + counter_step.unsigned_abs()
+                        + step.unsigned_abs() + remainder.unsigned_abs();
+                    if cost < best.0 {
+                        best = (cost, Some(Recipe { counter, counter_step, step, remainder }));
+                    }
+                }
+            }
+        }
+    }
+
+    let recipe = match best.1 {
+        Some(recipe) => recipe,
+        None => return push_repeated(program, direct),
+    };
+
+    let (to_scratch, from_scratch) = if scratch > 0 { (Right, Left) } else { (Left, Right) };
+    let distance = scratch.unsigned_abs();
+
+    let mut body = Vec::new();
+    push_commands(&mut body, from_scratch, distance);
+    push_repeated(&mut body, recipe.step);
+    push_commands(&mut body, to_scratch, distance);
+    push_repeated(&mut body, -recipe.counter_step);
+
+    push_commands(program, to_scratch, distance);
+    push_repeated(program, recipe.counter);
+    program.push(Statement::Loop(body.into_boxed_slice()));
+    push_commands(program, from_scratch, distance);
+    push_repeated(program, recipe.remainder);
+}
+
+/// How many times a loop runs whose counter starts at `counter` and is decreased by the odd
+/// `counter_step` each time, wrapping modulo 256, until it is zero.
+fn iterations(counter: isize, counter_step: isize) -> isize {
+    let counter = counter.rem_euclid(256);
+    let step = counter_step.rem_euclid(256);
+
+    // The inverse of an odd number modulo 256, by Newton’s method.
+    let mut inverse = step;
+    for _ in 0 .. 3 {
+        inverse = (inverse * (2 - step * inverse)).rem_euclid(256);
+    }
+
+    (counter * inverse).rem_euclid(256)
+}
+
+/// The representative of `n` modulo 256 nearest to zero.
+fn nearest(n: isize) -> isize {
+    let n = n.rem_euclid(256);
+    if n > 128 { n - 256 } else { n }
+}
+
+/// Adds `n` to the current cell with `+` or `-` commands.
+fn push_repeated(program: &mut Vec<Statement>, n: isize) {
+    let command = if n >= 0 { Up } else { Down };
+    push_commands(program, command, n.unsigned_abs());
+}
+
+fn push_commands(program: &mut Vec<Statement>, command: Command, count: usize) {
+    for _ in 0 .. count {
+        program.push(Statement::Cmd(command));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use text::Text;
+    use traits::Interpretable;
+
+    fn run(program: &Program) -> Vec<u8> {
+        program.interpret_memory(Some(8), b"").unwrap()
+    }
+
+    #[test]
+    fn prints_hello_world_compactly() {
+        let program = print_string(b"Hello, World!");
+        assert_eq!(run(&program), b"Hello, World!");
+        assert!(Text(&*program).to_string().len() < 200);
+    }
+
+    #[test]
+    fn prints_every_byte() {
+        let text: Vec<u8> = (0 ..= 255).chain((0 ..= 255).rev()).collect();
+        assert_eq!(run(&print_string(&text)), text);
+    }
+
+    #[test]
+    fn constants_are_exact_and_leave_scratch_zero() {
+        for &scratch in &[-2isize, 1, 3] {
+            let to_scratch = if scratch > 0 { ">" } else { "<" }.repeat(scratch.unsigned_abs());
+
+            for value in 0 ..= 255u8 {
+                // Start in the middle of the tape on a nonzero cell, then print it and the
+                // scratch cell.
+                let source = format!(">>>>+++{}.{}.", Text(&*set_constant(value, scratch)),
+                                     to_scratch);
+                let program = ::ast::parse_program(source.as_bytes()).unwrap();
+                assert_eq!(run(&program), vec![value, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn peephole_programs_become_their_loops_again() {
+        use traits::PeepholeCompilable;
+
+        let source = b"+++[->>+<<]>>[-<+>]--[>]<<+[[->+>+<<]>>>+++++[<-]<<]";
+        let peephole = ::ast::parse_program(source).unwrap().peephole_compile();
+        let program = from_peephole(&peephole);
+        assert_eq!(Text(&*program).to_string().as_bytes(), &source[..]);
+    }
+
+    #[test]
+    fn snippets_are_short() {
+        assert_eq!(Text(&*add_constant(2, 1)).to_string(), "++");
+        assert_eq!(Text(&*add_constant(254, 1)).to_string(), "--");
+        assert_eq!(Text(&*add_constant(100, 0)).to_string().len(), 100);
+        let longest = (0 ..= 255u8).map(|n| Text(&*add_constant(n, 1)).to_string().len()).max();
+        assert!(longest.unwrap() <= 32);
+    }
+}
@@ -3,10 +3,11 @@
 //! This includes error handling and the basic definition of Brainfuck commands.
 
 use std::fmt;
+use std::io;
 
 /// The result type for Brainfuck operations that can fail.
 ///
-/// This is `Result` specialized to the four kinds of Brainfuck
+/// This is `Result` specialized to the kinds of Brainfuck
 /// [`Error`](enum.Error.html)s
 pub type BfResult<T> = Result<T, Error>;
 
@@ -22,6 +23,19 @@ pub enum Error {
     /// If execution continues, the pointer will go beyond the high end of the
     /// memory (run-time error)
     PointerOverflow,
+    /// Reading the program source failed (I/O error)
+    Io(io::ErrorKind),
+    /// Sanitized native code tried to access the cell at the given offset from the start of
+    /// memory, which is outside it (run-time error)
+    PoisonedAccess(isize),
+    /// The source is longer than the limit, in bytes (limit error)
+    SourceTooLarge(usize),
+    /// Loops nest more deeply than the limit (limit error)
+    NestingTooDeep(usize),
+    /// The optimized program has more instructions than the limit (limit error)
+    ProgramTooLarge(usize),
+    /// The host stopped the run, as from a progress callback (run-time error)
+    Interrupted,
 }
 
 impl fmt::Display for Error {
@@ -33,6 +47,12 @@ impl fmt::Display for Error {
             UnmatchedEnd => write!(f, "unmatched ‘]’"),
             PointerUnderflow => write!(f, "pointer underflow"),
             PointerOverflow => write!(f, "pointer overflow"),
+            Io(kind) => write!(f, "I/O error: {:?}", kind),
+            PoisonedAccess(offset) => write!(f, "access to poisoned memory at offset {}", offset),
+            SourceTooLarge(limit) => write!(f, "source longer than {} bytes", limit),
+            NestingTooDeep(limit) => write!(f, "loops nested more than {} deep", limit),
+            ProgramTooLarge(limit) => write!(f, "program longer than {} instructions", limit),
+            Interrupted => write!(f, "interrupted"),
         }
     }
 }
@@ -132,5 +152,25 @@ pub enum Instruction {
     ///
     /// `FindZeroLeft(3)` is equivalent to the concrete Brainfuck loop `[<<<]`.
     FindZeroLeft(Count),
+    /// Does the work of the divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]` at once, if it can.
+    ///
+    /// With a dividend n at the pointer, a divisor d after it and zeros after that, the loop
+    /// leaves 0, d - n % d, n % d and n / d. The peephole optimizer emits `DivMod` just before
+    /// the loop, which then runs only if `DivMod` did nothing; see
+    /// [`State::div_mod`](../state/struct.State.html#method.div_mod) for when that is.
+    DivMod,
+    /// Does the work of an index loop that walks the byte at the pointer the given `Count` of
+    /// cells at a time to the right, counting it down, at once, if it can.
+    ///
+    /// Arrays in Brainfuck are indexed by loops like `[[->>+<<]>>-]` or `[-[->>+<<]>>]`,
+    /// which move an index n to n cells of the given stride along and leave it zero there, if
+    /// the cells along the way are zero. The peephole optimizer emits `IndexRight` just before
+    /// such a loop, which then runs only if `IndexRight` did nothing; see
+    /// [`State::index_right`](../state/struct.State.html#method.index_right) for when that is.
+    IndexRight(Count),
+    /// Does the work of an index loop like `[[-<<+>>]<<-]` that walks to the left, if it can.
+    ///
+    /// See [`IndexRight`](#variant.IndexRight).
+    IndexLeft(Count),
 }
 
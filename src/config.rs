@@ -0,0 +1,224 @@
+//! Configuration files, which give `bfi` its defaults.
+//!
+//! `bfi` reads the user’s `bf-rs/bf.toml` under `$XDG_CONFIG_HOME` (falling back to
+//! `~/.config`) and then the project’s `bf.toml`, the first found in the current directory or
+//! above it. Project settings override the user’s, and command-line flags override both. The
+//! files use a small part of TOML: `key = value` lines with string, integer or boolean values,
+//! `#` comments, and an optional `[bfi]` table header.
+//!
+//! ```toml
+//! [bfi]
+//! backend = "byte"      # ast, rle, peep, byte, threaded, trace, jit or llvm
+//! size = 65536          # memory size in bytes
+//! cell-width = 8        # bits per cell; 8 is the only width supported
+//! dialect = "brainfork" # or "multitape"
+//! opt-level = 2         # 0 to 3, picking ast, rle, peep or the default; backend wins
+//! unchecked = false     # leave out bounds checks in native code
+//! precompute = true     # run programs that read no input at compile time
+//! ```
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use text::{ParseError, ParseResult};
+
+/// The name of a configuration file.
+pub const FILE_NAME: &str = "bf.toml";
+
+/// The backends a configuration can name, by their `bfi` flags.
+pub const BACKENDS: &[&str] = &["ast", "rle", "peep", "byte", "threaded", "trace", "jit", "llvm"];
+
+/// Settings from a configuration file, each `None` if the file leaves it alone.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    /// The backend, one of [`BACKENDS`](constant.BACKENDS.html).
+    pub backend: Option<String>,
+    /// The memory size in bytes.
+    pub memory_size: Option<usize>,
+    /// The dialect, `"brainfork"` or `"multitape"`.
+    pub dialect: Option<String>,
+    /// The optimization level, from 0 to 3.
+    pub opt_level: Option<u8>,
+    /// Whether to leave out bounds checks in native code.
+    pub unchecked: Option<bool>,
+    /// Whether to run programs that read no input at compile time.
+    pub precompute: Option<bool>,
+}
+
+/// A value in a configuration file.
+enum Value<'a> {
+    Str(&'a str),
+    Int(u64),
+    Bool(bool),
+}
+
+impl Config {
+    /// Parses the text of a configuration file.
+    pub fn parse(input: &str) -> ParseResult<Self> {
+        let mut config = Config::default();
+
+        for (index, line) in input.lines().enumerate() {
+            let error = |message| ParseError { line: index + 1, message };
+            let line = strip_comment(line).trim();
+
+            if line.is_empty() || line == "[bfi]" {
+                continue;
+            }
+            if line.starts_with('[') {
+                return Err(error("unknown table"));
+            }
+
+            let equals = line.find('=').ok_or_else(|| error("expected ‘key = value’"))?;
+            let key = line[.. equals].trim();
+            let value = parse_value(line[equals + 1 ..].trim()).ok_or_else(|| error("bad value"))?;
+
+            match (key, value) {
+                ("backend", Value::Str(name)) if BACKENDS.contains(&name) =>
+                    config.backend = Some(name.to_owned()),
+                ("backend", _) => return Err(error("unknown backend")),
+                ("size", Value::Int(size)) if size > 0 =>
+                    config.memory_size = Some(size as usize),
+                ("size", _) => return Err(error("size must be a positive integer")),
+                ("cell-width", Value::Int(8)) => (),
+                ("cell-width", _) => return Err(error("only 8-bit cells are supported")),
+                ("dialect", Value::Str(name)) if name == "brainfork" || name == "multitape" =>
+                    config.dialect = Some(name.to_owned()),
+                ("dialect", _) => return Err(error("unknown dialect")),
+                ("opt-level", Value::Int(level)) if level <= 3 =>
+                    config.opt_level = Some(level as u8),
+                ("opt-level", _) => return Err(error("opt-level must be from 0 to 3")),
+                ("unchecked", Value::Bool(flag)) => config.unchecked = Some(flag),
+                ("precompute", Value::Bool(flag)) => config.precompute = Some(flag),
+                ("unchecked", _) | ("precompute", _) => return Err(error("expected a boolean")),
+                _ => return Err(error("unknown key")),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Combines two configurations, with the settings of `over` winning.
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            backend: over.backend.or(self.backend),
+            memory_size: over.memory_size.or(self.memory_size),
+            dialect: over.dialect.or(self.dialect),
+            opt_level: over.opt_level.or(self.opt_level),
+            unchecked: over.unchecked.or(self.unchecked),
+            precompute: over.precompute.or(self.precompute),
+        }
+    }
+
+    /// Reads and parses a configuration file.
+    pub fn read(path: &Path) -> Result<Self, LoadError> {
+        let text = fs::read_to_string(path).map_err(LoadError::Io)?;
+        Config::parse(&text).map_err(LoadError::Parse)
+    }
+
+    /// Reads the user’s configuration and then the project’s above `dir`, merging them. Files
+    /// that do not exist are skipped; an error comes with the path of the file at fault.
+    pub fn load(dir: &Path) -> Result<Self, (PathBuf, LoadError)> {
+        let mut config = Config::default();
+        for path in user_path().into_iter().chain(project_path(dir)) {
+            if path.is_file() {
+                let file = Config::read(&path).map_err(|error| (path, error))?;
+                config = config.merge(file);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Why a configuration file could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// Reading it failed.
+    Io(io::Error),
+    /// It does not parse.
+    Parse(ParseError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref error) => write!(f, "{}", error),
+            LoadError::Parse(ref error) => write!(f, "line {}: {}", error.line, error.message),
+        }
+    }
+}
+
+/// The user’s configuration file, if the environment gives a home for it.
+pub fn user_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("bf-rs").join(FILE_NAME))
+}
+
+/// The project’s configuration file: the first `bf.toml` in `dir` or a directory above it.
+pub fn project_path(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|dir| dir.join(FILE_NAME)).find(|path| path.is_file())
+}
+
+/// The line without any `#` comment, leaving `#` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[.. index],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_value<'a>(text: &'a str) -> Option<Value<'a>> {
+    match text {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') =>
+            Some(Value::Str(&text[1 .. text.len() - 1])),
+        _ => text.replace('_', "").parse().ok().map(Value::Int),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configs_parse() {
+        let config = Config::parse("# defaults\n[bfi]\nbackend = \"byte\"\nsize = 65_536 \
+                                    # bigger\ncell-width = 8\nprecompute = true\n").unwrap();
+        assert_eq!(config, Config {
+            backend: Some("byte".to_owned()),
+            memory_size: Some(65_536),
+            precompute: Some(true),
+            ..Config::default()
+        });
+
+        assert_eq!(Config::parse("backend = \"cobol\""),
+                   Err(ParseError { line: 1, message: "unknown backend" }));
+        assert_eq!(Config::parse("\ncell-width = 16"),
+                   Err(ParseError { line: 2, message: "only 8-bit cells are supported" }));
+        assert_eq!(Config::parse("colour = true"),
+                   Err(ParseError { line: 1, message: "unknown key" }));
+        assert_eq!(Config::parse("size"),
+                   Err(ParseError { line: 1, message: "expected ‘key = value’" }));
+    }
+
+    #[test]
+    fn later_configs_win() {
+        let user = Config::parse("backend = \"peep\"\nsize = 100").unwrap();
+        let project = Config::parse("size = 200\nunchecked = true").unwrap();
+        assert_eq!(user.merge(project), Config {
+            backend: Some("peep".to_owned()),
+            memory_size: Some(200),
+            unchecked: Some(true),
+            ..Config::default()
+        });
+    }
+}
@@ -0,0 +1,274 @@
+//! An estimate of how long programs take, independent of the machine, for `bfi cost`.
+//!
+//! Wall-clock time depends on the backend and the host, so it is a poor way to compare two
+//! programs, as a code-golf judge might. Instead, [`profile`](fn.profile.html) runs a program’s
+//! bytecode counting how often each instruction runs, and a [`CostModel`](struct.CostModel.html)
+//! weighs those counts by kind of instruction to give an estimated number of cycles, in total
+//! and for each loop. The default weights are rough; a judge can set its own.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+
+use bytecode::{self, Fault, StepResult};
+use common::Instruction;
+use state::State;
+use traits::IntoUsize;
+
+/// The cycles each kind of bytecode instruction is taken to cost.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CostModel {
+    /// `Left` and `Right`.
+    pub moves: u64,
+    /// `Add`.
+    pub add: u64,
+    /// `In` and `Out`.
+    pub io: u64,
+    /// `JumpZero` and `JumpNotZero`.
+    pub jump: u64,
+    /// `SetZero`.
+    pub set_zero: u64,
+    /// `OffsetAddRight` and `OffsetAddLeft`.
+    pub offset_add: u64,
+    /// `FindZeroRight` and `FindZeroLeft`, besides their steps.
+    pub scan: u64,
+    /// Each step `FindZeroRight` and `FindZeroLeft` take.
+    pub scan_step: u64,
+    /// `DivMod`.
+    pub div_mod: u64,
+    /// `IndexRight` and `IndexLeft`.
+    pub index: u64,
+}
+
+/// The names [`CostModel::set`](struct.CostModel.html#method.set) takes, in field order.
+pub const WEIGHTS: &[&str] = &["moves", "add", "io", "jump", "set-zero", "offset-add", "scan",
+                               "scan-step", "div-mod", "index"];
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            moves: 1,
+            add: 1,
+            io: 1,
+            jump: 1,
+            set_zero: 1,
+            offset_add: 2,
+            scan: 1,
+            scan_step: 1,
+            div_mod: 10,
+            index: 4,
+        }
+    }
+}
+
+impl CostModel {
+    /// Sets the weight with the given name, one of [`WEIGHTS`](constant.WEIGHTS.html), returning
+    /// whether there is one.
+    pub fn set(&mut self, name: &str, cycles: u64) -> bool {
+        let weight = match name {
+            "moves" => &mut self.moves,
+            "add" => &mut self.add,
+            "io" => &mut self.io,
+            "jump" => &mut self.jump,
+            "set-zero" => &mut self.set_zero,
+            "offset-add" => &mut self.offset_add,
+            "scan" => &mut self.scan,
+            "scan-step" => &mut self.scan_step,
+            "div-mod" => &mut self.div_mod,
+            "index" => &mut self.index,
+            _ => return false,
+        };
+        *weight = cycles;
+        true
+    }
+
+    /// The cost of running an instruction once, not counting any scan steps.
+    pub fn instruction(&self, instruction: Instruction) -> u64 {
+        use common::Instruction::*;
+
+        match instruction {
+            Left(_) | Right(_) => self.moves,
+            Add(_) => self.add,
+            In | Out => self.io,
+            JumpZero(_) | JumpNotZero(_) => self.jump,
+            SetZero => self.set_zero,
+            OffsetAddRight(_) | OffsetAddLeft(_) => self.offset_add,
+            FindZeroRight(_) | FindZeroLeft(_) => self.scan,
+            DivMod => self.div_mod,
+            IndexRight(_) | IndexLeft(_) => self.index,
+        }
+    }
+
+    /// The cost of the instructions at the given addresses, as often as they ran.
+    fn range_cost(&self, program: &bytecode::Program, profile: &Profile,
+                  begin: usize, end: usize) -> u64 {
+        (begin .. end).map(|pc| {
+            profile.counts[pc] * self.instruction(program[pc]) + profile.steps[pc] * self.scan_step
+        }).sum()
+    }
+
+    /// Weighs a run’s profile, giving its total cost and each loop’s.
+    pub fn report(&self, program: &bytecode::Program, profile: &Profile) -> CostReport {
+        let mut loops = Vec::new();
+
+        for (begin, &instruction) in program.iter().enumerate() {
+            if let Instruction::JumpZero(end) = instruction {
+                let end = end.into_usize();
+                loops.push(LoopCost {
+                    begin,
+                    end,
+                    iterations: profile.counts[end],
+                    cycles: self.range_cost(program, profile, begin, end + 1),
+                });
+            }
+        }
+
+        CostReport {
+            cycles: self.range_cost(program, profile, 0, program.len()),
+            instructions: profile.counts.iter().sum(),
+            loops,
+        }
+    }
+}
+
+/// How often each instruction of a program ran.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    /// How many times the instruction at each address ran.
+    pub counts: Vec<u64>,
+    /// How many steps the scan at each address took in all, or 0 for other instructions.
+    pub steps: Vec<u64>,
+}
+
+/// Interprets a bytecode program like
+/// [`interpret_locating`](../bytecode/fn.interpret_locating.html), counting the instructions
+/// it runs.
+pub fn profile<R, W>(program: &bytecode::Program, state: &mut State, mut input: R, mut output: W)
+                     -> Result<Profile, Fault>
+    where R: Read, W: Write
+{
+    use common::Instruction::*;
+
+    let mut profile = Profile { counts: vec![0; program.len()], steps: vec![0; program.len()] };
+    let mut pc = 0;
+
+    while let Some(&instruction) = program.get(pc) {
+        let at = pc;
+        profile.counts[at] += 1;
+
+        let mut byte = None;
+        if instruction == In {
+            let mut buffer = [0];
+            let _ = input.read_exact(&mut buffer);
+            byte = Some(buffer[0]);
+        }
+
+        let pointer = state.pointer();
+        let result = bytecode::execute(instruction, state, &mut pc, &mut byte)
+            .map_err(|error| Fault { error, pc: at })?;
+        if let StepResult::Output(byte) = result {
+            let _ = output.write_all(&[byte]);
+        }
+
+        if let FindZeroRight(stride) | FindZeroLeft(stride) = instruction {
+            let distance = (state.pointer() as isize - pointer as isize).unsigned_abs();
+            profile.steps[at] += (distance / stride.into_usize()) as u64;
+        }
+    }
+
+    Ok(profile)
+}
+
+/// The cost of one loop in a run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LoopCost {
+    /// The address of its `JumpZero`.
+    pub begin: usize,
+    /// The address of its `JumpNotZero`.
+    pub end: usize,
+    /// How many times its body ran to the end.
+    pub iterations: u64,
+    /// The estimated cycles spent in it, inner loops included.
+    pub cycles: u64,
+}
+
+/// The estimated cost of a run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CostReport {
+    /// The estimated cycles in all.
+    pub cycles: u64,
+    /// The number of instructions run.
+    pub instructions: u64,
+    /// Each loop’s cost, in program order.
+    pub loops: Vec<LoopCost>,
+}
+
+impl CostReport {
+    /// Writes the report out, with the `hottest` loops that cost the most, locating loops by
+    /// their `JumpZero` address with `locate`.
+    pub fn report<F: Fn(usize) -> String>(&self, hottest: usize, locate: F) -> String {
+        let mut result = String::new();
+        let _ = writeln!(result, "estimated cycles: {}", self.cycles);
+        let _ = writeln!(result, "instructions run: {}", self.instructions);
+
+        let mut loops: Vec<&LoopCost> = self.loops.iter().filter(|l| l.cycles > 0).collect();
+        loops.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.begin.cmp(&b.begin)));
+        for cost in loops.into_iter().take(hottest) {
+            let share = 100.0 * cost.cycles as f64 / self.cycles.max(1) as f64;
+            let _ = writeln!(result, "loop at {}: {} cycles ({:.1}%), {} iterations",
+                             locate(cost.begin), cost.cycles, share, cost.iterations);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn run(source: &[u8], input: &[u8]) -> (Box<bytecode::Program>, Profile, Vec<u8>) {
+        let program = ::ast::parse_program(source).unwrap().bytecode_compile();
+        let mut output = Vec::new();
+        let profile = profile(&program, &mut State::new(), input, &mut output).unwrap();
+        (program, profile, output)
+    }
+
+    #[test]
+    fn runs_are_weighed_by_instruction() {
+        // Add(3), JumpZero, Right, Add(2), Left, Add(255), JumpNotZero, Right, Out.
+        let (program, profile, output) = run(b"+++[>++<-]>.", b"");
+        assert_eq!(output, [6]);
+        assert_eq!(profile.counts, [1, 1, 3, 3, 3, 3, 3, 1, 1]);
+
+        let report = CostModel::default().report(&program, &profile);
+        assert_eq!((report.cycles, report.instructions), (19, 19));
+        assert_eq!(report.loops, [LoopCost { begin: 1, end: 6, iterations: 3, cycles: 16 }]);
+
+        let mut model = CostModel::default();
+        assert!(model.set("add", 5) && model.set("io", 100) && !model.set("mul", 1));
+        assert_eq!(model.report(&program, &profile).cycles, 19 + 4 * 7 + 99);
+    }
+
+    #[test]
+    fn scans_cost_by_distance() {
+        let (program, profile, _) = run(b"+>>+>>+<<<<[>>]", b"");
+        let scan = program.iter().position(|&i| i == Instruction::FindZeroRight(2)).unwrap();
+        assert_eq!(profile.steps[scan], 3);
+
+        let model = CostModel { scan_step: 10, ..CostModel::default() };
+        let report = model.report(&program, &profile);
+        assert_eq!(report.cycles, report.instructions + 3 * 10);
+    }
+
+    #[test]
+    fn reports_list_the_hottest_loops() {
+        let (program, profile, output) = run(FACTOR_SRC, b"12\n");
+        assert_eq!(output, b"12: 2 2 3\n");
+        let report = CostModel::default().report(&program, &profile);
+        let text = report.report(2, |pc| format!("#{}", pc));
+        assert!(text.starts_with(&format!("estimated cycles: {}\n", report.cycles)));
+        assert_eq!(text.lines().filter(|line| line.starts_with("loop at #")).count(), 2);
+    }
+}
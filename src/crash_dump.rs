@@ -0,0 +1,214 @@
+//! Crash dumps for bug reports.
+//!
+//! A run-time error message says what went wrong and where, but not how the program got there.
+//! [`interpret`](fn.interpret.html) runs bytecode as the interpreter does, keeping a
+//! [history](../history/index.html) of the last instructions it started, and when the run fails
+//! returns a [`CrashDump`](struct.CrashDump.html): the program’s
+//! [fingerprint](../fingerprint/index.html), how it was run, the step count, the pointer, the
+//! cells around it and that trace, whose source spans
+//! [`locate`](struct.CrashDump.html#method.locate) fills in. Its text form is meant to be
+//! attached to a bug report; `bfi --crash-dump FILE` writes one.
+//!
+//! ```
+//! use bf::crash_dump;
+//! use bf::state::State;
+//! use bf::traits::BytecodeCompilable;
+//!
+//! let program = bf::ast::parse_program(b"+[>+]").unwrap().bytecode_compile();
+//! let mut state = State::with_capacity(4);
+//! let dump = crash_dump::interpret(&program, &mut state, &b""[..], Vec::new(), "--byte -s 4")
+//!     .unwrap_err();
+//! assert_eq!(dump.pointer, 3);
+//! assert_eq!(dump.tape, [1, 1, 1, 1]);
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use bytecode::{self, Fault, StepResult};
+use common::{Error, Instruction};
+use fingerprint::Fingerprintable;
+use history::{self, Executed, History};
+use source_map::SourceMap;
+use state::State;
+
+/// (`== 16`) How many cells either side of the pointer a dump keeps.
+pub const TAPE_WINDOW: usize = 16;
+
+/// What a failed run was doing when it failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CrashDump {
+    /// The fingerprint of the bytecode.
+    pub fingerprint: u64,
+    /// How the program was run, such as the command line.
+    pub options: String,
+    /// The error.
+    pub error: Error,
+    /// The address of the failing instruction.
+    pub pc: usize,
+    /// The number of instructions that ran before it.
+    pub steps: u64,
+    /// The pointer.
+    pub pointer: usize,
+    /// The address of the first cell in `tape`.
+    pub tape_start: usize,
+    /// The cells around the pointer.
+    pub tape: Vec<u8>,
+    /// The instructions last started, oldest first, ending with the failing one.
+    pub trace: Vec<Executed>,
+}
+
+impl CrashDump {
+    /// The error and where it happened.
+    pub fn fault(&self) -> Fault {
+        Fault { error: self.error, pc: self.pc }
+    }
+
+    /// Fills in the source spans of the trace from the map of the program that failed.
+    pub fn locate(&mut self, map: &SourceMap) {
+        history::locate(&mut self.trace, map);
+    }
+
+    /// Writes the dump’s text form to the file at `path`.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for CrashDump {
+    /// One line for each field, then the tape with the pointer’s cell in brackets, then the
+    /// trace one instruction to a line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "bf crash dump")?;
+        writeln!(f, "error: {}", self.error)?;
+        writeln!(f, "fingerprint: {:016x}", self.fingerprint)?;
+        writeln!(f, "options: {}", self.options)?;
+        writeln!(f, "steps: {}", self.steps)?;
+        writeln!(f, "pc: {}", self.pc)?;
+        writeln!(f, "pointer: {}", self.pointer)?;
+
+        write!(f, "tape from {}:", self.tape_start)?;
+        for (address, cell) in (self.tape_start ..).zip(&self.tape) {
+            if address == self.pointer {
+                write!(f, " [{:02x}]", cell)?;
+            } else {
+                write!(f, " {:02x}", cell)?;
+            }
+        }
+        writeln!(f)?;
+
+        writeln!(f, "trace:")?;
+        for executed in &self.trace {
+            writeln!(f, "  {}", executed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Interprets a program like
+/// [`bytecode::interpret_locating`](../bytecode/fn.interpret_locating.html), keeping a trace
+/// of the instructions it runs for a dump.
+///
+/// # Errors
+///
+/// A dump of the run, with `options` saying how it was run, when it fails.
+pub fn interpret<R, W>(program: &bytecode::Program, state: &mut State, mut input: R,
+                       mut output: W, options: &str) -> Result<(), Box<CrashDump>>
+    where R: Read, W: Write
+{
+    let mut history = History::new(history::DEFAULT_LEN);
+    let mut steps = 0;
+    let mut pc = 0;
+
+    while let Some(&instruction) = program.get(pc) {
+        let at = pc;
+        history.record(at, instruction);
+
+        let mut byte = None;
+        if instruction == Instruction::In {
+            let mut buffer = [0];
+            let _ = input.read_exact(&mut buffer);
+            byte = Some(buffer[0]);
+        }
+
+        match bytecode::execute(instruction, state, &mut pc, &mut byte) {
+            Ok(StepResult::Output(byte)) => { let _ = output.write_all(&[byte]); }
+            Ok(_) => (),
+            Err(error) => {
+                let start = state.pointer().saturating_sub(TAPE_WINDOW);
+                let end = state.capacity().min(state.pointer() + TAPE_WINDOW + 1);
+                return Err(Box::new(CrashDump {
+                    fingerprint: program.fingerprint(),
+                    options: options.to_owned(),
+                    error,
+                    pc: at,
+                    steps,
+                    pointer: state.pointer(),
+                    tape_start: start,
+                    tape: state.memory()[start .. end].iter().map(|cell| cell.0).collect(),
+                    trace: history.executed(),
+                }));
+            }
+        }
+
+        steps += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::BytecodeCompilable;
+
+    fn crash(source: &[u8], memory_size: usize) -> Box<CrashDump> {
+        let program = ::ast::parse_program(source).unwrap().bytecode_compile();
+        let mut state = State::with_capacity(memory_size);
+        interpret(&program, &mut state, &b""[..], Vec::new(), "-s 4").unwrap_err()
+    }
+
+    #[test]
+    fn dumps_end_with_the_failing_instruction() {
+        let dump = crash(b"+[>+]", 4);
+        let program = ::ast::parse_program(b"+[>+]").unwrap().bytecode_compile();
+        let (_, stats) = bytecode::interpret_measuring(&program, &mut State::with_capacity(4),
+                                                       &b""[..], Vec::new());
+
+        assert_eq!(dump.fault(), Fault { error: Error::PointerOverflow, pc: 2 });
+        assert_eq!(Some(dump.steps), stats.steps);
+        assert_eq!(dump.fingerprint, program.fingerprint());
+        assert_eq!(dump.trace.last().map(|executed| (executed.pc, executed.instruction)),
+                   Some((2, Instruction::Right(1))));
+        assert_eq!(dump.trace.len(), 12);
+        assert_eq!((dump.tape_start, dump.pointer), (0, 3));
+    }
+
+    #[test]
+    fn traces_and_tapes_are_bounded() {
+        let dump = crash(b">>>>>>>>>>>>>>>>>>>>+[<+]", 100);
+        assert_eq!(dump.error, Error::PointerUnderflow);
+        assert_eq!(dump.trace.len(), history::DEFAULT_LEN);
+        assert_eq!(dump.tape, vec![1; TAPE_WINDOW + 1]);
+
+        let dump = crash(b"+[>+]", 1000);
+        assert_eq!(dump.tape_start, 999 - TAPE_WINDOW);
+        assert_eq!(dump.tape.len(), TAPE_WINDOW + 1);
+    }
+
+    #[test]
+    fn dumps_print_the_pointer_and_trace() {
+        let text = crash(b"+[>+]", 4).to_string();
+        assert!(text.starts_with("bf crash dump\nerror: "));
+        assert!(text.contains("\noptions: -s 4\nsteps: 11\npc: 2\npointer: 3\n"));
+        assert!(text.contains("\ntape from 0: 01 01 01 [01]\ntrace:\n"));
+        assert!(text.ends_with("  3: Add(1)\n  4: JumpNotZero(1)\n  2: Right(1)\n"));
+
+        let mut dump = crash(b"+[>+]", 4);
+        dump.locate(&SourceMap::new(b"+[>+]", &::peephole::compile(&::rle::compile(
+            &::ast::parse_program(b"+[>+]").unwrap()))));
+        assert!(dump.to_string().ends_with("  4: JumpNotZero(1) at 4..5\n  2: Right(1) at 2..3\n"));
+    }
+}
@@ -0,0 +1,471 @@
+//! Readable pseudo-code for a program, for reverse-engineering Brainfuck with
+//! `bfi explain --pseudo`.
+//!
+//! [`decompile`](fn.decompile.html) renders the peephole IR one operation per line, with the
+//! idioms the optimizer recognizes written as what they do: `c2 = 0` for a clear, `c1 += 3 * c0`
+//! for a multiplication loop, a copy through a temporary cell as one assignment, and scans and
+//! divisions as calls. Cells are named for their distance from the start, as `c0`, `c1` and so
+//! on, for as long as the pointer’s position is known, which it is through loops that come
+//! back to where they started, as [`LoopBalance`](../analysis/loop_balance/struct.LoopBalance.html)
+//! tells. Past a scan or an unbalanced loop, cells are named from a pointer `p`, as `p[0]`.
+//!
+//! ```text
+//! c0 = getchar()
+//! c1 += 2 * c0
+//! c0 = 0
+//! p = &c1
+//! while p[0] {
+//!     ...
+//! ```
+
+use std::fmt::Write;
+
+use analysis::loop_balance::LoopBalanceMap;
+use peephole::{Program, Statement};
+use traits::IntoUsize;
+
+/// Writes the program out as pseudo-code.
+pub fn decompile(program: &Program) -> String {
+    let mut decompiler = Decompiler {
+        balances: LoopBalanceMap::new(program),
+        next_loop: 0,
+        position: Position::Known(0),
+    };
+    let mut ops = decompiler.block(program);
+    fuse(&mut ops, true);
+
+    let mut result = String::new();
+    write_ops(&mut result, &ops, 0);
+    result
+}
+
+/// A cell, by its distance from the start or from the pointer `p`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Cell {
+    Absolute(isize),
+    Relative(isize),
+}
+
+/// Where the pointer is, as far as is known.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Position {
+    /// At this distance from the start.
+    Known(isize),
+    /// At this distance from `p`.
+    Relative(isize),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Op {
+    Add(Cell, u8),
+    Clear(Cell),
+    Set(Cell, u8),
+    Read(Cell),
+    Write(Cell),
+    /// Adds the cell times each factor to each target, and clears it.
+    Transfer(Cell, Vec<(Cell, u8)>),
+    /// Copies the first cell into the second through the third, which ends up zero.
+    Copy(Cell, Cell, Cell),
+    DivMod(Cell),
+    /// Moves `p` by the stride until it finds a zero.
+    Scan(isize),
+    /// Walks `p[0]` the stride along that many times.
+    Index(isize),
+    /// Points `p` at the cell.
+    Point(Cell),
+    /// Moves `p`.
+    Shift(isize),
+    While(Cell, Vec<Op>),
+}
+
+struct Decompiler {
+    balances: LoopBalanceMap,
+    /// The preorder number of the next loop.
+    next_loop: usize,
+    position: Position,
+}
+
+impl Decompiler {
+    fn cell(&self, offset: isize) -> Cell {
+        match self.position {
+            Position::Known(at) => Cell::Absolute(at + offset),
+            Position::Relative(at) => Cell::Relative(at + offset),
+        }
+    }
+
+    fn shift(&mut self, by: isize) {
+        self.position = match self.position {
+            Position::Known(at) => Position::Known(at + by),
+            Position::Relative(at) => Position::Relative(at + by),
+        };
+    }
+
+    /// Points `p` at the current cell, for code that loses track of the position.
+    fn pin(&mut self, ops: &mut Vec<Op>) {
+        match self.position {
+            Position::Known(at) => ops.push(Op::Point(Cell::Absolute(at))),
+            Position::Relative(0) => (),
+            Position::Relative(at) => ops.push(Op::Shift(at)),
+        }
+        self.position = Position::Relative(0);
+    }
+
+    fn block(&mut self, program: &[Statement]) -> Vec<Op> {
+        use common::Instruction::*;
+
+        let mut ops = Vec::new();
+        let mut skip_loop = false;
+
+        for statement in program {
+            let body = match *statement {
+                Statement::Loop(ref body) => body,
+                Statement::Instr(instruction) => {
+                    match instruction {
+                        Right(count) => self.shift(count.into_usize() as isize),
+                        Left(count) => self.shift(-(count.into_usize() as isize)),
+                        Add(amount) => ops.push(Op::Add(self.cell(0), amount)),
+                        SetZero => ops.push(Op::Clear(self.cell(0))),
+                        In => ops.push(Op::Read(self.cell(0))),
+                        Out => ops.push(Op::Write(self.cell(0))),
+                        OffsetAddRight(count) | OffsetAddLeft(count) => {
+                            let mut offset = count.into_usize() as isize;
+                            if let OffsetAddLeft(_) = instruction {
+                                offset = -offset;
+                            }
+                            ops.push(Op::Transfer(self.cell(0), vec![(self.cell(offset), 1)]));
+                        }
+                        FindZeroRight(count) | FindZeroLeft(count) => {
+                            self.pin(&mut ops);
+                            let stride = count.into_usize() as isize;
+                            ops.push(Op::Scan(if let FindZeroLeft(_) = instruction {
+                                -stride
+                            } else {
+                                stride
+                            }));
+                        }
+                        DivMod => {
+                            ops.push(Op::DivMod(self.cell(0)));
+                            skip_loop = true;
+                        }
+                        IndexRight(count) | IndexLeft(count) => {
+                            self.pin(&mut ops);
+                            let stride = count.into_usize() as isize;
+                            ops.push(Op::Index(if let IndexLeft(_) = instruction {
+                                -stride
+                            } else {
+                                stride
+                            }));
+                            skip_loop = true;
+                        }
+                        JumpZero(_) | JumpNotZero(_) => panic!("unexpected jump instruction"),
+                    }
+                    continue;
+                }
+            };
+
+            let index = self.next_loop;
+            self.next_loop += 1;
+
+            // The loop after a `DivMod` or index instruction does the same work, if any is
+            // left, so it says nothing new.
+            if skip_loop {
+                skip_loop = false;
+                self.next_loop += count_loops(body);
+                continue;
+            }
+
+            if let Some(targets) = linear(body) {
+                let targets = targets.into_iter()
+                    .map(|(offset, factor)| (self.cell(offset), factor)).collect();
+                ops.push(Op::Transfer(self.cell(0), targets));
+            } else if self.balances.get(index).is_balanced() {
+                let counter = self.cell(0);
+                let body = self.block(body);
+                ops.push(Op::While(counter, body));
+            } else {
+                self.pin(&mut ops);
+                let mut body = self.block(body);
+                self.pin(&mut body);
+                ops.push(Op::While(Cell::Relative(0), body));
+            }
+        }
+
+        ops
+    }
+}
+
+/// The targets and factors of a loop that only adds the cell it starts on to other cells, if
+/// the body is one.
+fn linear(body: &[Statement]) -> Option<Vec<(isize, u8)>> {
+    use common::Instruction::*;
+
+    let mut offset = 0isize;
+    let mut adds: Vec<(isize, u8)> = Vec::new();
+    for statement in body {
+        match *statement {
+            Statement::Instr(Right(count)) => offset += count.into_usize() as isize,
+            Statement::Instr(Left(count)) => offset -= count.into_usize() as isize,
+            Statement::Instr(Add(amount)) => match adds.iter_mut().find(|add| add.0 == offset) {
+                Some(add) => add.1 = add.1.wrapping_add(amount),
+                None => adds.push((offset, amount)),
+            },
+            _ => return None,
+        }
+    }
+
+    if offset != 0 || !adds.contains(&(0, 255)) {
+        return None;
+    }
+    adds.retain(|&(offset, amount)| offset != 0 && amount != 0);
+    Some(adds)
+}
+
+fn count_loops(body: &[Statement]) -> usize {
+    body.iter().map(|statement| match *statement {
+        Statement::Loop(ref body) => 1 + count_loops(body),
+        Statement::Instr(_) => 0,
+    }).sum()
+}
+
+/// Combines operations into the idioms they make up together.
+///
+/// A pair of transfers is a copy only if the temporary starts out zero, so this follows which
+/// cells are known to be: all of them at the start of the program, while `fresh`, and those
+/// cleared since.
+fn fuse(ops: &mut Vec<Op>, fresh: bool) {
+    let mut result: Vec<Op> = Vec::with_capacity(ops.len());
+    let mut zeros = Zeros { fresh, touched: Vec::new(), known: Vec::new() };
+    let mut before_last = zeros.clone();
+
+    for op in ops.drain(..) {
+        let fused = match (result.last(), &op) {
+            (Some(&Op::Clear(a)), &Op::Add(b, amount)) if a == b => Some(Op::Set(a, amount)),
+            (Some(&Op::Transfer(from, ref targets)), &Op::Transfer(temp, ref back))
+                if targets.len() == 2 && back.as_slice() == [(from, 1)]
+                    && before_last.is_zero(temp) =>
+            {
+                match (targets[0], targets[1]) {
+                    ((to, 1), (t, 1)) | ((t, 1), (to, 1)) if t == temp && to != from =>
+                        Some(Op::Copy(from, to, temp)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let op = match (fused, op) {
+            (Some(fused), _) => {
+                result.pop();
+                zeros = before_last;
+                fused
+            }
+            (None, Op::While(counter, mut body)) => {
+                fuse(&mut body, false);
+                Op::While(counter, body)
+            }
+            (None, op) => op,
+        };
+        before_last = zeros.clone();
+        zeros.apply(&op);
+        result.push(op);
+    }
+
+    *ops = result;
+}
+
+/// Which cells are known to be zero.
+#[derive(Clone, Debug)]
+struct Zeros {
+    /// Whether the cells not yet touched are still zero, as at the start of the program.
+    fresh: bool,
+    touched: Vec<Cell>,
+    known: Vec<Cell>,
+}
+
+impl Zeros {
+    fn is_zero(&self, cell: Cell) -> bool {
+        self.known.contains(&cell)
+            || (self.fresh && !self.touched.contains(&cell)
+                && matches!(cell, Cell::Absolute(_)))
+    }
+
+    /// Notes that the cell may now be anything. A cell named from `p` may be any cell named
+    /// from the start, and the other way around.
+    fn write(&mut self, cell: Cell) {
+        self.touched.push(cell);
+        match cell {
+            Cell::Absolute(_) => self.known.retain(|&known| known != cell
+                && matches!(known, Cell::Absolute(_))),
+            Cell::Relative(_) => {
+                self.fresh = false;
+                self.known.retain(|&known| known != cell
+                    && matches!(known, Cell::Relative(_)));
+            }
+        }
+    }
+
+    fn clear(&mut self, cell: Cell) {
+        self.write(cell);
+        self.known.push(cell);
+    }
+
+    fn apply(&mut self, op: &Op) {
+        match *op {
+            Op::Add(cell, _) | Op::Set(cell, _) | Op::Read(cell) => self.write(cell),
+            Op::Clear(cell) => self.clear(cell),
+            Op::Write(_) | Op::Scan(_) => (),
+            Op::Transfer(from, ref targets) => {
+                for &(to, _) in targets {
+                    self.write(to);
+                }
+                self.clear(from);
+            }
+            Op::Copy(_, to, temp) => {
+                self.write(to);
+                self.clear(temp);
+            }
+            Op::DivMod(cell) => {
+                for by in 1 .. 4 {
+                    self.write(match cell {
+                        Cell::Absolute(offset) => Cell::Absolute(offset + by),
+                        Cell::Relative(offset) => Cell::Relative(offset + by),
+                    });
+                }
+                self.clear(cell);
+            }
+            Op::Point(_) | Op::Shift(_) =>
+                self.known.retain(|&known| matches!(known, Cell::Absolute(_))),
+            Op::Index(_) | Op::While(..) => {
+                self.fresh = false;
+                self.known.clear();
+                if let Op::While(counter, _) = *op {
+                    self.known.push(counter);
+                }
+            }
+        }
+    }
+}
+
+fn name(cell: Cell) -> String {
+    match cell {
+        Cell::Absolute(offset) if offset >= 0 => format!("c{}", offset),
+        Cell::Absolute(offset) => format!("c[{}]", offset),
+        Cell::Relative(offset) => format!("p[{}]", offset),
+    }
+}
+
+/// `+= n` or `-= n`, whichever is shorter, for adding `amount` modulo 256.
+fn add_text(amount: u8) -> String {
+    if amount <= 128 {
+        format!("+= {}", amount)
+    } else {
+        format!("-= {}", amount.wrapping_neg())
+    }
+}
+
+fn write_ops(result: &mut String, ops: &[Op], depth: usize) {
+    macro_rules! line {
+        ($($arg:tt)*) => {
+            let _ = writeln!(result, "{:1$}{2}", "", 4 * depth, format_args!($($arg)*));
+        }
+    }
+
+    for op in ops {
+        match *op {
+            Op::Add(cell, amount) => { line!("{} {}", name(cell), add_text(amount)); }
+            Op::Clear(cell) => { line!("{} = 0", name(cell)); }
+            Op::Set(cell, value) => { line!("{} = {}", name(cell), value); }
+            Op::Read(cell) => { line!("{} = getchar()", name(cell)); }
+            Op::Write(cell) => { line!("putchar({})", name(cell)); }
+            Op::Transfer(from, ref targets) => {
+                for &(to, factor) in targets {
+                    let (sign, factor) = if factor <= 128 {
+                        ('+', factor)
+                    } else {
+                        ('-', factor.wrapping_neg())
+                    };
+                    if factor == 1 {
+                        line!("{} {}= {}", name(to), sign, name(from));
+                    } else {
+                        line!("{} {}= {} * {}", name(to), sign, factor, name(from));
+                    }
+                }
+                line!("{} = 0", name(from));
+            }
+            Op::Copy(from, to, temp) => {
+                line!("{} += {}  # copied through {}", name(to), name(from), name(temp));
+            }
+            Op::DivMod(cell) => {
+                let next = |by| match cell {
+                    Cell::Absolute(offset) => name(Cell::Absolute(offset + by)),
+                    Cell::Relative(offset) => name(Cell::Relative(offset + by)),
+                };
+                let (n, d) = (name(cell), next(1));
+                line!("{}, {} = {} % {}, {} / {}", next(2), next(3), n, d, n, d);
+                line!("{} -= {}", d, next(2));
+                line!("{} = 0", n);
+            }
+            Op::Scan(stride) => { line!("p = find_zero(p, {:+})", stride); }
+            Op::Index(stride) => { line!("p = index(p, {:+})", stride); }
+            Op::Point(cell) => { line!("p = &{}", name(cell)); }
+            Op::Shift(by) => {
+                line!("p {}= {}", if by < 0 { '-' } else { '+' }, by.unsigned_abs());
+            }
+            Op::While(counter, ref body) => {
+                line!("while {} {{", name(counter));
+                write_ops(result, body, depth + 1);
+                line!("}}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use traits::PeepholeCompilable;
+
+    fn pseudo(source: &[u8]) -> String {
+        decompile(&ast::parse_program(source).unwrap().peephole_compile())
+    }
+
+    #[test]
+    fn idioms_read_as_what_they_do() {
+        assert_eq!(pseudo(b",[->+>+<<]>>[-<<+>>]<<[->>+++<<]>>."),
+                   "c0 = getchar()\n\
+                    c1 += c0  # copied through c2\n\
+                    c2 += 3 * c0\n\
+                    c0 = 0\n\
+                    putchar(c2)\n");
+
+        // The temporary is not zero, so this is not a copy.
+        assert_eq!(pseudo(b">+++>>[-<+<+>>]<<[->>+<<]"),
+                   "c1 += 3\n\
+                    c2 += c3\n\
+                    c1 += c3\n\
+                    c3 = 0\n\
+                    c3 += c1\n\
+                    c1 = 0\n");
+    }
+
+    #[test]
+    fn unbalanced_loops_name_cells_from_the_pointer() {
+        assert_eq!(pseudo(b"+>,[>,]<[.<]>>[>]+"),
+                   "c0 += 1\n\
+                    c1 = getchar()\n\
+                    p = &c1\n\
+                    while p[0] {\n    \
+                        p[1] = getchar()\n    \
+                        p += 1\n\
+                    }\n\
+                    p -= 1\n\
+                    while p[0] {\n    \
+                        putchar(p[0])\n    \
+                        p -= 1\n\
+                    }\n\
+                    p += 2\n\
+                    p = find_zero(p, +1)\n\
+                    p[0] += 1\n");
+    }
+}
@@ -0,0 +1,203 @@
+//! Diagnostics with source spans, for editors and other tools.
+//!
+//! Where [`ast::parse_program`](../ast/fn.parse_program.html) stops at the first error, the
+//! [`check`](fn.check.html) function reports every problem it finds, each with the byte range
+//! of the source it concerns:
+//!
+//!  - unmatched `[` and `]` (errors);
+//!  - loops that can never run, because they begin the program, when every cell is zero, or
+//!    immediately follow another loop, which exits only on zero (warnings);
+//!  - empty loops `[]`, which never terminate once entered (warnings); and
+//!  - adjacent commands that cancel out, such as `+-` or `<>`, which the optimizer removes
+//!    (warnings).
+
+use std::fmt;
+
+/// A half-open range of byte offsets into the source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Span {
+    /// The offset of the first byte.
+    pub start: usize,
+    /// The offset just past the last byte.
+    pub end: usize,
+}
+
+impl Span {
+    /// The span of the single byte at `offset`.
+    pub fn at(offset: usize) -> Self {
+        Span { start: offset, end: offset + 1 }
+    }
+}
+
+/// How serious a diagnostic is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Severity {
+    /// The program cannot be run.
+    Error,
+    /// The program runs, but probably not as intended.
+    Warning,
+}
+
+/// A problem found in the source.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The part of the source the problem concerns.
+    pub span: Span,
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// A description of the problem.
+    pub message: &'static str,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {} at {}..{}", severity, self.message, self.span.start, self.span.end)
+    }
+}
+
+/// Checks Brainfuck source, returning its diagnostics in order of position.
+pub fn check(source: &[u8]) -> Vec<Diagnostic> {
+    let mut result = Vec::new();
+    let mut open = Vec::new();
+    // The last command before the current position, ignoring comments, with its offset.
+    let mut previous: Option<(u8, usize)> = None;
+
+    for (offset, &c) in source.iter().enumerate() {
+        match c {
+            b'[' => {
+                let dead = matches!(previous, None | Some((b']', _)));
+                open.push((offset, dead));
+            }
+
+            b']' => match open.pop() {
+                Some((start, dead)) => {
+                    if dead {
+                        result.push(warning(Span { start, end: offset + 1 }, "loop never runs"));
+                    } else if previous == Some((b'[', start)) {
+                        result.push(warning(Span { start, end: offset + 1 },
+                                            "empty loop never terminates once entered"));
+                    }
+                }
+                None => result.push(error(Span::at(offset), "unmatched ‘]’")),
+            },
+
+            b'+' | b'-' | b'<' | b'>' => {
+                if let Some((before, start)) = previous {
+                    if cancels(before, c) {
+                        result.push(warning(Span { start, end: offset + 1 },
+                                            "commands cancel out"));
+                    }
+                }
+            }
+
+            b',' | b'.' => (),
+
+            _ => continue,
+        }
+
+        previous = Some((c, offset));
+    }
+
+    for (start, _) in open {
+        result.push(error(Span::at(start), "unmatched ‘[’"));
+    }
+
+    result.sort_by_key(|diagnostic| diagnostic.span.start);
+    result
+}
+
+/// Finds the bracket matching the one at `offset`, if that is a bracket and it is matched.
+pub fn matching_bracket(source: &[u8], offset: usize) -> Option<usize> {
+    let mut depth = 0usize;
+
+    match source.get(offset) {
+        Some(&b'[') => {
+            for (i, &c) in source.iter().enumerate().skip(offset) {
+                match c {
+                    b'[' => depth += 1,
+                    b']' => {
+                        depth -= 1;
+                        if depth == 0 { return Some(i); }
+                    }
+                    _ => (),
+                }
+            }
+            None
+        }
+
+        Some(&b']') => {
+            for i in (0 ..= offset).rev() {
+                match source[i] {
+                    b']' => depth += 1,
+                    b'[' => {
+                        depth -= 1;
+                        if depth == 0 { return Some(i); }
+                    }
+                    _ => (),
+                }
+            }
+            None
+        }
+
+        _ => None,
+    }
+}
+
+fn cancels(a: u8, b: u8) -> bool {
+    matches!((a, b), (b'+', b'-') | (b'-', b'+') | (b'<', b'>') | (b'>', b'<'))
+}
+
+fn error(span: Span, message: &'static str) -> Diagnostic {
+    Diagnostic { span, severity: Severity::Error, message }
+}
+
+fn warning(span: Span, message: &'static str) -> Diagnostic {
+    Diagnostic { span, severity: Severity::Warning, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &[u8]) -> Vec<(usize, usize, &'static str)> {
+        check(source).into_iter()
+            .map(|diagnostic| (diagnostic.span.start, diagnostic.span.end, diagnostic.message))
+            .collect()
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(messages(b"+[ ] ] [+"),
+                   vec![(1, 4, "empty loop never terminates once entered"),
+                        (5, 6, "unmatched ‘]’"),
+                        (7, 8, "unmatched ‘[’")]);
+    }
+
+    #[test]
+    fn dead_and_empty_loops() {
+        assert_eq!(messages(b"[comment.] +[-] [>] +[]"),
+                   vec![(0, 10, "loop never runs"),
+                        (16, 19, "loop never runs"),
+                        (21, 23, "empty loop never terminates once entered")]);
+    }
+
+    #[test]
+    fn cancelling_commands() {
+        assert_eq!(messages(b"++ -. > x <"),
+                   vec![(1, 4, "commands cancel out"), (6, 11, "commands cancel out")]);
+    }
+
+    #[test]
+    fn brackets_match() {
+        let source = b"+[>[-]<]";
+        assert_eq!(matching_bracket(source, 1), Some(7));
+        assert_eq!(matching_bracket(source, 7), Some(1));
+        assert_eq!(matching_bracket(source, 5), Some(3));
+        assert_eq!(matching_bracket(source, 0), None);
+        assert_eq!(matching_bracket(b"[[]", 0), None);
+    }
+}
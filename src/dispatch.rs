@@ -0,0 +1,205 @@
+//! Which pairs of opcodes the bytecode interpreter dispatches one after the other, for
+//! `bfi dispatch`.
+//!
+//! Each instruction the interpreter runs costs a dispatch, a jump through its `match` that is
+//! hard to predict. A superinstruction that does the work of two at once saves one, but pays
+//! off only for pairs that run often in real programs. [`profile`](fn.profile.html) runs a
+//! program’s bytecode counting how often each opcode follows each other, whatever their
+//! operands, and [`DispatchStats::report`](struct.DispatchStats.html#method.report) lists the
+//! most frequent pairs as candidates to fuse.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+
+use bytecode::{self, Fault, StepResult};
+use common::Instruction;
+use state::State;
+
+/// The names of the opcodes, in the order of their numbers in
+/// [`opcode`](fn.opcode.html).
+pub const OPCODES: &[&str] = &["Left", "Right", "Add", "In", "Out", "JumpZero", "JumpNotZero",
+                               "SetZero", "OffsetAddRight", "OffsetAddLeft", "FindZeroRight",
+                               "FindZeroLeft", "DivMod", "IndexRight", "IndexLeft"];
+
+/// The number of an instruction’s opcode, as in the
+/// [`.bfc` format](../bytecode/format/index.html).
+pub fn opcode(instruction: Instruction) -> usize {
+    use common::Instruction::*;
+
+    match instruction {
+        Left(_) => 0,
+        Right(_) => 1,
+        Add(_) => 2,
+        In => 3,
+        Out => 4,
+        JumpZero(_) => 5,
+        JumpNotZero(_) => 6,
+        SetZero => 7,
+        OffsetAddRight(_) => 8,
+        OffsetAddLeft(_) => 9,
+        FindZeroRight(_) => 10,
+        FindZeroLeft(_) => 11,
+        DivMod => 12,
+        IndexRight(_) => 13,
+        IndexLeft(_) => 14,
+    }
+}
+
+/// How often each opcode ran, and how often each followed each other.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DispatchStats {
+    /// How many instructions ran with each opcode.
+    pub counts: Vec<u64>,
+    /// How many times the second opcode ran just after the first, at `first * OPCODES.len() +
+    /// second`.
+    pub pairs: Vec<u64>,
+}
+
+/// A pair of opcodes that often run one after the other.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Candidate {
+    /// The opcode that ran first.
+    pub first: usize,
+    /// The opcode that ran just after it.
+    pub second: usize,
+    /// How many times they did.
+    pub count: u64,
+}
+
+impl DispatchStats {
+    /// Stats with every count zero.
+    pub fn new() -> Self {
+        DispatchStats {
+            counts: vec![0; OPCODES.len()],
+            pairs: vec![0; OPCODES.len() * OPCODES.len()],
+        }
+    }
+
+    /// How many times `second` ran just after `first`.
+    pub fn pair(&self, first: usize, second: usize) -> u64 {
+        self.pairs[first * OPCODES.len() + second]
+    }
+
+    /// How many instructions ran in all.
+    pub fn dispatches(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The `top` pairs that ran most often, most often first, leaving out pairs that never ran.
+    pub fn candidates(&self, top: usize) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = self.pairs.iter().enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(index, &count)| Candidate {
+                first: index / OPCODES.len(),
+                second: index % OPCODES.len(),
+                count,
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.count.cmp(&a.count)
+            .then((a.first, a.second).cmp(&(b.first, b.second))));
+        candidates.truncate(top);
+        candidates
+    }
+
+    /// Writes out the number of dispatches and the `top` candidate pairs, each with its share
+    /// of the dispatches it would save if fused.
+    pub fn report(&self, top: usize) -> String {
+        let mut result = String::new();
+        let dispatches = self.dispatches();
+        let _ = writeln!(result, "dispatches: {}", dispatches);
+
+        let candidates = self.candidates(top);
+        if !candidates.is_empty() {
+            let _ = writeln!(result, "most frequent opcode pairs, as superinstruction candidates:");
+        }
+        for candidate in candidates {
+            let share = 100.0 * candidate.count as f64 / dispatches.max(1) as f64;
+            let name = format!("{} {}", OPCODES[candidate.first], OPCODES[candidate.second]);
+            let _ = writeln!(result, "  {:<28} {:>12} ({:.1}%)", name, candidate.count, share);
+        }
+
+        result
+    }
+}
+
+impl Default for DispatchStats {
+    fn default() -> Self {
+        DispatchStats::new()
+    }
+}
+
+/// Interprets a bytecode program like
+/// [`interpret_locating`](../bytecode/fn.interpret_locating.html), counting the opcodes it
+/// dispatches and the pairs they come in.
+pub fn profile<R, W>(program: &bytecode::Program, state: &mut State, mut input: R, mut output: W)
+                     -> Result<DispatchStats, Fault>
+    where R: Read, W: Write
+{
+    let mut stats = DispatchStats::new();
+    let mut previous = None;
+    let mut pc = 0;
+
+    while let Some(&instruction) = program.get(pc) {
+        let at = pc;
+        let current = opcode(instruction);
+        stats.counts[current] += 1;
+        if let Some(previous) = previous {
+            stats.pairs[previous * OPCODES.len() + current] += 1;
+        }
+        previous = Some(current);
+
+        let mut byte = None;
+        if instruction == Instruction::In {
+            let mut buffer = [0];
+            let _ = input.read_exact(&mut buffer);
+            byte = Some(buffer[0]);
+        }
+
+        let result = bytecode::execute(instruction, state, &mut pc, &mut byte)
+            .map_err(|error| Fault { error, pc: at })?;
+        if let StepResult::Output(byte) = result {
+            let _ = output.write_all(&[byte]);
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn run(source: &[u8], input: &[u8]) -> (DispatchStats, Vec<u8>) {
+        let program = ::ast::parse_program(source).unwrap().bytecode_compile();
+        let mut output = Vec::new();
+        let stats = profile(&program, &mut State::new(), input, &mut output).unwrap();
+        (stats, output)
+    }
+
+    #[test]
+    fn pairs_are_counted_across_jumps() {
+        // Add(3), JumpZero, Right, Add(2), Left, Add(255), JumpNotZero, Right, Out.
+        let (stats, output) = run(b"+++[>++<-]>.", b"");
+        assert_eq!(output, [6]);
+        assert_eq!(stats.dispatches(), 19);
+        let (add, right, left, jnz) = (2, 1, 0, 6);
+        assert_eq!(stats.pair(right, add), 3);
+        assert_eq!(stats.pair(left, add), 3);
+        assert_eq!(stats.pair(jnz, right), 3);
+        assert_eq!(stats.pair(add, jnz), 3);
+        assert_eq!(stats.pairs.iter().sum::<u64>(), 18);
+        assert_eq!(stats.candidates(1), [Candidate { first: left, second: add, count: 3 }]);
+    }
+
+    #[test]
+    fn reports_list_the_most_frequent_pairs() {
+        let (stats, output) = run(FACTOR_SRC, b"12\n");
+        assert_eq!(output, b"12: 2 2 3\n");
+        let text = stats.report(3);
+        assert!(text.starts_with(&format!("dispatches: {}\n", stats.dispatches())));
+        assert_eq!(text.lines().filter(|line| line.starts_with("  ")).count(), 3);
+        assert_eq!(run(b"", b"").0.report(3), "dispatches: 0\n");
+    }
+}
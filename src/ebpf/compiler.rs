@@ -0,0 +1,444 @@
+use std::cmp;
+
+use super::*;
+use common::Count;
+use peephole;
+use traits::IntoUsize;
+
+/// Program forms that can be compiled to eBPF.
+pub trait EbpfCompilable {
+    /// Compile the given program into the peephole AST to prepare for code generation.
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R;
+
+    /// Compile the given program to eBPF with the given limits.
+    fn ebpf_compile(&self, limits: &Limits) -> Box<Program> {
+        self.with_peephole(|program| compile(program, limits))
+    }
+}
+
+// Opcodes, from linux/bpf.h.
+const ADD_K: u8  = 0x07;
+const ADD_X: u8  = 0x0f;
+const SUB_K: u8  = 0x17;
+const MOV_K: u8  = 0xb7;
+const MOV_X: u8  = 0xbf;
+const MOV32_K: u8 = 0xb4;
+const LDX_B: u8  = 0x71;
+const LDX_W: u8  = 0x61;
+const STX_B: u8  = 0x73;
+const STX_W: u8  = 0x63;
+const ST_B: u8   = 0x72;
+const JA: u8     = 0x05;
+const JEQ_K: u8  = 0x15;
+const JGE_K: u8  = 0x35;
+const JGE_X: u8  = 0x3d;
+const JLT_K: u8  = 0xa5;
+const EXIT: u8   = 0x95;
+
+// Register assignments. `r1`–`r5` are scratch.
+const CONTEXT: u8 = 6;
+const POINTER: u8 = 7;
+const FUEL: u8    = 8;
+
+/// Compiles peephole-optimized AST to eBPF.
+///
+/// # Panics
+///
+/// Panics if the context region described by `limits`, or a jump over a loop body, is too large
+/// for eBPF’s 16-bit offsets.
+pub fn compile(program: &peephole::Program, limits: &Limits) -> Box<Program> {
+    assert!(limits.context_size() <= i16::MAX as usize,
+            "eBPF context region too large: {} bytes", limits.context_size());
+
+    let mut compiler = Compiler::new(limits);
+    compiler.compile(program);
+    compiler.into_program()
+}
+
+/// A jump target, resolved once all the code has been emitted.
+#[derive(Clone, Copy, Debug)]
+struct Label(usize);
+
+/// The compiler state.
+struct Compiler {
+    /// The code so far.
+    code: Vec<Insn>,
+    /// The address of each label, once placed.
+    labels: Vec<Option<usize>>,
+    /// Jumps to patch, as the instruction address and the target label.
+    fixups: Vec<(usize, Label)>,
+    limits: Limits,
+    underflow: Label,
+    overflow: Label,
+    out_of_fuel: Label,
+    output_full: Label,
+}
+
+impl Compiler {
+    fn new(limits: &Limits) -> Self {
+        let mut result = Compiler {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            limits: *limits,
+            underflow: Label(0),
+            overflow: Label(0),
+            out_of_fuel: Label(0),
+            output_full: Label(0),
+        };
+
+        result.underflow = result.new_label();
+        result.overflow = result.new_label();
+        result.out_of_fuel = result.new_label();
+        result.output_full = result.new_label();
+
+        result.emit(MOV_X, CONTEXT, 1, 0, 0);
+        result.emit(MOV_K, POINTER, 0, 0, 0);
+        result.emit(MOV32_K, FUEL, 0, 0, limits.fuel as i32);
+
+        result
+    }
+
+    fn into_program(mut self) -> Box<Program> {
+        self.exit_with(OKAY);
+
+        for &(label, code) in &[(self.underflow, UNDERFLOW), (self.overflow, OVERFLOW),
+                                (self.out_of_fuel, OUT_OF_FUEL),
+                                (self.output_full, OUTPUT_FULL)] {
+            self.place(label);
+            self.exit_with(code);
+        }
+
+        for &(address, Label(label)) in &self.fixups {
+            let target = self.labels[label].expect("unplaced label");
+            let distance = target as isize - address as isize - 1;
+            assert!(distance as i16 as isize == distance, "jump too long for eBPF");
+            self.code[address].off = distance as i16;
+        }
+
+        self.code.into_boxed_slice()
+    }
+
+    fn compile(&mut self, program: &[peephole::Statement]) {
+        for stm in program {
+            self.compile_statement(stm);
+        }
+    }
+
+    fn compile_statement(&mut self, stm: &peephole::Statement) {
+        use peephole::Statement::*;
+        use common::Instruction::*;
+
+        match *stm {
+            Instr(Right(count)) => {
+                self.offset_right(2, count);
+                self.emit(MOV_X, POINTER, 2, 0, 0);
+            }
+
+            Instr(Left(count)) => {
+                self.offset_left(2, count);
+                self.emit(MOV_X, POINTER, 2, 0, 0);
+            }
+
+            Instr(Add(amount)) => {
+                self.load_current(3);
+                self.emit(ADD_K, 3, 0, 0, amount as i32);
+                self.emit(STX_B, 2, 3, tape(), 0);
+            }
+
+            Instr(In) => {
+                let eof = self.new_label();
+                let done = self.new_label();
+
+                self.emit(LDX_W, 3, CONTEXT, INPUT_POS, 0);
+                self.emit(LDX_W, 4, CONTEXT, INPUT_LEN, 0);
+                self.jump(JGE_X, 3, 4, 0, eof);
+                self.emit(MOV_X, 4, CONTEXT, 0, 0);
+                self.emit(ADD_X, 4, 3, 0, 0);
+                self.emit(LDX_B, 5, 4, self.limits.input_offset() as i16, 0);
+                self.emit(ADD_K, 3, 0, 0, 1);
+                self.emit(STX_W, CONTEXT, 3, INPUT_POS, 0);
+                self.address_current();
+                self.emit(STX_B, 2, 5, tape(), 0);
+                self.jump(JA, 0, 0, 0, done);
+                self.place(eof);
+                self.address_current();
+                self.emit(ST_B, 2, 0, tape(), 0);
+                self.place(done);
+            }
+
+            Instr(Out) => {
+                let output_full = self.output_full;
+
+                self.emit(LDX_W, 3, CONTEXT, OUTPUT_LEN, 0);
+                self.jump(JGE_K, 3, 0, self.limits.output_size as i32, output_full);
+                self.load_current(5);
+                self.emit(MOV_X, 4, CONTEXT, 0, 0);
+                self.emit(ADD_X, 4, 3, 0, 0);
+                self.emit(STX_B, 4, 5, self.limits.output_offset() as i16, 0);
+                self.emit(ADD_K, 3, 0, 0, 1);
+                self.emit(STX_W, CONTEXT, 3, OUTPUT_LEN, 0);
+            }
+
+            Instr(SetZero) => {
+                self.address_current();
+                self.emit(ST_B, 2, 0, tape(), 0);
+            }
+
+            Instr(OffsetAddRight(offset)) => {
+                let skip = self.new_label();
+                self.load_current(3);
+                self.jump(JEQ_K, 3, 0, 0, skip);
+                self.offset_right(4, offset);
+                self.offset_add();
+                self.place(skip);
+            }
+
+            Instr(OffsetAddLeft(offset)) => {
+                let skip = self.new_label();
+                self.load_current(3);
+                self.jump(JEQ_K, 3, 0, 0, skip);
+                self.offset_left(4, offset);
+                self.offset_add();
+                self.place(skip);
+            }
+
+            Instr(FindZeroRight(skip)) => {
+                self.find_zero(|compiler| {
+                    compiler.offset_right(2, skip);
+                    compiler.emit(MOV_X, POINTER, 2, 0, 0);
+                });
+            }
+
+            Instr(FindZeroLeft(skip)) => {
+                self.find_zero(|compiler| {
+                    compiler.offset_left(2, skip);
+                    compiler.emit(MOV_X, POINTER, 2, 0, 0);
+                });
+            }
+
+            // The loop that follows does the work.
+            Instr(DivMod) | Instr(IndexRight(_)) | Instr(IndexLeft(_)) => (),
+
+            Instr(JumpZero(_)) | Instr(JumpNotZero(_)) =>
+                panic!("unexpected jump instruction"),
+
+            Loop(ref body) => self.find_zero(|compiler| compiler.compile(body)),
+        }
+    }
+
+    /// Emits a loop that runs `body` while the current cell is non-zero, spending one unit of
+    /// fuel per iteration.
+    fn find_zero<F: FnOnce(&mut Self)>(&mut self, body: F) {
+        let begin = self.new_label();
+        let end = self.new_label();
+        let out_of_fuel = self.out_of_fuel;
+
+        self.place(begin);
+        self.load_current(3);
+        self.jump(JEQ_K, 3, 0, 0, end);
+        body(self);
+        self.jump(JEQ_K, FUEL, 0, 0, out_of_fuel);
+        self.emit(SUB_K, FUEL, 0, 0, 1);
+        self.jump(JA, 0, 0, 0, begin);
+        self.place(end);
+    }
+
+    /// Adds the current cell’s value, in `r3`, to the cell whose index is in `r4`, and zeroes
+    /// the current cell, whose address is in `r2`.
+    fn offset_add(&mut self) {
+        self.emit(ST_B, 2, 0, tape(), 0);
+        self.emit(ADD_X, 4, CONTEXT, 0, 0);
+        self.emit(LDX_B, 5, 4, tape(), 0);
+        self.emit(ADD_X, 5, 3, 0, 0);
+        self.emit(STX_B, 4, 5, tape(), 0);
+    }
+
+    /// Sets `reg` to the pointer plus `count`, or exits if that is past the end of the tape.
+    fn offset_right(&mut self, reg: u8, count: Count) {
+        let overflow = self.overflow;
+        let count = self.clamp(count);
+        self.emit(MOV_X, reg, POINTER, 0, 0);
+        self.emit(ADD_K, reg, 0, 0, count);
+        self.jump(JGE_K, reg, 0, self.limits.memory_size as i32, overflow);
+    }
+
+    /// Sets `reg` to the pointer minus `count`, or exits if that is below the tape.
+    fn offset_left(&mut self, reg: u8, count: Count) {
+        let underflow = self.underflow;
+        let count = self.clamp(count);
+        self.jump(JLT_K, POINTER, 0, count, underflow);
+        self.emit(MOV_X, reg, POINTER, 0, 0);
+        self.emit(SUB_K, reg, 0, 0, count);
+    }
+
+    /// Moves of at least the tape size always fail, so they need not be exact.
+    fn clamp(&self, count: Count) -> i32 {
+        cmp::min(count.into_usize(), self.limits.memory_size) as i32
+    }
+
+    /// Loads the current cell into `reg`, leaving its address (less `TAPE`) in `r2`.
+    fn load_current(&mut self, reg: u8) {
+        self.address_current();
+        self.emit(LDX_B, reg, 2, tape(), 0);
+    }
+
+    /// Puts the current cell’s address (less `TAPE`) in `r2`.
+    fn address_current(&mut self) {
+        self.emit(MOV_X, 2, CONTEXT, 0, 0);
+        self.emit(ADD_X, 2, POINTER, 0, 0);
+    }
+
+    fn exit_with(&mut self, code: u64) {
+        self.emit(MOV_K, 0, 0, 0, code as i32);
+        self.emit(EXIT, 0, 0, 0, 0);
+    }
+
+    fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.code.push(Insn { code, dst, src, off, imm });
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, src: u8, imm: i32, target: Label) {
+        self.fixups.push((self.code.len(), target));
+        self.emit(code, dst, src, 0, imm);
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn place(&mut self, Label(label): Label) {
+        self.labels[label] = Some(self.code.len());
+    }
+}
+
+fn tape() -> i16 {
+    TAPE as i16
+}
+
+impl EbpfCompilable for peephole::Program {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(self)
+    }
+}
+
+impl<T: peephole::PeepholeCompilable + ?Sized> EbpfCompilable for T {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(&self.peephole_compile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    /// Runs a program on a minimal eBPF interpreter covering the opcodes we emit, returning the
+    /// result code and the output.
+    fn run(program: &Program, limits: &Limits, input: &[u8]) -> (u64, Vec<u8>) {
+        let mut context = vec![0u8; limits.context_size()];
+        context[limits.input_offset() .. limits.input_offset() + input.len()]
+            .copy_from_slice(input);
+        context[4 .. 8].copy_from_slice(&(input.len() as u32).to_le_bytes());
+
+        // Registers hold offsets into `context` where the real machine would hold addresses.
+        let mut regs = [0u64; 11];
+        let mut pc = 0;
+
+        loop {
+            let Insn { code, dst, src, off, imm } = program[pc];
+            let (dst, src) = (dst as usize, src as usize);
+            let address = |base: u64| (base as i64 + off as i64) as usize;
+            pc += 1;
+
+            match code {
+                ADD_K => regs[dst] = regs[dst].wrapping_add(imm as i64 as u64),
+                ADD_X => regs[dst] = regs[dst].wrapping_add(regs[src]),
+                SUB_K => regs[dst] = regs[dst].wrapping_sub(imm as i64 as u64),
+                MOV_K => regs[dst] = imm as i64 as u64,
+                MOV_X => regs[dst] = regs[src],
+                MOV32_K => regs[dst] = imm as u32 as u64,
+                LDX_B => regs[dst] = context[address(regs[src])] as u64,
+                LDX_W => {
+                    let at = address(regs[src]);
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&context[at .. at + 4]);
+                    regs[dst] = u32::from_le_bytes(bytes) as u64;
+                }
+                STX_B => context[address(regs[dst])] = regs[src] as u8,
+                STX_W => {
+                    let at = address(regs[dst]);
+                    context[at .. at + 4].copy_from_slice(&(regs[src] as u32).to_le_bytes());
+                }
+                ST_B => context[address(regs[dst])] = imm as u8,
+                EXIT => break,
+                _ => {
+                    let taken = match code {
+                        JA => true,
+                        JEQ_K => regs[dst] == imm as i64 as u64,
+                        JGE_K => regs[dst] >= imm as i64 as u64,
+                        JGE_X => regs[dst] >= regs[src],
+                        JLT_K => regs[dst] < imm as i64 as u64,
+                        _ => panic!("unknown opcode {:#x}", code),
+                    };
+                    if taken {
+                        pc = (pc as isize + off as isize) as usize;
+                    }
+                }
+            }
+        }
+
+        let mut length = [0; 4];
+        length.copy_from_slice(&context[8 .. 12]);
+        let length = u32::from_le_bytes(length) as usize;
+        let output = context[limits.output_offset() ..][.. length].to_vec();
+
+        (regs[0], output)
+    }
+
+    fn compile_and_run(source: &[u8], limits: &Limits, input: &[u8]) -> (u64, Vec<u8>) {
+        let program = ::ast::parse_program(source).unwrap().ebpf_compile(limits);
+        run(&program, limits, input)
+    }
+
+    #[test]
+    fn hello_world() {
+        assert_eq!(compile_and_run(HELLO_WORLD_SRC, &Limits::default(), b""),
+                   (OKAY, b"Hello, World!".to_vec()));
+    }
+
+    #[test]
+    fn factor() {
+        let limits = Limits { fuel: 100_000_000, ..Limits::default() };
+        assert_eq!(compile_and_run(FACTOR_SRC, &limits, b"2000\n"),
+                   (OKAY, b"2000: 2 2 2 2 5 5 5\n".to_vec()));
+    }
+
+    #[test]
+    fn input_past_end_reads_zero() {
+        assert_eq!(compile_and_run(b",+.,.", &Limits::default(), b"A"),
+                   (OKAY, b"B\0".to_vec()));
+    }
+
+    #[test]
+    fn errors() {
+        let limits = Limits { memory_size: 4, output_size: 2, fuel: 10, ..Limits::default() };
+        assert_eq!(compile_and_run(b"<", &limits, b"").0, UNDERFLOW);
+        assert_eq!(compile_and_run(b">>>>", &limits, b"").0, OVERFLOW);
+        assert_eq!(compile_and_run(b"+[>+]", &limits, b"").0, OVERFLOW);
+        assert_eq!(compile_and_run(b"+[]", &limits, b"").0, OUT_OF_FUEL);
+        assert_eq!(compile_and_run(b"...", &limits, b""), (OUTPUT_FULL, vec![0, 0]));
+    }
+
+    #[test]
+    fn encoding() {
+        let insn = Insn { code: STX_B, dst: 2, src: 5, off: 16, imm: -1 };
+        assert_eq!(insn.to_bytes(), [0x73, 0x52, 16, 0, 0xff, 0xff, 0xff, 0xff]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Experimental backend lowering Brainfuck to eBPF bytecode.
+//!
+//! eBPF programs must terminate and cannot make system calls, so the generated program runs on
+//! a fixed-size tape with buffered I/O, and every loop iteration spends one unit of fuel. All of
+//! it lives in a single context region, passed in `r1`, whose layout is given by
+//! [`Limits`](struct.Limits.html):
+//!
+//! | Offset                  | Size           | Contents                                  |
+//! |-------------------------|----------------|-------------------------------------------|
+//! | 0                       | 4              | input position, advanced by `,`           |
+//! | 4                       | 4              | input length, set by the loader           |
+//! | 8                       | 4              | output length, advanced by `.`            |
+//! | 12                      | 4              | reserved                                  |
+//! | `TAPE`                  | `memory_size`  | the tape, zeroed by the loader            |
+//! | `limits.input_offset()` | `input_size`   | the input                                 |
+//! | `limits.output_offset()`| `output_size`  | the output                                |
+//!
+//! The program returns one of the result codes below in `r0`. Reading past the end of the input
+//! stores 0, as the other backends do. The tape is always bounds checked.
+//!
+//! The kernel will not load these programs: its verifier rejects the tape accesses, which add a
+//! register to the context pointer. They run in user-space eBPF machines that give a program a
+//! plain memory region in `r1`, such as [rbpf](https://crates.io/crates/rbpf)’s, and in the
+//! small interpreter in this module’s tests.
+
+mod compiler;
+
+pub use self::compiler::{compile, EbpfCompilable};
+
+use rts;
+
+/// The program terminated successfully.
+pub const OKAY: u64 = rts::OKAY;
+
+/// The pointer would have moved below the tape.
+pub const UNDERFLOW: u64 = rts::UNDERFLOW;
+
+/// The pointer would have moved past the end of the tape.
+pub const OVERFLOW: u64 = rts::OVERFLOW;
+
+/// The program ran out of fuel before finishing.
+pub const OUT_OF_FUEL: u64 = 4;
+
+/// The program tried to write more output than fits in the output buffer.
+pub const OUTPUT_FULL: u64 = 5;
+
+/// The offset of the input position in the context region.
+pub const INPUT_POS: i16 = 0;
+
+/// The offset of the input length in the context region.
+pub const INPUT_LEN: i16 = 4;
+
+/// The offset of the output length in the context region.
+pub const OUTPUT_LEN: i16 = 8;
+
+/// The offset of the tape in the context region.
+pub const TAPE: usize = 16;
+
+/// Sizes of the tape and I/O buffers, and the fuel budget.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// The number of tape cells.
+    ///
+    /// Defaults to `256`.
+    pub memory_size: usize,
+    /// The capacity of the input buffer.
+    ///
+    /// Defaults to `256`.
+    pub input_size: usize,
+    /// The capacity of the output buffer.
+    ///
+    /// Defaults to `256`.
+    pub output_size: usize,
+    /// The number of loop iterations the program may run.
+    ///
+    /// Defaults to `1_000_000`.
+    pub fuel: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            memory_size: 256,
+            input_size:  256,
+            output_size: 256,
+            fuel:        1_000_000,
+        }
+    }
+}
+
+impl Limits {
+    /// The offset of the input buffer in the context region.
+    pub fn input_offset(&self) -> usize {
+        TAPE + self.memory_size
+    }
+
+    /// The offset of the output buffer in the context region.
+    pub fn output_offset(&self) -> usize {
+        self.input_offset() + self.input_size
+    }
+
+    /// The total size of the context region.
+    pub fn context_size(&self) -> usize {
+        self.output_offset() + self.output_size
+    }
+}
+
+/// A single eBPF instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Insn {
+    /// The opcode.
+    pub code: u8,
+    /// The destination register.
+    pub dst: u8,
+    /// The source register.
+    pub src: u8,
+    /// The offset, for memory accesses and jumps.
+    pub off: i16,
+    /// The immediate operand.
+    pub imm: i32,
+}
+
+impl Insn {
+    /// Encodes the instruction in the kernel’s (little-endian) format.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let off = self.off.to_le_bytes();
+        let imm = self.imm.to_le_bytes();
+        [self.code, self.src << 4 | self.dst, off[0], off[1], imm[0], imm[1], imm[2], imm[3]]
+    }
+}
+
+/// An eBPF program.
+pub type Program = [Insn];
+
+/// Encodes a program in the kernel’s instruction format.
+pub fn to_bytes(program: &Program) -> Vec<u8> {
+    program.iter().flat_map(|insn| insn.to_bytes().to_vec()).collect()
+}
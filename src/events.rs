@@ -0,0 +1,526 @@
+//! A program running on a worker thread, reporting what it does over a channel.
+//!
+//! A GUI or TUI visualizer can’t block its event loop on an interpreter, so a
+//! [`Worker`](struct.Worker.html) runs a [bytecode](../bytecode/index.html) program on a thread
+//! of its own and publishes each [`ExecEvent`](enum.ExecEvent.html) over an `mpsc` channel: the
+//! bytes it writes, a milestone every so many steps, a copy of the machine state when asked
+//! for one, and how the run ended. [`Command`](enum.Command.html)s sent back pause and resume
+//! the run, single-step it, set [breakpoints](../breakpoint/index.html), or stop it.
+//!
+//! With `Options::history` set, the worker keeps a [history](../history/index.html) of the
+//! last instructions it ran, and when one fails, reports them in an `ExecEvent::History` just
+//! before the run ends, so that a debugger can show what led up to the error.
+//!
+//! Breakpoints are checked before each instruction the run goes on to freely, so the one a
+//! run paused at does not stop it again on resuming, and single steps pass over them. To have
+//! one hit from the very start, start the worker paused and send it before resuming.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use bf::events::{Command, End, ExecEvent, Options, Worker};
+//! use bf::state::State;
+//! use bf::traits::BytecodeCompilable;
+//!
+//! let program = bf::ast::parse_program(b"++++++++[>++++++<-]>.").unwrap().bytecode_compile();
+//! let options = Options { start_paused: true, ..Options::default() };
+//! let worker = Worker::spawn(Arc::from(program), State::new(), &b""[..], &options);
+//! worker.send(Command::Step);
+//! worker.send(Command::Resume);
+//!
+//! let events: Vec<_> = worker.events().iter().collect();
+//! assert_eq!(events[1], ExecEvent::Paused { pc: 1, steps: 1 });
+//! assert!(events.contains(&ExecEvent::Output(b'0')));
+//! assert!(matches!(events.last(), Some(ExecEvent::Ended { end: End::Halted, .. })));
+//! ```
+
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use breakpoint::Breakpoint;
+use bytecode::{self, StepResult};
+use history::{Executed, History};
+use script::Action;
+use common::Error;
+use state::State;
+
+/// How a worker runs its program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Options {
+    /// How many steps apart to report milestones, or 0 for none.
+    ///
+    /// Defaults to 1,000,000.
+    pub milestone: u64,
+    /// Whether to wait for a `Resume` or `Step` before running the first instruction.
+    ///
+    /// Defaults to `false`.
+    pub start_paused: bool,
+    /// How many of the last instructions run to keep, to report if one fails, or 0 for none.
+    ///
+    /// Defaults to 0.
+    pub history: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { milestone: 1_000_000, start_paused: false, history: 0 }
+    }
+}
+
+/// What a worker reports.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecEvent {
+    /// The program wrote a byte.
+    Output(u8),
+    /// The program has run another `milestone` steps.
+    Milestone {
+        /// The address of the next instruction.
+        pc: usize,
+        /// The instructions run so far.
+        steps: u64,
+    },
+    /// The run is paused, by a `Pause` or after a `Step`.
+    Paused {
+        /// The address of the next instruction.
+        pc: usize,
+        /// The instructions run so far.
+        steps: u64,
+    },
+    /// The run reached a breakpoint whose condition held, and is paused before its
+    /// instruction, unless the breakpoint’s script resumed or stopped it.
+    Breakpoint {
+        /// The breakpoint’s index, in the order they were set.
+        index: usize,
+        /// The address of the next instruction.
+        pc: usize,
+        /// The instructions run so far.
+        steps: u64,
+    },
+    /// A line printed by a breakpoint’s script, or the error it failed with.
+    ScriptOutput(String),
+    /// The machine state, as a `Snapshot` command asked for.
+    Snapshot {
+        /// The address of the next instruction.
+        pc: usize,
+        /// The instructions run so far.
+        steps: u64,
+        /// A copy of the tape and pointer.
+        state: State,
+    },
+    /// The last instructions started, oldest first, ending with the one that failed, as kept
+    /// with `Options::history`. Sent just before a failed run ends.
+    History(Vec<Executed>),
+    /// The run is over; no more events follow.
+    Ended {
+        /// The address of the next instruction, or of the one that failed.
+        pc: usize,
+        /// The instructions run in all.
+        steps: u64,
+        /// Why it ended.
+        end: End,
+    },
+}
+
+/// Why a run ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum End {
+    /// The program finished.
+    Halted,
+    /// An instruction failed.
+    Failed(Error),
+    /// A `Stop` command stopped it.
+    Stopped,
+}
+
+/// What a worker can be told to do.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Stop running instructions until told to resume or step.
+    Pause,
+    /// Run freely again.
+    Resume,
+    /// Run one instruction, then pause.
+    Step,
+    /// Report the machine state.
+    Snapshot,
+    /// Add a breakpoint.
+    Break(Breakpoint),
+    /// Remove all the breakpoints.
+    ClearBreakpoints,
+    /// End the run.
+    Stop,
+}
+
+/// A program running on its own thread.
+#[derive(Debug)]
+pub struct Worker {
+    commands: Sender<Command>,
+    events:   Receiver<ExecEvent>,
+    thread:   JoinHandle<State>,
+}
+
+impl Worker {
+    /// Starts running the program against the given state on a new thread, reading its input
+    /// from `input` there.
+    pub fn spawn<R>(program: Arc<bytecode::Program>, state: State, input: R, options: &Options)
+                    -> Self
+        where R: Read + Send + 'static
+    {
+        let (commands, command_receiver) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let mut run = Run {
+            program,
+            state,
+            input,
+            pc: 0,
+            steps: 0,
+            milestone: options.milestone,
+            breakpoints: Vec::new(),
+            held: false,
+            history: History::new(options.history),
+            commands: command_receiver,
+            events: event_sender,
+        };
+        let paused = options.start_paused;
+        let thread = thread::spawn(move || {
+            run.run(paused);
+            run.state
+        });
+
+        Worker { commands, events, thread }
+    }
+
+    /// Sends a command, which is ignored once the run is over.
+    pub fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    /// The events reported so far and to come; iterating over them ends with the run.
+    pub fn events(&self) -> &Receiver<ExecEvent> {
+        &self.events
+    }
+
+    /// Waits for the run to end, returning the final machine state. A paused run ends at once,
+    /// since no more commands can reach it.
+    pub fn join(self) -> State {
+        drop(self.commands);
+        self.thread.join().expect("the worker thread does not panic")
+    }
+}
+
+/// The worker thread’s side.
+struct Run<R> {
+    program:   Arc<bytecode::Program>,
+    state:     State,
+    input:     R,
+    pc:        usize,
+    steps:     u64,
+    milestone: u64,
+    breakpoints: Vec<Breakpoint>,
+    /// Whether the next instruction is the one the run paused at, which its breakpoints
+    /// don’t stop again.
+    held:      bool,
+    history:   History,
+    commands:  Receiver<Command>,
+    events:    Sender<ExecEvent>,
+}
+
+impl<R: Read> Run<R> {
+    /// Runs until the program ends, it is stopped, or no one is left to listen.
+    fn run(&mut self, mut paused: bool) {
+        if paused && !self.paused() {
+            return;
+        }
+        self.held = paused;
+
+        loop {
+            let command = if paused {
+                match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            } else {
+                self.commands.try_recv().ok()
+            };
+
+            let mut step = !paused;
+            match command {
+                Some(Command::Pause) => {
+                    paused = true;
+                    step = false;
+                    self.held = true;
+                    if !self.paused() { return; }
+                }
+                Some(Command::Resume) => {
+                    paused = false;
+                    step = true;
+                }
+                Some(Command::Step) => step = true,
+                Some(Command::Snapshot) => {
+                    let snapshot = ExecEvent::Snapshot {
+                        pc: self.pc,
+                        steps: self.steps,
+                        state: self.state.clone(),
+                    };
+                    if !self.send(snapshot) { return; }
+                }
+                Some(Command::Break(breakpoint)) => self.breakpoints.push(breakpoint),
+                Some(Command::ClearBreakpoints) => self.breakpoints.clear(),
+                Some(Command::Stop) => {
+                    self.end(End::Stopped);
+                    return;
+                }
+                None => (),
+            }
+
+            if step && !paused && !self.held {
+                if let Some(index) = self.breakpoint() {
+                    let event = ExecEvent::Breakpoint { index, pc: self.pc, steps: self.steps };
+                    if !self.send(event) { return; }
+                    match self.script(index) {
+                        Some(Action::Resume) => (),
+                        Some(Action::Stop) => {
+                            self.end(End::Stopped);
+                            return;
+                        }
+                        Some(Action::Pause) => {
+                            paused = true;
+                            step = false;
+                            self.held = true;
+                        }
+                        None => return,
+                    }
+                }
+            }
+
+            if step {
+                self.held = paused;
+                if !self.step() { return; }
+                if paused && !self.paused() { return; }
+            }
+        }
+    }
+
+    /// Runs one instruction, reporting its output, returning whether the run goes on.
+    fn step(&mut self) -> bool {
+        if let Some(&instruction) = self.program.get(self.pc) {
+            self.history.record(self.pc, instruction);
+        }
+
+        let mut input = None;
+        let result = loop {
+            match bytecode::step(&self.program, &mut self.state, &mut self.pc, &mut input) {
+                Ok(StepResult::NeedsInput) => input = Some(self.read()),
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(StepResult::Halted) => {
+                self.end(End::Halted);
+                return false;
+            }
+            Err(error) => {
+                if self.history.capacity() > 0 {
+                    let history = ExecEvent::History(self.history.executed());
+                    if !self.send(history) { return false; }
+                }
+                self.end(End::Failed(error));
+                return false;
+            }
+            Ok(StepResult::Output(byte)) => {
+                if !self.send(ExecEvent::Output(byte)) { return false; }
+            }
+            Ok(_) => (),
+        }
+
+        self.steps += 1;
+        if self.milestone > 0 && self.steps.is_multiple_of(self.milestone) {
+            let milestone = ExecEvent::Milestone { pc: self.pc, steps: self.steps };
+            return self.send(milestone);
+        }
+        true
+    }
+
+    /// Counts a hit on each breakpoint at the next instruction, returning the first whose
+    /// condition holds.
+    fn breakpoint(&mut self) -> Option<usize> {
+        let mut stop = None;
+        for (index, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.pc == self.pc && breakpoint.hit(&self.state) && stop.is_none() {
+                stop = Some(index);
+            }
+        }
+        stop
+    }
+
+    /// Runs the breakpoint’s script, if it has one, reporting what it prints. Returns what the
+    /// run does next, or `None` if no one is left to listen.
+    fn script(&mut self, index: usize) -> Option<Action> {
+        let breakpoint = &self.breakpoints[index];
+        let script = match breakpoint.script {
+            Some(ref script) => script,
+            None => return Some(Action::Pause),
+        };
+
+        let mut output = Vec::new();
+        let action = script.run(&mut self.state, breakpoint.hits(), &mut output)
+            .unwrap_or_else(|error| {
+                output.push(format!("error: {}", error));
+                Action::Pause
+            });
+        for line in output {
+            if !self.send(ExecEvent::ScriptOutput(line)) { return None; }
+        }
+        Some(action)
+    }
+
+    /// Reads a byte of input, with end of input or an error reading as 0 like in the
+    /// interpreters.
+    fn read(&mut self) -> u8 {
+        let mut byte = [0];
+        let _ = self.input.read_exact(&mut byte);
+        byte[0]
+    }
+
+    fn paused(&self) -> bool {
+        self.send(ExecEvent::Paused { pc: self.pc, steps: self.steps })
+    }
+
+    fn end(&self, end: End) {
+        self.send(ExecEvent::Ended { pc: self.pc, steps: self.steps, end });
+    }
+
+    /// Reports an event, returning whether anyone is still listening.
+    fn send(&self, event: ExecEvent) -> bool {
+        self.events.send(event).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn spawn(source: &[u8], input: &'static [u8], options: &Options) -> Worker {
+        let program = ::ast::parse_program(source).unwrap().bytecode_compile();
+        Worker::spawn(Arc::from(program), State::new(), input, options)
+    }
+
+    #[test]
+    fn runs_report_output_and_how_they_ended() {
+        let worker = spawn(FACTOR_SRC, b"12\n", &Options { milestone: 0, ..Options::default() });
+        let mut output = Vec::new();
+        let mut last = None;
+        for event in worker.events().iter() {
+            match event {
+                ExecEvent::Output(byte) => output.push(byte),
+                event => last = Some(event),
+            }
+        }
+        assert_eq!(output, b"12: 2 2 3\n");
+        assert!(matches!(last, Some(ExecEvent::Ended { end: End::Halted, .. })));
+        worker.join();
+
+        let worker = spawn(b"<", b"", &Options::default());
+        let end = End::Failed(Error::PointerUnderflow);
+        assert_eq!(worker.events().iter().collect::<Vec<_>>(),
+                   [ExecEvent::Ended { pc: 0, steps: 0, end }]);
+    }
+
+    #[test]
+    fn paused_runs_step_and_show_their_state() {
+        let worker = spawn(b"+>++.", b"", &Options { start_paused: true, ..Options::default() });
+        worker.send(Command::Step);
+        worker.send(Command::Step);
+        worker.send(Command::Snapshot);
+        worker.send(Command::Resume);
+
+        let mut state = State::new();
+        state.up(1);
+        state.right(1usize).unwrap();
+        assert_eq!(worker.events().iter().collect::<Vec<_>>(), [
+            ExecEvent::Paused { pc: 0, steps: 0 },
+            ExecEvent::Paused { pc: 1, steps: 1 },
+            ExecEvent::Paused { pc: 2, steps: 2 },
+            ExecEvent::Snapshot { pc: 2, steps: 2, state },
+            ExecEvent::Output(2),
+            ExecEvent::Ended { pc: 4, steps: 4, end: End::Halted },
+        ]);
+        assert_eq!(worker.join().load(), 2);
+    }
+
+    #[test]
+    fn breakpoints_stop_when_their_conditions_hold() {
+        // Counts down from 3, printing each count.
+        let worker = spawn(b"+++[.-]", b"", &Options { start_paused: true, ..Options::default() });
+        let out = 2;
+        worker.send(Command::Break(Breakpoint::at(out).when("cell[0] < 3".parse().unwrap())));
+        worker.send(Command::Break(Breakpoint::at(out).when("hits == 3".parse().unwrap())));
+        worker.send(Command::Resume);
+
+        let events = worker.events();
+        assert_eq!(events.recv(), Ok(ExecEvent::Paused { pc: 0, steps: 0 }));
+        assert_eq!(events.recv(), Ok(ExecEvent::Output(3)));
+        assert_eq!(events.recv(), Ok(ExecEvent::Breakpoint { index: 0, pc: out, steps: 5 }));
+        worker.send(Command::Resume);
+        assert_eq!(events.recv(), Ok(ExecEvent::Output(2)));
+        assert_eq!(events.recv(), Ok(ExecEvent::Breakpoint { index: 0, pc: out, steps: 8 }));
+        worker.send(Command::ClearBreakpoints);
+        worker.send(Command::Resume);
+        assert_eq!(events.iter().collect::<Vec<_>>(), [
+            ExecEvent::Output(1),
+            ExecEvent::Ended { pc: 5, steps: 11, end: End::Halted },
+        ]);
+    }
+
+    #[test]
+    fn breakpoint_scripts_log_patch_and_resume() {
+        let script = "print cell[0]; if cell[0] == 1 stop; set cell[1] = hits; resume";
+        let breakpoint = Breakpoint::at(2).when("hits > 1".parse().unwrap())
+            .then(script.parse().unwrap());
+        let worker = spawn(b"+++[.-]", b"", &Options { start_paused: true, ..Options::default() });
+        worker.send(Command::Break(breakpoint));
+        worker.send(Command::Resume);
+
+        assert_eq!(worker.events().iter().skip(1).collect::<Vec<_>>(), [
+            ExecEvent::Output(3),
+            ExecEvent::Breakpoint { index: 0, pc: 2, steps: 5 },
+            ExecEvent::ScriptOutput("cell[0] = 2".to_owned()),
+            ExecEvent::Output(2),
+            ExecEvent::Breakpoint { index: 0, pc: 2, steps: 8 },
+            ExecEvent::ScriptOutput("cell[0] = 1".to_owned()),
+            ExecEvent::Ended { pc: 2, steps: 8, end: End::Stopped },
+        ]);
+        assert_eq!(worker.join().memory()[1].0, 2);
+    }
+
+    #[test]
+    fn failed_runs_report_what_led_up_to_the_error() {
+        let worker = spawn(b"+[<+]", b"", &Options { history: 3, ..Options::default() });
+        let events: Vec<_> = worker.events().iter().collect();
+        let executed = match events[0] {
+            ExecEvent::History(ref executed) => executed,
+            ref event => panic!("expected a history, found {:?}", event),
+        };
+        let pcs: Vec<_> = executed.iter().map(|executed| executed.pc).collect();
+        assert_eq!(pcs, [0, 1, 2]);
+        let end = End::Failed(Error::PointerUnderflow);
+        assert_eq!(events[1], ExecEvent::Ended { pc: 2, steps: 2, end });
+
+        let worker = spawn(b"+.", b"", &Options { history: 3, ..Options::default() });
+        assert!(!worker.events().iter().any(|event| matches!(event, ExecEvent::History(_))));
+    }
+
+    #[test]
+    fn endless_runs_pass_milestones_until_stopped() {
+        let worker = spawn(b"+[]", b"", &Options { milestone: 1000, ..Options::default() });
+        let events = worker.events();
+        assert!(matches!(events.recv(), Ok(ExecEvent::Milestone { steps: 1000, .. })));
+        worker.send(Command::Stop);
+        let last = events.iter().last();
+        assert!(matches!(last, Some(ExecEvent::Ended { end: End::Stopped, .. })));
+    }
+}
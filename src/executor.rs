@@ -0,0 +1,524 @@
+//! Running many sandboxed programs on a pool of threads.
+//!
+//! A service that runs programs for others wants a fixed number of threads running them, each
+//! run bounded, with the caller waiting for its own result however it likes. An
+//! [`Executor`](struct.Executor.html) starts its threads once and takes
+//! [`Job`](struct.Job.html)s, each a program, its input and [`JobOptions`](struct.JobOptions.html)
+//! that can lower the executor’s [sandbox](../sandbox/index.html) bounds for that run, but not
+//! raise them. A submitted job comes back as a [`Pending`](struct.Pending.html) result to wait
+//! for or poll. Tapes are shared between the threads through a
+//! [`TapePool`](../tape_pool/struct.TapePool.html).
+//!
+//! Jobs wait until a thread is free, and the job taken next is the one of highest priority,
+//! and among those the one that has waited longest. For serving many users, a job can name
+//! its tenant, and [`QueueLimits`](struct.QueueLimits.html) cap how many jobs of one tenant
+//! run at once, so that one tenant’s burst leaves threads for the rest, and how many may wait
+//! in all and for each tenant. A job over a queue limit is
+//! [`Rejected`](struct.Rejected.html) at once and handed back, for the caller to retry later
+//! or turn away, rather than waiting without bound.
+//!
+//! ```
+//! use bf::executor::{Executor, Job};
+//! use bf::sandbox::{Sandbox, Stop};
+//!
+//! let executor = Executor::new(Sandbox::default(), 2);
+//! let echo = executor.submit(Job::new(",[.,]", "hi")).unwrap();
+//! let spin = executor.submit(Job::new("+[]", "").fuel(1000).priority(1)).unwrap();
+//!
+//! assert_eq!(echo.wait().unwrap().output, b"hi");
+//! assert_eq!(spin.wait().unwrap().stop, Stop::OutOfFuel);
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use common::BfResult;
+use sandbox::{Report, Sandbox};
+use tape_pool::TapePool;
+use telemetry;
+
+/// Bounds on one job, each lowering the executor’s own where given.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct JobOptions {
+    /// How many bytecode instructions the program may run.
+    pub fuel: Option<usize>,
+    /// How long the program may run.
+    pub timeout: Option<Duration>,
+    /// The memory size in bytes.
+    pub memory_size: Option<usize>,
+    /// How many bytes the program may print.
+    pub max_output: Option<usize>,
+}
+
+impl JobOptions {
+    /// The sandbox to run a job in, given the executor’s.
+    pub fn bound(&self, sandbox: &Sandbox) -> Sandbox {
+        let lower = |option: Option<usize>, bound: usize| option.map_or(bound, |n| bound.min(n));
+        Sandbox {
+            fuel: lower(self.fuel, sandbox.fuel),
+            memory_size: lower(self.memory_size, sandbox.memory_size).max(1),
+            max_output: lower(self.max_output, sandbox.max_output),
+            timeout: match (self.timeout, sandbox.timeout) {
+                (Some(timeout), Some(bound)) => Some(timeout.min(bound)),
+                (timeout, bound) => timeout.or(bound),
+            },
+            ..*sandbox
+        }
+    }
+}
+
+/// A program to run, with its input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Job {
+    /// The program’s source.
+    pub program: Vec<u8>,
+    /// The program’s input. End of input reads as 0.
+    pub input: Vec<u8>,
+    /// Bounds on the run.
+    pub options: JobOptions,
+    /// Jobs of higher priority run first; the default is 0.
+    pub priority: i32,
+    /// Who the job runs for, to hold to the per-tenant limits, if anyone.
+    pub tenant: Option<String>,
+}
+
+impl Job {
+    /// A job running `program` on `input` within the executor’s bounds.
+    pub fn new<P, I>(program: P, input: I) -> Self
+        where P: Into<Vec<u8>>,
+              I: Into<Vec<u8>>
+    {
+        Job {
+            program: program.into(),
+            input: input.into(),
+            options: JobOptions::default(),
+            priority: 0,
+            tenant: None,
+        }
+    }
+
+    /// Runs the job ahead of those of lower priority.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Runs the job for `tenant`.
+    pub fn tenant<S: Into<String>>(mut self, tenant: S) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Runs the job with at most `fuel` steps.
+    pub fn fuel(mut self, fuel: usize) -> Self {
+        self.options.fuel = Some(fuel);
+        self
+    }
+
+    /// Runs the job for at most `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Runs the job with at most `memory_size` cells.
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.options.memory_size = Some(memory_size);
+        self
+    }
+
+    /// Runs the job printing at most `max_output` bytes.
+    pub fn max_output(mut self, max_output: usize) -> Self {
+        self.options.max_output = Some(max_output);
+        self
+    }
+}
+
+/// Caps on the jobs an executor holds, where given.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueLimits {
+    /// How many jobs may wait for a thread.
+    pub max_queued: Option<usize>,
+    /// How many jobs of one tenant may wait for a thread.
+    pub max_queued_per_tenant: Option<usize>,
+    /// How many jobs of one tenant may run at once. The rest wait, letting other tenants’ jobs
+    /// go ahead of them.
+    pub max_running_per_tenant: Option<usize>,
+}
+
+/// Why a job was not queued.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rejection {
+    /// As many jobs are waiting as may.
+    QueueFull,
+    /// As many of the tenant’s jobs are waiting as may.
+    TenantQueueFull,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::QueueFull => write!(f, "too many jobs are queued"),
+            Rejection::TenantQueueFull => write!(f, "too many of the tenant’s jobs are queued"),
+        }
+    }
+}
+
+/// A job the executor would not queue, handed back.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rejected {
+    /// Why it was not queued.
+    pub reason: Rejection,
+    /// The job.
+    pub job: Box<Job>,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// The result of a submitted job, once it has run.
+///
+/// The result is [`run`](../sandbox/struct.Sandbox.html#method.run)’s: `Err` if the program
+/// does not compile within the limits, and otherwise the report of how it ran.
+#[derive(Debug)]
+pub struct Pending {
+    receiver: Receiver<BfResult<Report>>,
+}
+
+impl Pending {
+    /// Waits for the job to run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the job’s thread panicked running it.
+    pub fn wait(self) -> BfResult<Report> {
+        self.receiver.recv().expect("the executor’s thread does not panic")
+    }
+
+    /// The result if the job has run, or else the job back to wait for again.
+    pub fn try_wait(self) -> Result<BfResult<Report>, Self> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => panic!("the executor’s thread does not panic"),
+        }
+    }
+
+    /// The channel the result arrives on, to select over or wait on with a timeout.
+    pub fn into_receiver(self) -> Receiver<BfResult<Report>> {
+        self.receiver
+    }
+}
+
+/// A job waiting for a thread.
+struct Task {
+    job: Job,
+    result: Sender<BfResult<Report>>,
+    queued_at: Instant,
+}
+
+/// What the threads share.
+struct Shared {
+    sandbox: Sandbox,
+    limits: QueueLimits,
+    tapes: TapePool,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// The jobs waiting, in the order they came, and the tenants’ counts.
+struct Queue {
+    tasks: VecDeque<Task>,
+    queued: HashMap<String, usize>,
+    running: HashMap<String, usize>,
+    closed: bool,
+}
+
+impl Queue {
+    /// Takes the first job of highest priority whose tenant may run another.
+    fn pop(&mut self, limits: &QueueLimits) -> Option<Task> {
+        let max_running = limits.max_running_per_tenant.unwrap_or(usize::MAX);
+        let mut best: Option<usize> = None;
+        for (index, task) in self.tasks.iter().enumerate() {
+            let blocked = task.job.tenant.as_ref().is_some_and(|tenant| {
+                self.running.get(tenant).is_some_and(|&running| running >= max_running)
+            });
+            let better = best.is_none_or(|best| task.job.priority > self.tasks[best].job.priority);
+            if !blocked && better {
+                best = Some(index);
+            }
+        }
+
+        let task = self.tasks.remove(best?)?;
+        telemetry::record_queued(self.tasks.len());
+        telemetry::record_wait(task.queued_at.elapsed());
+        if let Some(ref tenant) = task.job.tenant {
+            count(&mut self.queued, tenant, false);
+            count(&mut self.running, tenant, true);
+        }
+        Some(task)
+    }
+}
+
+/// Counts one more or one fewer of the tenant’s jobs.
+fn count(counts: &mut HashMap<String, usize>, tenant: &str, more: bool) {
+    if more {
+        *counts.entry(tenant.to_owned()).or_insert(0) += 1;
+    } else if let Some(count) = counts.get_mut(tenant) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(tenant);
+        }
+    }
+}
+
+/// A fixed pool of threads running jobs in a sandbox.
+///
+/// Dropping the executor runs the jobs already submitted and then joins its threads.
+pub struct Executor {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Executor {
+    /// Starts `threads` threads, at least one, running jobs within `sandbox`’s bounds, with
+    /// no limits on the queue.
+    pub fn new(sandbox: Sandbox, threads: usize) -> Self {
+        Self::with_limits(sandbox, threads, QueueLimits::default())
+    }
+
+    /// Starts `threads` threads, at least one, running jobs within `sandbox`’s bounds and
+    /// holding them to `limits`.
+    pub fn with_limits(sandbox: Sandbox, threads: usize, limits: QueueLimits) -> Self {
+        let shared = Arc::new(Shared {
+            sandbox,
+            limits,
+            tapes: TapePool::new(sandbox.memory_size).max_spare(threads.max(1)),
+            queue: Mutex::new(Queue {
+                tasks: VecDeque::new(),
+                queued: HashMap::new(),
+                running: HashMap::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+
+        let threads = (0 .. threads.max(1)).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || shared.work())
+        }).collect();
+
+        Executor { shared, threads }
+    }
+
+    /// The bounds jobs run within.
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.shared.sandbox
+    }
+
+    /// The number of threads running jobs.
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// The limits the queue is held to.
+    pub fn limits(&self) -> &QueueLimits {
+        &self.shared.limits
+    }
+
+    /// The number of jobs waiting for a thread.
+    pub fn queued(&self) -> usize {
+        self.shared.lock().tasks.len()
+    }
+
+    /// The number of jobs waiting for a thread and running for `tenant`.
+    pub fn tenant_jobs(&self, tenant: &str) -> (usize, usize) {
+        let queue = self.shared.lock();
+        let get = |counts: &HashMap<String, usize>| counts.get(tenant).cloned().unwrap_or(0);
+        (get(&queue.queued), get(&queue.running))
+    }
+
+    /// Queues a job to run once a thread is free.
+    ///
+    /// # Errors
+    ///
+    /// Hands the job back if the queue, or its tenant’s share of it, is at its limit.
+    pub fn submit(&self, job: Job) -> Result<Pending, Rejected> {
+        let limits = &self.shared.limits;
+        let mut queue = self.shared.lock();
+
+        let tenant_queued = job.tenant.as_ref().map(|tenant| {
+            queue.queued.get(tenant).cloned().unwrap_or(0)
+        });
+        let reason = if limits.max_queued.is_some_and(|max| queue.tasks.len() >= max) {
+            Some(Rejection::QueueFull)
+        } else if limits.max_queued_per_tenant.is_some_and(|max| tenant_queued >= Some(max)) {
+            Some(Rejection::TenantQueueFull)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            telemetry::record_rejected(reason);
+            return Err(Rejected { reason, job: Box::new(job) });
+        }
+        if let Some(ref tenant) = job.tenant {
+            count(&mut queue.queued, tenant, true);
+        }
+
+        let (result, receiver) = mpsc::channel();
+        queue.tasks.push_back(Task { job, result, queued_at: Instant::now() });
+        telemetry::record_queued(queue.tasks.len());
+        drop(queue);
+        self.shared.ready.notify_one();
+        Ok(Pending { receiver })
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.ready.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs jobs until the executor is dropped and the queue is empty.
+    fn work(&self) {
+        while let Some(Task { job, result, .. }) = self.next() {
+            let sandbox = job.options.bound(&self.sandbox);
+            let mut tape = self.tapes.take();
+            let report = sandbox.run_on(&job.program, &job.input, &mut tape);
+            self.tapes.put(tape);
+
+            if let Some(ref tenant) = job.tenant {
+                count(&mut self.lock().running, tenant, false);
+                // A job of the tenant’s may have been waiting for this one.
+                self.ready.notify_all();
+            }
+            // The caller may have stopped waiting.
+            let _ = result.send(report);
+        }
+    }
+
+    /// The next job to run, waiting for one.
+    fn next(&self) -> Option<Task> {
+        let mut queue = self.lock();
+        loop {
+            if let Some(task) = queue.pop(&self.limits) {
+                return Some(task);
+            }
+            if queue.closed && queue.tasks.is_empty() {
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Error;
+    use sandbox::Stop;
+    use test_helpers::*;
+
+    #[test]
+    fn jobs_run_within_their_own_bounds() {
+        let sandbox = Sandbox { fuel: 1_000_000, ..Sandbox::default() };
+        let executor = Executor::new(sandbox, 2);
+        let factor = executor.submit(Job::new(FACTOR_SRC, "100\n")).unwrap();
+        let spin = executor.submit(Job::new("+[]", "").timeout(Duration::from_millis(10))).unwrap();
+        let fuel = executor.submit(Job::new("+[]", "").fuel(10_000_000)).unwrap();
+        let memory = executor.submit(Job::new(">>>>", "").memory_size(4)).unwrap();
+        let broken = executor.submit(Job::new("[", "")).unwrap();
+
+        assert_eq!(factor.wait().unwrap().output, b"100: 2 2 5 5\n");
+        assert_eq!(spin.wait().unwrap().stop, Stop::TimedOut);
+        assert_eq!(fuel.wait().unwrap().steps, 1_000_000);
+        assert_eq!(memory.wait().unwrap().stop, Stop::Failed(Error::PointerOverflow));
+        assert_eq!(broken.wait(), Err(Error::UnmatchedBegin));
+    }
+
+    #[test]
+    fn dropping_the_executor_finishes_its_jobs() {
+        let executor = Executor::new(Sandbox::default(), 1);
+        assert_eq!(executor.threads(), 1);
+        let pending: Vec<_> = (0 .. 8).map(|i| executor.submit(Job::new(",.", vec![i])).unwrap())
+            .collect();
+        drop(executor);
+
+        for (i, pending) in pending.into_iter().enumerate() {
+            let report = pending.try_wait().expect("the job has run").unwrap();
+            assert_eq!(report.output, [i as u8]);
+        }
+    }
+
+    #[test]
+    fn jobs_are_taken_by_priority_within_the_tenant_limits() {
+        let mut queue = Queue {
+            tasks: VecDeque::new(),
+            queued: HashMap::new(),
+            running: HashMap::new(),
+            closed: false,
+        };
+        for &(name, priority, tenant) in &[("a", 0, "x"), ("b", 1, "x"), ("c", 1, "y"),
+                                           ("d", 1, "x"), ("e", 0, "y")] {
+            let job = Job::new(name, "").priority(priority).tenant(tenant);
+            count(&mut queue.queued, tenant, true);
+            let (result, queued_at) = (mpsc::channel().0, Instant::now());
+            queue.tasks.push_back(Task { job, result, queued_at });
+        }
+
+        let limits = QueueLimits { max_running_per_tenant: Some(1), ..QueueLimits::default() };
+        let pop = |queue: &mut Queue| queue.pop(&limits).map(|task| task.job.program);
+        assert_eq!(pop(&mut queue), Some(b"b".to_vec()));
+        assert_eq!(pop(&mut queue), Some(b"c".to_vec()));
+        assert_eq!(pop(&mut queue), None);
+
+        count(&mut queue.running, "x", false);
+        assert_eq!(pop(&mut queue), Some(b"d".to_vec()));
+        count(&mut queue.running, "x", false);
+        count(&mut queue.running, "y", false);
+        assert_eq!(pop(&mut queue), Some(b"a".to_vec()));
+        assert_eq!((queue.queued.get("y"), queue.running.get("x")), (Some(&1), Some(&1)));
+    }
+
+    #[test]
+    fn jobs_over_the_queue_limits_are_handed_back() {
+        let limits = QueueLimits {
+            max_queued: Some(3),
+            max_queued_per_tenant: Some(2),
+            ..QueueLimits::default()
+        };
+        let executor = Executor::with_limits(Sandbox::default(), 1, limits);
+        let busy = executor.submit(Job::new("+[]", "").timeout(Duration::from_millis(200)))
+            .unwrap();
+        while executor.queued() > 0 {
+            thread::yield_now();
+        }
+
+        let job = |tenant| Job::new("", "").tenant(tenant);
+        assert!(executor.submit(job("x")).is_ok());
+        assert!(executor.submit(job("x")).is_ok());
+        let rejected = executor.submit(job("x")).unwrap_err();
+        assert_eq!((rejected.reason, *rejected.job), (Rejection::TenantQueueFull, job("x")));
+        assert!(executor.submit(job("y")).is_ok());
+        assert_eq!(executor.submit(Job::new("", "")).unwrap_err().reason, Rejection::QueueFull);
+        assert_eq!(executor.tenant_jobs("x"), (2, 0));
+        assert_eq!(busy.wait().unwrap().stop, Stop::TimedOut);
+    }
+}
@@ -0,0 +1,270 @@
+//! Scripted conversations with interactive programs, for testing them.
+//!
+//! A [`Script`](struct.Script.html) alternates input to send with output to expect, in the
+//! manner of `expect(1)`, and a [`Session`](struct.Session.html) plays it through any
+//! interpreter’s `Read` and `Write`. Each expected piece of output may follow other output, but
+//! must appear before the program next reads; input is only given once everything expected
+//! before it has appeared, so a text adventure or other prompt-driven program is tested the way
+//! a person would use it, and the same way every run.
+//!
+//! ```
+//! use bf::expect::{Script, Session};
+//! use bf::traits::Interpretable;
+//!
+//! let program = bf::ast::parse_program(b">++++++[<++++++++>-]<.,[.,]").unwrap();
+//! let session = Session::new(Script::new().expect(b"0").send(b"hi").expect(b"hi"));
+//! program.interpret(None, session.input(), session.output()).unwrap();
+//! assert_eq!(session.finish().unwrap(), b"0hi");
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// One step of a script.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// Input to give the program.
+    Send(Vec<u8>),
+    /// Output the program must write.
+    Expect(Vec<u8>),
+}
+
+/// The steps of a conversation, in order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Script {
+    /// The steps.
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    /// An empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds input to give the program.
+    pub fn send(mut self, input: &[u8]) -> Self {
+        self.steps.push(Step::Send(input.to_vec()));
+        self
+    }
+
+    /// Adds output the program must write.
+    pub fn expect(mut self, output: &[u8]) -> Self {
+        self.steps.push(Step::Expect(output.to_vec()));
+        self
+    }
+}
+
+/// Why a program strayed from its script.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MismatchKind {
+    /// The program read input before writing the expected output.
+    ReadTooSoon,
+    /// The program finished before writing the expected output.
+    EndedTooSoon,
+}
+
+/// Where a program strayed from its script.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// What happened.
+    pub kind: MismatchKind,
+    /// The index of the `Expect` step that was not met.
+    pub step: usize,
+    /// The output expected.
+    pub expected: Vec<u8>,
+    /// The output written since the last step was met.
+    pub actual: Vec<u8>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let when = match self.kind {
+            MismatchKind::ReadTooSoon => "read input",
+            MismatchKind::EndedTooSoon => "finished",
+        };
+        write!(f, "at step {}, expected {:?}, but the program {} after writing {:?}",
+               self.step + 1, String::from_utf8_lossy(&self.expected), when,
+               String::from_utf8_lossy(&self.actual))
+    }
+}
+
+/// A script being played through a program’s input and output.
+#[derive(Debug)]
+pub struct Session {
+    inner: RefCell<Progress>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    steps: Vec<Step>,
+    /// The index of the current step.
+    step: usize,
+    /// How much of the current `Send` has been read.
+    sent: usize,
+    /// All output written.
+    output: Vec<u8>,
+    /// Where in the output the search for the current `Expect` starts.
+    matched: usize,
+    mismatch: Option<Mismatch>,
+}
+
+impl Progress {
+    /// Moves past each step that is done: a `Send` that has all been read, or an `Expect` whose
+    /// output has appeared.
+    fn advance(&mut self) {
+        loop {
+            match self.steps.get(self.step) {
+                Some(Step::Send(input)) if self.sent == input.len() => self.sent = 0,
+                Some(Step::Expect(expected)) if expected.is_empty() => (),
+                Some(Step::Expect(expected)) => {
+                    match self.output[self.matched ..].windows(expected.len())
+                        .position(|window| window == &expected[..])
+                    {
+                        Some(start) => self.matched += start + expected.len(),
+                        None => return,
+                    }
+                }
+                _ => return,
+            }
+            self.step += 1;
+        }
+    }
+
+    /// Records the first way the program strays, if it is waiting on an `Expect`.
+    fn check(&mut self, kind: MismatchKind) {
+        if self.mismatch.is_some() {
+            return;
+        }
+        if let Some(Step::Expect(expected)) = self.steps.get(self.step) {
+            self.mismatch = Some(Mismatch {
+                kind,
+                step: self.step,
+                expected: expected.clone(),
+                actual: self.output[self.matched ..].to_vec(),
+            });
+        }
+    }
+}
+
+impl Session {
+    /// Starts playing a script.
+    pub fn new(script: Script) -> Self {
+        let mut progress = Progress {
+            steps: script.steps,
+            step: 0,
+            sent: 0,
+            output: Vec::new(),
+            matched: 0,
+            mismatch: None,
+        };
+        progress.advance();
+        Session { inner: RefCell::new(progress) }
+    }
+
+    /// The program’s input, which gives each `Send` in turn and then reaches end of input.
+    pub fn input<'a>(&'a self) -> Input<'a> {
+        Input(&self.inner)
+    }
+
+    /// The program’s output, which is checked against each `Expect`.
+    pub fn output<'a>(&'a self) -> Output<'a> {
+        Output(&self.inner)
+    }
+
+    /// Ends the session once the program has finished, returning everything it wrote if it
+    /// followed the script.
+    ///
+    /// # Errors
+    ///
+    /// The first place the program strayed from the script.
+    pub fn finish(self) -> Result<Vec<u8>, Mismatch> {
+        let mut progress = self.inner.into_inner();
+        progress.check(MismatchKind::EndedTooSoon);
+        match progress.mismatch {
+            Some(mismatch) => Err(mismatch),
+            None => Ok(progress.output),
+        }
+    }
+}
+
+/// The input side of a [`Session`](struct.Session.html).
+#[derive(Debug)]
+pub struct Input<'a>(&'a RefCell<Progress>);
+
+impl<'a> Read for Input<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut progress = self.0.borrow_mut();
+        progress.check(MismatchKind::ReadTooSoon);
+        if progress.mismatch.is_some() {
+            return Ok(0);
+        }
+
+        let len = match progress.steps.get(progress.step) {
+            Some(Step::Send(input)) => {
+                let rest = &input[progress.sent ..];
+                let len = rest.len().min(buf.len());
+                buf[.. len].copy_from_slice(&rest[.. len]);
+                len
+            }
+            _ => return Ok(0),
+        };
+
+        progress.sent += len;
+        progress.advance();
+        Ok(len)
+    }
+}
+
+/// The output side of a [`Session`](struct.Session.html).
+#[derive(Debug)]
+pub struct Output<'a>(&'a RefCell<Progress>);
+
+impl<'a> Write for Output<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut progress = self.0.borrow_mut();
+        progress.output.extend_from_slice(buf);
+        progress.advance();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::Interpretable;
+
+    fn play(source: &[u8], script: Script) -> Result<Vec<u8>, Mismatch> {
+        let program = ::ast::parse_program(source).unwrap();
+        let session = Session::new(script);
+        program.interpret(None, session.input(), session.output()).unwrap();
+        session.finish()
+    }
+
+    #[test]
+    fn conversations_follow_the_script() {
+        let script = Script::new().send(b"12\n").expect(b"2 2 3\n");
+        assert_eq!(play(FACTOR_SRC, script.clone()), Ok(b"12: 2 2 3\n".to_vec()));
+
+        // Echoes a line at a time: input after the first line waits until it is echoed.
+        let echo = b",[.,]";
+        let script = Script::new().send(b"a\n").expect(b"a\n").send(b"b").expect(b"b");
+        assert_eq!(play(echo, script), Ok(b"a\nb".to_vec()));
+    }
+
+    #[test]
+    fn strays_are_reported() {
+        let mismatch = play(b",[.,]", Script::new().expect(b"hello")).unwrap_err();
+        assert_eq!(mismatch.kind, MismatchKind::ReadTooSoon);
+        assert_eq!(mismatch.to_string(),
+                   "at step 1, expected \"hello\", but the program read input after writing \"\"");
+
+        let mismatch = play(b"++++++++[>++++++<-]>.", Script::new().expect(b"1")).unwrap_err();
+        assert_eq!((mismatch.kind, mismatch.step, &mismatch.actual[..]),
+                   (MismatchKind::EndedTooSoon, 0, &b"0"[..]));
+    }
+}
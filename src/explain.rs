@@ -0,0 +1,143 @@
+//! What the optimizer made of a program, side by side with its source, for `bfi explain`.
+//!
+//! [`explain`](fn.explain.html) lines each bytecode instruction up with the source it was
+//! compiled from, as recorded in the [source map](../source_map/index.html): a run of `+` next
+//! to the one `Add` it became, a `[-]` next to `SetZero`, and a loop’s brackets next to its
+//! jumps, with the loop body indented between them. Source that compiled to nothing, such as a
+//! loop that can never run, is marked as removed.
+//!
+//! ```text
+//! ++++++++   | Add(8)
+//! [          | Loop {
+//! >++++      |     Right(1)
+//! ...
+//! ```
+
+use std::fmt::Write;
+
+use ast;
+use bytecode;
+use common::{BfResult, Instruction};
+use diagnostics::Span;
+use source_map::SourceMap;
+use traits::PeepholeCompilable;
+
+/// One line of an explanation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Row {
+    /// The span of source explained.
+    pub span: Span,
+    /// The instruction it became, indented by loop depth, or `None` if it became nothing.
+    pub instruction: Option<String>,
+}
+
+/// Lines up the program’s source with the instructions it compiles to.
+///
+/// # Errors
+///
+/// Fails if the program does not parse.
+pub fn rows(source: &[u8]) -> BfResult<Vec<Row>> {
+    let program = ast::parse_program(source)?.peephole_compile();
+    let map = SourceMap::new(source, &program);
+    let program = bytecode::compile(&program);
+
+    let mut rows = Vec::new();
+    let mut position = 0;
+    let mut depth = 0;
+
+    for (&instruction, &span) in program.iter().zip(map.spans()) {
+        if has_commands(&source[position.min(span.start) .. span.start]) {
+            rows.push(Row { span: Span { start: position, end: span.start }, instruction: None });
+        }
+
+        if let Instruction::JumpNotZero(_) = instruction {
+            depth -= 1;
+        }
+        let text = match instruction {
+            Instruction::JumpZero(_) => "Loop {".to_owned(),
+            Instruction::JumpNotZero(_) => "}".to_owned(),
+            _ => instruction.to_string(),
+        };
+        rows.push(Row { span, instruction: Some(format!("{:1$}{2}", "", 4 * depth, text)) });
+        if let Instruction::JumpZero(_) = instruction {
+            depth += 1;
+        }
+
+        // A `DivMod` or index instruction spans the loop that follows it.
+        if span.start >= position {
+            position = span.end.min(source.len());
+        }
+    }
+
+    if has_commands(&source[position ..]) {
+        rows.push(Row { span: Span { start: position, end: source.len() }, instruction: None });
+    }
+
+    Ok(rows)
+}
+
+/// Writes the explanation out, the source on the left in a column `width` characters wide and
+/// each instruction to its right.
+///
+/// # Errors
+///
+/// Fails if the program does not parse.
+pub fn explain(source: &[u8], width: usize) -> BfResult<String> {
+    let mut result = String::new();
+
+    for row in rows(source)? {
+        let commands: String = source[row.span.start .. row.span.end].iter()
+            .filter(|&&c| is_command(c))
+            .map(|&c| c as char)
+            .collect();
+        let commands = if commands.len() > width {
+            format!("{}…", &commands[.. width.saturating_sub(1)])
+        } else {
+            commands
+        };
+        let instruction = row.instruction.as_ref().map_or("(removed)", String::as_str);
+        let _ = writeln!(result, "{:2$} | {}", commands, instruction, width);
+    }
+
+    Ok(result)
+}
+
+fn is_command(c: u8) -> bool {
+    b"<>+-.,[]".contains(&c)
+}
+
+fn has_commands(source: &[u8]) -> bool {
+    source.iter().any(|&c| is_command(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_line_up_with_their_source() {
+        assert_eq!(explain(b"+++ [-] > [->+<] comment .", 8).unwrap(),
+                   "+++      | Add(3)\n\
+                    [-]      | SetZero\n\
+                    >        | Right(1)\n\
+                    [->+<]   | OffsetAddRight(1)\n\
+                    .        | Out\n");
+    }
+
+    #[test]
+    fn loops_indent_and_dead_code_is_marked() {
+        assert_eq!(explain(b"[-][+.]+[>+++++++++<-]", 6).unwrap(),
+                   "[-]    | SetZero\n\
+                    [+.]   | (removed)\n\
+                    +      | Add(1)\n\
+                    [      | Loop {\n\
+                    >      |     Right(1)\n\
+                    +++++… |     Add(9)\n\
+                    <      |     Left(1)\n\
+                    -      |     Add(255)\n\
+                    ]      | }\n");
+
+        let rows = rows(b"+[-]").unwrap();
+        assert_eq!(rows[1].span, Span { start: 1, end: 4 });
+    }
+}
@@ -0,0 +1,122 @@
+//! Stable content hashes of programs.
+//!
+//! A fingerprint is the 64-bit FNV-1a hash of a program’s [canonical text](../text/index.html),
+//! prefixed by the name of its IR level. It depends only on the program itself—not on the
+//! platform, the `Count` width, or where the program lives in memory—so it can serve as a key
+//! for caches of compiled code and memoized analyses.
+
+use std::fmt::{self, Write};
+
+use ast;
+use bytecode;
+use peephole;
+use rle;
+use text::Text;
+
+/// Program forms that have a stable fingerprint.
+pub trait Fingerprintable {
+    /// Computes the fingerprint of the program.
+    fn fingerprint(&self) -> u64;
+}
+
+impl Fingerprintable for ast::Program {
+    fn fingerprint(&self) -> u64 {
+        hash_text("ast", Text(self))
+    }
+}
+
+impl Fingerprintable for rle::Program {
+    fn fingerprint(&self) -> u64 {
+        hash_text("rle", Text(self))
+    }
+}
+
+impl Fingerprintable for peephole::Program {
+    fn fingerprint(&self) -> u64 {
+        hash_text("peephole", Text(self))
+    }
+}
+
+impl Fingerprintable for bytecode::Program {
+    fn fingerprint(&self) -> u64 {
+        hash_text("bytecode", Text(self))
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// An FNV-1a hasher that text can be formatted into directly.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Write for Fnv {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn hash_text<D: fmt::Display>(level: &str, text: D) -> u64 {
+    let mut hasher = Fnv::new();
+    writeln!(hasher, "{}", level).unwrap();
+    write!(hasher, "{}", text).unwrap();
+    hasher.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::PeepholeCompilable;
+
+    #[test]
+    fn equal_programs_have_equal_fingerprints() {
+        let one = ast::parse_program(b"+[->+<] comment").unwrap();
+        let two = ast::parse_program(b"+[-  >+<]").unwrap();
+        assert_eq!(one.fingerprint(), two.fingerprint());
+        assert_eq!(one.peephole_compile().fingerprint(), two.peephole_compile().fingerprint());
+    }
+
+    #[test]
+    fn different_programs_have_different_fingerprints() {
+        let one = ast::parse_program(b"+[->+<]").unwrap();
+        let two = ast::parse_program(b"+[-<+>]").unwrap();
+        assert_ne!(one.fingerprint(), two.fingerprint());
+        assert_ne!(one.peephole_compile().fingerprint(), two.peephole_compile().fingerprint());
+    }
+
+    #[test]
+    fn levels_are_distinguished() {
+        let program = ast::parse_program(b"").unwrap();
+        assert_ne!(program.fingerprint(), program.peephole_compile().fingerprint());
+    }
+
+    #[test]
+    fn pipelines_are_deterministic() {
+        use traits::{BytecodeCompilable, RleCompilable};
+
+        let one = ast::parse_program(FACTOR_SRC).unwrap();
+        let two = ast::parse_reader(FACTOR_SRC).unwrap();
+        assert_eq!(one.fingerprint(), two.fingerprint());
+        assert_eq!(one.rle_compile().fingerprint(), two.rle_compile().fingerprint());
+        assert_eq!(one.peephole_compile().fingerprint(), two.peephole_compile().fingerprint());
+        assert_eq!(one.bytecode_compile().fingerprint(), two.bytecode_compile().fingerprint());
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        let program = ast::parse_program(HELLO_WORLD_SRC).unwrap();
+        assert_eq!(program.fingerprint(), hash_text("ast", Text(&*program)));
+        assert_eq!(ast::parse_program(b"").unwrap().fingerprint(), 0x905e_8a84_424d_8023);
+    }
+}
@@ -0,0 +1,129 @@
+//! A source formatter for Brainfuck.
+//!
+//! [`format`](fn.format.html) lays a program out with one loop per block: each `[` ends a line,
+//! the loop body is indented, and the matching `]` gets a line of its own. Runs of other
+//! commands stay together on one line. Comments are kept, each comment line trimmed and put on
+//! a line of its own at the current indentation, so formatting never changes what a program
+//! does, and formatting formatted source changes nothing.
+//!
+//! Source with unmatched brackets is formatted as well as possible: an extra `]` is placed at
+//! the outermost level.
+
+/// The indentation for each level of loop nesting.
+const INDENT: &str = "    ";
+
+/// Formats Brainfuck source.
+pub fn format(source: &[u8]) -> String {
+    let mut formatter = Formatter {
+        out:   String::new(),
+        line:  String::new(),
+        depth: 0,
+    };
+
+    let mut comment = Vec::new();
+
+    for &c in source {
+        if is_command(c) {
+            if !comment.is_empty() {
+                formatter.comment(&String::from_utf8_lossy(&comment));
+                comment.clear();
+            }
+            formatter.command(c);
+        } else {
+            comment.push(c);
+        }
+    }
+
+    if !comment.is_empty() {
+        formatter.comment(&String::from_utf8_lossy(&comment));
+    }
+
+    formatter.finish_line();
+    formatter.out
+}
+
+struct Formatter {
+    /// The finished lines.
+    out: String,
+    /// The commands on the current line.
+    line: String,
+    /// The current loop nesting depth.
+    depth: usize,
+}
+
+impl Formatter {
+    fn command(&mut self, c: u8) {
+        match c {
+            b'[' => {
+                self.line.push('[');
+                self.finish_line();
+                self.depth += 1;
+            }
+
+            b']' => {
+                self.finish_line();
+                self.depth = self.depth.saturating_sub(1);
+                self.line.push(']');
+                self.finish_line();
+            }
+
+            _ => self.line.push(c as char),
+        }
+    }
+
+    fn comment(&mut self, text: &str) {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+        if lines.peek().is_none() { return; }
+
+        self.finish_line();
+        for line in lines {
+            self.line.push_str(line);
+            self.finish_line();
+        }
+    }
+
+    fn finish_line(&mut self) {
+        if self.line.is_empty() { return; }
+
+        for _ in 0 .. self.depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(&self.line);
+        self.out.push('\n');
+        self.line.clear();
+    }
+}
+
+fn is_command(c: u8) -> bool {
+    matches!(c, b'<' | b'>' | b'+' | b'-' | b',' | b'.' | b'[' | b']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!(format(b"  print  a  letter\n++++++[>++++++++++<-]>+++++. done "),
+                   "print  a  letter\n\
+                    ++++++[\n\
+                    \x20   >++++++++++<-\n\
+                    ]\n\
+                    >+++++.\n\
+                    done\n");
+    }
+
+    #[test]
+    fn formatting_is_idempotent_and_preserves_programs() {
+        let once = format(FACTOR_SRC);
+        assert_eq!(format(once.as_bytes()), once);
+        assert_eq!(::ast::parse_program(once.as_bytes()),
+                   ::ast::parse_program(FACTOR_SRC));
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(format(b"]+[-"), "]\n+[\n    -\n");
+    }
+}
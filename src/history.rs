@@ -0,0 +1,156 @@
+//! The last instructions a run executed.
+//!
+//! “What led up to the underflow?” is the first question about a run that failed. A
+//! [`History`](struct.History.html) keeps the last so many instructions a run started in a ring
+//! buffer of fixed size, so that the answer is at hand once it fails:
+//! [crash dumps](../crash_dump/index.html) include it, and a
+//! [worker](../events/struct.Worker.html) started with a `history` length reports it just
+//! before saying how the run ended. Keeping one is opt-in; a history of length 0 keeps nothing.
+//!
+//! Entries are recorded by address, and [`locate`](fn.locate.html) fills in their source spans
+//! from a [`SourceMap`](../source_map/struct.SourceMap.html).
+//!
+//! ```
+//! use bf::common::Instruction;
+//! use bf::history::History;
+//!
+//! let mut history = History::new(2);
+//! for (pc, &instruction) in [Instruction::Add(1), Instruction::Out, Instruction::In]
+//!     .iter().enumerate()
+//! {
+//!     history.record(pc, instruction);
+//! }
+//! let pcs: Vec<_> = history.executed().iter().map(|executed| executed.pc).collect();
+//! assert_eq!(pcs, [1, 2]);
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use common::Instruction;
+use diagnostics::Span;
+use source_map::SourceMap;
+
+/// (`== 32`) How many instructions a history keeps where the length is not given, as in crash
+/// dumps.
+pub const DEFAULT_LEN: usize = 32;
+
+/// An instruction a run started.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Executed {
+    /// Its address.
+    pub pc: usize,
+    /// The instruction.
+    pub instruction: Instruction,
+    /// The source it was compiled from, once [located](fn.locate.html).
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Executed {
+    /// The address and instruction, then the span if known, as `12: Right(1) at 5..6`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.pc, self.instruction)?;
+        if let Some(span) = self.span {
+            write!(f, " at {}..{}", span.start, span.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// The last instructions started, up to a fixed number.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<(usize, Instruction)>,
+}
+
+impl History {
+    /// A history of the last `capacity` instructions, or of none if it is 0.
+    pub fn new(capacity: usize) -> Self {
+        History { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    /// How many instructions the history keeps.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many instructions it has kept so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether it has kept none.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records that the instruction at `pc` is starting, forgetting the oldest if the history
+    /// is full.
+    #[inline]
+    pub fn record(&mut self, pc: usize, instruction: Instruction) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((pc, instruction));
+    }
+
+    /// Forgets everything recorded.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The instructions kept, oldest first, without their spans.
+    pub fn executed(&self) -> Vec<Executed> {
+        self.entries.iter()
+            .map(|&(pc, instruction)| Executed { pc, instruction, span: None })
+            .collect()
+    }
+}
+
+/// Fills in the spans of instructions from the map of the program they ran in.
+pub fn locate(executed: &mut [Executed], map: &SourceMap) {
+    for entry in executed {
+        entry.span = map.span(entry.pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::PeepholeCompilable;
+
+    #[test]
+    fn histories_keep_only_the_last_instructions() {
+        let mut history = History::new(3);
+        for pc in 0 .. 10 {
+            history.record(pc, Instruction::Add(pc as u8));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.executed()[0], Executed { pc: 7, instruction: Instruction::Add(7),
+                                                     span: None });
+
+        let mut off = History::new(0);
+        off.record(0, Instruction::Out);
+        assert!(off.is_empty());
+        history.clear();
+        assert!(history.executed().is_empty());
+    }
+
+    #[test]
+    fn located_instructions_show_their_spans() {
+        let source = b"++ >.";
+        let map = SourceMap::new(source, &::ast::parse_program(source).unwrap()
+            .peephole_compile());
+        let mut history = History::new(DEFAULT_LEN);
+        history.record(0, Instruction::Add(2));
+        history.record(1, Instruction::Right(1));
+        let mut executed = history.executed();
+        locate(&mut executed, &map);
+        assert_eq!(executed[0].to_string(), "0: Add(2) at 0..2");
+        assert_eq!(executed[1].span, Some(Span::at(3)));
+    }
+}
@@ -0,0 +1,150 @@
+//! Builds peephole programs from Rust, without going through Brainfuck source.
+//!
+//! A [`Builder`](struct.Builder.html) appends instructions and loops one call at a time, and the
+//! program it builds runs on any backend that takes peephole IR. It optimizes as the peephole
+//! compiler does: adjacent moves and adds are fused, loop bodies that match a
+//! [rule](../peephole/rules/index.html) become its instruction, and loops that can never run
+//! are dropped.
+//!
+//! ```
+//! use bf::ir::Builder;
+//! use bf::traits::Interpretable;
+//!
+//! // Doubles the input byte.
+//! let program = Builder::new()
+//!     .input()
+//!     .loop_(|b| b.sub(1).right(1).add(2).left(1))
+//!     .right(1).output()
+//!     .build();
+//! assert_eq!(program.interpret_memory(None, &[21]).unwrap(), [42]);
+//! ```
+
+use std::sync::Arc;
+
+use common::{Count, Instruction};
+use peephole::{Program, ProgramData, Statement};
+use peephole::link::Linker;
+use peephole::rules::{self, Action};
+
+/// Builds a peephole program.
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    linker: Linker,
+}
+
+// `add` and `sub` read as the Brainfuck they stand for, not as arithmetic on builders.
+#[allow(clippy::should_implement_trait)]
+impl Builder {
+    /// A builder for the empty program.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an instruction.
+    pub fn instr(mut self, instruction: Instruction) -> Self {
+        self.linker.push(Statement::Instr(instruction));
+        self
+    }
+
+    /// Adds to the current cell, modulo 256.
+    pub fn add(self, amount: u8) -> Self {
+        self.instr(Instruction::Add(amount))
+    }
+
+    /// Subtracts from the current cell, modulo 256.
+    pub fn sub(self, amount: u8) -> Self {
+        self.instr(Instruction::Add(amount.wrapping_neg()))
+    }
+
+    /// Moves the pointer right.
+    pub fn right(self, count: Count) -> Self {
+        self.instr(Instruction::Right(count))
+    }
+
+    /// Moves the pointer left.
+    pub fn left(self, count: Count) -> Self {
+        self.instr(Instruction::Left(count))
+    }
+
+    /// Reads a byte into the current cell.
+    pub fn input(self) -> Self {
+        self.instr(Instruction::In)
+    }
+
+    /// Writes the current cell.
+    pub fn output(self) -> Self {
+        self.instr(Instruction::Out)
+    }
+
+    /// Sets the current cell to zero.
+    pub fn zero(self) -> Self {
+        self.instr(Instruction::SetZero)
+    }
+
+    /// Appends a loop whose body `body` builds from an empty builder.
+    pub fn loop_<F>(mut self, body: F) -> Self
+        where F: FnOnce(Builder) -> Builder
+    {
+        let body = body(Builder::new()).build();
+        match rules::rewrite(&body) {
+            Some((_, instr, Action::Replace)) => self.linker.push(Statement::Instr(instr)),
+            Some((_, instr, Action::Prefix)) => {
+                self.linker.push(Statement::Instr(instr));
+                self.linker.push(Statement::Loop(body.into()));
+            }
+            None => self.linker.push(Statement::Loop(body.into())),
+        }
+        self
+    }
+
+    /// Appends a compiled fragment.
+    pub fn fragment(mut self, fragment: &Program) -> Self {
+        self.linker = self.linker.append(fragment);
+        self
+    }
+
+    /// The program built.
+    pub fn build(self) -> Box<Program> {
+        self.linker.into_program()
+    }
+
+    /// The program built, analyzed for sharing.
+    pub fn share(self) -> Arc<ProgramData> {
+        self.linker.link()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use traits::*;
+
+    fn peephole(source: &[u8]) -> Box<Program> {
+        ast::parse_program(source).unwrap().peephole_compile()
+    }
+
+    #[test]
+    fn builds_what_the_compiler_makes_of_the_source() {
+        let built = Builder::new()
+            .add(3).sub(1).right(2).right(1)
+            .loop_(|b| b.sub(1).right(1).add(1).left(1))
+            .loop_(|b| b.output())
+            .loop_(|b| b.add(1).loop_(|b| b.sub(1)))
+            .build();
+        assert_eq!(built, peephole(b"++>>>[->+<][.][+[-]]"));
+    }
+
+    #[test]
+    fn built_programs_run_on_other_backends() {
+        let program = Builder::new()
+            .add(8).loop_(|b| b.sub(1).right(1).add(8).left(1))
+            .right(1).add(1).output()
+            .fragment(&peephole(b"+."))
+            .build();
+        let expected = Ok(b"AB".to_vec());
+        assert_eq!(program.interpret_memory(None, b""), expected);
+        assert_eq!(program.bytecode_compile().interpret_memory(None, b""), expected);
+        assert_eq!(program.threaded_compile().interpret_memory(None, b""), expected);
+    }
+}
@@ -1,11 +1,18 @@
+use std::sync::Arc;
+
+use std::mem;
+
 use dynasmrt::x64::Assembler;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi};
 
 use super::*;
-use super::analysis::{BoundsAnalysis, AbstractInterpreter, NoAnalysis};
+use analysis::{self, BoundsAnalysis, AbstractInterpreter, NoAnalysis};
 use common::Count;
-use peephole;
+use options::CompileOptions;
+use peephole::{self, offsets, ProgramData};
 use rts;
+use sanitizer::Access;
+use traits::IntoUsize;
 
 /// Program forms that can be JIT compiled.
 pub trait JitCompilable {
@@ -17,27 +24,79 @@ pub trait JitCompilable {
     fn jit_compile(&self, checked: bool) -> Program {
         self.with_peephole(|ast| compile(ast, checked))
     }
+
+    /// JIT compile the given program with the given options.
+    fn jit_compile_with_options(&self, options: &CompileOptions) -> Program {
+        self.with_peephole(|ast| compile_with_options(ast, options))
+    }
 }
 
-dynasm!(asm
-    ; .alias pointer, r12
-    ; .alias mem_start, r13
-    ; .alias mem_limit, r14
-    ; .alias rts, r15
-);
+// Register convention. The machine state lives in callee-saved registers for the whole run,
+// so RTS calls, which follow the Win64 ABI, never need to save or reload it:
+//
+//  - `pointer`, `mem_start`, `mem_limit`, `rts` and `rts_table` (r12–r15, rbx) are set up
+//    by the prologue and only `pointer` changes afterwards;
+//  - `rsi` counts safepoints, and `steps` (rdi) counts steps in code compiled with
+//    accounting, which `->finish` stores in the RTS table;
+//  - `rax`, `rcx`, `rdx`, `r8`, `r9` and `r11` are scratch, and do not survive `rts_call`,
+//    which is the only place code calls out. `check_access` preserves `rax` itself, and the
+//    cell cache in `r11b` is written back, or dropped when input will overwrite it, first.
+macro_rules! emit {
+    ($asm:expr ; $($code:tt)*) => {
+        dynasm!($asm
+            ; .arch x64
+            ; .alias pointer, r12
+            ; .alias mem_start, r13
+            ; .alias mem_limit, r14
+            ; .alias rts, r15
+            ; .alias rts_table, rbx
+            ; .alias steps, rdi
+            ; $($code)*
+        )
+    };
+}
 
 /// Compiles peephole-optimized AST to x64 machine code.
 ///
 /// Uses the `dynasmrt` assembler
 pub fn compile(program: &peephole::Program, checked: bool) -> Program {
-    if checked {
-        let mut compiler = Compiler::<AbstractInterpreter>::new(program, true);
+    compile_with_options(program, &CompileOptions { checked, ..CompileOptions::default() })
+}
+
+/// Compiles peephole-optimized AST to x64 machine code with the given options.
+///
+/// Speculative code keeps a copy of the program to deoptimize into; use
+/// [`compile_shared`](fn.compile_shared.html) to have it share one instead.
+pub fn compile_with_options(program: &peephole::Program, options: &CompileOptions) -> Program {
+    let source = if options.speculate && options.checked {
+        Some(ProgramData::new(program.to_vec().into_boxed_slice()))
+    } else {
+        None
+    };
+    compile_source(program, options, source)
+}
+
+/// Compiles a shared program to x64 machine code with the given options. Speculative code
+/// refers to `program` rather than copying it.
+pub fn compile_shared(program: &Arc<ProgramData>, options: &CompileOptions) -> Program {
+    let source = if options.speculate && options.checked {
+        Some(Arc::clone(program))
+    } else {
+        None
+    };
+    compile_source(program, options, source)
+}
+
+fn compile_source(program: &peephole::Program, options: &CompileOptions,
+                  source: Option<Arc<ProgramData>>) -> Program {
+    if options.checked {
+        let mut compiler = Compiler::<AbstractInterpreter>::new(program, options);
         compiler.compile(program);
-        compiler.into_program()
+        compiler.into_program(source)
     } else {
-        let mut compiler = Compiler::<NoAnalysis>::new(program, false);
+        let mut compiler = Compiler::<NoAnalysis>::new(program, options);
         compiler.compile(program);
-        compiler.into_program()
+        compiler.into_program(source)
     }
 }
 
@@ -49,20 +108,67 @@ struct Compiler<B: BoundsAnalysis> {
     start: dynasmrt::AssemblyOffset,
     /// Whether we are emitting bounds checks.
     checked: bool,
+    /// Whether to call the RTS through the entry function’s table rather than by address.
+    deterministic: bool,
+    /// Whether to check every tape access with the RTS’s sanitizer.
+    sanitize: bool,
+    /// Whether to guard each loop iteration at a safepoint and elide the checks inside.
+    speculate: bool,
+    /// Whether to count steps and poll the RTS at safepoints.
+    accounting: bool,
+    /// Steps run by the code emitted since the count was last brought up to date.
+    pending_steps: u64,
     /// Abstract interpreter for bounds checking analysis.
     interpreter: B,
+    /// The code offset, from `start`, of each instruction, numbered as in bytecode.
+    code_offsets: Vec<usize>,
+    /// Where the code emitted so far leaves the current cell’s value.
+    cell: CellCache,
+    /// The safepoints’ bail-outs, by label and bytecode address, which are emitted after the
+    /// program so that the loops they guard stay dense.
+    cold: Vec<(DynamicLabel, usize)>,
+    /// The polls of code compiled with accounting, by label, the label to go back to and the
+    /// bytecode address of the safepoint, which are emitted after the program too.
+    polls: Vec<(DynamicLabel, DynamicLabel, usize)>,
+    /// The replays of straight-line blocks whose reach is off the tape, by label, the label to
+    /// go back to and the extremes to check, which are emitted after the program too.
+    replays: Vec<(DynamicLabel, DynamicLabel, Vec<isize>)>,
+}
+
+/// Where the current cell’s value lives at some point in the generated code.
+///
+/// Statements between loops, moves and I/O work on the cached value, and `spill_cell` writes
+/// it back before anything that reads the tape directly or clobbers `r11`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CellCache {
+    /// Only in memory.
+    Memory,
+    /// In `r11b`, and also in memory unless `dirty`.
+    Register { dirty: bool },
+    /// Known at compile time, and also in memory unless `dirty`.
+    Constant { value: u8, dirty: bool },
 }
 
 impl<B: BoundsAnalysis> Compiler<B> {
-    fn new(program: &peephole::Program, checked: bool) -> Self {
+    fn new(program: &peephole::Program, options: &CompileOptions) -> Self {
         let asm = Assembler::new().expect("Could not create assembler");
         let start = asm.offset();
 
         let mut result = Compiler {
-            asm: asm,
-            start: start,
-            checked: checked,
+            asm,
+            start,
+            checked: options.checked,
+            deterministic: options.deterministic,
+            sanitize: options.sanitize,
+            speculate: options.speculate && options.checked,
+            accounting: options.accounting,
+            pending_steps: 0,
             interpreter: B::new(program),
+            code_offsets: Vec::new(),
+            cell: CellCache::Memory,
+            cold: Vec::new(),
+            polls: Vec::new(),
+            replays: Vec::new(),
         };
 
         result.emit_prologue();
@@ -70,33 +176,56 @@ impl<B: BoundsAnalysis> Compiler<B> {
         result
     }
 
-    fn into_program(mut self) -> Program {
+    fn into_program(mut self, source: Option<Arc<ProgramData>>) -> Program {
+        self.spill_cell();
+        self.count_steps();
+        self.mark_instruction();
         self.emit_epilogue();
 
         Program {
             code: self.asm.finalize().unwrap(),
             start: self.start,
+            sanitize: self.sanitize,
+            accounting: self.accounting,
+            code_offsets: self.code_offsets.into_boxed_slice(),
+            source,
         }
     }
 
+    /// Records where the code for the next instruction begins.
+    fn mark_instruction(&mut self) {
+        let offset = self.asm.offset().0 - self.start.0;
+        self.code_offsets.push(offset);
+    }
+
     fn emit_prologue(&mut self) {
-        dynasm!(self.asm
+        emit!(self.asm
+            ; push rbx
             ; push r12
             ; push r13
             ; push r14
             ; push r15
+            ; push rsi
+            ; push rdi
+            ; xor rsi, rsi          // counts safepoints
+            ; xor steps, steps
             ; mov pointer, rcx      // first argument
             ; mov mem_start, rcx
             ; mov mem_limit, rcx
             ; add mem_limit, rdx    // second argument
             ; mov rts, r8           // third argument
+            ; mov rts_table, r9     // fourth argument
+            ; add pointer, QWORD [rts_table + RTS_POINTER_SLOT]
         );
     }
 
+    // Everything after the normal exit is cold: the bail-outs, the polls and replays, and the
+    // error exits that failed bounds checks jump to.
     fn emit_epilogue(&mut self) {
-        dynasm!(self.asm
+        emit!(self.asm
             ; mov rax, rts::OKAY as i32
             ; jmp ->finish
+            ;; self.emit_cold()
 
             ; ->underflow:
             ; mov rax, rts::UNDERFLOW as i32
@@ -104,31 +233,177 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
             ; ->overflow:
             ; mov rax, rts::OVERFLOW as i32
+            ; jmp ->finish
+
+            ; ->poisoned:
+            ; mov rax, rts::POISONED as i32
+            ; jmp ->finish
+
+            ; ->interrupted:
+            ; mov rax, rts::INTERRUPTED as i32
+            ; jmp ->finish
+
+            // Expects the safepoint’s bytecode address in rdx.
+            ; ->deopt:
+            ; mov r8, pointer
+            ; sub r8, mem_start
+            ; mov r9, rsi
+            ;; self.rts_call(rts::RtsState::bail_out as *const () as _, RTS_DEOPT_SLOT)
 
             ; ->finish:
+            ; mov QWORD [rts_table + RTS_STEPS_SLOT], steps
+            ; pop rdi
+            ; pop rsi
             ; pop r15
             ; pop r14
             ; pop r13
             ; pop r12
+            ; pop rbx
             ; ret
         );
     }
 
     fn compile(&mut self, program: &[peephole::Statement]) {
-        for stm in program {
-            self.compile_statement(stm);
+        let mut index = 0;
+
+        while index < program.len() {
+            // The sanitizer must see every access before the moves’ bounds checks.
+            let block = if self.sanitize { None } else { offsets::block(&program[index ..]) };
+
+            match block {
+                Some(ref block) if fits_displacement(block) => {
+                    let length = block.offsets.len();
+                    self.compile_offset_block(&program[index .. index + length], block);
+                    index += length;
+                }
+
+                _ => {
+                    self.compile_statement(&program[index]);
+                    index += 1;
+                }
+            }
         }
     }
 
+    /// Compiles a straight-line block by checking its reach once, updating its cells at
+    /// offsets from the starting pointer, and moving the pointer once at the end.
+    fn compile_offset_block(&mut self, body: &[peephole::Statement], block: &offsets::Block) {
+        use peephole::Statement::*;
+        use common::Instruction::*;
+
+        // The analysis sees the block’s moves as they come, but its reach is checked up front.
+        let proved_right = self.interpreter.check_right(block.max as Count);
+        let proved_left = self.interpreter.check_left(-block.min as Count);
+
+        self.spill_cell();
+        self.count_steps();
+        self.pending_steps += body.len() as u64;
+
+        for (index, (statement, &offset)) in body.iter().zip(&block.offsets).enumerate() {
+            self.mark_instruction();
+
+            if index == 0 {
+                self.check_reach(block, proved_right, proved_left);
+            }
+
+            let offset = offset as i32;
+            match *statement {
+                Instr(Right(count)) => { self.interpreter.move_right(count); }
+                Instr(Left(count)) => { self.interpreter.move_left(count); }
+
+                Instr(Add(count)) => {
+                    emit!(self.asm
+                        ; add BYTE [pointer + offset], count as i8
+                    );
+                }
+
+                Instr(SetZero) => {
+                    emit!(self.asm
+                        ; mov BYTE [pointer + offset], 0
+                    );
+                }
+
+                _ => unreachable!("not in a straight-line block"),
+            }
+        }
+
+        if block.delta > 0 {
+            emit!(self.asm
+                ;; self.load_constant(block.delta as Count)
+                ; add pointer, rax
+            );
+        } else if block.delta < 0 {
+            emit!(self.asm
+                ;; self.load_constant(-block.delta as Count)
+                ; sub pointer, rax
+            );
+        }
+    }
+
+    /// Checks both ends of a block’s reach, and if either is off the tape, jumps out of line to
+    /// replay its extremes in turn, so that it fails at the end moving step by step would.
+    fn check_reach(&mut self, block: &offsets::Block, proved_right: bool, proved_left: bool) {
+        let right = self.checked && !proved_right && block.max > 0;
+        let left = self.checked && !proved_left && block.min < 0;
+        if !right && !left {
+            return;
+        }
+
+        let replay = self.asm.new_dynamic_label();
+        let resume = self.asm.new_dynamic_label();
+        let extremes = block.extremes.iter().cloned()
+            .filter(|&extreme| if extreme > 0 { right } else { left })
+            .collect();
+        self.replays.push((replay, resume, extremes));
+
+        if right {
+            emit!(self.asm
+                ;; self.load_constant(block.max as Count)
+                ; mov rcx, mem_limit
+                ; sub rcx, pointer
+                ; cmp rcx, rax
+                ; jle =>replay
+            );
+        }
+
+        if left {
+            emit!(self.asm
+                ;; self.load_constant(-block.min as Count)
+                ; mov rcx, pointer
+                ; sub rcx, mem_start
+                ; cmp rcx, rax
+                ; jl =>replay
+            );
+        }
+
+        emit!(self.asm
+            ; =>resume
+        );
+    }
+
     fn compile_statement(&mut self, stm: &peephole::Statement) {
         use peephole::Statement::*;
         use common::Instruction::*;
 
+        self.mark_instruction();
+
+        // A loop counts its own jumps. Instructions whose code can branch bring the count up
+        // to date, counting themselves, before it.
+        match *stm {
+            Instr(Add(_)) | Instr(SetZero) => self.pending_steps += 1,
+            Loop(_) => (),
+            Instr(_) => {
+                self.pending_steps += 1;
+                self.count_steps();
+            }
+        }
+
         match *stm {
             Instr(Right(count)) => {
                 let proved = self.interpreter.move_right(count);
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
                     ;; self.load_pos_offset(count, proved)
                     ; add pointer, rax
                 );
@@ -136,49 +411,229 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
             Instr(Left(count)) => {
                 let proved = self.interpreter.move_left(count);
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
                     ;; self.load_neg_offset(count, proved)
                     ; sub pointer, rax
                 );
             }
 
-            Instr(Add(count)) => {
-                dynasm!(self.asm
-                    ; add [pointer], BYTE count as i8
+            Instr(Add(count)) => match self.cell {
+                CellCache::Memory => {
+                    emit!(self.asm
+                        ;; self.check_access(Access::Read, false)
+                        ;; self.check_access(Access::Write, false)
+                        ; add [pointer], BYTE count as i8
+                    );
+                }
+
+                CellCache::Register { .. } => {
+                    emit!(self.asm
+                        ; add r11b, BYTE count as i8
+                    );
+                    self.cell = CellCache::Register { dirty: true };
+                }
+
+                CellCache::Constant { value, .. } => {
+                    let value = value.wrapping_add(count);
+                    self.cell = CellCache::Constant { value, dirty: true };
+                }
+            },
+
+            // The sanitizer’s calls clobber `r11` and must see every access, so it gets the
+            // uncached code.
+            Instr(In) if self.sanitize => {
+                emit!(self.asm
+                    ;; self.check_access(Access::Write, false)
+                    ;; self.rts_call(rts::RtsState::read as *const () as _, RTS_READ_SLOT)
+                    ; mov [pointer], al
                 );
+                self.emit_io_poll();
             }
 
+            // The input overwrites the cell, so a pending write-back is dead.
             Instr(In) => {
-                dynasm!(self.asm
-                    ;; self.rts_call(rts::RtsState::read as _)
-                    ; mov [pointer], al
+                emit!(self.asm
+                    ;; self.rts_call(rts::RtsState::read as *const () as _, RTS_READ_SLOT)
+                    ; mov r11b, al
                 );
+
+                self.cell = CellCache::Register { dirty: true };
+                self.emit_io_poll();
             }
 
             Instr(Out) => {
-                dynasm!(self.asm
-                    ; xor rdx, rdx
-                    ; mov dl, [pointer]
-                    ;; self.rts_call(rts::RtsState::write as _)
+                self.flush_cell();
+
+                match self.cell {
+                    CellCache::Memory => {
+                        emit!(self.asm
+                            ;; self.check_access(Access::Read, false)
+                            ; movzx edx, BYTE [pointer]
+                        );
+                    }
+
+                    CellCache::Register { .. } => {
+                        emit!(self.asm
+                            ; movzx edx, r11b
+                        );
+                        self.cell = CellCache::Memory;
+                    }
+
+                    CellCache::Constant { value, .. } => {
+                        emit!(self.asm
+                            ; mov edx, value as i32
+                        );
+                    }
+                }
+
+                self.rts_call(rts::RtsState::write as *const () as _, RTS_WRITE_SLOT);
+                self.emit_io_poll();
+            }
+
+            // The sanitizer leaves the work to the loop, which it checks step by step.
+            Instr(DivMod) if self.sanitize => (),
+
+            // Does the following loop’s work in place when `State::div_mod` would, keeping
+            // n, d, r and q in `al`, `cl`, `dl` and `r8b`.
+            Instr(DivMod) => {
+                self.spill_cell();
+
+                emit!(self.asm
+                    ; mov rax, mem_limit
+                    ; sub rax, pointer
+                    ; cmp rax, 6
+                    ; jl >done
+                    ; cmp BYTE [pointer], 0
+                    ; je >done
+                    ; cmp BYTE [pointer + 4], 0
+                    ; jne >done
+                    ; cmp BYTE [pointer + 5], 0
+                    ; jne >done
+                    ; mov al, BYTE [pointer]
+                    ; mov cl, BYTE [pointer + 1]
+                    ; mov dl, BYTE [pointer + 2]
+                    ; mov r8b, BYTE [pointer + 3]
+                    // With `d + r == 1` and `0 < d <= n`, the loop walks off to the right once
+                    // `d` reaches zero, so leave it to the loop.
+                    ; mov r9b, cl
+                    ; add r9b, dl
+                    ; cmp r9b, 1
+                    ; jne >again
+                    ; test cl, cl
+                    ; jz >again
+                    ; cmp cl, al
+                    ; jbe >done
+                    ; again:
+                    ; dec cl
+                    ; jz >wrap
+                    ; inc dl
+                    ; jmp >next
+                    ; wrap:
+                    ; mov cl, dl
+                    ; inc cl
+                    ; xor edx, edx
+                    ; inc r8b
+                    ; next:
+                    ; dec al
+                    ; jnz <again
+                    ; mov BYTE [pointer], al
+                    ; mov BYTE [pointer + 1], cl
+                    ; mov BYTE [pointer + 2], dl
+                    ; mov BYTE [pointer + 3], r8b
+                    ; done:
                 );
             }
 
-            Instr(SetZero) => {
-                dynasm!(self.asm
+            // Sanitized code, and a stride too long for an immediate, leave the work to the loop.
+            Instr(IndexRight(skip)) | Instr(IndexLeft(skip))
+                if self.sanitize || !fits_immediate(skip) => (),
+
+            // Does the following loop’s work in place when `State::index_right` would, checking
+            // the cells from `rdx` up to the landing cell `r8`.
+            Instr(IndexRight(skip)) => {
+                self.interpreter.reset_right();
+                self.spill_cell();
+
+                emit!(self.asm
+                    ; movzx eax, BYTE [pointer]
+                    ; test eax, eax
+                    ; jz >done
+                    ; imul rax, rax, skip as i32
+                    ; mov rcx, mem_limit
+                    ; sub rcx, pointer
+                    ; cmp rax, rcx
+                    ; jae >done
+                    ; lea rdx, [pointer + skip as i32]
+                    ; lea r8, [pointer + rax]
+                    ; scan:
+                    ; cmp BYTE [rdx], 0
+                    ; jne >done
+                    ; add rdx, skip as i32
+                    ; cmp rdx, r8
+                    ; jbe <scan
+                    ; mov BYTE [pointer], 0
+                    ; mov pointer, r8
+                    ; done:
+                );
+            }
+
+            Instr(IndexLeft(skip)) => {
+                self.interpreter.reset_left();
+                self.spill_cell();
+
+                emit!(self.asm
+                    ; movzx eax, BYTE [pointer]
+                    ; test eax, eax
+                    ; jz >done
+                    ; imul rax, rax, skip as i32
+                    ; mov rcx, pointer
+                    ; sub rcx, mem_start
+                    ; cmp rax, rcx
+                    ; ja >done
+                    ; mov rdx, pointer
+                    ; sub rdx, skip as i32
+                    ; mov r8, pointer
+                    ; sub r8, rax
+                    ; scan:
+                    ; cmp BYTE [rdx], 0
+                    ; jne >done
+                    ; sub rdx, skip as i32
+                    ; cmp rdx, r8
+                    ; jae <scan
+                    ; mov BYTE [pointer], 0
+                    ; mov pointer, r8
+                    ; done:
+                );
+            }
+
+            Instr(SetZero) if self.sanitize => {
+                emit!(self.asm
+                    ;; self.check_access(Access::Write, false)
                     ; mov BYTE [pointer], 0
                 )
             }
 
+            Instr(SetZero) => {
+                self.cell = match self.cell {
+                    CellCache::Constant { value: 0, dirty } =>
+                        CellCache::Constant { value: 0, dirty },
+                    _ => CellCache::Constant { value: 0, dirty: true },
+                };
+            }
+
             Instr(FindZeroRight(skip)) => {
                 self.interpreter.reset_right();
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
                     ; jmp >end_loop
                     ; begin_loop:
                     ;; self.load_pos_offset(skip, false)
                     ; add pointer, rax
                     ; end_loop:
+                    ;; self.check_access(Access::Read, false)
                     ; cmp BYTE [pointer], 0
                     ; jnz <begin_loop
                 )
@@ -186,13 +641,15 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
             Instr(FindZeroLeft(skip)) => {
                 self.interpreter.reset_left();
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
                     ; jmp >end_loop
                     ; begin_loop:
                     ;; self.load_neg_offset(skip, false)
                     ; sub pointer, rax
                     ; end_loop:
+                    ;; self.check_access(Access::Read, false)
                     ; cmp BYTE [pointer], 0
                     ; jnz <begin_loop
                 )
@@ -200,11 +657,14 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
             Instr(OffsetAddRight(offset)) => {
                 let proved = self.interpreter.check_right(offset);
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
+                    ;; self.check_access(Access::Read, false)
                     ; cmp BYTE [pointer], 0
                     ; jz >skip
                     ;; self.load_pos_offset(offset, proved)
+                    ;; self.check_offset_add()
                     ; mov cl, BYTE [pointer]
                     ; mov BYTE [pointer], 0
                     ; add BYTE [pointer + rax], cl
@@ -214,14 +674,17 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
             Instr(OffsetAddLeft(offset)) => {
                 let proved = self.interpreter.check_left(offset);
+                self.spill_cell();
 
-                dynasm!(self.asm
+                emit!(self.asm
+                    ;; self.check_access(Access::Read, false)
                     ; cmp BYTE [pointer], 0
                     ; jz >skip
                     ;; self.load_neg_offset(offset, proved)
+                    ; neg rax
+                    ;; self.check_offset_add()
                     ; mov cl, BYTE [pointer]
                     ; mov BYTE [pointer], 0
-                    ; neg rax
                     ; add BYTE [pointer + rax], cl
                     ; skip:
                 );
@@ -236,11 +699,41 @@ impl<B: BoundsAnalysis> Compiler<B> {
 
                 self.interpreter.enter_loop(body);
 
-                dynasm!(self.asm
+                // With speculation, a safepoint before each iteration checks that the whole
+                // iteration stays on the tape, so the analysis can assume it.
+                let excursion = if self.speculate { Some(analysis::excursion(body)) } else { None };
+                if let Some((left, right)) = excursion {
+                    self.interpreter.assume(left, right);
+                }
+
+                // Both jumps join paths, so the cell is in memory at each of them. Reaching
+                // `end_label` runs a step, the `[` on entering or the `]` after each iteration,
+                // which is counted after the poll, as the safepoint is before the jump.
+                emit!(self.asm
+                    ;; self.spill_cell()
+                    ;; self.count_steps()
                     ; jmp =>end_label
                     ; =>begin_label
                     ;; self.compile(body)
+                    ;; self.spill_cell()
+                    ;; self.count_steps()
+                    ;; self.mark_instruction()
                     ; =>end_label
+                );
+                let pc = self.code_offsets.len() - 1;
+                self.emit_poll(pc);
+                if self.accounting {
+                    emit!(self.asm
+                        ; inc steps
+                    );
+                }
+
+                if let Some((left, right)) = excursion {
+                    self.emit_safepoint(pc, left, right);
+                }
+
+                emit!(self.asm
+                    ;; self.check_access(Access::Read, false)
                     ; cmp BYTE [pointer], 0
                     ; jnz =>begin_label
                 );
@@ -250,24 +743,204 @@ impl<B: BoundsAnalysis> Compiler<B> {
         }
     }
 
-    fn rts_call(&mut self, fun: i64) {
-        dynasm!(self.asm
-            ; mov rax, QWORD fun
+    /// Writes a cached cell value back to memory, if it is not there already.
+    fn flush_cell(&mut self) {
+        match self.cell {
+            CellCache::Register { dirty: true } => {
+                emit!(self.asm
+                    ; mov [pointer], r11b
+                );
+                self.cell = CellCache::Register { dirty: false };
+            }
+
+            CellCache::Constant { value, dirty: true } => {
+                emit!(self.asm
+                    ; mov BYTE [pointer], value as i8
+                );
+                self.cell = CellCache::Constant { value, dirty: false };
+            }
+
+            _ => (),
+        }
+    }
+
+    /// Writes back and then forgets a cached cell value, leaving the cell only in memory.
+    fn spill_cell(&mut self) {
+        self.flush_cell();
+        self.cell = CellCache::Memory;
+    }
+
+    /// Emits a safepoint for the loop jump at bytecode address `pc`, which bails out through
+    /// `->deopt` unless the pointer is at least `left` cells from the start of memory and
+    /// more than `right` from the end. The bail-out itself is emitted out of line.
+    fn emit_safepoint(&mut self, pc: usize, left: usize, right: usize) {
+        let bail = self.asm.new_dynamic_label();
+        self.cold.push((bail, pc));
+
+        emit!(self.asm
+            ; inc rsi
+            ;; self.load_constant(left as Count)
+            ; mov rcx, pointer
+            ; sub rcx, mem_start
+            ; cmp rcx, rax
+            ; jl =>bail
+            ;; self.load_constant(right as Count)
+            ; mov rcx, mem_limit
+            ; sub rcx, pointer
+            ; cmp rcx, rax
+            ; jle =>bail
+        );
+    }
+
+    /// Adds the steps run since the count was last brought up to date.
+    fn count_steps(&mut self) {
+        if !self.accounting { return; }
+
+        while self.pending_steps > 0 {
+            let count = self.pending_steps.min(i32::MAX as u64);
+            emit!(self.asm
+                ; add steps, DWORD count as i32
+            );
+            self.pending_steps -= count;
+        }
+    }
+
+    /// With accounting, emits a safepoint before bytecode address `pc`, which calls the RTS’s
+    /// `poll`, out of line, if the count has reached the point in the RTS table. Expects the
+    /// cell in memory and the count up to date.
+    fn emit_poll(&mut self, pc: usize) {
+        if !self.accounting { return; }
+
+        let poll = self.asm.new_dynamic_label();
+        let resume = self.asm.new_dynamic_label();
+        self.polls.push((poll, resume, pc));
+
+        emit!(self.asm
+            ; cmp steps, QWORD [rts_table + RTS_POLL_AT_SLOT]
+            ; jae =>poll
+            ; =>resume
+        );
+    }
+
+    /// With accounting, emits the safepoint after an I/O instruction, whose code has just been
+    /// emitted and counted.
+    fn emit_io_poll(&mut self) {
+        if !self.accounting { return; }
+
+        self.spill_cell();
+        let pc = self.code_offsets.len();
+        self.emit_poll(pc);
+    }
+
+    /// Emits the safepoints’ bail-outs, each passing its bytecode address to `->deopt`, the
+    /// polls, each storing where to poll next or stopping the run, and the blocks’ replays.
+    fn emit_cold(&mut self) {
+        for (bail, pc) in mem::take(&mut self.cold) {
+            emit!(self.asm
+                ; =>bail
+                ; mov rdx, QWORD pc as i64
+                ; jmp ->deopt
+            );
+        }
+
+        for (poll, resume, pc) in mem::take(&mut self.polls) {
+            emit!(self.asm
+                ; =>poll
+                ; mov rdx, steps
+                ; mov r8, QWORD pc as i64
+                ; mov r9, pointer
+                ; sub r9, mem_start
+                ;; self.rts_call(rts::RtsState::poll as *const () as _, RTS_POLL_SLOT)
+                ; test rax, rax
+                ; jz ->interrupted
+                ; mov QWORD [rts_table + RTS_POLL_AT_SLOT], rax
+                ; jmp =>resume
+            );
+        }
+
+        for (replay, resume, extremes) in mem::take(&mut self.replays) {
+            emit!(self.asm
+                ; =>replay
+            );
+            for extreme in extremes {
+                if extreme > 0 {
+                    self.load_pos_offset(extreme as Count, false);
+                } else {
+                    self.load_neg_offset(-extreme as Count, false);
+                }
+            }
+            emit!(self.asm
+                ; jmp =>resume
+            );
+        }
+    }
+
+    /// Calls an RTS function, either by its address or, in deterministic mode, through the
+    /// given byte offset into the RTS table. Clobbers every scratch register, but none of the
+    /// machine state.
+    fn rts_call(&mut self, fun: i64, slot: i32) {
+        if self.deterministic {
+            emit!(self.asm
+                ; mov rax, QWORD [rts_table + slot]
+            );
+        } else {
+            emit!(self.asm
+                ; mov rax, QWORD fun
+            );
+        }
+
+        emit!(self.asm
             ; mov rcx, rts
-            ; sub rsp, BYTE 0x28
+            ; sub rsp, BYTE 0x20
             ; call rax
-            ; add rsp, BYTE 0x28
+            ; add rsp, BYTE 0x20
         );
     }
 
+    /// When sanitizing, checks an access to `[pointer]`, or to `[pointer + rax]` if `indexed`,
+    /// jumping to `->poisoned` if the sanitizer refuses it. Preserves `rax`.
+    fn check_access(&mut self, access: Access, indexed: bool) {
+        if !self.sanitize { return; }
+
+        emit!(self.asm
+            ; push rax
+            ; push rax              // keeps the stack 16-byte aligned
+            ; mov rdx, pointer
+            ; sub rdx, mem_start
+        );
+
+        if indexed {
+            emit!(self.asm
+                ; add rdx, rax
+            );
+        }
+
+        emit!(self.asm
+            ; mov r8, (access == Access::Write) as i32
+            ;; self.rts_call(rts::RtsState::check as *const () as _, RTS_CHECK_SLOT)
+            ; mov rcx, rax
+            ; pop rax
+            ; pop rax
+            ; test rcx, rcx
+            ; jnz ->poisoned
+        );
+    }
+
+    /// Checks the accesses made by an offset add whose (signed) offset is in `rax`.
+    fn check_offset_add(&mut self) {
+        self.check_access(Access::Write, false);
+        self.check_access(Access::Read, true);
+        self.check_access(Access::Write, true);
+    }
+
     #[inline]
     fn load_constant(&mut self, count: Count) {
         if count as i32 as Count == count {
-            dynasm!(self.asm
+            emit!(self.asm
                 ; mov rax, DWORD count as i32
             );
         } else {
-            dynasm!(self.asm
+            emit!(self.asm
                 ; mov rax, QWORD count as i64
             );
         }
@@ -278,7 +951,7 @@ impl<B: BoundsAnalysis> Compiler<B> {
         self.load_constant(offset);
 
         if self.checked && !proved {
-            dynasm!(self.asm
+            emit!(self.asm
                 ; mov rcx, mem_limit
                 ; sub rcx, pointer
                 ; cmp rcx, rax
@@ -292,7 +965,7 @@ impl<B: BoundsAnalysis> Compiler<B> {
         self.load_constant(offset);
 
         if self.checked && !proved {
-            dynasm!(self.asm
+            emit!(self.asm
                 ; mov rcx, pointer
                 ; sub rcx, mem_start
                 ; cmp rcx, rax
@@ -302,6 +975,16 @@ impl<B: BoundsAnalysis> Compiler<B> {
     }
 }
 
+/// Whether a stride fits in an instruction’s 32-bit immediate.
+fn fits_immediate(skip: Count) -> bool {
+    skip.into_usize() <= i32::MAX as usize
+}
+
+/// Whether a block’s offsets all fit in an instruction’s displacement.
+fn fits_displacement(block: &offsets::Block) -> bool {
+    block.min >= i32::MIN as isize && block.max <= i32::MAX as isize
+}
+
 impl JitCompilable for peephole::Program {
     fn with_peephole<F, R>(&self, k: F) -> R
         where F: FnOnce(&peephole::Program) -> R
@@ -1,28 +1,56 @@
-//! Just-in-time compiles Brainfuck AST to x64 machine code (`--features jit`, nightly only)
+//! Just-in-time compiles Brainfuck AST to x64 machine code (`--features jit`)
 //!
-//! This uses the [`dynasm`](https://crates.io/search?q=dynasm) crate to generate x86-64
+//! This uses the [`dynasm`](https://crates.io/crates/dynasm) crate to generate x86-64
 //! machine code from peephole-optimized AST. This is currently the fastest implementation,
-//! but it is available only on nightly Rust because `dynasm` uses a plugin.
+//! and it builds only for x86-64 targets.
 //!
 //! In the `bfi` interpreter, this pass is enabled by default if compiled in.
 //! To go even faster, pass the `--unchecked` flag to the `bfi` interpreter to disable
 //! memory bounds checking in the generated code. Note that this runs Brainfuck in
 //! unsafe mode, which means that programs that move the pointer outside the allocated
 //! memory will access and possibly overwrite arbitrary memory locations.
+//!
+//! Compiled code starts with the pointer wherever the state has it, so a run can move into
+//! native code partway through: [`interpret_tiered`](fn.interpret_tiered.html) interprets
+//! bytecode until a loop is hot, then enters code from [`compile_osr`](fn.compile_osr.html) in
+//! the middle of that loop.
+//!
+//! Code that runs only when something goes wrong—the bail-outs of speculative safepoints and
+//! the exits for failed bounds checks—is laid out after the program, so the hot loops hold
+//! only the branches to it.
+//!
+//! Code compiled with [accounting](../options/struct.CompileOptions.html#structfield.accounting)
+//! counts the steps the bytecode interpreter would, keeping the count in a register and adding
+//! each straight-line stretch’s steps at once. At its safepoints, each loop jump and the
+//! instruction after each I/O, it compares the count with where the run-time system asked to
+//! be polled next, so that [`run_metered`](struct.Program.html#method.run_metered) can stop a
+//! run that is out of fuel, cancelled or past its deadline, and report progress with its
+//! [`RunStats`](../run_stats/struct.RunStats.html), as the interpreter does. The counts match
+//! the interpreter’s for runs that finish; a run that fails may be a few steps out, as a
+//! block’s steps are counted before its bounds checks. A run stopped at a safepoint leaves the
+//! tape as the interpreter would have at the [`Safepoint`](../rts/struct.Safepoint.html)’s
+//! bytecode address, so that it can go on from there.
 
-mod loop_balance;
-mod analysis;
 mod compiler;
+mod tiered;
 
-pub use self::compiler::{compile, JitCompilable};
+pub use self::compiler::{compile, compile_shared, compile_with_options, JitCompilable};
+pub use self::tiered::{compile_osr, interpret_tiered};
 
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use std::mem;
+use std::sync::Arc;
+use std::time::Instant;
 
 use dynasmrt;
 
-use common::{BfResult, Error};
-use rts::{self, RtsState};
+use adapters::BufferedWriter;
+use common::BfResult;
+use options::RunOptions;
+use peephole::{self, continuation::continuation, ProgramData};
+use rts::{Deopt, Meter, RtsState, Safepoint};
+use run_stats::{self, RunStats};
+use sanitizer::Sanitizer;
 use state::State;
 use traits::Interpretable;
 
@@ -36,6 +64,13 @@ use traits::Interpretable;
 pub struct Program {
     code: dynasmrt::ExecutableBuffer,
     start: dynasmrt::AssemblyOffset,
+    sanitize: bool,
+    /// Whether the code counts its steps.
+    accounting: bool,
+    /// Where the code for each bytecode address begins, followed by where the epilogue begins.
+    code_offsets: Box<[usize]>,
+    /// For speculative code, the program compiled, for finishing runs that deoptimize.
+    source: Option<Arc<ProgramData>>,
 }
 
 /// The type of function that we will assemble and then call.
@@ -49,27 +84,247 @@ pub struct Program {
 /// `memory_size` – the amount of memory allocated, defaults to 30,000 bytes.
 ///
 /// `rts_state` – the state that the run-time system needs to do I/O.
+///
+/// `rts_table` – the addresses of the run-time system’s functions, used by code compiled in
+/// [deterministic mode](../options/struct.CompileOptions.html#structfield.deterministic).
+/// `RtsState::check` is only called by code compiled in
+/// [sanitize mode](../options/struct.CompileOptions.html#structfield.sanitize). The table ends
+/// with the pointer’s starting offset from `memory`, and then `RtsState::bail_out`, called by
+/// [speculative](../options/struct.CompileOptions.html#structfield.speculate) code. Code
+/// compiled with [accounting](../options/struct.CompileOptions.html#structfield.accounting)
+/// calls `RtsState::poll`, next, at safepoints where its count of steps reaches the slot
+/// after, and stores the new point there; every run leaves its count in the last slot.
 type EntryFunction<'a> = extern "win64" fn(memory: *mut u8,
                                            memory_size: u64,
-                                           rts_state: *mut RtsState<'a>) -> u64;
+                                           rts_state: *mut RtsState<'a>,
+                                           rts_table: *mut u64) -> u64;
 
-impl Interpretable for Program {
-    fn interpret_state<R: Read, W: Write>(&self, mut state: State,
-                                          mut input: R, mut output: W)
-                                          -> BfResult<()>
+/// The byte offset of `RtsState::read` in the RTS table.
+const RTS_READ_SLOT: i32 = 0;
+
+/// The byte offset of `RtsState::write` in the RTS table.
+const RTS_WRITE_SLOT: i32 = 8;
+
+/// The byte offset of `RtsState::check` in the RTS table.
+const RTS_CHECK_SLOT: i32 = 16;
+
+/// The byte offset of the pointer’s starting position in the RTS table.
+const RTS_POINTER_SLOT: i32 = 24;
+
+/// The byte offset of `RtsState::bail_out` in the RTS table.
+const RTS_DEOPT_SLOT: i32 = 32;
+
+/// The byte offset of `RtsState::poll` in the RTS table.
+const RTS_POLL_SLOT: i32 = 40;
+
+/// The byte offset of the step count to poll at next in the RTS table.
+const RTS_POLL_AT_SLOT: i32 = 48;
+
+/// The byte offset of the step count at the end of the run in the RTS table.
+const RTS_STEPS_SLOT: i32 = 56;
+
+impl Program {
+    /// The bytecode address of the instruction whose code includes the given offset from the
+    /// start of the compiled function, numbered as by
+    /// [`bytecode::compile`](../bytecode/fn.compile.html) so that a
+    /// [`SourceMap`](../source_map/struct.SourceMap.html) applies. Returns `None` for offsets in
+    /// the prologue or epilogue, which includes the cold code laid out after the program.
+    pub fn pc_at(&self, code_offset: usize) -> Option<usize> {
+        let (&end, starts) = self.code_offsets.split_last()?;
+        if code_offset >= end { return None; }
+
+        match starts.binary_search(&code_offset) {
+            // Instructions that emit no code share an offset with the next one.
+            Ok(mut pc) => {
+                while pc + 1 < starts.len() && starts[pc + 1] == code_offset { pc += 1; }
+                Some(pc)
+            }
+            Err(0) => None,
+            Err(next) => Some(next - 1),
+        }
+    }
+
+    /// Runs a program compiled in sanitize mode, leaving the sanitizer’s findings in
+    /// `sanitizer`, which must be as large as the state’s memory.
+    pub fn interpret_sanitized<R: Read, W: Write>(&self, mut state: State,
+                                                  mut input: R, mut output: W,
+                                                  sanitizer: &mut Sanitizer)
+                                                  -> BfResult<()>
+    {
+        let deopt = self.run(&mut state, &mut RtsState::with_sanitizer(&mut input, &mut output,
+                                                                       sanitizer))?;
+        match deopt {
+            Some(deopt) => self.resume(&mut state, deopt, input, output),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the program, returning where it deoptimized, if it did. Code compiled with
+    /// [`speculate`](../options/struct.CompileOptions.html#structfield.speculate) bails out to
+    /// the peephole interpreter, which finishes the run.
+    pub fn interpret_deopt<R: Read, W: Write>(&self, mut state: State,
+                                              mut input: R, mut output: W)
+                                              -> BfResult<Option<Deopt>>
+    {
+        let deopt = self.run(&mut state, &mut RtsState::new(&mut input, &mut output))?;
+        if let Some(deopt) = deopt {
+            self.resume(&mut state, deopt, input, output)?;
+        }
+        Ok(deopt)
+    }
+
+    /// Runs the program on the given state, leaving it as the program does, as for comparing
+    /// it with the interpreters’. A run that deoptimizes is finished in the peephole
+    /// interpreter on the same state.
+    pub fn run_state<R: Read, W: Write>(&self, state: &mut State, mut input: R, mut output: W)
+                                        -> BfResult<()>
+    {
+        let mut sanitizer = Sanitizer::new(if self.sanitize { state.capacity() } else { 0 });
+        let deopt = if self.sanitize {
+            self.run(state, &mut RtsState::with_sanitizer(&mut input, &mut output, &mut sanitizer))?
+        } else {
+            self.run(state, &mut RtsState::new(&mut input, &mut output))?
+        };
+
+        match deopt {
+            Some(deopt) => self.resume(state, deopt, input, output),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the program with the output buffering and input batching of the given options.
+    /// Their memory size is not used, since the state is given.
+    pub fn interpret_with_options<R: Read, W: Write>(&self, state: State, input: R, output: W,
+                                                     options: &RunOptions)
+                                                     -> BfResult<()>
+    {
+        self.interpret_metered(state, input, output, options, Meter::default()).0
+    }
+
+    /// Runs the program like [`interpret_with_options`](#method.interpret_with_options),
+    /// within the meter’s fuel and calling its progress callback, and measures the run.
+    ///
+    /// Only code compiled with
+    /// [accounting](../options/struct.CompileOptions.html#structfield.accounting) is metered
+    /// and knows its steps. A run that deoptimizes is finished unmetered, and its steps are
+    /// not known either.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Interrupted`](../common/enum.Error.html#variant.Interrupted) when the
+    /// meter stops the run; [`run_metered`](#method.run_metered) says why, and where.
+    pub fn interpret_metered<R: Read, W: Write>(&self, mut state: State, input: R, output: W,
+                                                options: &RunOptions, meter: Meter)
+                                                -> (BfResult<()>, RunStats)
+    {
+        let run = self.run_metered(&mut state, input, output, options, meter);
+        (run.result, run.stats)
+    }
+
+    /// Runs the program on the given state like
+    /// [`interpret_metered`](#method.interpret_metered), leaving the state as the run does.
+    /// A run the meter stops is left at a safepoint, from which the bytecode interpreter can
+    /// [go on](../bytecode/struct.Execution.html#method.resume).
+    pub fn run_metered<R: Read, W: Write>(&self, state: &mut State, mut input: R, mut output: W,
+                                          options: &RunOptions, meter: Meter) -> Metered
     {
-        let mut rts = RtsState::new(&mut input, &mut output);
+        let start = Instant::now();
+        let mut sanitizer = Sanitizer::new(if self.sanitize { state.capacity() } else { 0 });
+        let (result, mut stats, unread, stopped_at) = {
+            let mut rts = if self.sanitize {
+                RtsState::with_sanitizer(&mut input, &mut output, &mut sanitizer)
+            } else {
+                RtsState::new(&mut input, &mut output)
+            };
+            rts.set_run_options(options);
+            rts.set_meter(meter);
+            let result = self.run(state, &mut rts);
+            (result, rts.stats(), rts.take_unread(), rts.stopped_at())
+        };
+
+        let result = match result {
+            Ok(Some(deopt)) => {
+                let input = Cursor::new(unread).chain(input);
+                let output = BufferedWriter::new(output, options.buffering);
+                let (result, rest) = run_stats::measure(input, output, |input, output| {
+                    self.resume(state, deopt, input, output)
+                });
+                stats.steps = None;
+                stats.input_bytes += rest.input_bytes;
+                stats.output_bytes += rest.output_bytes;
+                result
+            }
+            Ok(None) => Ok(()),
+            Err(error) => Err(error),
+        };
+
+        stats.run_time = start.elapsed();
+        Metered { result, stats, stopped_at }
+    }
 
+    fn run(&self, state: &mut State, rts: &mut RtsState) -> BfResult<Option<Deopt>> {
         let f: EntryFunction = unsafe { mem::transmute(self.code.ptr(self.start)) };
+        let mut rts_table = [RtsState::read as *const () as u64,
+                             RtsState::write as *const () as u64,
+                             RtsState::check as *const () as u64,
+                             state.pointer() as u64,
+                             RtsState::bail_out as *const () as u64,
+                             RtsState::poll as *const () as u64,
+                             rts.first_poll(), 0];
 
-        let result = f(state.as_mut_ptr(), state.capacity() as u64, &mut rts);;
+        let result = f(state.as_mut_ptr(), state.capacity() as u64, rts,
+                       rts_table.as_mut_ptr());
 
-        match result {
-            rts::OKAY      => Ok(()),
-            rts::UNDERFLOW => Err(Error::PointerUnderflow),
-            rts::OVERFLOW  => Err(Error::PointerOverflow),
-            _ => panic!(format!("Unknown result code: {}", result)),
+        if self.accounting {
+            rts.set_steps(rts_table[RTS_STEPS_SLOT as usize / 8]);
         }
+        rts.status(result)?;
+        Ok(rts.deopt())
+    }
+
+    /// Finishes a run that deoptimized in the peephole interpreter.
+    fn resume<R: Read, W: Write>(&self, state: &mut State, deopt: Deopt, input: R, output: W)
+                                 -> BfResult<()>
+    {
+        let source = self.source.as_ref().expect("only speculative code deoptimizes");
+        let rest = continuation(source, deopt.pc).expect("safepoints are at loop jumps");
+        *state.parts_mut().1 = deopt.pointer;
+        peephole::interpret_capped(&rest, state, input, output, u64::MAX)
+            .map_err(|stopped| match stopped {
+                peephole::Stopped::Error(error) => error,
+                peephole::Stopped::RunawayLoop(_) => unreachable!("the cap is never reached"),
+            })
+    }
+}
+
+/// How a [metered run](struct.Program.html#method.run_metered) went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metered {
+    /// The run’s result.
+    pub result: BfResult<()>,
+    /// The run’s stats.
+    pub stats: RunStats,
+    /// Where, and why, the meter stopped the run, if it did.
+    pub stopped_at: Option<Safepoint>,
+}
+
+impl Interpretable for Program {
+    fn interpret_state<R: Read, W: Write>(&self, state: State,
+                                          input: R, output: W)
+                                          -> BfResult<()>
+    {
+        if self.sanitize {
+            let mut sanitizer = Sanitizer::new(state.capacity());
+            self.interpret_sanitized(state, input, output, &mut sanitizer)
+        } else {
+            self.interpret_deopt(state, input, output).map(|_| ())
+        }
+    }
+
+    fn interpret_stats<R: Read, W: Write>(&self, state: State, input: R, output: W)
+        -> (BfResult<()>, RunStats)
+    {
+        self.interpret_metered(state, input, output, &RunOptions::default(), Meter::default())
     }
 }
 
@@ -77,6 +332,12 @@ impl Interpretable for Program {
 mod tests {
     use test_helpers::*;
     use common::{BfResult, Error};
+    use bytecode::{Control, Execution, StepResult};
+    use options::{CompileOptions, RunOptions};
+    use rts::{Interrupt, Meter, Safepoint};
+    use run_stats::RunStats;
+    use state::State;
+    use traits::{BytecodeCompilable, Interpretable, JitCompilable, PeepholeCompilable};
 
     #[test]
     fn move_right_once() {
@@ -116,6 +377,156 @@ mod tests {
         assert_parse_interpret(FACTOR_SRC, "100\n", Ok("100: 2 2 5 5\n"));
     }
 
+    #[test]
+    fn cached_cells_are_written_back() {
+        assert_parse_interpret(b",++.--.[-]+++[>+<-]>.", "A", Ok("CA\u{3}"));
+        assert_parse_interpret(b"+[-]+.>,[-]<.>.+.", "A", Ok("\u{1}\u{1}\u{0}\u{1}"));
+        assert_parse_interpret(b"[-]+++>,<[->+<]>.", "A", Ok("D"));
+    }
+
+    #[test]
+    fn deterministic_mode_runs() {
+        let options = CompileOptions { deterministic: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().jit_compile_with_options(&options);
+        assert_interpret(&program, b"6\n", b"6: 2 3\n");
+    }
+
+    #[test]
+    fn deterministic_mode_is_reproducible() {
+        let options = CompileOptions { deterministic: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+        let one = ::jit::compile_with_options(&program, &options);
+        let two = ::jit::compile_with_options(&program.to_vec().into_boxed_slice(), &options);
+        assert_eq!(&*one.code, &*two.code);
+    }
+
+    #[test]
+    fn sanitize_mode_runs() {
+        let options = CompileOptions { sanitize: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().jit_compile_with_options(&options);
+        assert_interpret(&program, b"6\n", b"6: 2 3\n");
+    }
+
+    #[test]
+    fn sanitize_mode_catches_unchecked_accesses() {
+        let options = CompileOptions { checked: false, sanitize: true,
+                                       ..CompileOptions::default() };
+        let program = ::ast::parse_program(b"+<+").unwrap().jit_compile_with_options(&options);
+        assert_interpret_result(&program, b"", Err(Error::PoisonedAccess(-1)));
+    }
+
+    #[test]
+    fn speculative_mode_runs() {
+        let options = CompileOptions { speculate: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().jit_compile_with_options(&options);
+        assert_interpret(&program, b"100\n", b"100: 2 2 5 5\n");
+        assert_interpret(&::ast::parse_program(HELLO_WORLD_SRC).unwrap()
+                             .jit_compile_with_options(&options),
+                         b"", b"Hello, World!");
+    }
+
+    #[test]
+    fn shared_speculative_code_keeps_the_program() {
+        let options = CompileOptions { speculate: true, ..CompileOptions::default() };
+        let data = ::ast::parse_program(b"+[>+>+<]").unwrap().peephole_share();
+        let program = ::jit::compile_shared(&data, &options);
+        assert_eq!(::std::sync::Arc::strong_count(&data), 2);
+        let result = program.interpret_deopt(::state::State::with_capacity(16), &b""[..],
+                                             Vec::new());
+        assert_eq!(result, Err(Error::PointerOverflow));
+    }
+
+    #[test]
+    fn speculative_mode_deoptimizes_near_the_edges() {
+        let options = CompileOptions { speculate: true, ..CompileOptions::default() };
+
+        // Each iteration reaches two cells right, so the last few run in the interpreter,
+        // which reports the overflow.
+        let program = ::ast::parse_program(b"+[>+>+<]").unwrap().jit_compile_with_options(&options);
+        let result = program.interpret_deopt(::state::State::with_capacity(16), &b""[..],
+                                             Vec::new());
+        assert_eq!(result, Err(Error::PointerOverflow));
+
+        // The inner loop never runs, so the iteration that fails the safepoint would not
+        // have left the tape.
+        let program = ::ast::parse_program(b"++[->[>>>>>>++<<<<<<-]<]+++.").unwrap()
+            .jit_compile_with_options(&options);
+        let mut output = Vec::new();
+        let deopt = program.interpret_deopt(::state::State::with_capacity(4), &b""[..],
+                                            &mut output);
+        assert_eq!(deopt, Ok(Some(::rts::Deopt { pc: 11, pointer: 0, steps: 1 })));
+        assert_eq!(output, vec![3]);
+    }
+
+    #[test]
+    fn accounting_counts_the_interpreters_steps() {
+        let options = CompileOptions { accounting: true, ..CompileOptions::default() };
+        let ast = ::ast::parse_program(FACTOR_SRC).unwrap();
+        let (result, stats) = ast.jit_compile_with_options(&options)
+            .interpret_stats(State::new(), &b"12\n"[..], Vec::new());
+        let (_, expected) = (*ast.bytecode_compile())
+            .interpret_stats(State::new(), &b"12\n"[..], Vec::new());
+        assert_eq!(result, Ok(()));
+        assert_eq!((stats.steps, stats.input_bytes, stats.output_bytes), (expected.steps, 3, 10));
+
+        let (_, stats) = ast.jit_compile(true).interpret_stats(State::new(), &b"12\n"[..],
+                                                               Vec::new());
+        assert_eq!((stats.steps, stats.output_bytes), (None, 10));
+    }
+
+    #[test]
+    fn metered_runs_stop_when_out_of_fuel() {
+        let options = CompileOptions { accounting: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(b"+[]").unwrap().jit_compile_with_options(&options);
+        let mut reported = Vec::new();
+        let mut on_progress = |stats: &RunStats| {
+            reported.push(stats.steps.unwrap());
+            Control::Continue
+        };
+        let meter = Meter { fuel: Some(10_000), every_n_steps: Some(4_000),
+                            on_progress: Some(&mut on_progress), ..Meter::default() };
+        let mut state = State::new();
+        let run = program.run_metered(&mut state, &b""[..], Vec::new(), &RunOptions::default(),
+                                      meter);
+        assert_eq!(run.result, Err(Error::Interrupted));
+        assert_eq!(run.stats.steps, Some(10_000));
+        assert_eq!(run.stopped_at, Some(Safepoint { pc: 2, pointer: 0, steps: 10_000,
+                                                    interrupt: Interrupt::OutOfFuel }));
+        assert_eq!(state.load(), 1);
+        assert_eq!(reported, [4_000, 8_000]);
+    }
+
+    #[test]
+    fn metered_runs_stop_after_io_and_can_go_on() {
+        let options = CompileOptions { accounting: true, ..CompileOptions::default() };
+        let ast = ::ast::parse_program(b"+[,[.,]+]").unwrap();
+        let program = ast.jit_compile_with_options(&options);
+        let meter = Meter { fuel: Some(3), ..Meter::default() };
+        let mut state = State::new();
+        let mut output = Vec::new();
+        let run = program.run_metered(&mut state, &b"ab"[..], &mut output,
+                                      &RunOptions::default(), meter);
+        assert_eq!(run.result, Err(Error::Interrupted));
+        let stopped_at = run.stopped_at.unwrap();
+        assert_eq!((stopped_at.pc, stopped_at.steps, stopped_at.interrupt),
+                   (3, 3, Interrupt::OutOfFuel));
+
+        let bytecode = ast.bytecode_compile();
+        let mut execution = Execution::resume(&bytecode, state, stopped_at.pc);
+        assert_eq!(execution.run(), Ok(StepResult::Output(b'a')));
+    }
+
+    #[test]
+    fn code_offsets_follow_bytecode_addresses() {
+        let program = ::ast::parse_program(b"+[->+<]>[.-]").unwrap().jit_compile(true);
+        let starts = &program.code_offsets[.. program.code_offsets.len() - 1];
+        assert_eq!(starts.len(), 7);
+        assert!(starts.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(program.pc_at(starts[3]), Some(3));
+        assert_eq!(program.pc_at(starts[3] + 1), Some(3));
+        assert_eq!(program.pc_at(0), None);
+    }
+
     fn assert_parse_interpret(program: &[u8], input: &str, output: BfResult<&str>) {
         let program = ::ast::parse_program(program).unwrap();
         let program = ::rle::compile(&program);
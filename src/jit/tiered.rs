@@ -0,0 +1,100 @@
+use std::io::{Read, Write};
+
+use super::*;
+use bytecode;
+use common::Instruction;
+use options::CompileOptions;
+use peephole::{self, continuation::continuation};
+
+/// Compiles the rest of a run paused at the loop jump at bytecode address `pc`, to be run on
+/// the paused state. Returns `None` if `pc` is not the address of a loop jump.
+///
+/// The code starts with the pointer wherever the state has it, and its bounds analysis
+/// starts knowing nothing about the pointer, so elided checks stay sound.
+pub fn compile_osr(program: &peephole::Program, pc: usize, options: &CompileOptions)
+                   -> Option<Program>
+{
+    continuation(program, pc).map(|rest| compile_with_options(&rest, options))
+}
+
+/// Runs a program in the bytecode interpreter until some loop has jumped back `threshold`
+/// times, then compiles the rest of the run and enters it in the middle of that loop.
+pub fn interpret_tiered<R: Read, W: Write>(program: &peephole::Program, mut state: State,
+                                           mut input: R, mut output: W,
+                                           options: &CompileOptions, threshold: usize)
+                                           -> BfResult<()>
+{
+    let code = bytecode::compile(program);
+    let mut counts = vec![0usize; code.len()];
+    let mut pc = 0;
+
+    while let Some(&instruction) = code.get(pc) {
+        match instruction {
+            Instruction::In => {
+                state.read(&mut input);
+                pc += 1;
+            }
+
+            Instruction::Out => {
+                state.write(&mut output);
+                pc += 1;
+            }
+
+            Instruction::JumpNotZero(_) if state.load() != 0 => {
+                counts[pc] += 1;
+                if counts[pc] >= threshold {
+                    let native = compile_osr(program, pc, options)
+                        .expect("JumpNotZero is a loop jump");
+                    return native.interpret_state(state, input, output);
+                }
+                bytecode::execute(instruction, &mut state, &mut pc, &mut None)?;
+            }
+
+            _ => { bytecode::execute(instruction, &mut state, &mut pc, &mut None)?; }
+        }
+    }
+
+    let _ = output.flush();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::PeepholeCompilable;
+
+    fn run_tiered(source: &[u8], input: &[u8], threshold: usize) -> BfResult<Vec<u8>> {
+        let program = ::ast::parse_program(source).unwrap().peephole_compile();
+        let mut output = Vec::new();
+        interpret_tiered(&program, State::new(), input, &mut output,
+                         &CompileOptions::default(), threshold)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn tiered_runs_agree_with_the_interpreter() {
+        for &threshold in &[1, 10, 1_000, usize::MAX] {
+            assert_eq!(run_tiered(FACTOR_SRC, b"100\n", threshold),
+                       Ok(b"100: 2 2 5 5\n".to_vec()));
+            assert_eq!(run_tiered(HELLO_WORLD_SRC, b"", threshold), Ok(b"Hello, World!".to_vec()));
+        }
+    }
+
+    #[test]
+    fn native_code_starts_at_the_state_pointer() {
+        // 0: JumpZero, 1: Add, 2: Right, 3: Add, 4: Left, 5: JumpNotZero, 6: Right, 7: Out
+        let program = ::ast::parse_program(b"[->++<]>.").unwrap().peephole_compile();
+        let native = compile_osr(&program, 5, &CompileOptions::default()).unwrap();
+        assert!(compile_osr(&program, 3, &CompileOptions::default()).is_none());
+
+        let mut state = State::with_capacity(8);
+        state.right(3usize).unwrap();
+        state.store(4);
+        let mut output = Vec::new();
+        native.interpret_state(state, &b""[..], &mut output).unwrap();
+        assert_eq!(output, vec![8]);
+
+        assert_eq!(run_tiered(b"+[<-]", b"", 1), Err(::common::Error::PointerUnderflow));
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+
+use common::Instruction;
+use options::CompileOptions;
+use peephole::{self, Statement};
+use state::DEFAULT_CAPACITY;
+
+/// Program forms that can be transpiled to JavaScript.
+pub trait JsCompilable {
+    /// Compile the given program into the peephole AST to prepare for transpiling.
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R;
+
+    /// Transpile the given program to a JavaScript module.
+    fn js_compile(&self, memory_size: Option<usize>, options: &CompileOptions) -> String {
+        self.with_peephole(|program| compile(program, memory_size, options))
+    }
+}
+
+const PRELUDE: &str = "\
+// Generated by bf-rs.
+
+export class BfError extends Error {
+  constructor(message) {
+    super(message);
+    this.name = \"BfError\";
+  }
+}
+
+function underflow() {
+  return new BfError(\"pointer underflow\");
+}
+
+function overflow() {
+  return new BfError(\"pointer overflow\");
+}
+";
+
+/// Transpiles the given program to a self-contained JavaScript module.
+///
+/// `memory_size` becomes the default for the exported `run` function’s `memorySize`
+/// parameter. Of the options, only `checked` applies.
+pub fn compile(program: &peephole::Program, memory_size: Option<usize>,
+               options: &CompileOptions) -> String {
+    let mut compiler = Compiler {
+        out:     String::from(PRELUDE),
+        checked: options.checked,
+        depth:   1,
+    };
+
+    let memory_size = memory_size.unwrap_or(DEFAULT_CAPACITY);
+    compiler.line(0, "");
+    compiler.line(0, &format!("export async function run(input, output, memorySize = {}) {{",
+                              memory_size));
+    compiler.line(1, "const mem = new Uint8Array(memorySize);");
+    compiler.line(1, "let p = 0;");
+    compiler.compile_block(program);
+    compiler.line(0, "}");
+
+    compiler.out
+}
+
+struct Compiler {
+    out:     String,
+    checked: bool,
+    depth:   usize,
+}
+
+impl Compiler {
+    fn line(&mut self, depth: usize, text: &str) {
+        if !text.is_empty() {
+            for _ in 0 .. depth {
+                self.out.push_str("  ");
+            }
+            self.out.push_str(text);
+        }
+        self.out.push('\n');
+    }
+
+    fn emit(&mut self, text: &str) {
+        let depth = self.depth;
+        self.line(depth, text);
+    }
+
+    fn compile_block(&mut self, program: &[Statement]) {
+        for statement in program {
+            self.compile_statement(statement);
+        }
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) {
+        match *statement {
+            Statement::Instr(instruction) => self.compile_instruction(instruction),
+
+            Statement::Loop(ref body) => {
+                self.emit("while (mem[p] !== 0) {");
+                self.depth += 1;
+                self.compile_block(body);
+                self.depth -= 1;
+                self.emit("}");
+            }
+        }
+    }
+
+    fn compile_instruction(&mut self, instruction: Instruction) {
+        use common::Instruction::*;
+
+        match instruction {
+            Left(count) => {
+                self.check_left(count);
+                self.emit(&format!("p -= {};", count));
+            }
+
+            Right(count) => {
+                self.check_right(count);
+                self.emit(&format!("p += {};", count));
+            }
+
+            Add(amount) => self.emit(&format!("mem[p] += {};", amount)),
+
+            In => self.emit("mem[p] = (await input()) | 0;"),
+
+            Out => self.emit("output(mem[p]);"),
+
+            SetZero => self.emit("mem[p] = 0;"),
+
+            OffsetAddRight(offset) => {
+                self.offset_add(|compiler| compiler.check_right(offset),
+                                &format!("mem[p + {}] += mem[p];", offset));
+            }
+
+            OffsetAddLeft(offset) => {
+                self.offset_add(|compiler| compiler.check_left(offset),
+                                &format!("mem[p - {}] += mem[p];", offset));
+            }
+
+            FindZeroRight(skip) => {
+                self.find_zero(|compiler| compiler.check_right(skip),
+                               &format!("p += {};", skip));
+            }
+
+            FindZeroLeft(skip) => {
+                self.find_zero(|compiler| compiler.check_left(skip),
+                               &format!("p -= {};", skip));
+            }
+
+            // The loop that follows does the work.
+            DivMod | IndexRight(_) | IndexLeft(_) => (),
+
+            JumpZero(_) | JumpNotZero(_) =>
+                panic!("unexpected jump instruction"),
+        }
+    }
+
+    fn offset_add<F: FnOnce(&mut Self)>(&mut self, check: F, add: &str) {
+        self.emit("if (mem[p] !== 0) {");
+        self.depth += 1;
+        check(self);
+        self.emit(add);
+        self.emit("mem[p] = 0;");
+        self.depth -= 1;
+        self.emit("}");
+    }
+
+    fn find_zero<F: FnOnce(&mut Self)>(&mut self, check: F, step: &str) {
+        self.emit("while (mem[p] !== 0) {");
+        self.depth += 1;
+        check(self);
+        self.emit(step);
+        self.depth -= 1;
+        self.emit("}");
+    }
+
+    fn check_left<C: fmt::Display>(&mut self, count: C) {
+        if self.checked {
+            self.emit(&format!("if (p < {}) throw underflow();", count));
+        }
+    }
+
+    fn check_right<C: fmt::Display>(&mut self, count: C) {
+        if self.checked {
+            self.emit(&format!("if (p + {} >= mem.length) throw overflow();", count));
+        }
+    }
+}
+
+impl JsCompilable for peephole::Program {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(self)
+    }
+}
+
+impl<T: peephole::PeepholeCompilable + ?Sized> JsCompilable for T {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(&self.peephole_compile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn js(source: &[u8], options: &CompileOptions) -> String {
+        ::ast::parse_program(source).unwrap().js_compile(Some(100), options)
+    }
+
+    #[test]
+    fn module_shape() {
+        let output = js(b",[.,]", &CompileOptions::default());
+        assert!(output.contains("export class BfError extends Error {"));
+        assert!(output.contains("export async function run(input, output, memorySize = 100) {"));
+        assert!(output.contains("const mem = new Uint8Array(memorySize);"));
+        assert!(output.contains("\n  mem[p] = (await input()) | 0;\n"));
+        assert!(output.contains("\n  while (mem[p] !== 0) {\n    output(mem[p]);\n"));
+    }
+
+    #[test]
+    fn peepholes_are_transpiled() {
+        let output = js(b"[-]>[->>+<<]<[<<]", &CompileOptions::default());
+        assert!(output.contains("mem[p] = 0;"));
+        assert!(output.contains("mem[p + 2] += mem[p];"));
+        assert!(output.contains("if (p < 2) throw underflow();\n    p -= 2;"));
+    }
+
+    #[test]
+    fn checks_can_be_omitted() {
+        let checked = js(FACTOR_SRC, &CompileOptions::default());
+        assert!(checked.contains("throw overflow()"));
+
+        let options = CompileOptions { checked: false, ..CompileOptions::default() };
+        let unchecked = js(FACTOR_SRC, &options);
+        assert!(!unchecked.contains("throw"));
+    }
+}
@@ -0,0 +1,18 @@
+//! Transpiler from the peephole IR to JavaScript.
+//!
+//! The output is a self-contained ES module, for running Brainfuck programs under Node or in
+//! the browser without shipping WebAssembly. It exports a `BfError` class and an async function
+//!
+//! ```js
+//! run(input, output, memorySize)
+//! ```
+//!
+//! which keeps the tape in a `Uint8Array`. For `,` it calls `await input()`, which should give
+//! the next byte, or `null` at end of input (which stores 0, as in the other backends). For `.`
+//! it calls `output(byte)`. With [`CompileOptions::checked`](../options/struct.CompileOptions.html)
+//! set, moving the pointer off either end of the tape rejects with a `BfError` whose message
+//! matches this crate’s [`Error`](../common/enum.Error.html) display.
+
+mod compiler;
+
+pub use self::compiler::{compile, JsCompilable};
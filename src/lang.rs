@@ -0,0 +1,463 @@
+//! A tiny imperative language that compiles to peephole IR, for `bfi lang`.
+//!
+//! Programs that generate Brainfuck by hand can be written in this instead. A program is
+//! statements ending in `;` or blocks, with `#` starting a comment that runs to the end of the
+//! line:
+//!
+//!  - `x = EXPR;` sets a variable, modulo 256; variables need no declaring and start at 0;
+//!  - `read x;` reads a byte of input into a variable, and `write EXPR;` writes one;
+//!  - `while EXPR { … }` runs the block for as long as the expression is not 0; and
+//!  - `if EXPR { … } else { … }` runs the first block if the expression is not 0 and the
+//!    second, which may be left out, otherwise.
+//!
+//! Expressions add and subtract with `+` and `-` and multiply by a number with `*`, wrapping
+//! modulo 256, and group with parentheses. Their terms are variables, numbers from 0 to 255,
+//! and characters in single quotes, such as `'A'`, which stand for their byte. There is no
+//! comparison; `while x - 'q' { … }` runs until `x` is `q`.
+//!
+//! Each variable has a cell of its own, in the order they first appear, and the cells after
+//! them hold temporaries. [`compile`](fn.compile.html) builds the IR with an
+//! [`ir::Builder`](../ir/struct.Builder.html), so it runs on any backend, and
+//! [`codegen::from_peephole`](../codegen/fn.from_peephole.html) writes it as Brainfuck.
+//!
+//! ```
+//! use bf::lang;
+//! use bf::traits::Interpretable;
+//!
+//! let program = lang::compile("read n; while n { write '*'; n = n - 1; }").unwrap();
+//! assert_eq!(program.interpret_memory(None, &[3]).unwrap(), b"***");
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bytecode::usize_to_count;
+use ir::Builder;
+use peephole::Program;
+
+/// An error compiling a program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LangError {
+    /// The (1-based) line where the error was detected.
+    pub line: usize,
+    /// The byte offset in the line.
+    pub offset: usize,
+    /// What went wrong.
+    pub message: &'static str,
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.offset + 1, self.message)
+    }
+}
+
+/// Compiles a program to peephole IR.
+pub fn compile(source: &str) -> Result<Box<Program>, LangError> {
+    let mut parser = Parser { source, position: 0, variables: HashMap::new() };
+    let mut statements = Vec::new();
+    parser.skip();
+    while parser.position < source.len() {
+        statements.push(parser.statement()?);
+    }
+
+    let mut codegen = Codegen { pointer: 0, next_temp: parser.variables.len() };
+    Ok(codegen.block(Builder::new(), &statements).build())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Expr {
+    Number(u8),
+    Variable(usize),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(u8, Box<Expr>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Statement {
+    Assign(usize, Expr),
+    Read(usize),
+    Write(Expr),
+    While(Expr, Vec<Statement>),
+    If(Expr, Vec<Statement>, Vec<Statement>),
+}
+
+impl Expr {
+    fn mentions(&self, variable: usize) -> bool {
+        match *self {
+            Expr::Number(_) => false,
+            Expr::Variable(other) => other == variable,
+            Expr::Add(ref a, ref b) | Expr::Sub(ref a, ref b) =>
+                a.mentions(variable) || b.mentions(variable),
+            Expr::Mul(_, ref a) => a.mentions(variable),
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+    /// The cell of each variable.
+    variables: HashMap<&'a str, usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, position: usize, message: &'static str) -> LangError {
+        let before = &self.source[.. position];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        LangError { line: before.matches('\n').count() + 1, offset: position - line_start, message }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position ..]
+    }
+
+    /// Skips whitespace and comments.
+    fn skip(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                return;
+            }
+            self.position += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if self.rest().starts_with(symbol) {
+            self.position += symbol.len();
+            self.skip();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str, message: &'static str) -> Result<(), LangError> {
+        if self.eat(symbol) { Ok(()) } else { Err(self.error(self.position, message)) }
+    }
+
+    /// The word at the position, if any, without moving past it.
+    fn peek_word(&self) -> &'a str {
+        let rest = self.rest();
+        let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        &rest[.. end]
+    }
+
+    fn name(&mut self) -> Result<usize, LangError> {
+        let word = self.peek_word();
+        if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit())
+            || KEYWORDS.contains(&word) {
+            return Err(self.error(self.position, "expected a variable"));
+        }
+        self.position += word.len();
+        self.skip();
+        let next = self.variables.len();
+        Ok(*self.variables.entry(word).or_insert(next))
+    }
+
+    fn statement(&mut self) -> Result<Statement, LangError> {
+        let word = self.peek_word();
+        let keyword = KEYWORDS.contains(&word);
+        if keyword {
+            self.position += word.len();
+            self.skip();
+        }
+
+        let statement = match word {
+            "read" => Statement::Read(self.name()?),
+            "write" => Statement::Write(self.expression()?),
+            "while" => {
+                let condition = self.expression()?;
+                return Ok(Statement::While(condition, self.block()?));
+            }
+            "if" => {
+                let condition = self.expression()?;
+                let then = self.block()?;
+                let otherwise = if self.peek_word() == "else" {
+                    self.position += "else".len();
+                    self.skip();
+                    self.block()?
+                } else {
+                    Vec::new()
+                };
+                return Ok(Statement::If(condition, then, otherwise));
+            }
+            "else" => return Err(self.error(self.position - word.len(), "‘else’ without ‘if’")),
+            _ => {
+                let variable = self.name()?;
+                self.expect("=", "expected ‘=’")?;
+                Statement::Assign(variable, self.expression()?)
+            }
+        };
+        self.expect(";", "expected ‘;’")?;
+        Ok(statement)
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, LangError> {
+        self.expect("{", "expected ‘{’")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            if self.position == self.source.len() {
+                return Err(self.error(self.position, "expected ‘}’"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> Result<Expr, LangError> {
+        let mut result = self.product()?;
+        loop {
+            if self.eat("+") {
+                result = Expr::Add(Box::new(result), Box::new(self.product()?));
+            } else if self.eat("-") {
+                result = Expr::Sub(Box::new(result), Box::new(self.product()?));
+            } else {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, LangError> {
+        let start = self.position;
+        let mut result = self.term()?;
+        while self.eat("*") {
+            let factor = self.term()?;
+            result = match (result, factor) {
+                (Expr::Number(a), Expr::Number(b)) => Expr::Number(a.wrapping_mul(b)),
+                (Expr::Number(n), e) | (e, Expr::Number(n)) => Expr::Mul(n, Box::new(e)),
+                _ => return Err(self.error(start, "can only multiply by a number")),
+            };
+        }
+        Ok(result)
+    }
+
+    fn term(&mut self) -> Result<Expr, LangError> {
+        let start = self.position;
+        let rest = self.rest();
+
+        if self.eat("(") {
+            let result = self.expression()?;
+            self.expect(")", "expected ‘)’")?;
+            Ok(result)
+        } else if let Some(quoted) = rest.strip_prefix('\'') {
+            let mut chars = quoted.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), Some('\'')) if c.is_ascii() => {
+                    self.position += 3;
+                    self.skip();
+                    Ok(Expr::Number(c as u8))
+                }
+                _ => Err(self.error(start, "expected an ASCII character in quotes")),
+            }
+        } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            let word = self.peek_word();
+            let value = word.parse().map_err(|_| self.error(start, "expected a number to 255"))?;
+            self.position += word.len();
+            self.skip();
+            Ok(Expr::Number(value))
+        } else {
+            Ok(Expr::Variable(self.name()?))
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &["read", "write", "while", "if", "else"];
+
+/// Emits IR, tracking where the pointer is.
+struct Codegen {
+    pointer: usize,
+    /// The first cell not holding a variable or live temporary; temporaries above it are zero.
+    next_temp: usize,
+}
+
+impl Codegen {
+    fn block(&mut self, mut b: Builder, statements: &[Statement]) -> Builder {
+        for statement in statements {
+            b = self.statement(b, statement);
+        }
+        b
+    }
+
+    fn statement(&mut self, b: Builder, statement: &Statement) -> Builder {
+        match *statement {
+            Statement::Assign(variable, ref value) if !value.mentions(variable) => {
+                let b = self.goto(b, variable).zero();
+                self.add(b, value, variable, 1)
+            }
+            Statement::Assign(variable, ref value) => {
+                let temp = self.temp();
+                let b = self.add(b, value, temp, 1);
+                let b = self.goto(b, variable).zero();
+                let b = self.transfer(b, temp, &[(variable, 1)]);
+                self.free(temp);
+                b
+            }
+            Statement::Read(variable) => self.goto(b, variable).input(),
+            Statement::Write(Expr::Variable(variable)) => self.goto(b, variable).output(),
+            Statement::Write(ref value) => {
+                let temp = self.temp();
+                let b = self.add(b, value, temp, 1);
+                let b = self.goto(b, temp).output().zero();
+                self.free(temp);
+                b
+            }
+            Statement::While(ref condition, ref body) => {
+                let flag = self.temp();
+                let b = self.add(b, condition, flag, 1);
+                let b = self.goto(b, flag).loop_(|b| {
+                    let b = b.zero();
+                    let b = self.block(b, body);
+                    let b = self.add(b, condition, flag, 1);
+                    self.goto(b, flag)
+                });
+                self.free(flag);
+                b
+            }
+            Statement::If(ref condition, ref then, ref otherwise) => {
+                let flag = self.temp();
+                let b = self.add(b, condition, flag, 1);
+                let other = if otherwise.is_empty() { None } else { Some(self.temp()) };
+                let b = match other {
+                    Some(other) => self.goto(b, other).add(1),
+                    None => b,
+                };
+
+                let b = self.goto(b, flag).loop_(|b| {
+                    let mut b = b.zero();
+                    if let Some(other) = other {
+                        b = self.goto(b, other).zero();
+                    }
+                    let b = self.block(b, then);
+                    self.goto(b, flag)
+                });
+
+                let b = match other {
+                    Some(other) => {
+                        let b = self.goto(b, other).loop_(|b| {
+                            let b = self.block(b.zero(), otherwise);
+                            self.goto(b, other)
+                        });
+                        self.free(other);
+                        b
+                    }
+                    None => b,
+                };
+                self.free(flag);
+                b
+            }
+        }
+    }
+
+    /// Adds `factor` times the value of the expression to the cell.
+    fn add(&mut self, b: Builder, value: &Expr, cell: usize, factor: u8) -> Builder {
+        match *value {
+            _ if factor == 0 => b,
+            Expr::Number(n) => self.goto(b, cell).add(n.wrapping_mul(factor)),
+            Expr::Variable(variable) => {
+                let temp = self.temp();
+                let b = self.transfer(b, variable, &[(cell, factor), (temp, 1)]);
+                let b = self.transfer(b, temp, &[(variable, 1)]);
+                self.free(temp);
+                b
+            }
+            Expr::Add(ref x, ref y) => {
+                let b = self.add(b, x, cell, factor);
+                self.add(b, y, cell, factor)
+            }
+            Expr::Sub(ref x, ref y) => {
+                let b = self.add(b, x, cell, factor);
+                self.add(b, y, cell, factor.wrapping_neg())
+            }
+            Expr::Mul(n, ref x) => self.add(b, x, cell, factor.wrapping_mul(n)),
+        }
+    }
+
+    /// Empties the cell `from`, adding its value times each factor to each target.
+    fn transfer(&mut self, b: Builder, from: usize, targets: &[(usize, u8)]) -> Builder {
+        self.goto(b, from).loop_(|mut b| {
+            b = b.sub(1);
+            for &(cell, factor) in targets {
+                b = self.goto(b, cell).add(factor);
+            }
+            self.goto(b, from)
+        })
+    }
+
+    fn goto(&mut self, b: Builder, cell: usize) -> Builder {
+        let pointer = self.pointer;
+        self.pointer = cell;
+        if cell > pointer {
+            b.right(usize_to_count(cell - pointer))
+        } else if cell < pointer {
+            b.left(usize_to_count(pointer - cell))
+        } else {
+            b
+        }
+    }
+
+    fn temp(&mut self) -> usize {
+        self.next_temp += 1;
+        self.next_temp - 1
+    }
+
+    fn free(&mut self, temp: usize) {
+        debug_assert_eq!(temp + 1, self.next_temp);
+        self.next_temp = temp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codegen;
+    use text::Text;
+    use traits::*;
+
+    fn run(source: &str, input: &[u8]) -> Vec<u8> {
+        compile(source).unwrap().interpret_memory(None, input).unwrap()
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        assert_eq!(run("x = 'A'; y = 3 * (x - 60) + 1; write x + y; y = y - y - 1; write y;",
+                       b""),
+                   [81, 255]);
+        assert_eq!(run("read a; read b; a = a + 2 * b; write a;", &[1, 200]), [145]);
+    }
+
+    #[test]
+    fn control_flow_runs_as_written() {
+        let echo = "# Echoes input up to a ‘.’, upper-casing a and b.
+            read c;
+            while c - '.' {
+                upper = 0;
+                if c - 'a' { if c - 'b' { } else { upper = 1; } } else { upper = 1; }
+                write c - 32 * upper;
+                read c;
+            }";
+        assert_eq!(run(echo, b"a big cab.xyz"), b"A Big cAB");
+
+        let program = compile(echo).unwrap();
+        let source = Text(&*codegen::from_peephole(&program)).to_string();
+        let brainfuck = ::ast::parse_program(source.as_bytes()).unwrap();
+        assert_eq!(brainfuck.interpret_memory(None, b"ab."), Ok(b"AB".to_vec()));
+        assert_eq!(program.bytecode_compile().interpret_memory(None, b"ab."),
+                   Ok(b"AB".to_vec()));
+    }
+
+    #[test]
+    fn errors_say_where() {
+        assert_eq!(compile("x = 1;\nwhile x {\n  y = 256;\n}").unwrap_err(),
+                   LangError { line: 3, offset: 6, message: "expected a number to 255" });
+        assert_eq!(compile("x = y * z;").unwrap_err().message, "can only multiply by a number");
+        assert_eq!(compile("read 3;").unwrap_err().to_string(),
+                   "line 1, column 6: expected a variable");
+        assert_eq!(compile("if x { write x }").unwrap_err().message, "expected ‘;’");
+    }
+}
@@ -6,13 +6,11 @@
 //! and an executable `bfi` that provides a command-line interface for executing
 //! Brainfuck programs.
 //!
-//! This crate supports Rust version 1.20 and later. However,
-//! by default, installing `bf` does not enable the JIT compiler because
-//! that requires nightly Rust. To build and install from crates.io with the native x86-64
-//! JIT enabled:
+//! By default, installing `bf` does not enable the JIT compiler, because it builds only for
+//! x86-64. To build and install from crates.io with the native x86-64 JIT enabled:
 //!
 //! ```shell
-//! $ cargo +nightly install bf --features=jit
+//! $ cargo install bf --features=jit
 //! ```
 //!
 //! [series on JIT compilation]: http://eli.thegreenplace.net/2017/adventures-in-jit-compilation-part-1-an-interpreter/
@@ -20,46 +18,172 @@
 //! This library implements a number of compilation passes:
 //!
 //!  - First, Brainfuck concrete syntax is parsed into
-//! [an abstract syntax tree](ast/index.html).
+//!    [an abstract syntax tree](ast/index.html).
 //!
 //!  - Then, repeated sequences of the same command are
-//! [run-length encoded](rle/index.html).
+//!    [run-length encoded](rle/index.html).
 //!
 //!  - Then, common loop forms are converted to new (non-Brainfuck)
-//! instructions by the [peephole optimizer](peephole/index.html).
+//!    instructions by the [peephole optimizer](peephole/index.html).
 //!
 //!  - The peephole output can be [flattened to bytecode](bytecode/index.html),
-//! which is then interpreted.
+//!    which is then interpreted.
 //!
-//!  - Or, if the `jit` feature is enabled (nightly only), the peephole output
-//! can be [just-in-time compiled to x64 machine code](jit/index.html).
+//!  - Or, the peephole output can be compiled to [closure-threaded code](threaded/index.html),
+//!    a portable middle tier between interpretation and the JIT.
 //!
-//!  - Or, if the `llvm` feature is enabled (LLVM ≥ 3.8 must be in the PATH to build),
-//! the peephole output can be [JIT compiled using LLVM](llvm/index.html).
-//! (This is quite slow right now.)
+//!  - Or, if the `jit` feature is enabled, the peephole output
+//!    can be [just-in-time compiled to x64 machine code](jit/index.html).
+//!
+//!  - Or, if the `llvm` feature is enabled (LLVM ≥ 7 must be in the PATH to build),
+//!    the peephole output can be [JIT compiled using LLVM](llvm/index.html).
+//!    (This is quite slow right now.) To build against LLVM 15–18, enable the matching
+//!    `llvm-15` through `llvm-18` feature instead.
+//!
+//!  - Or, the peephole output can be [emitted as x86-64 assembly source](asm/index.html)
+//!    with the same layout as the JIT’s, to inspect or assemble with standard tools.
+//!
+//!  - Or, experimentally, bounded programs can be [lowered to eBPF](ebpf/index.html).
+//!
+//!  - Or, the peephole output can be [transpiled to JavaScript](js/index.html), for running
+//!    under Node or in the browser, or [to C](c/index.html), for compiling ahead of time.
 //!
 //! Interpreters are provided for the intermediate forms as well. In particular,
 //! all representations of Brainfuck programs implement the
 //! [`Interpretable`](traits/trait.Interpretable.html) trait.
+//!
+//! For editors, [`diagnostics`](diagnostics/index.html) reports problems with their source spans
+//! and [`fmt`](fmt/index.html) formats source. The `lsp` feature builds `bf-lsp`, a language
+//! server using both.
+//! A [source map](source_map/index.html) takes bytecode addresses and JIT code offsets
+//! back to the source they were compiled from, and an [audit](audit/index.html) lists which
+//! tape accesses in native code keep their bounds checks.
+//! [`sources`](sources/index.html) keeps track of programs split across files, and
+//! [`macros`](macros/index.html) preprocesses them with named definitions.
+//! [`minify`](minify/index.html) strips programs down and checks the result behaves the same,
+//! and [`explain`](explain/index.html) shows what the optimizer made of each part of one.
+//! [`decompile`](decompile/index.html) writes programs as pseudo-code, for reading ones found in
+//! the wild.
+//! [`stats`](stats/index.html) summarizes a program statically, and [`cost`](cost/index.html)
+//! estimates the cycles a run takes, independent of the backend.
+//! [`dispatch`](dispatch/index.html) counts the opcode pairs the bytecode interpreter runs, as
+//! candidates for superinstructions.
+//! [`crash_dump`](crash_dump/index.html) records what a failed run was doing, for bug reports,
+//! including its [history](history/index.html) of the last instructions run.
+//! [`symex`](symex/index.html) runs small programs on symbolic input, finding inputs that make
+//! them fail and test corpora that cover their loops.
+//! [`lockstep`](lockstep/index.html) runs a backend side by side with the checked interpreter,
+//! reporting the first loop iteration where they part ways.
+//! [`snapshot`](snapshot/index.html) saves machine states in a portable format, as
+//! [`bytecode::format`](bytecode/format/index.html) does compiled programs.
+//! [`limits`](limits/index.html) bounds the programs compiled, for untrusted source, and
+//! [`sandbox`](sandbox/index.html) runs them with bounded fuel, memory and output; the `server`
+//! feature builds `bf-server`, a playground serving sandboxed runs over HTTP.
+//! [`tape_pool`](tape_pool/index.html) allocates tapes for machines that run at once, kept
+//! apart in the cache and reused from run to run, and an [`executor`](executor/index.html)
+//! runs sandboxed jobs on a fixed pool of threads. With the `metrics` feature, both record
+//! [metrics](telemetry/index.html) of what they do.
+//! [`expect`](expect/index.html) tests interactive programs against scripts of input to send
+//! and output to expect.
+//! [`testing::golden`](testing/golden/index.html) runs a directory of programs against the
+//! output they should print, in every backend.
+//! [`adapters`](adapters/index.html) wrap programs’ input and output, such as to decode output
+//! as UTF-8 for display.
+//! [`events`](events/index.html) runs a program on a worker thread for GUI frontends, reporting
+//! what it does over a channel, taking commands to pause or step it, and stopping at
+//! [breakpoints](breakpoint/index.html) whose conditions can look at the machine’s state and
+//! which can run [scripts](script/index.html) to log and patch it.
+//! [`visualize`](visualize/index.html) draws frames of a run for `bfi run --visualize`.
+//! The `node` directory holds Node.js bindings, for embedding the crate in JavaScript tools.
+//! [`codegen`](codegen/index.html) generates Brainfuck, and with the `samples` feature,
+//! [`samples`](samples/index.html) embeds a corpus of classic programs.
+//! [`ir`](ir/index.html) builds optimized programs directly from Rust instead, and
+//! [`peephole::link`](peephole/link/index.html) joins compiled fragments.
+//! [`lang`](lang/index.html) compiles a tiny imperative language to the same IR, for `bfi lang`.
+//! [`capabilities()`](capabilities/fn.capabilities.html) says which backends and features a
+//! build has, so frontends can offer only those.
+//!
+//! Most programs need only the [`prelude`](prelude/index.html), whose API is kept stable;
+//! `use bf::prelude::*` brings in parsing, the compilation traits, and the options and errors.
 
-#![cfg_attr(feature = "jit", feature(plugin))]
-#![cfg_attr(feature = "jit", plugin(dynasm))]
-
+#[cfg(feature = "jit")]
+#[macro_use]
+extern crate dynasm;
 #[cfg(feature = "jit")]
 extern crate dynasmrt;
 
 #[cfg(feature = "llvm")]
 extern crate llvm_sys;
 
+#[cfg(feature = "metrics")]
+extern crate metrics;
+
+pub mod prelude;
 pub mod common;
 pub mod state;
+pub mod snapshot;
 pub mod traits;
 pub mod rts;
+pub mod text;
+pub mod diagnostics;
+pub mod fmt;
+pub mod source_map;
+pub mod codegen;
+pub mod ir;
+pub mod lang;
+pub mod machine;
+pub mod brainfork;
+pub mod multitape;
+pub mod trace;
+pub mod precompute;
+pub mod audit;
+pub mod config;
+pub mod sources;
+pub mod macros;
+pub mod minify;
+pub mod explain;
+pub mod decompile;
+pub mod stats;
+pub mod cost;
+pub mod crash_dump;
+pub mod history;
+pub mod run_stats;
+pub mod dispatch;
+pub mod symex;
+pub mod lockstep;
+pub mod limits;
+pub mod sandbox;
+pub mod tape_pool;
+pub mod executor;
+pub mod telemetry;
+pub mod expect;
+pub mod adapters;
+pub mod events;
+pub mod breakpoint;
+pub mod script;
+pub mod visualize;
+#[cfg(feature = "samples")]
+pub mod samples;
+pub mod fingerprint;
+pub mod cache;
+pub mod options;
+pub mod sanitizer;
+pub mod capabilities;
+pub mod testing;
+
+pub use capabilities::capabilities;
 
 pub mod ast;
 pub mod rle;
 pub mod bytecode;
 pub mod peephole;
+pub mod threaded;
+pub mod js;
+pub mod c;
+pub mod asm;
+pub mod ebpf;
+
+mod analysis;
 
 #[cfg(feature = "jit")]
 pub mod jit;
@@ -67,4 +191,5 @@ pub mod jit;
 #[cfg(feature = "llvm")]
 pub mod llvm;
 
+#[doc(hidden)]
 pub mod test_helpers;
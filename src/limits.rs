@@ -0,0 +1,104 @@
+//! Limits on the programs compiled, for accepting source from untrusted users.
+//!
+//! A service that compiles submitted programs should not let one exhaust its memory or stack:
+//! [`parse_program`](../ast/fn.parse_program.html) recurses on loop nesting, so ten thousand
+//! `[`s are enough to overflow a thread’s stack, and a long program makes a long IR.
+//! [`SourceLimits::compile`](struct.SourceLimits.html#method.compile) checks the source’s
+//! size and nesting before parsing it, and the optimized program’s size afterwards, failing
+//! with a limit error from [`common::Error`](../common/enum.Error.html) instead.
+
+use ast;
+use common::{BfResult, Error};
+use peephole::{self, continuation};
+use traits::PeepholeCompilable;
+
+/// Bounds on a program’s source and IR. The default is unlimited.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SourceLimits {
+    /// The most bytes of source, comments included.
+    pub max_source_len: usize,
+    /// How deeply loops may nest.
+    pub max_depth: usize,
+    /// The most bytecode instructions the optimized program may compile to.
+    pub max_instructions: usize,
+}
+
+impl Default for SourceLimits {
+    fn default() -> Self {
+        SourceLimits {
+            max_source_len: usize::MAX,
+            max_depth: usize::MAX,
+            max_instructions: usize::MAX,
+        }
+    }
+}
+
+impl SourceLimits {
+    /// Checks the source’s size and loop nesting, without parsing it. Unmatched brackets are
+    /// left for the parser to report.
+    pub fn check_source(&self, source: &[u8]) -> BfResult<()> {
+        if source.len() > self.max_source_len {
+            return Err(Error::SourceTooLarge(self.max_source_len));
+        }
+
+        let mut depth = 0usize;
+        for &c in source {
+            match c {
+                b'[' => {
+                    depth += 1;
+                    if depth > self.max_depth {
+                        return Err(Error::NestingTooDeep(self.max_depth));
+                    }
+                }
+                b']' => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the size of an optimized program.
+    pub fn check_program(&self, program: &peephole::Program) -> BfResult<()> {
+        if continuation::bytecode_len(program) > self.max_instructions {
+            Err(Error::ProgramTooLarge(self.max_instructions))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks the source, then parses and optimizes it and checks the result.
+    pub fn compile(&self, source: &[u8]) -> BfResult<Box<peephole::Program>> {
+        self.check_source(source)?;
+        let program = ast::parse_program(source)?.peephole_compile();
+        self.check_program(&program)?;
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pathological_sources_are_refused() {
+        let limits = SourceLimits { max_source_len: 4_000_000, max_depth: 100,
+                                    ..SourceLimits::default() };
+        let deep = [vec![b'['; 1_000_000], vec![b']'; 1_000_000]].concat();
+        assert_eq!(limits.compile(&deep), Err(Error::NestingTooDeep(100)));
+        assert_eq!(limits.compile(&vec![b' '; 4_000_001]), Err(Error::SourceTooLarge(4_000_000)));
+
+        let nested = [vec![b'['; 100], vec![b']'; 100]].concat();
+        assert!(limits.compile(&nested).is_ok());
+        assert_eq!(limits.compile(b"]]["), Err(Error::UnmatchedEnd));
+    }
+
+    #[test]
+    fn optimized_programs_are_measured() {
+        let limits = SourceLimits { max_instructions: 4, ..SourceLimits::default() };
+        // `+++` and `[-]` become one instruction each, and a loop’s jumps count too.
+        assert!(limits.compile(b"+++[-].,").is_ok());
+        assert_eq!(limits.compile(b"+++[-].,."), Err(Error::ProgramTooLarge(4)));
+        assert_eq!(limits.compile(b"+[>++<-]"), Err(Error::ProgramTooLarge(4)));
+    }
+}
@@ -1,9 +1,11 @@
-use std::io;
+use std::{io, slice, thread};
 
-use common::{BfResult, Error, Count};
+use common::{BfResult, Count};
+use options::{CompileOptions, RunOptions};
 use rts::{self, RtsState};
+use sanitizer::Sanitizer;
 use state::DEFAULT_CAPACITY;
-use peephole;
+use peephole::{self, continuation::bytecode_len, offsets};
 
 use super::wrapper::*;
 
@@ -13,16 +15,27 @@ pub trait LlvmCompilable {
     fn with_peephole<F, R>(&self, k: F) -> R
         where F: FnOnce(&peephole::Program) -> R;
 
-    /// JIT compile and run the given program via LLVM.
-    fn llvm_run(&self, memory_size: Option<usize>) -> BfResult<()> {
+    /// JIT compile and run the given program via LLVM, with the memory size, output buffering
+    /// and input batching of the given run options.
+    fn llvm_run(&self, options: &CompileOptions, run: &RunOptions) -> BfResult<()> {
+        let memory_size = run.memory_size;
         let mut stdin = io::stdin();
         let mut stdout = io::stdout();
-        let rts_state = RtsState::new(&mut stdin, &mut stdout);
-        self.with_peephole(|ast| compile_and_run(ast, memory_size, false, rts_state))
+        let mut sanitizer = Sanitizer::new(memory_size.unwrap_or(DEFAULT_CAPACITY));
+        let mut rts_state = if options.sanitize {
+            RtsState::with_sanitizer(&mut stdin, &mut stdout, &mut sanitizer)
+        } else {
+            RtsState::new(&mut stdin, &mut stdout)
+        };
+        rts_state.set_run_options(run);
+        self.with_peephole(|ast| {
+            compile_and_run_with_options(ast, memory_size, options, false, rts_state)
+        })
     }
 }
 
 /// State required for the LLVM compiler.
+#[derive(Clone, Copy)]
 struct Compiler<'a> {
     /// The LLVM context
     context:        &'a Context,
@@ -34,9 +47,11 @@ struct Compiler<'a> {
     underflow:      BasicBlock<'a>,
     /// Label to jump to for pointer overflow
     overflow:       BasicBlock<'a>,
+    /// Label to jump to when a poll stops the run
+    interrupted:    BasicBlock<'a>,
     /// The size of memory, for bounds checks
     memory_size:    Value<'a>,
-    /// The main function
+    /// The function being compiled
     main_function:  Value<'a>,
     /// &RtsState<'a>
     rts_state:      Value<'a>,
@@ -44,64 +59,155 @@ struct Compiler<'a> {
     read_function:  Value<'a>,
     /// RtsSate::write_c
     write_function: Value<'a>,
+    /// RtsState::check_c
+    check_function: Value<'a>,
+    /// RtsState::poll_c
+    poll_function:  Value<'a>,
+    /// The step count and the count to poll at, as `[u64; 2]`
+    meter:          Value<'a>,
+    /// Whether to check tape accesses with `check_function`
+    sanitize:       bool,
+    /// Whether to count steps in `meter` and poll at safepoints
+    accounting:     bool,
     /// The program’s memory (“tape”)
     memory:         Value<'a>,
     /// The current offset into memory
     pointer:        Value<'a>,
+    /// TBAA access tag for tape bytes, if emitting metadata
+    tape_tbaa:      Option<Value<'a>>,
+    /// TBAA access tag for the data pointer, if emitting metadata
+    pointer_tbaa:   Option<Value<'a>>,
+}
+
+/// Compiles the given program to optimized LLVM IR, returned in textual form.
+///
+/// Value names are assigned deterministically, so the same program always produces the same IR.
+pub fn compile_to_ir(program: &peephole::Program, memory_size: Option<usize>,
+                     options: &CompileOptions) -> String {
+    let context = Context::new();
+    let compiler = Compiler::compile_module(&context, program, memory_size, options);
+    compiler.module.print_to_string()
 }
 
 /// JIT compile and run the given program via LLVM.
 pub fn compile_and_run<'a>(program: &peephole::Program, memory_size: Option<usize>, debug: bool,
-                           mut rts_state: RtsState<'a>) -> BfResult<()> {
-    let context = Context::new();
+                           rts_state: RtsState<'a>) -> BfResult<()> {
+    compile_and_run_with_options(program, memory_size, &CompileOptions::default(), debug,
+                                 rts_state)
+}
 
-    let compiler = Compiler::prologue(&context, memory_size.unwrap_or(DEFAULT_CAPACITY) as u64);
-    compiler.compile_block(program);
-    compiler.epilogue();
+/// JIT compile and run the given program via LLVM with the given options.
+///
+/// With [`sanitize`](../options/struct.CompileOptions.html#structfield.sanitize), `rts_state`
+/// should have been created by
+/// [`RtsState::with_sanitizer`](../rts/struct.RtsState.html#method.with_sanitizer).
+pub fn compile_and_run_with_options<'a>(program: &peephole::Program, memory_size: Option<usize>,
+                                        options: &CompileOptions, debug: bool,
+                                        mut rts_state: RtsState<'a>) -> BfResult<()> {
+    compile_and_run_on(program, memory_size, options, debug, &mut rts_state)
+}
 
-    compiler.module.optimize(3, 0);
+/// Like [`compile_and_run_with_options`](fn.compile_and_run_with_options.html), but leaving
+/// `rts_state` to the caller afterwards.
+///
+/// With [`accounting`](../options/struct.CompileOptions.html#structfield.accounting), the
+/// code counts its steps and polls at its safepoints, the loop jumps and the instructions after
+/// I/O, for the [meter](../rts/struct.RtsState.html#method.set_meter) set on `rts_state`. The
+/// state then has the steps in its [stats](../rts/struct.RtsState.html#method.stats) and, if
+/// the meter stopped the run, where it stopped and the
+/// [tape](../rts/struct.RtsState.html#method.take_stopped_tape) it stopped with.
+pub fn compile_and_run_on<'a>(program: &peephole::Program, memory_size: Option<usize>,
+                              options: &CompileOptions, debug: bool,
+                              rts_state: &mut RtsState<'a>) -> BfResult<()> {
+    let context = Context::new();
+    let compiler = Compiler::compile_module(&context, program, memory_size, options);
 
     if debug {
         compiler.module.dump();
         compiler.module.verify().unwrap();
     }
 
+    let mut meter = [0, rts_state.first_poll()];
+
     // This panics if LLVM fails.
     let result = unsafe {
         compiler.module.with_function("bfi_main",
-                                      |f: extern fn(rts_state: &mut RtsState<'a>,
-                                                    read: extern fn(&mut RtsState<'a>) -> u8,
-                                                    write: extern fn(&mut RtsState<'a>, u8) -> ())
-                                                        -> u64| {
-                                          f(&mut rts_state, RtsState::read_c, RtsState::write_c)
+                                      |f: MainFunction<'a>| {
+                                          f(rts_state, RtsState::read_c, RtsState::write_c,
+                                            RtsState::check_c, RtsState::poll_c,
+                                            meter.as_mut_ptr())
                                       }).unwrap()
     };
 
-    match result {
-        rts::OKAY       => Ok(()),
-        rts::UNDERFLOW  => Err(Error::PointerUnderflow),
-        rts::OVERFLOW   => Err(Error::PointerOverflow),
-        _ => panic!("unrecognized error code"),
+    if options.accounting {
+        rts_state.set_steps(meter[0]);
     }
+    rts_state.status(result)
 }
 
 impl<'a> Compiler<'a> {
-    fn compile_block(&self, body: &[peephole::Statement]) {
+    /// Builds and optimizes the module for the given program.
+    fn compile_module(context: &'a Context, program: &peephole::Program,
+                      memory_size: Option<usize>, options: &CompileOptions) -> Self {
+        let memory_size = memory_size.unwrap_or(DEFAULT_CAPACITY) as u64;
+        let outline = options.outline_loops || options.debug_symbols;
+        let parallel = outline && options.codegen_threads > 1;
+        let compiler = Compiler::prologue(context, memory_size, options);
+        if outline {
+            compiler.compile_outlined(program, options, !parallel);
+        } else {
+            compiler.compile_block(program, 0);
+        }
+        compiler.epilogue();
+        compiler.module.optimize(3, 0);
+
+        // The loops’ modules come back already optimized.
+        if parallel {
+            for bitcode in compile_loops_in_parallel(program, memory_size, options) {
+                let module = Module::parse_bitcode(context, &bitcode).unwrap();
+                compiler.module.link(module).unwrap();
+            }
+        }
+
+        compiler
+    }
+
+    /// Compile a block whose first statement is at bytecode address `pc`.
+    fn compile_block(&self, body: &[peephole::Statement], mut pc: usize) {
         use peephole::Statement::*;
         use common::Instruction::*;
 
         let builder = self.builder;
+        let mut index = 0;
+
+        while let Some(statement) = body.get(index) {
+            index += 1;
+
+            // The sanitizer must see every access before the moves’ bounds checks.
+            if !self.sanitize {
+                if let Some(block) = offsets::block(&body[index - 1 ..]) {
+                    self.count_steps(block.offsets.len() as u64);
+                    self.compile_offset_block(&body[index - 1 ..], &block);
+                    index += block.offsets.len() - 1;
+                    pc += block.offsets.len();
+                    continue;
+                }
+            }
+
+            // A loop counts its own jumps.
+            if let Instr(_) = *statement {
+                self.count_steps(1);
+            }
 
-        for statement in body {
             match *statement {
                 Instr(Right(count)) => {
                     let new_pointer = self.load_pos_offset(count, "new_pointer");
-                    builder.store(new_pointer, self.pointer);
+                    self.store_pointer(new_pointer);
                 }
 
                 Instr(Left(count)) => {
                     let new_pointer = self.load_neg_offset(count, "new_pointer");
-                    builder.store(new_pointer, self.pointer);
+                    self.store_pointer(new_pointer);
                 }
 
                 Instr(Add(count)) => {
@@ -112,27 +218,34 @@ impl<'a> Compiler<'a> {
                 }
 
                 Instr(In) => {
-                    let result = builder.call(self.read_function, &[self.rts_state], "");
+                    let (_, read_type, _, _) = rts_types(self.context);
+                    let result = builder.call(read_type, self.read_function, &[self.rts_state],
+                                              "");
                     self.store_data(result);
+                    self.safepoint(pc + 1);
                 }
 
                 Instr(Out) => {
                     let argument = self.load_data("data");
-                    builder.call(self.write_function, &[self.rts_state, argument], "");
+                    let (_, _, write_type, _) = rts_types(self.context);
+                    builder.call(write_type, self.write_function, &[self.rts_state, argument],
+                                 "");
+                    self.safepoint(pc + 1);
                 }
 
                 Instr(SetZero) => {
                     self.store_data(Value::get_u8(self.context, 0));
                 }
 
+                // The loop is one instruction, so it is one step with no safepoints.
                 Instr(FindZeroRight(count)) => {
-                    let instr = Loop(vec![Instr(Right(count))].into_boxed_slice());
-                    self.compile_block(&[instr]);
+                    let instr = Loop(vec![Instr(Right(count))].into());
+                    Compiler { accounting: false, ..*self }.compile_block(&[instr], pc);
                 }
 
                 Instr(FindZeroLeft(count)) => {
-                    let instr = Loop(vec![Instr(Left(count))].into_boxed_slice());
-                    self.compile_block(&[instr]);
+                    let instr = Loop(vec![Instr(Left(count))].into());
+                    Compiler { accounting: false, ..*self }.compile_block(&[instr], pc);
                 }
 
                 Instr(OffsetAddRight(count)) => {
@@ -171,36 +284,425 @@ impl<'a> Compiler<'a> {
                     builder.position_at_end(after);
                 }
 
+                // The sanitizer leaves the work to the loop, which it checks step by step.
+                Instr(DivMod) if self.sanitize => (),
+                Instr(DivMod) => self.compile_div_mod(),
+                Instr(IndexRight(_)) | Instr(IndexLeft(_)) if self.sanitize => (),
+                Instr(IndexRight(skip)) => self.compile_index(skip, true),
+                Instr(IndexLeft(skip)) => self.compile_index(skip, false),
+
                 Instr(JumpZero(_)) | Instr(JumpNotZero(_)) =>
                     panic!("unexpected instruction"),
 
+                // The header runs the `[` on entering and the `]` after each iteration, whose
+                // safepoint is before it.
                 Loop(ref body) => {
                     let header = self.main_function.append("loop_header");
                     let true_  = self.main_function.append("loop_body");
                     let false_ = self.main_function.append("after_loop");
 
+                    self.count_steps(1);
                     builder.br(header);
 
                     builder.position_at_end(header);
                     self.if_not0(true_, false_);
 
                     builder.position_at_end(true_);
-                    self.compile_block(body);
+                    self.compile_block(body, pc + 1);
+                    self.safepoint(pc + bytecode_len(body) + 1);
+                    self.count_steps(1);
                     builder.br(header);
 
                     builder.position_at_end(false_);
                 }
             }
+
+            pc += match *statement {
+                Loop(ref body) => bytecode_len(body) + 2,
+                Instr(_) => 1,
+            };
+        }
+    }
+
+    /// With accounting, add `steps` to the count.
+    fn count_steps(&self, steps: u64) {
+        if !self.accounting { return; }
+
+        let i64_type = Type::get_i64(self.context);
+        let count = self.builder.load(i64_type, self.meter, "steps");
+        let count = self.builder.add(count, Value::get_u64(self.context, steps), "steps");
+        self.builder.store(count, self.meter);
+    }
+
+    /// With accounting, emit the safepoint before bytecode address `pc`, which calls
+    /// `poll_function` once the count reaches the count to poll at, storing the next such
+    /// count or stopping the run.
+    fn safepoint(&self, pc: usize) {
+        use self::LLVMIntPredicate::{LLVMIntEQ, LLVMIntUGE};
+
+        if !self.accounting { return; }
+
+        let builder = self.builder;
+        let poll = self.main_function.append("poll");
+        let polled = self.main_function.append("polled");
+        let after = self.main_function.append("after_poll");
+
+        let i64_type = Type::get_i64(self.context);
+        let steps = builder.load(i64_type, self.meter, "steps");
+        let poll_at_ptr = builder.gep(i64_type, self.meter, &[Value::get_u64(self.context, 1)],
+                                      "poll_at_ptr");
+        let poll_at = builder.load(i64_type, poll_at_ptr, "poll_at");
+        let due = builder.cmp(LLVMIntUGE, steps, poll_at, "due");
+        builder.cond_br(due, poll, after);
+
+        builder.position_at_end(poll);
+        let pointer = self.load_pointer("pointer");
+        let next = builder.call(poll_type(self.context), self.poll_function,
+                                &[self.rts_state, steps, Value::get_u64(self.context, pc as u64),
+                                  pointer, self.memory, self.memory_size],
+                                "next_poll");
+        let stop = builder.cmp(LLVMIntEQ, next, Value::get_u64(self.context, 0), "stop");
+        builder.cond_br(stop, self.interrupted, polled);
+
+        builder.position_at_end(polled);
+        builder.store(next, poll_at_ptr);
+        builder.br(after);
+
+        builder.position_at_end(after);
+    }
+
+    /// Compile a straight-line block, which starts `body`, by checking its reach once,
+    /// updating cells at offsets from the starting pointer, and storing the pointer once.
+    fn compile_offset_block(&self, body: &[peephole::Statement], block: &offsets::Block) {
+        use self::LLVMIntPredicate::{LLVMIntULE, LLVMIntULT};
+        use peephole::Statement::*;
+        use common::Instruction::*;
+
+        let builder = self.builder;
+        let fits = self.main_function.append("block_fits");
+        let replay = self.main_function.append("block_replay");
+
+        // Both ends of the reach first, and only if one is off the tape, each extreme in turn,
+        // so the block fails at the end that moving step by step would.
+        let start = self.load_pointer("start");
+        let mut checks = Vec::new();
+        if block.max > 0 {
+            let room = builder.sub(self.memory_size, start, "room");
+            checks.push((LLVMIntULT, block.max as u64, room));
+        }
+        if block.min < 0 {
+            checks.push((LLVMIntULE, -block.min as u64, start));
+        }
+        for (pred, reach, limit) in checks {
+            let next = self.main_function.append("block_reach");
+            let comparison = builder.cmp(pred, Value::get_u64(self.context, reach), limit,
+                                         "allowed");
+            builder.cond_br(comparison, next, replay);
+            builder.position_at_end(next);
+        }
+        builder.br(fits);
+
+        builder.position_at_end(replay);
+        for &extreme in &block.extremes {
+            if extreme > 0 {
+                self.load_pos_offset(extreme as Count, "reach");
+            } else {
+                self.load_neg_offset(-extreme as Count, "reach");
+            }
+        }
+        builder.br(fits);
+
+        builder.position_at_end(fits);
+        let pointer = self.load_pointer("base");
+        let at = |offset: isize| builder.add(pointer, Value::get_u64(self.context, offset as u64),
+                                             "cell");
+
+        for (statement, &offset) in body.iter().zip(&block.offsets) {
+            match *statement {
+                Instr(Add(count)) => {
+                    let cell = at(offset);
+                    let count = Value::get_u8(self.context, count);
+                    let old_value = self.load_data_at(cell, "old_val");
+                    let new_value = builder.add(old_value, count, "new_val");
+                    self.store_data_at(cell, new_value);
+                }
+
+                Instr(SetZero) => {
+                    self.store_data_at(at(offset), Value::get_u8(self.context, 0));
+                }
+
+                _ => (),
+            }
+        }
+
+        self.store_pointer(at(block.delta));
+    }
+
+    /// Compile a `DivMod`, which does the following loop’s work in place when
+    /// [`State::div_mod`](../../state/struct.State.html#method.div_mod) would.
+    fn compile_div_mod(&self) {
+        use self::LLVMIntPredicate::{LLVMIntEQ, LLVMIntNE, LLVMIntUGT};
+
+        let builder = self.builder;
+        let function = self.main_function;
+        let block = |name| function.append(name);
+        let (dividend, fourth, fifth, period, divisor, limit) =
+            (block("div_mod_dividend"), block("div_mod_fourth"), block("div_mod_fifth"),
+             block("div_mod_period"), block("div_mod_divisor"), block("div_mod_limit"));
+        let (body, count, reset, next, after) =
+            (block("div_mod_body"), block("div_mod_count"), block("div_mod_reset"),
+             block("div_mod_next"), block("div_mod_after"));
+
+        let byte = |value| Value::get_u8(self.context, value);
+        let pointer = self.load_pointer("pointer");
+        let at = |offset| builder.add(pointer, Value::get_u64(self.context, offset), "cell");
+        let test = |pred, lhs, rhs, then, else_| {
+            let comparison = builder.cmp(pred, lhs, rhs, "test");
+            builder.cond_br(comparison, then, else_);
+        };
+
+        // The five cells to the right must be on the tape, the dividend nonzero, and the last
+        // two cells zero.
+        let room = builder.sub(self.memory_size, pointer, "room");
+        test(LLVMIntUGT, room, Value::get_u64(self.context, 5), dividend, after);
+
+        builder.position_at_end(dividend);
+        let n = self.load_data_at(pointer, "n");
+        test(LLVMIntNE, n, byte(0), fourth, after);
+
+        builder.position_at_end(fourth);
+        test(LLVMIntEQ, self.load_data_at(at(4), "fourth"), byte(0), fifth, after);
+
+        builder.position_at_end(fifth);
+        test(LLVMIntEQ, self.load_data_at(at(5), "fifth"), byte(0), period, after);
+
+        // With `d + r == 1` and `0 < d <= n`, the loop walks off to the right once `d`
+        // reaches zero, so leave it to the loop.
+        builder.position_at_end(period);
+        let d = self.load_data_at(at(1), "d");
+        let r = self.load_data_at(at(2), "r");
+        test(LLVMIntNE, builder.add(d, r, "sum"), byte(1), body, divisor);
+
+        builder.position_at_end(divisor);
+        test(LLVMIntEQ, d, byte(0), body, limit);
+
+        builder.position_at_end(limit);
+        test(LLVMIntUGT, d, n, body, after);
+
+        builder.position_at_end(body);
+        let n = self.load_data_at(pointer, "n");
+        self.store_data_at(pointer, builder.sub(n, byte(1), "n"));
+        let d = builder.sub(self.load_data_at(at(1), "d"), byte(1), "d");
+        self.store_data_at(at(1), d);
+        test(LLVMIntNE, d, byte(0), count, reset);
+
+        builder.position_at_end(count);
+        let r = self.load_data_at(at(2), "r");
+        self.store_data_at(at(2), builder.add(r, byte(1), "r"));
+        builder.br(next);
+
+        builder.position_at_end(reset);
+        let r = self.load_data_at(at(2), "r");
+        self.store_data_at(at(1), builder.add(r, byte(1), "d"));
+        self.store_data_at(at(2), byte(0));
+        let q = self.load_data_at(at(3), "q");
+        self.store_data_at(at(3), builder.add(q, byte(1), "q"));
+        builder.br(next);
+
+        builder.position_at_end(next);
+        test(LLVMIntNE, self.load_data_at(pointer, "n"), byte(0), body, after);
+
+        builder.position_at_end(after);
+    }
+
+    /// Compile an `IndexRight` or `IndexLeft`, which does the following loop’s work in place
+    /// when [`State::index_right`](../../state/struct.State.html#method.index_right) would.
+    ///
+    /// The index counts down in its cell as the pointer steps along, and both are put back if
+    /// a step would leave the tape or land on a cell that is not zero.
+    fn compile_index(&self, skip: Count, right: bool) {
+        use self::LLVMIntPredicate::{LLVMIntEQ, LLVMIntNE, LLVMIntUGT, LLVMIntULE};
+
+        let builder = self.builder;
+        let block = |name| self.main_function.append(name);
+        let (step, land, count, undo, after) =
+            (block("index_step"), block("index_land"), block("index_count"),
+             block("index_undo"), block("index_after"));
+
+        let zero = Value::get_u8(self.context, 0);
+        let skip = Value::get_u64(self.context, skip as u64);
+        let base = self.load_pointer("base");
+        let index = self.load_data_at(base, "index");
+        let test = |pred, lhs, rhs, then, else_| {
+            let comparison = builder.cmp(pred, lhs, rhs, "test");
+            builder.cond_br(comparison, then, else_);
+        };
+        test(LLVMIntNE, index, zero, step, after);
+
+        builder.position_at_end(step);
+        let pointer = self.load_pointer("pointer");
+        let next = if right {
+            let room = builder.sub(self.memory_size, pointer, "room");
+            test(LLVMIntUGT, room, skip, land, undo);
+            builder.position_at_end(land);
+            builder.add(pointer, skip, "next")
+        } else {
+            test(LLVMIntULE, skip, pointer, land, undo);
+            builder.position_at_end(land);
+            builder.sub(pointer, skip, "next")
+        };
+        test(LLVMIntEQ, self.load_data_at(next, "cell"), zero, count, undo);
+
+        builder.position_at_end(count);
+        self.store_pointer(next);
+        let left = self.load_data_at(base, "left");
+        let left = builder.sub(left, Value::get_u8(self.context, 1), "left");
+        self.store_data_at(base, left);
+        test(LLVMIntNE, left, zero, step, after);
+
+        builder.position_at_end(undo);
+        self.store_pointer(base);
+        self.store_data_at(base, index);
+        builder.br(after);
+
+        builder.position_at_end(after);
+    }
+
+    /// Compile a block, calling a separate function for each loop in it. If `define` is false,
+    /// the functions are only declared, to be linked in later.
+    fn compile_outlined(&self, body: &[peephole::Statement], options: &CompileOptions,
+                        define: bool) {
+        let mut next_loop = 0;
+        let mut straight = 0;
+        let mut straight_pc = 0;
+
+        for ((index, statement), pc) in body.iter().enumerate().zip(addresses(body)) {
+            if let peephole::Statement::Loop(ref loop_body) = *statement {
+                self.compile_block(&body[straight .. index], straight_pc);
+                straight = index + 1;
+                straight_pc = pc + bytecode_len(loop_body) + 2;
+
+                let function = Compiler::declare_loop(self.module, next_loop, options);
+                next_loop += 1;
+
+                if define {
+                    let resume = self.main_function.append("call_loop");
+                    self.builder.br(resume);
+                    Compiler::define_loop(self.module, self.builder, function, statement, pc,
+                                          self.memory_size, options);
+                    self.builder.position_at_end(resume);
+                }
+
+                let result = self.builder.call(loop_type(self.context), function,
+                                               &[self.rts_state, self.read_function,
+                                                 self.write_function, self.check_function,
+                                                 self.poll_function, self.meter,
+                                                 self.memory, self.pointer],
+                                               "loop_result");
+                self.return_unless_okay(result);
+            }
+        }
+
+        self.compile_block(&body[straight ..], straight_pc);
+    }
+
+    /// Return the given status code if it isn’t `OKAY`.
+    fn return_unless_okay(&self, status: Value<'a>) {
+        let okay = Value::get_u64(self.context, rts::OKAY);
+        let failed = self.main_function.append("failed");
+        let after = self.main_function.append("okay");
+        let comparison = self.builder.cmp(LLVMIntPredicate::LLVMIntNE, status, okay, "failed");
+        self.builder.cond_br(comparison, failed, after);
+
+        self.builder.position_at_end(failed);
+        self.builder.ret(status);
+
+        self.builder.position_at_end(after);
+    }
+
+    /// Declare the function `bfi_loop_<index>`, which takes the main function’s parameters, the
+    /// tape, and the data pointer, and returns a status code.
+    fn declare_loop(module: Module<'a>, index: usize, options: &CompileOptions) -> Value<'a> {
+        let function = module.add_function(&format!("bfi_loop_{}", index),
+                                           loop_type(module.context()));
+        // Otherwise the optimizer would just inline it back in.
+        function.add_attribute(FUNCTION_INDEX, "noinline");
+        add_function_attributes(function, options);
+        if options.metadata {
+            for param in 1 .. 9 {
+                function.add_attribute(param, "nonnull");
+            }
+            function.add_attribute(7, "noalias");
+            function.add_attribute(8, "noalias");
+        }
+
+        function
+    }
+
+    /// Emit the body of a function declared by `declare_loop`, which runs the given loop, at
+    /// bytecode address `pc`. This repositions the builder.
+    fn define_loop(module: Module<'a>, builder: Builder<'a>, function: Value<'a>,
+                   statement: &peephole::Statement, pc: usize, memory_size: Value<'a>,
+                   options: &CompileOptions) {
+        builder.position_at_end(function.append("entry"));
+        let compiler = Compiler::for_function(module, builder, function,
+                                              function.get_fun_param(6),
+                                              function.get_fun_param(7),
+                                              memory_size, options);
+        compiler.compile_block(slice::from_ref(statement), pc);
+        compiler.epilogue();
+    }
+
+    /// Set up to compile the body of `function`, whose first six parameters are those of
+    /// `bfi_main`, given its tape and data pointer. The builder must be in the entry block.
+    fn for_function(module: Module<'a>, builder: Builder<'a>, function: Value<'a>,
+                    memory: Value<'a>, pointer: Value<'a>, memory_size: Value<'a>,
+                    options: &CompileOptions) -> Self {
+        let context = module.context();
+
+        // Tape bytes and the data pointer never alias, which TBAA lets us tell LLVM.
+        let (tape_tbaa, pointer_tbaa) = if options.metadata {
+            let root = Value::md_node(context, &[Value::md_string(context, "bf tbaa root")]);
+            let tag = |name| {
+                let ty = Value::md_node(context, &[Value::md_string(context, name), root,
+                                                   Value::get_u64(context, 0)]);
+                Value::md_node(context, &[ty, ty, Value::get_u64(context, 0)])
+            };
+            (Some(tag("bf tape")), Some(tag("bf pointer")))
+        } else {
+            (None, None)
+        };
+
+        Compiler {
+            context,
+            module,
+            builder,
+            underflow:      function.append("underflow"),
+            overflow:       function.append("overflow"),
+            interrupted:    function.append("interrupted"),
+            memory_size,
+            main_function:  function,
+            pointer,
+            memory,
+            rts_state:      function.get_fun_param(0),
+            read_function:  function.get_fun_param(1),
+            write_function: function.get_fun_param(2),
+            check_function: function.get_fun_param(3),
+            poll_function:  function.get_fun_param(4),
+            meter:          function.get_fun_param(5),
+            sanitize:       options.sanitize,
+            accounting:     options.accounting,
+            tape_tbaa,
+            pointer_tbaa,
         }
     }
 
     /// Set up compilation.
-    fn prologue(context: &'a Context, memory_size: u64) -> Self {
+    fn prologue(context: &'a Context, memory_size: u64, options: &CompileOptions) -> Self {
         let module = Module::new(context, "bfi_module");
 
         // Some useful types
         let i64_type        = Type::get_i64(context);
-        let i32_type        = Type::get_i32(context);
         let i8_type         = Type::get_i8(context);
         let bool_type       = Type::get_bool(context);
         let void_type       = Type::get_void(context);
@@ -209,55 +711,44 @@ impl<'a> Compiler<'a> {
         // The size of memory as an LLVM Value
         let memory_size = Value::get_u64(context, memory_size);
 
-        let rts_state_type = Type::get_pointer(Type::get_void(context));
-        let write_function_type = Type::get_function(&[rts_state_type, i8_type], void_type);
-        let read_function_type = Type::get_function(&[rts_state_type], i8_type);
-
         // Create the main function, create an entry basic block, and position a builder at entry.
-        let main_function_type = Type::get_function(&[
-            rts_state_type,
-            Type::get_pointer(read_function_type),
-            Type::get_pointer(write_function_type)], i64_type);
+        let main_function_type = main_type(context);
         let main_function  = module.add_function("bfi_main", main_function_type);
+        add_function_attributes(main_function, options);
+        if options.metadata {
+            main_function.add_attribute(1, "noalias");
+            for param in 1 .. 7 {
+                main_function.add_attribute(param, "nonnull");
+            }
+        }
         let entry_bb = main_function.append("entry");
         let builder = Builder::new(context);
         builder.position_at_end(entry_bb);
 
         // All state for the compiler.
-        let compiler = Compiler {
-            context:        context,
-            module:         module,
-            builder:        builder,
-            underflow:      main_function.append("underflow"),
-            overflow:       main_function.append("overflow"),
-            memory_size:    memory_size,
-            main_function:  main_function,
-            pointer:        builder.alloca(i64_type, "pointer"),
-            memory:         builder.array_alloca(i8_type, memory_size, "memory"),
-            rts_state:      main_function.get_fun_param(0),
-            read_function:  main_function.get_fun_param(1),
-            write_function: main_function.get_fun_param(2),
-        };
+        let pointer = builder.alloca(i64_type, "pointer");
+        let memory  = builder.array_alloca(i8_type, memory_size, "memory");
+        let compiler = Compiler::for_function(module, builder, main_function, memory, pointer,
+                                              memory_size, options);
 
         // Zero-initialize the memory
-        let memset_type = Type::get_function(&[char_ptr_type, i8_type, i64_type, i32_type, bool_type],
+        let memset_type = Type::get_function(&[char_ptr_type, i8_type, i64_type, bool_type],
                                              void_type);
-        let memset = compiler.module.add_function("llvm.memset.p0i8.i64", memset_type);
-        builder.call(memset,
+        let memset = compiler.module.declare_memset(memset_type);
+        builder.call(memset_type, memset,
                      &[compiler.memory,
                          Value::get_u8(context, 0),
                          compiler.memory_size,
-                         Value::get_u32(context, 0),
                          Value::get_bool(context, false)],
                      "");
 
         // Start the data pointer at 0.
-        builder.store(Value::get_u64(context, 0), compiler.pointer);
+        compiler.store_pointer(Value::get_u64(context, 0));
 
         compiler
     }
 
-    /// Emit the returns for the successful path and both error paths.
+    /// Emit the returns for the successful path and the error and interruption paths.
     fn epilogue(&self) {
         self.builder.ret(Value::get_u64(self.context, rts::OKAY));
 
@@ -266,6 +757,9 @@ impl<'a> Compiler<'a> {
 
         self.builder.position_at_end(self.overflow);
         self.builder.ret(Value::get_u64(self.context, rts::OVERFLOW));
+
+        self.builder.position_at_end(self.interrupted);
+        self.builder.ret(Value::get_u64(self.context, rts::INTERRUPTED));
     }
 
     /// Branch based on whether the byte at the data pointer is 0.
@@ -276,34 +770,68 @@ impl<'a> Compiler<'a> {
         self.builder.cond_br(comparison, true_, false_);
     }
 
+    /// Attach the given TBAA tag, if any, to a load or store.
+    fn tag(&self, instruction: Value<'a>, tbaa: Option<Value<'a>>) -> Value<'a> {
+        if let Some(tbaa) = tbaa {
+            instruction.set_metadata("tbaa", tbaa);
+        }
+        instruction
+    }
+
+    /// Load the data pointer.
+    fn load_pointer(&self, name: &str) -> Value<'a> {
+        let i64_type = Type::get_i64(self.context);
+        self.tag(self.builder.load(i64_type, self.pointer, name), self.pointer_tbaa)
+    }
+
+    /// Store the data pointer.
+    fn store_pointer(&self, value: Value<'a>) {
+        self.tag(self.builder.store(value, self.pointer), self.pointer_tbaa);
+    }
+
+    /// If sanitizing, check an access at the given index into memory.
+    fn check_access(&self, index: Value<'a>, write: bool) {
+        if self.sanitize {
+            let (_, _, _, check_type) = rts_types(self.context);
+            let write = Value::get_u8(self.context, write as u8);
+            let status = self.builder.call(check_type, self.check_function,
+                                           &[self.rts_state, index, write], "status");
+            self.return_unless_okay(status);
+        }
+    }
+
     /// Load the byte from the given index into memory.
     fn load_data_at(&self, index: Value<'a>, name: &str) -> Value<'a> {
-        let address = self.builder.gep(self.memory, &[index], "data_ptr");
-        self.builder.load(address, name)
+        self.check_access(index, false);
+        let i8_type = Type::get_i8(self.context);
+        let address = self.builder.gep(i8_type, self.memory, &[index], "data_ptr");
+        self.tag(self.builder.load(i8_type, address, name), self.tape_tbaa)
     }
 
     /// Store the given value at the given index into memory.
     fn store_data_at(&self, index: Value<'a>, value: Value<'a>) {
-        let address = self.builder.gep(self.memory, &[index], "data_ptr");
-        self.builder.store(value, address);
+        self.check_access(index, true);
+        let i8_type = Type::get_i8(self.context);
+        let address = self.builder.gep(i8_type, self.memory, &[index], "data_ptr");
+        self.tag(self.builder.store(value, address), self.tape_tbaa);
     }
 
     /// Load the byte from the data pointer.
     fn load_data(&self, name: &str) -> Value<'a> {
-        let pointer = self.builder.load(self.pointer, "");
+        let pointer = self.load_pointer("");
         self.load_data_at(pointer, name)
     }
 
     /// Store the given value at the data pointer.
     fn store_data(&self, value: Value<'a>) {
-        let pointer = self.builder.load(self.pointer, "");
+        let pointer = self.load_pointer("");
         self.store_data_at(pointer, value);
     }
 
     /// Add the given offset to the data pointer, checking for overflow.
     fn load_pos_offset(&self, offset: Count, name: &str) -> Value<'a> {
         let success = self.main_function.append("right_success");
-        let old_pointer = self.builder.load(self.pointer, "old_pointer");
+        let old_pointer = self.load_pointer("old_pointer");
         let allowed = self.builder.sub(self.memory_size, old_pointer, "room");
         let offset = Value::get_u64(self.context, offset as u64);
         let comparison = self.builder.cmp(LLVMIntPredicate::LLVMIntULT, offset, allowed, "allowed");
@@ -315,7 +843,7 @@ impl<'a> Compiler<'a> {
     /// Subtract the given offset from the data pointer, checking for underflow.
     fn load_neg_offset(&self, offset: Count, name: &str) -> Value<'a> {
         let success = self.main_function.append("left_success");
-        let old_pointer = self.builder.load(self.pointer, "old_pointer");
+        let old_pointer = self.load_pointer("old_pointer");
         let offset = Value::get_u64(self.context, offset as u64);
         let comparison = self.builder.cmp(LLVMIntPredicate::LLVMIntULE, offset, old_pointer,
                                      "allowed");
@@ -325,6 +853,127 @@ impl<'a> Compiler<'a> {
     }
 }
 
+/// Add the function attributes that the options call for.
+fn add_function_attributes(function: Value, options: &CompileOptions) {
+    if options.metadata {
+        function.add_attribute(FUNCTION_INDEX, "nounwind");
+    }
+
+    // Frame pointers let debuggers walk the stack through generated code. (Unwind tables would
+    // too, but how to ask for them depends on the LLVM version.)
+    if options.debug_symbols {
+        function.add_string_attribute(FUNCTION_INDEX, "frame-pointer", "all");
+    }
+}
+
+/// The types of `&mut RtsState`, `RtsState::read_c`, `RtsState::write_c`, and
+/// `RtsState::check_c`.
+///
+/// The state is opaque to LLVM, so we pass it as an `i8*` (LLVM has no `void*`).
+fn rts_types<'a>(context: &'a Context) -> (Type<'a>, Type<'a>, Type<'a>, Type<'a>) {
+    let i8_type = Type::get_i8(context);
+    let i64_type = Type::get_i64(context);
+    let rts_state_type = Type::get_pointer(i8_type);
+    let write_function_type = Type::get_function(&[rts_state_type, i8_type],
+                                                 Type::get_void(context));
+    let read_function_type = Type::get_function(&[rts_state_type], i8_type);
+    let check_function_type = Type::get_function(&[rts_state_type, i64_type, i8_type],
+                                                 i64_type);
+    (rts_state_type, read_function_type, write_function_type, check_function_type)
+}
+
+/// The type of `RtsState::poll_c`.
+fn poll_type(context: &Context) -> Type<'_> {
+    let i64_type = Type::get_i64(context);
+    let (rts_state_type, _, _, _) = rts_types(context);
+    Type::get_function(&[rts_state_type, i64_type, i64_type, i64_type,
+                         Type::get_pointer(Type::get_i8(context)), i64_type],
+                       i64_type)
+}
+
+/// The parameter types of `bfi_main`, which takes the run-time state, its functions, and the
+/// step count followed by the count to poll at.
+fn main_params(context: &Context) -> Vec<Type<'_>> {
+    let (rts_state_type, read_function_type, write_function_type, check_function_type) =
+        rts_types(context);
+    vec![rts_state_type,
+         Type::get_pointer(read_function_type),
+         Type::get_pointer(write_function_type),
+         Type::get_pointer(check_function_type),
+         Type::get_pointer(poll_type(context)),
+         Type::get_pointer(Type::get_i64(context))]
+}
+
+/// The type of `bfi_main`.
+fn main_type(context: &Context) -> Type<'_> {
+    Type::get_function(&main_params(context), Type::get_i64(context))
+}
+
+/// The type of an outlined loop, which takes `bfi_main`’s parameters, the tape, and the data
+/// pointer.
+fn loop_type(context: &Context) -> Type<'_> {
+    let i64_type = Type::get_i64(context);
+    let mut params = main_params(context);
+    params.push(Type::get_pointer(Type::get_i8(context)));
+    params.push(Type::get_pointer(i64_type));
+    Type::get_function(&params, i64_type)
+}
+
+/// Compile the program’s top-level loops into optimized modules on `codegen_threads` worker
+/// threads, each with its own context, returning the modules as bitcode.
+fn compile_loops_in_parallel(program: &peephole::Program, memory_size: u64,
+                             options: &CompileOptions) -> Vec<Vec<u8>> {
+    let threads = options.codegen_threads;
+    let options = *options;
+
+    let mut work = vec![Vec::new(); threads];
+    let loops = program.iter().zip(addresses(program))
+        .filter(|&(s, _)| matches!(*s, peephole::Statement::Loop(_)));
+    for (index, (statement, pc)) in loops.enumerate() {
+        work[index % threads].push((index, pc, statement.clone()));
+    }
+
+    let handles = work.into_iter()
+        .filter(|loops| !loops.is_empty())
+        .map(|loops| thread::spawn(move || compile_loops(&loops, memory_size, &options)))
+        .collect::<Vec<_>>();
+
+    handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+}
+
+/// Compile the given loops, with their indices and bytecode addresses, into an optimized module
+/// in a fresh context, returned as bitcode.
+fn compile_loops(loops: &[(usize, usize, peephole::Statement)], memory_size: u64,
+                 options: &CompileOptions) -> Vec<u8>
+{
+    let context = Context::new();
+    let module = Module::new(&context, "bfi_loops");
+    let builder = Builder::new(&context);
+    let memory_size = Value::get_u64(&context, memory_size);
+
+    for &(index, pc, ref statement) in loops {
+        let function = Compiler::declare_loop(module, index, options);
+        Compiler::define_loop(module, builder, function, statement, pc, memory_size, options);
+    }
+
+    module.optimize(3, 0);
+    module.write_bitcode()
+}
+
+/// The bytecode addresses of a block’s statements, given that it starts at address 0.
+fn addresses(body: &[peephole::Statement]) -> Vec<usize> {
+    body.iter()
+        .scan(0, |pc, statement| {
+            let address = *pc;
+            *pc += match *statement {
+                peephole::Statement::Loop(ref body) => bytecode_len(body) + 2,
+                peephole::Statement::Instr(_) => 1,
+            };
+            Some(address)
+        })
+        .collect()
+}
+
 impl LlvmCompilable for peephole::Program {
     fn with_peephole<F, R>(&self, k: F) -> R
         where F: FnOnce(&peephole::Program) -> R
@@ -341,4 +990,277 @@ impl<T: peephole::PeepholeCompilable + ?Sized> LlvmCompilable for T {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    use bytecode::{Execution, StepResult};
+    use common::Error;
+    use rts::{Interrupt, Meter};
+    use state::State;
+    use test_helpers::*;
+    use traits::{BytecodeCompilable, Interpretable, PeepholeCompilable};
 
+    #[test]
+    fn ir_is_deterministic() {
+        let options = CompileOptions::default();
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+        let copy = program.to_vec().into_boxed_slice();
+        assert_eq!(compile_to_ir(&program, None, &options), compile_to_ir(&copy, None, &options));
+    }
+
+    #[test]
+    fn metadata_can_be_toggled() {
+        let program = ::ast::parse_program(b",[>,]<[.<]").unwrap().peephole_compile();
+        let with = CompileOptions::default();
+        let without = CompileOptions { metadata: false, ..with };
+
+        let ir = compile_to_ir(&program, None, &with);
+        assert!(ir.contains("noalias") && ir.contains("nounwind") && ir.contains("!tbaa"));
+
+        let ir = compile_to_ir(&program, None, &without);
+        assert!(!ir.contains("noalias") && !ir.contains("!tbaa"));
+    }
+
+    #[test]
+    fn factoring_with_and_without_metadata() {
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+
+        for &metadata in &[true, false] {
+            let options = CompileOptions { metadata, ..CompileOptions::default() };
+            let mut input: &[u8] = b"100\n";
+            let mut output = Vec::new();
+            let result = {
+                let rts_state = RtsState::new(&mut input, &mut output);
+                compile_and_run_with_options(&program, None, &options, false, rts_state)
+            };
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, b"100: 2 2 5 5\n");
+        }
+    }
+
+    #[test]
+    fn offset_blocks_update_cells_and_check_reach() {
+        let run = |source: &[u8], memory_size: usize| {
+            let program = ::ast::parse_program(source).unwrap().peephole_compile();
+            let mut input: &[u8] = b"";
+            let mut output = Vec::new();
+            let result = {
+                let rts_state = RtsState::new(&mut input, &mut output);
+                compile_and_run_with_options(&program, Some(memory_size), &CompileOptions::default(),
+                                             false, rts_state)
+            };
+            result.map(|()| output)
+        };
+
+        assert_eq!(run(b">+++>++<<[-]+>>>++++<<.>.<<.", 8), Ok(vec![3, 2, 1]));
+        assert_eq!(run(b">+>+<<<+", 8), Err(Error::PointerUnderflow));
+        assert_eq!(run(b">+>+>>+", 4), Err(Error::PointerOverflow));
+        assert_eq!(run(b"+>>>><<<<<", 4), Err(Error::PointerOverflow));
+    }
+
+    #[test]
+    fn div_mod_divides_in_place() {
+        let run = |n: u8, d: u8, r: u8, memory_size: usize| {
+            let mut source = vec![b'+'; n as usize];
+            source.push(b'>');
+            source.extend(vec![b'+'; d as usize]);
+            source.push(b'>');
+            source.extend(vec![b'+'; r as usize]);
+            source.extend_from_slice(b"<<[->-[>+>>]>[+[-<+>]>+>>]<<<<<].>.>.>.");
+            let program = ::ast::parse_program(&source).unwrap();
+            let expected = program.interpret_memory(Some(memory_size), b"");
+            let program = program.peephole_compile();
+            let mut input: &[u8] = b"";
+            let mut output = Vec::new();
+            let result = {
+                let rts_state = RtsState::new(&mut input, &mut output);
+                compile_and_run_with_options(&program, Some(memory_size), &CompileOptions::default(),
+                                             false, rts_state)
+            };
+            assert_eq!(result.map(|()| output), expected, "{} {} {} {}", n, d, r, memory_size);
+        };
+
+        for &(n, d, r) in &[(17, 5, 0), (200, 7, 0), (10, 1, 0), (3, 0, 1), (9, 4, 2), (4, 0, 0)] {
+            run(n, d, r, 30);
+        }
+        run(17, 5, 0, 5);
+    }
+
+    #[test]
+    fn index_loops_move_in_place() {
+        let run = |source: &[u8], memory_size: usize| {
+            let program = ::ast::parse_program(source).unwrap();
+            let expected = program.interpret_memory(Some(memory_size), b"");
+            let program = program.peephole_compile();
+            let mut input: &[u8] = b"";
+            let mut output = Vec::new();
+            let result = {
+                let rts_state = RtsState::new(&mut input, &mut output);
+                compile_and_run_with_options(&program, Some(memory_size), &CompileOptions::default(),
+                                             false, rts_state)
+            };
+            assert_eq!(result.map(|()| output), expected, "{}", String::from_utf8_lossy(source));
+        };
+
+        for &size in &[7, 10] {
+            run(b">+>>>+<<<<>>>>+++[[->+<]>-].>.<<<.", size);
+            run(b">>>>>>++[[->+<]>-].>.<<<.", size);
+            run(b">>>>>>++[-[-<<+>>]<<].>.<<<.", size);
+            run(b">+<+++++>>>>+++[[-<+>]<-].<.", size);
+        }
+    }
+
+    #[test]
+    fn outlined_loops() {
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+
+        for &codegen_threads in &[1, 3] {
+            let options = CompileOptions {
+                outline_loops: true,
+                codegen_threads,
+                ..CompileOptions::default()
+            };
+
+            let ir = compile_to_ir(&program, None, &options);
+            assert!(ir.contains("@bfi_loop_0("));
+            assert_eq!(ir, compile_to_ir(&program, None, &options));
+
+            let mut input: &[u8] = b"100\n";
+            let mut output = Vec::new();
+            let result = {
+                let rts_state = RtsState::new(&mut input, &mut output);
+                compile_and_run_with_options(&program, None, &options, false, rts_state)
+            };
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, b"100: 2 2 5 5\n");
+        }
+    }
+
+    #[test]
+    fn outlined_loop_errors_propagate() {
+        let options = CompileOptions { outline_loops: true, ..CompileOptions::default() };
+        let program = ::ast::parse_program(b"+[<+]").unwrap().peephole_compile();
+        let mut input: &[u8] = b"";
+        let mut output = Vec::new();
+        let result = {
+            let rts_state = RtsState::new(&mut input, &mut output);
+            compile_and_run_with_options(&program, None, &options, false, rts_state)
+        };
+        assert_eq!(result, Err(Error::PointerUnderflow));
+    }
+
+    #[test]
+    fn accounting_counts_the_interpreters_steps() {
+        let ast = ::ast::parse_program(FACTOR_SRC).unwrap();
+        let (_, expected) = (*ast.bytecode_compile())
+            .interpret_stats(State::new(), &b"12\n"[..], Vec::new());
+        let program = ast.peephole_compile();
+
+        for &outline_loops in &[false, true] {
+            let options = CompileOptions { accounting: true, outline_loops,
+                                           ..CompileOptions::default() };
+            let mut input: &[u8] = b"12\n";
+            let mut output = Vec::new();
+            let (result, stats) = {
+                let mut rts_state = RtsState::new(&mut input, &mut output);
+                let result = compile_and_run_on(&program, None, &options, false, &mut rts_state);
+                (result, rts_state.stats())
+            };
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, b"12: 2 2 3\n");
+            assert_eq!((stats.steps, stats.input_bytes, stats.output_bytes),
+                       (expected.steps, 3, 10));
+        }
+    }
+
+    #[test]
+    fn safepoints_stop_runs_with_their_tape() {
+        let program = ::ast::parse_program(b"+[>+[>+<+]<+]").unwrap().peephole_compile();
+        let options = CompileOptions { accounting: true, ..CompileOptions::default() };
+        let cancel = AtomicBool::new(true);
+        let (mut input, mut output) = (&b""[..], Vec::new());
+        let mut rts_state = RtsState::new(&mut input, &mut output);
+        rts_state.set_meter(Meter { cancel: Some(&cancel), ..Meter::default() });
+
+        let result = compile_and_run_on(&program, Some(10), &options, false, &mut rts_state);
+        assert_eq!(result, Err(Error::Interrupted));
+        let stopped_at = rts_state.stopped_at().unwrap();
+        assert_eq!(stopped_at.interrupt, Interrupt::Cancelled);
+
+        // The bytecode interpreter goes on from the safepoint with the tape left there, and
+        // finishes as if it had run the whole way.
+        let bytecode = ::bytecode::compile(&program);
+        let finish = |mut execution: Execution| {
+            let mut steps = 0;
+            while execution.step() == Ok(StepResult::Continue) {
+                steps += 1;
+            }
+            (steps, execution.into_state())
+        };
+        let tape = rts_state.take_stopped_tape().unwrap();
+        assert_eq!((tape.capacity(), tape.pointer()), (10, stopped_at.pointer));
+        let (more, state) = finish(Execution::resume(&bytecode, tape, stopped_at.pc));
+        let (steps, expected) = finish(Execution::new(&bytecode, State::with_capacity(10)));
+        assert_eq!((stopped_at.steps + more, state), (steps, expected));
+
+        // Output is a safepoint too.
+        let program = ::ast::parse_program(b"+.+.+.+.").unwrap().peephole_compile();
+        let (mut input, mut output) = (&b""[..], Vec::new());
+        let stopped_at = {
+            let mut rts_state = RtsState::new(&mut input, &mut output);
+            rts_state.set_meter(Meter { fuel: Some(5), ..Meter::default() });
+            let result = compile_and_run_on(&program, Some(10), &options, false, &mut rts_state);
+            assert_eq!(result, Err(Error::Interrupted));
+            rts_state.stopped_at().unwrap()
+        };
+        assert_eq!((stopped_at.pc, stopped_at.steps, stopped_at.interrupt),
+                   (6, 6, Interrupt::OutOfFuel));
+        assert_eq!(output, [1, 2, 3]);
+    }
+
+    #[test]
+    fn debug_symbols() {
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+        let options = CompileOptions { debug_symbols: true, ..CompileOptions::default() };
+
+        let ir = compile_to_ir(&program, None, &options);
+        assert!(ir.contains("@bfi_loop_0("));
+        assert!(ir.contains("\"frame-pointer\"=\"all\""));
+
+        let ir = compile_to_ir(&program, None, &CompileOptions::default());
+        assert!(!ir.contains("frame-pointer"));
+    }
+
+    #[test]
+    fn sanitized_factoring() {
+        let program = ::ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+        let options = CompileOptions { sanitize: true, ..CompileOptions::default() };
+        let mut sanitizer = Sanitizer::new(DEFAULT_CAPACITY);
+        let mut input: &[u8] = b"100\n";
+        let mut output = Vec::new();
+        let result = {
+            let rts_state = RtsState::with_sanitizer(&mut input, &mut output, &mut sanitizer);
+            compile_and_run_with_options(&program, None, &options, false, rts_state)
+        };
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, b"100: 2 2 5 5\n");
+        assert_eq!(sanitizer.violation(), None);
+    }
+
+    #[test]
+    fn sanitizer_records_uninitialized_reads() {
+        let program = ::ast::parse_program(b"+>.<.>>[-]").unwrap().peephole_compile();
+        let options = CompileOptions { sanitize: true, ..CompileOptions::default() };
+        let mut sanitizer = Sanitizer::new(DEFAULT_CAPACITY);
+        let mut input: &[u8] = b"";
+        let mut output = Vec::new();
+        let result = {
+            let rts_state = RtsState::with_sanitizer(&mut input, &mut output, &mut sanitizer);
+            compile_and_run_with_options(&program, None, &options, false, rts_state)
+        };
+        assert_eq!(result, Ok(()));
+        assert_eq!(sanitizer.uninitialized_reads(), &[0, 1]);
+    }
+}
@@ -2,8 +2,13 @@
 //!
 //! Enabled with `--features=llvm`. This is actually quite slow, because LLVM takes a long time
 //! optimizing. However, the actual running of the optimized code appears to be quite fast.
+//!
+//! The generated code calls back into the run-time system through function pointers passed to
+//! it as arguments, so it has no external symbols for the execution engine to resolve, and
+//! nothing beyond LLVM itself (such as libffi) needs to be linked.
 
 mod wrapper;
 mod compiler;
 
-pub use self::compiler::{LlvmCompilable, compile_and_run};
+pub use self::compiler::{LlvmCompilable, compile_and_run, compile_and_run_with_options,
+                         compile_to_ir};
@@ -1,50 +1,142 @@
 use std::ffi::{CString, CStr};
 use std::os::raw::{c_char, c_uint};
-use std::{mem, ptr};
+use std::{mem, ptr, slice};
 use std::cell::RefCell;
+use std::collections::HashMap;
 
 use llvm_sys;
 use llvm_sys::prelude::*;
 use llvm_sys::core::*;
 use llvm_sys::target;
 use llvm_sys::analysis::{LLVMVerifyModule, LLVMVerifierFailureAction};
+use llvm_sys::bit_reader::LLVMParseBitcodeInContext2;
+use llvm_sys::bit_writer::LLVMWriteBitcodeToMemoryBuffer;
+use llvm_sys::linker::LLVMLinkModules2;
+#[cfg(not(feature = "llvm-17"))]
 use llvm_sys::transforms::pass_manager_builder as builder;
 use llvm_sys::execution_engine as engine;
 pub use llvm_sys::LLVMIntPredicate;
 
 use rts::RtsState;
 
+/// An opaque LLVM attribute.
+enum LLVMOpaqueAttribute {}
+
+/// A reference to an LLVM attribute.
+type LLVMAttributeRef = *mut LLVMOpaqueAttribute;
+
+/// The attribute index denoting the function itself (rather than its result or a parameter).
+pub const FUNCTION_INDEX: c_uint = !0;
+
+/// The type of `RtsState::poll_c`.
+pub type PollFunction<'a> = unsafe extern "C" fn(&mut RtsState<'a>, u64, u64, u64, *const u8,
+                                                 u64) -> u64;
+
+/// The type of the generated `bfi_main`, which takes the run-time state, its read, write, check
+/// and poll functions, and the meter.
+pub type MainFunction<'a> = extern "C" fn(&mut RtsState<'a>,
+                                          extern "C" fn(&mut RtsState<'a>) -> u8,
+                                          extern "C" fn(&mut RtsState<'a>, u8),
+                                          extern "C" fn(&mut RtsState<'a>, i64, u8) -> u64,
+                                          PollFunction<'a>,
+                                          *mut u64) -> u64;
+
+// The enum-attribute API is available since LLVM 3.9, but `llvm-sys` 38 does not bind it.
+extern "C" {
+    fn LLVMGetEnumAttributeKindForName(name: *const c_char, len: usize) -> c_uint;
+    fn LLVMCreateEnumAttribute(context: LLVMContextRef, kind: c_uint, value: u64)
+        -> LLVMAttributeRef;
+    fn LLVMCreateStringAttribute(context: LLVMContextRef, key: *const c_char, key_len: c_uint,
+                                 value: *const c_char, value_len: c_uint) -> LLVMAttributeRef;
+    fn LLVMAddAttributeAtIndex(function: LLVMValueRef, index: c_uint, attribute: LLVMAttributeRef);
+}
+
+// Opaque pointers (the default since LLVM 15) mean the pointee type can no longer be recovered
+// from a pointer, so these take it explicitly. They are available since LLVM 8.
+#[cfg(feature = "llvm-15")]
+extern "C" {
+    fn LLVMBuildCall2(builder: LLVMBuilderRef, ty: LLVMTypeRef, function: LLVMValueRef,
+                      args: *mut LLVMValueRef, num_args: c_uint, name: *const c_char)
+        -> LLVMValueRef;
+    fn LLVMBuildGEP2(builder: LLVMBuilderRef, ty: LLVMTypeRef, pointer: LLVMValueRef,
+                     indices: *mut LLVMValueRef, num_indices: c_uint, name: *const c_char)
+        -> LLVMValueRef;
+    fn LLVMBuildLoad2(builder: LLVMBuilderRef, ty: LLVMTypeRef, pointer: LLVMValueRef,
+                      name: *const c_char) -> LLVMValueRef;
+    fn LLVMLookupIntrinsicID(name: *const c_char, len: usize) -> c_uint;
+    fn LLVMGetIntrinsicDeclaration(module: LLVMModuleRef, id: c_uint,
+                                   param_types: *mut LLVMTypeRef, param_count: usize)
+        -> LLVMValueRef;
+}
+
+/// Options for the new pass manager.
+#[cfg(feature = "llvm-17")]
+enum LLVMOpaquePassBuilderOptions {}
+
+/// An error returned by the LLVM C API.
+#[cfg(feature = "llvm-17")]
+enum LLVMOpaqueError {}
+
+// The legacy pass manager builder is gone in LLVM 17; this is its replacement since LLVM 13.
+#[cfg(feature = "llvm-17")]
+extern "C" {
+    fn LLVMCreatePassBuilderOptions() -> *mut LLVMOpaquePassBuilderOptions;
+    fn LLVMDisposePassBuilderOptions(options: *mut LLVMOpaquePassBuilderOptions);
+    fn LLVMRunPasses(module: LLVMModuleRef, passes: *const c_char,
+                     target_machine: llvm_sys::target_machine::LLVMTargetMachineRef,
+                     options: *mut LLVMOpaquePassBuilderOptions) -> *mut LLVMOpaqueError;
+    fn LLVMGetErrorMessage(error: *mut LLVMOpaqueError) -> *mut c_char;
+    fn LLVMDisposeErrorMessage(message: *mut c_char);
+}
+
 pub struct Context {
     context_ref: LLVMContextRef,
-    strings:     RefCell<Vec<CString>>,
+    /// Interned value names, keyed by their Rust form.
+    names:       RefCell<HashMap<String, CString>>,
 }
 
 impl Context {
     pub fn new() -> Self {
         Context {
             context_ref: unsafe { LLVMContextCreate() },
-            strings:     RefCell::new(Vec::new()),
+            names:       RefCell::new(HashMap::new()),
         }
     }
 
+    /// Interns a name, returning a C string that remains valid as long as the context.
+    ///
+    /// LLVM copies the names it is given, so the result strictly need only outlive the call it
+    /// is passed to. Interning means repeated names (which is nearly all of them) are stored
+    /// once, rather than the store growing with every value emitted. Each `CString` owns a heap
+    /// buffer that does not move when the map rehashes, so returned pointers stay valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` contains a NUL byte.
     pub fn new_name(&self, name: &str) -> *const c_char {
+        let mut names = self.names.borrow_mut();
+
+        if let Some(string) = names.get(name) {
+            return string.as_ptr();
+        }
+
         let string = CString::new(name).unwrap();
         let ptr    = string.as_ptr();
-        self.strings.borrow_mut().push(string);
+        names.insert(name.to_owned(), string);
         ptr
     }
 
-    fn wrap_value(&self, value_ref: LLVMValueRef) -> Value {
+    fn wrap_value(&self, value_ref: LLVMValueRef) -> Value<'_> {
         Value {
-            value_ref: value_ref,
-            context:   self,
+            value_ref,
+            context: self,
         }
     }
 
-    fn wrap_type(&self, type_ref: LLVMTypeRef) -> Type {
+    fn wrap_type(&self, type_ref: LLVMTypeRef) -> Type<'_> {
         Type {
-            type_ref: type_ref,
-            context:  self,
+            type_ref,
+            context: self,
         }
     }
 }
@@ -70,10 +162,54 @@ impl<'a> Module<'a> {
             module_ref: unsafe {
                 LLVMModuleCreateWithNameInContext(name, context.context_ref)
             },
-            context: context,
+            context,
+        }
+    }
+
+    /// Reads a module from bitcode, such as that produced by
+    /// [`write_bitcode`](#method.write_bitcode), in the given context.
+    pub fn parse_bitcode(context: &'a Context, bitcode: &[u8]) -> Result<Self, String> {
+        let mut module_ref = ptr::null_mut();
+
+        unsafe {
+            let name   = context.new_name("bitcode");
+            let buffer = LLVMCreateMemoryBufferWithMemoryRange(bitcode.as_ptr() as *const c_char,
+                                                               bitcode.len(), name, 0);
+            let failed = LLVMParseBitcodeInContext2(context.context_ref, buffer, &mut module_ref);
+            LLVMDisposeMemoryBuffer(buffer);
+
+            if failed != 0 {
+                return Err("Could not parse LLVM bitcode.".to_owned());
+            }
+        }
+
+        Ok(Module { module_ref, context })
+    }
+
+    /// Serializes the module to bitcode, which can be moved to another thread and context.
+    pub fn write_bitcode(&self) -> Vec<u8> {
+        unsafe {
+            let buffer = LLVMWriteBitcodeToMemoryBuffer(self.module_ref);
+            let start  = LLVMGetBufferStart(buffer) as *const u8;
+            let result = slice::from_raw_parts(start, LLVMGetBufferSize(buffer)).to_vec();
+            LLVMDisposeMemoryBuffer(buffer);
+            result
         }
     }
 
+    /// Links `other` into this module, consuming it.
+    pub fn link(&self, other: Module<'a>) -> Result<(), String> {
+        if unsafe { LLVMLinkModules2(self.module_ref, other.module_ref) } == 0 {
+            Ok(())
+        } else {
+            Err("Could not link LLVM modules.".to_owned())
+        }
+    }
+
+    pub fn context(&self) -> &'a Context {
+        self.context
+    }
+
     pub fn add_function(&self, name: &str, ty: Type<'a>) -> Value<'a> {
         let name = self.context.new_name(name);
         self.context.wrap_value(unsafe {
@@ -81,7 +217,30 @@ impl<'a> Module<'a> {
         })
     }
 
+    /// Declares the `llvm.memset` intrinsic for an `i8*` destination and an `i64` length, whose
+    /// type `ty` must be `void (i8*, i8, i64, i1)`.
+    #[cfg(not(feature = "llvm-15"))]
+    pub fn declare_memset(&self, ty: Type<'a>) -> Value<'a> {
+        self.add_function("llvm.memset.p0i8.i64", ty)
+    }
+
+    /// Declares the `llvm.memset` intrinsic for an `i8*` destination and an `i64` length, whose
+    /// type `ty` must be `void (i8*, i8, i64, i1)`.
+    #[cfg(feature = "llvm-15")]
+    pub fn declare_memset(&self, _ty: Type<'a>) -> Value<'a> {
+        // The mangled name depends on whether pointers are opaque, so let LLVM choose it.
+        let name = "llvm.memset";
+        let mut overloads = [Type::get_pointer(Type::get_i8(self.context)).type_ref,
+                             Type::get_i64(self.context).type_ref];
+        self.context.wrap_value(unsafe {
+            let id = LLVMLookupIntrinsicID(name.as_ptr() as *const c_char, name.len());
+            LLVMGetIntrinsicDeclaration(self.module_ref, id,
+                                        overloads.as_mut_ptr(), overloads.len())
+        })
+    }
+
     // From llvm-alt:
+    #[cfg(not(feature = "llvm-17"))]
     pub fn optimize(&self, opt_level: usize, size_level: usize) {
         unsafe {
             let builder = builder::LLVMPassManagerBuilderCreate();
@@ -95,12 +254,49 @@ impl<'a> Module<'a> {
         }
     }
 
+    /// Runs the new pass manager’s default pipeline for the given levels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if LLVM rejects the pipeline.
+    #[cfg(feature = "llvm-17")]
+    pub fn optimize(&self, opt_level: usize, size_level: usize) {
+        let pipeline = match size_level {
+            0 => format!("default<O{}>", opt_level),
+            1 => "default<Os>".to_owned(),
+            _ => "default<Oz>".to_owned(),
+        };
+        let pipeline = CString::new(pipeline).unwrap();
+
+        unsafe {
+            let options = LLVMCreatePassBuilderOptions();
+            let error = LLVMRunPasses(self.module_ref, pipeline.as_ptr(), ptr::null_mut(), options);
+            LLVMDisposePassBuilderOptions(options);
+
+            if !error.is_null() {
+                let message = LLVMGetErrorMessage(error);
+                let result = CStr::from_ptr(message).to_string_lossy().into_owned();
+                LLVMDisposeErrorMessage(message);
+                panic!("LLVM optimization failed: {}", result);
+            }
+        }
+    }
+
     pub fn dump(&self) {
         unsafe {
             LLVMDumpModule(self.module_ref);
         }
     }
 
+    pub fn print_to_string(&self) -> String {
+        unsafe {
+            let message = LLVMPrintModuleToString(self.module_ref);
+            let result = CStr::from_ptr(message).to_string_lossy().into_owned();
+            LLVMDisposeMessage(message);
+            result
+        }
+    }
+
     pub fn verify(&self) -> Result<(), String> {
         let mut out_message: *mut c_char = ptr::null_mut();
 
@@ -117,10 +313,10 @@ impl<'a> Module<'a> {
         }
     }
 
+    /// Compiles the module and calls the named function. This consumes the module, which must
+    /// not be used afterwards.
     pub unsafe fn with_function<'b, F>(&self, name: &str, with: F) -> Result<u64, String>
-        where F: FnOnce(extern fn (&mut RtsState<'b>,
-                                   extern fn(&mut RtsState<'b>) -> u8,
-                                   extern fn(&mut RtsState<'b>, u8) -> ()) -> u64) -> u64
+        where F: FnOnce(MainFunction<'b>) -> u64
     {
         let mut out_message: *mut c_char = ptr::null_mut();
         let mut exec: engine::LLVMExecutionEngineRef = ptr::null_mut();
@@ -156,9 +352,13 @@ impl<'a> Module<'a> {
 
         let cname    = CString::new(name).unwrap();
         let fun_addr = engine::LLVMGetFunctionAddress(exec, cname.as_ptr());
-        let fun = mem::transmute(fun_addr);
+        let fun = mem::transmute::<u64, MainFunction<'b>>(fun_addr);
+        let result = with(fun);
 
-        Ok(with(fun))
+        // The engine owns the module now, and frees it along with the generated code.
+        engine::LLVMDisposeExecutionEngine(exec);
+
+        Ok(result)
     }
 }
 
@@ -175,12 +375,6 @@ impl<'a> Type<'a> {
         })
     }
 
-    pub fn get_i32(context: &'a Context) -> Self {
-        context.wrap_type(unsafe {
-            LLVMInt32TypeInContext(context.context_ref)
-        })
-    }
-
     pub fn get_i8(context: &'a Context) -> Self {
         context.wrap_type(unsafe {
             LLVMInt8TypeInContext(context.context_ref)
@@ -206,7 +400,7 @@ impl<'a> Type<'a> {
     }
 
     pub fn get_function(args: &[Type<'a>], result: Type<'a>) -> Self {
-        let mut args = args.into_iter().map(|arg| arg.type_ref).collect::<Vec<_>>();
+        let mut args = args.iter().map(|arg| arg.type_ref).collect::<Vec<_>>();
         result.context.wrap_type(unsafe {
             LLVMFunctionType(result.type_ref,
                              args.as_mut_ptr(),
@@ -223,6 +417,61 @@ pub struct Value<'a> {
 }
 
 impl<'a> Value<'a> {
+    /// Adds the named enum attribute (such as `nounwind`) to a function at the given index.
+    ///
+    /// Index 0 is the result, parameters start at 1, and
+    /// [`FUNCTION_INDEX`](constant.FUNCTION_INDEX.html) is the function itself.
+    pub fn add_attribute(&self, index: c_uint, name: &str) {
+        unsafe {
+            let kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len());
+            assert!(kind != 0, "unknown LLVM attribute: {}", name);
+            let attribute = LLVMCreateEnumAttribute(self.context.context_ref, kind, 0);
+            LLVMAddAttributeAtIndex(self.value_ref, index, attribute);
+        }
+    }
+
+    /// Adds a string attribute (such as `"frame-pointer"="all"`) to a function at the given
+    /// index.
+    pub fn add_string_attribute(&self, index: c_uint, key: &str, value: &str) {
+        unsafe {
+            let attribute = LLVMCreateStringAttribute(self.context.context_ref,
+                                                      key.as_ptr() as *const c_char,
+                                                      key.len() as c_uint,
+                                                      value.as_ptr() as *const c_char,
+                                                      value.len() as c_uint);
+            LLVMAddAttributeAtIndex(self.value_ref, index, attribute);
+        }
+    }
+
+    /// Attaches metadata of the given kind (such as `tbaa`) to an instruction.
+    pub fn set_metadata(&self, kind: &str, node: Value<'a>) {
+        unsafe {
+            let kind = LLVMGetMDKindIDInContext(self.context.context_ref,
+                                                kind.as_ptr() as *const c_char,
+                                                kind.len() as c_uint);
+            LLVMSetMetadata(self.value_ref, kind, node.value_ref);
+        }
+    }
+
+    /// Creates a metadata string.
+    pub fn md_string(context: &'a Context, string: &str) -> Self {
+        context.wrap_value(unsafe {
+            LLVMMDStringInContext(context.context_ref,
+                                  string.as_ptr() as *const c_char,
+                                  string.len() as c_uint)
+        })
+    }
+
+    /// Creates a metadata node with the given operands.
+    pub fn md_node(context: &'a Context, operands: &[Value<'a>]) -> Self {
+        let mut operands = operands.iter().map(|op| op.value_ref).collect::<Vec<_>>();
+        context.wrap_value(unsafe {
+            LLVMMDNodeInContext(context.context_ref,
+                                operands.as_mut_ptr(),
+                                operands.len() as c_uint)
+        })
+    }
+
     pub fn get_fun_param(&self, index: usize) -> Self {
         self.context.wrap_value(unsafe {
             LLVMGetParam(self.value_ref, index as _)
@@ -237,7 +486,7 @@ impl<'a> Value<'a> {
                                           name)
         };
         BasicBlock {
-            bb_ref: bb_ref,
+            bb_ref,
             _context: self.context,
         }
     }
@@ -250,14 +499,6 @@ impl<'a> Value<'a> {
         })
     }
 
-    pub fn get_u32(context: &'a Context, value: u32) -> Self {
-        context.wrap_value(unsafe {
-            LLVMConstInt(Type::get_i32(context).type_ref,
-                         value as _,
-                         false as _)
-        })
-    }
-
     pub fn get_u8(context: &'a Context, value: u8) -> Self {
         context.wrap_value(unsafe {
             LLVMConstInt(Type::get_i8(context).type_ref,
@@ -292,7 +533,7 @@ impl<'a> Builder<'a> {
     pub fn new(context: &'a Context) -> Self {
         Builder {
             builder_ref: unsafe { LLVMCreateBuilderInContext(context.context_ref) },
-            context:     context,
+            context,
         }
     }
 
@@ -332,9 +573,13 @@ impl<'a> Builder<'a> {
         }
     }
 
-    pub fn call(&self, fun: Value<'a>, args: &[Value<'a>], name: &str) -> Value<'a> {
+    /// Calls `fun`, whose type is `ty`.
+    #[cfg(not(feature = "llvm-15"))]
+    pub fn call(&self, _ty: Type<'a>, fun: Value<'a>, args: &[Value<'a>], name: &str)
+        -> Value<'a>
+    {
         let name = self.context.new_name(name);
-        let mut args = args.into_iter().map(|arg| arg.value_ref).collect::<Vec<_>>();
+        let mut args = args.iter().map(|arg| arg.value_ref).collect::<Vec<_>>();
         self.context.wrap_value(unsafe {
             LLVMBuildCall(self.builder_ref,
                           fun.value_ref,
@@ -344,6 +589,23 @@ impl<'a> Builder<'a> {
         })
     }
 
+    /// Calls `fun`, whose type is `ty`.
+    #[cfg(feature = "llvm-15")]
+    pub fn call(&self, ty: Type<'a>, fun: Value<'a>, args: &[Value<'a>], name: &str)
+        -> Value<'a>
+    {
+        let name = self.context.new_name(name);
+        let mut args = args.iter().map(|arg| arg.value_ref).collect::<Vec<_>>();
+        self.context.wrap_value(unsafe {
+            LLVMBuildCall2(self.builder_ref,
+                           ty.type_ref,
+                           fun.value_ref,
+                           args.as_mut_ptr(),
+                           args.len() as u32,
+                           name)
+        })
+    }
+
     pub fn cmp(&self, pred: LLVMIntPredicate, lhs: Value<'a>, rhs: Value<'a>,
                name: &str) -> Value<'a> {
         let name = self.context.new_name(name);
@@ -359,9 +621,13 @@ impl<'a> Builder<'a> {
         }
     }
 
-    pub fn gep(&self, ptr: Value<'a>, indices: &[Value<'a>], name: &str) -> Value<'a> {
+    /// Indexes `ptr`, which points to elements of type `ty`.
+    #[cfg(not(feature = "llvm-15"))]
+    pub fn gep(&self, _ty: Type<'a>, ptr: Value<'a>, indices: &[Value<'a>], name: &str)
+        -> Value<'a>
+    {
         let name = self.context.new_name(name);
-        let mut indices = indices.into_iter().map(|i| i.value_ref).collect::<Vec<_>>();
+        let mut indices = indices.iter().map(|i| i.value_ref).collect::<Vec<_>>();
         self.context.wrap_value(unsafe {
             LLVMBuildGEP(self.builder_ref,
                          ptr.value_ref,
@@ -371,23 +637,51 @@ impl<'a> Builder<'a> {
         })
     }
 
-    pub fn load(&self, ptr: Value<'a>, name: &str) -> Value<'a> {
+    /// Indexes `ptr`, which points to elements of type `ty`.
+    #[cfg(feature = "llvm-15")]
+    pub fn gep(&self, ty: Type<'a>, ptr: Value<'a>, indices: &[Value<'a>], name: &str)
+        -> Value<'a>
+    {
+        let name = self.context.new_name(name);
+        let mut indices = indices.iter().map(|i| i.value_ref).collect::<Vec<_>>();
+        self.context.wrap_value(unsafe {
+            LLVMBuildGEP2(self.builder_ref,
+                          ty.type_ref,
+                          ptr.value_ref,
+                          indices.as_mut_ptr(),
+                          indices.len() as u32,
+                          name)
+        })
+    }
+
+    /// Loads a value of type `ty` from `ptr`.
+    #[cfg(not(feature = "llvm-15"))]
+    pub fn load(&self, _ty: Type<'a>, ptr: Value<'a>, name: &str) -> Value<'a> {
         let name = self.context.new_name(name);
         self.context.wrap_value(unsafe {
             LLVMBuildLoad(self.builder_ref, ptr.value_ref, name)
         })
     }
 
+    /// Loads a value of type `ty` from `ptr`.
+    #[cfg(feature = "llvm-15")]
+    pub fn load(&self, ty: Type<'a>, ptr: Value<'a>, name: &str) -> Value<'a> {
+        let name = self.context.new_name(name);
+        self.context.wrap_value(unsafe {
+            LLVMBuildLoad2(self.builder_ref, ty.type_ref, ptr.value_ref, name)
+        })
+    }
+
     pub fn ret(&self, value: Value<'a>) {
         unsafe {
             LLVMBuildRet(self.builder_ref, value.value_ref);
         }
     }
 
-    pub fn store(&self, src: Value<'a>, dst: Value<'a>) {
-        unsafe {
-            LLVMBuildStore(self.builder_ref, src.value_ref, dst.value_ref);
-        }
+    pub fn store(&self, src: Value<'a>, dst: Value<'a>) -> Value<'a> {
+        self.context.wrap_value(unsafe {
+            LLVMBuildStore(self.builder_ref, src.value_ref, dst.value_ref)
+        })
     }
 
     pub fn sub(&self, v1: Value<'a>, v2: Value<'a>, name: &str) -> Value<'a> {
@@ -411,3 +705,21 @@ impl<'a> Builder<'a> {
 //        })
 //    }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_interned() {
+        let context = Context::new();
+        let first = context.new_name("data");
+        let other = context.new_name("other");
+        let again = context.new_name("data");
+
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+        assert_eq!(context.names.borrow().len(), 2);
+        assert_eq!(unsafe { CStr::from_ptr(again) }.to_str(), Ok("data"));
+    }
+}
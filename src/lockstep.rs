@@ -0,0 +1,422 @@
+//! Runs a backend side by side with the checked interpreter, to pin down codegen bugs.
+//!
+//! [`lockstep`](fn.lockstep.html) first runs the whole program both in a
+//! [`Tier`](trait.Tier.html), such as the [JIT](../jit/index.html), and in the
+//! [peephole interpreter](../peephole/index.html), on the same input. If they end differently,
+//! it runs them again a [`Segment`](enum.Segment.html) at a time—each iteration of each loop,
+//! and each stretch of straight-line code—starting the tier on the interpreter’s state at every
+//! segment and comparing the two after it. The first segment where they part ways is reported
+//! as a [`Divergence`](struct.Divergence.html), innermost loop first, with the segment’s IR and
+//! whatever the tier can show of the code it ran, such as the machine code.
+//!
+//! Segments are compiled on their own, so a bug that only shows when the code around a segment
+//! is compiled with it is reported for the program as a whole.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+
+use common::{BfResult, Error};
+use peephole::{self, continuation::bytecode_len, Statement, Stopped};
+use state::State;
+use text::Text;
+use threaded;
+
+/// A backend to check against the interpreter.
+pub trait Tier {
+    /// The compiled form of a program.
+    type Code;
+
+    /// Compiles a program, or a segment of one.
+    fn compile(&self, program: &peephole::Program) -> Self::Code;
+
+    /// Runs compiled code on the state, leaving it as the code does.
+    fn run(&self, code: &Self::Code, state: &mut State, input: &mut dyn Read,
+           output: &mut dyn Write) -> BfResult<()>;
+
+    /// What there is to show of the code compiled for a program, such as its machine code.
+    fn context(&self, _program: &peephole::Program) -> Option<String> {
+        None
+    }
+}
+
+/// The [closure-threaded](../threaded/index.html) backend.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Threaded;
+
+impl Tier for Threaded {
+    type Code = threaded::Program;
+
+    fn compile(&self, program: &peephole::Program) -> threaded::Program {
+        threaded::compile(program)
+    }
+
+    fn run(&self, code: &threaded::Program, state: &mut State, input: &mut dyn Read,
+           output: &mut dyn Write) -> BfResult<()> {
+        code.run_state(state, input, output)
+    }
+}
+
+/// The [JIT](../jit/index.html), compiled with the given options, showing the
+/// [assembly](../asm/index.html) that has the same layout as its machine code.
+#[cfg(feature = "jit")]
+#[derive(Clone, Debug, Default)]
+pub struct Jit(pub ::options::CompileOptions);
+
+#[cfg(feature = "jit")]
+impl Tier for Jit {
+    type Code = ::jit::Program;
+
+    fn compile(&self, program: &peephole::Program) -> ::jit::Program {
+        ::jit::compile_with_options(program, &self.0)
+    }
+
+    fn run(&self, code: &::jit::Program, state: &mut State, input: &mut dyn Read,
+           output: &mut dyn Write) -> BfResult<()> {
+        code.run_state(state, input, output)
+    }
+
+    fn context(&self, program: &peephole::Program) -> Option<String> {
+        Some(::asm::compile(program, Some(1), ::asm::Syntax::Intel, &self.0))
+    }
+}
+
+/// Where a run can be checked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Segment {
+    /// The whole program, when no smaller segment ran differently.
+    Program,
+    /// A stretch of straight-line code, starting at the given bytecode address.
+    Code {
+        /// The address of its first instruction.
+        pc: usize,
+    },
+    /// One iteration of a loop.
+    Iteration {
+        /// The address of the loop’s `JumpZero`.
+        pc: usize,
+        /// Which iteration, counting from 1 each time the loop is entered.
+        iteration: u64,
+    },
+}
+
+/// How a segment, or a whole run, ended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+    /// Whether it failed.
+    pub result: BfResult<()>,
+    /// The machine state.
+    pub state: State,
+    /// What it wrote.
+    pub output: Vec<u8>,
+    /// How many bytes of input it read.
+    pub read: usize,
+}
+
+impl Checkpoint {
+    /// Whether a run ended the same way, comparing
+    /// [state hashes](../state/struct.State.html#method.hash) rather than whole tapes.
+    fn agrees(&self, result: &BfResult<()>, state: &State, output: &[u8], read: usize) -> bool {
+        self.result == *result && self.read == read && self.output == output
+            && self.state.hash() == state.hash()
+    }
+}
+
+/// The first place a tier ran differently from the interpreter.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Where.
+    pub segment: Segment,
+    /// The segment’s peephole IR, in [canonical text](../text/index.html).
+    pub ir: String,
+    /// How the interpreter ended the segment.
+    pub expected: Checkpoint,
+    /// How the tier did.
+    pub actual: Checkpoint,
+    /// What the tier shows of its code for the segment.
+    pub context: Option<String>,
+}
+
+impl Divergence {
+    /// The first difference between the two checkpoints, such as `cell 5 is 3, expected 4`.
+    pub fn difference(&self) -> String {
+        let (expected, actual) = (&self.expected, &self.actual);
+        if expected.result != actual.result {
+            return format!("{} where the interpreter {}", outcome(&actual.result),
+                           outcome(&expected.result).replace("failed", "fails")
+                               .replace("finished", "finishes"));
+        }
+        if expected.state.pointer() != actual.state.pointer() {
+            return format!("the pointer is at {}, expected {}", actual.state.pointer(),
+                           expected.state.pointer());
+        }
+        let cells = expected.state.memory().iter().zip(actual.state.memory());
+        if let Some((address, (e, a))) = cells.enumerate().find(|&(_, (e, a))| e != a) {
+            return format!("cell {} is {}, expected {}", address, a.0, e.0);
+        }
+        if expected.output != actual.output {
+            return format!("it wrote {:?}, expected {:?}", String::from_utf8_lossy(&actual.output),
+                           String::from_utf8_lossy(&expected.output));
+        }
+        format!("it read {} bytes of input, expected {}", actual.read, expected.read)
+    }
+}
+
+fn outcome(result: &BfResult<()>) -> String {
+    match *result {
+        Ok(()) => "it finished".to_owned(),
+        Err(ref error) => format!("it failed with {}", error),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.segment {
+            Segment::Program => write!(f, "the whole program")?,
+            Segment::Code { pc } => write!(f, "the code at address {}", pc)?,
+            Segment::Iteration { pc, iteration } => {
+                write!(f, "iteration {} of the loop at address {}", iteration, pc)?
+            }
+        }
+        writeln!(f, " diverged: {}", self.difference())?;
+        write!(f, "\nIR:\n{}", self.ir)?;
+        if let Some(ref context) = self.context {
+            write!(f, "\ncode:\n{}", context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the program in the tier and the interpreter on the same state and input. Returns how
+/// the interpreter’s run ended, if the tier’s ended the same.
+///
+/// # Errors
+///
+/// The first place the tier ran differently.
+pub fn lockstep<T: Tier>(program: &peephole::Program, tier: &T, state: State, input: &[u8])
+                         -> Result<Checkpoint, Box<Divergence>>
+{
+    let mut walker = Walker { tier, input, read: 0, output: Vec::new(), codes: HashMap::new() };
+    let mut reference = state.clone();
+    let result = walker.interpret(program, &mut reference);
+    let expected = Checkpoint {
+        result,
+        state: reference,
+        output: walker.output.split_off(0),
+        read: walker.read,
+    };
+
+    let mut actual = state.clone();
+    let mut rest = input;
+    let mut output = Vec::new();
+    let result = tier.run(&tier.compile(program), &mut actual, &mut rest, &mut output);
+    let actual = Checkpoint { result, state: actual, output, read: input.len() - rest.len() };
+
+    if expected.agrees(&actual.result, &actual.state, &actual.output, actual.read) {
+        return Ok(expected);
+    }
+
+    walker.read = 0;
+    let mut state = state;
+    match walker.block(program, 0, &mut state) {
+        Err(Stop::Diverged(divergence)) => Err(divergence),
+        _ => Err(Box::new(Divergence {
+            segment: Segment::Program,
+            ir: Text(program).to_string(),
+            expected,
+            actual,
+            context: tier.context(program),
+        })),
+    }
+}
+
+/// Why a checked run stopped early.
+enum Stop {
+    /// The interpreter and the tier failed the same way.
+    Failed(Error),
+    Diverged(Box<Divergence>),
+}
+
+struct Walker<'a, T: Tier + 'a> {
+    tier: &'a T,
+    input: &'a [u8],
+    /// How much input the interpreter has read.
+    read: usize,
+    /// What the interpreter has written.
+    output: Vec<u8>,
+    /// Compiled segments, by their bytecode address.
+    codes: HashMap<usize, T::Code>,
+}
+
+impl<'a, T: Tier> Walker<'a, T> {
+    /// Checks each segment of a block that starts at bytecode address `pc`.
+    fn block(&mut self, block: &[Statement], mut pc: usize, state: &mut State)
+             -> Result<(), Stop>
+    {
+        let mut start = 0;
+        while start < block.len() {
+            if let Statement::Loop(ref body) = block[start] {
+                // A body with no loops is one segment, so it is only checked as an iteration.
+                let nested = body.iter().any(|statement| matches!(statement, Statement::Loop(_)));
+                let mut iteration = 0;
+                while state.load() != 0 {
+                    iteration += 1;
+                    self.check(Segment::Iteration { pc, iteration }, body, state, |walker, state| {
+                        if nested {
+                            walker.block(body, pc + 1, state)
+                        } else {
+                            walker.interpret(body, state).map_err(Stop::Failed)
+                        }
+                    })?;
+                }
+                pc += bytecode_len(body) + 2;
+                start += 1;
+            } else {
+                let end = block[start ..].iter()
+                    .position(|statement| matches!(statement, Statement::Loop(_)))
+                    .map_or(block.len(), |length| start + length);
+                let code = &block[start .. end];
+                self.check(Segment::Code { pc }, code, state, |walker, state| {
+                    walker.interpret(code, state).map_err(Stop::Failed)
+                })?;
+                pc += end - start;
+                start = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a segment in the tier, and then in the interpreter with `reference`, and compares
+    /// them.
+    fn check<F>(&mut self, segment: Segment, code: &[Statement], state: &mut State,
+                reference: F) -> Result<(), Stop>
+        where F: FnOnce(&mut Self, &mut State) -> Result<(), Stop>
+    {
+        let pc = match segment {
+            Segment::Code { pc } | Segment::Iteration { pc, .. } => pc,
+            Segment::Program => 0,
+        };
+        let tier = self.tier;
+        let compiled = self.codes.entry(pc).or_insert_with(|| tier.compile(code));
+
+        let mut actual = state.clone();
+        let mut rest = &self.input[self.read ..];
+        let available = rest.len();
+        let mut output = Vec::new();
+        let result = tier.run(compiled, &mut actual, &mut rest, &mut output);
+        let actual = Checkpoint { result, state: actual, output, read: available - rest.len() };
+
+        let (read, written) = (self.read, self.output.len());
+        let result = match reference(self, state) {
+            Ok(()) => Ok(()),
+            Err(Stop::Failed(error)) => Err(error),
+            Err(diverged) => return Err(diverged),
+        };
+
+        if !actual.agrees(&result, state, &self.output[written ..], self.read - read) {
+            let expected = Checkpoint {
+                result,
+                state: state.clone(),
+                output: self.output[written ..].to_vec(),
+                read: self.read - read,
+            };
+            return Err(Stop::Diverged(Box::new(Divergence {
+                segment,
+                ir: Text(code).to_string(),
+                expected,
+                actual,
+                context: self.tier.context(code),
+            })));
+        }
+        result.map_err(Stop::Failed)
+    }
+
+    /// Runs code in the interpreter, reading from the rest of the input.
+    fn interpret(&mut self, code: &[Statement], state: &mut State) -> BfResult<()> {
+        let mut rest = &self.input[self.read ..];
+        let available = rest.len();
+        let result = peephole::interpret_capped(code, state, &mut rest, &mut self.output,
+                                                u64::MAX);
+        self.read += available - rest.len();
+        result.map_err(|stopped| match stopped {
+            Stopped::Error(error) => error,
+            Stopped::RunawayLoop(_) => unreachable!("the cap is never reached"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction;
+    use test_helpers::*;
+    use traits::PeepholeCompilable;
+
+    /// Closure-threaded code that adds one too many at the end of any code that adds 3.
+    struct OffByOne;
+
+    fn adds_three(program: &[Statement]) -> bool {
+        program.iter().any(|statement| match *statement {
+            Statement::Instr(instruction) => instruction == Instruction::Add(3),
+            Statement::Loop(ref body) => adds_three(body),
+        })
+    }
+
+    impl Tier for OffByOne {
+        type Code = (threaded::Program, bool);
+
+        fn compile(&self, program: &peephole::Program) -> Self::Code {
+            (threaded::compile(program), adds_three(program))
+        }
+
+        fn run(&self, code: &Self::Code, state: &mut State, input: &mut dyn Read,
+               output: &mut dyn Write) -> BfResult<()> {
+            code.0.run_state(state, input, output)?;
+            if code.1 {
+                state.up(1);
+            }
+            Ok(())
+        }
+
+        fn context(&self, _program: &peephole::Program) -> Option<String> {
+            Some("(threaded)".to_owned())
+        }
+    }
+
+    fn lockstep_with<T: Tier>(tier: &T, source: &[u8], input: &[u8])
+                              -> Result<Checkpoint, Box<Divergence>> {
+        let program = ::ast::parse_program(source).unwrap().peephole_compile();
+        lockstep(&program, tier, State::new(), input)
+    }
+
+    #[test]
+    fn agreeing_runs_give_the_interpreters_result() {
+        let checkpoint = lockstep_with(&Threaded, FACTOR_SRC, b"12\n").unwrap();
+        assert_eq!((checkpoint.result, &checkpoint.output[..], checkpoint.read),
+                   (Ok(()), &b"12: 2 2 3\n"[..], 3));
+        assert_eq!(lockstep_with(&Threaded, b">+<<", b"").unwrap().result,
+                   Err(Error::PointerUnderflow));
+    }
+
+    #[test]
+    fn divergences_are_found_innermost_first() {
+        // 0: Add(2), 1: JumpZero, 2: Right(1), 3: Add(3), 4: JumpZero, ...
+        let divergence = lockstep_with(&OffByOne, b"++[>+++[.-]<-]", b"").unwrap_err();
+        assert_eq!(divergence.segment, Segment::Code { pc: 2 });
+        assert_eq!(divergence.difference(), "cell 1 is 4, expected 3");
+        assert_eq!(divergence.ir, "Right(1)\nAdd(3)\n");
+
+        let text = divergence.to_string();
+        assert!(text.starts_with("the code at address 2 diverged: cell 1 is 4, expected 3\n"));
+        assert!(text.ends_with("\ncode:\n(threaded)"));
+    }
+
+    #[test]
+    fn iterations_and_failures_are_compared_too() {
+        let divergence = lockstep_with(&OffByOne, b",[+++.,]", b"ab").unwrap_err();
+        assert_eq!(divergence.segment, Segment::Iteration { pc: 1, iteration: 1 });
+        assert_eq!((&divergence.expected.output[..], &divergence.actual.output[..]),
+                   (&b"d"[..], &b"d"[..]));
+        assert_eq!(divergence.difference(), "cell 0 is 99, expected 98");
+    }
+}
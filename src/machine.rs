@@ -0,0 +1,252 @@
+//! Many machines running one program.
+//!
+//! A [`Machine`](struct.Machine.html) pairs a shared, compiled [bytecode](../bytecode/index.html)
+//! program with its own state and I/O, and an [`Executor`](struct.Executor.html) interleaves
+//! any number of machines round-robin on one thread, a fixed number of instructions each turn.
+//! This suits environments where many Brainfuck bots or contestants run side by side.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use bytecode::{self, StepResult};
+use common::BfResult;
+use state::State;
+
+/// A program with its own state and I/O.
+pub struct Machine<'a> {
+    program: Arc<bytecode::Program>,
+    state:   State,
+    pc:      usize,
+    input:   Box<dyn Read + 'a>,
+    output:  Box<dyn Write + 'a>,
+    /// How the machine stopped, once it has.
+    result:  Option<BfResult<()>>,
+}
+
+impl<'a> Machine<'a> {
+    /// Creates a machine to run the given program against the given state and I/O.
+    pub fn new<R, W>(program: Arc<bytecode::Program>, state: State, input: R, output: W) -> Self
+        where R: Read + 'a, W: Write + 'a
+    {
+        Machine {
+            program,
+            state,
+            pc: 0,
+            input: Box::new(input),
+            output: Box::new(output),
+            result: None,
+        }
+    }
+
+    /// Runs one instruction, doing its I/O. Returns whether the machine is still running.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the instruction fails, and the same `Err` again on later calls.
+    pub fn step(&mut self) -> BfResult<bool> {
+        if let Some(result) = self.result {
+            return result.map(|()| false);
+        }
+
+        let mut input = None;
+        let outcome = loop {
+            match bytecode::step(&self.program, &mut self.state, &mut self.pc, &mut input) {
+                Ok(StepResult::NeedsInput) => input = Some(self.read()),
+                outcome => break outcome,
+            }
+        };
+
+        match outcome {
+            Ok(StepResult::Output(byte)) => {
+                let _ = self.output.write_all(&[byte]);
+                Ok(true)
+            }
+            Ok(StepResult::Halted) => {
+                let _ = self.output.flush();
+                self.result = Some(Ok(()));
+                Ok(false)
+            }
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let _ = self.output.flush();
+                self.result = Some(Err(e));
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs up to `steps` instructions. Returns whether the machine is still running.
+    pub fn run_for(&mut self, steps: usize) -> BfResult<bool> {
+        for _ in 0 .. steps {
+            if !self.step()? {
+                return Ok(false);
+            }
+        }
+
+        Ok(self.result.is_none())
+    }
+
+    /// How the machine stopped, or `None` if it is still running.
+    pub fn result(&self) -> Option<BfResult<()>> {
+        self.result
+    }
+
+    /// The program the machine runs.
+    pub fn program(&self) -> &Arc<bytecode::Program> {
+        &self.program
+    }
+
+    /// The address of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The machine state.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Consumes the machine, returning its state.
+    pub fn into_state(self) -> State {
+        self.state
+    }
+
+    /// Reads a byte of input, with end of input or an error reading as 0 like in the
+    /// interpreters.
+    fn read(&mut self) -> u8 {
+        let mut byte = [0];
+        let _ = self.input.read_exact(&mut byte);
+        byte[0]
+    }
+}
+
+/// Runs machines round-robin.
+pub struct Executor<'a> {
+    machines: Vec<Machine<'a>>,
+    quantum:  usize,
+}
+
+impl<'a> Executor<'a> {
+    /// Creates an executor that runs each machine for up to `quantum` instructions per turn.
+    pub fn new(quantum: usize) -> Self {
+        Executor { machines: Vec::new(), quantum: quantum.max(1) }
+    }
+
+    /// Adds a machine, returning its index.
+    pub fn add(&mut self, machine: Machine<'a>) -> usize {
+        self.machines.push(machine);
+        self.machines.len() - 1
+    }
+
+    /// The machine with the given index.
+    pub fn machine(&self, index: usize) -> &Machine<'a> {
+        &self.machines[index]
+    }
+
+    /// The machines, in order by index.
+    pub fn machines(&self) -> &[Machine<'a>] {
+        &self.machines
+    }
+
+    /// Gives each running machine one turn. Returns whether any machine is still running.
+    ///
+    /// A machine’s error stops only that machine; see
+    /// [`Machine::result`](struct.Machine.html#method.result).
+    pub fn round(&mut self) -> bool {
+        let mut running = false;
+
+        for machine in &mut self.machines {
+            if machine.result.is_none() {
+                running |= machine.run_for(self.quantum).unwrap_or(false);
+            }
+        }
+
+        running
+    }
+
+    /// Runs every machine to completion, returning how each stopped.
+    pub fn run(&mut self) -> Vec<BfResult<()>> {
+        while self.round() {}
+
+        self.machines.iter()
+            .map(|machine| machine.result.unwrap_or(Ok(())))
+            .collect()
+    }
+
+    /// Consumes the executor, returning its machines.
+    pub fn into_machines(self) -> Vec<Machine<'a>> {
+        self.machines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use common::Error;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn compile(source: &[u8]) -> Arc<bytecode::Program> {
+        Arc::from(::ast::parse_program(source).unwrap().bytecode_compile())
+    }
+
+    /// A writer appending to a shared buffer.
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn machines_share_a_program() {
+        let program = compile(FACTOR_SRC);
+        let mut outputs = vec![Vec::new(), Vec::new()];
+
+        {
+            let mut executor = Executor::new(100);
+            let inputs: [&[u8]; 2] = [b"6\n", b"100\n"];
+            for (input, output) in inputs.iter().zip(&mut outputs) {
+                executor.add(Machine::new(program.clone(), State::new(), *input, output));
+            }
+            assert_eq!(executor.run(), vec![Ok(()), Ok(())]);
+        }
+
+        assert_eq!(outputs, vec![b"6: 2 3\n".to_vec(), b"100: 2 2 5 5\n".to_vec()]);
+    }
+
+    #[test]
+    fn turns_interleave() {
+        let program = compile(b"+.+.+.");
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let mut state = State::new();
+        state.store(10);
+
+        let mut executor = Executor::new(2);
+        executor.add(Machine::new(program.clone(), State::new(), io::empty(),
+                                  Shared(output.clone())));
+        executor.add(Machine::new(program, state, io::empty(), Shared(output.clone())));
+        executor.run();
+
+        assert_eq!(*output.borrow(), vec![1, 11, 2, 12, 3, 13]);
+    }
+
+    #[test]
+    fn errors_stop_one_machine() {
+        let mut executor = Executor::new(1);
+        executor.add(Machine::new(compile(b"<"), State::new(), io::empty(), io::sink()));
+        executor.add(Machine::new(compile(b"+++[-]"), State::new(), io::empty(), io::sink()));
+        assert_eq!(executor.run(), vec![Err(Error::PointerUnderflow), Ok(())]);
+        assert_eq!(executor.machine(0).pc(), 0);
+        assert_eq!(executor.machine(1).result(), Some(Ok(())));
+    }
+}
@@ -0,0 +1,362 @@
+//! A macro preprocessor, for writing large programs by hand.
+//!
+//! Preprocessing is opt-in (`bfi --macros`), since `@` is otherwise a comment. It understands
+//! three directives:
+//!
+//!  - `@define name { ... }` defines a macro, whose body runs to the matching `}`;
+//!  - `@name` expands to the body of the macro, itself preprocessed; and
+//!  - `@include "file"` splices in another file, found as by
+//!    [`sources::find`](../sources/fn.find.html). Each file is included at most once.
+//!
+//! Names are ASCII letters, digits and `_`, not starting with a digit. A macro must be defined
+//! before the code that expands it runs through the preprocessor, though a body may name
+//! macros defined after it. Definitions and includes may only appear at the top level of a
+//! file, and a macro that expands to itself, directly or not, is an error.
+//!
+//! ```
+//! use bf::macros;
+//! use bf::sources::Sources;
+//!
+//! let mut sources = Sources::new();
+//! sources.push("clear.bf", b"@define clear { [-] } +++ @clear");
+//! let expansion = macros::preprocess(&sources, &[]).unwrap();
+//! assert_eq!(expansion.text(), b" +++  [-] ");
+//! ```
+//!
+//! The [`Expansion`](struct.Expansion.html) remembers where each byte of its text was written,
+//! and the macro use it came from, so that errors can be reported in terms of the source.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use sources::{self, Location, Sources};
+
+/// A preprocessed program.
+#[derive(Clone, Debug)]
+pub struct Expansion {
+    text: Vec<u8>,
+    origins: Vec<Origin>,
+    sources: Sources,
+}
+
+/// Where a byte of an expansion comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Origin {
+    /// Where the byte is written, as an offset into the expansion’s sources.
+    pub offset: usize,
+    /// Where the outermost macro use the byte came from is, if it came from one.
+    pub expanded_at: Option<usize>,
+}
+
+impl Expansion {
+    /// The preprocessed text.
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// Where the byte at the given offset into the text comes from.
+    pub fn origin(&self, offset: usize) -> Option<Origin> {
+        self.origins.get(offset).cloned()
+    }
+
+    /// The sources, with any included files after the files preprocessed.
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+}
+
+/// A preprocessing error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Error {
+    /// The file the error is in.
+    pub file: String,
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column, counting from 1.
+    pub column: usize,
+    /// What went wrong.
+    pub kind: ErrorKind,
+}
+
+/// What went wrong in preprocessing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// An `@` is not followed by a name.
+    ExpectedName,
+    /// A definition’s name is not followed by `{`.
+    ExpectedBody,
+    /// An include is not followed by a quoted file name.
+    ExpectedFile,
+    /// A definition’s body or an include’s file name is never closed.
+    Unterminated,
+    /// A definition or include is inside a macro body.
+    NotAtTopLevel,
+    /// The macro is defined twice.
+    Redefined(String),
+    /// The macro is not defined.
+    Undefined(String),
+    /// The macros expand to themselves, each expanding the next and the last the first.
+    Cycle(Vec<String>),
+    /// The file could not be included.
+    Include(String, io::ErrorKind),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: ", self.file, self.line, self.column)?;
+        match self.kind {
+            ErrorKind::ExpectedName => write!(f, "expected a name after ‘@’"),
+            ErrorKind::ExpectedBody => write!(f, "expected ‘{{’ to begin the macro’s body"),
+            ErrorKind::ExpectedFile => write!(f, "expected a quoted file name"),
+            ErrorKind::Unterminated => write!(f, "unterminated directive"),
+            ErrorKind::NotAtTopLevel => write!(f, "directive inside a macro body"),
+            ErrorKind::Redefined(ref name) => write!(f, "macro ‘{}’ is already defined", name),
+            ErrorKind::Undefined(ref name) => write!(f, "macro ‘{}’ is not defined", name),
+            ErrorKind::Cycle(ref names) =>
+                write!(f, "macros expand themselves: ‘{}’ → ‘{}’",
+                       names.join("’ → ‘"), names[0]),
+            ErrorKind::Include(ref file, kind) =>
+                write!(f, "could not include ‘{}’: {:?}", file, kind),
+        }
+    }
+}
+
+/// The result of preprocessing.
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Preprocesses the sources, looking for included files on the search path.
+pub fn preprocess(sources: &Sources, search_path: &[PathBuf]) -> Result<Expansion> {
+    let mut preprocessor = Preprocessor {
+        sources: sources.clone(),
+        search_path,
+        definitions: HashMap::new(),
+        included: HashSet::new(),
+        stack: Vec::new(),
+        text: Vec::new(),
+        origins: Vec::new(),
+    };
+    preprocessor.scan(0, sources.text().len(), None)?;
+
+    Ok(Expansion {
+        text: preprocessor.text,
+        origins: preprocessor.origins,
+        sources: preprocessor.sources,
+    })
+}
+
+struct Preprocessor<'a> {
+    sources: Sources,
+    search_path: &'a [PathBuf],
+    /// The offsets of each macro’s body.
+    definitions: HashMap<String, (usize, usize)>,
+    included: HashSet<PathBuf>,
+    /// The macros being expanded, outermost first.
+    stack: Vec<String>,
+    text: Vec<u8>,
+    origins: Vec<Origin>,
+}
+
+impl<'a> Preprocessor<'a> {
+    /// Preprocesses the sources from `start` to `end`: a file if `expanded_at` is `None`, or
+    /// else the body of a macro used at that offset.
+    fn scan(&mut self, start: usize, end: usize, expanded_at: Option<usize>) -> Result<()> {
+        let mut offset = start;
+
+        while offset < end {
+            let byte = self.sources.text()[offset];
+            if byte != b'@' {
+                self.text.push(byte);
+                self.origins.push(Origin { offset, expanded_at });
+                offset += 1;
+                continue;
+            }
+
+            let at = offset;
+            let name = self.name(at + 1, end)
+                .ok_or_else(|| self.error(at, ErrorKind::ExpectedName))?;
+            offset = at + 1 + name.len();
+
+            match &name[..] {
+                "define" | "include" if expanded_at.is_some() =>
+                    return Err(self.error(at, ErrorKind::NotAtTopLevel)),
+                "define" => offset = self.define(offset, end)?,
+                "include" => offset = self.include(offset, end)?,
+                _ => self.expand(name, at, expanded_at)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the definition after `@define`, returning the offset after it.
+    fn define(&mut self, offset: usize, end: usize) -> Result<usize> {
+        let offset = self.skip_space(offset, end);
+        let name = self.name(offset, end)
+            .ok_or_else(|| self.error(offset, ErrorKind::ExpectedName))?;
+        let brace = self.skip_space(offset + name.len(), end);
+        if self.sources.text().get(brace) != Some(&b'{') || brace >= end {
+            return Err(self.error(brace, ErrorKind::ExpectedBody));
+        }
+
+        let mut depth = 0;
+        for close in brace .. end {
+            match self.sources.text()[close] {
+                b'{' => depth += 1,
+                b'}' => depth -= 1,
+                _ => continue,
+            }
+            if depth == 0 {
+                if self.definitions.contains_key(&name) {
+                    return Err(self.error(offset, ErrorKind::Redefined(name)));
+                }
+                self.definitions.insert(name, (brace + 1, close));
+                return Ok(close + 1);
+            }
+        }
+
+        Err(self.error(brace, ErrorKind::Unterminated))
+    }
+
+    /// Reads and preprocesses the file named after `@include`, returning the offset after it.
+    fn include(&mut self, offset: usize, end: usize) -> Result<usize> {
+        let quote = self.skip_space(offset, end);
+        if self.sources.text().get(quote) != Some(&b'"') || quote >= end {
+            return Err(self.error(quote, ErrorKind::ExpectedFile));
+        }
+        let length = self.sources.text()[quote + 1 .. end].iter().position(|&c| c == b'"')
+            .ok_or_else(|| self.error(quote, ErrorKind::Unterminated))?;
+        let name = String::from_utf8_lossy(&self.sources.text()[quote + 1 .. quote + 1 + length])
+            .into_owned();
+
+        let path = sources::find(&name, self.search_path)
+            .and_then(|path| fs::canonicalize(path).ok())
+            .ok_or_else(|| {
+                self.error(quote, ErrorKind::Include(name.clone(), io::ErrorKind::NotFound))
+            })?;
+        if self.included.insert(path.clone()) {
+            let contents = fs::read(&path)
+                .map_err(|e| self.error(quote, ErrorKind::Include(name.clone(), e.kind())))?;
+            let start = self.sources.text().len();
+            self.sources.push(&name, &contents);
+            self.scan(start, start + contents.len(), None)?;
+        }
+
+        Ok(quote + length + 2)
+    }
+
+    /// Expands the named macro, used at offset `at`.
+    fn expand(&mut self, name: String, at: usize, expanded_at: Option<usize>) -> Result<()> {
+        let (start, end) = match self.definitions.get(&name) {
+            Some(&body) => body,
+            None => return Err(self.error(at, ErrorKind::Undefined(name))),
+        };
+        if let Some(first) = self.stack.iter().position(|active| *active == name) {
+            let cycle = self.stack[first ..].to_vec();
+            return Err(self.error(at, ErrorKind::Cycle(cycle)));
+        }
+
+        self.stack.push(name);
+        self.scan(start, end, Some(expanded_at.unwrap_or(at)))?;
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// The name starting at `offset`, if there is one.
+    fn name(&self, offset: usize, end: usize) -> Option<String> {
+        let text = &self.sources.text()[offset.min(end) .. end];
+        let length = text.iter().position(|&c| !(c.is_ascii_alphanumeric() || c == b'_'))
+            .unwrap_or(text.len());
+        if length == 0 || text[0].is_ascii_digit() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&text[.. length]).into_owned())
+        }
+    }
+
+    fn skip_space(&self, offset: usize, end: usize) -> usize {
+        let text = self.sources.text();
+        (offset .. end).find(|&i| !text[i].is_ascii_whitespace()).unwrap_or(end)
+    }
+
+    fn error(&self, offset: usize, kind: ErrorKind) -> Error {
+        let Location { file, line, column } = self.sources.locate(offset)
+            .unwrap_or(Location { file: "", line: 1, column: 1 });
+        Error { file: file.to_owned(), line, column, kind }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess_str(source: &str) -> Result<Expansion> {
+        let mut sources = Sources::new();
+        sources.push("test.bf", source.as_bytes());
+        preprocess(&sources, &[])
+    }
+
+    fn kind(source: &str) -> ErrorKind {
+        preprocess_str(source).unwrap_err().kind
+    }
+
+    #[test]
+    fn macros_expand_with_their_origins() {
+        let expansion = preprocess_str("@define two{++}@define four{@two@two}>@four").unwrap();
+        assert_eq!(expansion.text(), b">++++");
+
+        let origins: Vec<_> = (0 .. 5).map(|i| expansion.origin(i).unwrap()).collect();
+        let use_site = Some(38);
+        assert_eq!(origins, [
+            Origin { offset: 37, expanded_at: None },
+            Origin { offset: 12, expanded_at: use_site },
+            Origin { offset: 13, expanded_at: use_site },
+            Origin { offset: 12, expanded_at: use_site },
+            Origin { offset: 13, expanded_at: use_site },
+        ]);
+
+        assert_eq!(preprocess_str("@define loop { [ { } ] } @loop").unwrap().text(),
+                   b"  [ { } ] ");
+    }
+
+    #[test]
+    fn bad_macros_are_errors() {
+        assert_eq!(kind("@"), ErrorKind::ExpectedName);
+        assert_eq!(kind("@define x +"), ErrorKind::ExpectedBody);
+        assert_eq!(kind("@define x { [-]"), ErrorKind::Unterminated);
+        assert_eq!(kind("@define x {} @define x {}"), ErrorKind::Redefined("x".to_owned()));
+        assert_eq!(kind("@nope"), ErrorKind::Undefined("nope".to_owned()));
+        assert_eq!(kind("@define x { @define y {} }@x"), ErrorKind::NotAtTopLevel);
+        assert_eq!(kind("@define a { @b } @define b { +@a } @a"),
+                   ErrorKind::Cycle(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(preprocess_str("@define a { @a } @a").unwrap_err().to_string(),
+                   "test.bf:1:13: macros expand themselves: ‘a’ → ‘a’");
+
+        let error = preprocess_str("+\n @oops").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 2));
+        assert_eq!(error.to_string(), "test.bf:2:2: macro ‘oops’ is not defined");
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let search_path = [::std::env::temp_dir()
+                               .join(format!("bf-macros-{}", ::std::process::id()))];
+        let dir = &search_path[0];
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("lib.bf"), b"@define clear{[-]}").unwrap();
+
+        let mut sources = Sources::new();
+        sources.push("main.bf", b"@include \"lib.bf\" @include \"lib.bf\"@clear");
+        let expansion = preprocess(&sources, &search_path).unwrap();
+        assert_eq!(expansion.text(), b" [-]");
+        assert_eq!(expansion.sources().files().collect::<Vec<_>>(), ["main.bf", "lib.bf"]);
+
+        let origin = expansion.origin(1).unwrap();
+        let location = expansion.sources().locate(origin.offset).unwrap();
+        assert_eq!((location.file, location.column), ("lib.bf", 15));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Minification, with a check that the minified program behaves the same.
+//!
+//! [`strip`](fn.strip.html) removes everything but the eight commands. [`shrink`](fn.shrink.html)
+//! goes further and writes the peephole optimizer’s output back out as Brainfuck, with adds
+//! such as `+-` combined and loops that can never run left out. Either way,
+//! [`verify`](fn.verify.html) runs the original and the minified program side by side on
+//! random inputs and compares what they print and the state they halt in. This is what
+//! `bfi minify` does.
+
+use ast;
+use bytecode::{self, Execution, StepResult};
+use common::{BfResult, Instruction};
+use peephole::{self, Statement};
+use state::State;
+use traits::{IntoUsize, PeepholeCompilable};
+
+/// (`== 10_000_000`) The number of steps the original program gets on each input before the
+/// trial is given up on.
+pub const STEP_BUDGET: usize = 10_000_000;
+
+/// An input on which the minified program behaves differently.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// The program’s input.
+    pub input: Vec<u8>,
+    /// What went wrong.
+    pub reason: &'static str,
+}
+
+/// Removes everything but commands from the source.
+pub fn strip(source: &[u8]) -> Vec<u8> {
+    source.iter().cloned()
+        .filter(|&c| matches!(c, b'<' | b'>' | b'+' | b'-' | b',' | b'.' | b'[' | b']'))
+        .collect()
+}
+
+/// Writes a peephole-optimized program back out as Brainfuck.
+///
+/// Instructions become the shortest loops or runs that do their work, adds in a row are
+/// combined, and loops where the cell is known to be zero, such as at the start of the program,
+/// are left out. So is a `DivMod` or index instruction, since the loop after it does the same
+/// work. Moves are not combined, since `<>` fails at the start of the tape.
+pub fn shrink(program: &peephole::Program) -> Vec<u8> {
+    let mut out = Vec::new();
+    emit(program, true, &mut out);
+    out
+}
+
+/// Emits statements, starting where the current cell is zero if `known_zero`.
+fn emit(program: &[Statement], mut known_zero: bool, out: &mut Vec<u8>) {
+    let run = |out: &mut Vec<u8>, c, count: usize| out.extend(vec![c; count]);
+    let mut add = 0u8;
+
+    for statement in program {
+        if let Statement::Instr(Instruction::Add(value)) = *statement {
+            add = add.wrapping_add(value);
+            continue;
+        }
+        if add != 0 {
+            emit_add(add, out);
+            add = 0;
+            known_zero = false;
+        }
+
+        match *statement {
+            Statement::Instr(instruction) => match instruction {
+                Instruction::Left(count) => run(out, b'<', count.into_usize()),
+                Instruction::Right(count) => run(out, b'>', count.into_usize()),
+                Instruction::In => out.push(b','),
+                Instruction::Out => out.push(b'.'),
+                Instruction::SetZero | Instruction::OffsetAddRight(_) |
+                Instruction::OffsetAddLeft(_) | Instruction::FindZeroRight(_) |
+                Instruction::FindZeroLeft(_) if known_zero => (),
+                Instruction::SetZero => out.extend_from_slice(b"[-]"),
+                Instruction::OffsetAddRight(count) => {
+                    out.extend_from_slice(b"[-");
+                    run(out, b'>', count.into_usize());
+                    out.push(b'+');
+                    run(out, b'<', count.into_usize());
+                    out.push(b']');
+                }
+                Instruction::OffsetAddLeft(count) => {
+                    out.extend_from_slice(b"[-");
+                    run(out, b'<', count.into_usize());
+                    out.push(b'+');
+                    run(out, b'>', count.into_usize());
+                    out.push(b']');
+                }
+                Instruction::FindZeroRight(count) => {
+                    out.push(b'[');
+                    run(out, b'>', count.into_usize());
+                    out.push(b']');
+                }
+                Instruction::FindZeroLeft(count) => {
+                    out.push(b'[');
+                    run(out, b'<', count.into_usize());
+                    out.push(b']');
+                }
+                Instruction::DivMod | Instruction::IndexRight(_) | Instruction::IndexLeft(_) => (),
+                Instruction::Add(_) | Instruction::JumpZero(_) | Instruction::JumpNotZero(_) =>
+                    unreachable!(),
+            },
+
+            Statement::Loop(_) if known_zero => (),
+            Statement::Loop(ref body) => {
+                out.push(b'[');
+                emit(body, false, out);
+                out.push(b']');
+            }
+        }
+
+        known_zero = match *statement {
+            Statement::Instr(Instruction::Left(_)) | Statement::Instr(Instruction::Right(_)) |
+            Statement::Instr(Instruction::In) => false,
+            Statement::Instr(Instruction::Out) | Statement::Instr(Instruction::DivMod) |
+            Statement::Instr(Instruction::IndexRight(_)) |
+            Statement::Instr(Instruction::IndexLeft(_)) => known_zero,
+            _ => true,
+        };
+    }
+
+    if add != 0 {
+        emit_add(add, out);
+    }
+}
+
+/// Emits the shorter of `+` and `-` runs that add the value.
+fn emit_add(value: u8, out: &mut Vec<u8>) {
+    if value <= 128 {
+        out.extend(vec![b'+'; value as usize]);
+    } else {
+        out.extend(vec![b'-'; 256 - value as usize]);
+    }
+}
+
+/// Checks that `minified` behaves as `original` does, running both on `trials` inputs: the
+/// empty input and then random ones drawn from the seed. Returns the number of trials that
+/// were conclusive, which leaves out those where the original did not halt in time.
+pub fn verify(original: &ast::Program, minified: &[u8], seed: u64, trials: usize)
+              -> Result<usize, Mismatch>
+{
+    let fail = |input: &[u8], reason| Mismatch { input: input.to_owned(), reason };
+
+    let original = bytecode::compile(&original.peephole_compile());
+    let minified = ast::parse_program(minified)
+        .map_err(|_| fail(&[], "the minified program does not parse"))?;
+    let minified = bytecode::compile(&minified.peephole_compile());
+
+    let mut rng = seed | 1;
+    let mut next = || {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng
+    };
+
+    let mut conclusive = 0;
+    for trial in 0 .. trials {
+        let length = if trial == 0 { 0 } else { next() % 17 };
+        let input: Vec<u8> = (0 .. length).map(|_| next() as u8).collect();
+
+        let expected = match run(&original, &input, STEP_BUDGET) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        // The minified program may compile a little differently, so give it some slack.
+        let actual = run(&minified, &input, 2 * STEP_BUDGET)
+            .ok_or_else(|| fail(&input, "the minified program does not halt"))?;
+
+        match (expected, actual) {
+            ((Ok(expected), out1), (Ok(actual), out2)) => {
+                if out1 != out2 {
+                    return Err(fail(&input, "the output differs"));
+                }
+                if expected != actual {
+                    return Err(fail(&input, "the final state differs"));
+                }
+            }
+            ((Err(expected), out1), (Err(actual), out2)) => {
+                if out1 != out2 {
+                    return Err(fail(&input, "the output differs"));
+                }
+                if expected != actual {
+                    return Err(fail(&input, "the error differs"));
+                }
+            }
+            _ => return Err(fail(&input, "one fails and the other does not")),
+        }
+        conclusive += 1;
+    }
+
+    Ok(conclusive)
+}
+
+/// Runs a program on the given input for at most `budget` steps, returning its final state or
+/// error and its output, or `None` if it does not halt in time.
+fn run(program: &bytecode::Program, input: &[u8], budget: usize)
+       -> Option<(BfResult<State>, Vec<u8>)>
+{
+    let mut execution = Execution::new(program, State::new());
+    let mut input = input.iter().cloned();
+    let mut output = Vec::new();
+
+    for _ in 0 .. budget {
+        match execution.step() {
+            Ok(StepResult::Continue) => (),
+            Ok(StepResult::Output(byte)) => output.push(byte),
+            Ok(StepResult::Halted) => return Some((Ok(execution.into_state()), output)),
+            Ok(StepResult::NeedsInput) => execution.provide_input(input.next()),
+            Err(error) => return Some((Err(error), output)),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shrink_source(source: &[u8]) -> Vec<u8> {
+        shrink(&ast::parse_program(source).unwrap().peephole_compile())
+    }
+
+    #[test]
+    fn minified_programs_keep_only_what_matters() {
+        assert_eq!(strip(b"Hello [world]: +-, and <.>!\n"), b"[]+-,<.>");
+        assert_eq!(shrink_source(b"[dead]+-+++<>>,[-]>[->>+<<]---.[>]"),
+                   &b"+++<>>,[-]>[->>+<<]---.[>]"[..]);
+        assert_eq!(shrink_source(b",[-].[-]+-[>]"), b",[-].");
+        assert_eq!(shrink_source(&[b'+'; 200]), &[b'-'; 56][..]);
+    }
+
+    #[test]
+    fn minified_programs_are_verified() {
+        let source = b"comment ,[->+>+<<]>[-<+>]>[-.]<.";
+        let program = ast::parse_program(source).unwrap();
+        assert_eq!(verify(&program, &strip(source), 1, 20), Ok(20));
+        assert_eq!(verify(&program, &shrink(&program.peephole_compile()), 1, 20), Ok(20));
+
+        let mismatch = verify(&program, b",[->+>+<<]>[-<+>]>[-.]<..", 1, 20).unwrap_err();
+        assert_eq!(mismatch, Mismatch { input: Vec::new(), reason: "the output differs" });
+        assert_eq!(verify(&program, b"[", 1, 20).unwrap_err().reason,
+                   "the minified program does not parse");
+    }
+}
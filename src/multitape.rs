@@ -0,0 +1,175 @@
+//! A multi-tape dialect, where `{` and `}` switch tapes.
+//!
+//! Programs have up to [`MAX_TAPES`](../state/constant.MAX_TAPES.html) tapes, each with its own
+//! pointer, kept in a [`Tapes`](../state/struct.Tapes.html). The usual commands act on the
+//! current tape; `}` switches to the next tape, creating it the first time, and `{` switches
+//! back to the previous one. Keeping the pointer in the same column on every tape makes them
+//! a 2D grid, with `{` and `}` moving up and down.
+//!
+//! Programs are represented as bytecode [`Instruction`](enum.Instruction.html)s. Runs of
+//! moves, adds, and tape switches are combined, but loops are not optimized.
+
+use std::io::{Read, Write};
+
+use bytecode;
+use common::{self, BfResult, Count, Error};
+use state::{State, Tapes};
+use traits::Interpretable;
+
+/// A multi-tape program is a sequence of instructions.
+pub type Program = [Instruction];
+
+/// A multi-tape instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// A Brainfuck instruction, acting on the current tape. Jump addresses are into the
+    /// multi-tape program.
+    Cell(common::Instruction),
+    /// Switch to an earlier tape by the specified offset.
+    PreviousTape(Count),
+    /// Switch to a later tape by the specified offset.
+    NextTape(Count),
+}
+
+/// Parses multi-tape source.
+///
+/// # Errors
+///
+/// Unmatched square brackets will result in an `Err` return. See
+/// [`common::Error`](../common/enum.Error.html).
+pub fn parse_program(source: &[u8]) -> BfResult<Box<Program>> {
+    use common::Instruction::*;
+    use self::Instruction::*;
+
+    let mut program = Vec::new();
+    let mut open = Vec::new();
+
+    for &c in source {
+        match (c, program.last_mut()) {
+            (b'+', Some(&mut Cell(Add(ref mut n)))) => { *n = n.wrapping_add(1); continue; }
+            (b'-', Some(&mut Cell(Add(ref mut n)))) => { *n = n.wrapping_sub(1); continue; }
+            (b'>', Some(&mut Cell(Right(ref mut n)))) => { *n += 1; continue; }
+            (b'<', Some(&mut Cell(Left(ref mut n)))) => { *n += 1; continue; }
+            (b'}', Some(&mut NextTape(ref mut n))) => { *n += 1; continue; }
+            (b'{', Some(&mut PreviousTape(ref mut n))) => { *n += 1; continue; }
+            _ => (),
+        }
+
+        let instruction = match c {
+            b'+' => Cell(Add(1)),
+            b'-' => Cell(Add(255)),
+            b'>' => Cell(Right(1)),
+            b'<' => Cell(Left(1)),
+            b',' => Cell(In),
+            b'.' => Cell(Out),
+            b'}' => NextTape(1),
+            b'{' => PreviousTape(1),
+            b'[' => {
+                open.push(program.len());
+                Cell(JumpZero(0))
+            }
+            b']' => {
+                let begin = open.pop().ok_or(Error::UnmatchedEnd)?;
+                program[begin] = Cell(JumpZero(bytecode::usize_to_count(program.len())));
+                Cell(JumpNotZero(bytecode::usize_to_count(begin)))
+            }
+            _ => continue,
+        };
+
+        program.push(instruction);
+    }
+
+    if !open.is_empty() {
+        return Err(Error::UnmatchedBegin);
+    }
+
+    Ok(program.into_boxed_slice())
+}
+
+/// Runs a program against the given tapes.
+pub fn interpret_tapes<R: Read, W: Write>(program: &Program, tapes: &mut Tapes,
+                                          mut input: R, mut output: W) -> BfResult<()>
+{
+    use common::Instruction::{In, Out};
+    use self::Instruction::*;
+
+    let mut pc = 0;
+
+    while let Some(&instruction) = program.get(pc) {
+        match instruction {
+            Cell(In) => {
+                tapes.current_mut().read(&mut input);
+                pc += 1;
+            }
+
+            Cell(Out) => {
+                tapes.current().write(&mut output);
+                pc += 1;
+            }
+
+            Cell(instruction) => {
+                bytecode::execute(instruction, tapes.current_mut(), &mut pc, &mut None)?;
+            }
+
+            PreviousTape(count) => {
+                tapes.previous_tape(count)?;
+                pc += 1;
+            }
+
+            NextTape(count) => {
+                tapes.next_tape(count)?;
+                pc += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Interpretable for Program {
+    /// Runs the program with the given state as its first tape.
+    fn interpret_state<R: Read, W: Write>(&self, state: State, input: R, output: W)
+                                          -> BfResult<()>
+    {
+        interpret_tapes(self, &mut Tapes::new(state), input, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    #[test]
+    fn brainfuck_runs_on_the_first_tape() {
+        let program = parse_program(FACTOR_SRC).unwrap();
+        assert_interpret(&*program, b"6\n", b"6: 2 3\n");
+    }
+
+    #[test]
+    fn runs_are_combined() {
+        use common::Instruction::*;
+        use super::Instruction::*;
+
+        assert_eq!(&*parse_program(b"++}}}{>[-]").unwrap(),
+                   &[Cell(Add(2)), NextTape(3), PreviousTape(1), Cell(Right(1)),
+                     Cell(JumpZero(6)), Cell(Add(255)), Cell(JumpNotZero(4))]);
+    }
+
+    #[test]
+    fn tapes_are_separate() {
+        // Read two bytes onto two tapes, then print them in the other order.
+        let program = parse_program(b",},.{.").unwrap();
+        assert_interpret(&*program, b"ab", b"ba");
+
+        let mut tapes = Tapes::new(State::with_capacity(4));
+        interpret_tapes(&parse_program(b"}>+}++").unwrap(), &mut tapes, &b""[..], Vec::new())
+            .unwrap();
+        assert_eq!(tapes.index(), 2);
+        assert_eq!(tapes.tapes()[1].load(), 1);
+        assert_eq!(tapes.current().load(), 2);
+
+        assert_interpret_result(&*parse_program(b"{").unwrap(), b"",
+                                Err(Error::PointerUnderflow));
+    }
+}
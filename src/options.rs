@@ -0,0 +1,173 @@
+//! Options controlling compilation by the native backends, and running programs.
+
+use analysis::ProgramAnalysis;
+use peephole;
+use state::DEFAULT_CAPACITY;
+
+/// Options for the [JIT](../jit/index.html) and [LLVM](../llvm/index.html) backends.
+///
+/// The [JavaScript](../js/index.html) and [C](../c/index.html) transpilers honor only `checked`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompileOptions {
+    /// Emit run-time bounds checks wherever the analysis cannot prove them unnecessary.
+    ///
+    /// Defaults to `true`.
+    pub checked: bool,
+    /// Generate code that does not depend on addresses in the host process, such as the
+    /// locations of the run-time system’s functions, so that compiling the same program twice
+    /// produces byte-identical output.
+    ///
+    /// Defaults to `false`.
+    pub deterministic: bool,
+    /// Annotate generated LLVM IR with function attributes (`nounwind`, `noalias`, `nonnull`)
+    /// and type-based alias analysis metadata separating tape accesses from the data pointer.
+    /// Turning this off can help when debugging the optimizer’s treatment of the IR.
+    ///
+    /// Defaults to `true`. Ignored by the JIT. Requires LLVM ≥ 3.9.
+    pub metadata: bool,
+    /// Emit each top-level loop as its own LLVM function, rather than one function for the
+    /// whole program. This can cut LLVM compile time dramatically for machine-generated
+    /// programs with many loops, at some cost in run-time performance.
+    ///
+    /// Defaults to `false`. Ignored by the JIT.
+    pub outline_loops: bool,
+    /// With `outline_loops`, the number of threads to compile the outlined loops on. Each
+    /// thread builds and optimizes its own LLVM module, and the results are linked together.
+    ///
+    /// Defaults to `1`. Ignored by the JIT.
+    pub codegen_threads: usize,
+    /// Make generated code easier to debug natively: top-level loops are outlined (as with
+    /// `outline_loops`) into functions named `bfi_loop_<n>`, and every function keeps its frame
+    /// pointer. MCJIT registers the code it generates with GDB’s JIT interface, so these frames
+    /// show up by name in backtraces.
+    ///
+    /// Defaults to `false`. Ignored by the JIT.
+    pub debug_symbols: bool,
+    /// Check every tape access against [shadow memory](../sanitizer/index.html), stopping with
+    /// an error on any access outside the tape and recording reads of never-written cells.
+    /// This is slow, but catches wild accesses that unchecked or miscompiled code would make
+    /// silently.
+    ///
+    /// Defaults to `false`.
+    pub sanitize: bool,
+    /// With `checked`, check at the top of each loop iteration that the iteration stays on the
+    /// tape, so that the checks inside it can be left out. When that check fails, the run
+    /// deoptimizes: it bails out to the peephole interpreter, which finishes the run and
+    /// reports any error where it happens.
+    ///
+    /// Defaults to `false`. Ignored by LLVM.
+    pub speculate: bool,
+    /// Have the C program put itself in seccomp’s strict mode before it runs, so that the only
+    /// system calls it can make are `read`, `write` and `exit`. It then does its I/O without
+    /// `stdio`, which would need more. This is for distributing executables compiled from
+    /// programs that are not trusted; the C needs Linux to build.
+    ///
+    /// Defaults to `false`. Ignored except by C.
+    pub seccomp: bool,
+    /// Count steps in the generated code, as the bytecode interpreter counts instructions, and
+    /// poll the run-time system at safepoints, the loop jumps and the instructions after I/O,
+    /// so that native runs can have [fuel, cancellation, deadlines and progress
+    /// callbacks](../rts/struct.Meter.html), report their steps in
+    /// [`RunStats`](../run_stats/struct.RunStats.html), and stop where the bytecode interpreter
+    /// can go on. This costs an add per straight-line stretch of code and a compare per loop
+    /// iteration.
+    ///
+    /// Defaults to `false`. Ignored except by the JIT and LLVM.
+    pub accounting: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            checked: true,
+            deterministic: false,
+            metadata: true,
+            outline_loops: false,
+            codegen_threads: 1,
+            debug_symbols: false,
+            sanitize: false,
+            speculate: false,
+            seccomp: false,
+            accounting: false,
+        }
+    }
+}
+
+/// Options for running a program.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RunOptions {
+    /// The memory size in cells, or `None` for the
+    /// [default](../state/constant.DEFAULT_CAPACITY.html).
+    ///
+    /// Defaults to `None`.
+    pub memory_size: Option<usize>,
+    /// Size the tape to just the cells the program can reach, as found by
+    /// [`ProgramAnalysis::max_cells`](../analysis/struct.ProgramAnalysis.html#method.max_cells),
+    /// when that is less than the memory size. The program behaves the same, since it never
+    /// reaches the cells left out.
+    ///
+    /// Defaults to `false`.
+    pub auto_memory: bool,
+    /// When output is written out.
+    ///
+    /// Defaults to `Buffering::Unbuffered`.
+    pub buffering: Buffering,
+    /// How many bytes of input the [native backends](../rts/index.html) read at a time, serving
+    /// `,` from what they read ahead, or 0 or 1 to read each byte when it is needed. Reading
+    /// ahead suits input from a file or pipe; input typed at a terminal arrives a line at a time
+    /// anyway.
+    ///
+    /// Defaults to 0.
+    pub input_batch: usize,
+}
+
+/// When a program’s output is written out, for [`RunOptions`](struct.RunOptions.html).
+///
+/// Whatever the policy, buffered output is written out when the program finishes, and the
+/// [native backends](../rts/index.html) also write it out before each read, so that prompts
+/// show before the program waits for an answer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Buffering {
+    /// Each byte as it is printed, which is slow when each write is a system call.
+    #[default]
+    Unbuffered,
+    /// At the end of each line.
+    Line,
+    /// Whenever the given number of bytes is waiting.
+    Block(usize),
+}
+
+impl RunOptions {
+    /// The memory size to run the program with.
+    pub fn memory_size_for(&self, program: &peephole::Program) -> Option<usize> {
+        if !self.auto_memory {
+            return self.memory_size;
+        }
+
+        let size = self.memory_size.unwrap_or(DEFAULT_CAPACITY);
+        match ProgramAnalysis::new(program).max_cells() {
+            Some(cells) if cells < size => Some(cells),
+            _ => self.memory_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::PeepholeCompilable;
+
+    #[test]
+    fn auto_memory_shrinks_the_tape_only() {
+        let program = ::ast::parse_program(b">>+<<").unwrap().peephole_compile();
+        let options = |memory_size, auto_memory| {
+            RunOptions { memory_size, auto_memory, ..RunOptions::default() }
+        };
+        assert_eq!(options(None, false).memory_size_for(&program), None);
+        assert_eq!(options(None, true).memory_size_for(&program), Some(3));
+        assert_eq!(options(Some(2), true).memory_size_for(&program), Some(2));
+
+        let unbounded = ::ast::parse_program(b"+[>+]").unwrap().peephole_compile();
+        assert_eq!(options(Some(100), true).memory_size_for(&unbounded), Some(100));
+    }
+}
@@ -1,4 +1,7 @@
+use std::sync::Arc;
+
 use super::*;
+use super::rules::{self, Action};
 use rle;
 
 /// Program forms that can be compiled to the peephole AST.
@@ -11,11 +14,19 @@ pub trait PeepholeCompilable {
     fn peephole_compile(&self) -> Box<Program> {
         self.with_rle(compile)
     }
+
+    /// Peephole optimize the given program and analyze it, for sharing.
+    fn peephole_share(&self) -> Arc<ProgramData> {
+        ProgramData::new(self.peephole_compile())
+    }
 }
 
 /// Peephole-optimizes run-length encoded AST.
 ///
-/// See [`Instruction`](struct.Instruction.html) for descriptions of the peepholes.
+/// See [`Instruction`](struct.Instruction.html) for descriptions of the peepholes, and
+/// [`rules`](rules/index.html) for the loops they come from. Loops that start where the current
+/// cell is known to be zero, just after another loop, never run and are dropped; this removes a
+/// redundant `SetZero` or comment loop after a loop, for instance.
 pub fn compile(src: &[rle::Statement]) -> Box<Program> {
     let mut compiler = Compiler::new();
     compiler.compile(src);
@@ -24,17 +35,15 @@ pub fn compile(src: &[rle::Statement]) -> Box<Program> {
 
 pub struct Compiler {
     instructions: Vec<Statement>,
-}
-
-macro_rules! or_else {
-    ($x:expr) => ($x);
-    ($x:expr, $($y:expr),+) => ($x.or_else(|| or_else!($($y),+)))
+    /// Whether the current cell is known to be zero here.
+    known_zero: bool,
 }
 
 impl Compiler {
     pub fn new() -> Self {
         Compiler {
             instructions: Vec::new(),
+            known_zero: false,
         }
     }
 
@@ -70,22 +79,27 @@ impl Compiler {
                 Cmd(Begin, _) | Cmd(End, _) =>
                     panic!("bad opcode"),
 
+                Loop(_) if self.known_zero => (),
+
                 Loop(ref body) => {
-                    let body = compile(&*body);
-
-                    let peephole = or_else!(
-                        set_zero_peephole(&body),
-                        find_zero_peephole(&body),
-                        offset_add_peephole(&body)
-                    );
-
-                    if let Some(instr) = peephole {
-                        self.push(instr);
-                    } else {
-                        self.instructions.push(Statement::Loop(body))
+                    let body = compile(body);
+
+                    match rules::rewrite(&body) {
+                        Some((_, instr, Action::Replace)) => self.push(instr),
+                        Some((_, instr, Action::Prefix)) => {
+                            self.push(instr);
+                            self.instructions.push(Statement::Loop(body.into()));
+                        }
+                        None => self.instructions.push(Statement::Loop(body.into())),
                     }
                 }
             }
+
+            self.known_zero = match *instruction {
+                Loop(_) => true,
+                Cmd(Out, _) => self.known_zero,
+                _ => false,
+            };
         }
     }
 
@@ -98,69 +112,109 @@ impl Compiler {
     }
 }
 
-pub fn set_zero_peephole(body: &[Statement]) -> Option<common::Instruction> {
-    use self::Statement::*;
-    use common::Instruction::*;
+impl PeepholeCompilable for rle::Program {
+    fn with_rle<F, R>(&self, k: F) -> R
+        where F: FnOnce(&rle::Program) -> R
+    {
+        k(self)
+    }
+}
 
-    if body.len() == 1 && (body[0] == Instr(Add(1)) || body[0] == Instr(Add(255))) {
-        Some(SetZero)
-    } else {
-        None
+impl<T: rle::RleCompilable + ?Sized> PeepholeCompilable for T {
+    fn with_rle<F, R>(&self, k: F) -> R
+        where F: FnOnce(&rle::Program) -> R
+    {
+        k(&self.rle_compile())
     }
 }
 
-pub fn find_zero_peephole(body: &[Statement]) -> Option<common::Instruction> {
-    use self::Statement::*;
+#[cfg(test)]
+mod tests {
     use common::Instruction::*;
+    use peephole::Statement::*;
+    use traits::PeepholeCompilable;
 
-    if body.len() == 1 {
-        match body[0] {
-            Instr(Right(count)) =>
-                Some(FindZeroRight(count)),
-            Instr(Left(count)) =>
-                Some(FindZeroLeft(count)),
-            _ => None,
-        }
-    } else {
-        None
+    fn peephole(source: &[u8]) -> Vec<::peephole::Statement> {
+        ::ast::parse_program(source).unwrap().peephole_compile().to_vec()
     }
-}
 
-pub fn offset_add_peephole(body: &[Statement]) -> Option<common::Instruction> {
-    use self::Statement::*;
-    use common::Instruction::*;
+    #[test]
+    fn div_mod_loops_get_a_div_mod_first() {
+        let program = peephole(b">+++++++<++++++++++++++++[->-[>+>>]>[+[-<+>]>+>>]<<<<<]");
+        assert_eq!(program[4], Instr(DivMod));
+        assert_eq!(::peephole::rules::rewrite(match program[5] {
+            Loop(ref body) => body,
+            _ => panic!("expected the loop"),
+        }).map(|(rule, _, _)| rule.name), Some("divmod"));
+    }
 
-    if body.len() == 4 {
-        match (&body[0], &body[1], &body[2], &body[3]) {
-            (&Instr(Add(255)), &Instr(Right(count_l)), &Instr(Add(1)), &Instr(Left(count_r)))
-            if count_l == count_r => {
-                Some(OffsetAddRight(count_l))
-            }
+    #[test]
+    fn div_mod_agrees_with_its_loop() {
+        use traits::Interpretable;
 
-            (&Instr(Add(255)), &Instr(Left(count_l)), &Instr(Add(1)), &Instr(Right(count_r)))
-            if count_l == count_r => {
-                Some(OffsetAddLeft(count_l))
+        let cells = |values: &[u8]| -> Vec<u8> {
+            let mut source = Vec::new();
+            for &value in values {
+                source.extend(vec![b'+'; value as usize]);
+                source.push(b'>');
             }
+            source.pop();
+            source.extend(vec![b'<'; values.len() - 1]);
+            source.extend_from_slice(b"[->-[>+>>]>[+[-<+>]>+>>]<<<<<].>.>.>.");
+            source
+        };
+
+        for n in 0 .. 20 {
+            for d in 0 .. 20 {
+                for &(r, q, zeros) in &[(0, 0, 0), (1, 3, 0), (255, 0, 0), (0, 0, 1), (2, 0, 2)] {
+                    let source = cells(&[n, d, r, q, zeros, zeros / 2]);
+                    let ast = ::ast::parse_program(&source).unwrap();
+                    for &size in &[6, 30] {
+                        assert_eq!(ast.peephole_compile().interpret_memory(Some(size), b""),
+                                   ast.interpret_memory(Some(size), b""),
+                                   "{}", String::from_utf8_lossy(&source));
+                    }
+                }
 
-            _ => None,
+                let ast = ::ast::parse_program(&cells(&[n, d, 0, 0, 0])).unwrap();
+                assert_eq!(ast.peephole_compile().interpret_memory(Some(5), b""),
+                           ast.interpret_memory(Some(5), b""));
+            }
         }
-    } else {
-        None
     }
-}
 
-impl PeepholeCompilable for rle::Program {
-    fn with_rle<F, R>(&self, k: F) -> R
-        where F: FnOnce(&rle::Program) -> R
-    {
-        k(self)
+    #[test]
+    fn index_loops_agree_with_their_loops() {
+        use traits::Interpretable;
+
+        assert_eq!(peephole(b"+[[->>+<<]>>-]")[1], Instr(IndexRight(2)));
+        assert_eq!(peephole(b"+[-[-<+>]<]")[1], Instr(IndexLeft(1)));
+
+        let loops: &[&[u8]] = &[b"[[->+<]>-]", b"[-[->>+<<]>>]", b"[[-<+>]<-]", b"[-[-<<+>>]<<]"];
+        for &index in loops {
+            for &(before, cells) in &[(b">>>>+++" as &[u8], b">+>>>+<<<<" as &[u8]),
+                                      (b">>>>>>+", b""), (b">>>>>>++", b">>>+<<<"),
+                                      (b">>>>++++++", b""), (b"+++", b"<+>"), (b"+++", b"")] {
+                let mut source = cells.to_vec();
+                source.extend_from_slice(before);
+                source.extend_from_slice(index);
+                source.extend_from_slice(b".>.[-]<<<.");
+                let ast = ::ast::parse_program(&source).unwrap();
+                for &size in &[7, 10] {
+                    assert_eq!(ast.peephole_compile().interpret_memory(Some(size), b""),
+                               ast.interpret_memory(Some(size), b""),
+                               "{}", String::from_utf8_lossy(&source));
+                }
+            }
+        }
     }
-}
 
-impl<T: rle::RleCompilable + ?Sized> PeepholeCompilable for T {
-    fn with_rle<F, R>(&self, k: F) -> R
-        where F: FnOnce(&rle::Program) -> R
-    {
-        k(&self.rle_compile())
+    #[test]
+    fn loops_after_loops_are_dropped() {
+        assert_eq!(peephole(b"[>]<[-]"), vec![Instr(FindZeroRight(1)), Instr(Left(1)),
+                                             Instr(SetZero)]);
+        assert_eq!(peephole(b"[>][-].[.]+[-]"), vec![Instr(FindZeroRight(1)), Instr(Out),
+                                                     Instr(Add(1)), Instr(SetZero)]);
+        assert_eq!(peephole(b"[[-][+]]"), vec![Loop(vec![Instr(SetZero)].into())]);
     }
 }
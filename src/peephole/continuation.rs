@@ -0,0 +1,138 @@
+//! Continuations, for moving a run paused at a loop into another backend.
+//!
+//! A run of the bytecode interpreter that stops at one of a loop’s jumps can be finished by a
+//! [`continuation`](fn.continuation.html): a program that, started on the same state, tests
+//! the loop’s condition, finishes the loop, and then runs the rest of each enclosing loop body,
+//! the enclosing loop itself, and so on out to the end of the program. Compiling it is how the
+//! JIT enters a hot loop from the interpreter, on-stack replacement style.
+
+use super::{Program, Statement};
+
+/// The continuation of a run paused at bytecode address `pc`, which must be the `JumpZero` or
+/// `JumpNotZero` of a loop, numbered as by
+/// [`bytecode::compile`](../../bytecode/fn.compile.html). Returns `None` if `pc` is not such an
+/// address.
+pub fn continuation(program: &Program, pc: usize) -> Option<Box<Program>> {
+    let mut result = Vec::new();
+    if push_continuation(&mut result, program, 0, pc) {
+        Some(result.into_boxed_slice())
+    } else {
+        None
+    }
+}
+
+/// The number of bytecode instructions a program compiles to.
+pub fn bytecode_len(program: &Program) -> usize {
+    program.iter()
+        .map(|statement| match *statement {
+            Statement::Instr(_) => 1,
+            Statement::Loop(ref body) => bytecode_len(body) + 2,
+        })
+        .sum()
+}
+
+/// Pushes the continuation from `pc` of `program`, which starts at bytecode address `start`,
+/// returning whether `pc` is one of its loops’ jumps.
+fn push_continuation(result: &mut Vec<Statement>, program: &Program, start: usize, pc: usize)
+                     -> bool
+{
+    let mut address = start;
+
+    for (index, statement) in program.iter().enumerate() {
+        let body = match *statement {
+            Statement::Instr(_) => {
+                address += 1;
+                continue;
+            }
+            Statement::Loop(ref body) => body,
+        };
+
+        let end = address + bytecode_len(body) + 1;
+
+        if address < pc && pc < end {
+            if !push_continuation(result, body, address + 1, pc) {
+                return false;
+            }
+        } else if pc != address && pc != end {
+            address = end + 1;
+            continue;
+        }
+
+        result.extend_from_slice(&program[index ..]);
+        return true;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::{self, Execution, StepResult};
+    use common::Instruction;
+    use state::State;
+    use test_helpers::*;
+    use traits::{Interpretable, PeepholeCompilable};
+
+    fn peephole(source: &[u8]) -> Box<Program> {
+        ::ast::parse_program(source).unwrap().peephole_compile()
+    }
+
+    #[test]
+    fn continuations_resume_nested_loops() {
+        use common::Instruction::*;
+        use peephole::Statement::*;
+
+        let program = peephole(b"+[>+[->+<]<-]>.");
+        // 0: Add, 1: JumpZero, 2: Right, 3: Add, 4: OffsetAddRight, 5: Left, 6: Add,
+        // 7: JumpNotZero, 8: Right, 9: Out
+        assert_eq!(bytecode_len(&program), 10);
+        assert_eq!(continuation(&program, 7), Some(program[1 ..].to_vec().into_boxed_slice()));
+        assert_eq!(continuation(&program, 0), None);
+        assert_eq!(continuation(&program, 4), None);
+
+        let program = peephole(b"[>[-<]+]");
+        let body = match program[0] { Loop(ref body) => body.clone(), _ => unreachable!() };
+        assert_eq!(continuation(&program, 2).unwrap().to_vec(),
+                   [&body[1 ..], &program[..]].concat());
+        assert_eq!(&continuation(&program, 2).unwrap()[1], &Instr(Add(1)));
+    }
+
+    #[test]
+    fn continuations_finish_paused_runs() {
+        let program = peephole(FACTOR_SRC);
+        let code = bytecode::compile(&program);
+        let expected = program.interpret_memory(None, b"100\n").unwrap();
+
+        // Pause at every thousandth loop jump, and finish the run with the continuation.
+        let mut jumps = 0;
+        let mut execution = Execution::new(&code, State::new());
+        let mut input = b"100\n".iter().cloned();
+        let mut output = Vec::new();
+
+        loop {
+            let pc = execution.pc();
+            if let Some(&Instruction::JumpNotZero(_)) = code.get(pc) {
+                jumps += 1;
+                if jumps % 1000 == 0 {
+                    let rest = continuation(&program, pc).unwrap();
+                    let rest_input: Vec<u8> = input.clone().collect();
+                    let mut finished = output.clone();
+                    rest.interpret_state(execution.state().clone(), &*rest_input, &mut finished)
+                        .unwrap();
+                    assert_eq!(finished, expected);
+                }
+            }
+
+            match execution.step().unwrap() {
+                StepResult::NeedsInput => execution.provide_input(input.next()),
+                StepResult::Output(byte) => output.push(byte),
+                StepResult::Continue => (),
+                StepResult::Halted => break,
+            }
+        }
+
+        assert_eq!(output, expected);
+        assert!(jumps > 1000);
+    }
+}
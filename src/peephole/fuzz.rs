@@ -0,0 +1,217 @@
+//! Random testing for the peephole [`rules`](../rules/index.html).
+//!
+//! [`check_rule`](fn.check_rule.html) writes out a rule’s pattern as Brainfuck, for random
+//! amounts `N`, and runs the loop both as it is and as the rule rewrites it, from random tape
+//! states. The two must print the same bytes and then halt in the same state or fail with the
+//! same error. Every rule in [`RULES`](../rules/constant.RULES.html) is checked this way by the
+//! tests, and a new rule should be too.
+
+use std::sync::Arc;
+
+use bytecode::{self, Execution, StepResult};
+use common::{BfResult, Count};
+use state::State;
+use traits::{IntoUsize, PeepholeCompilable};
+use super::{Program, Statement};
+use super::rules::{Action, Amount, Pattern, Rule};
+
+/// (`== 12`) The number of cells on the tapes the loops run on.
+pub const TAPE_SIZE: usize = 12;
+
+/// (`== 100_000`) The number of steps the original loop gets before a trial is given up on.
+pub const STEP_BUDGET: usize = 100_000;
+
+/// A trial on which a rule went wrong.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Counterexample {
+    /// The loop the rule was applied to, as Brainfuck.
+    pub source: Vec<u8>,
+    /// The tape the loop started on.
+    pub memory: Vec<u8>,
+    /// Where on the tape the loop started.
+    pub pointer: usize,
+    /// What went wrong.
+    pub reason: &'static str,
+}
+
+/// Checks a rule on `trials` random loops and tapes, drawn from the given seed.
+pub fn check_rule(rule: &Rule, seed: u64, trials: usize) -> Result<(), Counterexample> {
+    let mut rng = Rng::new(seed);
+
+    for _ in 0 .. trials {
+        let n = 1 + rng.below(4) as Count;
+        let memory: Vec<u8> = (0 .. TAPE_SIZE).map(|_| match rng.below(4) {
+            0 | 1 => 0,
+            2 => 1 + rng.below(4) as u8,
+            _ => rng.below(256) as u8,
+        }).collect();
+        let pointer = rng.below(TAPE_SIZE as u64) as usize;
+
+        let mut body_source = Vec::new();
+        render(rule.body, n, &mut body_source);
+        let source = [&b"["[..], &body_source, b"]"].concat();
+        let fail = |reason| Counterexample {
+            source: source.clone(),
+            memory: memory.clone(),
+            pointer,
+            reason,
+        };
+
+        let body = ::ast::parse_program(&body_source)
+            .map_err(|_| fail("the pattern does not parse"))?
+            .peephole_compile();
+        let (instruction, action) = rule.apply(&body)
+            .ok_or_else(|| fail("the rule does not match its own pattern"))?;
+
+        let body: Arc<Program> = body.into();
+        let original = vec![Statement::Loop(body.clone())];
+        let rewritten = match action {
+            Action::Replace => vec![Statement::Instr(instruction)],
+            Action::Prefix => vec![Statement::Instr(instruction), Statement::Loop(body)],
+        };
+
+        let expected = match run(&original, &memory, pointer, STEP_BUDGET) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        let actual = run(&rewritten, &memory, pointer, STEP_BUDGET)
+            .ok_or_else(|| fail("the rewritten loop does not halt"))?;
+
+        match (expected, actual) {
+            ((Ok(expected), out1), (Ok(actual), out2)) => {
+                if out1 != out2 {
+                    return Err(fail("the output differs"));
+                }
+                if expected != actual {
+                    return Err(fail("the final state differs"));
+                }
+            }
+            ((Err(expected), _), (Err(actual), _)) => {
+                if expected != actual {
+                    return Err(fail("the error differs"));
+                }
+            }
+            _ => return Err(fail("one fails and the other does not")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes out patterns as Brainfuck, with `n` for the variable.
+pub fn render(patterns: &[Pattern], n: Count, out: &mut Vec<u8>) {
+    let amount = |amount| match amount {
+        Amount::Exactly(count) => count.into_usize(),
+        Amount::N => n.into_usize(),
+    };
+
+    for pattern in patterns {
+        match *pattern {
+            Pattern::Left(count) => out.extend(vec![b'<'; amount(count)]),
+            Pattern::Right(count) => out.extend(vec![b'>'; amount(count)]),
+            Pattern::Add(255) => out.push(b'-'),
+            Pattern::Add(value) => out.extend(vec![b'+'; value as usize]),
+            Pattern::OffsetAddRight(count) => {
+                out.extend_from_slice(b"[-");
+                out.extend(vec![b'>'; amount(count)]);
+                out.push(b'+');
+                out.extend(vec![b'<'; amount(count)]);
+                out.push(b']');
+            }
+            Pattern::OffsetAddLeft(count) => {
+                out.extend_from_slice(b"[-");
+                out.extend(vec![b'<'; amount(count)]);
+                out.push(b'+');
+                out.extend(vec![b'>'; amount(count)]);
+                out.push(b']');
+            }
+            Pattern::Loop(body) => {
+                out.push(b'[');
+                render(body, n, out);
+                out.push(b']');
+            }
+        }
+    }
+}
+
+/// Runs a program on the given tape for at most `budget` steps, returning its final state or
+/// error and its output, or `None` if it does not halt in time.
+fn run(program: &Program, memory: &[u8], pointer: usize, budget: usize)
+       -> Option<(BfResult<State>, Vec<u8>)>
+{
+    let mut state = State::with_capacity(memory.len());
+    for (index, &value) in memory.iter().enumerate() {
+        state.store(value);
+        if index + 1 < memory.len() {
+            state.right(1usize).expect("the tape fits");
+        }
+    }
+    state.left(memory.len() - 1 - pointer).expect("the pointer is on the tape");
+
+    let code = bytecode::compile(program);
+    let mut execution = Execution::new(&code, state);
+    let mut output = Vec::new();
+
+    for _ in 0 .. budget {
+        match execution.step() {
+            Ok(StepResult::Continue) => (),
+            Ok(StepResult::Output(byte)) => output.push(byte),
+            Ok(StepResult::Halted) => return Some((Ok(execution.into_state()), output)),
+            Ok(StepResult::NeedsInput) => execution.provide_input(None),
+            Err(error) => return Some((Err(error), output)),
+        }
+    }
+
+    None
+}
+
+/// A small xorshift generator, so that trials can be replayed from their seed.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    /// A number in `0 .. bound`.
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction;
+    use peephole::rules::RULES;
+
+    #[test]
+    fn every_rule_survives_fuzzing() {
+        for rule in RULES {
+            for seed in 1 .. 5 {
+                assert_eq!(check_rule(rule, seed, 500), Ok(()), "rule {}", rule.name);
+            }
+        }
+    }
+
+    #[test]
+    fn unsound_rules_are_caught() {
+        // `[-[->+<]]` clears two cells and leaves the pointer where it was.
+        let rule = Rule {
+            name: "bogus",
+            body: &[Pattern::Add(255), Pattern::OffsetAddRight(Amount::N)],
+            canonical: false,
+            action: Action::Replace,
+            instruction: Instruction::FindZeroRight,
+        };
+        assert_eq!(check_rule(&rule, 1, 500).unwrap_err().reason, "the final state differs");
+
+        // `>>` is a single `Right(2)` once compiled.
+        let rule = Rule { body: &[Pattern::Right(Amount::N), Pattern::Right(Amount::N)], ..rule };
+        assert_eq!(check_rule(&rule, 1, 500).unwrap_err().reason,
+                   "the rule does not match its own pattern");
+    }
+}
@@ -1,8 +1,12 @@
 use std::io::{Read, Write};
+use std::sync::Arc;
 
+use bytecode;
 use state::State;
-use common::BfResult;
-use traits::Interpretable;
+use common::{BfResult, Error, Instruction};
+use diagnostics::{Diagnostic, Severity, Span};
+use source_map::SourceMap;
+use traits::{Interpretable, IntoUsize};
 use super::*;
 
 impl Interpretable for Program {
@@ -31,57 +35,179 @@ fn interpret_instruction<R, W>(instructions: &Statement, state: &mut State,
     where R: Read, W: Write
 {
     use super::Statement::*;
-    use common::Instruction::*;
 
     match *instructions {
-        Instr(Left(count)) => state.left(count as usize)?,
+        Instr(instr) => interpret_instr(instr, state, input, output)?,
+
+        Loop(ref body) => {
+            while state.load() != 0 {
+                interpret(body, state, input, output)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Why a run with a loop-iteration cap stopped early.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Stopped {
+    /// The program failed.
+    Error(Error),
+    /// A loop ran more iterations in a row than the cap allows, so probably never terminates.
+    RunawayLoop(RunawayLoop),
+}
+
+/// A loop stopped for running too long, as found by
+/// [`interpret_capped`](fn.interpret_capped.html).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunawayLoop {
+    body: Arc<Program>,
+}
+
+impl RunawayLoop {
+    /// The loop’s number in `program`, counting loops from 0 in the order they begin.
+    pub fn index(&self, program: &Program) -> Option<usize> {
+        fn find(program: &Program, body: &Arc<Program>, count: &mut usize) -> Option<usize> {
+            for statement in program {
+                if let Statement::Loop(ref inner) = *statement {
+                    if Arc::ptr_eq(inner, body) {
+                        return Some(*count);
+                    }
+                    *count += 1;
+                    if let Some(index) = find(inner, body, count) {
+                        return Some(index);
+                    }
+                }
+            }
+            None
+        }
+
+        find(program, &self.body, &mut 0)
+    }
+
+    /// The loop’s span, from `[` to `]`, in the source `program` was compiled from.
+    pub fn span(&self, source: &[u8], program: &Program) -> Option<Span> {
+        let index = self.index(program)?;
+        let map = SourceMap::new(source, program);
+        let code = bytecode::compile(program);
+
+        // Loops compile to jumps in the same order.
+        let begin = code.iter().enumerate()
+            .filter(|&(_, &instruction)| matches!(instruction, Instruction::JumpZero(_)))
+            .nth(index)?;
+        let end = match *begin.1 {
+            Instruction::JumpZero(end) => end.into_usize(),
+            _ => unreachable!(),
+        };
+        Some(Span { start: map.span(begin.0)?.start, end: map.span(end)?.end })
+    }
+
+    /// A diagnostic pointing at the loop, as for grading a submission.
+    pub fn diagnostic(&self, source: &[u8], program: &Program) -> Option<Diagnostic> {
+        Some(Diagnostic {
+            span: self.span(source, program)?,
+            severity: Severity::Error,
+            message: "loop ran past its iteration limit and probably never terminates",
+        })
+    }
+}
+
+/// Interprets a program, stopping any loop that runs more than `max_iterations` times each
+/// time it is entered.
+///
+/// Unlike a budget for the whole run, this catches an infinite loop in a program that
+/// otherwise runs for a long time, and says which loop it is. The cap should be large, so that
+/// loops that are merely long, such as counting to a million, are not stopped.
+pub fn interpret_capped<R, W>(program: &Program, state: &mut State, mut input: R, mut output: W,
+                              max_iterations: u64) -> Result<(), Stopped>
+    where R: Read, W: Write
+{
+    interpret_capped_body(program, state, &mut input, &mut output, max_iterations)
+}
 
-        Instr(Right(count)) => state.right(count as usize)?,
+fn interpret_capped_body<R, W>(instructions: &[Statement], state: &mut State,
+                               input: &mut R, output: &mut W, cap: u64)
+                               -> Result<(), Stopped>
+    where R: Read, W: Write
+{
+    use super::Statement::*;
+
+    for statement in instructions {
+        match *statement {
+            Instr(instr) => interpret_instr(instr, state, input, output).map_err(Stopped::Error)?,
+
+            Loop(ref body) => {
+                let mut iterations = 0;
+                while state.load() != 0 {
+                    if iterations == cap {
+                        return Err(Stopped::RunawayLoop(RunawayLoop { body: body.clone() }));
+                    }
+                    iterations += 1;
+                    interpret_capped_body(body, state, input, output, cap)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Interprets a single non-loop instruction.
+fn interpret_instr<R, W>(instr: Instruction, state: &mut State,
+                         input: &mut R, output: &mut W)
+                         -> BfResult<()>
+    where R: Read, W: Write
+{
+    use common::Instruction::*;
 
-        Instr(Add(amount)) => state.up(amount),
+    match instr {
+        Left(count) => state.left(count)?,
 
-        Instr(In) => state.read(input),
+        Right(count) => state.right(count)?,
 
-        Instr(Out) => state.write(output),
+        Add(amount) => state.up(amount),
 
-        Instr(SetZero) => state.store(0),
+        In => state.read(input),
 
-        Instr(OffsetAddRight(offset)) => {
+        Out => state.write(output),
+
+        SetZero => state.store(0),
+
+        OffsetAddRight(offset) => {
             let value = state.load();
             if value != 0 {
                 state.store(0);
-                state.up_pos_offset(offset as usize, value)?;
+                state.up_pos_offset(offset, value)?;
             }
         }
 
-        Instr(OffsetAddLeft(offset)) => {
+        OffsetAddLeft(offset) => {
             let value = state.load();
             if value != 0 {
                 state.store(0);
-                state.up_neg_offset(offset as usize, value)?;
+                state.up_neg_offset(offset, value)?;
             }
         }
 
-        Instr(FindZeroRight(skip)) => {
+        FindZeroRight(skip) => {
             while state.load() != 0 {
-                state.right(skip as usize)?;
+                state.right(skip)?;
             }
         }
 
-        Instr(FindZeroLeft(skip)) => {
+        FindZeroLeft(skip) => {
             while state.load() != 0 {
-                state.left(skip as usize)?;
+                state.left(skip)?;
             }
         }
 
-        Instr(JumpZero(_)) | Instr(JumpNotZero(_)) =>
-            panic!("unexpected jump instruction"),
+        DivMod => { state.div_mod(); }
+        IndexRight(skip) => { state.index_right(skip); }
+        IndexLeft(skip) => { state.index_left(skip); }
 
-        Loop(ref body) => {
-            while state.load() != 0 {
-                interpret(body, state, input, output)?;
-            }
-        }
+        JumpZero(_) | JumpNotZero(_) =>
+            panic!("unexpected jump instruction"),
     }
 
     Ok(())
@@ -96,6 +222,37 @@ mod tests {
         assert_parse_interpret(HELLO_WORLD_SRC, "", "Hello, World!");
     }
 
+    #[test]
+    fn runaway_loops_are_located() {
+        use common::Error;
+        use diagnostics::Span;
+        use state::State;
+        use super::{interpret_capped, Stopped};
+        use traits::PeepholeCompilable;
+
+        let run = |source: &[u8], cap| {
+            let program = ::ast::parse_program(source).unwrap().peephole_compile();
+            let result = interpret_capped(&program, &mut State::with_capacity(8), &b""[..],
+                                          Vec::new(), cap);
+            (program, result)
+        };
+
+        // The last loop leaves its cell at 3 each time around.
+        let source = b"+++[>+<-]>[>++[-]<]";
+        let (program, result) = run(source, 1_000);
+        let runaway = match result {
+            Err(Stopped::RunawayLoop(runaway)) => runaway,
+            other => panic!("expected a runaway loop, got {:?}", other),
+        };
+        assert_eq!(runaway.index(&program), Some(0));
+        assert_eq!(runaway.span(source, &program), Some(Span { start: 10, end: 19 }));
+        assert_eq!(runaway.diagnostic(source, &program).unwrap().span, Span { start: 10, end: 19 });
+
+        assert_eq!(run(b"+++[>+>+<<-]", 3).1, Ok(()));
+        assert!(matches!(run(b"+++[>+>+<<-]", 2).1, Err(Stopped::RunawayLoop(_))));
+        assert_eq!(run(b"+[>+]", 1_000).1, Err(Stopped::Error(Error::PointerOverflow)));
+    }
+
     #[test]
     fn factoring() {
         assert_parse_interpret(FACTOR_SRC, "2\n", "2: 2\n");
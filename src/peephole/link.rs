@@ -0,0 +1,150 @@
+//! Joining peephole programs from fragments, without going back to source.
+//!
+//! A tool that generates Brainfuck can compile its snippets once and link them as IR. A
+//! [`Linker`](struct.Linker.html) appends fragments one after another, fusing what the peephole
+//! compiler would have fused had they been written together: adjacent moves in the same
+//! direction and adjacent adds, and loops where the current cell is known to be zero, such as
+//! just after another loop, which never run. The result is a plain program, or a
+//! [`ProgramData`](../shared/struct.ProgramData.html) analyzed afresh, since the analysis of
+//! the fragments says nothing about their sum.
+//! [`ProgramData::concat`](../shared/struct.ProgramData.html#method.concat) and
+//! [`ProgramData::repeat`](../shared/struct.ProgramData.html#method.repeat) do the common
+//! cases.
+//!
+//! ```
+//! use bf::ast;
+//! use bf::peephole::link::Linker;
+//! use bf::traits::{Interpretable, PeepholeCompilable};
+//!
+//! let print = ast::parse_program(b".").unwrap().peephole_compile();
+//! let next = ast::parse_program(b"+").unwrap().peephole_compile();
+//! let program = Linker::new().repeat(&next, 65).append(&print).repeat(&next, 2).append(&print)
+//!     .link();
+//! assert_eq!(program.interpret_memory(None, b"").unwrap(), b"AC");
+//! ```
+
+use std::sync::Arc;
+
+use common::Instruction::*;
+use super::{Program, ProgramData, Statement};
+
+/// Builds a program from fragments.
+#[derive(Clone, Debug, Default)]
+pub struct Linker {
+    statements: Vec<Statement>,
+    /// Whether the current cell is known to be zero at the end so far.
+    known_zero: bool,
+}
+
+impl Linker {
+    /// A linker with nothing appended yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a fragment.
+    pub fn append(mut self, fragment: &Program) -> Self {
+        for statement in fragment {
+            self.push(statement.clone());
+        }
+        self
+    }
+
+    /// Appends a fragment `times` times.
+    pub fn repeat(mut self, fragment: &Program, times: usize) -> Self {
+        for _ in 0 .. times {
+            self = self.append(fragment);
+        }
+        self
+    }
+
+    /// Appends one statement, fusing it with the last if it can.
+    ///
+    /// A loop, or an instruction made of one, is dropped where the current cell is known to be
+    /// zero, since it would do nothing.
+    pub fn push(&mut self, statement: Statement) {
+        let (zeroes, skippable) = match statement {
+            Statement::Loop(_) => (true, true),
+            Statement::Instr(instruction) => match instruction {
+                SetZero | FindZeroRight(_) | FindZeroLeft(_) | OffsetAddRight(_)
+                | OffsetAddLeft(_) => (true, true),
+                DivMod | IndexRight(_) | IndexLeft(_) => (false, true),
+                _ => (false, false),
+            },
+        };
+        if skippable && self.known_zero {
+            return;
+        }
+        self.known_zero = zeroes || (self.known_zero && statement == Statement::Instr(Out));
+
+        let fused = match (self.statements.last_mut(), &statement) {
+            (Some(&mut Statement::Instr(Right(ref mut a))), &Statement::Instr(Right(b)))
+            | (Some(&mut Statement::Instr(Left(ref mut a))), &Statement::Instr(Left(b))) => {
+                a.checked_add(b).map(|sum| *a = sum).is_some()
+            }
+            (Some(&mut Statement::Instr(Add(ref mut a))), &Statement::Instr(Add(b))) => {
+                *a = a.wrapping_add(b);
+                true
+            }
+            _ => false,
+        };
+
+        if !fused {
+            self.statements.push(statement);
+        } else if self.statements.last() == Some(&Statement::Instr(Add(0))) {
+            self.statements.pop();
+        }
+    }
+
+    /// The program linked so far.
+    pub fn into_program(self) -> Box<Program> {
+        self.statements.into_boxed_slice()
+    }
+
+    /// The program linked so far, analyzed for sharing.
+    pub fn link(self) -> Arc<ProgramData> {
+        ProgramData::new(self.into_program())
+    }
+}
+
+/// Joins the fragments in order.
+pub fn concat(fragments: &[&Program]) -> Box<Program> {
+    fragments.iter().fold(Linker::new(), |linker, fragment| linker.append(fragment))
+        .into_program()
+}
+
+/// Joins `times` copies of the fragment.
+pub fn repeat(fragment: &Program, times: usize) -> Box<Program> {
+    Linker::new().repeat(fragment, times).into_program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use traits::{Interpretable, PeepholeCompilable};
+
+    fn peephole(source: &[u8]) -> Box<Program> {
+        ast::parse_program(source).unwrap().peephole_compile()
+    }
+
+    #[test]
+    fn seams_are_fused() {
+        assert_eq!(concat(&[&peephole(b">+++"), &peephole(b"++>>"), &peephole(b"<")]),
+                   peephole(b">+++++>><"));
+        assert_eq!(concat(&[&peephole(b"+"), &peephole(b"-.")]), peephole(b"."));
+        assert_eq!(concat(&[&peephole(b",[.,]"), &peephole(b".[.]>")]), peephole(b",[.,].>"));
+        assert_eq!(concat(&[&peephole(b",[->+<]"), &peephole(b"[-]")]), peephole(b",[->+<][-]"));
+        assert_eq!(repeat(&peephole(b">"), 3), peephole(b">>>"));
+        assert!(repeat(&peephole(b">"), 0).is_empty());
+    }
+
+    #[test]
+    fn linked_programs_run_as_their_source() {
+        let (head, body) = (peephole(b",[>+>+<<-]"), peephole(b">[<+>-]<"));
+        let linked = Linker::new().append(&head).repeat(&body, 2).link();
+        let source = ast::parse_program(b",[>+>+<<-]>[<+>-]<>[<+>-]<").unwrap();
+        assert_eq!(linked.interpret_memory(None, b"7"), source.interpret_memory(None, b"7"));
+        assert_eq!(linked.analysis().max_cells(), Some(3));
+    }
+}
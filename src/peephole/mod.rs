@@ -10,16 +10,30 @@
 //! instruction. See the [`common::Instruction`](../common/enum.Instruction.html) enum for a list of
 //! the instructions produced by the [peephole compiler](fn.compile.html).
 
+use std::sync::Arc;
+
 use common;
 
 mod interpreter;
 mod compiler;
+pub mod visit;
+pub mod continuation;
+pub mod offsets;
+pub mod rules;
+pub mod link;
+#[doc(hidden)]
+pub mod fuzz;
+pub mod shared;
 
 pub use self::compiler::{compile, PeepholeCompilable};
+pub use self::interpreter::{interpret_capped, RunawayLoop, Stopped};
+pub use self::shared::ProgramData;
 
 /// At this level, a program is a rose tree of statements.
 ///
-/// All instructions are leaves except for the `Loop` instruction, which contains a boxed `Program`.
+/// All instructions are leaves except for the `Loop` instruction, which contains a shared
+/// `Program`. Loop bodies are reference counted, so copying a statement, as continuations and
+/// the JIT’s deoptimization source do, never copies the loops inside it.
 pub type Program = [Statement];
 
 /// Instructions as output by the peephole optimizer.
@@ -32,5 +46,5 @@ pub enum Statement {
     /// Should not contain a `JumpZero` or `JumpNotZero` instruction.
     Instr(common::Instruction),
     /// A loop.
-    Loop(Box<[Statement]>),
+    Loop(Arc<[Statement]>),
 }
@@ -0,0 +1,198 @@
+//! Pointer-offset tracking for runs of moves and adds.
+//!
+//! A run of `Left`, `Right` and `Add` instructions amounts to adding a constant at each of some
+//! offsets from where it starts, and then moving by its net offset. Rewriting a run in a
+//! canonical order lets the loop peepholes match it however its commands were ordered, so that
+//! `[>+<-]` and `[>-<->++<]` are recognized as well as `[->+<]`.
+
+use std::collections::BTreeMap;
+
+use common::{Count, Instruction};
+use super::Statement;
+use traits::IntoUsize;
+
+/// Rewrites a run of moves and adds so that it adds at its start first, then visits the other
+/// offsets it adds at in order outwards, out to the furthest cell it reaches, and then moves to
+/// its net offset.
+///
+/// Returns `None` if the program is not such a run, or if it reaches both sides of where it
+/// starts: reordering that could change which end of the tape a bad pointer falls off.
+pub fn canonicalize(program: &[Statement]) -> Option<Vec<Statement>> {
+    let mut adds = BTreeMap::new();
+    let mut offset = 0isize;
+    let mut min = 0isize;
+    let mut max = 0isize;
+
+    for statement in program {
+        match *statement {
+            Statement::Instr(Instruction::Right(count)) => offset += count as isize,
+            Statement::Instr(Instruction::Left(count)) => offset -= count as isize,
+            Statement::Instr(Instruction::Add(amount)) => {
+                let total = adds.entry(offset).or_insert(0u8);
+                *total = total.wrapping_add(amount);
+            }
+            _ => return None,
+        }
+
+        min = min.min(offset);
+        max = max.max(offset);
+    }
+
+    if min < 0 && max > 0 {
+        return None;
+    }
+
+    let mut targets: Vec<isize> = adds.keys().cloned().filter(|&target| target != 0).collect();
+    if min < 0 {
+        targets.reverse();
+    }
+    targets.push(if min < 0 { min } else { max });
+    targets.push(offset);
+
+    let mut result = Vec::new();
+    let mut position = 0;
+    push_add(&mut result, adds.remove(&0));
+
+    for target in targets {
+        if target > position {
+            result.push(Statement::Instr(Instruction::Right((target - position) as Count)));
+        } else if target < position {
+            result.push(Statement::Instr(Instruction::Left((position - target) as Count)));
+        }
+        position = target;
+        push_add(&mut result, adds.remove(&target));
+    }
+
+    Some(result)
+}
+
+/// A straight-line block of moves, adds and zeroings, which can update its cells at fixed
+/// offsets from where it starts and then move the pointer once.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    /// The offset from the start at which each statement of the block acts.
+    pub offsets: Vec<isize>,
+    /// The net offset the block moves the pointer.
+    pub delta: isize,
+    /// The furthest offset the block reaches to the left, which is at most 0.
+    pub min: isize,
+    /// The furthest offset the block reaches to the right, which is at least 0.
+    pub max: isize,
+    /// Each offset at which the block first goes further left or right than it had, in order.
+    /// Checking these one at a time fails the way moving step by step would.
+    pub extremes: Vec<isize>,
+}
+
+/// The longest block of `Left`, `Right`, `Add` and `SetZero` at the start of the program, if it
+/// moves the pointer at least twice, since fewer moves would gain nothing from the lowering,
+/// and its reach fits in a `Count`.
+pub fn block(program: &[Statement]) -> Option<Block> {
+    use common::Instruction::*;
+
+    let mut offsets = Vec::new();
+    let mut offset = 0isize;
+    let mut min = 0isize;
+    let mut max = 0isize;
+    let mut extremes = Vec::new();
+    let mut moves = 0;
+
+    for statement in program {
+        offsets.push(offset);
+
+        match *statement {
+            Statement::Instr(Right(count)) => offset += count as isize,
+            Statement::Instr(Left(count)) => offset -= count as isize,
+            Statement::Instr(Add(_)) | Statement::Instr(SetZero) => continue,
+            _ => {
+                offsets.pop();
+                break;
+            }
+        }
+
+        moves += 1;
+        if offset < min || offset > max {
+            extremes.push(offset);
+        }
+        min = min.min(offset);
+        max = max.max(offset);
+    }
+
+    let fits = |n: isize| n.unsigned_abs() <= Count::MAX.into_usize();
+    if moves < 2 || !fits(min) || !fits(max) || !fits(offset) {
+        return None;
+    }
+
+    Some(Block {
+        offsets,
+        delta: offset,
+        min,
+        max,
+        extremes,
+    })
+}
+
+fn push_add(result: &mut Vec<Statement>, amount: Option<u8>) {
+    match amount {
+        Some(0) | None => (),
+        Some(amount) => result.push(Statement::Instr(Instruction::Add(amount))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction::*;
+    use peephole::Statement::*;
+    use traits::PeepholeCompilable;
+
+    fn peephole(source: &[u8]) -> Box<[Statement]> {
+        ::ast::parse_program(source).unwrap().peephole_compile()
+    }
+
+    #[test]
+    fn reordered_transfer_loops_become_offset_adds() {
+        assert_eq!(&*peephole(b"[>+<-]"), &[Instr(OffsetAddRight(1))]);
+        assert_eq!(&*peephole(b"[<<+>>-]"), &[Instr(OffsetAddLeft(2))]);
+        assert_eq!(&*peephole(b"[>-<->++<]"), &[Instr(OffsetAddRight(1))]);
+        assert_eq!(&*peephole(b"[>++<-]"),
+                   &[Loop(vec![Instr(Right(1)), Instr(Add(2)), Instr(Left(1)), Instr(Add(255))]
+                          .into())]);
+    }
+
+    #[test]
+    fn runs_keep_their_reach() {
+        let run = [Instr(Right(3)), Instr(Left(3)), Instr(Add(1)), Instr(Right(1))];
+        assert_eq!(canonicalize(&run),
+                   Some(vec![Instr(Add(1)), Instr(Right(3)), Instr(Left(2))]));
+
+        let run = [Instr(Left(1)), Instr(Add(2)), Instr(Left(2)), Instr(Add(1)), Instr(Right(3))];
+        assert_eq!(canonicalize(&run),
+                   Some(vec![Instr(Left(1)), Instr(Add(2)), Instr(Left(2)), Instr(Add(1)),
+                             Instr(Right(3))]));
+    }
+
+    #[test]
+    fn blocks_record_offsets_and_reach() {
+        let program = [Instr(Right(2)), Instr(Add(1)), Instr(Left(3)), Instr(SetZero),
+                       Instr(Right(2)), Instr(Out), Instr(Right(1))];
+        assert_eq!(block(&program), Some(Block {
+            offsets: vec![0, 2, 2, -1, -1],
+            delta: 1,
+            min: -1,
+            max: 2,
+            extremes: vec![2, -1],
+        }));
+        assert_eq!(block(&program[2 ..]).map(|block| block.extremes), Some(vec![-3]));
+
+        let program = [Instr(Add(1)), Instr(Right(2)), Instr(Left(5)), Instr(Right(9))];
+        assert_eq!(block(&program).map(|block| block.extremes), Some(vec![2, -3, 6]));
+        assert_eq!(block(&program[4 ..]), None);
+    }
+
+    #[test]
+    fn only_one_sided_runs_are_rewritten() {
+        assert_eq!(canonicalize(&[Instr(Right(1)), Instr(Add(1)), Instr(Left(2))]), None);
+        assert_eq!(canonicalize(&[Instr(Add(1)), Instr(Out)]), None);
+        assert_eq!(canonicalize(&[]), Some(vec![]));
+    }
+}
@@ -0,0 +1,228 @@
+//! The peephole rules, as a table of loop-body patterns and the instructions they become.
+//!
+//! Each [`Rule`](struct.Rule.html) gives a pattern that a loop’s (already optimized) body must
+//! match statement for statement, and the instruction to make of a match. Amounts in a pattern
+//! are either exact or the rule’s variable `N`, and every `N` in a pattern must match the same
+//! amount; the instruction is made from that amount. The compiler tries the
+//! [`RULES`](constant.RULES.html) in order and uses the first that matches.
+
+use common::{Count, Instruction};
+use super::{offsets, Statement};
+
+/// An amount in a pattern.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Amount {
+    /// Exactly this amount.
+    Exactly(Count),
+    /// Whatever amount the rule’s variable stands for.
+    N,
+}
+
+/// A pattern for one statement.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pattern {
+    /// `Left` by the amount.
+    Left(Amount),
+    /// `Right` by the amount.
+    Right(Amount),
+    /// `Add` of exactly this value.
+    Add(u8),
+    /// `OffsetAddRight` by the amount.
+    OffsetAddRight(Amount),
+    /// `OffsetAddLeft` by the amount.
+    OffsetAddLeft(Amount),
+    /// A loop whose body matches these patterns.
+    Loop(&'static [Pattern]),
+}
+
+/// What a rule does with a loop it matches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Replace the loop with the instruction.
+    Replace,
+    /// Put the instruction before the loop, which it does the work of when it can.
+    Prefix,
+}
+
+/// A peephole rule.
+#[derive(Clone, Copy)]
+pub struct Rule {
+    /// A name for the rule, for messages.
+    pub name: &'static str,
+    /// The pattern for the loop body.
+    pub body: &'static [Pattern],
+    /// Whether to match the body after putting its moves first, as by
+    /// [`offsets::canonicalize`](../offsets/fn.canonicalize.html).
+    pub canonical: bool,
+    /// What to do with a match.
+    pub action: Action,
+    /// Makes the instruction from the amount `N` matched, or 0 if the pattern has no `N`.
+    pub instruction: fn(Count) -> Instruction,
+}
+
+use self::Amount::*;
+use self::Pattern::*;
+
+/// The rules, in the order they are tried.
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "set zero down",
+        body: &[Add(255)],
+        canonical: false,
+        action: Action::Replace,
+        instruction: |_| Instruction::SetZero,
+    },
+    Rule {
+        name: "set zero up",
+        body: &[Add(1)],
+        canonical: false,
+        action: Action::Replace,
+        instruction: |_| Instruction::SetZero,
+    },
+    Rule {
+        name: "find zero right",
+        body: &[Right(N)],
+        canonical: false,
+        action: Action::Replace,
+        instruction: Instruction::FindZeroRight,
+    },
+    Rule {
+        name: "find zero left",
+        body: &[Left(N)],
+        canonical: false,
+        action: Action::Replace,
+        instruction: Instruction::FindZeroLeft,
+    },
+    Rule {
+        name: "offset add right",
+        body: &[Add(255), Right(N), Add(1), Left(N)],
+        canonical: true,
+        action: Action::Replace,
+        instruction: Instruction::OffsetAddRight,
+    },
+    Rule {
+        name: "offset add left",
+        body: &[Add(255), Left(N), Add(1), Right(N)],
+        canonical: true,
+        action: Action::Replace,
+        instruction: Instruction::OffsetAddLeft,
+    },
+    Rule {
+        name: "divmod",
+        body: &[Add(255), Right(Exactly(1)), Add(255),
+                Loop(&[Right(Exactly(1)), Add(1), Right(Exactly(2))]),
+                Right(Exactly(1)),
+                Loop(&[Add(1), OffsetAddLeft(Exactly(1)), Right(Exactly(1)), Add(1),
+                       Right(Exactly(2))]),
+                Left(Exactly(5))],
+        canonical: false,
+        action: Action::Prefix,
+        instruction: |_| Instruction::DivMod,
+    },
+    Rule {
+        name: "index right, carry first",
+        body: &[OffsetAddRight(N), Right(N), Add(255)],
+        canonical: false,
+        action: Action::Prefix,
+        instruction: Instruction::IndexRight,
+    },
+    Rule {
+        name: "index right, count first",
+        body: &[Add(255), OffsetAddRight(N), Right(N)],
+        canonical: false,
+        action: Action::Prefix,
+        instruction: Instruction::IndexRight,
+    },
+    Rule {
+        name: "index left, carry first",
+        body: &[OffsetAddLeft(N), Left(N), Add(255)],
+        canonical: false,
+        action: Action::Prefix,
+        instruction: Instruction::IndexLeft,
+    },
+    Rule {
+        name: "index left, count first",
+        body: &[Add(255), OffsetAddLeft(N), Left(N)],
+        canonical: false,
+        action: Action::Prefix,
+        instruction: Instruction::IndexLeft,
+    },
+];
+
+impl Rule {
+    /// The instruction and action for a loop with the given body, if the rule matches it.
+    pub fn apply(&self, body: &[Statement]) -> Option<(Instruction, Action)> {
+        let canonical;
+        let body = if self.canonical {
+            canonical = offsets::canonicalize(body)?;
+            &canonical[..]
+        } else {
+            body
+        };
+
+        let mut n = None;
+        if matches(self.body, body, &mut n) {
+            Some(((self.instruction)(n.unwrap_or(0)), self.action))
+        } else {
+            None
+        }
+    }
+}
+
+/// The first rule that matches a loop with the given body, with its instruction and action.
+pub fn rewrite(body: &[Statement]) -> Option<(&'static Rule, Instruction, Action)> {
+    RULES.iter().filter_map(|rule| {
+        rule.apply(body).map(|(instruction, action)| (rule, instruction, action))
+    }).next()
+}
+
+/// Whether the statements match the patterns, binding `N` in `n` as it goes.
+fn matches(patterns: &[Pattern], statements: &[Statement], n: &mut Option<Count>) -> bool {
+    patterns.len() == statements.len()
+        && patterns.iter().zip(statements).all(|(pattern, statement)| {
+            match (*pattern, statement) {
+                (Left(amount), &Statement::Instr(Instruction::Left(count))) |
+                (Right(amount), &Statement::Instr(Instruction::Right(count))) |
+                (OffsetAddRight(amount), &Statement::Instr(Instruction::OffsetAddRight(count))) |
+                (OffsetAddLeft(amount), &Statement::Instr(Instruction::OffsetAddLeft(count))) =>
+                    bind(amount, count, n),
+                (Add(value), &Statement::Instr(Instruction::Add(actual))) => value == actual,
+                (Loop(inner), Statement::Loop(body)) => matches(inner, body, n),
+                _ => false,
+            }
+        })
+}
+
+/// Whether `count` is the amount, binding `N` to it if it is not yet bound.
+fn bind(amount: Amount, count: Count, n: &mut Option<Count>) -> bool {
+    match amount {
+        Exactly(expected) => count == expected,
+        N => *n.get_or_insert(count) == count,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction as I;
+    use peephole::Statement::Instr;
+
+    #[test]
+    fn variables_must_agree() {
+        let rule = RULES.iter().find(|rule| rule.name == "offset add right").unwrap();
+        assert_eq!(rule.apply(&[Instr(I::Add(255)), Instr(I::Right(3)), Instr(I::Add(1)),
+                                Instr(I::Left(3))]),
+                   Some((I::OffsetAddRight(3), Action::Replace)));
+        assert_eq!(rule.apply(&[Instr(I::Add(255)), Instr(I::Right(3)), Instr(I::Add(1)),
+                                Instr(I::Left(2))]),
+                   None);
+    }
+
+    #[test]
+    fn canonical_rules_see_moves_first() {
+        let (rule, instruction, _) = rewrite(&[Instr(I::Right(2)), Instr(I::Add(1)),
+                                               Instr(I::Left(2)), Instr(I::Add(255))]).unwrap();
+        assert_eq!((rule.name, instruction), ("offset add right", I::OffsetAddRight(2)));
+        assert!(rewrite(&[Instr(I::Add(2))]).is_none());
+    }
+}
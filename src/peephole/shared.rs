@@ -0,0 +1,102 @@
+//! Peephole programs built once and shared.
+//!
+//! A [`ProgramData`](struct.ProgramData.html) holds a finished program and the results of
+//! analyzing it behind an `Arc`, so that what is compiled from the program can keep a reference
+//! to it rather than a copy. Speculative JIT code, which falls back to interpreting the program
+//! when it deoptimizes, does this. Loop bodies are shared too, so the continuation of a run
+//! copies only the statements around the point it resumes from, however large the loops are.
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use analysis::ProgramAnalysis;
+use analysis::loop_balance::LoopBalanceMap;
+use super::Program;
+use super::link::{self, Linker};
+
+/// An immutable peephole program, with its analysis.
+#[derive(Debug)]
+pub struct ProgramData {
+    statements: Box<Program>,
+    loop_balances: LoopBalanceMap,
+    analysis: ProgramAnalysis,
+}
+
+impl ProgramData {
+    /// Analyzes the program and puts it behind an `Arc`.
+    pub fn new(statements: Box<Program>) -> Arc<Self> {
+        Arc::new(ProgramData {
+            loop_balances: LoopBalanceMap::new(&statements),
+            analysis: ProgramAnalysis::new(&statements),
+            statements,
+        })
+    }
+
+    /// The program.
+    pub fn statements(&self) -> &Program {
+        &self.statements
+    }
+
+    /// The net movement of each of the program’s loops.
+    pub fn loop_balances(&self) -> &LoopBalanceMap {
+        &self.loop_balances
+    }
+
+    /// How far the program can move the pointer.
+    pub fn analysis(&self) -> ProgramAnalysis {
+        self.analysis
+    }
+
+    /// Links the programs in order and analyzes the result.
+    pub fn concat(parts: &[&ProgramData]) -> Arc<Self> {
+        parts.iter().fold(Linker::new(), |linker, part| linker.append(part)).link()
+    }
+
+    /// Links `times` copies of the program and analyzes the result.
+    pub fn repeat(&self, times: usize) -> Arc<Self> {
+        ProgramData::new(link::repeat(self, times))
+    }
+}
+
+impl Deref for ProgramData {
+    type Target = Program;
+
+    fn deref(&self) -> &Program {
+        &self.statements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use peephole::Statement;
+    use peephole::continuation::continuation;
+    use traits::PeepholeCompilable;
+
+    fn share(source: &[u8]) -> Arc<ProgramData> {
+        ast::parse_program(source).unwrap().peephole_share()
+    }
+
+    #[test]
+    fn continuations_share_loop_bodies() {
+        let data = share(b"+[>+[->+<]<-]>.");
+        let rest = continuation(&data, 7).unwrap();
+        match (&data[1], &rest[0]) {
+            (Statement::Loop(original), Statement::Loop(resumed)) =>
+                assert!(Arc::ptr_eq(original, resumed)),
+            _ => panic!("expected loops"),
+        }
+    }
+
+    #[test]
+    fn analysis_is_kept_with_the_program() {
+        let data = share(b">+[>++<-]");
+        assert_eq!(data.analysis().max_cells(), Some(3));
+        assert!(data.loop_balances().get(0).is_balanced());
+
+        let shared = Arc::clone(&data);
+        assert_eq!(shared.statements(), data.statements());
+        assert_eq!(Arc::strong_count(&data), 2);
+    }
+}
@@ -0,0 +1,129 @@
+//! Visitor and folder traits for the peephole IR.
+//!
+//! Implement [`Visitor`](trait.Visitor.html) to inspect a program, or
+//! [`Folder`](trait.Folder.html) to rebuild one, overriding only the methods for the cases of
+//! interest. The default methods perform the traversal by calling the `walk_*` and `fold_*`
+//! functions of this module, which an overriding method can also call to recurse.
+
+use std::sync::Arc;
+
+use common::Instruction;
+use super::{Program, Statement};
+
+/// A read-only traversal of a peephole program.
+pub trait Visitor {
+    /// Visits a sequence of statements.
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program);
+    }
+
+    /// Visits a single statement.
+    fn visit_statement(&mut self, statement: &Statement) {
+        walk_statement(self, statement);
+    }
+
+    /// Visits a non-loop instruction.
+    fn visit_instr(&mut self, _instr: Instruction) { }
+
+    /// Visits a loop, given its body.
+    fn visit_loop(&mut self, body: &Program) {
+        self.visit_program(body);
+    }
+}
+
+/// Visits each statement of `program` in order.
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    for statement in program {
+        visitor.visit_statement(statement);
+    }
+}
+
+/// Dispatches on the kind of `statement`.
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, statement: &Statement) {
+    match *statement {
+        Statement::Instr(instr) => visitor.visit_instr(instr),
+        Statement::Loop(ref body) => visitor.visit_loop(body),
+    }
+}
+
+/// A rewriting traversal of a peephole program, which consumes the program and builds a new one.
+pub trait Folder {
+    /// Folds a sequence of statements.
+    fn fold_program(&mut self, program: Box<Program>) -> Box<Program> {
+        fold_program(self, program)
+    }
+
+    /// Folds a single statement.
+    fn fold_statement(&mut self, statement: Statement) -> Statement {
+        fold_statement(self, statement)
+    }
+
+    /// Folds a non-loop instruction.
+    fn fold_instr(&mut self, instr: Instruction) -> Statement {
+        Statement::Instr(instr)
+    }
+
+    /// Folds a loop, given its body. The body may be shared, so folding it copies its
+    /// statements, though not the loops inside them.
+    fn fold_loop(&mut self, body: Arc<Program>) -> Statement {
+        Statement::Loop(self.fold_program(body.to_vec().into_boxed_slice()).into())
+    }
+}
+
+/// Folds each statement of `program` in order.
+pub fn fold_program<F: Folder + ?Sized>(folder: &mut F, program: Box<Program>) -> Box<Program> {
+    program.into_vec().into_iter()
+        .map(|statement| folder.fold_statement(statement))
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
+}
+
+/// Dispatches on the kind of `statement`.
+pub fn fold_statement<F: Folder + ?Sized>(folder: &mut F, statement: Statement) -> Statement {
+    match statement {
+        Statement::Instr(instr) => folder.fold_instr(instr),
+        Statement::Loop(body) => folder.fold_loop(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Instruction::*;
+    use traits::PeepholeCompilable;
+
+    fn compile(src: &[u8]) -> Box<Program> {
+        ::ast::parse_program(src).unwrap().peephole_compile()
+    }
+
+    #[test]
+    fn visitor_sees_peephole_instructions() {
+        struct SetZeros(usize);
+
+        impl Visitor for SetZeros {
+            fn visit_instr(&mut self, instr: Instruction) {
+                if instr == SetZero { self.0 += 1; }
+            }
+        }
+
+        let mut count = SetZeros(0);
+        count.visit_program(&compile(b"[-]>[<[+]>,]"));
+        assert_eq!(count.0, 2);
+    }
+
+    #[test]
+    fn folder_can_replace_loops() {
+        struct ClearPrintLoops;
+
+        impl Folder for ClearPrintLoops {
+            fn fold_loop(&mut self, body: Arc<Program>) -> Statement {
+                match *body {
+                    [Statement::Instr(Out)] => Statement::Instr(SetZero),
+                    _ => Statement::Loop(self.fold_program(body.to_vec().into()).into()),
+                }
+            }
+        }
+
+        assert_eq!(ClearPrintLoops.fold_program(compile(b"+[,[.]]")), compile(b"+[,[-]]"));
+    }
+}
@@ -0,0 +1,86 @@
+//! Compile-time evaluation of programs that read no input.
+//!
+//! A program without `,` does the same thing every time it runs from a fresh state, so if it
+//! halts within a step budget its whole run can happen at compile time, leaving a program that
+//! just prints the output. `bfi --precompute` does this before running the selected pass.
+
+use ast;
+use bytecode::{Execution, StepResult};
+use codegen;
+use common::Instruction;
+use state::State;
+use traits::BytecodeCompilable;
+
+/// (`== 10_000_000`) The default step budget, as used by `bfi --precompute`.
+pub const DEFAULT_BUDGET: u64 = 10_000_000;
+
+/// Runs a program that reads no input at compile time, returning what it prints.
+///
+/// The program runs from a fresh state with the given memory size (or the default), counting
+/// one step per bytecode instruction. Returns `None` if the program reads input, fails, or does
+/// not halt within `budget` steps; the program must then be run as usual.
+pub fn evaluate(program: &ast::Program, memory_size: Option<usize>, budget: u64)
+                -> Option<Vec<u8>>
+{
+    let code = program.bytecode_compile();
+    if code.contains(&Instruction::In) {
+        return None;
+    }
+
+    let state = memory_size.map(State::with_capacity).unwrap_or_default();
+    let mut execution = Execution::new(&code, state);
+    let mut output = Vec::new();
+
+    for _ in 0 .. budget {
+        match execution.step() {
+            Ok(StepResult::Continue) => (),
+            Ok(StepResult::Output(byte)) => output.push(byte),
+            Ok(StepResult::Halted) => return Some(output),
+            Ok(StepResult::NeedsInput) | Err(_) => return None,
+        }
+    }
+
+    None
+}
+
+/// Replaces a program that reads no input with one that prints what it would, as found by
+/// [`evaluate`](fn.evaluate.html). The replacement, from
+/// [`codegen::print_string`](../codegen/fn.print_string.html), needs two cells of memory.
+pub fn precompute(program: &ast::Program, memory_size: Option<usize>, budget: u64)
+                  -> Option<Box<ast::Program>>
+{
+    if memory_size.is_some_and(|size| size < 2) {
+        return None;
+    }
+
+    evaluate(program, memory_size, budget).map(|output| codegen::print_string(&output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::Interpretable;
+
+    fn parse(source: &[u8]) -> Box<ast::Program> {
+        ast::parse_program(source).unwrap()
+    }
+
+    #[test]
+    fn hello_world_is_precomputed() {
+        let program = precompute(&parse(HELLO_WORLD_SRC), None, DEFAULT_BUDGET).unwrap();
+        assert_eq!(program.interpret_memory(None, b""), Ok(b"Hello, World!".to_vec()));
+        assert_eq!(program, codegen::print_string(b"Hello, World!"));
+    }
+
+    #[test]
+    fn only_halting_input_free_runs_are_evaluated() {
+        assert_eq!(evaluate(&parse(b"++.>+."), None, 10), Some(vec![2, 1]));
+        assert_eq!(evaluate(&parse(b"++.>+."), None, 3), None);
+        assert_eq!(evaluate(&parse(b",."), None, 10), None);
+        assert_eq!(evaluate(&parse(b"+[]"), None, 1_000), None);
+        assert_eq!(evaluate(&parse(b".<"), None, 10), None);
+        assert_eq!(evaluate(&parse(b">>"), Some(2), 10), None);
+        assert_eq!(precompute(&parse(b"."), Some(1), 10), None);
+    }
+}
@@ -0,0 +1,75 @@
+//! The crate’s stable API, for glob import.
+//!
+//! ```
+//! use bf::prelude::*;
+//!
+//! let program = parse_program(b",[.,]").unwrap().bytecode_compile();
+//! assert_eq!(program.interpret_memory(None, b"hi").unwrap(), b"hi");
+//! ```
+//!
+//! This covers parsing, the optimizing passes and backends (through their `*Compilable`
+//! traits), running programs, and their options and errors. What it exports keeps its
+//! signature between minor versions; the rest of the crate is public for tools and
+//! experiments, and may change, and the modules only the backends use are hidden from
+//! the documentation.
+
+pub use ast::{parse_program, parse_reader};
+pub use common::{BfResult, Error};
+pub use limits::SourceLimits;
+pub use options::{Buffering, CompileOptions, RunOptions};
+pub use sandbox::{Report, Sandbox, Stop};
+pub use state::State;
+pub use traits::{BytecodeCompilable, Interpretable, PeepholeCompilable, RleCompilable,
+                 ThreadedCompilable};
+#[cfg(feature = "jit")]
+pub use traits::JitCompilable;
+#[cfg(feature = "llvm")]
+pub use traits::LlvmCompilable;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use {ast, bytecode, peephole};
+
+    type InterpretMemory = fn(&bytecode::Program, Option<usize>, &[u8]) -> BfResult<Vec<u8>>;
+
+    // Changing any of these signatures breaks users of the prelude.
+    #[test]
+    fn signatures_are_stable() {
+        let _: fn(&[u8]) -> BfResult<Box<ast::Program>> = parse_program;
+        let _: fn(io::Stdin) -> BfResult<Box<ast::Program>> = parse_reader;
+        let _: fn(&ast::Program) -> Box<peephole::Program> =
+            PeepholeCompilable::peephole_compile;
+        let _: fn(&ast::Program) -> Box<bytecode::Program> =
+            BytecodeCompilable::bytecode_compile;
+        let _: InterpretMemory = Interpretable::interpret_memory;
+        let _: fn(&SourceLimits, &[u8]) -> BfResult<Box<peephole::Program>> =
+            SourceLimits::compile;
+        let _: fn(&Sandbox, &[u8], &[u8]) -> BfResult<Report> = Sandbox::run;
+        let _: fn(usize) -> State = State::with_capacity;
+    }
+
+    #[test]
+    fn options_keep_their_defaults() {
+        let compile = CompileOptions::default();
+        assert!(compile.checked && compile.metadata);
+        assert!(!compile.deterministic && !compile.sanitize);
+        assert_eq!(RunOptions::default().memory_size, None);
+        assert!(!RunOptions::default().auto_memory);
+        assert_eq!(SourceLimits::default().max_depth, usize::MAX);
+    }
+
+    #[test]
+    fn backends_agree_through_the_prelude() {
+        let program = parse_program(b",[.,]").unwrap();
+        let input = b"prelude";
+        let expected = program.interpret_memory(None, input).unwrap();
+        assert_eq!(program.rle_compile().interpret_memory(None, input).unwrap(), expected);
+        assert_eq!(program.peephole_compile().interpret_memory(None, input).unwrap(), expected);
+        assert_eq!(program.bytecode_compile().interpret_memory(None, input).unwrap(), expected);
+        assert_eq!(program.threaded_compile().interpret_memory(None, input).unwrap(), expected);
+        assert_eq!(Sandbox::default().run(b",[.,]", input).map(|r| r.output), Ok(expected));
+        assert_eq!(parse_program(b"]"), Err(Error::UnmatchedEnd));
+    }
+}
@@ -82,6 +82,13 @@ impl Compiler {
     }
 }
 
+impl RleCompilable for ast::Program {
+    fn with_ast<F, R>(&self, k: F) -> R
+        where F: FnOnce(&ast::Program) -> R
+    {
+        k(self)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -128,11 +135,3 @@ mod tests {
     }
 }
 
-impl RleCompilable for ast::Program {
-    fn with_ast<F, R>(&self, k: F) -> R
-        where F: FnOnce(&ast::Program) -> R
-    {
-        k(self)
-    }
-}
-
@@ -7,9 +7,40 @@
 //! to that struct to the generated program, and then have the generated program pass the pointer
 //! to that struct to the RTS’s read and write functions.
 //!
+//! Output can be [buffered](struct.RtsState.html#method.set_buffering), so that printing a byte
+//! is not a system call each time, and input
+//! [read in batches](struct.RtsState.html#method.set_input_batch), with `,` served from what was
+//! read ahead. Waiting output is written out whenever the run-time system has to wait for more
+//! input, and when the state is dropped, so an interactive program’s prompts show before it
+//! blocks.
+//!
+//! Code compiled with [accounting](../options/struct.CompileOptions.html#structfield.accounting)
+//! counts its steps and, at its safepoints, [polls](struct.RtsState.html#method.poll) the
+//! run-time system whenever the count reaches the next point a [`Meter`](struct.Meter.html)
+//! asked for. The poll stops runs that are out of fuel, cancelled, or past their deadline, and
+//! calls the progress callback with the [`RunStats`](../run_stats/struct.RunStats.html) so far.
+//! The run-time system counts the bytes read and written itself, as all I/O goes through it.
+//!
+//! The safepoints are the loop jumps and the instructions after I/O, where the code has every
+//! cell in memory. A run stopped at one records a [`Safepoint`](struct.Safepoint.html) giving
+//! the bytecode address to go on from, so that the run can be finished, or its state saved, as
+//! if the bytecode interpreter had stopped there.
+//!
 //! [the `dynlib-rs` tutorial]:(https://censoredusername.github.io/dynasm-rs/language/tutorial.html#advanced-usage)
 
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use adapters::BufferedWriter;
+use bytecode::Control;
+use common::{BfResult, Error};
+use options::{Buffering, RunOptions};
+use run_stats::RunStats;
+use sanitizer::{Access, Sanitizer};
+use state::State;
 
 /// The object code terminated successfully.
 pub const OKAY: u64      = 0;
@@ -20,40 +51,516 @@ pub const UNDERFLOW: u64 = 1;
 /// The pointer would have pointed above the allocated buffer had the program continued.
 pub const OVERFLOW: u64  = 2;
 
+/// A sanitizer check failed; the RTS state’s sanitizer has the details.
+pub const POISONED: u64  = 3;
+
+/// Speculative code bailed out at a safepoint; the RTS state’s [`Deopt`](struct.Deopt.html)
+/// says where, and the caller finishes the run.
+pub const DEOPT: u64     = 4;
+
+/// A poll stopped the run; the RTS state’s [`Safepoint`](struct.Safepoint.html) says where
+/// and why.
+pub const INTERRUPTED: u64 = 5;
+
+/// (`== 4096`) How often, in steps, code polls at the most while its meter has a cancellation
+/// flag or a deadline, so as to notice them.
+pub const SAFEPOINT_INTERVAL: u64 = 4096;
+
+/// Limits on, and a progress callback for, runs of code compiled with
+/// [accounting](../options/struct.CompileOptions.html#structfield.accounting).
+///
+/// Code polls only at its safepoints, so a run can go past its fuel, or a progress point, by
+/// the steps of one loop iteration’s straight-line code.
+#[derive(Default)]
+pub struct Meter<'a> {
+    /// How many steps the run may take, counted as the bytecode interpreter does.
+    pub fuel: Option<u64>,
+    /// How often to call `on_progress`, in steps. Defaults to never.
+    pub every_n_steps: Option<u64>,
+    /// Called with the stats so far, which can stop the run.
+    pub on_progress: Option<&'a mut dyn FnMut(&RunStats) -> Control>,
+    /// Stops the run once set, as another thread can do.
+    pub cancel: Option<&'a AtomicBool>,
+    /// When to stop the run, checked every [`SAFEPOINT_INTERVAL`](constant.SAFEPOINT_INTERVAL.html)
+    /// steps.
+    pub deadline: Option<Instant>,
+}
+
+impl<'a> fmt::Debug for Meter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Meter")
+            .field("fuel", &self.fuel)
+            .field("every_n_steps", &self.every_n_steps)
+            .field("on_progress", &self.on_progress.is_some())
+            .field("cancel", &self.cancel)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+/// Why a poll stopped a run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    /// The run took all of its fuel.
+    OutOfFuel,
+    /// The meter’s cancellation flag was set.
+    Cancelled,
+    /// The meter’s deadline passed.
+    TimedOut,
+    /// The progress callback stopped the run.
+    Stopped,
+}
+
+/// Where a poll stopped a run, which can be finished from there by the bytecode interpreter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Safepoint {
+    /// The bytecode address of the next instruction to run.
+    pub pc: usize,
+    /// The pointer’s position.
+    pub pointer: usize,
+    /// How many steps the run took to get there.
+    pub steps: u64,
+    /// Why the run stopped.
+    pub interrupt: Interrupt,
+}
+
+/// A meter in use.
+struct Metering<'a> {
+    meter: Meter<'a>,
+    /// The step count at which to call `on_progress` next.
+    next_progress: u64,
+    start: Instant,
+}
+
+/// Where speculative code bailed out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Deopt {
+    /// The bytecode address of the loop jump the safepoint is at.
+    pub pc: usize,
+    /// The pointer’s position.
+    pub pointer: usize,
+    /// How many safepoints the code passed, counting this one.
+    pub steps: u64,
+}
+
 /// Minimal state for our minimal run-time system.
 ///
 /// Trait objects providing channels for standard input and output.
 pub struct RtsState<'a> {
     /// Input channel for the `,` operation.
-    input:  &'a mut Read,
+    input:  &'a mut dyn Read,
     /// Output channel for the `.` operation.
-    output: &'a mut Write,
+    output: BufferedWriter<&'a mut dyn Write>,
+    /// Shadow memory for sanitized code.
+    sanitizer: Option<&'a mut Sanitizer>,
+    /// Where speculative code bailed out, if it did.
+    deopt: Option<Deopt>,
+    /// How many bytes of input to read at a time.
+    input_batch: usize,
+    /// Input read ahead, of which `prefetched[next ..]` is yet to be served.
+    prefetched: Vec<u8>,
+    next: usize,
+    /// How many bytes were served to `,` and written by `.`.
+    input_bytes: u64,
+    output_bytes: u64,
+    /// The steps counted by code compiled with accounting, once it returns.
+    steps: Option<u64>,
+    metering: Option<Metering<'a>>,
+    /// Where a poll stopped the run, if one did.
+    stopped_at: Option<Safepoint>,
+    /// The tape that LLVM code stopped with, which the code does not share.
+    stopped_tape: Option<State>,
 }
 
 impl<'a> RtsState<'a> {
     pub fn new<R: Read, W: Write>(input: &'a mut R, output: &'a mut W) -> Self {
-        RtsState { input, output,
+        Self::with_parts(input, output, None)
+    }
+
+    /// Creates a state that services sanitized code’s checks with the given shadow memory.
+    pub fn with_sanitizer<R: Read, W: Write>(input: &'a mut R, output: &'a mut W,
+                                             sanitizer: &'a mut Sanitizer) -> Self {
+        Self::with_parts(input, output, Some(sanitizer))
+    }
+
+    fn with_parts<R: Read, W: Write>(input: &'a mut R, output: &'a mut W,
+                                     sanitizer: Option<&'a mut Sanitizer>) -> Self {
+        RtsState {
+            input,
+            output: BufferedWriter::new(output, Buffering::Unbuffered),
+            sanitizer,
+            deopt: None,
+            input_batch: 1,
+            prefetched: Vec::new(),
+            next: 0,
+            input_bytes: 0,
+            output_bytes: 0,
+            steps: None,
+            metering: None,
+            stopped_at: None,
+            stopped_tape: None,
+        }
+    }
+
+    /// Meters the run, for code compiled with accounting.
+    pub fn set_meter<'m: 'a>(&mut self, meter: Meter<'m>) {
+        // The callback can outlive the state, and the unsizing shortens it to match.
+        let Meter { fuel, every_n_steps, on_progress, cancel, deadline } = meter;
+        let on_progress: Option<&'a mut dyn FnMut(&RunStats) -> Control> = match on_progress {
+            Some(on_progress) => Some(on_progress),
+            None => None,
+        };
+        let meter = Meter { fuel, every_n_steps, on_progress, cancel, deadline };
+        let next_progress = meter.every_n_steps.map_or(u64::MAX, |every| every.max(1));
+        self.metering = Some(Metering { meter, next_progress, start: Instant::now() });
+    }
+
+    /// The step count at which code compiled with accounting first polls.
+    pub fn first_poll(&self) -> u64 {
+        self.metering.as_ref().map_or(u64::MAX, |metering| metering.next_poll(0))
+    }
+
+    /// Records the steps that code compiled with accounting counted.
+    pub fn set_steps(&mut self, steps: u64) {
+        self.steps = Some(steps);
+    }
+
+    /// Where, and why, a poll stopped the run, if one did.
+    pub fn stopped_at(&self) -> Option<Safepoint> {
+        self.stopped_at
+    }
+
+    /// Takes the tape that LLVM code stopped at a safepoint with, positioned at the
+    /// safepoint’s pointer. The JIT’s code runs on the caller’s tape, so has none to take.
+    pub fn take_stopped_tape(&mut self) -> Option<State> {
+        self.stopped_tape.take()
+    }
+
+    /// The stats so far: the input and output bytes, and the steps if they were counted.
+    /// The run time is left for the caller to fill in.
+    pub fn stats(&self) -> RunStats {
+        RunStats {
+            steps: self.steps,
+            input_bytes: self.input_bytes,
+            output_bytes: self.output_bytes,
+            ..RunStats::default()
+        }
+    }
+
+    /// Applies the buffering and input batching of the given run options.
+    pub fn set_run_options(&mut self, options: &RunOptions) {
+        self.set_buffering(options.buffering);
+        self.set_input_batch(options.input_batch);
+    }
+
+    /// Buffers output by the given policy.
+    pub fn set_buffering(&mut self, buffering: Buffering) {
+        let _ = self.output.set_buffering(buffering);
+    }
+
+    /// Reads input `size` bytes at a time, or whatever is available if less, rather than a
+    /// byte at a time. A size of 0 counts as 1.
+    pub fn set_input_batch(&mut self, size: usize) {
+        self.input_batch = size.max(1);
+    }
+
+    /// Takes the input that was read ahead but not yet served, which a run continuing
+    /// elsewhere must read first.
+    pub fn take_unread(&mut self) -> Vec<u8> {
+        let unread = self.prefetched.split_off(self.next);
+        self.prefetched.clear();
+        self.next = 0;
+        unread
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        if let Some(&byte) = self.prefetched.get(self.next) {
+            self.next += 1;
+            self.input_bytes += 1;
+            return byte;
+        }
+
+        if self.output.buffering() != Buffering::Unbuffered {
+            let _ = self.output.flush();
+        }
+
+        if self.input_batch == 1 {
+            let mut buf = [0];
+            if self.input.read_exact(&mut buf).is_ok() {
+                self.input_bytes += 1;
+            }
+            return buf[0];
+        }
+
+        self.prefetched.resize(self.input_batch, 0);
+        let len = loop {
+            match self.input.read(&mut self.prefetched) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result.unwrap_or(0),
+            }
+        };
+        self.prefetched.truncate(len);
+        self.next = 0;
+
+        // At end of input, reads give 0 as with `read_exact`.
+        match self.prefetched.first() {
+            Some(&byte) => {
+                self.next = 1;
+                self.input_bytes += 1;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    /// Interprets a status code returned by object code. `DEOPT` counts as success, since
+    /// the run continues elsewhere.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the code is not one of those defined in this module.
+    pub fn status(&self, code: u64) -> BfResult<()> {
+        match code {
+            OKAY      => Ok(()),
+            DEOPT     => Ok(()),
+            INTERRUPTED => Err(Error::Interrupted),
+            UNDERFLOW => Err(Error::PointerUnderflow),
+            OVERFLOW  => Err(Error::PointerOverflow),
+            POISONED  => {
+                let violation = self.sanitizer.as_ref().and_then(|sanitizer| sanitizer.violation());
+                Err(Error::PoisonedAccess(violation.expect("poisoned without a violation").0))
+            }
+            _ => panic!("unrecognized result code: {}", code),
+        }
+    }
+
+    /// Where speculative code bailed out, if it did.
+    pub fn deopt(&self) -> Option<Deopt> {
+        self.deopt
+    }
+
+    fn check_access(&mut self, offset: i64, write: u8) -> u64 {
+        let access = if write == 0 { Access::Read } else { Access::Write };
+        match self.sanitizer {
+            Some(ref mut sanitizer) => {
+                if sanitizer.check(offset as isize, access) { OKAY } else { POISONED }
+            }
+            None => OKAY,
         }
     }
 
     pub extern "win64" fn read(&mut self) -> u8 {
-        let mut buf = [0];
-        let _ = self.input.read_exact(&mut buf);
-        buf[0]
+        self.read_byte()
     }
 
     pub extern "win64" fn write(&mut self, byte: u8) {
+        self.write_byte(byte);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.output_bytes += 1;
         let _ = self.output.write_all(&[byte]);
     }
 
+    /// Called by code compiled with accounting, at its safepoint before bytecode address `pc`
+    /// with the pointer at `offset` from the start of memory, when its step count reaches the
+    /// point it was told to poll at. Returns the next such point, or 0 to stop the run.
+    pub extern "win64" fn poll(&mut self, steps: u64, pc: u64, offset: u64) -> u64 {
+        self.poll_at(steps, pc, offset)
+    }
+
+    /// Like [`poll`](#method.poll), but with the C calling convention, and given the
+    /// `memory_size` bytes of tape at `memory` to keep if the run stops, as LLVM code’s tape is
+    /// its own.
+    ///
+    /// # Safety
+    ///
+    /// `memory` must point to `memory_size` readable bytes.
+    pub unsafe extern "C" fn poll_c(&mut self, steps: u64, pc: u64, offset: u64,
+                                    memory: *const u8, memory_size: u64) -> u64 {
+        let next = self.poll_at(steps, pc, offset);
+        if next == 0 {
+            let mut tape = State::with_capacity(memory_size as usize);
+            let bytes = slice::from_raw_parts(memory, memory_size as usize);
+            slice::from_raw_parts_mut(tape.as_mut_ptr(), bytes.len()).copy_from_slice(bytes);
+            *tape.parts_mut().1 = offset as usize;
+            self.stopped_tape = Some(tape);
+        }
+        next
+    }
+
+    fn poll_at(&mut self, steps: u64, pc: u64, offset: u64) -> u64 {
+        let stats = RunStats { steps: Some(steps), ..self.stats() };
+        let metering = match self.metering {
+            Some(ref mut metering) => metering,
+            None => return u64::MAX,
+        };
+
+        let interrupt = if metering.meter.fuel.is_some_and(|fuel| steps >= fuel) {
+            Some(Interrupt::OutOfFuel)
+        } else if metering.meter.cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            Some(Interrupt::Cancelled)
+        } else if metering.meter.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(Interrupt::TimedOut)
+        } else if steps >= metering.next_progress {
+            let every = metering.meter.every_n_steps.map_or(u64::MAX, |every| every.max(1));
+            metering.next_progress = (steps / every).saturating_add(1).saturating_mul(every);
+            let stats = RunStats { run_time: metering.start.elapsed(), ..stats };
+            match metering.meter.on_progress {
+                Some(ref mut on_progress) => match on_progress(&stats) {
+                    Control::Stop => Some(Interrupt::Stopped),
+                    Control::Continue => None,
+                },
+                None => None,
+            }
+        } else {
+            None
+        };
+
+        match interrupt {
+            Some(interrupt) => {
+                self.stopped_at = Some(Safepoint { pc: pc as usize, pointer: offset as usize,
+                                                   steps, interrupt });
+                0
+            }
+            None => metering.next_poll(steps),
+        }
+    }
+
+    /// Checks an access by sanitized code at `offset` from the start of memory, writing if
+    /// `write` is non-zero. Returns `OKAY` or `POISONED`.
+    pub extern "win64" fn check(&mut self, offset: i64, write: u8) -> u64 {
+        self.check_access(offset, write)
+    }
+
+    /// Records that speculative code bailed out at the safepoint for bytecode address `pc`,
+    /// with the pointer at `offset` from the start of memory. Returns `DEOPT`.
+    pub extern "win64" fn bail_out(&mut self, pc: u64, offset: u64, steps: u64) -> u64 {
+        self.deopt = Some(Deopt { pc: pc as usize, pointer: offset as usize, steps });
+        DEOPT
+    }
+
     pub extern "C" fn read_c(&mut self) -> u8 {
-        let mut buf = [0];
-        let _ = self.input.read_exact(&mut buf);
-        buf[0]
+        self.read_byte()
     }
 
     pub extern "C" fn write_c(&mut self, byte: u8) {
-        let _ = self.output.write_all(&[byte]);
+        self.write_byte(byte);
+    }
+
+    /// Like [`check`](#method.check), but with the C calling convention.
+    pub extern "C" fn check_c(&mut self, offset: i64, write: u8) -> u64 {
+        self.check_access(offset, write)
     }
 }
 
+impl<'a> Metering<'a> {
+    /// The step count at which to poll next, at `steps` now.
+    fn next_poll(&self, steps: u64) -> u64 {
+        let mut next = self.meter.fuel.map_or(self.next_progress,
+                                              |fuel| fuel.min(self.next_progress));
+        if self.meter.cancel.is_some() || self.meter.deadline.is_some() {
+            next = next.min(steps.saturating_add(SAFEPOINT_INTERVAL));
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffered_output_is_written_out_before_reads() {
+        let mut input: &[u8] = b"x";
+        let mut output = Vec::new();
+        {
+            let mut rts = RtsState::new(&mut input, &mut output);
+            rts.set_buffering(Buffering::Block(100));
+            rts.write_c(b'?');
+            rts.write_c(b' ');
+            assert_eq!(rts.output.buffer_len(), 2);
+            assert_eq!(rts.read_c(), b'x');
+            assert_eq!(rts.output.buffer_len(), 0);
+            rts.write_c(b'!');
+        }
+        assert_eq!(output, b"? !");
+    }
+
+    #[test]
+    fn batched_input_is_served_from_what_was_read_ahead() {
+        let mut input: &[u8] = b"abcde";
+        let mut output = Vec::new();
+        let mut rts = RtsState::new(&mut input, &mut output);
+        rts.set_run_options(&RunOptions {
+            buffering: Buffering::Line,
+            input_batch: 3,
+            ..RunOptions::default()
+        });
+
+        rts.write_c(b'>');
+        assert_eq!(rts.read_c(), b'a');
+        assert_eq!(rts.output.buffer_len(), 0);
+        rts.write_c(b'>');
+        assert_eq!(rts.read_c(), b'b');
+        // Served from the batch, so there was no need to write out the prompt.
+        assert_eq!(rts.output.buffer_len(), 1);
+        assert_eq!(rts.take_unread(), b"c");
+        assert_eq!((rts.read_c(), rts.read_c(), rts.read_c(), rts.read_c()), (b'd', b'e', 0, 0));
+        assert_eq!(rts.stats().input_bytes, 4);
+    }
+
+    #[test]
+    fn polls_stop_at_the_fuel_and_report_progress() {
+        let (mut input, mut output) = (&b""[..], Vec::new());
+        let mut seen = Vec::new();
+        {
+            let mut on_progress = |stats: &RunStats| {
+                seen.push((stats.steps, stats.output_bytes));
+                if stats.steps < Some(250) { Control::Continue } else { Control::Stop }
+            };
+            let mut rts = RtsState::new(&mut input, &mut output);
+            rts.set_meter(Meter { fuel: Some(1_000), every_n_steps: Some(100),
+                                  on_progress: Some(&mut on_progress), ..Meter::default() });
+            assert_eq!(rts.first_poll(), 100);
+            rts.write_c(b'!');
+            assert_eq!(rts.poll(130, 7, 0), 200);
+            assert_eq!(rts.poll(250, 9, 3), 0);
+            assert_eq!(rts.stopped_at(), Some(Safepoint { pc: 9, pointer: 3, steps: 250,
+                                                          interrupt: Interrupt::Stopped }));
+        }
+        assert_eq!(seen, [(Some(130), 1), (Some(250), 1)]);
+
+        let mut rts = RtsState::new(&mut input, &mut output);
+        rts.set_meter(Meter { fuel: Some(50), ..Meter::default() });
+        assert_eq!((rts.first_poll(), rts.poll(49, 2, 0)), (50, 50));
+        assert_eq!(rts.poll(57, 2, 0), 0);
+        assert_eq!(rts.stopped_at().map(|at| at.interrupt), Some(Interrupt::OutOfFuel));
+    }
+
+    #[test]
+    fn cancellation_is_noticed_within_the_interval_and_keeps_the_tape() {
+        let (mut input, mut output) = (&b""[..], Vec::new());
+        let cancel = AtomicBool::new(false);
+        {
+            let mut rts = RtsState::new(&mut input, &mut output);
+            rts.set_meter(Meter { fuel: Some(10_000), cancel: Some(&cancel),
+                                  ..Meter::default() });
+            assert_eq!(rts.first_poll(), SAFEPOINT_INTERVAL);
+            assert_eq!(rts.poll(9_000, 4, 0), 10_000);
+
+            cancel.store(true, Ordering::Relaxed);
+            let memory = [1, 2, 3];
+            assert_eq!(unsafe { rts.poll_c(9_001, 5, 2, memory.as_ptr(), 3) }, 0);
+            assert_eq!(rts.stopped_at().map(|at| (at.pc, at.interrupt)),
+                       Some((5, Interrupt::Cancelled)));
+            let tape = rts.take_stopped_tape().unwrap();
+            assert_eq!((tape.pointer(), tape.load(), tape.capacity()), (2, 3, 3));
+        }
+
+        let mut rts = RtsState::new(&mut input, &mut output);
+        rts.set_meter(Meter { deadline: Some(Instant::now()), ..Meter::default() });
+        assert_eq!(rts.poll(1, 0, 0), 0);
+        assert_eq!(rts.stopped_at().map(|at| at.interrupt), Some(Interrupt::TimedOut));
+    }
+}
@@ -0,0 +1,102 @@
+//! What a run cost, measured the same way whichever backend ran it.
+//!
+//! Every [`Interpretable`](../traits/trait.Interpretable.html) program form can run with
+//! [`interpret_stats`](../traits/trait.Interpretable.html#method.interpret_stats), which gives
+//! [`RunStats`](struct.RunStats.html) alongside the result. All backends count input and
+//! output bytes and time the run; those that step through instructions, such as the bytecode
+//! interpreter, also count steps and follow the pointer. Compile time is the frontend’s to
+//! fill in, as it does the compiling. `bfi --stats` and the playground server report these.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use adapters::Counting;
+
+/// The cost of one run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RunStats {
+    /// How many instructions ran, for backends that count them.
+    pub steps: Option<u64>,
+    /// How many bytes of input were read.
+    pub input_bytes: u64,
+    /// How many bytes were written.
+    pub output_bytes: u64,
+    /// The furthest right the pointer went, for backends that follow it.
+    pub peak_pointer: Option<usize>,
+    /// How long the run took.
+    pub run_time: Duration,
+    /// How long compiling the program took, where known.
+    pub compile_time: Option<Duration>,
+}
+
+/// Runs `run` on counting wrappers of the input and output, timing it, for stats without steps
+/// or the peak pointer.
+pub fn measure<R, W, T, F>(input: R, output: W, run: F) -> (T, RunStats)
+    where R: Read, W: Write, F: FnOnce(&mut Counting<R>, &mut Counting<W>) -> T
+{
+    let mut input = Counting::new(input);
+    let mut output = Counting::new(output);
+    let start = Instant::now();
+    let result = run(&mut input, &mut output);
+    let stats = RunStats {
+        input_bytes: input.count(),
+        output_bytes: output.count(),
+        run_time: start.elapsed(),
+        ..RunStats::default()
+    };
+    (result, stats)
+}
+
+impl fmt::Display for RunStats {
+    /// One line for each statistic that is known.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(steps) = self.steps {
+            writeln!(f, "steps: {}", steps)?;
+        }
+        writeln!(f, "input: {} bytes", self.input_bytes)?;
+        writeln!(f, "output: {} bytes", self.output_bytes)?;
+        if let Some(peak) = self.peak_pointer {
+            writeln!(f, "peak pointer: {}", peak)?;
+        }
+        if let Some(time) = self.compile_time {
+            writeln!(f, "compile time: {:.3} ms", time.as_secs_f64() * 1000.0)?;
+        }
+        writeln!(f, "run time: {:.3} ms", self.run_time.as_secs_f64() * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::*;
+
+    #[test]
+    fn every_backend_counts_the_same_bytes() {
+        let ast = ::ast::parse_program(FACTOR_SRC).unwrap();
+        let mut runs = vec![
+            ast.interpret_stats(Default::default(), &b"12\n"[..], Vec::new()),
+            ast.peephole_compile().interpret_stats(Default::default(), &b"12\n"[..], Vec::new()),
+            (*ast.bytecode_compile()).interpret_stats(Default::default(), &b"12\n"[..],
+                                                      Vec::new()),
+        ];
+        let (result, bytecode) = runs.pop().unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!((bytecode.input_bytes, bytecode.output_bytes), (3, 10));
+        assert!(bytecode.steps.unwrap() > 0);
+        assert_eq!(bytecode.peak_pointer, Some(67));
+
+        for (result, stats) in runs {
+            assert_eq!(result, Ok(()));
+            assert_eq!((stats.input_bytes, stats.output_bytes, stats.steps), (3, 10, None));
+        }
+    }
+
+    #[test]
+    fn only_known_stats_are_shown() {
+        let stats = RunStats { output_bytes: 2, peak_pointer: Some(4), ..RunStats::default() };
+        assert_eq!(stats.to_string(),
+                   "input: 0 bytes\noutput: 2 bytes\npeak pointer: 4\nrun time: 0.000 ms\n");
+    }
+}
@@ -0,0 +1,129 @@
+//! A corpus of classic Brainfuck programs (`--features samples`).
+//!
+//! The programs are embedded in the library, for benchmarks, tests that compare backends, and
+//! examples. Each [`Sample`](struct.Sample.html) comes with an input and the output the program
+//! should produce for it. The `dbfi` sample is a self-interpreter, and
+//! [`self_interpreter_input`](fn.self_interpreter_input.html) makes it run any other sample,
+//! for measuring nested execution.
+
+/// A sample program.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// The name to look the sample up by.
+    pub name: &'static str,
+    /// What the program does.
+    pub description: &'static str,
+    /// The program’s source.
+    pub source: &'static str,
+    /// An input for the program.
+    pub input: &'static [u8],
+    /// The program’s output given `input`.
+    pub output: &'static [u8],
+}
+
+/// All the samples, in order by name.
+pub const SAMPLES: &[Sample] = &[
+    Sample {
+        name: "dbfi",
+        description: "A Brainfuck self-interpreter, by Daniel B. Cristofani, running rot13",
+        source: include_str!("../bf/dbfi.bf"),
+        input: concat!(include_str!("../bf/rot13.bf"), "!Hello, World!\n").as_bytes(),
+        output: b"Uryyb, Jbeyq!\n",
+    },
+    Sample {
+        name: "factor",
+        description: "Factors an integer, by Brian Raiter",
+        source: include_str!("../bf/factor.bf"),
+        input: b"1000000\n",
+        output: b"1000000: 2 2 2 2 2 2 5 5 5 5 5 5\n",
+    },
+    Sample {
+        name: "hello",
+        description: "Prints “Hello, World!”",
+        source: include_str!("../bf/hello.bf"),
+        input: b"",
+        output: b"Hello, World!",
+    },
+    Sample {
+        name: "mandelbrot",
+        description: "Draws the Mandelbrot set, by Erik Bosman",
+        source: include_str!("../bf/mandelbrot.bf"),
+        input: b"",
+        output: include_bytes!("../bf/mandelbrot.out"),
+    },
+    Sample {
+        name: "rot13",
+        description: "Applies ROT13 to its input until end of input",
+        source: include_str!("../bf/rot13.bf"),
+        input: b"Hello, World!\n",
+        output: b"Uryyb, Jbeyq!\n",
+    },
+];
+
+/// The source of the named sample.
+pub fn by_name(name: &str) -> Option<&'static str> {
+    sample(name).map(|sample| sample.source)
+}
+
+/// The named sample.
+pub fn sample(name: &str) -> Option<&'static Sample> {
+    SAMPLES.iter().find(|sample| sample.name == name)
+}
+
+/// The input that makes the `dbfi` self-interpreter run `guest` on the guest’s input: its
+/// source, with any `!` removed, then `!`, then the input.
+pub fn self_interpreter_input(guest: &Sample) -> Vec<u8> {
+    let mut input: Vec<u8> = guest.source.bytes().filter(|&c| c != b'!').collect();
+    input.push(b'!');
+    input.extend_from_slice(guest.input);
+    input
+}
+
+/// The names of all the samples.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    SAMPLES.iter().map(|sample| sample.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::{Interpretable, PeepholeCompilable};
+
+    #[test]
+    fn lookup() {
+        assert_eq!(by_name("hello"), Some(include_str!("../bf/hello.bf")));
+        assert_eq!(by_name("nope"), None);
+        let names: Vec<_> = names().collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+    }
+
+    #[test]
+    fn samples_produce_their_outputs() {
+        // Mandelbrot is too slow for an unoptimized build.
+        for sample in SAMPLES.iter().filter(|sample| sample.name != "mandelbrot") {
+            let program = ::ast::parse_program(sample.source.as_bytes()).unwrap();
+            assert_eq!(program.peephole_compile().interpret_memory(None, sample.input),
+                       Ok(sample.output.to_vec()), "{}", sample.name);
+        }
+    }
+
+    #[test]
+    fn self_interpreter_runs_guests() {
+        let dbfi = ::ast::parse_program(by_name("dbfi").unwrap().as_bytes()).unwrap()
+            .peephole_compile();
+        for name in &["hello", "rot13"] {
+            let guest = sample(name).unwrap();
+            assert_eq!(dbfi.interpret_memory(None, &self_interpreter_input(guest)),
+                       Ok(guest.output.to_vec()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn samples_parse() {
+        for sample in SAMPLES {
+            assert!(::ast::parse_program(sample.source.as_bytes()).is_ok(), "{}", sample.name);
+        }
+    }
+}
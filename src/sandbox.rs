@@ -0,0 +1,246 @@
+//! Running untrusted programs within fixed bounds.
+//!
+//! A [`Sandbox`](struct.Sandbox.html) compiles a program under
+//! [`SourceLimits`](../limits/struct.SourceLimits.html) and runs it in the bytecode interpreter
+//! with a fixed amount of memory, a budget of steps (its fuel), a cap on how much it may
+//! print, and optionally a time limit. Whatever stops the run, the output so far comes back in a
+//! [`Report`](struct.Report.html), so a playground can show it. This is what `bf-server`
+//! runs programs with, reusing tapes from one run to the next. With the `metrics` feature, each
+//! compile and run is [recorded](../telemetry/index.html).
+
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use bytecode::{self, Execution, StepResult};
+use common::{BfResult, Error};
+use limits::SourceLimits;
+use run_stats::RunStats;
+use state::{State, DEFAULT_CAPACITY};
+use telemetry;
+
+/// Bounds on compiling and running a program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sandbox {
+    /// Limits on the program’s source and optimized size.
+    pub limits: SourceLimits,
+    /// The memory size in bytes.
+    pub memory_size: usize,
+    /// How many bytecode instructions the program may run.
+    pub fuel: usize,
+    /// How many bytes the program may print.
+    pub max_output: usize,
+    /// How long the program may run, if there is a limit besides its fuel. The clock is read
+    /// every [`CLOCK_INTERVAL`](constant.CLOCK_INTERVAL.html) steps, so a run may go a little
+    /// over.
+    pub timeout: Option<Duration>,
+}
+
+/// (`== 4096`) How many steps a sandboxed run takes between reading the clock.
+pub const CLOCK_INTERVAL: usize = 4096;
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            limits: SourceLimits {
+                max_source_len: 1 << 20,
+                max_depth: 1_000,
+                max_instructions: 1 << 20,
+            },
+            memory_size: DEFAULT_CAPACITY,
+            fuel: 100_000_000,
+            max_output: 1 << 20,
+            timeout: None,
+        }
+    }
+}
+
+/// Why a sandboxed run stopped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// The program finished.
+    Halted,
+    /// The program failed with a run-time error.
+    Failed(Error),
+    /// The program used up its fuel.
+    OutOfFuel,
+    /// The program tried to print more than it may.
+    OutputFull,
+    /// The program ran past its timeout.
+    TimedOut,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Halted => write!(f, "halted"),
+            Stop::Failed(error) => write!(f, "{}", error),
+            Stop::OutOfFuel => write!(f, "out of fuel"),
+            Stop::OutputFull => write!(f, "output limit reached"),
+            Stop::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// The outcome of a sandboxed run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Report {
+    /// What the program printed before it stopped.
+    pub output: Vec<u8>,
+    /// The number of bytecode instructions the program compiled to.
+    pub instructions: usize,
+    /// The number of steps it ran.
+    pub steps: usize,
+    /// Why it stopped.
+    pub stop: Stop,
+    /// What the run cost, with its compile time once compiled by the sandbox.
+    pub stats: RunStats,
+}
+
+impl Sandbox {
+    /// Compiles and runs the program on the given input. End of input reads as 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the program does not parse or is larger than the limits allow; errors
+    /// while it runs are reported in the `Report`.
+    pub fn run(&self, source: &[u8], input: &[u8]) -> BfResult<Report> {
+        self.run_on(source, input, &mut State::with_capacity(self.memory_size))
+    }
+
+    /// Compiles and runs the program like [`run`](#method.run), on a tape left from an earlier
+    /// run, which is [reset](../state/struct.State.html#method.reset) first, or replaced if it
+    /// is not the sandbox’s memory size.
+    ///
+    /// # Errors
+    ///
+    /// As for `run`.
+    pub fn run_on(&self, source: &[u8], input: &[u8], tape: &mut State) -> BfResult<Report> {
+        let start = Instant::now();
+        let compiled = self.limits.compile(source);
+        telemetry::record_compile("bytecode", start.elapsed(), compiled.is_ok());
+        let program = bytecode::compile(&compiled?);
+        let compile_time = start.elapsed();
+        let mut report = self.run_bytecode_on(&program, input, tape);
+        report.stats.compile_time = Some(compile_time);
+        Ok(report)
+    }
+
+    /// Runs a compiled program on the given input, within the memory, fuel, output and time
+    /// bounds.
+    pub fn run_bytecode(&self, program: &bytecode::Program, input: &[u8]) -> Report {
+        self.run_bytecode_on(program, input, &mut State::with_capacity(self.memory_size))
+    }
+
+    /// Runs a compiled program like [`run_bytecode`](#method.run_bytecode), on a tape left from
+    /// an earlier run, as [`run_on`](#method.run_on) does.
+    pub fn run_bytecode_on(&self, program: &bytecode::Program, input: &[u8], tape: &mut State)
+                           -> Report
+    {
+        if tape.capacity() == self.memory_size {
+            tape.reset();
+        } else {
+            *tape = State::with_capacity(self.memory_size);
+        }
+
+        let mut execution = Execution::new(program, mem::replace(tape, State::with_capacity(0)));
+        let mut input = input.iter().cloned();
+        let available = input.len();
+        let mut output = Vec::new();
+        let mut steps = 0;
+        let mut peak_pointer = execution.state().pointer();
+        let start = Instant::now();
+        let deadline = self.timeout.and_then(|timeout| start.checked_add(timeout));
+
+        let stop = loop {
+            if steps == self.fuel {
+                break Stop::OutOfFuel;
+            }
+            if steps % CLOCK_INTERVAL == 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+                break Stop::TimedOut;
+            }
+            steps += 1;
+
+            match execution.step() {
+                Ok(StepResult::Continue) => (),
+                Ok(StepResult::NeedsInput) => {
+                    execution.provide_input(input.next());
+                    steps -= 1;
+                }
+                Ok(StepResult::Output(_)) if output.len() == self.max_output =>
+                    break Stop::OutputFull,
+                Ok(StepResult::Output(byte)) => output.push(byte),
+                Ok(StepResult::Halted) => {
+                    steps -= 1;
+                    break Stop::Halted;
+                }
+                Err(error) => break Stop::Failed(error),
+            }
+            peak_pointer = peak_pointer.max(execution.state().pointer());
+        };
+
+        let stats = RunStats {
+            steps: Some(steps as u64),
+            input_bytes: (available - input.len()) as u64,
+            output_bytes: output.len() as u64,
+            peak_pointer: Some(peak_pointer),
+            run_time: start.elapsed(),
+            compile_time: None,
+        };
+        telemetry::record_run("bytecode", stop, &stats);
+        *tape = execution.into_state();
+        Report { output, instructions: program.len(), steps, stop, stats }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    #[test]
+    fn runs_finish_within_bounds() {
+        let report = Sandbox::default().run(FACTOR_SRC, b"100\n").unwrap();
+        assert_eq!(report.output, b"100: 2 2 5 5\n");
+        assert_eq!(report.stop, Stop::Halted);
+        assert!(report.steps > 0);
+        assert_eq!((report.stats.steps, report.stats.input_bytes, report.stats.output_bytes),
+                   (Some(report.steps as u64), 4, 13));
+        assert!(report.stats.compile_time.is_some());
+
+        let report = Sandbox::default().run(b",.<", b"").unwrap();
+        assert_eq!((report.output, report.steps), (vec![0], 3));
+        assert_eq!(report.stop, Stop::Failed(Error::PointerUnderflow));
+    }
+
+    #[test]
+    fn runs_stop_at_their_bounds() {
+        let sandbox = Sandbox { fuel: 1_000, max_output: 3, memory_size: 4,
+                                ..Sandbox::default() };
+        assert_eq!(sandbox.run(b"+[]", b"").unwrap().stop, Stop::OutOfFuel);
+        assert_eq!(sandbox.run(b"+[]", b"").unwrap().steps, 1_000);
+
+        let report = sandbox.run(b"+[.]", b"").unwrap();
+        assert_eq!((report.output, report.stop), (vec![1; 3], Stop::OutputFull));
+        assert_eq!(sandbox.run(b">>>>", b"").unwrap().stop,
+                   Stop::Failed(Error::PointerOverflow));
+        assert_eq!(sandbox.run(b"[", b""), Err(Error::UnmatchedBegin));
+
+        let sandbox = Sandbox { timeout: Some(Duration::from_millis(10)), ..Sandbox::default() };
+        let report = sandbox.run(b"+[]", b"").unwrap();
+        assert_eq!(report.stop, Stop::TimedOut);
+        assert_eq!(report.steps % CLOCK_INTERVAL, 0);
+    }
+
+    #[test]
+    fn tapes_are_reused_from_a_clean_start() {
+        let sandbox = Sandbox { memory_size: 8, ..Sandbox::default() };
+        let mut tape = State::with_capacity(3);
+        let report = sandbox.run_on(b">>+++.>+", b"", &mut tape).unwrap();
+        assert_eq!((report.output, tape.pointer(), tape.capacity()), (vec![3], 3, 8));
+
+        let report = sandbox.run_on(b">>.<<.", b"", &mut tape).unwrap();
+        assert_eq!((report.output, report.stop), (vec![0, 0], Stop::Halted));
+        assert_eq!(tape, State::with_capacity(8));
+    }
+}
@@ -0,0 +1,118 @@
+//! Shadow memory for the native backends’ sanitizer mode.
+//!
+//! Code compiled with [`CompileOptions::sanitize`](../options/struct.CompileOptions.html)
+//! asks a `Sanitizer` for permission before each tape access. Every cell outside the tape is
+//! poisoned, so an access that would have strayed into the surrounding memory—as unchecked
+//! or miscompiled code can—stops the program with
+//! [`Error::PoisonedAccess`](../common/enum.Error.html) instead. Reads of cells that have never
+//! been written are allowed, since Brainfuck memory starts zeroed, but each such cell is
+//! recorded the first time it happens.
+
+/// Whether an access reads or writes the tape.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// The cell’s value is examined.
+    Read,
+    /// The cell is assigned or modified in place.
+    Write,
+}
+
+/// The shadow state for one cell of the tape.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Shadow {
+    /// Neither written nor read.
+    Fresh,
+    /// Read before being written, which has been recorded.
+    ReadFresh,
+    /// Written at least once.
+    Written,
+}
+
+/// Shadow memory tracking one tape.
+#[derive(Clone, Debug)]
+pub struct Sanitizer {
+    shadow:              Vec<Shadow>,
+    uninitialized_reads: Vec<usize>,
+    violation:           Option<(isize, Access)>,
+}
+
+impl Sanitizer {
+    /// Creates shadow memory for a tape of the given size.
+    pub fn new(memory_size: usize) -> Self {
+        Sanitizer {
+            shadow:              vec![Shadow::Fresh; memory_size],
+            uninitialized_reads: Vec::new(),
+            violation:           None,
+        }
+    }
+
+    /// Checks an access at the given offset from the start of the tape, returning whether the
+    /// access may go ahead.
+    ///
+    /// A refused access is remembered as [the violation](#method.violation).
+    pub fn check(&mut self, offset: isize, access: Access) -> bool {
+        let shadow = if offset < 0 { None } else { self.shadow.get_mut(offset as usize) };
+
+        match (shadow, access) {
+            (None, _) => {
+                self.violation = Some((offset, access));
+                false
+            }
+
+            (Some(shadow), Access::Read) => {
+                if *shadow == Shadow::Fresh {
+                    *shadow = Shadow::ReadFresh;
+                    self.uninitialized_reads.push(offset as usize);
+                }
+                true
+            }
+
+            (Some(shadow), Access::Write) => {
+                *shadow = Shadow::Written;
+                true
+            }
+        }
+    }
+
+    /// The cells that were read before ever being written, in the order first read.
+    pub fn uninitialized_reads(&self) -> &[usize] {
+        &self.uninitialized_reads
+    }
+
+    /// The poisoned access that stopped the program, if any.
+    pub fn violation(&self) -> Option<(isize, Access)> {
+        self.violation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outside_is_poisoned() {
+        let mut sanitizer = Sanitizer::new(4);
+        assert!(sanitizer.check(0, Access::Write));
+        assert!(sanitizer.check(3, Access::Read));
+        assert_eq!(sanitizer.violation(), None);
+
+        assert!(!sanitizer.check(4, Access::Read));
+        assert_eq!(sanitizer.violation(), Some((4, Access::Read)));
+
+        assert!(!sanitizer.check(-1, Access::Write));
+        assert_eq!(sanitizer.violation(), Some((-1, Access::Write)));
+    }
+
+    #[test]
+    fn uninitialized_reads_are_recorded_once() {
+        let mut sanitizer = Sanitizer::new(4);
+        sanitizer.check(1, Access::Write);
+        sanitizer.check(1, Access::Read);
+        sanitizer.check(2, Access::Read);
+        sanitizer.check(0, Access::Read);
+        sanitizer.check(2, Access::Read);
+        sanitizer.check(0, Access::Write);
+        sanitizer.check(0, Access::Read);
+        assert_eq!(sanitizer.uninitialized_reads(), &[2, 0]);
+    }
+}
@@ -0,0 +1,261 @@
+//! Small scripts that run when a [breakpoint](../breakpoint/index.html) stops a run.
+//!
+//! A [`Script`](struct.Script.html) attached to a breakpoint with
+//! [`Breakpoint::then`](../breakpoint/struct.Breakpoint.html#method.then) runs on the worker
+//! thread each time the breakpoint stops the run, so an automated bug hunt through a large
+//! generated program can log what it finds, patch memory and carry on, without a round trip
+//! through the frontend per hit. A script is commands one per line or separated by `;`, with
+//! `#` starting a comment:
+//!
+//!  - `print EXPR, …` prints each expression and its value, as `cell[0] = 5, ptr = 3`;
+//!  - `dump FROM, TO` prints the cells from `FROM` up to `TO`;
+//!  - `set cell[EXPR] = EXPR` stores a value, modulo 256, and `set ptr = EXPR` moves the
+//!    pointer;
+//!  - `resume` lets the run go on rather than staying paused, and `stop` ends it; either ends
+//!    the script; and
+//!  - `if EXPR COMMAND` runs the command only when the expression is not 0.
+//!
+//! Expressions are those of [breakpoint conditions](../breakpoint/index.html), with `hits`
+//! the breakpoint’s hit count.
+//!
+//! ```
+//! use bf::script::{Action, Script};
+//! use bf::state::State;
+//!
+//! let script: Script = "set cell[0] = cell[0] + 1; print cell[0]; if hits > 1 stop".parse()
+//!     .unwrap();
+//! let mut state = State::new();
+//! let mut output = Vec::new();
+//! assert_eq!(script.run(&mut state, 1, &mut output), Ok(Action::Pause));
+//! assert_eq!(script.run(&mut state, 2, &mut output), Ok(Action::Stop));
+//! assert_eq!(output, ["cell[0] = 1", "cell[0] = 2"]);
+//! ```
+
+use std::fmt;
+use std::num::Wrapping;
+use std::str::FromStr;
+
+use breakpoint::{ConditionError, Expr, Parser};
+use state::State;
+
+/// Commands to run when a breakpoint stops a run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Script {
+    /// Each command, with the line it came from.
+    commands: Vec<(usize, Statement)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Statement {
+    /// Each expression with its text.
+    Print(Vec<(String, Expr)>),
+    Dump(Expr, Expr),
+    SetCell(Expr, Expr),
+    SetPointer(Expr),
+    Resume,
+    Stop,
+    If(Expr, Box<Statement>),
+}
+
+/// What a run does once its breakpoint’s script is done.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// It stays paused, as with no script.
+    Pause,
+    /// It goes on.
+    Resume,
+    /// It ends.
+    Stop,
+}
+
+/// An error parsing or running a script.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScriptError {
+    /// The (1-based) line where the error was detected.
+    pub line: usize,
+    /// The byte offset in the line, or 0 for an error running it.
+    pub offset: usize,
+    /// What went wrong.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.offset + 1, self.message)
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, ScriptError> {
+        let mut commands = Vec::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let mut start = 0;
+            for command in code.split(';') {
+                let mut parser = Parser::new(command);
+                let error = |e: ConditionError| {
+                    ScriptError { line: index + 1, offset: start + e.offset, message: e.message }
+                };
+                if !command.trim().is_empty() {
+                    let statement = statement(&mut parser).map_err(error)?;
+                    parser.end().map_err(error)?;
+                    commands.push((index + 1, statement));
+                }
+                start += command.len() + 1;
+            }
+        }
+
+        Ok(Script { commands })
+    }
+}
+
+fn statement(parser: &mut Parser) -> Result<Statement, ConditionError> {
+    let word = parser.word();
+    let start = parser.position() - word.len();
+    match word {
+        "print" => {
+            let mut exprs = Vec::new();
+            loop {
+                let from = parser.position();
+                let expr = parser.expression()?;
+                exprs.push((parser.since(from).trim().to_owned(), expr));
+                if !parser.eat(",") {
+                    return Ok(Statement::Print(exprs));
+                }
+            }
+        }
+        "dump" => {
+            let from = parser.expression()?;
+            parser.expect(",")?;
+            Ok(Statement::Dump(from, parser.expression()?))
+        }
+        "set" => {
+            let place = parser.expression()?;
+            parser.expect("=")?;
+            let value = parser.expression()?;
+            match place {
+                Expr::Cell(index) => Ok(Statement::SetCell(*index, value)),
+                Expr::Pointer => Ok(Statement::SetPointer(value)),
+                _ => Err(ConditionError { offset: start, message: "can only set a cell or ptr" }),
+            }
+        }
+        "resume" => Ok(Statement::Resume),
+        "stop" => Ok(Statement::Stop),
+        "if" => {
+            let condition = parser.expression()?;
+            Ok(Statement::If(condition, Box::new(statement(parser)?)))
+        }
+        _ => Err(ConditionError {
+            offset: start,
+            message: "expected ‘print’, ‘dump’, ‘set’, ‘resume’, ‘stop’ or ‘if’",
+        }),
+    }
+}
+
+impl Script {
+    /// Runs the script against the state, with the breakpoint hit `hits` times, adding a line
+    /// to `output` for each thing printed.
+    ///
+    /// # Errors
+    ///
+    /// Fails, having run the commands before, if `set ptr` would move the pointer off the tape.
+    pub fn run(&self, state: &mut State, hits: u64, output: &mut Vec<String>)
+               -> Result<Action, ScriptError>
+    {
+        for &(line, ref statement) in &self.commands {
+            let error = |message| ScriptError { line, offset: 0, message };
+            if let Some(action) = execute(statement, state, hits, output).map_err(error)? {
+                return Ok(action);
+            }
+        }
+        Ok(Action::Pause)
+    }
+}
+
+fn execute(statement: &Statement, state: &mut State, hits: u64, output: &mut Vec<String>)
+           -> Result<Option<Action>, &'static str>
+{
+    match *statement {
+        Statement::Print(ref exprs) => {
+            let values: Vec<String> = exprs.iter()
+                .map(|(text, expr)| format!("{} = {}", text, expr.eval(state, hits)))
+                .collect();
+            output.push(values.join(", "));
+        }
+        Statement::Dump(ref from, ref to) => {
+            let memory = state.memory();
+            let from = (from.eval(state, hits).max(0) as usize).min(memory.len());
+            let to = (to.eval(state, hits).max(0) as usize).clamp(from, memory.len());
+            let cells: Vec<String> = memory[from .. to].iter().map(|c| c.0.to_string()).collect();
+            output.push(format!("cell[{}..{}] = {}", from, to, cells.join(" ")));
+        }
+        Statement::SetCell(ref index, ref value) => {
+            let index = index.eval(state, hits);
+            let value = value.eval(state, hits);
+            let (memory, _) = state.parts_mut();
+            if index >= 0 {
+                if let Some(cell) = memory.get_mut(index as usize) {
+                    *cell = Wrapping(value as u8);
+                }
+            }
+        }
+        Statement::SetPointer(ref value) => {
+            let value = value.eval(state, hits);
+            let (memory, pointer) = state.parts_mut();
+            if value < 0 || value as usize >= memory.len() {
+                return Err("ptr moved off the tape");
+            }
+            *pointer = value as usize;
+        }
+        Statement::Resume => return Ok(Some(Action::Resume)),
+        Statement::Stop => return Ok(Some(Action::Stop)),
+        Statement::If(ref condition, ref statement) => {
+            if condition.eval(state, hits) != 0 {
+                return execute(statement, state, hits, output);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str, state: &mut State, hits: u64) -> (Result<Action, ScriptError>, String) {
+        let mut output = Vec::new();
+        let result = script.parse::<Script>().unwrap().run(state, hits, &mut output);
+        (result, output.join("\n"))
+    }
+
+    #[test]
+    fn scripts_print_and_patch_memory() {
+        let mut state = State::with_capacity(8);
+        let script = "set cell[2] = 300   # wraps to 44\n\
+                      set ptr = 2; print ptr, cell[ptr] - 4\n\
+                      dump 1, 4; dump 6, 100";
+        let printed = "ptr = 2, cell[ptr] - 4 = 40\ncell[1..4] = 0 44 0\ncell[6..8] = 0 0";
+        assert_eq!(run(script, &mut state, 1), (Ok(Action::Pause), printed.to_owned()));
+        assert_eq!(state.load(), 44);
+
+        assert_eq!(run("if hits < 3 resume; stop", &mut state, 2).0, Ok(Action::Resume));
+        assert_eq!(run("if hits < 3 resume; stop", &mut state, 3).0, Ok(Action::Stop));
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let error = |script: &str| script.parse::<Script>().unwrap_err().to_string();
+        assert_eq!(error("print 1\nset 3 = 4"), "line 2, column 1: can only set a cell or ptr");
+        assert_eq!(error("resume; jump 4"),
+                   "line 1, column 9: expected ‘print’, ‘dump’, ‘set’, ‘resume’, ‘stop’ or ‘if’");
+        assert_eq!(error("dump 1 2"), "line 1, column 8: expected ‘,’");
+
+        let mut state = State::with_capacity(4);
+        assert_eq!(run("print 1\nset ptr = 4", &mut state, 1),
+                   (Err(ScriptError { line: 2, offset: 0, message: "ptr moved off the tape" }),
+                    "1 = 1".to_owned()));
+    }
+}
@@ -0,0 +1,173 @@
+//! A portable binary format for machine states.
+//!
+//! A snapshot of a [`State`](../state/struct.State.html) reads back the same on any machine,
+//! whatever its word size or byte order, so runs can be saved on one and resumed or replayed on
+//! another. It is laid out as:
+//!
+//!  - the magic bytes `\0bfs`;
+//!  - the format version, a little-endian `u16`;
+//!  - the width of a cell in bytes, which is always 1 for now;
+//!  - the pointer, a little-endian `u64`;
+//!  - the number of cells, a little-endian `u64`;
+//!  - the cells, each in little-endian order.
+
+use std::fmt;
+use std::num::Wrapping;
+
+use state::State;
+
+/// The magic bytes that start every snapshot.
+pub const MAGIC: [u8; 4] = *b"\0bfs";
+
+/// The version of the format that [`write`](fn.write.html) uses.
+pub const VERSION: u16 = 1;
+
+/// The width of a cell in bytes.
+pub const CELL_WIDTH: u8 = 1;
+
+/// The length of the header before the cells.
+const HEADER_LEN: usize = 4 + 2 + 1 + 8 + 8;
+
+/// An error loading a snapshot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnapshotError {
+    /// The data does not start with the magic bytes.
+    NotSnapshot,
+    /// The snapshot was written with a version of the format this crate does not know.
+    UnsupportedVersion(u16),
+    /// The snapshot’s cells are wider than this crate’s.
+    UnsupportedCellWidth(u8),
+    /// The snapshot is longer or shorter than its header says.
+    BadLength,
+    /// The pointer is outside the memory.
+    BadPointer(u64),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SnapshotError::*;
+
+        match *self {
+            NotSnapshot => write!(f, "not a state snapshot"),
+            UnsupportedVersion(version) =>
+                write!(f, "unsupported snapshot version {} (the latest is {})", version, VERSION),
+            UnsupportedCellWidth(width) =>
+                write!(f, "unsupported cell width of {} bytes", width),
+            BadLength => write!(f, "snapshot length does not match its header"),
+            BadPointer(pointer) => write!(f, "snapshot pointer {} is outside its memory", pointer),
+        }
+    }
+}
+
+/// The result of loading a snapshot.
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+
+/// Serializes a state.
+pub fn write(state: &State) -> Vec<u8> {
+    let memory = state.memory();
+
+    let mut result = Vec::with_capacity(HEADER_LEN + memory.len());
+    result.extend_from_slice(&MAGIC);
+    result.extend_from_slice(&u16_bytes(VERSION));
+    result.push(CELL_WIDTH);
+    result.extend_from_slice(&u64_bytes(state.pointer() as u64));
+    result.extend_from_slice(&u64_bytes(memory.len() as u64));
+    result.extend(memory.iter().map(|cell| cell.0));
+    result
+}
+
+/// Loads a state from a snapshot.
+pub fn read(bytes: &[u8]) -> SnapshotResult<State> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err(SnapshotError::NotSnapshot);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(SnapshotError::BadLength);
+    }
+
+    let version = u16::from(bytes[4]) | u16::from(bytes[5]) << 8;
+    if version == 0 || version > VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    if bytes[6] != CELL_WIDTH {
+        return Err(SnapshotError::UnsupportedCellWidth(bytes[6]));
+    }
+
+    let pointer = read_u64(&bytes[7 .. 15]);
+    let len = read_u64(&bytes[15 .. HEADER_LEN]);
+    let cells = &bytes[HEADER_LEN ..];
+    if len != cells.len() as u64 {
+        return Err(SnapshotError::BadLength);
+    }
+    if pointer >= len && pointer != 0 {
+        return Err(SnapshotError::BadPointer(pointer));
+    }
+
+    let memory = cells.iter().map(|&cell| Wrapping(cell)).collect::<Vec<_>>();
+    Ok(State::from_parts(memory.into_boxed_slice(), pointer as usize))
+}
+
+fn u16_bytes(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn u64_bytes(value: u64) -> [u8; 8] {
+    let mut result = [0; 8];
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+    result
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> State {
+        let mut state = State::with_capacity(300);
+        state.right(258usize).unwrap();
+        state.store(0xab);
+        state
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let state = sample();
+        assert_eq!(read(&write(&state)), Ok(state));
+        assert_eq!(read(&write(&State::with_capacity(0))), Ok(State::with_capacity(0)));
+        assert_eq!(read(&write(&State::new())), Ok(State::new()));
+    }
+
+    // The exact bytes, so that the layout cannot change with the host.
+    #[test]
+    fn layout_is_fixed() {
+        let bytes = write(&sample());
+        assert_eq!(&bytes[.. HEADER_LEN],
+                   &[0, b'b', b'f', b's', 1, 0, 1,
+                     2, 1, 0, 0, 0, 0, 0, 0,
+                     44, 1, 0, 0, 0, 0, 0, 0][..]);
+        assert_eq!(bytes.len(), HEADER_LEN + 300);
+        assert_eq!(bytes[HEADER_LEN + 258], 0xab);
+    }
+
+    #[test]
+    fn damaged_snapshots_are_refused() {
+        let bytes = write(&sample());
+        let with = |index: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[index] = byte;
+            read(&bytes)
+        };
+
+        assert_eq!(read(b"\0bfc\x03\0"), Err(SnapshotError::NotSnapshot));
+        assert_eq!(with(4, 2), Err(SnapshotError::UnsupportedVersion(2)));
+        assert_eq!(with(6, 4), Err(SnapshotError::UnsupportedCellWidth(4)));
+        assert_eq!(read(&bytes[.. bytes.len() - 1]), Err(SnapshotError::BadLength));
+        assert_eq!(read(&bytes[.. 10]), Err(SnapshotError::BadLength));
+        assert_eq!(with(8, 2), Err(SnapshotError::BadPointer(514)));
+    }
+}
@@ -0,0 +1,297 @@
+//! Maps compiled programs back to their source.
+//!
+//! The optimizer fuses runs of commands, and whole loops, into single instructions, so an
+//! instruction rarely corresponds to one character of source. A [`SourceMap`](struct.SourceMap.html)
+//! records, for each [bytecode](../bytecode/index.html) instruction, the span of source it was
+//! compiled from:
+//!
+//!  - a run-length encoded move or add covers its whole run, comments included;
+//!  - a loop replaced by a single instruction, such as `SetZero`, covers the loop from `[` to
+//!    `]`; and
+//!  - the jumps of a loop that was not replaced cover its brackets, and a `DivMod` or index
+//!    instruction put before such a loop covers the whole loop.
+//!
+//! The JIT numbers its code the same way, so a code offset found with
+//! `jit::Program::pc_at` can be looked up here too. The text form of a map, one
+//! `pc start end` line per instruction, is what `bfi --source-map` writes.
+
+use std::fmt;
+
+use ast;
+use bytecode;
+use common::{BfResult, Count};
+use diagnostics::{matching_bracket, Span};
+use peephole;
+use text::{ParseError, ParseResult};
+use traits::{IntoUsize, PeepholeCompilable};
+
+/// The source span of each bytecode instruction, indexed by address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMap {
+    spans: Box<[Span]>,
+}
+
+impl SourceMap {
+    /// Maps the bytecode for `program`, which must be the peephole-optimized form of `source`.
+    pub fn new(source: &[u8], program: &peephole::Program) -> Self {
+        let mut builder = Builder { source, position: 0, spans: Vec::new(), known_zero: false };
+        builder.statements(program);
+        SourceMap { spans: builder.spans.into_boxed_slice() }
+    }
+
+    /// The span of the instruction at the given address.
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        self.spans.get(pc).cloned()
+    }
+
+    /// The spans of all the instructions, in order by address.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The number of instructions mapped.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// The address of the first instruction compiled from the source byte at `offset`, as for
+    /// setting a breakpoint there.
+    pub fn pc_at(&self, offset: usize) -> Option<usize> {
+        self.spans.iter().position(|span| span.start <= offset && offset < span.end)
+    }
+}
+
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pc, span) in self.spans.iter().enumerate() {
+            writeln!(f, "{} {} {}", pc, span.start, span.end)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses and compiles source to bytecode, along with its source map.
+///
+/// # Errors
+///
+/// Unmatched square brackets will result in an `Err` return, as for
+/// [`ast::parse_program`](../ast/fn.parse_program.html).
+pub fn compile(source: &[u8]) -> BfResult<(Box<bytecode::Program>, SourceMap)> {
+    let program = ast::parse_program(source)?.peephole_compile();
+    let map = SourceMap::new(source, &program);
+    Ok((bytecode::compile(&program), map))
+}
+
+/// Parses the text form of a source map.
+pub fn parse(input: &str) -> ParseResult<SourceMap> {
+    let mut spans = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.is_empty() { continue; }
+
+        let number = |i: usize| fields.get(i).and_then(|field| field.parse::<usize>().ok())
+            .ok_or(ParseError { line: line_number, message: "expected a number" });
+
+        if fields.len() != 3 {
+            return Err(ParseError { line: line_number, message: "expected ‘pc start end’" });
+        }
+        if number(0)? != spans.len() {
+            return Err(ParseError { line: line_number, message: "addresses out of order" });
+        }
+
+        let span = Span { start: number(1)?, end: number(2)? };
+        if span.end < span.start {
+            return Err(ParseError { line: line_number, message: "span ends before it starts" });
+        }
+        spans.push(span);
+    }
+
+    Ok(SourceMap { spans: spans.into_boxed_slice() })
+}
+
+/// The (1-based) line and column of the given byte offset, counting columns in characters.
+pub fn line_column(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[.. offset.min(source.len())];
+    let line_start = before.iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+    let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+    // Count everything but UTF-8 continuation bytes.
+    let column = before[line_start ..].iter().filter(|&&c| c & 0xC0 != 0x80).count() + 1;
+    (line, column)
+}
+
+struct Builder<'a> {
+    source: &'a [u8],
+    /// How far into the source the instructions mapped so far reach.
+    position: usize,
+    spans: Vec<Span>,
+    /// Whether the current cell is known to be zero, so that the compiler dropped any loop here.
+    known_zero: bool,
+}
+
+impl<'a> Builder<'a> {
+    /// Follows the compiler: each statement consumes the commands it was compiled from.
+    fn statements(&mut self, program: &[peephole::Statement]) {
+        use common::Instruction::*;
+        use peephole::Statement::*;
+
+        for statement in program {
+            self.skip_dead_loops();
+
+            match *statement {
+                Instr(Left(_)) | Instr(Right(_)) | Instr(Add(_)) => {
+                    let span = self.run();
+                    self.spans.push(span);
+                }
+
+                Instr(In) | Instr(Out) => {
+                    let span = self.command();
+                    self.spans.push(span);
+                }
+
+                // The loop itself comes next, so leave it to be consumed.
+                Instr(DivMod) | Instr(IndexRight(_)) | Instr(IndexLeft(_)) => {
+                    let start = self.peek().unwrap_or(self.source.len());
+                    let end = matching_bracket(self.source, start)
+                        .map_or(self.source.len(), |close| close + 1);
+                    self.spans.push(Span { start, end });
+                }
+
+                Instr(_) => {
+                    let open = self.command();
+                    let end = matching_bracket(self.source, open.start)
+                        .map_or(self.source.len(), |close| close + 1);
+                    self.position = end;
+                    self.spans.push(Span { start: open.start, end });
+                }
+
+                Loop(ref body) => {
+                    let open = self.command();
+                    self.spans.push(open);
+                    self.known_zero = false;
+                    self.statements(body);
+                    let close = self.command();
+                    self.spans.push(close);
+                }
+            }
+
+            self.known_zero = match *statement {
+                Instr(Out) => self.known_zero,
+                Instr(Left(_)) | Instr(Right(_)) | Instr(Add(_)) | Instr(In) | Instr(DivMod) |
+                Instr(IndexRight(_)) | Instr(IndexLeft(_)) => false,
+                _ => true,
+            };
+        }
+
+        self.skip_dead_loops();
+    }
+
+    /// Consumes the loops that the compiler dropped because they start at a known zero.
+    fn skip_dead_loops(&mut self) {
+        while self.known_zero {
+            match self.peek() {
+                Some(open) if self.source[open] == b'[' => {
+                    self.position = matching_bracket(self.source, open)
+                        .map_or(self.source.len(), |close| close + 1);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Consumes a run of one command, as run-length encoding groups them.
+    fn run(&mut self) -> Span {
+        let first = self.command();
+        let c = self.source.get(first.start).cloned();
+        let mut span = first;
+        let mut length = 1;
+
+        while length < Count::MAX.into_usize() {
+            match self.peek() {
+                Some(next) if Some(self.source[next]) == c => {
+                    self.position = next + 1;
+                    span.end = next + 1;
+                    length += 1;
+                }
+                _ => break,
+            }
+        }
+
+        span
+    }
+
+    /// Consumes the next command.
+    fn command(&mut self) -> Span {
+        match self.peek() {
+            Some(offset) => {
+                self.position = offset + 1;
+                Span::at(offset)
+            }
+            None => Span { start: self.source.len(), end: self.source.len() },
+        }
+    }
+
+    /// The offset of the next command.
+    fn peek(&self) -> Option<usize> {
+        self.source[self.position ..].iter()
+            .position(|c| b"<>+-,.[]".contains(c))
+            .map(|i| self.position + i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+
+    fn spans(source: &[u8]) -> Vec<(usize, usize)> {
+        compile(source).unwrap().1.spans().iter().map(|span| (span.start, span.end)).collect()
+    }
+
+    #[test]
+    fn runs_loops_and_fused_loops() {
+        //                     0123456789012345678
+        assert_eq!(spans(b"++ +>[-] ,,[<.>-]"),
+                   vec![(0, 4), (4, 5), (5, 8), (9, 10), (10, 11),
+                        (11, 12), (12, 13), (13, 14), (14, 15), (15, 16), (16, 17)]);
+    }
+
+    #[test]
+    fn skips_dropped_loops() {
+        //                     0123456789012
+        assert_eq!(spans(b"[-][.]>[-][+]"),
+                   vec![(0, 3), (6, 7), (7, 10)]);
+    }
+
+    #[test]
+    fn maps_every_instruction() {
+        let (program, map) = compile(FACTOR_SRC).unwrap();
+        assert_eq!(map.len(), program.len());
+        assert!(map.spans().iter().all(|span| span.end <= FACTOR_SRC.len()));
+        assert!(map.spans().windows(2).all(|pair| pair[0].start < pair[1].start));
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let (_, map) = compile(b"+[->+<]>.").unwrap();
+        assert_eq!(parse(&map.to_string()), Ok(map.clone()));
+        assert_eq!(map.pc_at(3), Some(1));
+        assert_eq!(parse("0 1 0").map_err(|e| e.message), Err("span ends before it starts"));
+        assert_eq!(parse("1 0 1").map_err(|e| e.message), Err("addresses out of order"));
+    }
+
+    #[test]
+    fn locates_offsets() {
+        let source = "+\n é+".as_bytes();
+        assert_eq!(line_column(source, 0), (1, 1));
+        assert_eq!(line_column(source, 2), (2, 1));
+        assert_eq!(line_column(source, 5), (2, 3));
+    }
+}
@@ -0,0 +1,137 @@
+//! Programs read from several files.
+//!
+//! `bfi part1.bf part2.bf` runs the files as one program, concatenated in order. A
+//! [`Sources`](struct.Sources.html) keeps the text together with where each file begins in it,
+//! so that a position in the program can be reported as a position in the file it came from.
+//! Names that are not found as given are looked up in a search path, which `bfi` builds from
+//! its `--include` flags.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use source_map;
+
+/// The text of a program and the files it was read from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Sources {
+    text: Vec<u8>,
+    /// The name of each file and the offset in `text` where it begins, in order.
+    files: Vec<(String, usize)>,
+}
+
+/// A position in one of the files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Location<'a> {
+    /// The name of the file.
+    pub file: &'a str,
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column in characters, counting from 1.
+    pub column: usize,
+}
+
+impl Sources {
+    /// No sources at all.
+    pub fn new() -> Self {
+        Sources::default()
+    }
+
+    /// Adds text from the named file at the end of the program.
+    pub fn push(&mut self, name: &str, text: &[u8]) {
+        self.files.push((name.to_owned(), self.text.len()));
+        self.text.extend_from_slice(text);
+    }
+
+    /// Finds the named file on the search path and adds its contents. Returns the path read.
+    pub fn read(&mut self, name: &str, search_path: &[PathBuf]) -> io::Result<PathBuf> {
+        let path = find(name, search_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "file not found"))?;
+        let text = fs::read(&path)?;
+        self.push(name, &text);
+        Ok(path)
+    }
+
+    /// The text of the whole program.
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// The names of the files, in order.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|(name, _)| &name[..])
+    }
+
+    /// The file and position of an offset into the whole program, or `None` if there are no
+    /// files. An offset past the end is placed at the end of the last file.
+    pub fn locate<'a>(&'a self, offset: usize) -> Option<Location<'a>> {
+        let offset = offset.min(self.text.len());
+        let index = match self.files.binary_search_by_key(&offset, |&(_, start)| start) {
+            // Empty files share a start with the file after them; take the last.
+            Ok(index) => index + self.files[index ..].iter()
+                .take_while(|&&(_, start)| start == offset).count() - 1,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        let (ref file, start) = self.files[index];
+        let end = self.files.get(index + 1).map_or(self.text.len(), |&(_, end)| end);
+        let (line, column) = source_map::line_column(&self.text[start .. end], offset - start);
+        Some(Location { file, line, column })
+    }
+}
+
+/// Finds a file by name: as given if it exists there, or else in the first directory of the
+/// search path that has it. Absolute names are never searched for.
+pub fn find(name: &str, search_path: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return Some(path.to_owned());
+    }
+    if path.is_absolute() {
+        return None;
+    }
+    search_path.iter().map(|dir| dir.join(path)).find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_located_in_their_files() {
+        let mut sources = Sources::new();
+        sources.push("a.bf", b"++\n+");
+        sources.push("empty.bf", b"");
+        sources.push("b.bf", b"[\n-]");
+        assert_eq!(sources.text(), b"++\n+[\n-]");
+        assert_eq!(sources.files().collect::<Vec<_>>(), ["a.bf", "empty.bf", "b.bf"]);
+
+        let at = |offset| {
+            let location = sources.locate(offset).unwrap();
+            (location.file, location.line, location.column)
+        };
+        assert_eq!(at(0), ("a.bf", 1, 1));
+        assert_eq!(at(3), ("a.bf", 2, 1));
+        assert_eq!(at(4), ("b.bf", 1, 1));
+        assert_eq!(at(6), ("b.bf", 2, 1));
+        assert_eq!(at(100), ("b.bf", 2, 3));
+        assert_eq!(Sources::new().locate(0), None);
+    }
+
+    #[test]
+    fn names_are_found_on_the_search_path() {
+        let search_path = [::std::env::temp_dir()
+                               .join(format!("bf-sources-{}", ::std::process::id()))];
+        let dir = &search_path[0];
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("lib.bf"), b"+").unwrap();
+
+        let mut sources = Sources::new();
+        assert!(sources.read("lib.bf", &[]).is_err());
+        assert_eq!(sources.read("lib.bf", &search_path).unwrap(), dir.join("lib.bf"));
+        assert_eq!(sources.text(), b"+");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
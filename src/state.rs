@@ -2,10 +2,22 @@
 //!
 //! Useful for creating initial states for testing, and also the interface used by the
 //! interpreters to access the state.
+//!
+//! A state can be [reset](struct.State.html#method.reset) and reused for the next run. It keeps
+//! track of which pages of its tape have been written since it was last reset, so that a server
+//! running many small programs on a tape of megabytes clears only the few pages each one used.
+//!
+//! A large tape can be [put on huge pages](struct.State.html#method.with_huge_pages), which saves
+//! TLB misses for programs that range over much of it.
 
+use std::alloc::{self, Layout};
+use std::cell::Cell;
 use std::default::Default;
+use std::fmt;
 use std::io::{Read, Write};
 use std::num::Wrapping;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
 
 use common::{BfResult, Error};
 use traits::IntoUsize;
@@ -14,11 +26,165 @@ use traits::IntoUsize;
 /// [`State::new`](struct.State.html#method.new).
 pub const DEFAULT_CAPACITY: usize = 30_000;
 
+/// (`== 4096`) The number of cells in each page that
+/// [`State::reset`](struct.State.html#method.reset) clears as a whole.
+pub const PAGE_CELLS: usize = 4096;
+
+/// (`== 2 MiB`) The size of the huge pages that
+/// [`State::with_huge_pages`](struct.State.html#method.with_huge_pages) asks for.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// How a tape’s memory is laid out, for
+/// [`State::with_layout`](struct.State.html#method.with_layout).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TapeLayout {
+    /// The boundary in bytes, a power of two, that the tape starts on, or 0 for any.
+    pub align: usize,
+    /// How many unused bytes follow the tape, so that whatever is allocated next does not
+    /// share its last cache line.
+    pub padding: usize,
+    /// Whether to touch each page of the tape as it is allocated. Linux gives a page memory
+    /// from the NUMA node of the thread that first touches it, so this puts the tape near the
+    /// thread that allocates it, rather than whichever first runs on it.
+    pub first_touch: bool,
+}
+
+/// The memory of a tape, allocated with a [`TapeLayout`](struct.TapeLayout.html).
+struct Memory {
+    cells: NonNull<Wrapping<u8>>,
+    len: usize,
+    /// The layout of the allocation, or `None` for an empty tape, which has none.
+    layout: Option<Layout>,
+}
+
+// `Memory` owns its cells, as a box would.
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+impl Memory {
+    /// Zeroed memory of `len` cells.
+    fn zeroed(len: usize, tape: TapeLayout) -> Self {
+        let layout = Layout::from_size_align(len + tape.padding, tape.align.max(1))
+            .expect("tape too large");
+        if layout.size() == 0 {
+            return Memory { cells: NonNull::dangling(), len, layout: None };
+        }
+
+        // Safe since the layout is not empty.
+        let cells = unsafe { alloc::alloc_zeroed(layout) } as *mut Wrapping<u8>;
+        let cells = NonNull::new(cells).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        let mut result = Memory { cells, len, layout: Some(layout) };
+
+        if tape.first_touch && len > 0 {
+            let cells = result.as_mut_ptr();
+            for offset in (0 .. len).step_by(PAGE_BYTES).chain(Some(len - 1)) {
+                // A plain store of zero to zeroed memory could be left out. Safe since the
+                // offset is within the tape.
+                unsafe { ptr::write_volatile(cells.add(offset), Wrapping(0)) };
+            }
+        }
+
+        result
+    }
+}
+
+impl From<Box<[Wrapping<u8>]>> for Memory {
+    fn from(cells: Box<[Wrapping<u8>]>) -> Self {
+        let len = cells.len();
+        let layout = if len == 0 { None } else { Some(Layout::for_value(&*cells)) };
+        let cells = NonNull::new(Box::into_raw(cells) as *mut Wrapping<u8>)
+            .expect("boxes are not null");
+        Memory { cells, len, layout }
+    }
+}
+
+impl Deref for Memory {
+    type Target = [Wrapping<u8>];
+
+    fn deref(&self) -> &[Wrapping<u8>] {
+        // Safe since `cells` holds `len` initialized cells, or is dangling with `len` 0.
+        unsafe { ::std::slice::from_raw_parts(self.cells.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [Wrapping<u8>] {
+        // Safe as for `deref`, and `&mut self` is unique.
+        unsafe { ::std::slice::from_raw_parts_mut(self.cells.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Some(layout) = self.layout {
+            // Safe since `cells` was allocated with `layout`, by `zeroed` or as a box.
+            unsafe { alloc::dealloc(self.cells.as_ptr() as *mut u8, layout) };
+        }
+    }
+}
+
+impl Clone for Memory {
+    /// A copy with the same alignment and padding.
+    fn clone(&self) -> Self {
+        let align = self.layout.map_or(1, |layout| layout.align());
+        let padding = self.layout.map_or(0, |layout| layout.size() - self.len);
+        let layout = TapeLayout { align, padding, first_touch: false };
+        let mut result = Memory::zeroed(self.len, layout);
+        result.copy_from_slice(self);
+        result
+    }
+}
+
+impl PartialEq for Memory {
+    fn eq(&self, other: &Memory) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Memory {}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// The size of the pages that `first_touch` touches, the smallest Linux uses.
+const PAGE_BYTES: usize = 4096;
+
 /// The Brainfuck machine state.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq)]
 pub struct State {
-    memory: Box<[Wrapping<u8>]>,
+    memory: Memory,
     pointer: usize,
+    /// The sum behind [`hash`](#method.hash), kept up to date by each write through the
+    /// state’s methods, or `None` after memory was written by other means, until it is next
+    /// needed.
+    sum: Cell<Option<u64>>,
+    /// A bit for each page, set once a cell in it is written through the state’s methods.
+    touched: Box<[u64]>,
+    /// Whether memory may have been written by other means, so that any page may be dirty.
+    wild: bool,
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &State) -> bool {
+        self.pointer == other.pointer && self.memory == other.memory
+    }
+}
+
+/// The number of pages a tape of the given size takes.
+fn pages(memory_size: usize) -> usize {
+    memory_size.div_ceil(PAGE_CELLS)
+}
+
+/// The weight of the cell at an address in the sum, a mix of its bits like SplitMix64’s.
+#[inline]
+fn weight(address: usize) -> u64 {
+    let mut z = (address as u64).wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 impl State {
@@ -30,9 +196,100 @@ impl State {
 
     /// Creates a new BF machine state with the given memory capacity.
     pub fn with_capacity(memory_size: usize) -> Self {
+        Self::with_layout(memory_size, TapeLayout::default())
+    }
+
+    /// Creates a new BF machine state with the given memory capacity, its memory laid out as
+    /// `layout` says, as for keeping the tapes of machines that run at once from sharing cache
+    /// lines. See [`TapePool`](../tape_pool/struct.TapePool.html).
+    pub fn with_layout(memory_size: usize, layout: TapeLayout) -> Self {
         State {
-            memory: vec![Wrapping(0); memory_size].into_boxed_slice(),
+            memory: Memory::zeroed(memory_size, layout),
             pointer: 0,
+            sum: Cell::new(Some(0)),
+            touched: vec![0; pages(memory_size).div_ceil(64)].into_boxed_slice(),
+            wild: false,
+        }
+    }
+
+    /// Creates a new BF machine state with the given memory capacity, asking the OS to back
+    /// its memory with [huge pages](constant.HUGE_PAGE_SIZE.html).
+    ///
+    /// Only whole huge pages within the tape can be, so this matters only for tapes of several
+    /// megabytes. Where the OS has no transparent huge pages, or declines, the state is just
+    /// as from [`with_capacity`](#method.with_capacity). Clones get normal pages.
+    pub fn with_huge_pages(memory_size: usize) -> Self {
+        let mut result = Self::with_capacity(memory_size);
+        advise_huge_pages(&mut result.memory);
+        result
+    }
+
+    /// A state with the given memory and pointer, which must be within it unless the memory
+    /// is empty.
+    pub(crate) fn from_parts(memory: Box<[Wrapping<u8>]>, pointer: usize) -> Self {
+        debug_assert!(pointer < memory.len() || pointer == 0);
+        let touched = vec![0; pages(memory.len()).div_ceil(64)].into_boxed_slice();
+        State { memory: memory.into(), pointer, sum: Cell::new(None), touched, wild: true }
+    }
+
+    /// Zeroes the memory and moves the pointer back to the start, as a new state, to run another
+    /// program. Only the pages written since the state was created or last reset are cleared,
+    /// unless memory was written directly, as by native code, when it clears them all.
+    pub fn reset(&mut self) {
+        if self.wild {
+            for cell in self.memory.iter_mut() {
+                *cell = Wrapping(0);
+            }
+        } else {
+            for (word, bits) in self.touched.iter().enumerate() {
+                let mut bits = *bits;
+                while bits != 0 {
+                    let page = word * 64 + bits.trailing_zeros() as usize;
+                    let end = (page * PAGE_CELLS + PAGE_CELLS).min(self.memory.len());
+                    for cell in &mut self.memory[page * PAGE_CELLS .. end] {
+                        *cell = Wrapping(0);
+                    }
+                    bits &= bits - 1;
+                }
+            }
+        }
+
+        for bits in self.touched.iter_mut() {
+            *bits = 0;
+        }
+        self.wild = false;
+        self.pointer = 0;
+        self.sum.set(Some(0));
+    }
+
+    /// A hash of the memory and the pointer, for telling states apart at checkpoints. Equal
+    /// states hash the same, and different ones almost never do.
+    ///
+    /// It takes constant time, with the hash updated as each cell is written, except just
+    /// after code such as the JIT’s has written memory directly, when it scans the tape.
+    pub fn hash(&self) -> u64 {
+        let sum = self.sum.get().unwrap_or_else(|| {
+            let sum = self.memory.iter().enumerate().fold(0u64, |sum, (address, cell)| {
+                sum.wrapping_add(weight(address).wrapping_mul(u64::from(cell.0)))
+            });
+            self.sum.set(Some(sum));
+            sum
+        });
+        let mut z = sum ^ weight(self.pointer).rotate_left(32);
+        z = (z ^ (z >> 33)).wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+        z ^ (z >> 33)
+    }
+
+    /// Sets the cell at an address, keeping the hash up to date.
+    #[inline]
+    fn set(&mut self, address: usize, value: Wrapping<u8>) {
+        let old = self.memory[address];
+        self.memory[address] = value;
+        let page = address / PAGE_CELLS;
+        self.touched[page / 64] |= 1 << (page % 64);
+        if let Some(ref mut sum) = *self.sum.get_mut() {
+            let change = u64::from(value.0).wrapping_sub(u64::from(old.0));
+            *sum = sum.wrapping_add(weight(address).wrapping_mul(change));
         }
     }
 
@@ -83,7 +340,8 @@ impl State {
     /// Wraps around modulo 256.
     #[inline]
     pub fn up(&mut self, count: u8) {
-        self.memory[self.pointer] += Wrapping(count);
+        let address = self.pointer;
+        self.set(address, self.memory[address] + Wrapping(count));
     }
 
     /// Decrements/decreases the byte at the pointer.
@@ -91,7 +349,8 @@ impl State {
     /// Wraps around modulo 256.
     #[inline]
     pub fn down(&mut self, count: u8) {
-        self.memory[self.pointer] -= Wrapping(count);
+        let address = self.pointer;
+        self.set(address, self.memory[address] - Wrapping(count));
     }
 
     /// Gets the value of the byte at the pointer.
@@ -103,14 +362,15 @@ impl State {
     /// Sets the value of the byte at the pointer.
     #[inline]
     pub fn store(&mut self, value: u8) {
-        self.memory[self.pointer] = Wrapping(value);
+        let address = self.pointer;
+        self.set(address, Wrapping(value));
     }
 
     /// Adds the given value at the given positive offset from the pointer.
     #[inline]
     pub fn up_pos_offset<C: IntoUsize>(&mut self, offset: C, value: u8) -> BfResult<()> {
         let address = self.pos_offset(offset)?;
-        self.memory[address] += Wrapping(value);
+        self.set(address, self.memory[address] + Wrapping(value));
         Ok(())
     }
 
@@ -118,10 +378,88 @@ impl State {
     #[inline]
     pub fn up_neg_offset<C: IntoUsize>(&mut self, offset: C, value: u8) -> BfResult<()> {
         let address = self.neg_offset(offset)?;
-        self.memory[address] += Wrapping(value);
+        self.set(address, self.memory[address] + Wrapping(value));
         Ok(())
     }
 
+    /// Does the work of the divmod loop `[->-[>+>>]>[+[-<+>]>+>>]<<<<<]` at once, leaving the
+    /// pointer’s cell zero, unless the loop would not run, would leave the tape, or would not
+    /// come back to where it started. Those are the cases where the cell is zero, the five
+    /// cells to its right are not all on the tape, or the last two of them are not zero, and
+    /// the one where the divisor reaches 1 with nothing counted towards the remainder.
+    ///
+    /// Returns whether it did the loop’s work.
+    pub fn div_mod(&mut self) -> bool {
+        let p = self.pointer;
+        if self.memory[p].0 == 0 || self.memory.len() - p < 6
+            || self.memory[p + 4].0 != 0 || self.memory[p + 5].0 != 0 {
+            return false;
+        }
+
+        let (mut n, mut d, mut r, mut q) =
+            (self.memory[p].0, self.memory[p + 1].0, self.memory[p + 2].0, self.memory[p + 3].0);
+
+        // `d + r` stays the same, so the divisor reaching 1 with nothing counted happens only
+        // if that is 1, once the divisor has counted down.
+        if d.wrapping_add(r) == 1 && d != 0 && d <= n {
+            return false;
+        }
+
+        while n != 0 {
+            n -= 1;
+            d = d.wrapping_sub(1);
+            if d != 0 {
+                r = r.wrapping_add(1);
+            } else {
+                d = r.wrapping_add(1);
+                r = 0;
+                q = q.wrapping_add(1);
+            }
+        }
+
+        self.set(p, Wrapping(0));
+        self.set(p + 1, Wrapping(d));
+        self.set(p + 2, Wrapping(r));
+        self.set(p + 3, Wrapping(q));
+        true
+    }
+
+    /// Does the work of an index loop such as `[[->>+<<]>>-]`, which walks the pointer’s cell
+    /// `skip` cells at a time to the right, counting it down, by moving there at once. It does
+    /// so unless the loop would not run, would leave the tape, or would step on a cell that is
+    /// not zero; that is, unless the cell is zero or the cells it names, `skip` apart, are not
+    /// all on the tape and zero.
+    ///
+    /// Returns whether it did the loop’s work.
+    pub fn index_right<C: IntoUsize>(&mut self, skip: C) -> bool {
+        let (p, skip) = (self.pointer, skip.into_usize());
+        let end = p + self.load() as usize * skip;
+        if end == p || end >= self.memory.len()
+            || self.memory[p + skip ..= end].iter().step_by(skip).any(|cell| cell.0 != 0) {
+            return false;
+        }
+
+        self.set(p, Wrapping(0));
+        self.pointer = end;
+        true
+    }
+
+    /// Does the work of an index loop such as `[[-<<+>>]<<-]`, like
+    /// [`index_right`](#method.index_right) but to the left.
+    pub fn index_left<C: IntoUsize>(&mut self, skip: C) -> bool {
+        let (p, skip) = (self.pointer, skip.into_usize());
+        let distance = self.load() as usize * skip;
+        if distance == 0 || distance > p
+            || self.memory[p - distance .. p].iter().rev().skip(skip - 1).step_by(skip)
+                   .any(|cell| cell.0 != 0) {
+            return false;
+        }
+
+        self.set(p, Wrapping(0));
+        self.pointer = p - distance;
+        true
+    }
+
     /// Reads from a `Read` into the byte at the pointer.
     #[inline]
     pub fn read<R: Read>(&mut self, input: &mut R) {
@@ -136,26 +474,154 @@ impl State {
         let _ = output.write_all(&[self.load()]);
     }
 
+    /// Copies the cells after the pointer into a new state, with its pointer on the first.
+    ///
+    /// # Errors
+    ///
+    /// Return `Err` if the pointer is on the last cell.
+    pub fn split_suffix(&self) -> BfResult<State> {
+        let start = self.pos_offset(1usize)?;
+        Ok(State::from_parts(self.memory[start ..].to_vec().into_boxed_slice(), 0))
+    }
+
     /// The memory capacity.
     pub fn capacity(&self) -> usize {
         self.memory.len()
     }
 
+    /// The position of the pointer.
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// The contents of memory.
+    pub fn memory(&self) -> &[Wrapping<u8>] {
+        &self.memory
+    }
+
+    /// The memory and the pointer, for execution tiers that address cells relative to a
+    /// pointer of their own. The pointer must be left within the memory.
+    pub(crate) fn parts_mut(&mut self) -> (&mut [Wrapping<u8>], &mut usize) {
+        self.sum.set(None);
+        self.wild = true;
+        (&mut *self.memory, &mut self.pointer)
+    }
+
     /// Gets a mutable, raw pointer to the start of memory.
     ///
     /// This is used by the JIT RTS to pass the memory pointer to the generated code.
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.sum.set(None);
+        self.wild = true;
         // Assumes that Wrapping<u8> == u8:
         self.memory.as_mut_ptr() as *mut u8
     }
 }
 
+/// Asks Linux to back the huge pages wholly within `memory` with huge pages, before they are
+/// first touched, returning whether it agreed to.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn advise_huge_pages(memory: &mut [Wrapping<u8>]) -> bool {
+    extern "C" {
+        fn madvise(address: *mut u8, length: usize, advice: i32) -> i32;
+    }
+    const MADV_HUGEPAGE: i32 = 14;
+
+    let start = memory.as_mut_ptr() as usize;
+    let first = start.next_multiple_of(HUGE_PAGE_SIZE);
+    let end = (start + memory.len()) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+    // The advice changes only how the pages are backed, not what they hold.
+    first < end && unsafe { madvise(first as *mut u8, end - first, MADV_HUGEPAGE) == 0 }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn advise_huge_pages(_memory: &mut [Wrapping<u8>]) -> bool {
+    false
+}
+
 impl Default for State {
     fn default() -> Self {
         State::new()
     }
 }
 
+/// (`== 256`) The most tapes a [`Tapes`](struct.Tapes.html) can hold.
+pub const MAX_TAPES: usize = 256;
+
+/// Several tapes, each with its own pointer, for dialects that switch between tapes.
+///
+/// There is always a current tape. Tapes past the last are created as they are first switched
+/// to, each as large as the first.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tapes {
+    tapes: Vec<State>,
+    current: usize,
+}
+
+impl Tapes {
+    /// Creates a set of tapes with the given state as the first and current one.
+    pub fn new(first: State) -> Self {
+        Tapes { tapes: vec![first], current: 0 }
+    }
+
+    /// The current tape.
+    #[inline]
+    pub fn current(&self) -> &State {
+        &self.tapes[self.current]
+    }
+
+    /// The current tape, mutably.
+    #[inline]
+    pub fn current_mut(&mut self) -> &mut State {
+        &mut self.tapes[self.current]
+    }
+
+    /// The index of the current tape.
+    pub fn index(&self) -> usize {
+        self.current
+    }
+
+    /// The tapes created so far.
+    pub fn tapes(&self) -> &[State] {
+        &self.tapes
+    }
+
+    /// Switches to an earlier tape.
+    ///
+    /// # Errors
+    ///
+    /// Return `Err` if that would go before the first tape.
+    pub fn previous_tape<C: IntoUsize>(&mut self, count: C) -> BfResult<()> {
+        let count = count.into_usize();
+        if self.current >= count {
+            self.current -= count;
+            Ok(())
+        } else {
+            Err(Error::PointerUnderflow)
+        }
+    }
+
+    /// Switches to a later tape, creating it if necessary.
+    ///
+    /// # Errors
+    ///
+    /// Return `Err` if that would go past [`MAX_TAPES`](constant.MAX_TAPES.html).
+    pub fn next_tape<C: IntoUsize>(&mut self, count: C) -> BfResult<()> {
+        let index = self.current + count.into_usize();
+        if index >= MAX_TAPES {
+            return Err(Error::PointerOverflow);
+        }
+
+        let capacity = self.tapes[0].capacity();
+        while self.tapes.len() <= index {
+            self.tapes.push(State::with_capacity(capacity));
+        }
+
+        self.current = index;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -255,10 +721,103 @@ mod tests {
         machine.left(1usize).unwrap();
     }
 
+    #[test]
+    fn split_suffix_copies_later_cells() {
+        assert_eq!(make(&[1, 2, 3], 0).split_suffix(), Ok(make(&[2, 3], 0)));
+        assert_eq!(make(&[1, 2, 3], 2).split_suffix(), Err(Error::PointerOverflow));
+    }
+
+    #[test]
+    fn div_mod_divides_when_it_can() {
+        let mut state = make(&[14, 5, 0, 0, 0, 0], 0);
+        assert!(state.div_mod());
+        assert_eq!(state, make(&[0, 1, 4, 2, 0, 0], 0));
+
+        assert!(!make(&[14, 1, 0, 0, 0, 0], 0).div_mod());
+        assert!(!make(&[14, 5, 0, 0, 1, 0], 0).div_mod());
+        assert!(!make(&[0, 5, 0, 0, 0, 0], 0).div_mod());
+        assert!(!make(&[14, 5, 0, 0, 0], 0).div_mod());
+    }
+
+    #[test]
+    fn index_moves_when_it_can() {
+        let mut state = make(&[2, 7, 0, 7, 0, 7], 0);
+        assert!(state.index_right(2usize));
+        assert_eq!(state, make(&[0, 7, 0, 7, 0, 7], 4));
+        assert!(!make(&[3, 7, 0, 7, 0, 7], 0).index_right(2usize));
+        assert!(!make(&[2, 0, 1, 0, 0], 0).index_right(1usize));
+
+        let mut state = make(&[0, 0, 0, 3], 3);
+        assert!(state.index_left(1usize));
+        assert_eq!(state, make(&[0, 0, 0, 0], 0));
+        assert!(!make(&[0, 0, 2], 2).index_left(2usize));
+        assert!(!make(&[0, 1, 1, 1, 2], 4).index_left(2usize));
+    }
+
+    #[test]
+    fn tapes_switch_and_grow() {
+        let mut tapes = Tapes::new(make(&[1, 2], 1));
+        assert_eq!(tapes.previous_tape(1usize), Err(Error::PointerUnderflow));
+        tapes.next_tape(2usize).unwrap();
+        tapes.current_mut().store(7);
+        assert_eq!(tapes.tapes(), &[make(&[1, 2], 1), make(&[0, 0], 0), make(&[7, 0], 0)]);
+        tapes.previous_tape(2usize).unwrap();
+        assert_eq!(tapes.current().load(), 2);
+        assert_eq!(tapes.next_tape(MAX_TAPES), Err(Error::PointerOverflow));
+    }
+
+    #[test]
+    fn hashes_follow_writes() {
+        let mut state = State::with_capacity(8);
+        state.right(3usize).unwrap();
+        state.up(200);
+        state.up_pos_offset(2usize, 9).unwrap();
+        state.store(7);
+        assert_eq!(state.hash(), make(&[0, 0, 0, 7, 0, 9, 0, 0], 3).hash());
+        assert_ne!(state.hash(), make(&[0, 0, 0, 7, 0, 9, 0, 0], 4).hash());
+        assert_ne!(state.hash(), make(&[0, 0, 0, 9, 0, 7, 0, 0], 3).hash());
+
+        let before = state.hash();
+        state.parts_mut().0[6] = Wrapping(1);
+        assert_ne!(state.hash(), before);
+        state.parts_mut().0[6] = Wrapping(0);
+        assert_eq!(state.hash(), before);
+        state.down(7);
+        assert_eq!(state.hash(), make(&[0, 0, 0, 0, 0, 9, 0, 0], 3).hash());
+    }
+
+    #[test]
+    fn resets_clear_the_pages_written() {
+        let mut state = State::with_capacity(3 * PAGE_CELLS + 10);
+        state.right(PAGE_CELLS + 5).unwrap();
+        state.up(9);
+        state.right(2 * PAGE_CELLS).unwrap();
+        state.store(4);
+        assert_eq!(state.touched[0], 0b1010);
+
+        state.reset();
+        assert_eq!(state, State::with_capacity(3 * PAGE_CELLS + 10));
+        assert_eq!((state.touched[0], state.hash()), (0, State::with_capacity(1).hash()));
+
+        state.parts_mut().0[7] = Wrapping(1);
+        state.reset();
+        assert_eq!(state.memory()[7].0, 0);
+    }
+
+    #[test]
+    fn huge_page_tapes_behave_as_any_other() {
+        let size = 3 * HUGE_PAGE_SIZE;
+        let mut state = State::with_huge_pages(size);
+        state.right(HUGE_PAGE_SIZE + 1).unwrap();
+        state.up(5);
+        assert_eq!(state.memory()[HUGE_PAGE_SIZE + 1].0, 5);
+        state.reset();
+        assert_eq!(state, State::with_capacity(size));
+        assert!(!advise_huge_pages(&mut State::with_capacity(16).memory));
+    }
+
     fn make(memory: &[u8], pointer: usize) -> State {
-        State {
-            memory: memory.iter().map(|&b| Wrapping(b)).collect::<Vec<_>>().into_boxed_slice(),
-            pointer: pointer,
-        }
+        let memory = memory.iter().map(|&b| Wrapping(b)).collect::<Vec<_>>();
+        State::from_parts(memory.into_boxed_slice(), pointer)
     }
 }
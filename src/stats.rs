@@ -0,0 +1,314 @@
+//! A static summary of a program, for `bfi analyze`.
+//!
+//! [`summarize`](fn.summarize.html) counts the program’s commands and compiled instructions,
+//! measures its loop nesting, lists the loops whose net movement the
+//! [loop balance analysis](../analysis/loop_balance/index.html) cannot pin down, and bounds the
+//! memory the program needs. It also gives each loop’s balance, with what in its body makes it
+//! unbalanced. Only balanced loops, which bring the pointer back to where it started each time
+//! around, have their bounds checks elided, so
+//! [`Summary::report_loops`](struct.Summary.html#method.report_loops) shows which loops cost
+//! checks and why. The lower bound comes from the
+//! [`AbstractInterpreter`](../analysis/struct.AbstractInterpreter.html): the furthest right
+//! the pointer is proved to get, outside of any loop, which the program reaches unless it fails
+//! first. The upper bound, when the pointer’s travel to the right is bounded, comes from
+//! [`ProgramAnalysis`](../analysis/struct.ProgramAnalysis.html).
+
+use std::fmt::Write;
+
+use analysis::{AbstractInterpreter, BoundsAnalysis};
+use analysis::loop_balance::{LoopBalance, LoopBalanceMap};
+use ast;
+use bytecode;
+use common::{BfResult, Instruction};
+use diagnostics::Span;
+use peephole::{self, Statement};
+use source_map::SourceMap;
+use traits::{IntoUsize, PeepholeCompilable};
+
+/// The commands, in the order [`Summary::commands`](struct.Summary.html#structfield.commands)
+/// counts them.
+pub const COMMANDS: &[u8; 8] = b"><+-.,[]";
+
+/// Static facts about a program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Summary {
+    /// How many times each command appears in the source, in the order of
+    /// [`COMMANDS`](constant.COMMANDS.html).
+    pub commands: [usize; 8],
+    /// The number of peephole instructions, counting each loop once.
+    pub instructions: usize,
+    /// The number of bytecode instructions.
+    pub bytecode_instructions: usize,
+    /// The number of loops left after optimization.
+    pub loops: usize,
+    /// How deeply those loops nest; 0 if there are none.
+    pub max_depth: usize,
+    /// The source spans of the loops whose net movement is unknown, in order.
+    pub unknown_loops: Vec<Span>,
+    /// Each loop left after optimization, in preorder.
+    pub loop_nest: Vec<LoopSummary>,
+    /// The number of cells the program is proved to reach, if it does not fail first.
+    pub min_memory: usize,
+    /// The number of cells the program can reach at most, if that is bounded.
+    pub max_memory: Option<usize>,
+}
+
+/// What the loop balance analysis found about one loop.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LoopSummary {
+    /// The loop’s source.
+    pub span: Span,
+    /// How many loops it is nested in.
+    pub depth: usize,
+    /// The net movement of one iteration.
+    pub balance: LoopBalance,
+    /// The source of the first statement in the body whose movement is not exact, a scan or an
+    /// unbalanced inner loop, if there is one.
+    pub cause: Option<Span>,
+}
+
+impl Summary {
+    /// Writes the summary out, one fact a line, locating source offsets with `locate`.
+    pub fn report<F: Fn(usize) -> String>(&self, locate: F) -> String {
+        let mut result = String::new();
+
+        let total: usize = self.commands.iter().sum();
+        let counts: Vec<String> = COMMANDS.iter().zip(&self.commands)
+            .map(|(&command, count)| format!("{} {}", command as char, count))
+            .collect();
+        let _ = writeln!(result, "commands: {} ({})", total, counts.join(", "));
+        let _ = writeln!(result, "instructions: {} peephole, {} bytecode",
+                         self.instructions, self.bytecode_instructions);
+        let _ = writeln!(result, "loops: {}, nested at most {} deep", self.loops, self.max_depth);
+
+        let _ = write!(result, "loops with unknown balance: {}", self.unknown_loops.len());
+        for (index, span) in self.unknown_loops.iter().enumerate() {
+            result.push_str(if index == 0 { ", at " } else { ", " });
+            result.push_str(&locate(span.start));
+        }
+        result.push('\n');
+
+        let cells = |count| match count {
+            1 => "1 cell".to_owned(),
+            _ => format!("{} cells", count),
+        };
+        let _ = match self.max_memory {
+            Some(max) if max == self.min_memory =>
+                writeln!(result, "memory needed: {}", cells(max)),
+            Some(max) => writeln!(result, "memory needed: {} to {}", self.min_memory, cells(max)),
+            None => writeln!(result, "memory needed: at least {}", cells(self.min_memory)),
+        };
+
+        result
+    }
+
+    /// Writes out the loop nest, a line for each loop indented by its depth, with its balance
+    /// and what makes it unbalanced, locating source offsets with `locate`.
+    pub fn report_loops<F: Fn(usize) -> String>(&self, locate: F) -> String {
+        let mut result = String::new();
+
+        for summary in &self.loop_nest {
+            let _ = write!(result, "{:1$}loop at {2}: {3}", "", 2 * summary.depth,
+                           locate(summary.span.start), summary.balance);
+            if let Some(cause) = summary.cause {
+                let _ = write!(result, ", from {}", locate(cause.start));
+            }
+            if !summary.balance.is_balanced() {
+                result.push_str("; bounds checked");
+            }
+            result.push('\n');
+        }
+
+        result
+    }
+}
+
+/// Summarizes the program with the given source.
+pub fn summarize(source: &[u8]) -> BfResult<Summary> {
+    let program = ast::parse_program(source)?.peephole_share();
+
+    let mut commands = [0; 8];
+    for &c in source {
+        if let Some(index) = COMMANDS.iter().position(|&command| command == c) {
+            commands[index] += 1;
+        }
+    }
+
+    let mut walker = Walker {
+        map: SourceMap::new(source, &program),
+        balances: program.loop_balances(),
+        interpreter: AbstractInterpreter::new(&program),
+        pc: 0,
+        loops: 0,
+        instructions: 0,
+        max_depth: 0,
+        unknown_loops: Vec::new(),
+        loop_nest: Vec::new(),
+        open: Vec::new(),
+        reach: 0,
+    };
+    walker.walk(&program, 0);
+
+    Ok(Summary {
+        commands,
+        instructions: walker.instructions,
+        bytecode_instructions: bytecode::compile(&program).len(),
+        loops: walker.loops,
+        max_depth: walker.max_depth,
+        unknown_loops: walker.unknown_loops,
+        loop_nest: walker.loop_nest,
+        min_memory: walker.reach + 1,
+        max_memory: program.analysis().max_cells(),
+    })
+}
+
+struct Walker<'a> {
+    map: SourceMap,
+    balances: &'a LoopBalanceMap,
+    interpreter: AbstractInterpreter,
+    pc: usize,
+    /// The number of loops entered so far, which is the preorder index of the next.
+    loops: usize,
+    instructions: usize,
+    max_depth: usize,
+    unknown_loops: Vec<Span>,
+    loop_nest: Vec<LoopSummary>,
+    /// The indices in `loop_nest` of the loops being walked, innermost last.
+    open: Vec<usize>,
+    /// The furthest cell proved reached outside of loops.
+    reach: usize,
+}
+
+impl<'a> Walker<'a> {
+    /// Walks the program as the bounds analysis does, in preorder.
+    fn walk(&mut self, program: &peephole::Program, depth: usize) {
+        for statement in program {
+            self.instructions += 1;
+
+            let inexact = match *statement {
+                Statement::Instr(Instruction::FindZeroRight(_)) |
+                Statement::Instr(Instruction::FindZeroLeft(_)) |
+                Statement::Instr(Instruction::IndexRight(_)) |
+                Statement::Instr(Instruction::IndexLeft(_)) => true,
+                Statement::Loop(_) => !self.balances.get(self.loops).is_balanced(),
+                Statement::Instr(_) => false,
+            };
+            if let Some(&innermost) = self.open.last() {
+                if inexact && self.loop_nest[innermost].cause.is_none() {
+                    self.loop_nest[innermost].cause = self.map.span(self.pc);
+                }
+            }
+
+            let offset = match *statement {
+                Statement::Instr(Instruction::Right(count)) => {
+                    self.interpreter.move_right(count);
+                    0
+                }
+                Statement::Instr(Instruction::Left(count)) => {
+                    self.interpreter.move_left(count);
+                    0
+                }
+                Statement::Instr(Instruction::OffsetAddRight(count)) => count.into_usize(),
+                Statement::Instr(Instruction::FindZeroRight(_)) |
+                Statement::Instr(Instruction::IndexRight(_)) => {
+                    self.interpreter.reset_right();
+                    0
+                }
+                Statement::Instr(Instruction::FindZeroLeft(_)) |
+                Statement::Instr(Instruction::IndexLeft(_)) => {
+                    self.interpreter.reset_left();
+                    0
+                }
+                Statement::Instr(_) => 0,
+
+                Statement::Loop(ref body) => {
+                    let balance = self.balances.get(self.loops);
+                    let span = self.map.span(self.pc);
+                    if balance.is_unknown() {
+                        if let Some(span) = span {
+                            self.unknown_loops.push(span);
+                        }
+                    }
+                    self.open.push(self.loop_nest.len());
+                    self.loop_nest.push(LoopSummary {
+                        span: span.unwrap_or(Span::at(0)),
+                        depth,
+                        balance,
+                        cause: None,
+                    });
+                    self.loops += 1;
+                    self.max_depth = self.max_depth.max(depth + 1);
+
+                    self.interpreter.enter_loop(body);
+                    self.pc += 1;
+                    self.walk(body, depth + 1);
+                    self.interpreter.leave_loop();
+                    self.open.pop();
+                    0
+                }
+            };
+
+            if depth == 0 {
+                self.reach = self.reach.max(self.interpreter.left_mark() + offset);
+            }
+            self.pc += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source_map::line_column;
+
+    #[test]
+    fn programs_are_summarized() {
+        // `[->>+<<]` and `[>]` become single instructions, leaving one loop.
+        let source = b"++>+[->>+<<]>.<[[>]<-]";
+        let summary = summarize(source).unwrap();
+        assert_eq!(summary, Summary {
+            commands: [5, 4, 4, 2, 1, 0, 3, 3],
+            instructions: 11,
+            bytecode_instructions: 12,
+            loops: 1,
+            max_depth: 1,
+            unknown_loops: vec![Span { start: 15, end: 16 }],
+            loop_nest: vec![LoopSummary {
+                span: Span { start: 15, end: 16 },
+                depth: 0,
+                balance: LoopBalance { min: Some(-1), max: None },
+                cause: Some(Span { start: 16, end: 19 }),
+            }],
+            min_memory: 4,
+            max_memory: None,
+        });
+
+        let report = summary.report(|offset| {
+            let (line, column) = line_column(source, offset);
+            format!("{}:{}", line, column)
+        });
+        assert_eq!(report, "commands: 22 (> 5, < 4, + 4, - 2, . 1, , 0, [ 3, ] 3)\n\
+                            instructions: 11 peephole, 12 bytecode\n\
+                            loops: 1, nested at most 1 deep\n\
+                            loops with unknown balance: 1, at 1:16\n\
+                            memory needed: at least 4 cells\n");
+    }
+
+    #[test]
+    fn fixed_offset_programs_have_exact_memory() {
+        let summary = summarize(b">>+[-<+>]<.").unwrap();
+        assert_eq!((summary.min_memory, summary.max_memory), (3, Some(3)));
+        assert!(summary.unknown_loops.is_empty());
+        assert_eq!(summarize(b"+[>+]").unwrap().max_memory, None);
+    }
+
+    #[test]
+    fn loop_nests_say_what_unbalances_them() {
+        let source = b"+[>+[-<+>>]<[-]<]+[>[-]<-]";
+        let report = summarize(source).unwrap().report_loops(|offset| offset.to_string());
+        assert_eq!(report, "loop at 1: moves right by an unknown amount or left by at most 1, \
+                            from 4; bounds checked\n\
+                            \x20 loop at 4: moves +1 per iteration; bounds checked\n\
+                            loop at 18: balanced\n");
+    }
+}
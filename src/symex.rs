@@ -0,0 +1,518 @@
+//! Symbolic execution of small programs, for `bfi analyze --symex`.
+//!
+//! Instead of running a program on one input, [`explore`](fn.explore.html) runs its bytecode
+//! with every byte it reads left unknown, and follows both ways at each loop test that depends
+//! on one. A cell holds either a known byte or an input byte plus a constant, and each path
+//! keeps the values its input bytes may still take, so a path that reaches a run-time error,
+//! such as the pointer going below zero, gives a concrete input that triggers it. Each such
+//! input is checked by running the program on it.
+//!
+//! Exploration is bounded in the steps each path takes, the input bytes it reads, and the
+//! number of paths. Where a cell would hold a combination of input bytes, one of them is fixed
+//! to a value it may take, and that path is followed alone. An
+//! [`Outcome`](struct.Outcome.html) says whether the whole of every path was followed, in
+//! which case a program with no findings cannot fail on any input.
+//!
+//! The same search gives tests: [`cover`](fn.cover.html) keeps the input of each path that
+//! takes a loop’s jump, or falls through it, in a way no earlier path did, so the inputs
+//! together cover every loop edge the search reaches.
+
+use std::num::Wrapping;
+
+use bytecode::{self, StepResult};
+use common::{Error, Instruction};
+use sandbox::{Sandbox, Stop};
+use state::{State, DEFAULT_CAPACITY};
+use traits::IntoUsize;
+
+/// Bounds on exploration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Options {
+    /// The most steps a path may take, counting each cell a scan passes.
+    pub max_steps: usize,
+    /// The most paths to follow.
+    pub max_paths: usize,
+    /// The most input bytes a path may read.
+    pub max_input: usize,
+    /// The memory size in bytes.
+    pub memory_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_steps: 100_000,
+            max_paths: 10_000,
+            max_input: 16,
+            memory_size: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+/// A run-time error and an input that triggers it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Finding {
+    /// The error.
+    pub error: Error,
+    /// The address of the failing instruction.
+    pub pc: usize,
+    /// An input on which the program fails this way.
+    pub input: Vec<u8>,
+}
+
+/// What exploration found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Outcome {
+    /// The first input found for each kind of error, in the order found.
+    pub findings: Vec<Finding>,
+    /// The number of paths followed.
+    pub paths: usize,
+    /// Whether every path was followed to its end, without reaching a bound or fixing an
+    /// input byte to one value.
+    pub complete: bool,
+}
+
+/// Explores the program’s paths on all inputs, within the bounds.
+pub fn explore(program: &bytecode::Program, options: &Options) -> Outcome {
+    let explorer = Explorer::run(program, options);
+    Outcome { findings: explorer.findings, paths: explorer.paths, complete: explorer.complete }
+}
+
+/// One way through a loop’s jump: the `JumpZero` or `JumpNotZero` at an address, jumping or
+/// falling through.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Edge {
+    /// The address of the jump.
+    pub pc: usize,
+    /// Whether the jump is taken.
+    pub jumps: bool,
+}
+
+impl Edge {
+    fn index(self) -> usize {
+        2 * self.pc + self.jumps as usize
+    }
+}
+
+/// An input in a test corpus.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Test {
+    /// The input.
+    pub input: Vec<u8>,
+    /// The edges it was the first to take.
+    pub covers: Vec<Edge>,
+}
+
+/// A test corpus covering a program’s loops.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Coverage {
+    /// The tests, the first of which is always the first path’s input.
+    pub tests: Vec<Test>,
+    /// The edges no path took, either because none can or because of the bounds.
+    pub uncovered: Vec<Edge>,
+    /// Whether every path was followed to its end, as in an [`Outcome`](struct.Outcome.html).
+    pub complete: bool,
+}
+
+/// Searches the program’s paths for inputs that between them take each way through each of
+/// its loops’ jumps, within the bounds.
+pub fn cover(program: &bytecode::Program, options: &Options) -> Coverage {
+    let explorer = Explorer::run(program, options);
+    let uncovered = program.iter().enumerate()
+        .filter(|&(_, &instruction)| {
+            matches!(instruction, Instruction::JumpZero(_) | Instruction::JumpNotZero(_))
+        })
+        .flat_map(|(pc, _)| vec![Edge { pc, jumps: false }, Edge { pc, jumps: true }])
+        .filter(|edge| !explorer.covered[edge.index()])
+        .collect();
+    Coverage { tests: explorer.tests, uncovered, complete: explorer.complete }
+}
+
+/// A cell’s value on a path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Value {
+    Known(u8),
+    /// The input byte with the given index, plus a constant.
+    Input(usize, u8),
+}
+
+/// What a path knows about one input byte.
+#[derive(Clone, Copy, Debug, Default)]
+struct Byte {
+    fixed: Option<u8>,
+    excluded: [u64; 4],
+}
+
+impl Byte {
+    fn allows(&self, value: u8) -> bool {
+        match self.fixed {
+            Some(fixed) => fixed == value,
+            None => self.excluded[value as usize / 64] & 1 << (value % 64) == 0,
+        }
+    }
+
+    fn allows_other_than(&self, value: u8) -> bool {
+        (0 ..= 255).any(|other| other != value && self.allows(other))
+    }
+
+    fn exclude(&mut self, value: u8) {
+        self.excluded[value as usize / 64] |= 1 << (value % 64);
+    }
+
+    /// The least value the byte may take.
+    fn example(&self) -> u8 {
+        self.fixed.unwrap_or_else(|| (0 ..= 255).find(|&value| self.allows(value)).unwrap_or(0))
+    }
+}
+
+/// One path through the program. Cells past the end of the tape are known zeros.
+#[derive(Clone, Debug, Default)]
+struct Path {
+    pc: usize,
+    pointer: usize,
+    tape: Vec<Value>,
+    input: Vec<Byte>,
+    steps: usize,
+    /// The edges taken that were not yet covered when they were.
+    edges: Vec<Edge>,
+}
+
+impl Path {
+    fn get(&self, index: usize) -> Value {
+        self.tape.get(index).cloned().unwrap_or(Value::Known(0))
+    }
+
+    fn set(&mut self, index: usize, value: Value) {
+        if self.tape.len() <= index {
+            self.tape.resize(index + 1, Value::Known(0));
+        }
+        self.tape[index] = value;
+    }
+
+    /// Fixes an input byte, replacing it with its value in every cell.
+    fn fix(&mut self, index: usize, value: u8) {
+        self.input[index].fixed = Some(value);
+        for cell in &mut self.tape {
+            if let Value::Input(input, plus) = *cell {
+                if input == index {
+                    *cell = Value::Known(value.wrapping_add(plus));
+                }
+            }
+        }
+    }
+
+    fn example_input(&self) -> Vec<u8> {
+        self.input.iter().map(Byte::example).collect()
+    }
+}
+
+/// Which ways a zero test can go.
+enum Branch {
+    Zero,
+    NonZero,
+    /// Either way, on whether the input byte with the given index is the given value.
+    Either(usize, u8),
+}
+
+struct Explorer<'a> {
+    program: &'a bytecode::Program,
+    options: &'a Options,
+    findings: Vec<Finding>,
+    paths: usize,
+    complete: bool,
+    /// Whether each edge has been covered, by `Edge::index`.
+    covered: Vec<bool>,
+    tests: Vec<Test>,
+}
+
+impl<'a> Explorer<'a> {
+    fn run(program: &'a bytecode::Program, options: &'a Options) -> Self {
+        let mut explorer = Explorer {
+            program,
+            options,
+            findings: Vec::new(),
+            paths: 1,
+            complete: true,
+            covered: vec![false; 2 * program.len()],
+            tests: Vec::new(),
+        };
+        let mut pending = vec![Path::default()];
+
+        while let Some(mut path) = pending.pop() {
+            explorer.follow(&mut path, &mut pending);
+            explorer.finish(&path);
+        }
+
+        explorer
+    }
+
+    /// Follows a path to its end, queueing the other side of each fork.
+    fn follow(&mut self, path: &mut Path, pending: &mut Vec<Path>) {
+        use common::Instruction::*;
+
+        while let Some(&instruction) = self.program.get(path.pc) {
+            if path.steps == self.options.max_steps {
+                self.complete = false;
+                return;
+            }
+            path.steps += 1;
+
+            let pointer = path.pointer;
+            match instruction {
+                Left(count) => match pointer.checked_sub(count.into_usize()) {
+                    Some(pointer) => path.pointer = pointer,
+                    None => return self.fail(path, Error::PointerUnderflow),
+                },
+
+                Right(count) => {
+                    path.pointer += count.into_usize();
+                    if path.pointer >= self.options.memory_size {
+                        return self.fail(path, Error::PointerOverflow);
+                    }
+                }
+
+                Add(amount) => {
+                    let value = match path.get(pointer) {
+                        Value::Known(value) => Value::Known(value.wrapping_add(amount)),
+                        Value::Input(index, plus) => Value::Input(index, plus.wrapping_add(amount)),
+                    };
+                    path.set(pointer, value);
+                }
+
+                In => {
+                    if path.input.len() == self.options.max_input {
+                        self.complete = false;
+                        return;
+                    }
+                    path.input.push(Byte::default());
+                    let index = path.input.len() - 1;
+                    path.set(pointer, Value::Input(index, 0));
+                }
+
+                Out => (),
+
+                JumpZero(address) | JumpNotZero(address) => {
+                    let jumps = self.is_zero(path, pending) == (instruction == JumpZero(address));
+                    let edge = Edge { pc: path.pc, jumps };
+                    if !self.covered[edge.index()] && !path.edges.contains(&edge) {
+                        path.edges.push(edge);
+                    }
+                    if jumps {
+                        path.pc = address.into_usize();
+                    }
+                }
+
+                SetZero => path.set(pointer, Value::Known(0)),
+
+                OffsetAddRight(offset) | OffsetAddLeft(offset) => {
+                    if !self.is_zero(path, pending) {
+                        let target = match instruction {
+                            OffsetAddRight(_) => pointer.checked_add(offset.into_usize())
+                                .filter(|&target| target < self.options.memory_size),
+                            _ => pointer.checked_sub(offset.into_usize()),
+                        };
+                        let target = match (target, instruction) {
+                            (Some(target), _) => target,
+                            (None, OffsetAddRight(_)) =>
+                                return self.fail(path, Error::PointerOverflow),
+                            (None, _) => return self.fail(path, Error::PointerUnderflow),
+                        };
+                        let (a, b) = (path.get(target), path.get(pointer));
+                        let sum = self.add(path, a, b);
+                        path.set(target, sum);
+                        path.set(pointer, Value::Known(0));
+                    }
+                }
+
+                FindZeroRight(skip) | FindZeroLeft(skip) => {
+                    if !self.is_zero(path, pending) {
+                        let next = match instruction {
+                            FindZeroRight(_) => pointer.checked_add(skip.into_usize())
+                                .filter(|&next| next < self.options.memory_size),
+                            _ => pointer.checked_sub(skip.into_usize()),
+                        };
+                        match (next, instruction) {
+                            (Some(next), _) => path.pointer = next,
+                            (None, FindZeroRight(_)) =>
+                                return self.fail(path, Error::PointerOverflow),
+                            (None, _) => return self.fail(path, Error::PointerUnderflow),
+                        }
+                        // Test the next cell.
+                        continue;
+                    }
+                }
+
+                DivMod | IndexRight(_) | IndexLeft(_) => self.run_concretely(path),
+            }
+
+            path.pc += 1;
+        }
+    }
+
+    /// Adds the path’s input to the corpus if it takes an edge no earlier path did.
+    fn finish(&mut self, path: &Path) {
+        let covers: Vec<Edge> = path.edges.iter()
+            .filter(|edge| !self.covered[edge.index()])
+            .cloned()
+            .collect();
+        if covers.is_empty() && !self.tests.is_empty() {
+            return;
+        }
+
+        for edge in &covers {
+            self.covered[edge.index()] = true;
+        }
+        self.tests.push(Test { input: path.example_input(), covers });
+    }
+
+    /// Tests the current cell, forking the path if it can go either way.
+    fn is_zero(&mut self, path: &mut Path, pending: &mut Vec<Path>) -> bool {
+        match self.branch(path) {
+            Branch::Zero => true,
+            Branch::NonZero => false,
+            Branch::Either(index, value) => {
+                if self.paths < self.options.max_paths {
+                    let mut other = path.clone();
+                    other.fix(index, value);
+                    pending.push(other);
+                    self.paths += 1;
+                } else {
+                    self.complete = false;
+                }
+                path.input[index].exclude(value);
+                false
+            }
+        }
+    }
+
+    fn branch(&self, path: &Path) -> Branch {
+        match path.get(path.pointer) {
+            Value::Known(0) => Branch::Zero,
+            Value::Known(_) => Branch::NonZero,
+            Value::Input(index, plus) => {
+                let zero = plus.wrapping_neg();
+                let byte = &path.input[index];
+                match (byte.allows(zero), byte.allows_other_than(zero)) {
+                    (true, true) => Branch::Either(index, zero),
+                    (true, false) => Branch::Zero,
+                    _ => Branch::NonZero,
+                }
+            }
+        }
+    }
+
+    /// Adds two values, fixing an input byte if both depend on one.
+    fn add(&mut self, path: &mut Path, a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::Known(a), Value::Known(b)) => Value::Known(a.wrapping_add(b)),
+            (Value::Known(k), Value::Input(index, plus)) |
+            (Value::Input(index, plus), Value::Known(k)) =>
+                Value::Input(index, plus.wrapping_add(k)),
+            (Value::Input(index, plus), b) => {
+                let value = path.input[index].example();
+                path.fix(index, value);
+                self.complete = false;
+                self.add(path, Value::Known(value.wrapping_add(plus)), b)
+            }
+        }
+    }
+
+    /// Runs the current instruction on known values, fixing every input byte on the tape.
+    fn run_concretely(&mut self, path: &mut Path) {
+        for index in 0 .. path.input.len() {
+            if path.input[index].fixed.is_none() && path.tape.iter().any(|&cell| {
+                matches!(cell, Value::Input(input, _) if input == index)
+            }) {
+                let value = path.input[index].example();
+                path.fix(index, value);
+                self.complete = false;
+            }
+        }
+
+        let mut memory = vec![Wrapping(0); self.options.memory_size];
+        for (cell, &value) in memory.iter_mut().zip(&path.tape) {
+            if let Value::Known(value) = value {
+                *cell = Wrapping(value);
+            }
+        }
+        let mut state = State::from_parts(memory.into_boxed_slice(), path.pointer);
+        let mut pc = path.pc;
+        let result = bytecode::execute(self.program[pc], &mut state, &mut pc, &mut None);
+        debug_assert_eq!(result, Ok(StepResult::Continue));
+
+        let used = state.memory().iter().rposition(|cell| cell.0 != 0).map_or(0, |last| last + 1);
+        path.tape = state.memory()[.. used].iter().map(|cell| Value::Known(cell.0)).collect();
+        path.pointer = state.pointer();
+    }
+
+    /// Records a failure, if it checks out and is the first of its kind.
+    fn fail(&mut self, path: &Path, error: Error) {
+        if self.findings.iter().any(|finding| finding.error == error) {
+            return;
+        }
+
+        let input = path.example_input();
+        let sandbox = Sandbox {
+            memory_size: self.options.memory_size,
+            fuel: self.options.max_steps,
+            max_output: usize::MAX,
+            ..Sandbox::default()
+        };
+        if sandbox.run_bytecode(self.program, &input).stop == Stop::Failed(error) {
+            self.findings.push(Finding { error, pc: path.pc, input });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::BytecodeCompilable;
+
+    fn explore_source(source: &[u8], options: &Options) -> Outcome {
+        explore(&::ast::parse_program(source).unwrap().bytecode_compile(), options)
+    }
+
+    #[test]
+    fn failing_inputs_are_found() {
+        // Moves left off the tape only if the byte read is ‘A’.
+        let source = [&b","[..], &[b'-'; 65][..], b">+<[>-<[-]]>[<<]"].concat();
+        let outcome = explore_source(&source, &Options::default());
+        assert_eq!(outcome.findings.len(), 1);
+        assert_eq!(outcome.findings[0].error, Error::PointerUnderflow);
+        assert_eq!(outcome.findings[0].input, b"A");
+        assert!(outcome.complete);
+        assert_eq!(outcome.paths, 2);
+
+        let outcome = explore_source(b",[->+<]>[<<]", &Options::default());
+        assert_eq!(outcome.findings[0].input, [1]);
+        assert!(outcome.complete);
+    }
+
+    #[test]
+    fn bounded_memory_and_input() {
+        let options = Options { memory_size: 4, ..Options::default() };
+        let outcome = explore_source(b",[>,]", &options);
+        assert_eq!(outcome.findings, vec![Finding { error: Error::PointerOverflow, pc: 2,
+                                                    input: vec![1; 4] }]);
+        assert_eq!((outcome.paths, outcome.complete), (5, true));
+
+        let outcome = explore_source(b",[.,]", &Options { max_input: 3, ..options });
+        assert_eq!((outcome.findings.len(), outcome.complete), (0, false));
+        assert_eq!(explore_source(b",[-]<", &options).findings[0].input, [0]);
+    }
+
+    #[test]
+    fn corpora_cover_each_loop_edge() {
+        let program = ::ast::parse_program(b",[.,]").unwrap().bytecode_compile();
+        let coverage = cover(&program, &Options { max_input: 3, ..Options::default() });
+        let inputs: Vec<&[u8]> = coverage.tests.iter().map(|test| &test.input[..]).collect();
+        assert_eq!(inputs, [&[1, 1, 1][..], &[1, 1, 0], &[0]]);
+        assert_eq!(coverage.tests[2].covers, [Edge { pc: 1, jumps: true }]);
+        assert!(coverage.uncovered.is_empty());
+
+        let program = ::ast::parse_program(b",.+[-]-[>]").unwrap().bytecode_compile();
+        let coverage = cover(&program, &Options::default());
+        assert_eq!(coverage.tests, [Test { input: vec![0], covers: vec![] }]);
+    }
+}
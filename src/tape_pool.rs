@@ -0,0 +1,130 @@
+//! Tapes for machines that run at once.
+//!
+//! Tapes allocated one after another can share cache lines at their ends, so that a server
+//! running one machine per thread has its threads taking lines from each other for cells that
+//! neither program reads. A [`TapePool`](struct.TapePool.html) lays each tape out by a
+//! [`TapeLayout`](../state/struct.TapeLayout.html), by default on a cache line boundary with a
+//! line of padding after it, touched as it is allocated so that Linux places it on the NUMA
+//! node of the thread that will run it. Tapes that runs are done with are kept, reset, for the
+//! next ones; a kept tape stays where its first thread placed it.
+//!
+//! ```
+//! use bf::sandbox::Sandbox;
+//! use bf::tape_pool::TapePool;
+//!
+//! let sandbox = Sandbox::default();
+//! let pool = TapePool::new(sandbox.memory_size);
+//! let mut tape = pool.take();
+//! let report = sandbox.run_on(b"+++.", b"", &mut tape).unwrap();
+//! pool.put(tape);
+//! assert_eq!(report.output, [3]);
+//! assert_eq!(pool.spare(), 1);
+//! ```
+
+use std::sync::Mutex;
+
+use state::{State, TapeLayout};
+
+/// (`== 128`) The cache line size that tapes are aligned and padded to by default, which is
+/// the line size on some ARM processors and the pair of lines that x86 prefetches together.
+pub const CACHE_LINE: usize = 128;
+
+/// (`== 16`) How many spare tapes a pool keeps by default.
+pub const DEFAULT_MAX_SPARE: usize = 16;
+
+/// Allocates tapes of one size and keeps them between runs.
+#[derive(Debug)]
+pub struct TapePool {
+    memory_size: usize,
+    layout: TapeLayout,
+    max_spare: usize,
+    spare: Mutex<Vec<State>>,
+}
+
+impl TapePool {
+    /// A pool of tapes of `memory_size` cells, aligned to and padded by a
+    /// [cache line](constant.CACHE_LINE.html) and touched when allocated.
+    pub fn new(memory_size: usize) -> Self {
+        TapePool {
+            memory_size,
+            layout: TapeLayout { align: CACHE_LINE, padding: CACHE_LINE, first_touch: true },
+            max_spare: DEFAULT_MAX_SPARE,
+            spare: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Lays out the tapes allocated from now on by `layout` instead.
+    pub fn layout(mut self, layout: TapeLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Keeps at most `max_spare` tapes between runs.
+    pub fn max_spare(mut self, max_spare: usize) -> Self {
+        self.max_spare = max_spare;
+        self
+    }
+
+    /// The size of the pool’s tapes.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// A clean tape, either one kept from an earlier run or newly allocated.
+    pub fn take(&self) -> State {
+        self.spare.lock().ok().and_then(|mut spare| spare.pop())
+            .unwrap_or_else(|| State::with_layout(self.memory_size, self.layout))
+    }
+
+    /// Gives back a tape that a run is done with, resetting it to be kept for the next if there
+    /// is room. A tape of another size is dropped.
+    pub fn put(&self, mut tape: State) {
+        if tape.capacity() != self.memory_size {
+            return;
+        }
+        if let Ok(mut spare) = self.spare.lock() {
+            if spare.len() < self.max_spare {
+                tape.reset();
+                spare.push(tape);
+            }
+        }
+    }
+
+    /// The number of tapes kept.
+    pub fn spare(&self) -> usize {
+        self.spare.lock().map_or(0, |spare| spare.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tapes_are_aligned_and_reused_clean() {
+        let pool = TapePool::new(1000).max_spare(1);
+        let mut tape = pool.take();
+        assert_eq!(tape.as_mut_ptr() as usize % CACHE_LINE, 0);
+        tape.right(5usize).unwrap();
+        tape.up(3);
+
+        pool.put(tape);
+        pool.put(State::with_capacity(1000));
+        pool.put(State::with_capacity(10));
+        assert_eq!(pool.spare(), 1);
+        assert_eq!(pool.take(), State::with_capacity(1000));
+        assert_eq!(pool.spare(), 0);
+    }
+
+    #[test]
+    fn laid_out_tapes_behave_as_any_other() {
+        let layout = TapeLayout { align: 64, padding: 10, first_touch: true };
+        let mut tape = State::with_layout(3 * 4096 + 1, layout);
+        tape.right(3 * 4096usize).unwrap();
+        tape.store(7);
+        let copy = tape.clone();
+        assert_eq!(copy, tape);
+        assert_eq!(copy.memory()[3 * 4096].0, 7);
+        assert_eq!(State::with_layout(0, layout).capacity(), 0);
+    }
+}
@@ -0,0 +1,137 @@
+//! Counters and histograms of what the execution service does (`--features metrics`).
+//!
+//! With the `metrics` feature, the [sandbox](../sandbox/index.html) and the
+//! [executor](../executor/index.html) record their compiles, runs and queue through the
+//! [`metrics`](https://docs.rs/metrics) facade, for whichever recorder the embedder installs,
+//! such as a Prometheus exporter. Without it, the functions here do nothing. Each metric is
+//! labelled with the backend that did the work, so that other backends can record theirs with
+//! [`record_compile`](fn.record_compile.html) and [`record_run`](fn.record_run.html) too.
+//!
+//! | Metric | Kind | Labels |
+//! |---|---|---|
+//! | `bf_compiles_total` | counter | `backend`, `result` (`ok` or `error`) |
+//! | `bf_compile_seconds` | histogram | `backend` |
+//! | `bf_runs_total` | counter | `backend`, `stop` |
+//! | `bf_run_seconds` | histogram | `backend` |
+//! | `bf_fuel_exhausted_total` | counter | `backend` |
+//! | `bf_timeouts_total` | counter | `backend` |
+//! | `bf_output_bytes_total` | counter | `backend` |
+//! | `bf_jobs_queued` | gauge | |
+//! | `bf_jobs_rejected_total` | counter | `reason` (`queue_full` or `tenant_queue_full`) |
+//! | `bf_job_wait_seconds` | histogram | |
+//!
+//! The `stop` label is one of `halted`, `failed`, `out_of_fuel`, `output_full` and
+//! `timed_out`.
+
+use std::time::Duration;
+
+use executor::Rejection;
+use run_stats::RunStats;
+use sandbox::Stop;
+
+/// Compiles, by backend and result.
+pub const COMPILES: &str = "bf_compiles_total";
+/// How long compiles take, in seconds.
+pub const COMPILE_SECONDS: &str = "bf_compile_seconds";
+/// Runs, by backend and why they stopped.
+pub const RUNS: &str = "bf_runs_total";
+/// How long runs take, in seconds.
+pub const RUN_SECONDS: &str = "bf_run_seconds";
+/// Runs that used up their fuel.
+pub const FUEL_EXHAUSTED: &str = "bf_fuel_exhausted_total";
+/// Runs that ran past their timeout.
+pub const TIMEOUTS: &str = "bf_timeouts_total";
+/// Bytes that runs printed.
+pub const OUTPUT_BYTES: &str = "bf_output_bytes_total";
+/// Jobs waiting for an executor thread.
+pub const JOBS_QUEUED: &str = "bf_jobs_queued";
+/// Jobs an executor would not queue, by why.
+pub const JOBS_REJECTED: &str = "bf_jobs_rejected_total";
+/// How long jobs wait for a thread, in seconds.
+pub const JOB_WAIT_SECONDS: &str = "bf_job_wait_seconds";
+
+/// Records a compile by `backend` that took `duration` and succeeded if `ok`.
+pub fn record_compile(backend: &'static str, duration: Duration, ok: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if ok { "ok" } else { "error" };
+        ::metrics::counter!(COMPILES, "backend" => backend, "result" => result).increment(1);
+        ::metrics::histogram!(COMPILE_SECONDS, "backend" => backend)
+            .record(duration.as_secs_f64());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (backend, duration, ok);
+}
+
+/// Records a run by `backend` that stopped for `stop`, with its statistics.
+pub fn record_run(backend: &'static str, stop: Stop, stats: &RunStats) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(RUNS, "backend" => backend, "stop" => stop_label(stop)).increment(1);
+        ::metrics::histogram!(RUN_SECONDS, "backend" => backend)
+            .record(stats.run_time.as_secs_f64());
+        ::metrics::counter!(OUTPUT_BYTES, "backend" => backend).increment(stats.output_bytes);
+        match stop {
+            Stop::OutOfFuel => ::metrics::counter!(FUEL_EXHAUSTED, "backend" => backend)
+                .increment(1),
+            Stop::TimedOut => ::metrics::counter!(TIMEOUTS, "backend" => backend).increment(1),
+            _ => (),
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (backend, stop, stats);
+}
+
+/// Records how many jobs are waiting.
+pub(crate) fn record_queued(queued: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(JOBS_QUEUED).set(queued as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = queued;
+}
+
+/// Records a job turned away.
+pub(crate) fn record_rejected(reason: Rejection) {
+    #[cfg(feature = "metrics")]
+    {
+        let reason = match reason {
+            Rejection::QueueFull => "queue_full",
+            Rejection::TenantQueueFull => "tenant_queue_full",
+        };
+        ::metrics::counter!(JOBS_REJECTED, "reason" => reason).increment(1);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = reason;
+}
+
+/// Records how long a job waited for a thread.
+pub(crate) fn record_wait(waited: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(JOB_WAIT_SECONDS).record(waited.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = waited;
+}
+
+/// The `stop` label for why a run stopped.
+pub fn stop_label(stop: Stop) -> &'static str {
+    match stop {
+        Stop::Halted => "halted",
+        Stop::Failed(_) => "failed",
+        Stop::OutOfFuel => "out_of_fuel",
+        Stop::OutputFull => "output_full",
+        Stop::TimedOut => "timed_out",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Error;
+
+    #[test]
+    fn stops_are_labelled_in_snake_case() {
+        assert_eq!(stop_label(Stop::Failed(Error::PointerOverflow)), "failed");
+        assert_eq!(stop_label(Stop::OutOfFuel), "out_of_fuel");
+        assert_eq!(stop_label(Stop::TimedOut), "timed_out");
+    }
+}
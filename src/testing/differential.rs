@@ -0,0 +1,292 @@
+//! Differential tests: random programs run in every backend against the bytecode interpreter.
+//!
+//! [`check`](fn.check.html) draws programs from a seed, made of runs of moves and adds, I/O,
+//! nested loops and the loop forms the [peephole rules](../../peephole/rules/index.html)
+//! rewrite, and runs each on a small tape with random input. Every backend this build has, and
+//! the JIT and LLVM in their other modes, must print what the interpreter prints and then halt
+//! or fail the way it does. [`compare`](fn.compare.html) does the same for one program, as for
+//! a regression test.
+//!
+//! ```
+//! bf::testing::differential::check(1, 50).unwrap();
+//! bf::testing::differential::compare(b"+[>+]", 8, b"").unwrap();
+//! ```
+
+use std::fmt;
+
+use ast;
+use bytecode::{Execution, StepResult};
+use common::{BfResult, Count};
+use peephole::fuzz::{render, Rng};
+use peephole::rules::RULES;
+use state::State;
+use trace;
+use traits::*;
+
+/// (`== 16`) The number of cells on the tapes that [`check`](fn.check.html) runs programs on.
+pub const TAPE_SIZE: usize = 16;
+
+/// (`== 10_000`) The number of steps the interpreter gets before a program is given up on.
+pub const STEP_BUDGET: usize = 10_000;
+
+/// What a run printed, and whether it halted or failed.
+pub type Outcome = (Vec<u8>, BfResult<()>);
+
+/// A backend, by name, and how to run a parsed program in it on a tape of the given size.
+type Backend = (&'static str, fn(&ast::Program, usize, &[u8]) -> Outcome);
+
+/// A run in some backend that did not end the way the interpreter’s did.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disagreement {
+    /// The program.
+    pub source: Vec<u8>,
+    /// The size of the tape it ran on.
+    pub memory_size: usize,
+    /// The input it was given.
+    pub input: Vec<u8>,
+    /// The backend that disagreed.
+    pub backend: &'static str,
+    /// How the interpreter’s run ended.
+    pub expected: Outcome,
+    /// How the backend’s run ended.
+    pub actual: Outcome,
+}
+
+impl fmt::Display for Disagreement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {} cells with input {:?}: {} gave {:?}, expected {:?}",
+               String::from_utf8_lossy(&self.source), self.memory_size, self.input,
+               self.backend, self.actual, self.expected)
+    }
+}
+
+/// Runs `trials` random programs, drawn from the given seed, in every backend. Returns how
+/// many of them the interpreter finished, and so were compared.
+///
+/// # Errors
+///
+/// The first run that disagreed with the interpreter.
+pub fn check(seed: u64, trials: usize) -> Result<usize, Box<Disagreement>> {
+    let mut rng = Rng::new(seed);
+    let mut compared = 0;
+
+    for _ in 0 .. trials {
+        let mut source = Vec::new();
+        program(&mut rng, 3, &mut source);
+        let input: Vec<u8> = (0 .. rng.below(4)).map(|_| rng.below(256) as u8).collect();
+
+        if try_compare(&source, TAPE_SIZE, &input)?.is_some() {
+            compared += 1;
+        }
+    }
+
+    Ok(compared)
+}
+
+/// Runs a program in every backend on a tape of `memory_size` cells, checking that each ends
+/// the way the interpreter does. A program the interpreter does not finish within
+/// [`STEP_BUDGET`](constant.STEP_BUDGET.html) steps is not run elsewhere.
+///
+/// # Panics
+///
+/// If the program does not parse.
+///
+/// # Errors
+///
+/// The first run that disagreed with the interpreter.
+pub fn compare(source: &[u8], memory_size: usize, input: &[u8])
+               -> Result<(), Box<Disagreement>> {
+    try_compare(source, memory_size, input).map(|_| ())
+}
+
+/// Compares the backends’ runs, returning the interpreter’s outcome, or `None` if it did not
+/// finish in time.
+fn try_compare(source: &[u8], memory_size: usize, input: &[u8])
+               -> Result<Option<Outcome>, Box<Disagreement>> {
+    let program = ast::parse_program(source).expect("the program parses");
+    let expected = match interpret(&program, memory_size, input) {
+        Some(expected) => expected,
+        None => return Ok(None),
+    };
+
+    for &(backend, run) in &backends() {
+        let actual = run(&program, memory_size, input);
+        if actual != expected {
+            return Err(Box::new(Disagreement {
+                source: source.to_vec(),
+                memory_size,
+                input: input.to_vec(),
+                backend,
+                expected,
+                actual,
+            }));
+        }
+    }
+
+    Ok(Some(expected))
+}
+
+/// Writes a random sequence of statements, nesting loops up to `depth` deep.
+fn program(rng: &mut Rng, depth: usize, source: &mut Vec<u8>) {
+    for _ in 0 .. 1 + rng.below(6) {
+        let choices = if depth == 0 { 7 } else { 9 };
+        match rng.below(choices) {
+            0 => source.extend(vec![b'+'; 1 + rng.below(3) as usize]),
+            1 => source.extend(vec![b'-'; 1 + rng.below(3) as usize]),
+            2 => source.extend(vec![b'>'; 1 + rng.below(4) as usize]),
+            3 => source.extend(vec![b'<'; 1 + rng.below(4) as usize]),
+            4 => source.push(b'.'),
+            5 => source.push(b','),
+            6 => {
+                let rule = &RULES[rng.below(RULES.len() as u64) as usize];
+                source.push(b'[');
+                render(rule.body, 1 + rng.below(4) as Count, source);
+                source.push(b']');
+            }
+            _ => {
+                source.push(b'[');
+                program(rng, depth - 1, source);
+                source.push(b']');
+            }
+        }
+    }
+}
+
+/// Runs a program in the bytecode interpreter, or returns `None` if it does not finish
+/// within the step budget.
+fn interpret(program: &ast::Program, memory_size: usize, input: &[u8]) -> Option<Outcome> {
+    let code = program.bytecode_compile();
+    let mut execution = Execution::new(&code, State::with_capacity(memory_size));
+    let mut input = input.iter().cloned();
+    let mut output = Vec::new();
+
+    for _ in 0 .. STEP_BUDGET {
+        match execution.step() {
+            Ok(StepResult::Continue) => (),
+            Ok(StepResult::NeedsInput) => execution.provide_input(input.next()),
+            Ok(StepResult::Output(byte)) => output.push(byte),
+            Ok(StepResult::Halted) => return Some((output, Ok(()))),
+            Err(error) => return Some((output, Err(error))),
+        }
+    }
+
+    None
+}
+
+/// Runs an interpretable program on a tape of `memory_size` cells.
+fn run<P: Interpretable + ?Sized>(program: &P, memory_size: usize, input: &[u8]) -> Outcome {
+    let mut output = Vec::new();
+    let result = program.interpret(Some(memory_size), input, &mut output);
+    (output, result)
+}
+
+/// The backends this build has, with the JIT and LLVM in several modes.
+fn backends() -> Vec<Backend> {
+    #[allow(unused_mut)]
+    let mut backends: Vec<Backend> = vec![
+        ("ast", |program, size, input| run(program, size, input)),
+        ("rle", |program, size, input| run(&*program.rle_compile(), size, input)),
+        ("peep", |program, size, input| run(&*program.peephole_compile(), size, input)),
+        ("byte", |program, size, input| run(&*program.bytecode_compile(), size, input)),
+        ("threaded", |program, size, input| run(&program.threaded_compile(), size, input)),
+        ("trace", |program, size, input| {
+            run(&trace::Program::new(program.bytecode_compile(), trace::Options::default()),
+                size, input)
+        }),
+    ];
+
+    #[cfg(feature = "jit")]
+    {
+        use options::CompileOptions;
+
+        fn jit(program: &ast::Program, size: usize, input: &[u8], options: CompileOptions)
+               -> Outcome {
+            run(&program.jit_compile_with_options(&options), size, input)
+        }
+
+        backends.push(("jit", |program, size, input| {
+            jit(program, size, input, CompileOptions::default())
+        }));
+        backends.push(("jit speculate", |program, size, input| {
+            let options = CompileOptions { speculate: true, ..CompileOptions::default() };
+            jit(program, size, input, options)
+        }));
+        backends.push(("jit accounting", |program, size, input| {
+            let options = CompileOptions { accounting: true, ..CompileOptions::default() };
+            jit(program, size, input, options)
+        }));
+        backends.push(("jit deterministic", |program, size, input| {
+            let options = CompileOptions { deterministic: true, ..CompileOptions::default() };
+            jit(program, size, input, options)
+        }));
+        backends.push(("jit sanitize", |program, size, input| {
+            let options = CompileOptions { sanitize: true, ..CompileOptions::default() };
+            jit(program, size, input, options)
+        }));
+    }
+
+    #[cfg(feature = "llvm")]
+    {
+        use options::CompileOptions;
+
+        fn llvm(program: &ast::Program, size: usize, mut input: &[u8],
+                options: CompileOptions) -> Outcome {
+            let mut output = Vec::new();
+            let result = ::llvm::compile_and_run_with_options(
+                &program.peephole_compile(), Some(size), &options, false,
+                ::rts::RtsState::new(&mut input, &mut output));
+            (output, result)
+        }
+
+        backends.push(("llvm", |program, size, input| {
+            llvm(program, size, input, CompileOptions::default())
+        }));
+        backends.push(("llvm outlined", |program, size, input| {
+            let options = CompileOptions { outline_loops: true, ..CompileOptions::default() };
+            llvm(program, size, input, options)
+        }));
+    }
+
+    backends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Error;
+
+    #[test]
+    fn random_programs_agree_everywhere() {
+        for seed in 1 .. 5 {
+            if let Err(disagreement) = check(seed, 200) {
+                panic!("{}", disagreement);
+            }
+        }
+        assert!(check(5, 100).unwrap() > 50);
+    }
+
+    #[test]
+    fn blocks_fail_at_the_end_they_reach_first() {
+        // Goes right, then off the left end, and only then as far right as it ever goes.
+        let source = b"+>><<<<<>>>>>>>>>";
+        let program = ast::parse_program(source).unwrap();
+        assert_eq!(interpret(&program, 5, b""), Some((vec![], Err(Error::PointerUnderflow))));
+        assert_eq!(compare(source, 5, b""), Ok(()));
+        assert_eq!(compare(b"+<<>>>>>>>>>>", 5, b""), Ok(()));
+    }
+
+    #[test]
+    fn disagreements_say_what_differed() {
+        let disagreement = Disagreement {
+            source: b"<".to_vec(),
+            memory_size: 4,
+            input: vec![],
+            backend: "jit",
+            expected: (vec![], Err(Error::PointerUnderflow)),
+            actual: (vec![], Ok(())),
+        };
+        assert_eq!(disagreement.to_string(),
+                   "< on 4 cells with input []: jit gave ([], Ok(())), \
+                    expected ([], Err(PointerUnderflow))");
+    }
+}
@@ -0,0 +1,205 @@
+//! Golden tests: programs run against the output they should print.
+//!
+//! A directory of golden programs, such as the crate’s own `tests/programs/`, holds each program
+//! as `NAME.bf`, the input to give it as `NAME.in` if it reads any, and the output it must
+//! print as `NAME.out`. [`run_all`](fn.run_all.html) runs every program in every backend this
+//! build has, as [`capabilities`](../../capabilities/fn.capabilities.html) lists them, and
+//! reports each run that failed or printed something else.
+//!
+//! ```
+//! let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/programs");
+//! let report = bf::testing::golden::run_all(dir).unwrap();
+//! assert!(report.passed(), "{}", report);
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ast;
+use common::BfResult;
+use trace;
+use traits::*;
+
+/// A backend, by its `bfi` flag, and how to run a parsed program in it on some input.
+type Backend = (&'static str, fn(&ast::Program, &[u8]) -> BfResult<Vec<u8>>);
+
+/// A run whose result was not the golden output.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// The program’s `.bf` file.
+    pub program: PathBuf,
+    /// The backend that ran it, or `parse` if it did not parse.
+    pub backend: &'static str,
+    /// What the run printed, or how it failed.
+    pub result: BfResult<Vec<u8>>,
+    /// What it should have printed.
+    pub expected: Vec<u8>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in {}: ", self.program.display(), self.backend)?;
+        match self.result {
+            Ok(ref output) => write!(f, "printed {:?}, expected {:?}",
+                                     String::from_utf8_lossy(output),
+                                     String::from_utf8_lossy(&self.expected)),
+            Err(ref error) => write!(f, "failed with {}", error),
+        }
+    }
+}
+
+/// What running a directory of golden programs found.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    /// The number of programs found.
+    pub programs: usize,
+    /// The number of runs, one for each program in each backend.
+    pub runs: usize,
+    /// The runs that did not print the golden output, in order by program.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    /// Whether every run printed the golden output.
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    /// A summary line, then one line for each mismatch.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} programs, {} runs, {} mismatches", self.programs, self.runs,
+                 self.mismatches.len())?;
+        for mismatch in &self.mismatches {
+            writeln!(f, "  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs each `.bf` program in `dir` in every backend, with its `.in` file as input, comparing
+/// what it prints with its `.out` file.
+///
+/// # Errors
+///
+/// Fails if the directory, or a program’s `.bf` or `.out` file, can’t be read.
+pub fn run_all<P: AsRef<Path>>(dir: P) -> io::Result<Report> {
+    let mut programs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "bf") {
+            programs.push(path);
+        }
+    }
+    programs.sort();
+
+    let backends = backends();
+    let mut report = Report { programs: programs.len(), ..Report::default() };
+    for path in programs {
+        let source = read(&path)?;
+        let expected = read(&path.with_extension("out"))?;
+        let input = match fs::read(path.with_extension("in")) {
+            Ok(input) => input,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let program = match ast::parse_program(&source) {
+            Ok(program) => program,
+            Err(error) => {
+                report.mismatches.push(Mismatch { program: path, backend: "parse",
+                                                  result: Err(error), expected });
+                continue;
+            }
+        };
+
+        for &(backend, run) in &backends {
+            report.runs += 1;
+            let result = run(&program, &input);
+            if result.as_ref() != Ok(&expected) {
+                report.mismatches.push(Mismatch { program: path.clone(), backend, result,
+                                                  expected: expected.clone() });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Reads a file, naming it in the error.
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// The backends this build has, in the order `capabilities` lists them.
+fn backends() -> Vec<Backend> {
+    #[allow(unused_mut)]
+    let mut backends: Vec<Backend> = vec![
+        ("ast", |program, input| program.interpret_memory(None, input)),
+        ("rle", |program, input| program.rle_compile().interpret_memory(None, input)),
+        ("peep", |program, input| program.peephole_compile().interpret_memory(None, input)),
+        ("byte", |program, input| program.bytecode_compile().interpret_memory(None, input)),
+        ("threaded", |program, input| program.threaded_compile().interpret_memory(None, input)),
+        ("trace", |program, input| {
+            trace::Program::new(program.bytecode_compile(), trace::Options::default())
+                .interpret_memory(None, input)
+        }),
+    ];
+
+    #[cfg(feature = "jit")]
+    backends.push(("jit", |program, input| {
+        program.jit_compile(true).interpret_memory(None, input)
+    }));
+
+    #[cfg(feature = "llvm")]
+    backends.push(("llvm", |program, mut input| {
+        let mut output = Vec::new();
+        ::llvm::compile_and_run(&program.peephole_compile(), None, false,
+                                ::rts::RtsState::new(&mut input, &mut output))?;
+        Ok(output)
+    }));
+
+    backends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn every_backend_runs_each_program() {
+        let report = run_all(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/programs")).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.runs, report.programs * backends().len());
+        let names: Vec<_> = backends().iter().map(|&(name, _)| name).collect();
+        assert_eq!(names, ::capabilities::capabilities().backends);
+    }
+
+    #[test]
+    fn wrong_output_and_failures_are_reported() {
+        let dir = env::temp_dir().join(format!("bf-rs-golden-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("echo.bf"), ",.").unwrap();
+        fs::write(dir.join("echo.in"), "a").unwrap();
+        fs::write(dir.join("echo.out"), "b").unwrap();
+        fs::write(dir.join("under.bf"), "<").unwrap();
+        fs::write(dir.join("under.out"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "not a program").unwrap();
+
+        let report = run_all(&dir).unwrap();
+        assert_eq!((report.programs, report.mismatches.len()), (2, 2 * backends().len()));
+        assert_eq!(report.mismatches[0].to_string(),
+                   format!("{} in ast: printed \"a\", expected \"b\"",
+                           dir.join("echo.bf").display()));
+        assert!(report.to_string().contains("under.bf in byte: failed with pointer underflow"));
+
+        fs::remove_file(dir.join("under.out")).unwrap();
+        assert_eq!(run_all(&dir).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
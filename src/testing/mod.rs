@@ -0,0 +1,8 @@
+//! Helpers for testing programs against every backend.
+//!
+//! [`golden`](golden/index.html) runs a directory of programs against the output they should
+//! print, and [`differential`](differential/index.html) runs random programs in every backend
+//! against the bytecode interpreter.
+
+pub mod differential;
+pub mod golden;
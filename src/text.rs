@@ -0,0 +1,533 @@
+//! Canonical textual forms of every IR level.
+//!
+//! Wrapping a program in [`Text`](struct.Text.html) gives it a `Display` implementation, and the
+//! `parse_*` functions read that output back exactly, so optimizer output can be pasted into bug
+//! reports and golden files and then re-ingested:
+//!
+//!  - The unoptimized [AST](../ast/index.html) is displayed as Brainfuck concrete syntax, with
+//!    comments removed, and is read back by
+//!    [`ast::parse_program`](../ast/fn.parse_program.html).
+//!
+//!  - [Run-length encoded](../rle/index.html) and [peephole](../peephole/index.html) programs are
+//!    displayed one instruction per line, such as `Up(3)` or `OffsetAddRight(2)`, with loops
+//!    written as `Loop {` … `}` and their bodies indented four spaces.
+//!
+//!  - [Bytecode](../bytecode/index.html) is displayed one instruction per line, with jumps
+//!    giving their target addresses, as in `JumpZero(4)`.
+//!
+//! The parsers ignore whitespace and line breaks, and treat `#` as starting a comment that runs
+//! to the end of the line.
+
+use std::fmt;
+
+use ast;
+use bytecode;
+use common::{Command, Count, Instruction};
+use peephole;
+use rle;
+use traits::IntoUsize;
+
+/// Wraps a program at any IR level to display it in canonical textual form.
+pub struct Text<'a, P: ?Sized + 'a>(pub &'a P);
+
+/// An error parsing a textual IR listing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// The (1-based) line where the error was detected.
+    pub line: usize,
+    /// What went wrong.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// The result of parsing a textual IR listing.
+pub type ParseResult<T> = Result<T, ParseError>;
+
+impl<'a> fmt::Display for Text<'a, ast::Program> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for statement in self.0 {
+            match *statement {
+                ast::Statement::Cmd(command) => write!(f, "{}", command_char(command))?,
+                ast::Statement::Loop(ref body) => write!(f, "[{}]", Text(&**body))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Text<'a, rle::Program> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_tree(f, self.0, 0)
+    }
+}
+
+impl<'a> fmt::Display for Text<'a, peephole::Program> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_tree(f, self.0, 0)
+    }
+}
+
+impl<'a> fmt::Display for Text<'a, bytecode::Program> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in self.0 {
+            writeln!(f, "{}", instruction)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use common::Instruction::*;
+
+        match *self {
+            Left(count) => write!(f, "Left({})", count),
+            Right(count) => write!(f, "Right({})", count),
+            Add(amount) => write!(f, "Add({})", amount),
+            In => write!(f, "In"),
+            Out => write!(f, "Out"),
+            JumpZero(address) => write!(f, "JumpZero({})", address),
+            JumpNotZero(address) => write!(f, "JumpNotZero({})", address),
+            SetZero => write!(f, "SetZero"),
+            OffsetAddRight(offset) => write!(f, "OffsetAddRight({})", offset),
+            OffsetAddLeft(offset) => write!(f, "OffsetAddLeft({})", offset),
+            FindZeroRight(skip) => write!(f, "FindZeroRight({})", skip),
+            FindZeroLeft(skip) => write!(f, "FindZeroLeft({})", skip),
+            DivMod => write!(f, "DivMod"),
+            IndexRight(skip) => write!(f, "IndexRight({})", skip),
+            IndexLeft(skip) => write!(f, "IndexLeft({})", skip),
+        }
+    }
+}
+
+/// Parses the textual form of a run-length encoded program.
+pub fn parse_rle(input: &str) -> ParseResult<Box<rle::Program>> {
+    parse_tree(input)
+}
+
+/// Parses the textual form of a peephole program.
+pub fn parse_peephole(input: &str) -> ParseResult<Box<peephole::Program>> {
+    parse_tree(input)
+}
+
+/// Parses the textual form of a bytecode program.
+///
+/// Jump targets are checked: each `JumpZero` must name its matching `JumpNotZero`, and vice
+/// versa.
+pub fn parse_bytecode(input: &str) -> ParseResult<Box<bytecode::Program>> {
+    use common::Instruction::*;
+
+    let mut tokens = Tokens::new(input);
+    let mut instructions = Vec::new();
+    let mut open = Vec::new();
+
+    while let Some((line, token)) = tokens.next()? {
+        let instruction = match token {
+            Token::Ident(name) => {
+                let argument = tokens.argument()?;
+                parse_instruction(name, argument).map_err(|message| ParseError { line, message })?
+            }
+            _ => return Err(ParseError { line, message: "expected an instruction" }),
+        };
+
+        let pc = instructions.len();
+        match instruction {
+            JumpZero(_) => open.push((line, pc)),
+            JumpNotZero(address) => {
+                let matched = open.pop().map(|(_, begin)| begin);
+                if matched != Some(address.into_usize()) {
+                    return Err(ParseError { line, message: "mismatched jump target" });
+                }
+                if instructions[address.into_usize()] != JumpZero(pc as Count) {
+                    return Err(ParseError { line, message: "mismatched jump target" });
+                }
+            }
+            _ => (),
+        }
+
+        instructions.push(instruction);
+    }
+
+    if let Some((line, _)) = open.pop() {
+        return Err(ParseError { line, message: "unmatched JumpZero" });
+    }
+
+    Ok(instructions.into_boxed_slice())
+}
+
+fn command_char(command: Command) -> char {
+    use common::Command::*;
+
+    match command {
+        Right => '>',
+        Left => '<',
+        Up => '+',
+        Down => '-',
+        In => ',',
+        Out => '.',
+        Begin => '[',
+        End => ']',
+    }
+}
+
+/// The statements of the tree-shaped IRs.
+trait TreeStatement: Sized {
+    /// Gets the body if this is a loop.
+    fn as_loop(&self) -> Option<&[Self]>;
+
+    /// Displays a non-loop statement.
+    fn fmt_leaf(&self, f: &mut fmt::Formatter) -> fmt::Result;
+
+    /// Builds a non-loop statement from its name and optional argument.
+    fn from_leaf(name: &str, argument: Option<u64>) -> Result<Self, &'static str>;
+
+    /// Builds a loop.
+    fn from_loop(body: Box<[Self]>) -> Self;
+}
+
+impl TreeStatement for rle::Statement {
+    fn as_loop(&self) -> Option<&[Self]> {
+        match *self {
+            rle::Statement::Loop(ref body) => Some(body),
+            rle::Statement::Cmd(..) => None,
+        }
+    }
+
+    fn fmt_leaf(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            rle::Statement::Cmd(command, count) => write!(f, "{:?}({})", command, count),
+            rle::Statement::Loop(_) => unreachable!(),
+        }
+    }
+
+    fn from_leaf(name: &str, argument: Option<u64>) -> Result<Self, &'static str> {
+        use common::Command::*;
+
+        let command = match name {
+            "Right" => Right,
+            "Left" => Left,
+            "Up" => Up,
+            "Down" => Down,
+            "In" => In,
+            "Out" => Out,
+            _ => return Err("unknown command"),
+        };
+
+        let count = to_count(argument.ok_or("expected a count")?)?;
+        Ok(rle::Statement::Cmd(command, count))
+    }
+
+    fn from_loop(body: Box<[Self]>) -> Self {
+        rle::Statement::Loop(body)
+    }
+}
+
+impl TreeStatement for peephole::Statement {
+    fn as_loop(&self) -> Option<&[Self]> {
+        match *self {
+            peephole::Statement::Loop(ref body) => Some(body),
+            peephole::Statement::Instr(_) => None,
+        }
+    }
+
+    fn fmt_leaf(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            peephole::Statement::Instr(instruction) => write!(f, "{}", instruction),
+            peephole::Statement::Loop(_) => unreachable!(),
+        }
+    }
+
+    fn from_leaf(name: &str, argument: Option<u64>) -> Result<Self, &'static str> {
+        match parse_instruction(name, argument)? {
+            Instruction::JumpZero(_) | Instruction::JumpNotZero(_) =>
+                Err("jumps are not allowed in peephole programs"),
+            instruction => Ok(peephole::Statement::Instr(instruction)),
+        }
+    }
+
+    fn from_loop(body: Box<[Self]>) -> Self {
+        peephole::Statement::Loop(body.into())
+    }
+}
+
+fn write_tree<S: TreeStatement>(f: &mut fmt::Formatter, program: &[S], depth: usize)
+    -> fmt::Result
+{
+    for statement in program {
+        for _ in 0 .. depth {
+            write!(f, "    ")?;
+        }
+
+        if let Some(body) = statement.as_loop() {
+            writeln!(f, "Loop {{")?;
+            write_tree(f, body, depth + 1)?;
+            for _ in 0 .. depth {
+                write!(f, "    ")?;
+            }
+            writeln!(f, "}}")?;
+        } else {
+            statement.fmt_leaf(f)?;
+            writeln!(f)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_tree<S: TreeStatement>(input: &str) -> ParseResult<Box<[S]>> {
+    let mut tokens = Tokens::new(input);
+    let mut current = Vec::new();
+    let mut stack = Vec::new();
+
+    while let Some((line, token)) = tokens.next()? {
+        match token {
+            Token::Ident("Loop") => {
+                match tokens.next()? {
+                    Some((_, Token::LBrace)) => (),
+                    _ => return Err(ParseError { line, message: "expected ‘{’ after Loop" }),
+                }
+                stack.push((line, current));
+                current = Vec::new();
+            }
+
+            Token::Ident(name) => {
+                let argument = tokens.argument()?;
+                let statement = S::from_leaf(name, argument)
+                    .map_err(|message| ParseError { line, message })?;
+                current.push(statement);
+            }
+
+            Token::RBrace => {
+                let body = current.into_boxed_slice();
+                current = match stack.pop() {
+                    Some((_, parent)) => parent,
+                    None => return Err(ParseError { line, message: "unmatched ‘}’" }),
+                };
+                current.push(S::from_loop(body));
+            }
+
+            _ => return Err(ParseError { line, message: "expected an instruction" }),
+        }
+    }
+
+    if let Some((line, _)) = stack.pop() {
+        return Err(ParseError { line, message: "unclosed Loop" });
+    }
+
+    Ok(current.into_boxed_slice())
+}
+
+fn parse_instruction(name: &str, argument: Option<u64>) -> Result<Instruction, &'static str> {
+    use common::Instruction::*;
+
+    let no_argument = |instruction| match argument {
+        None => Ok(instruction),
+        Some(_) => Err("unexpected argument"),
+    };
+    let count = || argument.ok_or("expected a count").and_then(to_count);
+
+    match name {
+        "Left" => Ok(Left(count()?)),
+        "Right" => Ok(Right(count()?)),
+        "Add" => {
+            let amount = argument.ok_or("expected an amount")?;
+            if amount > 255 {
+                return Err("amount out of range");
+            }
+            Ok(Add(amount as u8))
+        }
+        "In" => no_argument(In),
+        "Out" => no_argument(Out),
+        "JumpZero" => Ok(JumpZero(count()?)),
+        "JumpNotZero" => Ok(JumpNotZero(count()?)),
+        "SetZero" => no_argument(SetZero),
+        "OffsetAddRight" => Ok(OffsetAddRight(count()?)),
+        "OffsetAddLeft" => Ok(OffsetAddLeft(count()?)),
+        "FindZeroRight" => Ok(FindZeroRight(count()?)),
+        "FindZeroLeft" => Ok(FindZeroLeft(count()?)),
+        "DivMod" => no_argument(DivMod),
+        "IndexRight" => Ok(IndexRight(count()?)),
+        "IndexLeft" => Ok(IndexLeft(count()?)),
+        _ => Err("unknown instruction"),
+    }
+}
+
+fn to_count(value: u64) -> Result<Count, &'static str> {
+    if value <= Count::MAX as u64 {
+        Ok(value as Count)
+    } else {
+        Err("count out of range")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Number(u64),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+}
+
+struct Tokens<'a> {
+    input: &'a str,
+    line: usize,
+    peeked: Option<(usize, Token<'a>)>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(input: &'a str) -> Self {
+        Tokens { input, line: 1, peeked: None }
+    }
+
+    fn next(&mut self) -> ParseResult<Option<(usize, Token<'a>)>> {
+        if let Some(token) = self.peeked.take() {
+            return Ok(Some(token));
+        }
+
+        loop {
+            let c = match self.input.chars().next() {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+
+            if c == '\n' {
+                self.line += 1;
+                self.input = &self.input[1 ..];
+            } else if c.is_whitespace() {
+                self.input = &self.input[c.len_utf8() ..];
+            } else if c == '#' {
+                let end = self.input.find('\n').unwrap_or(self.input.len());
+                self.input = &self.input[end ..];
+            } else {
+                break;
+            }
+        }
+
+        let line = self.line;
+        let bytes = self.input.as_bytes();
+        let token = match bytes[0] {
+            b'(' => { self.input = &self.input[1 ..]; Token::LParen }
+            b')' => { self.input = &self.input[1 ..]; Token::RParen }
+            b'{' => { self.input = &self.input[1 ..]; Token::LBrace }
+            b'}' => { self.input = &self.input[1 ..]; Token::RBrace }
+            b'0' ..= b'9' => {
+                let end = bytes.iter().position(|b| !b.is_ascii_digit()).unwrap_or(bytes.len());
+                let number = self.input[.. end].parse()
+                    .map_err(|_| ParseError { line, message: "number out of range" })?;
+                self.input = &self.input[end ..];
+                Token::Number(number)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let end = bytes.iter().position(|b| !b.is_ascii_alphanumeric())
+                    .unwrap_or(bytes.len());
+                let ident = &self.input[.. end];
+                self.input = &self.input[end ..];
+                Token::Ident(ident)
+            }
+            _ => return Err(ParseError { line, message: "unexpected character" }),
+        };
+
+        Ok(Some((line, token)))
+    }
+
+    /// Parses an optional parenthesized numeric argument.
+    fn argument(&mut self) -> ParseResult<Option<u64>> {
+        match self.next()? {
+            Some((line, Token::LParen)) => {
+                match (self.next()?, self.next()?) {
+                    (Some((_, Token::Number(n))), Some((_, Token::RParen))) => Ok(Some(n)),
+                    _ => Err(ParseError { line, message: "expected ‘(number)’" }),
+                }
+            }
+            other => {
+                self.peeked = other;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::*;
+    use traits::{BytecodeCompilable, PeepholeCompilable, RleCompilable};
+
+    #[test]
+    fn ast_round_trips() {
+        let program = ast::parse_program(FACTOR_SRC).unwrap();
+        let text = Text(&*program).to_string();
+        assert_eq!(ast::parse_program(text.as_bytes()).unwrap(), program);
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let program = ast::parse_program(FACTOR_SRC).unwrap().rle_compile();
+        let text = Text(&*program).to_string();
+        assert_eq!(parse_rle(&text).unwrap(), program);
+    }
+
+    #[test]
+    fn peephole_round_trips() {
+        let program = ast::parse_program(FACTOR_SRC).unwrap().peephole_compile();
+        let text = Text(&*program).to_string();
+        assert_eq!(parse_peephole(&text).unwrap(), program);
+    }
+
+    #[test]
+    fn bytecode_round_trips() {
+        let program = ast::parse_program(FACTOR_SRC).unwrap().bytecode_compile();
+        let text = Text(&*program).to_string();
+        assert_eq!(parse_bytecode(&text).unwrap(), program);
+    }
+
+    #[test]
+    fn peephole_golden_output() {
+        let program = ast::parse_program(b"++[->+<]>[-]<<[>>]").unwrap().peephole_compile();
+        assert_eq!(Text(&*program).to_string(),
+                   "Add(2)\n\
+                    OffsetAddRight(1)\n\
+                    Right(1)\n\
+                    SetZero\n\
+                    Left(2)\n\
+                    FindZeroRight(2)\n");
+    }
+
+    #[test]
+    fn nested_loops_are_indented() {
+        let program = ast::parse_program(b"[>[.]]").unwrap().peephole_compile();
+        assert_eq!(Text(&*program).to_string(),
+                   "Loop {\n    Right(1)\n    Loop {\n        Out\n    }\n}\n");
+    }
+
+    #[test]
+    fn parser_accepts_free_form_input() {
+        let program = parse_peephole("Add(3) # three\nLoop { Out Add ( 255 ) }").unwrap();
+        assert_eq!(program, ast::parse_program(b"+++[.-]").unwrap().peephole_compile());
+    }
+
+    #[test]
+    fn parse_errors_have_lines() {
+        assert_eq!(parse_peephole("Out\nBogus"),
+                   Err(ParseError { line: 2, message: "unknown instruction" }));
+        assert_eq!(parse_peephole("Loop {\nOut"),
+                   Err(ParseError { line: 1, message: "unclosed Loop" }));
+        assert_eq!(parse_peephole("}"),
+                   Err(ParseError { line: 1, message: "unmatched ‘}’" }));
+        assert_eq!(parse_peephole("Add(256)"),
+                   Err(ParseError { line: 1, message: "amount out of range" }));
+        assert_eq!(parse_rle("Up"),
+                   Err(ParseError { line: 1, message: "expected a count" }));
+        assert_eq!(parse_bytecode("JumpZero(1)\nJumpNotZero(5)"),
+                   Err(ParseError { line: 2, message: "mismatched jump target" }));
+    }
+}
@@ -0,0 +1,202 @@
+use super::*;
+use common::Instruction;
+use peephole::{self, Statement};
+
+/// Program forms that can be compiled to closure-threaded code.
+pub trait ThreadedCompilable {
+    /// Compile the given program into the peephole AST to prepare for threading.
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R;
+
+    /// Compile the given program to closure-threaded code.
+    fn threaded_compile(&self) -> Program {
+        self.with_peephole(compile)
+    }
+}
+
+/// The most statements to chain together before starting a new chain.
+const CHAIN_LENGTH: usize = 64;
+
+/// Compiles peephole-optimized AST to closure-threaded code.
+pub fn compile(program: &peephole::Program) -> Program {
+    Program { start: compile_block(program) }
+}
+
+fn compile_block(block: &[Statement]) -> Thunk {
+    let mut chains: Vec<Thunk> = block.chunks(CHAIN_LENGTH).map(compile_chain).collect();
+
+    if chains.len() == 1 {
+        chains.pop().unwrap()
+    } else {
+        Box::new(move |machine| {
+            for chain in &chains {
+                chain(machine)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+fn compile_chain(statements: &[Statement]) -> Thunk {
+    let mut next: Thunk = Box::new(|_| Ok(()));
+
+    for statement in statements.iter().rev() {
+        next = match *statement {
+            Statement::Instr(instruction) => compile_instruction(instruction, next),
+
+            Statement::Loop(ref body) => {
+                let body = compile_block(body);
+                Box::new(move |machine| {
+                    while machine.state.load() != 0 {
+                        body(machine)?;
+                    }
+                    next(machine)
+                })
+            }
+        };
+    }
+
+    next
+}
+
+fn compile_instruction(instruction: Instruction, next: Thunk) -> Thunk {
+    use common::Instruction::*;
+
+    match instruction {
+        Left(count) => Box::new(move |machine| {
+            machine.state.left(count)?;
+            next(machine)
+        }),
+
+        Right(count) => Box::new(move |machine| {
+            machine.state.right(count)?;
+            next(machine)
+        }),
+
+        Add(amount) => Box::new(move |machine| {
+            machine.state.up(amount);
+            next(machine)
+        }),
+
+        In => Box::new(move |machine| {
+            machine.state.read(&mut machine.input);
+            next(machine)
+        }),
+
+        Out => Box::new(move |machine| {
+            machine.state.write(&mut machine.output);
+            next(machine)
+        }),
+
+        SetZero => Box::new(move |machine| {
+            machine.state.store(0);
+            next(machine)
+        }),
+
+        OffsetAddRight(offset) => Box::new(move |machine| {
+            let value = machine.state.load();
+            if value != 0 {
+                machine.state.store(0);
+                machine.state.up_pos_offset(offset, value)?;
+            }
+            next(machine)
+        }),
+
+        OffsetAddLeft(offset) => Box::new(move |machine| {
+            let value = machine.state.load();
+            if value != 0 {
+                machine.state.store(0);
+                machine.state.up_neg_offset(offset, value)?;
+            }
+            next(machine)
+        }),
+
+        FindZeroRight(skip) => Box::new(move |machine| {
+            while machine.state.load() != 0 {
+                machine.state.right(skip)?;
+            }
+            next(machine)
+        }),
+
+        FindZeroLeft(skip) => Box::new(move |machine| {
+            while machine.state.load() != 0 {
+                machine.state.left(skip)?;
+            }
+            next(machine)
+        }),
+
+        DivMod => Box::new(move |machine| {
+            machine.state.div_mod();
+            next(machine)
+        }),
+
+        IndexRight(skip) => Box::new(move |machine| {
+            machine.state.index_right(skip);
+            next(machine)
+        }),
+
+        IndexLeft(skip) => Box::new(move |machine| {
+            machine.state.index_left(skip);
+            next(machine)
+        }),
+
+        JumpZero(_) | JumpNotZero(_) =>
+            panic!("unexpected jump instruction"),
+    }
+}
+
+impl ThreadedCompilable for peephole::Program {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(self)
+    }
+}
+
+impl<T: peephole::PeepholeCompilable + ?Sized> ThreadedCompilable for T {
+    fn with_peephole<F, R>(&self, k: F) -> R
+        where F: FnOnce(&peephole::Program) -> R
+    {
+        k(&self.peephole_compile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Error;
+    use test_helpers::*;
+
+    fn assert_threaded(source: &[u8], input: &[u8], output: &[u8]) {
+        let program = ::ast::parse_program(source).unwrap().threaded_compile();
+        assert_interpret(&program, input, output);
+    }
+
+    #[test]
+    fn hello_world() {
+        assert_threaded(HELLO_WORLD_SRC, b"", b"Hello, World!");
+    }
+
+    #[test]
+    fn factor() {
+        assert_threaded(FACTOR_SRC, b"2\n", b"2: 2\n");
+        assert_threaded(FACTOR_SRC, b"3450\n", b"3450: 2 3 5 5 23\n");
+    }
+
+    #[test]
+    fn long_chains_are_split() {
+        let mut source = Vec::new();
+        for _ in 0 .. 100_000 {
+            source.extend_from_slice(b"+>");
+        }
+        source.extend_from_slice(b"<.");
+        let program = ::ast::parse_program(&source).unwrap().threaded_compile();
+        assert_eq!(program.interpret_memory(Some(200_000), b""), Ok(vec![1]));
+    }
+
+    #[test]
+    fn errors() {
+        let program = ::ast::parse_program(b"+[<+]").unwrap().threaded_compile();
+        assert_interpret_result(&program, b"", Err(Error::PointerUnderflow));
+    }
+}
@@ -0,0 +1,59 @@
+//! Closure-threaded code, a middle tier between interpretation and native JIT.
+//!
+//! This pass compiles each peephole statement into a boxed closure that does its work and then
+//! calls the closure for the next statement, so running a program is a chain of indirect calls
+//! with no dispatch on instructions. It is slower than the [JIT](../jit/index.html), but it is
+//! portable and contains no `unsafe` code.
+//!
+//! Chains are cut every few dozen statements, and the pieces run in sequence, so that the
+//! native stack depth stays bounded by the nesting of loops rather than the length of the
+//! program.
+
+use std::io::{Read, Write};
+
+use common::BfResult;
+use state::State;
+use traits::Interpretable;
+
+mod compiler;
+
+pub use self::compiler::{compile, ThreadedCompilable};
+
+/// The state a compiled closure runs against.
+struct Machine<'a> {
+    state:  &'a mut State,
+    input:  &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+/// A compiled statement, together with everything after it in its chain.
+type Thunk = Box<dyn Fn(&mut Machine) -> BfResult<()>>;
+
+/// A closure-threaded program.
+pub struct Program {
+    start: Thunk,
+}
+
+impl Program {
+    /// Runs the program on the given state, leaving it as the program does, as for comparing
+    /// it with another backend’s.
+    pub fn run_state<R: Read, W: Write>(&self, state: &mut State, mut input: R, mut output: W)
+                                        -> BfResult<()>
+    {
+        let mut machine = Machine {
+            state,
+            input: &mut input,
+            output: &mut output,
+        };
+
+        (self.start)(&mut machine)
+    }
+}
+
+impl Interpretable for Program {
+    fn interpret_state<R: Read, W: Write>(
+        &self, mut state: State, input: R, output: W) -> BfResult<()>
+    {
+        self.run_state(&mut state, input, output)
+    }
+}
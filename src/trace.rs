@@ -0,0 +1,419 @@
+//! An experimental tracing tier for the bytecode interpreter.
+//!
+//! The interpreter counts how often each loop jumps back. Once a loop is hot, the next
+//! iteration is recorded as a linear trace of the instructions actually run, with each branch
+//! replaced by a guard on the value the recorder observed. Traces are then compiled to
+//! straight-line [`Op`](enum.Op.html)s with pointer movement folded into constant offsets,
+//! so that an iteration does a single bounds check rather than one per move.
+//!
+//! When a guard fails, the trace exits to the interpreter at the instruction the failing
+//! branch would have gone to, with the pointer materialized where the trace had reached.
+//! When an iteration might leave the tape, the trace deoptimizes at the top of the loop and
+//! the interpreter runs that iteration, reporting any error exactly as it would without
+//! traces. Loops that cannot be traced, because they scan with `FindZero` or run too long,
+//! are interpreted from then on.
+
+use std::io::{Read, Write};
+use std::num::Wrapping;
+
+use bytecode;
+use common::{BfResult, Instruction};
+use state::State;
+use traits::{Interpretable, IntoUsize};
+
+/// Options for the tracing tier.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Options {
+    /// How many times a loop jumps back before its next iteration is recorded.
+    pub hot_threshold: usize,
+    /// The most instructions a trace may record before it is abandoned.
+    pub max_trace_length: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { hot_threshold: 50, max_trace_length: 1_000 }
+    }
+}
+
+/// Counts of what the tracing tier did during a run.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Traces recorded and compiled.
+    pub traces: usize,
+    /// Recordings abandoned.
+    pub aborted: usize,
+    /// Loop iterations run by traces.
+    pub trace_iterations: usize,
+    /// Exits to the interpreter on guard failure.
+    pub side_exits: usize,
+    /// Iterations handed back to the interpreter because they might leave the tape.
+    pub deopts: usize,
+}
+
+/// A compiled trace operation, addressing cells at an offset from the pointer at the top of
+/// the iteration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Add to a cell.
+    Add(isize, u8),
+    /// Zero a cell.
+    SetZero(isize),
+    /// Add the first cell to the second and zero the first.
+    OffsetAdd(isize, isize),
+    /// Read into a cell.
+    In(isize),
+    /// Write a cell.
+    Out(isize),
+    /// Exit to the interpreter at `exit`, with the pointer on the cell, unless the cell is
+    /// nonzero exactly when `nonzero` is.
+    Guard {
+        /// The cell tested.
+        offset:  isize,
+        /// Whether the recorder saw the cell nonzero.
+        nonzero: bool,
+        /// Where the interpreter resumes if the guard fails.
+        exit:    usize,
+    },
+}
+
+/// A compiled trace of one iteration of a loop.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trace {
+    /// The address of the loop’s `JumpNotZero`.
+    end:   usize,
+    /// The address of the first instruction in the loop body.
+    header: usize,
+    ops:   Box<[Op]>,
+    /// How far the pointer moves in an iteration.
+    delta: isize,
+    /// The lowest and highest offsets the pointer reaches in an iteration.
+    min:   isize,
+    max:   isize,
+}
+
+impl Trace {
+    /// The compiled operations.
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// How far the pointer moves in an iteration.
+    pub fn delta(&self) -> isize {
+        self.delta
+    }
+}
+
+/// A bytecode program to run with the tracing tier.
+#[derive(Clone, Debug)]
+pub struct Program {
+    code: Box<bytecode::Program>,
+    options: Options,
+}
+
+impl Program {
+    /// Wraps a bytecode program to run with the given options.
+    pub fn new(code: Box<bytecode::Program>, options: Options) -> Self {
+        Program { code, options }
+    }
+
+    /// Runs the program against the given state, returning what the tracing tier did.
+    pub fn interpret_stats<R: Read, W: Write>(&self, state: &mut State, input: R, output: W)
+                                              -> BfResult<Stats>
+    {
+        interpret(&self.code, state, input, output, &self.options)
+    }
+}
+
+impl Interpretable for Program {
+    fn interpret_state<R: Read, W: Write>(&self, mut state: State, input: R, output: W)
+                                          -> BfResult<()>
+    {
+        self.interpret_stats(&mut state, input, output).map(|_| ())
+    }
+}
+
+/// What is known about a loop, stored at the address of its `JumpNotZero`.
+enum Loop {
+    /// Counting jumps back.
+    Cold(usize),
+    /// Traced.
+    Hot(Box<Trace>),
+    /// Not to be traced.
+    Blacklisted,
+}
+
+/// Runs a bytecode program, tracing hot loops.
+pub fn interpret<R: Read, W: Write>(program: &bytecode::Program, state: &mut State,
+                                    mut input: R, mut output: W, options: &Options)
+                                    -> BfResult<Stats>
+{
+    use common::Instruction::*;
+
+    let mut loops: Vec<Loop> = program.iter().map(|_| Loop::Cold(0)).collect();
+    let mut stats = Stats::default();
+    let mut pc = 0;
+
+    while let Some(&instruction) = program.get(pc) {
+        if let JumpNotZero(_) = instruction {
+            if state.load() != 0 {
+                let entry = &mut loops[pc];
+
+                match *entry {
+                    Loop::Cold(ref mut count) if *count + 1 < options.hot_threshold => {
+                        *count += 1;
+                    }
+
+                    Loop::Cold(_) => {
+                        let (next, trace) = record(program, state, &mut input, &mut output,
+                                                   pc, options)?;
+                        *entry = match trace {
+                            Some(trace) => { stats.traces += 1; Loop::Hot(Box::new(trace)) }
+                            None => { stats.aborted += 1; Loop::Blacklisted }
+                        };
+                        pc = next;
+                        continue;
+                    }
+
+                    Loop::Hot(ref trace) => {
+                        pc = run_trace(trace, state, &mut input, &mut output, &mut stats);
+                        continue;
+                    }
+
+                    Loop::Blacklisted => (),
+                }
+            }
+        }
+
+        // The commonest instructions, kept out of `step` for speed.
+        match instruction {
+            Left(count) => state.left(count)?,
+            Right(count) => state.right(count)?,
+            Add(count) => state.up(count),
+            _ => {
+                step(instruction, state, &mut pc, &mut input, &mut output)?;
+                continue;
+            }
+        }
+
+        pc += 1;
+    }
+
+    let _ = output.flush();
+    Ok(stats)
+}
+
+/// Runs one instruction in the interpreter.
+fn step<R: Read, W: Write>(instruction: Instruction, state: &mut State, pc: &mut usize,
+                           input: &mut R, output: &mut W) -> BfResult<()>
+{
+    match instruction {
+        Instruction::In => { state.read(input); *pc += 1; }
+        Instruction::Out => { state.write(output); *pc += 1; }
+        _ => { bytecode::execute(instruction, state, pc, &mut None)?; }
+    }
+
+    Ok(())
+}
+
+/// Takes the back jump of the loop ending at `end` and records the next iteration, running it
+/// as it goes. Returns where to continue and, if the iteration made it back to `end`, the
+/// compiled trace.
+fn record<R: Read, W: Write>(program: &bytecode::Program, state: &mut State, input: &mut R,
+                             output: &mut W, end: usize, options: &Options)
+                             -> BfResult<(usize, Option<Trace>)>
+{
+    use common::Instruction::*;
+
+    let header = match program[end] {
+        JumpNotZero(address) => address.into_usize() + 1,
+        _ => unreachable!(),
+    };
+
+    let mut ops = Vec::new();
+    let mut pc = header;
+    let (mut offset, mut min, mut max) = (0isize, 0isize, 0isize);
+
+    loop {
+        if ops.len() >= options.max_trace_length {
+            return Ok((pc, None));
+        }
+
+        let instruction = program[pc];
+        let nonzero = state.load() != 0;
+
+        match instruction {
+            Left(count) => offset -= count.into_usize() as isize,
+            Right(count) => offset += count.into_usize() as isize,
+            Add(count) => ops.push(Op::Add(offset, count)),
+            SetZero => ops.push(Op::SetZero(offset)),
+
+            OffsetAddRight(count) => {
+                let to = offset + count.into_usize() as isize;
+                ops.push(Op::OffsetAdd(offset, to));
+                max = max.max(to);
+            }
+
+            OffsetAddLeft(count) => {
+                let to = offset - count.into_usize() as isize;
+                ops.push(Op::OffsetAdd(offset, to));
+                min = min.min(to);
+            }
+
+            In => ops.push(Op::In(offset)),
+            Out => ops.push(Op::Out(offset)),
+            FindZeroRight(_) | FindZeroLeft(_) | DivMod | IndexRight(_) | IndexLeft(_) =>
+                return Ok((pc, None)),
+
+            JumpZero(address) => {
+                let exit = if nonzero { address.into_usize() + 1 } else { pc + 1 };
+                ops.push(Op::Guard { offset, nonzero, exit });
+            }
+
+            JumpNotZero(_) if pc == end => {
+                if !nonzero {
+                    return Ok((pc + 1, None));
+                }
+
+                let trace = Trace {
+                    end, header, ops: ops.into_boxed_slice(), delta: offset, min, max,
+                };
+                return Ok((header, Some(trace)));
+            }
+
+            JumpNotZero(address) => {
+                let exit = if nonzero { pc + 1 } else { address.into_usize() + 1 };
+                ops.push(Op::Guard { offset, nonzero, exit });
+            }
+        }
+
+        step(instruction, state, &mut pc, input, output)?;
+        min = min.min(offset);
+        max = max.max(offset);
+    }
+}
+
+/// Runs a trace from the back jump of its loop until it exits, returning where the
+/// interpreter continues.
+fn run_trace<R: Read, W: Write>(trace: &Trace, state: &mut State, input: &mut R,
+                                output: &mut W, stats: &mut Stats) -> usize
+{
+    let (memory, pointer) = state.parts_mut();
+    let cell = |base: isize, offset: isize| (base + offset) as usize;
+
+    loop {
+        let base = *pointer as isize;
+        if base + trace.min < 0 || base + trace.max >= memory.len() as isize {
+            stats.deopts += 1;
+            return trace.header;
+        }
+
+        for &op in trace.ops.iter() {
+            match op {
+                Op::Add(offset, count) => memory[cell(base, offset)] += Wrapping(count),
+                Op::SetZero(offset) => memory[cell(base, offset)] = Wrapping(0),
+
+                Op::OffsetAdd(from, to) => {
+                    let value = memory[cell(base, from)];
+                    if value.0 != 0 {
+                        memory[cell(base, to)] += value;
+                        memory[cell(base, from)] = Wrapping(0);
+                    }
+                }
+
+                Op::In(offset) => {
+                    let mut byte = [0];
+                    let _ = input.read_exact(&mut byte);
+                    memory[cell(base, offset)] = Wrapping(byte[0]);
+                }
+
+                Op::Out(offset) => {
+                    let _ = output.write_all(&[memory[cell(base, offset)].0]);
+                }
+
+                Op::Guard { offset, nonzero, exit } => {
+                    if (memory[cell(base, offset)].0 != 0) != nonzero {
+                        *pointer = cell(base, offset);
+                        stats.side_exits += 1;
+                        return exit;
+                    }
+                }
+            }
+        }
+
+        *pointer = cell(base, trace.delta);
+        stats.trace_iterations += 1;
+
+        if memory[*pointer].0 == 0 {
+            return trace.end + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Error;
+    use test_helpers::*;
+    use traits::BytecodeCompilable;
+
+    fn compile(source: &[u8], hot_threshold: usize) -> Program {
+        let code = ::ast::parse_program(source).unwrap().bytecode_compile();
+        Program::new(code, Options { hot_threshold, ..Options::default() })
+    }
+
+    fn run_stats(program: &Program, input: &[u8]) -> BfResult<Stats> {
+        program.interpret_stats(&mut State::new(), input, Vec::new())
+    }
+
+    /// The trace recorded for the last loop of `source`, starting with every cell 2.
+    fn record_source(source: &[u8]) -> Option<Trace> {
+        let code = ::ast::parse_program(source).unwrap().bytecode_compile();
+        let end = code.iter().rposition(|i| matches!(i, Instruction::JumpNotZero(_))).unwrap();
+        let mut state = State::with_capacity(8);
+        for cell in state.parts_mut().0 {
+            *cell = Wrapping(2);
+        }
+        record(&code, &mut state, &mut &b""[..], &mut Vec::new(), end, &Options::default())
+            .unwrap().1
+    }
+
+    #[test]
+    fn traced_programs_agree_with_the_interpreter() {
+        for &threshold in &[1, 2, 50] {
+            assert_interpret(&compile(FACTOR_SRC, threshold), b"100\n", b"100: 2 2 5 5\n");
+            assert_interpret(&compile(HELLO_WORLD_SRC, threshold), b"", b"Hello, World!");
+        }
+
+        let stats = run_stats(&compile(FACTOR_SRC, 1), b"100\n").unwrap();
+        assert!(stats.traces > 0 && stats.trace_iterations > 0 && stats.side_exits > 0);
+    }
+
+    #[test]
+    fn traces_fold_moves_into_offsets() {
+        let trace = record_source(b"[->>+<]").unwrap();
+        assert_eq!(trace.ops(), &[Op::Add(0, 255), Op::Add(2, 1)]);
+        assert_eq!(trace.delta(), 1);
+
+        let trace = record_source(b"[>[-]<-]").unwrap();
+        assert_eq!(trace.ops(), &[Op::SetZero(1), Op::Add(0, 255)]);
+
+        // Inner loops are unrolled behind guards on what the recorder saw.
+        let trace = record_source(b"[->[-]+[->+<]<]").unwrap();
+        assert_eq!(trace.ops(), &[Op::Add(0, 255), Op::SetZero(1), Op::Add(1, 1),
+                                  Op::OffsetAdd(1, 2)]);
+        assert_eq!(record_source(b"[>[>]<-]"), None);
+    }
+
+    #[test]
+    fn leaving_the_tape_falls_back_to_the_interpreter() {
+        assert_interpret_result(&compile(b"+[>+]", 2), b"", Err(Error::PointerOverflow));
+        assert_interpret_result(&compile(b">>>>+[<+]", 2), b"", Err(Error::PointerUnderflow));
+
+        // The trace reaches three cells left, so it deoptimizes for the last iteration.
+        let program = compile(b">>>>>>>+[-<+<<+>>]", 2);
+        let mut state = State::with_capacity(8);
+        assert_eq!(program.interpret_stats(&mut state, &b""[..], Vec::new()),
+                   Err(Error::PointerUnderflow));
+    }
+}
@@ -4,11 +4,14 @@
 use std::io::{Cursor, Read, Write, stdin, stdout};
 
 use common::BfResult;
+use run_stats::{self, RunStats};
 use state::State;
 
 pub use rle::RleCompilable;
 pub use peephole::PeepholeCompilable;
 pub use bytecode::BytecodeCompilable;
+pub use threaded::ThreadedCompilable;
+pub use fingerprint::Fingerprintable;
 #[cfg(feature = "jit")]
 pub use jit::JitCompilable;
 #[cfg(feature = "llvm")]
@@ -21,11 +24,22 @@ pub trait Interpretable {
                                           input: R, output: W)
         -> BfResult<()>;
 
+    /// Interprets a program against the given state, measuring the run. By default, only the
+    /// input and output bytes and the run time are known; backends that step through
+    /// instructions say more.
+    fn interpret_stats<R: Read, W: Write>(&self, state: State, input: R, output: W)
+        -> (BfResult<()>, RunStats)
+    {
+        run_stats::measure(input, output, |input, output| {
+            self.interpret_state(state, input, output)
+        })
+    }
+
     /// Interprets a program. If the given `size` is `None`, the default memory size.
     fn interpret<R: Read, W: Write>(
         &self, size: Option<usize>, input: R, output: W) -> BfResult<()>
     {
-        let state = size.map(State::with_capacity).unwrap_or_else(State::new);
+        let state = size.map(State::with_capacity).unwrap_or_default();
         self.interpret_state(state, input, output)
     }
 
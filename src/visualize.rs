@@ -0,0 +1,171 @@
+//! Frames of a program running, for `bfi run --visualize`.
+//!
+//! A [`Frame`](struct.Frame.html) is what there is to see at one step: the source with the
+//! instruction about to run highlighted, the tape around the pointer, and the output so far.
+//! A [`Layout`](struct.Layout.html) draws it as text with ANSI reverse video for the
+//! highlights, sized to fit a terminal; `bfi` redraws one after each step it takes through the
+//! [event channel](../events/index.html).
+
+use std::fmt::Write;
+
+use diagnostics::Span;
+use state::State;
+
+/// What there is to see at one step of a run.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    /// The program’s source.
+    pub source: &'a [u8],
+    /// The span of source compiled to the next instruction, or `None` once the run is over.
+    pub span: Option<Span>,
+    /// How many instructions have run.
+    pub steps: u64,
+    /// The machine state.
+    pub state: &'a State,
+    /// Everything the program has written.
+    pub output: &'a [u8],
+}
+
+/// How much of each part of a frame to show.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Layout {
+    /// Lines of source, around the highlighted instruction.
+    pub source_lines: usize,
+    /// Cells of tape, around the pointer.
+    pub tape_cells: usize,
+    /// The last lines of output.
+    pub output_lines: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout { source_lines: 12, tape_cells: 16, output_lines: 6 }
+    }
+}
+
+const HIGHLIGHT: &str = "\x1b[7m";
+const PLAIN: &str = "\x1b[0m";
+
+impl Layout {
+    /// Draws a frame.
+    pub fn render(&self, frame: &Frame) -> String {
+        let mut result = String::new();
+        let _ = writeln!(result, "step {}", frame.steps);
+        result.push('\n');
+        self.source(&mut result, frame.source, frame.span);
+        result.push('\n');
+        self.tape(&mut result, frame.state);
+        result.push('\n');
+        self.output(&mut result, frame.output);
+        result
+    }
+
+    /// The lines of source around the span, with the span highlighted.
+    fn source(&self, result: &mut String, source: &[u8], span: Option<Span>) {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for line in source.split(|&c| c == b'\n') {
+            lines.push(start .. start + line.len());
+            start += line.len() + 1;
+        }
+
+        let current = span.map_or(0, |span| {
+            lines.iter().position(|line| span.start <= line.end).unwrap_or(0)
+        });
+        let first = current.saturating_sub(self.source_lines / 2)
+            .min(lines.len().saturating_sub(self.source_lines));
+
+        for line in lines.iter().skip(first).take(self.source_lines) {
+            let mut text = Vec::new();
+            let mut highlighted = false;
+            for offset in line.clone() {
+                let inside = span.is_some_and(|span| span.start <= offset && offset < span.end);
+                if inside != highlighted {
+                    text.extend_from_slice(if inside { HIGHLIGHT } else { PLAIN }.as_bytes());
+                    highlighted = inside;
+                }
+                text.push(source[offset]);
+            }
+            if highlighted {
+                text.extend_from_slice(PLAIN.as_bytes());
+            }
+            result.push_str(&String::from_utf8_lossy(&text));
+            result.push('\n');
+        }
+    }
+
+    /// The addresses and values of the cells around the pointer, with the pointer’s
+    /// highlighted.
+    fn tape(&self, result: &mut String, state: &State) {
+        let memory = state.memory();
+        let first = state.pointer().saturating_sub(self.tape_cells / 2)
+            .min(memory.len().saturating_sub(self.tape_cells));
+        let cells = first .. (first + self.tape_cells).min(memory.len());
+
+        let mut addresses = String::new();
+        let mut values = String::new();
+        for address in cells {
+            let (on, off) = if address == state.pointer() { (HIGHLIGHT, PLAIN) } else { ("", "") };
+            let _ = write!(addresses, " {:>5}", address);
+            let _ = write!(values, " {}{:>5}{}", on, memory[address].0, off);
+        }
+        let _ = writeln!(result, "{}\n{}", addresses, values);
+    }
+
+    /// The last lines of output, with control characters other than newlines shown as `·`.
+    fn output(&self, result: &mut String, output: &[u8]) {
+        let text: String = String::from_utf8_lossy(output).chars()
+            .map(|c| if c.is_control() && c != '\n' { '·' } else { c })
+            .collect();
+        let lines: Vec<&str> = text.split('\n').collect();
+        for line in &lines[lines.len().saturating_sub(self.output_lines) ..] {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_highlight_the_instruction_and_the_pointer() {
+        let mut state = State::with_capacity(4);
+        state.right(2usize).unwrap();
+        state.up(7);
+        let frame = Frame {
+            source: b"+>\n[-]<\n.",
+            span: Some(Span { start: 3, end: 6 }),
+            steps: 5,
+            state: &state,
+            output: b"ab\ncd",
+        };
+        let layout = Layout { source_lines: 2, tape_cells: 3, output_lines: 1 };
+        assert_eq!(layout.render(&frame),
+                   "step 5\n\n\
+                    +>\n\
+                    \x1b[7m[-]\x1b[0m<\n\n     \
+                    1     2     3\n     \
+                    0 \x1b[7m    7\x1b[0m     0\n\n\
+                    cd\n");
+    }
+
+    #[test]
+    fn windows_follow_the_run() {
+        let source = b"0\n1\n2\n3\n4\n5\n6\n7\n8\n9";
+        let state = State::with_capacity(100);
+        let frame = Frame { source, span: Some(Span::at(16)), steps: 0, state: &state,
+                            output: b"\x07" };
+        let text = Layout { source_lines: 3, ..Layout::default() }.render(&frame);
+        assert!(text.contains("\n7\n\x1b[7m8\x1b[0m\n9\n"));
+        assert!(text.ends_with("\n·\n"));
+
+        let mut end = state.clone();
+        end.right(99usize).unwrap();
+        let frame = Frame { state: &end, span: None, ..frame };
+        let text = Layout { tape_cells: 2, ..Layout::default() }.render(&frame);
+        assert!(text.contains("    98    99\n"));
+        assert!(text.starts_with("step 0\n\n0\n1\n"));
+    }
+}